@@ -0,0 +1,243 @@
+use std::{
+    io::{self, ErrorKind, Read, Write},
+    net::{SocketAddr, TcpStream, ToSocketAddrs},
+    sync::mpsc::{self, RecvTimeoutError},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    error::AgentError,
+    protocol::{self, Command, UdpTask, MAX_RESPONSE_LEN},
+};
+
+/// Where adnet-agent runs in the course network
+pub const DEFAULT_AGENT: &str = "10.0.0.3:12345";
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+pub const DEFAULT_RESPONSE_TIMEOUT: Duration = Duration::from_secs(30);
+
+// How long to wait for an attempt before starting the next one in parallel (RFC 8305)
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Client for the adnet-agent control connection.
+///
+/// ```no_run
+/// use adnet_core::AgentClient;
+///
+/// let client = AgentClient::new("10.0.0.3:12345");
+/// let (_socket, task) = client.request_udp("keyword")?;
+/// println!("Send {} bytes of {}", task.size, task.character as char);
+/// # Ok::<(), adnet_core::AgentError>(())
+/// ```
+#[derive(Debug, Clone)]
+pub struct AgentClient {
+    address: String,
+    pub(crate) connect_timeout: Duration,
+    pub(crate) response_timeout: Duration,
+}
+
+impl Default for AgentClient {
+    fn default() -> Self {
+        Self::new(DEFAULT_AGENT)
+    }
+}
+
+impl AgentClient {
+    /// `address` is `host:port`, where host can also be a name.
+    pub fn new(address: impl Into<String>) -> Self {
+        Self {
+            address: address.into(),
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            response_timeout: DEFAULT_RESPONSE_TIMEOUT,
+        }
+    }
+
+    /// Time limit for establishing the TCP connection.
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// Time limit for the agent to answer a request that has a response.
+    pub fn response_timeout(mut self, timeout: Duration) -> Self {
+        self.response_timeout = timeout;
+        self
+    }
+
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+
+    /// Host part of the address, without brackets around IPv6 addresses.
+    pub fn host(&self) -> &str {
+        let host = self
+            .address
+            .rsplit_once(':')
+            .map_or(self.address.as_str(), |(host, _)| host);
+        host.trim_start_matches('[').trim_end_matches(']')
+    }
+
+    /// Resolves the agent address. Done separately from connecting so that
+    /// every address can be tried with a timeout.
+    pub fn resolve(&self) -> Result<Vec<SocketAddr>, AgentError> {
+        let addrs: Vec<SocketAddr> = self
+            .address
+            .to_socket_addrs()
+            .map_err(|e| AgentError::Resolve {
+                agent: self.address.clone(),
+                source: Some(e),
+            })?
+            .collect();
+        if addrs.is_empty() {
+            return Err(AgentError::Resolve {
+                agent: self.address.clone(),
+                source: None,
+            });
+        }
+        Ok(addrs)
+    }
+
+    /// Opens the TCP connection to the agent without sending anything.
+    pub fn connect(&self) -> Result<TcpStream, AgentError> {
+        let addrs = self.resolve()?;
+        let (socket, _) = self.connect_to(addrs)?;
+        Ok(socket)
+    }
+
+    /// Connects to one of the already resolved addresses, see [`connect_any`].
+    pub fn connect_to(
+        &self,
+        addrs: Vec<SocketAddr>,
+    ) -> Result<(TcpStream, SocketAddr), AgentError> {
+        connect_any(addrs, self.connect_timeout).map_err(|e| AgentError::Connect {
+            agent: self.address.clone(),
+            source: e,
+        })
+    }
+
+    /// Connects and sends the command. Returns the socket so that the caller
+    /// can continue with the task-specific part of the protocol.
+    pub fn request(&self, command: &Command) -> Result<TcpStream, AgentError> {
+        let mut socket = self.connect()?;
+        send_command(&mut socket, command)?;
+        Ok(socket)
+    }
+
+    /// Sends TASK-UDP and waits for the agent to tell what to send.
+    pub fn request_udp(&self, keyword: &str) -> Result<(TcpStream, UdpTask), AgentError> {
+        let command = Command::Udp {
+            keyword: keyword.to_string(),
+        };
+        let mut socket = self.request(&command)?;
+        socket.set_read_timeout(Some(self.response_timeout))?;
+        let task = read_udp_task(&mut socket).map_err(|e| match e {
+            AgentError::Io(e) if is_timeout(&e) => AgentError::Timeout(self.response_timeout),
+            e => e,
+        })?;
+        socket.set_read_timeout(None)?;
+        Ok((socket, task))
+    }
+}
+
+/// Writes the control message to an already established connection, which
+/// may also be e.g. a TLS stream.
+pub fn send_command<W: Write>(socket: &mut W, command: &Command) -> io::Result<()> {
+    socket.write_all(&command.to_bytes())?;
+    socket.flush()
+}
+
+/// Reads the agent's answer to TASK-UDP. Any read timeout must be set on the
+/// socket by the caller.
+pub fn read_udp_task<R: Read>(socket: &mut R) -> Result<UdpTask, AgentError> {
+    let mut buf = [0u8; MAX_RESPONSE_LEN];
+    let mut len = 0;
+    loop {
+        let n = match socket.read(&mut buf[len..]) {
+            Ok(n) => n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+        if n == 0 {
+            return match len {
+                0 => Err(AgentError::Closed),
+                _ => String::from_utf8_lossy(&buf[..len]).parse(),
+            };
+        }
+        len += n;
+        if let Some(result) = protocol::parse_udp_task(&buf[..len]) {
+            return result;
+        }
+    }
+}
+
+/// Connects to whichever of the addresses answers first, in the spirit of Happy
+/// Eyeballs (RFC 8305): addresses are tried in order alternating between IPv6
+/// and IPv4, and a new attempt is started in parallel whenever the previous
+/// one fails or has not completed in CONNECTION_ATTEMPT_DELAY.
+pub fn connect_any(
+    addrs: Vec<SocketAddr>,
+    timeout: Duration,
+) -> io::Result<(TcpStream, SocketAddr)> {
+    let addrs = interleave(addrs);
+    let deadline = Instant::now() + timeout;
+    let (tx, rx) = mpsc::channel();
+    let mut started = 0;
+    let mut failed = 0;
+
+    loop {
+        if started < addrs.len() {
+            let addr = addrs[started];
+            let tx = tx.clone();
+            // Late connections of losing attempts are just dropped by the thread
+            thread::spawn(move || {
+                let _ = tx.send((addr, TcpStream::connect_timeout(&addr, timeout)));
+            });
+            started += 1;
+        }
+
+        let wait = if started < addrs.len() {
+            CONNECTION_ATTEMPT_DELAY
+        } else {
+            deadline.saturating_duration_since(Instant::now())
+        };
+
+        match rx.recv_timeout(wait) {
+            Ok((addr, Ok(stream))) => return Ok((stream, addr)),
+            Ok((addr, Err(e))) => {
+                tracing::debug!("Connection attempt to {} failed: {}", addr, e);
+                failed += 1;
+                if failed == addrs.len() {
+                    return Err(e);
+                }
+            }
+            Err(RecvTimeoutError::Timeout) if started < addrs.len() => {}
+            Err(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("no address answered within {:?}", timeout),
+                ))
+            }
+        }
+    }
+}
+
+/// Orders addresses so that families alternate, starting with the family of
+/// the first resolved address.
+fn interleave(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let first_v6 = addrs.first().is_some_and(SocketAddr::is_ipv6);
+    let (first, second): (Vec<_>, Vec<_>) =
+        addrs.into_iter().partition(|a| a.is_ipv6() == first_v6);
+    let (mut first, mut second) = (first.into_iter(), second.into_iter());
+    let mut ordered = Vec::new();
+    loop {
+        match (first.next(), second.next()) {
+            (None, None) => break,
+            (a, b) => ordered.extend(a.into_iter().chain(b)),
+        }
+    }
+    ordered
+}
+
+pub(crate) fn is_timeout(e: &io::Error) -> bool {
+    e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut
+}
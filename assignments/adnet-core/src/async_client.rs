@@ -0,0 +1,102 @@
+//! Async variants of the AgentClient operations for the tokio based tasks.
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{self, TcpStream},
+    time,
+};
+
+use crate::{
+    agent::AgentClient,
+    error::AgentError,
+    protocol::{self, Command, UdpTask, MAX_RESPONSE_LEN},
+};
+
+impl AgentClient {
+    /// Opens the TCP connection to the agent. The addresses are tried in turn,
+    /// and the connect timeout covers all of them.
+    pub async fn connect_async(&self) -> Result<TcpStream, AgentError> {
+        let addrs: Vec<_> = net::lookup_host(self.address())
+            .await
+            .map_err(|e| AgentError::Resolve {
+                agent: self.address().to_string(),
+                source: Some(e),
+            })?
+            .collect();
+        if addrs.is_empty() {
+            return Err(AgentError::Resolve {
+                agent: self.address().to_string(),
+                source: None,
+            });
+        }
+
+        let timeout = self.connect_timeout;
+        match time::timeout(timeout, TcpStream::connect(&addrs[..])).await {
+            Ok(Ok(socket)) => Ok(socket),
+            Ok(Err(e)) => Err(AgentError::Connect {
+                agent: self.address().to_string(),
+                source: e,
+            }),
+            Err(_) => Err(AgentError::Connect {
+                agent: self.address().to_string(),
+                source: std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    format!("no address answered within {:?}", timeout),
+                ),
+            }),
+        }
+    }
+
+    /// Connects and sends the command, see [`AgentClient::request`].
+    pub async fn request_async(&self, command: &Command) -> Result<TcpStream, AgentError> {
+        let mut socket = self.connect_async().await?;
+        send_command_async(&mut socket, command).await?;
+        Ok(socket)
+    }
+
+    /// Sends TASK-UDP and waits for the agent to tell what to send.
+    pub async fn request_udp_async(
+        &self,
+        keyword: &str,
+    ) -> Result<(TcpStream, UdpTask), AgentError> {
+        let command = Command::Udp {
+            keyword: keyword.to_string(),
+        };
+        let mut socket = self.request_async(&command).await?;
+        let timeout = self.response_timeout;
+        let task = time::timeout(timeout, read_udp_task_async(&mut socket))
+            .await
+            .map_err(|_| AgentError::Timeout(timeout))??;
+        Ok((socket, task))
+    }
+}
+
+/// Writes the control message to an already established connection.
+pub async fn send_command_async<W: AsyncWriteExt + Unpin>(
+    socket: &mut W,
+    command: &Command,
+) -> std::io::Result<()> {
+    socket.write_all(&command.to_bytes()).await?;
+    socket.flush().await
+}
+
+/// Reads the agent's answer to TASK-UDP, see [`crate::agent::read_udp_task`].
+pub async fn read_udp_task_async<R: AsyncReadExt + Unpin>(
+    socket: &mut R,
+) -> Result<UdpTask, AgentError> {
+    let mut buf = [0u8; MAX_RESPONSE_LEN];
+    let mut len = 0;
+    loop {
+        let n = socket.read(&mut buf[len..]).await?;
+        if n == 0 {
+            return match len {
+                0 => Err(AgentError::Closed),
+                _ => String::from_utf8_lossy(&buf[..len]).parse(),
+            };
+        }
+        len += n;
+        if let Some(result) = protocol::parse_udp_task(&buf[..len]) {
+            return result;
+        }
+    }
+}
@@ -0,0 +1,71 @@
+//! Settings from a TOML file. All programs can share one file, each reading
+//! its own section:
+//!
+//! ```toml
+//! [task-udp]
+//! server = "10.0.0.3"
+//! keyword = "secret"
+//!
+//! [task-srv]
+//! keyword = "secret"
+//! port = 2000
+//! ```
+//!
+//! Options given on the command line override values from the file.
+
+use std::{fs, io, path::PathBuf, time::Duration};
+
+use serde::{de::DeserializeOwned, Deserialize, Deserializer};
+
+/// The `--config` option common to all programs.
+#[derive(clap::Args, Debug, Clone, Default)]
+pub struct ConfigArgs {
+    /// TOML file with default values for the options
+    #[arg(long, global = true, value_name = "FILE")]
+    pub config: Option<PathBuf>,
+}
+
+impl ConfigArgs {
+    /// Reads the named section of the config file. Without `--config`, or if
+    /// the file has no such section, returns the default value, which for the
+    /// per-program settings means that nothing is set.
+    pub fn section<T: DeserializeOwned + Default>(&self, name: &str) -> io::Result<T> {
+        let Some(path) = &self.config else {
+            return Ok(T::default());
+        };
+        let invalid = |e: &dyn std::fmt::Display| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid config file {}: {}", path.display(), e),
+            )
+        };
+
+        let text = fs::read_to_string(path).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("Cannot read config file {}: {}", path.display(), e),
+            )
+        })?;
+        let mut file: toml::Table = toml::from_str(&text).map_err(|e| invalid(&e))?;
+        match file.remove(name) {
+            Some(section) => section.try_into().map_err(|e| invalid(&e)),
+            None => Ok(T::default()),
+        }
+    }
+}
+
+/// Parses a duration given in seconds, possibly fractional, for the
+/// `value_parser` of the timeout and interval options on the command line.
+pub fn parse_secs(s: &str) -> Result<Duration, String> {
+    let secs: f64 = s.parse().map_err(|e| format!("{}", e))?;
+    Duration::try_from_secs_f64(secs).map_err(|e| format!("{}", e))
+}
+
+/// Reads a duration given in seconds, like the timeout options on the
+/// command line. Use with `#[serde(default, deserialize_with = "...")]`.
+pub fn secs<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Duration>, D::Error> {
+    let secs = f64::deserialize(d)?;
+    Duration::try_from_secs_f64(secs)
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
@@ -0,0 +1,42 @@
+use std::{fmt, io, time::Duration};
+
+use thiserror::Error;
+
+/// Errors from talking to adnet-agent.
+#[derive(Error)]
+pub enum AgentError {
+    /// The agent address did not resolve to any socket address
+    #[error("Failed to resolve agent address {agent}{}", suffix(.source))]
+    Resolve {
+        agent: String,
+        source: Option<io::Error>,
+    },
+    /// None of the resolved addresses accepted the connection
+    #[error("Connection to {agent} failed: {source}")]
+    Connect { agent: String, source: io::Error },
+    /// The agent did not answer within the response timeout
+    #[error("No response from the agent in {0:?}")]
+    Timeout(Duration),
+    /// The agent closed the connection before answering
+    #[error("Agent closed the connection without a response")]
+    Closed,
+    /// The agent answered something we do not understand
+    #[error("Invalid agent response: {0:?}")]
+    InvalidResponse(String),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+fn suffix(source: &Option<io::Error>) -> String {
+    source
+        .as_ref()
+        .map(|e| format!(": {}", e))
+        .unwrap_or_default()
+}
+
+// Binaries return errors from main, which prints them with Debug
+impl fmt::Debug for AgentError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
@@ -0,0 +1,114 @@
+//! Process exit codes, the same for all programs, so that scripts can tell
+//! why a run failed without parsing the error message:
+//!
+//! | Code | Meaning |
+//! |------|---------|
+//! | 0    | Success |
+//! | 1    | Any other failure |
+//! | 2    | Invalid command line or config file |
+//! | 3    | Could not connect, to adnet-agent or to a peer |
+//! | 4    | Protocol error: the peer answered something unexpected |
+//! | 5    | Timeout |
+//! | 6    | Verification failed: the data was not what was expected |
+//! | 130  | Interrupted by Ctrl-C or SIGTERM before the work was done |
+//!
+//! A second signal exits at once with 128 plus the signal number, see
+//! [`shutdown`](crate::shutdown). Each program has its own error type that
+//! says which [`Code`] its errors get, and ends `main` with [`exit`]:
+//!
+//! ```no_run
+//! # fn run() -> Result<(), adnet_core::AgentError> { Ok(()) }
+//! fn main() -> std::process::ExitCode {
+//!     adnet_core::exit::exit(run())
+//! }
+//! ```
+
+use std::{error::Error, fmt::Display, io, process::ExitCode};
+
+use crate::AgentError;
+
+/// Why a program failed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Code {
+    Failure = 1,
+    Usage = 2,
+    Connect = 3,
+    Protocol = 4,
+    Timeout = 5,
+    Verify = 6,
+    Interrupted = 130,
+}
+
+/// Errors that know their exit code.
+pub trait HasCode {
+    fn exit_code(&self) -> Code;
+}
+
+impl HasCode for io::Error {
+    fn exit_code(&self) -> Code {
+        use io::ErrorKind::*;
+        match self.kind() {
+            TimedOut | WouldBlock => Code::Timeout,
+            ConnectionRefused | ConnectionReset | ConnectionAborted | NotConnected
+            | AddrNotAvailable | HostUnreachable | NetworkUnreachable => Code::Connect,
+            InvalidData | UnexpectedEof => Code::Protocol,
+            Interrupted => Code::Interrupted,
+            _ => Code::Failure,
+        }
+    }
+}
+
+impl HasCode for AgentError {
+    fn exit_code(&self) -> Code {
+        match self {
+            AgentError::Resolve { .. } | AgentError::Connect { .. } => Code::Connect,
+            AgentError::Timeout(_) => Code::Timeout,
+            AgentError::Closed | AgentError::InvalidResponse(_) => Code::Protocol,
+            AgentError::Io(e) => e.exit_code(),
+        }
+    }
+}
+
+/// Code of an error whose type is not known, from the first error of a
+/// known type among it and its sources.
+pub fn classify(error: &(dyn Error + 'static)) -> Code {
+    let mut next = Some(error);
+    while let Some(error) = next {
+        if let Some(e) = error.downcast_ref::<AgentError>() {
+            return e.exit_code();
+        }
+        if let Some(e) = error.downcast_ref::<io::Error>() {
+            return e.exit_code();
+        }
+        next = error.source();
+    }
+    Code::Failure
+}
+
+impl HasCode for Box<dyn Error> {
+    fn exit_code(&self) -> Code {
+        classify(self.as_ref())
+    }
+}
+
+impl From<Code> for ExitCode {
+    fn from(code: Code) -> Self {
+        ExitCode::from(code as u8)
+    }
+}
+
+/// Ends `main` with the code of `result`, after printing the error.
+pub fn exit<E: Display + HasCode>(result: Result<(), E>) -> ExitCode {
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => fail(&e, e.exit_code()),
+    }
+}
+
+/// Prints the error as returning it from `main` would, and returns `code`
+/// for `main` to exit with.
+pub fn fail(error: &dyn Display, code: Code) -> ExitCode {
+    eprintln!("Error: {}", error);
+    code.into()
+}
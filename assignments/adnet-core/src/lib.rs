@@ -0,0 +1,27 @@
+//! Code shared by the assignment programs. Every assignment starts by opening
+//! a TCP connection to adnet-agent and sending a control message of the form
+//! `TASK-XXX keyword [arguments]`; [`AgentClient`] does that part, and
+//! [`protocol`] has the message formats. [`logging`] sets up the same log
+//! output for all of the programs, [`config`] reads their settings from a
+//! file, and [`metrics`] exports comparable runtime metrics from all of them.
+//! [`rtt`] estimates round-trip times for the protocols built on UDP, and
+//! [`shutdown`] lets the long-running programs exit cleanly on Ctrl-C.
+//! [`report`] writes the result of a run as JSON for scripts, and [`exit`]
+//! gives them exit codes that tell why a run failed.
+
+pub mod agent;
+#[cfg(feature = "tokio")]
+pub mod async_client;
+pub mod config;
+pub mod error;
+pub mod exit;
+pub mod logging;
+pub mod metrics;
+pub mod protocol;
+pub mod report;
+pub mod rtt;
+pub mod shutdown;
+
+pub use agent::AgentClient;
+pub use error::AgentError;
+pub use protocol::{Command, UdpTask};
@@ -0,0 +1,89 @@
+//! Log output shared by the task programs. They log through `tracing`, and
+//! call [`init`] at startup with the [`LogArgs`] flattened into their command
+//! line arguments.
+//!
+//! The level is `info` unless overridden with RUST_LOG, for example
+//! `RUST_LOG=debug` or `RUST_LOG=task_tun=trace,adnet_core=debug`, and
+//! long-running programs can change it while they run with [`set_filter`].
+
+use std::{
+    fs::OpenOptions,
+    io,
+    path::PathBuf,
+    sync::{Mutex, OnceLock},
+};
+
+use tracing_subscriber::{
+    fmt, layer::Layered, prelude::*, registry::Registry, reload, EnvFilter, Layer,
+};
+
+pub const DEFAULT_FILTER: &str = "info";
+
+type Subscriber = Layered<reload::Layer<EnvFilter, Registry>, Registry>;
+
+// The filter of the installed subscriber
+static FILTER: OnceLock<reload::Handle<EnvFilter, Registry>> = OnceLock::new();
+
+/// Logging options common to all programs.
+#[derive(clap::Args, Debug, Clone, Default)]
+pub struct LogArgs {
+    /// Write log records as JSON lines
+    #[arg(long, global = true)]
+    pub log_json: bool,
+
+    /// Append the log to this file instead of printing it
+    #[arg(long, global = true, value_name = "FILE")]
+    pub log_file: Option<PathBuf>,
+}
+
+/// Installs the global log subscriber. Records from the `log` crate, such as
+/// those of aya, are forwarded as well.
+pub fn init(args: &LogArgs) -> io::Result<()> {
+    let filter =
+        EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(DEFAULT_FILTER));
+    let (filter, handle) = reload::Layer::new(filter);
+    let layer = fmt::layer().with_target(false);
+
+    let output: Box<dyn Layer<Subscriber> + Send + Sync> = match &args.log_file {
+        Some(path) => {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map_err(|e| {
+                    io::Error::new(
+                        e.kind(),
+                        format!("Cannot open log file {}: {}", path.display(), e),
+                    )
+                })?;
+            let layer = layer.with_ansi(false).with_writer(Mutex::new(file));
+            match args.log_json {
+                true => layer.json().boxed(),
+                false => layer.boxed(),
+            }
+        }
+        None => match args.log_json {
+            true => layer.json().boxed(),
+            false => layer.boxed(),
+        },
+    };
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(output)
+        .try_init()
+        .map_err(io::Error::other)?;
+    let _ = FILTER.set(handle);
+    Ok(())
+}
+
+/// Replaces the filter of the log, in the syntax of RUST_LOG, e.g. with
+/// `debug` to see every packet for a while and [`DEFAULT_FILTER`] to go
+/// back to normal.
+pub fn set_filter(directives: &str) -> io::Result<()> {
+    let filter = EnvFilter::try_new(directives)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let handle = FILTER
+        .get()
+        .ok_or_else(|| io::Error::other("Logging is not initialized"))?;
+    handle.reload(filter).map_err(io::Error::other)
+}
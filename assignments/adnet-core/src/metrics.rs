@@ -0,0 +1,337 @@
+//! Runtime metrics shared by the task programs, so that all of them report
+//! comparable numbers. The programs create their counters, gauges and
+//! histograms in the [`global`] registry, and [`init`] sets up the backends
+//! selected with the [`MetricsArgs`] flattened into their command line
+//! arguments:
+//!
+//! - `--metrics-listen ADDR` serves the metrics in the Prometheus text format
+//!   over HTTP
+//! - `--metrics-interval SECS` writes all metrics to the log periodically
+//!
+//! Metric names follow the Prometheus conventions, for example
+//! `udp_packets_sent_total` or `srv_request_duration_seconds`.
+
+use std::{
+    fmt::Write as _,
+    io::{self, BufRead, BufReader, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicI64, AtomicU64, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+    thread,
+    time::Duration,
+};
+
+use tracing::{info, warn};
+
+/// Options for exporting metrics, common to all programs.
+#[derive(clap::Args, Debug, Clone, Default)]
+pub struct MetricsArgs {
+    /// Serve metrics in the Prometheus format at this address, e.g. 0.0.0.0:9100
+    #[arg(long, global = true, value_name = "ADDR")]
+    pub metrics_listen: Option<SocketAddr>,
+
+    /// Write all metrics to the log every this many seconds
+    #[arg(long, global = true, value_name = "SECS", value_parser = crate::config::parse_secs)]
+    pub metrics_interval: Option<Duration>,
+}
+
+/// Starts the backends selected in `args` for the [`global`] registry. Both
+/// run in their own threads until the program exits.
+pub fn init(args: &MetricsArgs) -> io::Result<()> {
+    if let Some(addr) = args.metrics_listen {
+        let listener = TcpListener::bind(addr).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("Cannot listen for metrics at {}: {}", addr, e),
+            )
+        })?;
+        info!("Serving metrics at http://{}/metrics", addr);
+        thread::spawn(move || serve_prometheus(listener, global()));
+    }
+    if let Some(interval) = args.metrics_interval {
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            global().log_dump();
+        });
+    }
+    Ok(())
+}
+
+/// The registry the programs record their metrics in.
+pub fn global() -> &'static Registry {
+    static GLOBAL: OnceLock<Registry> = OnceLock::new();
+    GLOBAL.get_or_init(Registry::new)
+}
+
+/// Shorthand for [`Registry::counter`] of the [`global`] registry.
+pub fn counter(name: &str, help: &str) -> Counter {
+    global().counter(name, help)
+}
+
+/// Shorthand for [`Registry::gauge`] of the [`global`] registry.
+pub fn gauge(name: &str, help: &str) -> Gauge {
+    global().gauge(name, help)
+}
+
+/// Shorthand for [`Registry::histogram`] of the [`global`] registry.
+pub fn histogram(name: &str, help: &str, bounds: &[f64]) -> Histogram {
+    global().histogram(name, help, bounds)
+}
+
+/// A value that only grows, such as the number of packets sent.
+#[derive(Clone, Default)]
+pub struct Counter(Arc<AtomicU64>);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// A value that goes up and down, such as the number of open connections.
+#[derive(Clone, Default)]
+pub struct Gauge(Arc<AtomicI64>);
+
+impl Gauge {
+    pub fn set(&self, value: i64) {
+        self.0.store(value, Ordering::Relaxed);
+    }
+
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn dec(&self) {
+        self.add(-1);
+    }
+
+    pub fn add(&self, n: i64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> i64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Distribution of observed values, counted in buckets with fixed upper bounds.
+#[derive(Clone)]
+pub struct Histogram(Arc<HistogramData>);
+
+struct HistogramData {
+    bounds: Vec<f64>,
+    // One more than bounds, the last one for values above every bound
+    buckets: Vec<AtomicU64>,
+    count: AtomicU64,
+    // f64 bits, updated with compare-and-swap
+    sum: AtomicU64,
+}
+
+impl Histogram {
+    fn new(bounds: &[f64]) -> Self {
+        let mut bounds = bounds.to_vec();
+        bounds.sort_by(f64::total_cmp);
+        let buckets = (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect();
+        Histogram(Arc::new(HistogramData {
+            bounds,
+            buckets,
+            count: AtomicU64::new(0),
+            sum: AtomicU64::new(0f64.to_bits()),
+        }))
+    }
+
+    pub fn observe(&self, value: f64) {
+        let data = &self.0;
+        let bucket = data.bounds.partition_point(|&bound| bound < value);
+        data.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        data.count.fetch_add(1, Ordering::Relaxed);
+        let _ = data
+            .sum
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+                Some((f64::from_bits(bits) + value).to_bits())
+            });
+    }
+
+    /// Number of observed values.
+    pub fn count(&self) -> u64 {
+        self.0.count.load(Ordering::Relaxed)
+    }
+
+    /// Sum of the observed values.
+    pub fn sum(&self) -> f64 {
+        f64::from_bits(self.0.sum.load(Ordering::Relaxed))
+    }
+
+    /// Upper bounds with the number of values at most that bound, the last
+    /// one being infinity with the total count.
+    pub fn cumulative(&self) -> Vec<(f64, u64)> {
+        let data = &self.0;
+        let bounds = data.bounds.iter().copied().chain([f64::INFINITY]);
+        let mut total = 0;
+        bounds
+            .zip(&data.buckets)
+            .map(|(bound, n)| {
+                total += n.load(Ordering::Relaxed);
+                (bound, total)
+            })
+            .collect()
+    }
+}
+
+#[derive(Clone)]
+enum Metric {
+    Counter(Counter),
+    Gauge(Gauge),
+    Histogram(Histogram),
+}
+
+struct Entry {
+    name: String,
+    help: String,
+    metric: Metric,
+}
+
+/// A set of named metrics. Asking for a metric with a name that is already
+/// registered returns the existing one, so that the same metric can be
+/// looked up in several places.
+#[derive(Default)]
+pub struct Registry {
+    entries: Mutex<Vec<Entry>>,
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn counter(&self, name: &str, help: &str) -> Counter {
+        match self.register(name, help, || Metric::Counter(Counter::default())) {
+            Metric::Counter(counter) => counter,
+            _ => panic!("metric {} is not a counter", name),
+        }
+    }
+
+    pub fn gauge(&self, name: &str, help: &str) -> Gauge {
+        match self.register(name, help, || Metric::Gauge(Gauge::default())) {
+            Metric::Gauge(gauge) => gauge,
+            _ => panic!("metric {} is not a gauge", name),
+        }
+    }
+
+    /// A histogram with the given bucket upper bounds. Values above all of
+    /// them are counted in an implicit `+Inf` bucket.
+    pub fn histogram(&self, name: &str, help: &str, bounds: &[f64]) -> Histogram {
+        match self.register(name, help, || Metric::Histogram(Histogram::new(bounds))) {
+            Metric::Histogram(histogram) => histogram,
+            _ => panic!("metric {} is not a histogram", name),
+        }
+    }
+
+    fn register(&self, name: &str, help: &str, new: impl FnOnce() -> Metric) -> Metric {
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(entry) = entries.iter().find(|e| e.name == name) {
+            return entry.metric.clone();
+        }
+        let metric = new();
+        entries.push(Entry {
+            name: name.to_string(),
+            help: help.to_string(),
+            metric: metric.clone(),
+        });
+        metric
+    }
+
+    /// All metrics in the Prometheus text exposition format.
+    pub fn render_prometheus(&self) -> String {
+        let mut out = String::new();
+        for entry in self.entries.lock().unwrap().iter() {
+            let name = &entry.name;
+            let kind = match entry.metric {
+                Metric::Counter(_) => "counter",
+                Metric::Gauge(_) => "gauge",
+                Metric::Histogram(_) => "histogram",
+            };
+            let _ = writeln!(out, "# HELP {} {}", name, entry.help);
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            match &entry.metric {
+                Metric::Counter(counter) => {
+                    let _ = writeln!(out, "{} {}", name, counter.get());
+                }
+                Metric::Gauge(gauge) => {
+                    let _ = writeln!(out, "{} {}", name, gauge.get());
+                }
+                Metric::Histogram(histogram) => {
+                    for (bound, n) in histogram.cumulative() {
+                        let le = match bound.is_infinite() {
+                            true => "+Inf".to_string(),
+                            false => bound.to_string(),
+                        };
+                        let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, le, n);
+                    }
+                    let _ = writeln!(out, "{}_sum {}", name, histogram.sum());
+                    let _ = writeln!(out, "{}_count {}", name, histogram.count());
+                }
+            }
+        }
+        out
+    }
+
+    /// Writes every metric to the log on its own line, histograms as their
+    /// count and mean.
+    pub fn log_dump(&self) {
+        for entry in self.entries.lock().unwrap().iter() {
+            match &entry.metric {
+                Metric::Counter(counter) => info!("{} {}", entry.name, counter.get()),
+                Metric::Gauge(gauge) => info!("{} {}", entry.name, gauge.get()),
+                Metric::Histogram(histogram) => {
+                    let count = histogram.count();
+                    let mean = histogram.sum() / count.max(1) as f64;
+                    info!("{} count={} mean={:.6}", entry.name, count, mean);
+                }
+            }
+        }
+    }
+}
+
+/// Answers every HTTP request on the listener with the metrics of `registry`.
+fn serve_prometheus(listener: TcpListener, registry: &Registry) {
+    for stream in listener.incoming() {
+        let result = stream.and_then(|stream| respond(stream, registry));
+        if let Err(e) = result {
+            warn!("Metrics request failed: {}", e);
+        }
+    }
+}
+
+fn respond(stream: TcpStream, registry: &Registry) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+    // The request itself does not matter, but read the headers before answering
+    let mut reader = BufReader::new(&stream);
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 0 && line != "\r\n" && line != "\n" {
+        line.clear();
+    }
+
+    let body = registry.render_prometheus();
+    let mut stream = &stream;
+    write!(
+        stream,
+        "HTTP/1.1 200 OK\r\n\
+         Content-Type: text/plain; version=0.0.4\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\r\n{}",
+        body.len(),
+        body
+    )
+}
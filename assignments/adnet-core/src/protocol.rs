@@ -0,0 +1,102 @@
+use std::{fmt, net::SocketAddr, str::FromStr};
+
+use crate::error::AgentError;
+
+/// Longest response we accept from the agent
+pub const MAX_RESPONSE_LEN: usize = 256;
+
+/// Control messages understood by adnet-agent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    /// `TASK-CLI keyword`: the agent streams data and then closes the connection
+    Cli { keyword: String },
+    /// `TASK-SRV keyword ip:port`: the agent connects to our server at `server`
+    Srv { keyword: String, server: SocketAddr },
+    /// `TASK-UDP keyword`: the agent answers with the amount and the character
+    /// of data to send to its UDP port
+    Udp { keyword: String },
+}
+
+impl Command {
+    pub fn keyword(&self) -> &str {
+        match self {
+            Command::Cli { keyword } | Command::Srv { keyword, .. } | Command::Udp { keyword } => {
+                keyword
+            }
+        }
+    }
+
+    /// The message as written to the socket.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut message = self.to_string();
+        // TASK-UDP expects an answer, so the agent reads it up to the newline
+        if let Command::Udp { .. } = self {
+            message.push('\n');
+        }
+        message.into_bytes()
+    }
+}
+
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Command::Cli { keyword } => write!(f, "TASK-CLI {}", keyword),
+            Command::Srv { keyword, server } => write!(f, "TASK-SRV {} {}", keyword, server),
+            Command::Udp { keyword } => write!(f, "TASK-UDP {}", keyword),
+        }
+    }
+}
+
+/// The agent's `<size> <character>` answer to TASK-UDP.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UdpTask {
+    /// Number of bytes to send
+    pub size: usize,
+    /// Value of every payload byte
+    pub character: u8,
+}
+
+impl FromStr for UdpTask {
+    type Err = AgentError;
+
+    fn from_str(response: &str) -> Result<Self, Self::Err> {
+        let invalid = || AgentError::InvalidResponse(response.to_string());
+        let mut parts = response.split_whitespace();
+        let size = parts
+            .next()
+            .and_then(|s| s.parse().ok())
+            .ok_or_else(invalid)?;
+        let character = match parts.next().map(str::as_bytes) {
+            Some(&[c]) => c,
+            _ => return Err(invalid()),
+        };
+        if parts.next().is_some() {
+            return Err(invalid());
+        }
+        Ok(UdpTask { size, character })
+    }
+}
+
+/// Tries to parse the TASK-UDP response from what has been read so far.
+/// Returns None if more data is needed: the response may arrive in several
+/// segments, and the agent does not necessarily end it with a newline.
+pub(crate) fn parse_udp_task(buf: &[u8]) -> Option<Result<UdpTask, AgentError>> {
+    let text = match std::str::from_utf8(buf) {
+        Ok(text) => text,
+        // A multi-byte character may be split between reads
+        Err(e) if e.error_len().is_none() => return None,
+        Err(_) => {
+            let response = String::from_utf8_lossy(buf).into_owned();
+            return Some(Err(AgentError::InvalidResponse(response)));
+        }
+    };
+    // Once the second field has started, the first one is complete, and the
+    // second one is a single character
+    if text.contains('\n') || text.split_whitespace().count() >= 2 {
+        Some(text.parse())
+    } else if buf.len() >= MAX_RESPONSE_LEN {
+        Some(Err(AgentError::InvalidResponse(text.to_string())))
+    } else {
+        None
+    }
+}
@@ -0,0 +1,210 @@
+//! Round-trip time estimation for the protocols of the assignments. Besides
+//! the smoothed RTT and retransmission timeout of Jacobson/Karels, the
+//! estimator keeps the minimum RTT over a sliding window, as BBR and HyStart
+//! need, and the recent samples for the maximum and percentiles.
+
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+
+const INITIAL_RTO: Duration = Duration::from_secs(1);
+/// Bounds of the retransmission timeout, unless set with
+/// [`RttEstimator::with_rto_bounds`]
+pub const MIN_RTO: Duration = Duration::from_millis(200);
+pub const MAX_RTO: Duration = Duration::from_secs(10);
+/// Window of the minimum RTT and the kept samples, as in BBR
+pub const DEFAULT_WINDOW: Duration = Duration::from_secs(10);
+// Samples kept for the maximum and percentiles, however short the RTT
+const MAX_SAMPLES: usize = 1024;
+
+/// One RTT measurement.
+#[derive(Clone, Copy, Debug)]
+pub struct RttSample {
+    pub at: Instant,
+    pub rtt_ms: f64,
+}
+
+/// Summary of the estimator, e.g. for reports. The windowed values are
+/// `None` before the first sample.
+#[derive(Clone, Debug, Serialize)]
+pub struct RttStats {
+    pub samples: u64,
+    pub srtt_ms: Option<f64>,
+    pub rttvar_ms: Option<f64>,
+    pub rto_ms: f64,
+    pub min_ms: Option<f64>,
+    pub max_ms: Option<f64>,
+    pub p50_ms: Option<f64>,
+    pub p90_ms: Option<f64>,
+    pub p99_ms: Option<f64>,
+}
+
+pub struct RttEstimator {
+    srtt: f64,
+    rttvar: f64,
+    pub rto: Duration,
+    min_rto: Duration,
+    max_rto: Duration,
+    window: Duration,
+    count: u64,
+    // Samples of the window in the order they were taken
+    samples: VecDeque<RttSample>,
+    // Candidates for the windowed minimum, increasing in both time and RTT,
+    // so that the minimum is always at the front
+    minimums: VecDeque<RttSample>,
+}
+
+impl RttEstimator {
+    pub fn new() -> Self {
+        Self::with_window(DEFAULT_WINDOW)
+    }
+
+    /// Estimator whose minimum, maximum and percentiles cover the samples
+    /// of the last `window`.
+    pub fn with_window(window: Duration) -> Self {
+        Self {
+            srtt: 0.0,
+            rttvar: 0.0,
+            rto: INITIAL_RTO,
+            min_rto: MIN_RTO,
+            max_rto: MAX_RTO,
+            window,
+            count: 0,
+            samples: VecDeque::new(),
+            minimums: VecDeque::new(),
+        }
+    }
+
+    /// Keeps the retransmission timeout between `min` and `max` instead of
+    /// [`MIN_RTO`] and [`MAX_RTO`]. `min` must not be above `max`.
+    pub fn with_rto_bounds(mut self, min: Duration, max: Duration) -> Self {
+        self.min_rto = min;
+        self.max_rto = max;
+        self.rto = INITIAL_RTO.clamp(min, max);
+        self
+    }
+
+    pub fn update(&mut self, rtt_ms: f64) {
+        self.update_at(rtt_ms, Instant::now());
+    }
+
+    /// Adds a sample taken at `now`, which must not be earlier than the
+    /// previous one.
+    pub fn update_at(&mut self, rtt_ms: f64, now: Instant) {
+        if self.count == 0 {
+            // First measurement
+            self.srtt = rtt_ms;
+            self.rttvar = rtt_ms / 2.0;
+        } else {
+            // These formulas are shamelessly stolen from Jacobson/Karels algorithm: https://tcpcc.systemsapproach.org/algorithm.html
+            self.rttvar = 0.75 * self.rttvar + 0.25 * (self.srtt - rtt_ms).abs();
+            self.srtt = 0.875 * self.srtt + 0.125 * rtt_ms;
+        }
+        self.count += 1;
+        self.update_rto();
+
+        let sample = RttSample { at: now, rtt_ms };
+        while self
+            .minimums
+            .back()
+            .is_some_and(|last| last.rtt_ms >= rtt_ms)
+        {
+            self.minimums.pop_back();
+        }
+        self.minimums.push_back(sample);
+        self.samples.push_back(sample);
+        if self.samples.len() > MAX_SAMPLES {
+            self.samples.pop_front();
+        }
+        self.expire(now);
+    }
+
+    /// Drops the samples that have left the window ending at `now`.
+    fn expire(&mut self, now: Instant) {
+        let Some(start) = now.checked_sub(self.window) else {
+            return;
+        };
+        while self.samples.front().is_some_and(|s| s.at < start) {
+            self.samples.pop_front();
+        }
+        // The latest sample always stays, so there is a minimum after any sample
+        while self.minimums.len() > 1 && self.minimums[0].at < start {
+            self.minimums.pop_front();
+        }
+    }
+
+    /// Double RTO on timeout - idea also shamelessly stolen from Karn's algorithm https://tcpcc.systemsapproach.org/algorithm.html
+    pub fn backoff(&mut self) {
+        self.rto = (self.rto * 2).min(self.max_rto);
+    }
+
+    fn update_rto(&mut self) {
+        let rto_ms = (self.srtt + 4.0 * self.rttvar).clamp(
+            self.min_rto.as_millis() as f64,
+            self.max_rto.as_millis() as f64,
+        );
+        self.rto = Duration::from_millis(rto_ms as u64);
+    }
+
+    /// Number of samples since the start
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn srtt_ms(&self) -> Option<f64> {
+        (self.count > 0).then_some(self.srtt)
+    }
+
+    pub fn rttvar_ms(&self) -> Option<f64> {
+        (self.count > 0).then_some(self.rttvar)
+    }
+
+    /// Smallest RTT in the window that ends at the latest sample
+    pub fn min_rtt_ms(&self) -> Option<f64> {
+        self.minimums.front().map(|s| s.rtt_ms)
+    }
+
+    /// Largest RTT of the kept samples
+    pub fn max_rtt_ms(&self) -> Option<f64> {
+        self.samples.iter().map(|s| s.rtt_ms).reduce(f64::max)
+    }
+
+    /// The `p`th percentile (0-100) of the kept samples, by the nearest-rank method.
+    pub fn percentile_ms(&self, p: f64) -> Option<f64> {
+        let mut rtts: Vec<f64> = self.samples.iter().map(|s| s.rtt_ms).collect();
+        if rtts.is_empty() {
+            return None;
+        }
+        rtts.sort_by(f64::total_cmp);
+        let rank = (p.clamp(0.0, 100.0) / 100.0 * rtts.len() as f64).ceil() as usize;
+        Some(rtts[rank.saturating_sub(1)])
+    }
+
+    /// The samples in the window, oldest first. At most the latest 1024 are kept.
+    pub fn samples(&self) -> impl Iterator<Item = &RttSample> {
+        self.samples.iter()
+    }
+
+    pub fn stats(&self) -> RttStats {
+        RttStats {
+            samples: self.count,
+            srtt_ms: self.srtt_ms(),
+            rttvar_ms: self.rttvar_ms(),
+            rto_ms: self.rto.as_secs_f64() * 1000.0,
+            min_ms: self.min_rtt_ms(),
+            max_ms: self.max_rtt_ms(),
+            p50_ms: self.percentile_ms(50.0),
+            p90_ms: self.percentile_ms(90.0),
+            p99_ms: self.percentile_ms(99.0),
+        }
+    }
+}
+
+impl Default for RttEstimator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,343 @@
+//! Graceful shutdown for the long-running programs. [`install`] turns SIGINT
+//! and SIGTERM into a request on the returned [`Shutdown`], which the main
+//! loops of a program poll with [`Shutdown::is_requested`] or wait on, and a
+//! second signal exits at once. Work still in flight when the request comes
+//! is tracked with [`Shutdown::track`] and waited for with
+//! [`Shutdown::drain`], and the hooks added with [`Shutdown::on_exit`] print
+//! the final summary when the program calls [`Shutdown::finish`]. Programs
+//! that can reload their configuration add hooks for SIGHUP with
+//! [`Shutdown::on_hangup`]. Programs that also stop on their own after
+//! `--count` rounds or `--duration` check [`StopAfter::reached`].
+//!
+//! ```no_run
+//! # use adnet_core::shutdown;
+//! let shutdown = shutdown::install()?;
+//! shutdown.on_exit(|| println!("Done"));
+//! while !shutdown.is_requested() {
+//!     // Serve until Ctrl-C
+//!     # break;
+//! }
+//! shutdown.drain(shutdown::DEFAULT_DRAIN_TIMEOUT);
+//! shutdown.finish();
+//! # Ok::<(), std::io::Error>(())
+//! ```
+
+use std::{
+    fs::File,
+    io::{self, Read},
+    os::fd::FromRawFd,
+    process,
+    sync::{
+        atomic::{AtomicBool, AtomicI32, Ordering},
+        Arc, Condvar, Mutex, MutexGuard,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use tracing::{info, warn};
+
+/// How long the programs wait for work in flight after a shutdown request
+pub const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+type Hook = Box<dyn FnOnce() + Send>;
+type HangupHook = Arc<dyn Fn() + Send + Sync>;
+
+/// Shutdown request shared by the parts of a program. Clones refer to the
+/// same request.
+#[derive(Clone, Default)]
+pub struct Shutdown(Arc<Inner>);
+
+#[derive(Default)]
+struct Inner {
+    requested: AtomicBool,
+    state: Mutex<State>,
+    // Signalled on the request and whenever tracked work ends
+    changed: Condvar,
+    #[cfg(feature = "tokio")]
+    notify: tokio::sync::Notify,
+}
+
+#[derive(Default)]
+struct State {
+    in_flight: usize,
+    on_request: Vec<Hook>,
+    on_exit: Vec<Hook>,
+    on_hangup: Vec<HangupHook>,
+}
+
+impl Shutdown {
+    /// A request that only [`Shutdown::request`] triggers, e.g. for tests or
+    /// programs run as part of another.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.0.state.lock().unwrap()
+    }
+
+    /// Requests the shutdown and runs the hooks of [`Shutdown::on_request`].
+    /// Requests after the first do nothing.
+    pub fn request(&self) {
+        if self.0.requested.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let hooks = std::mem::take(&mut self.state().on_request);
+        self.notify();
+        for hook in hooks {
+            hook();
+        }
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.0.requested.load(Ordering::SeqCst)
+    }
+
+    /// Sleeps until the shutdown is requested or the timeout passes, and
+    /// returns whether it was requested. A replacement for `thread::sleep`
+    /// in loops that should stop promptly.
+    pub fn wait_timeout(&self, timeout: Duration) -> bool {
+        let state = self.state();
+        let _state = self
+            .0
+            .changed
+            .wait_timeout_while(state, timeout, |_| !self.is_requested())
+            .unwrap();
+        self.is_requested()
+    }
+
+    /// Runs `hook` when the shutdown is requested, in the thread that
+    /// requests it, or right away if it already has been. Meant for waking
+    /// up loops that cannot wait on the request otherwise, such as a mio
+    /// poll.
+    pub fn on_request(&self, hook: impl FnOnce() + Send + 'static) {
+        let mut state = self.state();
+        if self.is_requested() {
+            drop(state);
+            hook();
+        } else {
+            state.on_request.push(Box::new(hook));
+        }
+    }
+
+    /// Adds a hook for [`Shutdown::finish`], typically one that prints the
+    /// final statistics of the program.
+    pub fn on_exit(&self, hook: impl FnOnce() + Send + 'static) {
+        self.state().on_exit.push(Box::new(hook));
+    }
+
+    /// Runs `hook` on every SIGHUP, in the signal watcher thread, typically
+    /// one that tells the main loop to reload a file. The SIGHUP handler is
+    /// installed with the first hook, if [`install`] has installed the
+    /// others, so that programs without hooks still exit on SIGHUP.
+    pub fn on_hangup(&self, hook: impl Fn() + Send + Sync + 'static) -> io::Result<()> {
+        let mut state = self.state();
+        if state.on_hangup.is_empty() && SIGNAL_PIPE.load(Ordering::SeqCst) >= 0 {
+            set_handler(libc::SIGHUP)?;
+        }
+        state.on_hangup.push(Arc::new(hook));
+        Ok(())
+    }
+
+    /// Runs the hooks of [`Shutdown::on_hangup`], as a SIGHUP does.
+    pub fn hangup(&self) {
+        let hooks = self.state().on_hangup.clone();
+        for hook in hooks {
+            hook();
+        }
+    }
+
+    /// Runs the hooks of [`Shutdown::on_exit`] in the order they were added.
+    /// Each hook runs once, however often this is called.
+    pub fn finish(&self) {
+        let hooks = std::mem::take(&mut self.state().on_exit);
+        for hook in hooks {
+            hook();
+        }
+    }
+
+    /// Counts a piece of work, such as a client connection, as in flight
+    /// until the returned guard is dropped.
+    pub fn track(&self) -> InFlight {
+        self.state().in_flight += 1;
+        InFlight(self.clone())
+    }
+
+    /// Number of tracked pieces of work in flight
+    pub fn in_flight(&self) -> usize {
+        self.state().in_flight
+    }
+
+    /// Waits up to `timeout` for the tracked work to end, and returns whether
+    /// it did.
+    pub fn drain(&self, timeout: Duration) -> bool {
+        let state = self.state();
+        let (state, _) = self
+            .0
+            .changed
+            .wait_timeout_while(state, timeout, |state| state.in_flight > 0)
+            .unwrap();
+        drained(state.in_flight, timeout)
+    }
+
+    /// Waits until the shutdown is requested.
+    #[cfg(feature = "tokio")]
+    pub async fn requested(&self) {
+        self.wait_async(|| self.is_requested()).await
+    }
+
+    /// Like [`Shutdown::drain`], without blocking the runtime.
+    #[cfg(feature = "tokio")]
+    pub async fn drain_async(&self, timeout: Duration) -> bool {
+        let idle = self.wait_async(|| self.in_flight() == 0);
+        let _ = tokio::time::timeout(timeout, idle).await;
+        drained(self.in_flight(), timeout)
+    }
+
+    #[cfg(feature = "tokio")]
+    async fn wait_async(&self, done: impl Fn() -> bool) {
+        loop {
+            // Registered before the check, so that no notification is missed
+            let mut notified = std::pin::pin!(self.0.notify.notified());
+            notified.as_mut().enable();
+            if done() {
+                return;
+            }
+            notified.await;
+        }
+    }
+
+    fn notify(&self) {
+        self.0.changed.notify_all();
+        #[cfg(feature = "tokio")]
+        self.0.notify.notify_waiters();
+    }
+}
+
+fn drained(in_flight: usize, timeout: Duration) -> bool {
+    if in_flight > 0 {
+        warn!(
+            "{} still in flight after {:?}, exiting anyway",
+            in_flight, timeout
+        );
+    }
+    in_flight == 0
+}
+
+/// Guard of [`Shutdown::track`].
+pub struct InFlight(Shutdown);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.state().in_flight -= 1;
+        self.0.notify();
+    }
+}
+
+/// The `--count` and `--duration` limits of a program, e.g. of the packets
+/// it captures or sends. Either may be absent, and the first reached wins.
+#[derive(Debug, Clone, Copy)]
+pub struct StopAfter {
+    count: Option<u64>,
+    deadline: Option<Instant>,
+}
+
+impl StopAfter {
+    /// Limits counted from now.
+    pub fn new(count: Option<u64>, duration: Option<Duration>) -> Self {
+        Self {
+            count,
+            deadline: duration.map(|duration| Instant::now() + duration),
+        }
+    }
+
+    /// Whether the program should stop, after `done` rounds.
+    pub fn reached(&self, done: u64) -> bool {
+        self.count.is_some_and(|count| done >= count)
+            || self
+                .deadline
+                .is_some_and(|deadline| Instant::now() >= deadline)
+    }
+}
+
+// Write end of the pipe that the signal handler reports signals to
+static SIGNAL_PIPE: AtomicI32 = AtomicI32::new(-1);
+// The request of the installed handlers
+static INSTALLED: Mutex<Option<Shutdown>> = Mutex::new(None);
+
+extern "C" fn on_signal(signal: libc::c_int) {
+    // Only async-signal-safe calls here: the watcher thread does the rest
+    unsafe {
+        let errno = *libc::__errno_location();
+        let byte = signal as u8;
+        libc::write(
+            SIGNAL_PIPE.load(Ordering::SeqCst),
+            &byte as *const u8 as *const libc::c_void,
+            1,
+        );
+        *libc::__errno_location() = errno;
+    }
+}
+
+/// Installs the SIGINT and SIGTERM handlers, and returns the request they
+/// trigger. The first signal requests the shutdown, and the second exits
+/// the program with 128 plus the signal number, as shells do. Later calls
+/// return the same request.
+pub fn install() -> io::Result<Shutdown> {
+    let mut installed = INSTALLED.lock().unwrap();
+    if let Some(shutdown) = &*installed {
+        return Ok(shutdown.clone());
+    }
+
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let reader = unsafe { File::from_raw_fd(fds[0]) };
+    SIGNAL_PIPE.store(fds[1], Ordering::SeqCst);
+
+    let shutdown = Shutdown::new();
+    let watcher = shutdown.clone();
+    thread::Builder::new()
+        .name("shutdown".to_string())
+        .spawn(move || watch(reader, watcher))?;
+    for signal in [libc::SIGINT, libc::SIGTERM] {
+        set_handler(signal)?;
+    }
+
+    *installed = Some(shutdown.clone());
+    Ok(shutdown)
+}
+
+fn set_handler(signal: libc::c_int) -> io::Result<()> {
+    let handler = on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    if unsafe { libc::signal(signal, handler) } == libc::SIG_ERR {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Turns the signals written to the pipe into the shutdown request, or the
+/// hangup hooks.
+fn watch(mut reader: File, shutdown: Shutdown) {
+    let mut byte = [0u8; 1];
+    while reader.read_exact(&mut byte).is_ok() {
+        let signal = byte[0] as libc::c_int;
+        if signal == libc::SIGHUP {
+            info!("SIGHUP, reloading");
+            shutdown.hangup();
+            continue;
+        }
+        let name = match signal {
+            libc::SIGINT => "SIGINT",
+            _ => "SIGTERM",
+        };
+        if shutdown.is_requested() {
+            warn!("{} again, exiting immediately", name);
+            process::exit(128 + signal);
+        }
+        info!("{}, shutting down (again to exit immediately)", name);
+        shutdown.request();
+    }
+}
@@ -0,0 +1,220 @@
+//! Live dashboard of the running assignment programs. Each program started
+//! with `--metrics-listen ADDR` serves its metrics over HTTP, and adnet-top
+//! polls those addresses and shows the throughput, connections, drops,
+//! errors and health of all of them on one screen, followed by each
+//! program's own metrics and their rates.
+//!
+//! The `adnet top` subcommand is a thin wrapper around [`run`].
+
+pub mod scrape;
+pub mod view;
+
+use std::{
+    collections::HashMap,
+    error::Error,
+    io::{self, Write},
+    time::{Duration, Instant},
+};
+
+use adnet_core::{
+    config::{self, ConfigArgs},
+    logging::{self, LogArgs},
+    shutdown,
+};
+use clap::Parser;
+use scrape::{Kind, Metric};
+use serde::Deserialize;
+use tracing::debug;
+
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(1);
+// Longest a scrape may take, so that one stuck program does not stall the screen
+const SCRAPE_TIMEOUT: Duration = Duration::from_secs(1);
+
+// Alternate screen without cursor, and back
+const ENTER_SCREEN: &str = "\x1b[?1049h\x1b[?25l";
+const LEAVE_SCREEN: &str = "\x1b[?25h\x1b[?1049l";
+const CLEAR_SCREEN: &str = "\x1b[H\x1b[2J";
+
+/// Shows the live metrics of running assignment programs on one screen.
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+pub struct Args {
+    /// Program to watch as [NAME=]ADDR, where ADDR is its --metrics-listen
+    /// address; can be repeated
+    #[arg(short, long = "target", value_name = "[NAME=]ADDR", value_parser = parse_target)]
+    targets: Vec<Target>,
+
+    /// Seconds between updates [default: 1]
+    #[arg(short, long, value_parser = config::parse_secs)]
+    interval: Option<Duration>,
+
+    /// Show only the summary line of each program
+    #[arg(short, long)]
+    summary: bool,
+
+    /// Print one update and exit instead of redrawing the screen
+    #[arg(long)]
+    once: bool,
+
+    #[command(flatten)]
+    config: ConfigArgs,
+
+    #[command(flatten)]
+    log: LogArgs,
+}
+
+/// The [adnet-top] section of the config file
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct FileConfig {
+    /// As on the command line, [NAME=]ADDR
+    targets: Vec<String>,
+    #[serde(deserialize_with = "config::secs")]
+    interval: Option<Duration>,
+    summary: bool,
+}
+
+/// A program to watch
+#[derive(Clone, Debug)]
+pub struct Target {
+    pub name: String,
+    pub address: String,
+}
+
+fn parse_target(s: &str) -> Result<Target, String> {
+    let (name, address) = match s.split_once('=') {
+        Some((name, address)) => (name, address),
+        None => (s, s),
+    };
+    if name.is_empty() || address.is_empty() {
+        return Err(format!("Expected [NAME=]ADDR, got {:?}", s));
+    }
+    Ok(Target {
+        name: name.to_string(),
+        address: address.to_string(),
+    })
+}
+
+/// Result of the latest scrape of a program
+pub enum Health {
+    /// Not scraped yet
+    Unknown,
+    /// Answered in the given time
+    Up(Duration),
+    /// Did not answer, with the reason
+    Down(String),
+}
+
+/// What is known of one watched program.
+pub struct Watch {
+    pub target: Target,
+    pub health: Health,
+    /// Metrics of the latest successful scrape
+    pub metrics: Vec<Metric>,
+    /// Per-second rates of the counters between the last two successful
+    /// scrapes. Counters that went down, e.g. after a restart, have none.
+    pub rates: HashMap<String, f64>,
+    /// Time of the latest successful scrape
+    pub seen: Option<Instant>,
+}
+
+impl Watch {
+    fn new(target: Target) -> Self {
+        Watch {
+            target,
+            health: Health::Unknown,
+            metrics: Vec::new(),
+            rates: HashMap::new(),
+            seen: None,
+        }
+    }
+
+    /// Scrapes the program and updates the metrics and rates.
+    fn update(&mut self, timeout: Duration) {
+        let start = Instant::now();
+        let body = match scrape::fetch(&self.target.address, timeout) {
+            Ok(body) => body,
+            Err(e) => {
+                debug!("Scraping {} failed: {}", self.target.address, e);
+                self.health = Health::Down(e.to_string());
+                return;
+            }
+        };
+        let now = Instant::now();
+        self.health = Health::Up(now - start);
+
+        let metrics = scrape::parse(&body);
+        self.rates.clear();
+        if let Some(seen) = self.seen {
+            let secs = (now - seen).as_secs_f64();
+            for metric in metrics.iter().filter(|m| m.kind != Kind::Gauge) {
+                let previous = self.metrics.iter().find(|m| m.name == metric.name);
+                if let Some(previous) = previous.filter(|p| p.value <= metric.value) {
+                    let rate = (metric.value - previous.value) / secs;
+                    self.rates.insert(metric.name.clone(), rate);
+                }
+            }
+        }
+        self.metrics = metrics;
+        self.seen = Some(now);
+    }
+
+    pub fn rate(&self, name: &str) -> Option<f64> {
+        self.rates.get(name).copied()
+    }
+}
+
+/// Watches the programs with the given arguments, as `adnet top` does.
+pub fn run(args: Args) -> Result<(), Box<dyn Error>> {
+    logging::init(&args.log)?;
+    // Command line options take precedence over the config file
+    let file: FileConfig = args.config.section("adnet-top")?;
+    let targets = if args.targets.is_empty() {
+        file.targets
+            .iter()
+            .map(|s| parse_target(s))
+            .collect::<Result<Vec<_>, _>>()?
+    } else {
+        args.targets
+    };
+    if targets.is_empty() {
+        return Err("No programs to watch, give them with --target".into());
+    }
+    let interval = args.interval.or(file.interval).unwrap_or(DEFAULT_INTERVAL);
+    let summary = args.summary || file.summary;
+    let timeout = SCRAPE_TIMEOUT.min(interval).max(Duration::from_millis(100));
+
+    let shutdown = shutdown::install()?;
+    let mut watches: Vec<Watch> = targets.into_iter().map(Watch::new).collect();
+    let update = |watches: &mut [Watch]| {
+        for watch in watches {
+            watch.update(timeout);
+        }
+    };
+
+    let mut out = io::stdout().lock();
+    if args.once {
+        // Rates need two scrapes
+        update(&mut watches);
+        if !shutdown.wait_timeout(interval) {
+            update(&mut watches);
+        }
+        write!(out, "{}", view::render(&watches, summary))?;
+        return Ok(());
+    }
+
+    write!(out, "{}", ENTER_SCREEN)?;
+    let result = (|| -> io::Result<()> {
+        while !shutdown.is_requested() {
+            update(&mut watches);
+            let screen = view::render(&watches, summary);
+            write!(out, "{}{}", CLEAR_SCREEN, screen)?;
+            out.flush()?;
+            shutdown.wait_timeout(interval);
+        }
+        Ok(())
+    })();
+    write!(out, "{}", LEAVE_SCREEN)?;
+    out.flush()?;
+    Ok(result?)
+}
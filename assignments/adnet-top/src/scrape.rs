@@ -0,0 +1,129 @@
+//! Fetching the metrics of a program from its `--metrics-listen` address,
+//! and parsing the Prometheus text format that adnet-core serves there.
+
+use std::{
+    io::{self, Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    time::Duration,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    Counter,
+    Gauge,
+    Histogram,
+    /// Without a TYPE line, e.g. from a program other than the assignment ones
+    Untyped,
+}
+
+/// One metric of a program. Histograms are reduced to their count and sum.
+#[derive(Clone, Debug)]
+pub struct Metric {
+    pub name: String,
+    pub kind: Kind,
+    /// The value, or the number of observations of a histogram
+    pub value: f64,
+    /// Sum of the observations of a histogram
+    pub sum: Option<f64>,
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Gets the metrics page at `address` over HTTP and returns its body.
+pub fn fetch(address: &str, timeout: Duration) -> io::Result<String> {
+    let addr = address.to_socket_addrs()?.next().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} did not resolve to any address", address),
+        )
+    })?;
+    let mut stream = TcpStream::connect_timeout(&addr, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    write!(
+        stream,
+        "GET /metrics HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+        address
+    )?;
+
+    // The server closes the connection after the response
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    let (head, body) = response
+        .split_once("\r\n\r\n")
+        .ok_or_else(|| invalid("Incomplete HTTP response".to_string()))?;
+    let status = head.lines().next().unwrap_or_default();
+    if status.split_whitespace().nth(1) != Some("200") {
+        return Err(invalid(format!("Unexpected answer: {}", status)));
+    }
+    Ok(body.to_string())
+}
+
+/// Parses the metrics in the order they appear. Samples with labels, such as
+/// the buckets of histograms, are skipped.
+pub fn parse(text: &str) -> Vec<Metric> {
+    let mut metrics: Vec<Metric> = Vec::new();
+    let mut kinds: Vec<(String, Kind)> = Vec::new();
+    for line in text.lines() {
+        let mut words = line.split_whitespace();
+        match (words.next(), words.next(), words.next()) {
+            (Some("#"), Some("TYPE"), Some(name)) => {
+                let kind = match words.next() {
+                    Some("counter") => Kind::Counter,
+                    Some("gauge") => Kind::Gauge,
+                    Some("histogram") => Kind::Histogram,
+                    _ => Kind::Untyped,
+                };
+                kinds.push((name.to_string(), kind));
+            }
+            (Some(name), Some(value), _) if !name.starts_with('#') && !name.contains('{') => {
+                let Ok(value) = value.parse::<f64>() else {
+                    continue;
+                };
+                add(&mut metrics, &kinds, name, value);
+            }
+            _ => {}
+        }
+    }
+    metrics
+}
+
+/// Adds a sample, folding the count and sum of a histogram into one metric.
+fn add(metrics: &mut Vec<Metric>, kinds: &[(String, Kind)], name: &str, value: f64) {
+    let histogram = |suffix| {
+        let base = name.strip_suffix(suffix)?;
+        kinds
+            .iter()
+            .any(|(name, kind)| name == base && *kind == Kind::Histogram)
+            .then_some(base)
+    };
+    let (name, part) = match (histogram("_count"), histogram("_sum")) {
+        (Some(base), _) => (base, Some(false)),
+        (_, Some(base)) => (base, Some(true)),
+        _ => (name, None),
+    };
+
+    let index = match metrics.iter().position(|m| m.name == name) {
+        Some(index) => index,
+        None => {
+            let kind = kinds
+                .iter()
+                .find(|(known, _)| known == name)
+                .map_or(Kind::Untyped, |(_, kind)| *kind);
+            metrics.push(Metric {
+                name: name.to_string(),
+                kind,
+                value: 0.0,
+                sum: None,
+            });
+            metrics.len() - 1
+        }
+    };
+    let metric = &mut metrics[index];
+    match part {
+        Some(true) => metric.sum = Some(value),
+        _ => metric.value = value,
+    }
+}
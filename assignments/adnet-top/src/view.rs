@@ -0,0 +1,149 @@
+//! Text of the dashboard: a summary line per program, and below it the
+//! program's own metrics. The summary picks its columns by the naming
+//! conventions of the assignment programs' metrics, so that new metrics show
+//! up there without changes here.
+
+use std::fmt::Write;
+
+use crate::{
+    scrape::{Kind, Metric},
+    Health, Watch,
+};
+
+// Words in the names of counters that count failures
+const ERROR_WORDS: [&str; 4] = ["error", "fail", "timeout", "refused"];
+
+fn is_bytes(metric: &Metric) -> bool {
+    metric.kind == Kind::Counter && metric.name.contains("bytes")
+}
+
+fn is_connections(metric: &Metric) -> bool {
+    metric.kind == Kind::Gauge && metric.name.ends_with("active_connections")
+}
+
+fn is_drops(metric: &Metric) -> bool {
+    metric.kind == Kind::Counter && metric.name.contains("dropped")
+}
+
+fn is_errors(metric: &Metric) -> bool {
+    metric.kind == Kind::Counter && ERROR_WORDS.iter().any(|w| metric.name.contains(w))
+}
+
+/// Sum of the matching metrics, or `None` if the program has none.
+fn total(watch: &Watch, matches: fn(&Metric) -> bool) -> Option<f64> {
+    let mut metrics = watch.metrics.iter().filter(|m| matches(m)).peekable();
+    metrics.peek()?;
+    Some(metrics.map(|m| m.value).sum())
+}
+
+/// Sum of the rates of the matching metrics, or `None` before there are any.
+fn total_rate(watch: &Watch, matches: fn(&Metric) -> bool) -> Option<f64> {
+    let mut rates = watch
+        .metrics
+        .iter()
+        .filter(|m| matches(m))
+        .filter_map(|m| watch.rate(&m.name))
+        .peekable();
+    rates.peek()?;
+    Some(rates.sum())
+}
+
+/// Bytes per second with a decimal unit, e.g. "1.2 MB/s"
+pub fn throughput(bytes_per_sec: f64) -> String {
+    let units = ["B/s", "kB/s", "MB/s", "GB/s"];
+    let mut value = bytes_per_sec;
+    let mut unit = 0;
+    while value >= 1000.0 && unit < units.len() - 1 {
+        value /= 1000.0;
+        unit += 1;
+    }
+    match unit {
+        0 => format!("{:.0} {}", value, units[unit]),
+        _ => format!("{:.1} {}", value, units[unit]),
+    }
+}
+
+/// A value without decimals when it is whole, as counters are
+fn number(value: f64) -> String {
+    match value.fract() == 0.0 && value.abs() < 1e15 {
+        true => format!("{}", value as i64),
+        false => format!("{:.3}", value),
+    }
+}
+
+fn or_dash(value: Option<String>) -> String {
+    value.unwrap_or_else(|| "-".to_string())
+}
+
+fn health(watch: &Watch) -> String {
+    match &watch.health {
+        Health::Unknown => "...".to_string(),
+        Health::Up(latency) => format!("UP {}ms", latency.as_millis()),
+        Health::Down(error) => match watch.seen {
+            Some(seen) => format!("DOWN {}s: {}", seen.elapsed().as_secs(), error),
+            None => format!("DOWN: {}", error),
+        },
+    }
+}
+
+/// The whole screen, ending with a newline.
+pub fn render(watches: &[Watch], summary: bool) -> String {
+    let width = watches
+        .iter()
+        .map(|w| w.target.name.len())
+        .max()
+        .unwrap_or(0)
+        .max(4);
+    let mut out = String::new();
+    let header = format!(
+        "{:<width$}  {:>12}  {:>6}  {:>10}  {:>10}  HEALTH",
+        "NAME", "THROUGHPUT", "CONNS", "DROPS", "ERRORS"
+    );
+    let _ = writeln!(out, "{}", header);
+    for watch in watches {
+        let _ = writeln!(
+            out,
+            "{:<width$}  {:>12}  {:>6}  {:>10}  {:>10}  {}",
+            watch.target.name,
+            or_dash(total_rate(watch, is_bytes).map(throughput)),
+            or_dash(total(watch, is_connections).map(number)),
+            or_dash(total(watch, is_drops).map(number)),
+            or_dash(total(watch, is_errors).map(number)),
+            health(watch),
+        );
+    }
+    if summary {
+        return out;
+    }
+
+    for watch in watches.iter().filter(|w| !w.metrics.is_empty()) {
+        let _ = writeln!(out, "\n{} ({})", watch.target.name, watch.target.address);
+        let width = watch
+            .metrics
+            .iter()
+            .map(|m| m.name.len())
+            .max()
+            .unwrap_or(0);
+        for metric in &watch.metrics {
+            let value = match (metric.kind, metric.sum) {
+                // Count and mean of the observations
+                (Kind::Histogram, Some(sum)) if metric.value > 0.0 => format!(
+                    "{} (mean {})",
+                    number(metric.value),
+                    number(sum / metric.value)
+                ),
+                _ => number(metric.value),
+            };
+            let rate = match watch.rate(&metric.name) {
+                Some(rate) => format!("{:.1}/s", rate),
+                None => String::new(),
+            };
+            let _ = writeln!(
+                out,
+                "  {:<width$}  {:>24}  {:>12}",
+                metric.name, value, rate
+            );
+        }
+    }
+    out
+}
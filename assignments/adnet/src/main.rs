@@ -0,0 +1,205 @@
+//! One binary for all assignment programs, so that a machine needs only one
+//! installed artifact. Every subcommand takes the same options as the program
+//! it replaces, including the shared `--config`, `--report` and logging
+//! options:
+//!
+//! ```text
+//! adnet udp-send --server 10.0.0.3 --keyword secret --report udp.json
+//! adnet tun --config adnet.toml
+//! adnet tun-ctl --control tun0.ctl stats
+//! adnet srv --port 2000 --keyword secret
+//! adnet cli --keyword secret task-cli --verify
+//! adnet ping 10.0.0.3 --count 10
+//! adnet trace 10.0.0.3 --mode icmp --json
+//! adnet dns example.com --type a --type aaaa
+//! adnet quic client --server 10.0.0.3 --keyword secret --insecure
+//! adnet socks --listen 0.0.0.0:1080 --username user --password secret
+//! adnet fwd --listen 0.0.0.0:8080 --target 10.100.0.2:2000 --interface tun0
+//! adnet nat --address 10.100.0.1 --public 10.200.0.2 --uplink-address 10.200.0.1
+//! adnet perf client --server 10.0.0.3 --udp --streams 4 --bitrate 10
+//! adnet scan 10.0.0.3 --ports 1-1024 --rate 500 --json
+//! adnet time client --server 10.0.0.3 --count 8
+//! adnet mcast receive --group 239.1.2.3:5000 --duration 30
+//! adnet tftp get --server 10.0.0.3 boot/kernel.img
+//! adnet ws client --url ws://10.0.0.3:9001/ --count 10 --ping
+//! adnet arp --interface eth0 --json
+//! adnet stun client --server stun.l.google.com:19302 --server stun1.l.google.com:19302 --bind 0.0.0.0:5000
+//! adnet dhcp --interface veth0 --offers
+//! adnet mdns --service _http._tcp --duration 5
+//! adnet capture --interface veth0 --filter "tcp and port 80" --write http.pcap
+//! adnet gen 192.168.76.1 --interface veth1 --protocol tcp --port 80 --count 10
+//! adnet ebpf --iface veth0 --metrics-listen 127.0.0.1:9103
+//! adnet top --target tun=127.0.0.1:9101 --target srv=127.0.0.1:9102 --target ebpf=127.0.0.1:9103
+//! ```
+
+use std::{
+    env,
+    error::Error,
+    ffi::OsString,
+    fmt::Display,
+    future::Future,
+    path::PathBuf,
+    process::{self, Command, ExitCode},
+};
+
+use adnet_core::exit::{self, HasCode};
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand};
+
+/// The eBPF loader builds with a nightly toolchain in its own workspace, so it
+/// cannot be linked in and is run as a separate program instead.
+const EBPF_BINARY: &str = "task-ebpf";
+
+/// Advanced Networking assignment programs.
+#[derive(Parser, Debug)]
+#[command(name = "adnet", version, about, long_about = None)]
+struct Adnet {
+    #[command(subcommand)]
+    tool: Tool,
+}
+
+#[derive(Subcommand, Debug)]
+enum Tool {
+    /// Send the data requested by adnet-agent reliably over UDP (task-udp)
+    UdpSend(task_udp::Args),
+
+    /// IP tunnel over UDP between two TUN devices (task-tun)
+    Tun(task_tun::Args),
+
+    /// Send a command to the control socket of a running tunnel (task-tun-ctl)
+    TunCtl(task_tun::control::CtlArgs),
+
+    /// TCP server that answers the requests of adnet-agent (task-srv)
+    Srv(task_srv::Args),
+
+    /// Control interactions with adnet-agent (adnet-cli)
+    Cli(task_cli::Cli),
+
+    /// Send ICMP echo requests and report RTT, loss and jitter (task-ping)
+    Ping(task_ping::Args),
+
+    /// Print the route to a host with UDP or ICMP probes (task-trace)
+    Trace(task_trace::Args),
+
+    /// Look up DNS records and measure resolver latency (task-dns)
+    Dns(task_dns::Args),
+
+    /// Keyword and bulk transfer over QUIC, as server or client (task-quic)
+    Quic(task_quic::Args),
+
+    /// SOCKS5 proxy with optional username/password authentication (task-socks)
+    Socks(task_socks::Args),
+
+    /// Forward TCP connections to a target, optionally through a tunnel (task-fwd)
+    Fwd(task_fwd::Args),
+
+    /// Source NAT from a TUN device to a second device or a UDP tunnel (task-nat)
+    Nat(task_nat::Args),
+
+    /// Measure TCP and UDP throughput, loss and jitter, as server or client (task-perf)
+    Perf(task_perf::Args),
+
+    /// Scan TCP or UDP ports for open, closed and filtered ones (task-scan)
+    Scan(task_scan::Args),
+
+    /// Measure clock offset and path delay against a peer or NTP server (task-time)
+    Time(task_time::Args),
+
+    /// Send and receive sequenced datagrams over IP multicast (task-mcast)
+    Mcast(task_mcast::Args),
+
+    /// Transfer files with TFTP, as client or server (task-tftp)
+    Tftp(task_tftp::Args),
+
+    /// WebSocket echo server and latency client (task-ws)
+    Ws(task_ws::Args),
+
+    /// Watch ARP traffic and alert on spoofed mappings and storms (task-arp)
+    Arp(task_arp::Args),
+
+    /// Discover the public address of a UDP socket with STUN (task-stun)
+    Stun(task_stun::Args),
+
+    /// Get a DHCP lease on an interface and print it (task-dhcp)
+    Dhcp(task_dhcp::Args),
+
+    /// List the services announced on the local network with mDNS (task-mdns)
+    Mdns(task_mdns::Args),
+
+    /// Capture packets on an interface to the terminal or a pcap file (pktcap)
+    Capture(pktcap::Args),
+
+    /// Send crafted UDP, TCP or ICMP packets on an interface at a given rate (pktgen)
+    Gen(pktgen::Args),
+
+    /// Live dashboard of the metrics of running programs (adnet-top)
+    Top(adnet_top::Args),
+
+    /// Count and filter packets with XDP (task-ebpf, installed separately)
+    #[command(disable_help_flag = true)]
+    Ebpf {
+        /// Arguments passed on to task-ebpf
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+        args: Vec<OsString>,
+    },
+}
+
+fn main() -> ExitCode {
+    let matches = Adnet::command().get_matches();
+    let adnet = Adnet::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+
+    match adnet.tool {
+        Tool::UdpSend(args) => block_on(task_udp::run(args)),
+        Tool::Tun(args) => exit::exit(task_tun::run(args)),
+        Tool::TunCtl(args) => exit::exit(task_tun::control::run(args)),
+        Tool::Srv(args) => block_on(task_srv::run(args)),
+        Tool::Cli(cli) => {
+            let (_, matches) = matches.subcommand().expect("subcommand is required");
+            exit::exit(task_cli::run(cli, matches))
+        }
+        Tool::Ping(args) => exit::exit(task_ping::run(args)),
+        Tool::Trace(args) => exit::exit(task_trace::run(args)),
+        Tool::Dns(args) => exit::exit(task_dns::run(args)),
+        Tool::Quic(args) => block_on(task_quic::run(args)),
+        Tool::Socks(args) => block_on(task_socks::run(args)),
+        Tool::Fwd(args) => block_on(task_fwd::run(args)),
+        Tool::Nat(args) => exit::exit(task_nat::run(args)),
+        Tool::Perf(args) => exit::exit(task_perf::run(args)),
+        Tool::Scan(args) => block_on(task_scan::run(args)),
+        Tool::Time(args) => exit::exit(task_time::run(args)),
+        Tool::Mcast(args) => exit::exit(task_mcast::run(args)),
+        Tool::Tftp(args) => exit::exit(task_tftp::run(args)),
+        Tool::Ws(args) => block_on(task_ws::run(args)),
+        Tool::Arp(args) => exit::exit(task_arp::run(args)),
+        Tool::Stun(args) => exit::exit(task_stun::run(args)),
+        Tool::Dhcp(args) => exit::exit(task_dhcp::run(args)),
+        Tool::Mdns(args) => exit::exit(task_mdns::run(args)),
+        Tool::Capture(args) => exit::exit(pktcap::run(args)),
+        Tool::Gen(args) => exit::exit(pktgen::run(args)),
+        Tool::Top(args) => exit::exit(adnet_top::run(args)),
+        Tool::Ebpf { args } => exit::exit(run_ebpf(args)),
+    }
+}
+
+/// Runs an async program on a runtime of its own.
+fn block_on<E: Display + HasCode>(program: impl Future<Output = Result<(), E>>) -> ExitCode {
+    match tokio::runtime::Runtime::new() {
+        Ok(runtime) => exit::exit(runtime.block_on(program)),
+        Err(e) => exit::exit(Err(e)),
+    }
+}
+
+/// Runs task-ebpf from the directory of this binary, or else from PATH, and
+/// exits with its exit status.
+fn run_ebpf(args: Vec<OsString>) -> Result<(), Box<dyn Error>> {
+    let program = env::current_exe()
+        .ok()
+        .and_then(|exe| Some(exe.parent()?.join(EBPF_BINARY)))
+        .filter(|path| path.is_file())
+        .unwrap_or_else(|| PathBuf::from(EBPF_BINARY));
+
+    let status = Command::new(&program)
+        .args(args)
+        .status()
+        .map_err(|e| format!("Cannot run {}: {}", program.display(), e))?;
+    process::exit(status.code().unwrap_or(1));
+}
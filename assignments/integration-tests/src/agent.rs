@@ -0,0 +1,116 @@
+//! Mock of the adnet-agent control connection.
+
+use std::{
+    io::{ErrorKind, Read},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::Arc,
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+/// How long the mock waits for the client before giving up, so that a broken
+/// client fails the test instead of hanging it
+const ACCEPT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Port adnet-agent listens on
+pub const AGENT_PORT: u16 = 12345;
+
+/// Listens at `addr` and serves one control connection: reads the control
+/// message and passes it and the socket to `handler`. Returns the address
+/// listened on, and a join handle that returns the control message.
+pub fn spawn<F>(addr: SocketAddr, handler: F) -> (SocketAddr, JoinHandle<String>)
+where
+    F: FnOnce(&str, TcpStream) + Send + 'static,
+{
+    let listener = TcpListener::bind(addr).expect("mock agent cannot bind");
+    let addr = listener.local_addr().unwrap();
+    let handle = thread::spawn(move || {
+        let mut socket = accept(&listener);
+        let message = read_message(&mut socket);
+        handler(&message, socket);
+        message
+    });
+    (addr, handle)
+}
+
+/// Like [`spawn`], but serves `connections` control connections, each in a
+/// thread of its own so that they can be open at once. The handler gets the
+/// order of the connection from 0 as well. The join handle returns the
+/// control messages in that order.
+pub fn spawn_many<F>(
+    addr: SocketAddr,
+    connections: usize,
+    handler: F,
+) -> (SocketAddr, JoinHandle<Vec<String>>)
+where
+    F: Fn(usize, &str, TcpStream) + Send + Sync + 'static,
+{
+    let listener = TcpListener::bind(addr).expect("mock agent cannot bind");
+    let addr = listener.local_addr().unwrap();
+    let handler = Arc::new(handler);
+    let handle = thread::spawn(move || {
+        let served: Vec<_> = (0..connections)
+            .map(|index| {
+                let mut socket = accept(&listener);
+                let handler = handler.clone();
+                thread::spawn(move || {
+                    let message = read_message(&mut socket);
+                    handler(index, &message, socket);
+                    message
+                })
+            })
+            .collect();
+        served.into_iter().map(|t| t.join().unwrap()).collect()
+    });
+    (addr, handle)
+}
+
+fn accept(listener: &TcpListener) -> TcpStream {
+    listener.set_nonblocking(true).unwrap();
+    let start = std::time::Instant::now();
+    loop {
+        match listener.accept() {
+            Ok((socket, _)) => {
+                socket.set_nonblocking(false).unwrap();
+                return socket;
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                assert!(
+                    start.elapsed() < ACCEPT_TIMEOUT,
+                    "nobody connected to the mock agent"
+                );
+                thread::sleep(Duration::from_millis(10));
+            }
+            Err(e) => panic!("mock agent accept failed: {}", e),
+        }
+    }
+}
+
+/// Reads the control message. Some messages end with a newline and some do
+/// not, so the message is complete at a newline or when the client pauses.
+fn read_message(socket: &mut TcpStream) -> String {
+    socket
+        .set_read_timeout(Some(Duration::from_millis(200)))
+        .unwrap();
+    let mut message = Vec::new();
+    let mut buf = [0u8; 256];
+    loop {
+        match socket.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => {
+                message.extend_from_slice(&buf[..n]);
+                if message.ends_with(b"\n") {
+                    break;
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+                if !message.is_empty() {
+                    break;
+                }
+            }
+            Err(e) => panic!("mock agent read failed: {}", e),
+        }
+    }
+    socket.set_read_timeout(None).unwrap();
+    String::from_utf8_lossy(&message).trim_end().to_string()
+}
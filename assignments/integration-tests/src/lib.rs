@@ -0,0 +1,148 @@
+//! Helpers for the end-to-end tests under tests/: building and running the
+//! task programs, and mock versions of the adnet-agent side of each protocol.
+//!
+//! Every test uses its own loopback address (127.0.0.x) so that the fixed
+//! ports of the protocol, such as UDP port 20000, do not collide when tests
+//! run in parallel.
+
+pub mod agent;
+pub mod udp;
+
+use std::{
+    io::Read,
+    path::PathBuf,
+    process::{Command, Stdio},
+    sync::Once,
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Binaries the tests run, built once per test process
+const BINARIES: &[(&str, &str)] = &[
+    ("task-cli", "adnet-cli"),
+    ("task-srv", "task-srv"),
+    ("task-udp", "task-udp"),
+];
+
+static BUILD: Once = Once::new();
+
+/// Path to a binary of the workspace. Cargo does not build the binaries of
+/// other packages for tests, so they are built here on first use.
+pub fn binary(name: &str) -> PathBuf {
+    // The test executable is target/<profile>/deps/<test>-<hash>
+    let exe = std::env::current_exe().unwrap();
+    let dir = exe.parent().unwrap().parent().unwrap();
+
+    BUILD.call_once(|| {
+        let mut cargo = Command::new(env!("CARGO"));
+        cargo.args(["build", "--quiet"]);
+        if dir.ends_with("release") {
+            cargo.arg("--release");
+        }
+        for (package, _) in BINARIES {
+            cargo.args(["-p", package]);
+        }
+        let status = cargo.status().expect("failed to run cargo build");
+        assert!(status.success(), "building the task binaries failed");
+    });
+    assert!(
+        BINARIES.iter().any(|&(_, bin)| bin == name),
+        "unknown binary {}",
+        name
+    );
+    dir.join(name)
+}
+
+/// Result of running a program to completion.
+pub struct Run {
+    pub success: bool,
+    /// Exit code, or `None` if a signal ended the program
+    pub code: Option<i32>,
+    pub stdout: String,
+    pub stderr: String,
+    pub elapsed: Duration,
+}
+
+impl Run {
+    /// Everything the program printed, for assertion messages.
+    pub fn output(&self) -> String {
+        format!("stdout:\n{}\nstderr:\n{}", self.stdout, self.stderr)
+    }
+}
+
+/// Runs the command, killing it if it has not finished within `timeout`.
+pub fn run(command: &mut Command, timeout: Duration) -> Run {
+    let start = Instant::now();
+    let mut child = command
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("failed to start the program");
+
+    // Read the pipes in threads so that the program never blocks on a full pipe
+    let mut stdout = child.stdout.take().unwrap();
+    let mut stderr = child.stderr.take().unwrap();
+    let out = thread::spawn(move || {
+        let mut s = String::new();
+        let _ = stdout.read_to_string(&mut s);
+        s
+    });
+    let err = thread::spawn(move || {
+        let mut s = String::new();
+        let _ = stderr.read_to_string(&mut s);
+        s
+    });
+
+    let status = loop {
+        if let Some(status) = child.try_wait().unwrap() {
+            break Some(status);
+        }
+        if start.elapsed() > timeout {
+            let _ = child.kill();
+            let _ = child.wait();
+            break None;
+        }
+        thread::sleep(Duration::from_millis(10));
+    };
+
+    let run = Run {
+        success: status.is_some_and(|s| s.success()),
+        code: status.and_then(|s| s.code()),
+        stdout: out.join().unwrap(),
+        stderr: err.join().unwrap(),
+        elapsed: start.elapsed(),
+    };
+    assert!(
+        status.is_some(),
+        "program did not finish in {:?}\n{}",
+        timeout,
+        run.output()
+    );
+    run
+}
+
+/// A program that runs until the test ends, such as a server. Its output is
+/// discarded, so its behaviour is checked through the network.
+pub struct Background(std::process::Child);
+
+impl Background {
+    pub fn spawn(command: &mut Command) -> Self {
+        let child = command
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("failed to start the program");
+        Background(child)
+    }
+
+    pub fn is_running(&mut self) -> bool {
+        matches!(self.0.try_wait(), Ok(None))
+    }
+}
+
+impl Drop for Background {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
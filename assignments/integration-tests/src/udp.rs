@@ -0,0 +1,114 @@
+//! Mock of the adnet-agent UDP receiver for task-udp.
+
+use std::{
+    collections::BTreeMap,
+    io::ErrorKind,
+    net::{SocketAddr, UdpSocket},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use netem::{Datagram, ImpairedSocket, Impairment, SystemClock};
+use wire::Ack;
+
+/// Port adnet-agent receives the data on
+pub const UDP_PORT: u16 = 20000;
+const TRANSFER_TIMEOUT: Duration = Duration::from_secs(60);
+// After everything has arrived, keep acknowledging retransmissions until the
+// sender has been quiet this long, in case the last acknowledgements were lost
+const LINGER: Duration = Duration::from_millis(1500);
+
+/// What the receiver got.
+#[derive(Debug)]
+pub struct Transfer {
+    /// Payload in sequence number order
+    pub data: Vec<u8>,
+    /// Checknum sent in the last acknowledgement
+    pub checknum: u8,
+    /// Data packets received, including retransmissions and duplicates
+    pub packets: u64,
+    pub elapsed: Duration,
+}
+
+/// Receives `size` bytes at `addr` with the task-udp protocol: packets carry a
+/// 4-byte sequence number starting from 1 and a 2-byte payload length, and
+/// each one is answered with the highest sequence number received in order
+/// and a checknum. Acknowledgements are sent through a link with the given
+/// impairment.
+///
+/// The checknum of the real agent is its own business; this one is the sum of
+/// the payload bytes received in order, and the tests check that the sender
+/// reports the receiver's value.
+pub fn spawn_receiver(
+    addr: SocketAddr,
+    size: usize,
+    impairment: Impairment,
+) -> JoinHandle<Transfer> {
+    let socket = UdpSocket::bind(addr).expect("mock receiver cannot bind");
+    socket
+        .set_read_timeout(Some(Duration::from_millis(10)))
+        .unwrap();
+    let mut socket = ImpairedSocket::new(socket, impairment, SystemClock::new());
+
+    thread::spawn(move || {
+        let start = Instant::now();
+        let mut pending: BTreeMap<u32, Vec<u8>> = BTreeMap::new();
+        let mut data = Vec::with_capacity(size);
+        let mut next_seq = 1;
+        let mut checknum = 0u8;
+        let mut packets = 0;
+        let mut completed: Option<Instant> = None;
+        let mut last_packet = Instant::now();
+        let mut buf = [0u8; 65536];
+
+        loop {
+            assert!(
+                start.elapsed() < TRANSFER_TIMEOUT,
+                "transfer did not complete, got {} of {} bytes",
+                data.len(),
+                size
+            );
+            if completed.is_some() && last_packet.elapsed() > LINGER {
+                break;
+            }
+            socket.flush().unwrap();
+
+            let (n, from) = match socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+                    continue
+                }
+                Err(e) => panic!("mock receiver failed: {}", e),
+            };
+            let (header, payload) = wire::decode(&buf[..n])
+                .unwrap_or_else(|e| panic!("invalid packet of {} bytes: {}", n, e));
+            packets += 1;
+            last_packet = Instant::now();
+
+            if header.seq >= next_seq {
+                pending.insert(header.seq, payload.to_vec());
+            }
+            while let Some(payload) = pending.remove(&next_seq) {
+                checknum = payload.iter().fold(checknum, |c, &b| c.wrapping_add(b));
+                data.extend_from_slice(&payload);
+                next_seq += 1;
+            }
+            if data.len() >= size && completed.is_none() {
+                completed = Some(Instant::now());
+            }
+
+            let ack = Ack {
+                seq: next_seq - 1,
+                checknum,
+            };
+            socket.send_to(&ack.encode(), from).unwrap();
+        }
+
+        Transfer {
+            data,
+            checknum,
+            packets,
+            elapsed: completed.unwrap() - start,
+        }
+    })
+}
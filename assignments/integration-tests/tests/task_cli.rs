@@ -0,0 +1,146 @@
+//! adnet-cli task-cli against a mock agent that streams data.
+
+use std::{io::Write, net::SocketAddr, process::Command, thread, time::Duration};
+
+use integration_tests::{agent, binary, run};
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+fn adnet_cli(agent: SocketAddr) -> Command {
+    let mut command = Command::new(binary("adnet-cli"));
+    command.args(["--agent", &agent.to_string(), "--keyword", "secret"]);
+    command
+}
+
+#[test]
+fn receives_and_verifies_the_whole_stream() {
+    let (addr, agent) = agent::spawn("127.0.0.11:0".parse().unwrap(), |_, mut socket| {
+        socket.write_all(&[b'x'; 1_000_000]).unwrap();
+    });
+
+    let run = run(
+        adnet_cli(addr).args(["task-cli", "--verify", "--expect-byte", "x"]),
+        TIMEOUT,
+    );
+
+    assert!(run.success, "{}", run.output());
+    assert_eq!(agent.join().unwrap(), "TASK-CLI secret");
+    assert!(
+        run.stdout.contains("Total size: 1000000 bytes"),
+        "{}",
+        run.output()
+    );
+    assert!(run.stdout.contains("Verification OK"), "{}", run.output());
+    assert!(
+        run.elapsed < Duration::from_secs(10),
+        "took {:?}",
+        run.elapsed
+    );
+}
+
+#[test]
+fn reports_the_offset_of_corrupted_data() {
+    let (addr, _agent) = agent::spawn("127.0.0.12:0".parse().unwrap(), |_, mut socket| {
+        let mut data = vec![b'x'; 200_000];
+        data[123_456] = b'y';
+        socket.write_all(&data).unwrap();
+    });
+
+    let run = run(
+        adnet_cli(addr).args(["task-cli", "--verify", "--expect-byte", "x"]),
+        TIMEOUT,
+    );
+
+    assert!(!run.success, "{}", run.output());
+    // Verification failure, see adnet_core::exit
+    assert_eq!(run.code, Some(6), "{}", run.output());
+    assert!(
+        run.stdout
+            .contains("Verification FAILED: byte at offset 123456"),
+        "{}",
+        run.output()
+    );
+}
+
+#[test]
+fn deadline_stops_a_stalled_transfer() {
+    let (addr, _agent) = agent::spawn("127.0.0.13:0".parse().unwrap(), |_, mut socket| {
+        socket.write_all(&[b'x'; 1000]).unwrap();
+        // Keep the connection open without sending more
+        thread::sleep(Duration::from_secs(5));
+    });
+
+    let run = run(
+        adnet_cli(addr).args(["task-cli", "--deadline", "1"]),
+        TIMEOUT,
+    );
+
+    assert!(!run.success, "{}", run.output());
+    // Timeout
+    assert_eq!(run.code, Some(5), "{}", run.output());
+    assert!(
+        run.stderr.contains("deadline exceeded after 1000 bytes"),
+        "{}",
+        run.output()
+    );
+    assert!(
+        run.elapsed >= Duration::from_secs(1) && run.elapsed < Duration::from_secs(4),
+        "took {:?}",
+        run.elapsed
+    );
+}
+
+#[test]
+fn shares_requests_between_streams_and_retries_a_stalled_one() {
+    let (addr, agent) = agent::spawn_many(
+        "127.0.0.14:0".parse().unwrap(),
+        7,
+        |index, _, mut socket| {
+            if index == 0 {
+                // Stall past the read timeout, so that the request is retried
+                socket.write_all(&[b'x'; 1000]).unwrap();
+                thread::sleep(Duration::from_secs(3));
+            } else {
+                socket.write_all(&[b'x'; 200_000]).unwrap();
+            }
+        },
+    );
+
+    let run = run(
+        adnet_cli(addr).args([
+            "--read-timeout",
+            "1",
+            "task-cli",
+            "--streams",
+            "3",
+            "--count",
+            "6",
+            "--verify",
+            "--expect-byte",
+            "x",
+        ]),
+        TIMEOUT,
+    );
+
+    assert!(run.success, "{}", run.output());
+    assert!(
+        agent.join().unwrap().iter().all(|m| m == "TASK-CLI secret"),
+        "{}",
+        run.output()
+    );
+    assert!(run.stdout.contains("retrying in"), "{}", run.output());
+    for stream in 1..=3 {
+        assert!(
+            run.stdout
+                .contains(&format!("Stream {}: 2 transfers, 400000 bytes", stream)),
+            "{}",
+            run.output()
+        );
+    }
+    assert!(
+        run.stdout.contains("1200000 bytes over 3 streams"),
+        "{}",
+        run.output()
+    );
+    assert!(run.stdout.contains("6 requests in"), "{}", run.output());
+}
@@ -0,0 +1,310 @@
+//! task-srv on loopback: it registers with a mock agent, and adnet-cli plays
+//! the part of the agent making requests.
+
+use std::{
+    io::{Read, Write},
+    net::{SocketAddr, TcpStream},
+    process::Command,
+    time::{Duration, Instant},
+};
+
+use integration_tests::{agent, binary, run, Background};
+use wire::srv::{Crc32, Mode, Request};
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+const SERVER_PORT: u16 = 40000;
+
+/// Starts task-srv at `ip` with the `extra` options, and waits until it has
+/// sent its control message.
+fn start_server(ip: &str, extra: &[&str]) -> (Background, SocketAddr) {
+    let agent_addr: SocketAddr = format!("{}:{}", ip, agent::AGENT_PORT).parse().unwrap();
+    let (_, agent) = agent::spawn(agent_addr, |_, _| {});
+
+    let server = Background::spawn(
+        Command::new(binary("task-srv"))
+            .args([
+                "--keyword",
+                "secret",
+                "--ip",
+                ip,
+                "--port",
+                &SERVER_PORT.to_string(),
+                "--agent",
+                &agent_addr.to_string(),
+            ])
+            .args(extra),
+    );
+
+    let server_addr: SocketAddr = format!("{}:{}", ip, SERVER_PORT).parse().unwrap();
+    assert_eq!(
+        agent.join().unwrap(),
+        format!("TASK-SRV secret {}", server_addr)
+    );
+    (server, server_addr)
+}
+
+#[test]
+fn serves_repeated_requests_on_one_connection() {
+    let (mut server, addr) = start_server("127.0.0.21", &[]);
+
+    let run = run(
+        Command::new(binary("adnet-cli")).args([
+            "task-srv-request",
+            "--server",
+            &addr.to_string(),
+            "--size",
+            "250000",
+            "--count",
+            "3",
+        ]),
+        TIMEOUT,
+    );
+
+    assert!(run.success, "{}", run.output());
+    for i in 1..=3 {
+        assert!(
+            run.stdout.contains(&format!("Request {}: 250000 bytes", i)),
+            "{}",
+            run.output()
+        );
+    }
+    assert!(run.stdout.contains("3 requests in"), "{}", run.output());
+    assert!(server.is_running());
+}
+
+#[test]
+fn serves_a_new_connection_for_each_request() {
+    let (_server, addr) = start_server("127.0.0.22", &[]);
+
+    let run = run(
+        Command::new(binary("adnet-cli")).args([
+            "task-srv-request",
+            "--server",
+            &addr.to_string(),
+            "--size",
+            "1000",
+            "--count",
+            "5",
+            "--reconnect",
+        ]),
+        TIMEOUT,
+    );
+
+    assert!(run.success, "{}", run.output());
+    assert!(run.stdout.contains("5 requests in"), "{}", run.output());
+    assert!(
+        run.elapsed < Duration::from_secs(10),
+        "took {:?}",
+        run.elapsed
+    );
+}
+
+#[test]
+fn answers_pipelined_requests_in_order() {
+    let (_server, addr) = start_server("127.0.0.23", &[]);
+
+    let mut socket = TcpStream::connect(addr).unwrap();
+    socket.set_read_timeout(Some(TIMEOUT)).unwrap();
+    // Both requests are sent before reading anything
+    let mut requests = Vec::new();
+    for (size, byte) in [(3000u32, b'a'), (70_000, b'b')] {
+        requests.extend_from_slice(&size.to_be_bytes());
+        requests.push(byte);
+    }
+    socket.write_all(&requests).unwrap();
+
+    let mut response = vec![0u8; 73_000];
+    socket.read_exact(&mut response).unwrap();
+    assert!(response[..3000].iter().all(|&b| b == b'a'));
+    assert!(response[3000..].iter().all(|&b| b == b'b'));
+
+    // Nothing more than requested
+    socket
+        .set_read_timeout(Some(Duration::from_millis(200)))
+        .unwrap();
+    let mut extra = [0u8; 1];
+    assert!(socket.read(&mut extra).is_err());
+}
+
+/// Sends a request for `size` bytes of `byte`.
+fn request(socket: &mut TcpStream, size: u32, byte: u8) {
+    socket.write_all(&size.to_be_bytes()).unwrap();
+    socket.write_all(&[byte]).unwrap();
+}
+
+#[test]
+fn queues_connections_past_the_limit() {
+    let (_server, addr) = start_server("127.0.0.24", &["--max-connections", "1"]);
+
+    let mut first = TcpStream::connect(addr).unwrap();
+    first.set_read_timeout(Some(TIMEOUT)).unwrap();
+    request(&mut first, 100, b'a');
+    let mut response = [0u8; 100];
+    first.read_exact(&mut response).unwrap();
+
+    // The second connection waits in the backlog while the first is open
+    let mut second = TcpStream::connect(addr).unwrap();
+    request(&mut second, 100, b'b');
+    second
+        .set_read_timeout(Some(Duration::from_millis(500)))
+        .unwrap();
+    let mut byte = [0u8; 1];
+    assert!(second.read(&mut byte).is_err());
+
+    drop(first);
+    second.set_read_timeout(Some(TIMEOUT)).unwrap();
+    second.read_exact(&mut response).unwrap();
+    assert!(response.iter().all(|&b| b == b'b'));
+}
+
+#[test]
+fn serves_tls_and_plaintext_side_by_side() {
+    let (_server, addr) = start_server("127.0.0.25", &["--tls-port", "40001"]);
+    let tls_addr = SocketAddr::new(addr.ip(), 40001);
+
+    let request = |server: SocketAddr, tls: &[&str]| {
+        run(
+            Command::new(binary("adnet-cli"))
+                .args(tls)
+                .args(["task-srv-request", "--server", &server.to_string()])
+                .args(["--size", "100000", "--count", "2"]),
+            TIMEOUT,
+        )
+    };
+
+    let run = request(tls_addr, &["--tls", "--insecure"]);
+    assert!(run.success, "{}", run.output());
+    assert!(run.stdout.contains("TLS established"), "{}", run.output());
+    assert!(run.stdout.contains("2 requests in"), "{}", run.output());
+
+    let run = request(addr, &[]);
+    assert!(run.success, "{}", run.output());
+    assert!(!run.stdout.contains("TLS established"), "{}", run.output());
+    assert!(run.stdout.contains("2 requests in"), "{}", run.output());
+}
+
+#[test]
+fn answers_requests_over_udp() {
+    let (_server, addr) = start_server("127.0.0.26", &["--udp-port", "40002"]);
+    let udp_addr = SocketAddr::new(addr.ip(), 40002);
+
+    let run = run(
+        Command::new(binary("adnet-cli")).args([
+            "task-srv-request",
+            "--udp",
+            "--server",
+            &udp_addr.to_string(),
+            "--size",
+            "250000",
+            "--count",
+            "2",
+        ]),
+        TIMEOUT,
+    );
+
+    assert!(run.success, "{}", run.output());
+    for i in 1..=2 {
+        assert!(
+            run.stdout.contains(&format!("Request {}: 250000 bytes", i)),
+            "{}",
+            run.output()
+        );
+    }
+    // The sum of 250000 bytes of 'A', modulo 256
+    assert!(run.stdout.contains("Checknum: 144"), "{}", run.output());
+    assert!(run.stdout.contains("2 requests in"), "{}", run.output());
+}
+
+#[test]
+fn serves_metrics_over_http() {
+    let (_server, addr) = start_server("127.0.0.27", &["--http-port", "40003"]);
+
+    let mut socket = TcpStream::connect(SocketAddr::new(addr.ip(), 40003)).unwrap();
+    socket.set_read_timeout(Some(TIMEOUT)).unwrap();
+    // The metrics are asked for after the bytes on the same connection, so
+    // they include that request
+    socket
+        .write_all(
+            b"GET /bytes?n=100&b=A HTTP/1.1\r\nHost: test\r\n\r\n\
+              GET /metrics HTTP/1.1\r\nHost: test\r\nConnection: close\r\n\r\n",
+        )
+        .unwrap();
+    let mut response = String::new();
+    socket.read_to_string(&mut response).unwrap();
+
+    for line in [
+        "# TYPE srv_requests_total counter",
+        "srv_requests_total 1",
+        "srv_bytes_written_total 100",
+        "srv_active_connections 1",
+        "srv_request_duration_seconds_count 1",
+        "srv_timeouts_total 0",
+        "srv_errors_total 0",
+    ] {
+        assert!(response.lines().any(|l| l == line), "{}", response);
+    }
+}
+
+#[test]
+fn answers_extended_requests_with_a_crc() {
+    let (_server, addr) = start_server("127.0.0.28", &[]);
+
+    let mut socket = TcpStream::connect(addr).unwrap();
+    socket.set_read_timeout(Some(TIMEOUT)).unwrap();
+    let extended = Request::extended(300, 250, Mode::Increment);
+    socket.write_all(&extended.encode().unwrap()).unwrap();
+    let mut response = [0u8; 304];
+    socket.read_exact(&mut response).unwrap();
+    let (bytes, crc) = response.split_at(300);
+    assert_eq!(&bytes[..8], [250, 251, 252, 253, 254, 255, 0, 1]);
+    let mut expected = Crc32::new();
+    expected.update(bytes);
+    assert_eq!(crc, expected.finish().to_be_bytes());
+
+    // The request of the assignment still gets the bytes alone
+    socket
+        .write_all(&Request::repeat(3, b'A').encode().unwrap())
+        .unwrap();
+    let mut response = [0u8; 3];
+    socket.read_exact(&mut response).unwrap();
+    assert_eq!(&response, b"AAA");
+    drop(socket);
+
+    let run = run(
+        Command::new(binary("adnet-cli")).args([
+            "task-srv-request",
+            "--server",
+            &addr.to_string(),
+            "--size",
+            "250000",
+            "--mode",
+            "random",
+            "--count",
+            "2",
+        ]),
+        TIMEOUT,
+    );
+
+    assert!(run.success, "{}", run.output());
+    assert!(run.stdout.contains("CRC32: "), "{}", run.output());
+    assert!(run.stdout.contains("2 requests in"), "{}", run.output());
+}
+
+#[test]
+fn closes_a_request_of_the_assignment_with_the_top_bit_set() {
+    let (_server, addr) = start_server("127.0.0.29", &[]);
+
+    // 2 GiB of the value, which reads as the start of an extended request
+    let mut socket = TcpStream::connect(addr).unwrap();
+    socket.set_read_timeout(Some(TIMEOUT)).unwrap();
+    socket.write_all(&[0x80, 0, 0, 0, b'A']).unwrap();
+    let start = Instant::now();
+    let mut response = Vec::new();
+    socket.read_to_end(&mut response).unwrap();
+    assert!(response.is_empty(), "{} bytes", response.len());
+    assert!(
+        start.elapsed() < Duration::from_secs(10),
+        "{:?}",
+        start.elapsed()
+    );
+}
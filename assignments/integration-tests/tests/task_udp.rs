@@ -0,0 +1,276 @@
+//! task-udp against a mock agent and UDP receiver, with and without
+//! impairments on the acknowledgements, and against its own receiver mode.
+
+use std::{
+    io::Write,
+    net::{SocketAddr, TcpStream},
+    process::Command,
+    thread,
+    time::{Duration, Instant},
+};
+
+use integration_tests::{agent, binary, run, udp, Background};
+use netem::Impairment;
+
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Runs a task-udp transfer of `size` bytes of 'Q' from `ip`, checking that
+/// the receiver got exactly that and that task-udp reports its checknum.
+fn transfer(ip: &str, size: usize, ack_impairment: Impairment) -> udp::Transfer {
+    let agent_addr: SocketAddr = format!("{}:{}", ip, agent::AGENT_PORT).parse().unwrap();
+    let receiver_addr: SocketAddr = format!("{}:{}", ip, udp::UDP_PORT).parse().unwrap();
+
+    let receiver = udp::spawn_receiver(receiver_addr, size, ack_impairment);
+    let (_, agent) = agent::spawn(agent_addr, move |_, mut socket| {
+        write!(socket, "{} Q", size).unwrap();
+    });
+
+    let run = run(
+        Command::new(binary("task-udp"))
+            .args(["--server", ip, "--keyword", "secret"])
+            .env("NO_COLOR", "1"),
+        TIMEOUT,
+    );
+    assert!(run.success, "{}", run.output());
+    assert_eq!(agent.join().unwrap(), "TASK-UDP secret");
+
+    let transfer = receiver.join().unwrap();
+    assert_eq!(transfer.data.len(), size);
+    assert!(transfer.data.iter().all(|&b| b == b'Q'));
+    assert!(
+        run.stdout.contains(&format!(
+            "Size: {} -- Checknum: {}",
+            size, transfer.checknum
+        )),
+        "{}",
+        run.output()
+    );
+    transfer
+}
+
+#[test]
+fn clean_link() {
+    let transfer = transfer("127.0.0.31", 100_000, Impairment::default());
+
+    // 1200-byte payloads, and nothing should need retransmitting
+    assert_eq!(transfer.packets, 100_000u64.div_ceil(1200));
+    assert!(
+        transfer.elapsed < Duration::from_secs(5),
+        "took {:?}",
+        transfer.elapsed
+    );
+}
+
+#[test]
+fn partial_last_packet() {
+    transfer("127.0.0.32", 1201, Impairment::default());
+}
+
+#[test]
+fn lost_and_delayed_acknowledgements() {
+    let impairment = Impairment {
+        loss: 20.0,
+        delay: Duration::from_millis(5),
+        jitter: Duration::from_millis(2),
+        seed: 42,
+        ..Impairment::default()
+    };
+    let transfer = transfer("127.0.0.33", 50_000, impairment);
+    assert!(
+        transfer.elapsed < Duration::from_secs(30),
+        "took {:?}",
+        transfer.elapsed
+    );
+}
+
+#[test]
+fn duplicated_and_reordered_acknowledgements() {
+    let impairment = Impairment {
+        delay: Duration::from_millis(10),
+        reorder: 25.0,
+        duplicate: 25.0,
+        seed: 7,
+        ..Impairment::default()
+    };
+    transfer("127.0.0.34", 50_000, impairment);
+}
+
+/// Runs task-udp as the receiver at `ip` and a task-udp sender against it.
+fn transfer_to_listener(ip: &str, size: usize, receiver_args: &[&str], sender_args: &[&str]) {
+    let _receiver = Background::spawn(
+        Command::new(binary("task-udp"))
+            .args([
+                "--listen",
+                ip,
+                "--size",
+                &size.to_string(),
+                "--character",
+                "Q",
+            ])
+            .args(receiver_args),
+    );
+    // The receiver takes the probe for a sender that gave up
+    let agent_addr = SocketAddr::new(ip.parse().unwrap(), agent::AGENT_PORT);
+    let start = Instant::now();
+    while TcpStream::connect(agent_addr).is_err() {
+        assert!(start.elapsed() < TIMEOUT, "receiver did not start");
+        thread::sleep(Duration::from_millis(20));
+    }
+
+    let run = run(
+        Command::new(binary("task-udp"))
+            .args(["--server", ip, "--keyword", "secret"])
+            .args(sender_args)
+            .env("NO_COLOR", "1"),
+        TIMEOUT,
+    );
+    assert!(run.success, "{}", run.output());
+    // The receiver's checknum is the sum of the payload bytes
+    let checknum = (size as u64 * b'Q' as u64 % 256) as u8;
+    assert!(
+        run.stdout
+            .contains(&format!("Size: {} -- Checknum: {}", size, checknum)),
+        "{}",
+        run.output()
+    );
+}
+
+#[test]
+fn listen_mode() {
+    transfer_to_listener("127.0.0.35", 100_000, &[], &[]);
+}
+
+#[test]
+fn listen_mode_with_gap_fill_quirk() {
+    transfer_to_listener("127.0.0.36", 50_000, &["--gap-fill-quirk"], &[]);
+}
+
+#[test]
+fn listen_mode_with_sack() {
+    transfer_to_listener("127.0.0.37", 100_000, &["--sack"], &[]);
+}
+
+#[test]
+fn listen_mode_under_loss_and_jitter() {
+    // Both directions lose a fifth of the datagrams, and the data is delayed
+    // by 0 to 400 ms, which also reorders it
+    transfer_to_listener(
+        "127.0.0.39",
+        50_000,
+        &["--loss", "20", "--seed", "7"],
+        &[
+            "--loss", "20", "--delay", "200", "--jitter", "200", "--seed", "3",
+        ],
+    );
+}
+
+#[test]
+fn listen_mode_with_cubic_under_loss() {
+    transfer_to_listener(
+        "127.0.0.40",
+        50_000,
+        &[],
+        &["--cc", "cubic", "--loss", "10", "--seed", "5"],
+    );
+}
+
+#[test]
+fn listen_mode_with_bbr_under_loss() {
+    transfer_to_listener(
+        "127.0.0.41",
+        50_000,
+        &[],
+        &["--cc", "bbr", "--loss", "10", "--seed", "5"],
+    );
+}
+
+#[test]
+fn listen_mode_with_tuning() {
+    // Smaller packets, a larger window and a fast retransmit after two
+    // duplicate acknowledgements
+    transfer_to_listener(
+        "127.0.0.42",
+        50_000,
+        &[],
+        &[
+            "--max-payload",
+            "500",
+            "--initial-window",
+            "4",
+            "--max-window",
+            "8",
+            "--dup-ack-threshold",
+            "2",
+            "--min-rto",
+            "100",
+            "--loss",
+            "10",
+            "--seed",
+            "9",
+        ],
+    );
+}
+
+#[test]
+fn listen_mode_over_ipv6() {
+    transfer_to_listener("::1", 50_000, &[], &[]);
+}
+
+#[test]
+fn listen_mode_on_other_udp_port() {
+    transfer_to_listener(
+        "127.0.0.43",
+        50_000,
+        &["--udp-port", "20001"],
+        &["--udp-port", "20001"],
+    );
+}
+
+#[test]
+fn file_transfer_to_listener() {
+    let ip = "127.0.0.38";
+    let dir = std::env::temp_dir();
+    let input = dir.join(format!("task-udp-input-{}", std::process::id()));
+    let output = dir.join(format!("task-udp-output-{}", std::process::id()));
+    // Not a multiple of the payload size, and every byte value
+    let data: Vec<u8> = (0..30_001u32).map(|i| (i * 7 % 256) as u8).collect();
+    std::fs::write(&input, &data).unwrap();
+    let _ = std::fs::remove_file(&output);
+
+    let _receiver = Background::spawn(
+        Command::new(binary("task-udp"))
+            .args(["--listen", ip, "--sack", "--output"])
+            .arg(&output),
+    );
+    let agent_addr: SocketAddr = format!("{}:{}", ip, agent::AGENT_PORT).parse().unwrap();
+    let start = Instant::now();
+    while TcpStream::connect(agent_addr).is_err() {
+        assert!(start.elapsed() < TIMEOUT, "receiver did not start");
+        thread::sleep(Duration::from_millis(20));
+    }
+
+    let run = run(
+        Command::new(binary("task-udp"))
+            .args(["--server", ip, "--keyword", "secret", "--file"])
+            .arg(&input)
+            .env("NO_COLOR", "1"),
+        TIMEOUT,
+    );
+    assert!(run.success, "{}", run.output());
+    let checknum = data.iter().fold(0u8, |c, &b| c.wrapping_add(b));
+    assert!(
+        run.stdout
+            .contains(&format!("Size: {} -- Checknum: {}", data.len(), checknum)),
+        "{}",
+        run.output()
+    );
+
+    // The receiver writes the file once it has stopped lingering
+    let start = Instant::now();
+    while std::fs::read(&output).ok().as_ref() != Some(&data) {
+        assert!(start.elapsed() < TIMEOUT, "receiver did not write the data");
+        thread::sleep(Duration::from_millis(100));
+    }
+    let _ = std::fs::remove_file(&input);
+    let _ = std::fs::remove_file(&output);
+}
@@ -0,0 +1,267 @@
+//! The course lab on one machine: a client, a router and a server, each in a
+//! network namespace of its own, with a configurable link in between. This
+//! replaces setting up namespaces and veth pairs with shell scripts. The
+//! topology is in [`topology`]. The labnet binary is a thin wrapper around
+//! [`run`], and needs root privileges like the `ip` commands it runs.
+
+pub mod topology;
+
+use std::{
+    error::Error,
+    ffi::OsString,
+    os::unix::process::CommandExt,
+    process::{self, Child},
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+    time::Duration,
+};
+
+use adnet_core::{
+    config::{self, ConfigArgs},
+    logging::{self, LogArgs},
+};
+use clap::{Parser, Subcommand};
+use serde::Deserialize;
+use topology::{exit_code, Link, Node, Topology};
+use tracing::{info, warn};
+
+const DEFAULT_PREFIX: &str = "lab";
+const DEFAULT_STARTUP: Duration = Duration::from_millis(500);
+
+/// Creates client, router and server network namespaces and runs programs in them.
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+pub struct Args {
+    #[command(subcommand)]
+    action: Action,
+
+    /// Prefix of the namespace names, e.g. lab-client [default: lab]
+    #[arg(long, global = true)]
+    prefix: Option<String>,
+
+    #[command(flatten)]
+    config: ConfigArgs,
+
+    #[command(flatten)]
+    log: LogArgs,
+}
+
+#[derive(Subcommand, Debug)]
+enum Action {
+    /// Create the namespaces and links, and leave them running
+    Up {
+        #[command(flatten)]
+        link: LinkArgs,
+    },
+
+    /// Remove the namespaces and links
+    Down,
+
+    /// Run a program in the namespace of a node
+    Exec {
+        node: Node,
+
+        /// Program and its arguments
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true, required = true)]
+        command: Vec<OsString>,
+    },
+
+    /// Create the lab, run the server and router commands in the background
+    /// and the client command in the foreground, and remove the lab when the
+    /// client exits
+    Run {
+        #[command(flatten)]
+        link: LinkArgs,
+
+        /// Shell command to run on the server, e.g. "target/debug/task-srv"
+        #[arg(long)]
+        server: Option<String>,
+
+        /// Shell command to run on the router
+        #[arg(long)]
+        router: Option<String>,
+
+        /// Shell command to run on the client
+        #[arg(long)]
+        client: Option<String>,
+
+        /// Seconds to let the server and router start before the client [default: 0.5]
+        #[arg(long, value_parser = config::parse_secs)]
+        startup: Option<Duration>,
+    },
+}
+
+/// Link impairment, applied in both directions on the router.
+#[derive(clap::Args, Debug)]
+struct LinkArgs {
+    /// Delay added to every packet on both links, in milliseconds
+    #[arg(long, value_name = "MS")]
+    delay: Option<f64>,
+
+    /// Random variation of the delay, in milliseconds
+    #[arg(long, value_name = "MS")]
+    jitter: Option<f64>,
+
+    /// Percentage of packets to drop on both links
+    #[arg(long, value_name = "PERCENT")]
+    loss: Option<f64>,
+
+    /// Rate limit of both links in tc syntax, e.g. 10mbit
+    #[arg(long)]
+    rate: Option<String>,
+}
+
+/// The [labnet] section of the config file
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct FileConfig {
+    prefix: Option<String>,
+    delay: Option<f64>,
+    jitter: Option<f64>,
+    loss: Option<f64>,
+    rate: Option<String>,
+    server: Option<String>,
+    router: Option<String>,
+    client: Option<String>,
+    #[serde(deserialize_with = "config::secs")]
+    startup: Option<Duration>,
+}
+
+fn millis(ms: f64, name: &str) -> Result<Duration, String> {
+    Duration::try_from_secs_f64(ms / 1000.0).map_err(|e| format!("Invalid {}: {}", name, e))
+}
+
+impl LinkArgs {
+    fn link(self, file: &FileConfig) -> Result<Link, String> {
+        let loss = self.loss.or(file.loss).unwrap_or(0.0);
+        if !(0.0..=100.0).contains(&loss) {
+            return Err("Loss must be between 0 and 100 percent".to_string());
+        }
+        Ok(Link {
+            delay: millis(self.delay.or(file.delay).unwrap_or(0.0), "delay")?,
+            jitter: millis(self.jitter.or(file.jitter).unwrap_or(0.0), "jitter")?,
+            loss,
+            rate: self.rate.or(file.rate.clone()),
+        })
+    }
+}
+
+// Set by the SIGINT handler while `run` waits for the client
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_interrupt(_: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+/// Runs the action with the given arguments, as the labnet binary does.
+pub fn run(args: Args) -> Result<(), Box<dyn Error>> {
+    logging::init(&args.log)?;
+
+    // Command line options take precedence over the config file
+    let file: FileConfig = args.config.section("labnet")?;
+    let prefix = args
+        .prefix
+        .or(file.prefix.clone())
+        .unwrap_or_else(|| DEFAULT_PREFIX.to_string());
+    let topology = Topology::new(&prefix);
+
+    match args.action {
+        Action::Up { link } => {
+            topology.up(&link.link(&file)?)?;
+            for node in Node::ALL {
+                info!("{} is {}", topology.namespace(node), node.address());
+            }
+            Ok(())
+        }
+        Action::Down => {
+            topology.down();
+            Ok(())
+        }
+        Action::Exec { node, command } => {
+            let (program, args) = command.split_first().expect("command is required");
+            let status = topology.exec(node, program, args).status()?;
+            process::exit(exit_code(status));
+        }
+        Action::Run {
+            link,
+            server,
+            router,
+            client,
+            startup,
+        } => {
+            let commands = [
+                (Node::Server, server.or(file.server.clone())),
+                (Node::Router, router.or(file.router.clone())),
+            ];
+            let client = client
+                .or(file.client.clone())
+                .ok_or("Client command is required (--client)")?;
+            let startup = startup.or(file.startup).unwrap_or(DEFAULT_STARTUP);
+            let link = link.link(&file)?;
+
+            topology.up(&link)?;
+            let code = run_lab(&topology, &commands, &client, startup);
+            topology.down();
+            process::exit(code?);
+        }
+    }
+}
+
+/// Runs the background commands and the client, and stops the background
+/// commands when the client exits. Returns the exit code of the client.
+fn run_lab(
+    topology: &Topology,
+    commands: &[(Node, Option<String>)],
+    client: &str,
+    startup: Duration,
+) -> Result<i32, Box<dyn Error>> {
+    // Ctrl-C goes to the client, which is in the foreground process group.
+    // labnet only notes it, so that the lab is still removed afterwards.
+    unsafe {
+        libc::signal(
+            libc::SIGINT,
+            on_interrupt as extern "C" fn(libc::c_int) as libc::sighandler_t,
+        );
+    }
+
+    let mut background: Vec<(Node, Child)> = Vec::new();
+    let mut result = Ok(0);
+    for (node, command) in commands {
+        let Some(command) = command else { continue };
+        info!("Starting on {:?}: {}", node, command);
+        // Own process group, so that the whole shell pipeline can be stopped
+        match topology.shell(*node, command).process_group(0).spawn() {
+            Ok(child) => background.push((*node, child)),
+            Err(e) => {
+                result = Err(format!("Cannot start {:?} command: {}", node, e).into());
+                break;
+            }
+        }
+    }
+
+    if result.is_ok() {
+        if !background.is_empty() {
+            thread::sleep(startup);
+        }
+        info!("Starting on Client: {}", client);
+        result = topology
+            .shell(Node::Client, client)
+            .status()
+            .map(exit_code)
+            .map_err(|e| format!("Cannot start client command: {}", e).into());
+    }
+    if INTERRUPTED.load(Ordering::SeqCst) {
+        info!("Interrupted");
+    }
+
+    for (node, mut child) in background {
+        match child.try_wait() {
+            Ok(Some(status)) => warn!("{:?} command exited early with {}", node, status),
+            _ => {
+                unsafe { libc::kill(-(child.id() as libc::pid_t), libc::SIGTERM) };
+                let _ = child.wait();
+            }
+        }
+    }
+    result
+}
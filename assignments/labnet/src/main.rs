@@ -0,0 +1,8 @@
+use adnet_core::exit;
+use clap::Parser;
+use labnet::Args;
+use std::process::ExitCode;
+
+fn main() -> ExitCode {
+    exit::exit(labnet::run(Args::parse()))
+}
@@ -0,0 +1,243 @@
+//! The namespaces and links of the lab, created and removed with the `ip`
+//! and `tc` commands of iproute2.
+//!
+//! ```text
+//!  client           router             server
+//!  eth0 ---------- eth0  br0  eth1 ---------- eth0
+//!  10.0.0.1            10.0.0.2             10.0.0.3
+//! ```
+//!
+//! The router bridges its two ports like the switches of the Mininet
+//! topology, so all nodes share 10.0.0.0/24 and the addresses used in the
+//! assignments work unchanged. Delay, loss and rate limits are applied as
+//! netem qdiscs on the router's ports, that is, in both directions.
+
+use std::{
+    ffi::OsStr,
+    io,
+    os::unix::process::ExitStatusExt,
+    process::{Command, ExitStatus},
+    time::Duration,
+};
+
+use tracing::debug;
+
+const PREFIX_LEN: u8 = 24;
+
+/// A node of the lab: a network namespace with one interface, or two on
+/// the router.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum Node {
+    Client,
+    Router,
+    Server,
+}
+
+impl Node {
+    pub const ALL: [Node; 3] = [Node::Client, Node::Router, Node::Server];
+
+    fn name(self) -> &'static str {
+        match self {
+            Node::Client => "client",
+            Node::Router => "router",
+            Node::Server => "server",
+        }
+    }
+
+    /// Address of the node in the lab network
+    pub fn address(self) -> &'static str {
+        match self {
+            Node::Client => "10.0.0.1",
+            Node::Router => "10.0.0.2",
+            Node::Server => "10.0.0.3",
+        }
+    }
+}
+
+/// Impairment of the links, applied to both directions of both links.
+#[derive(Clone, Debug, Default)]
+pub struct Link {
+    pub delay: Duration,
+    pub jitter: Duration,
+    /// Percentage of packets to drop
+    pub loss: f64,
+    /// Rate limit in tc syntax, e.g. 10mbit
+    pub rate: Option<String>,
+}
+
+impl Link {
+    fn is_none(&self) -> bool {
+        self.delay.is_zero() && self.loss == 0.0 && self.rate.is_none()
+    }
+
+    /// Arguments to `tc qdisc add dev DEV root netem`
+    fn netem_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        if !self.delay.is_zero() {
+            args.push("delay".to_string());
+            args.push(format!("{}us", self.delay.as_micros()));
+            if !self.jitter.is_zero() {
+                args.push(format!("{}us", self.jitter.as_micros()));
+            }
+        }
+        if self.loss > 0.0 {
+            args.push("loss".to_string());
+            args.push(format!("{}%", self.loss));
+        }
+        if let Some(rate) = &self.rate {
+            args.push("rate".to_string());
+            args.push(rate.clone());
+        }
+        args
+    }
+}
+
+/// The lab, with namespace names starting with `prefix`, so that several
+/// labs can exist side by side.
+pub struct Topology {
+    prefix: String,
+}
+
+/// Runs a command to completion, failing if it does not succeed.
+fn command<I, S>(program: &str, args: I) -> io::Result<()>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+{
+    let mut command = Command::new(program);
+    command.args(args);
+    debug!("Running {:?}", command);
+    let output = command
+        .output()
+        .map_err(|e| io::Error::new(e.kind(), format!("Cannot run {}: {}", program, e)))?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!(
+            "{:?} failed: {}",
+            command,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(())
+}
+
+impl Topology {
+    pub fn new(prefix: &str) -> Self {
+        Topology {
+            prefix: prefix.to_string(),
+        }
+    }
+
+    /// Name of the network namespace of the node
+    pub fn namespace(&self, node: Node) -> String {
+        format!("{}-{}", self.prefix, node.name())
+    }
+
+    /// Runs `ip` in the namespace of the node.
+    fn ip(&self, node: Node, args: &[&str]) -> io::Result<()> {
+        let namespace = self.namespace(node);
+        command("ip", ["-n", namespace.as_str()].iter().chain(args))
+    }
+
+    /// Creates the namespaces and links. Anything created before a failure
+    /// is removed again.
+    pub fn up(&self, link: &Link) -> io::Result<()> {
+        let result = self.create(link);
+        if result.is_err() {
+            self.down();
+        }
+        result
+    }
+
+    fn create(&self, link: &Link) -> io::Result<()> {
+        for node in Node::ALL {
+            command("ip", ["netns", "add", self.namespace(node).as_str()])?;
+            self.ip(node, &["link", "set", "lo", "up"])?;
+        }
+
+        // The veth pairs are created with their ends directly in the namespaces
+        let router = self.namespace(Node::Router);
+        for (node, port) in [(Node::Client, "eth0"), (Node::Server, "eth1")] {
+            let namespace = self.namespace(node);
+            command(
+                "ip",
+                [
+                    "link",
+                    "add",
+                    "eth0",
+                    "netns",
+                    namespace.as_str(),
+                    "type",
+                    "veth",
+                    "peer",
+                    "name",
+                    port,
+                    "netns",
+                    router.as_str(),
+                ],
+            )?;
+            let address = format!("{}/{}", node.address(), PREFIX_LEN);
+            self.ip(node, &["addr", "add", &address, "dev", "eth0"])?;
+            self.ip(node, &["link", "set", "eth0", "up"])?;
+        }
+
+        self.ip(Node::Router, &["link", "add", "br0", "type", "bridge"])?;
+        for port in ["eth0", "eth1"] {
+            self.ip(Node::Router, &["link", "set", port, "master", "br0"])?;
+            self.ip(Node::Router, &["link", "set", port, "up"])?;
+        }
+        let address = format!("{}/{}", Node::Router.address(), PREFIX_LEN);
+        self.ip(Node::Router, &["addr", "add", &address, "dev", "br0"])?;
+        self.ip(Node::Router, &["link", "set", "br0", "up"])?;
+
+        if !link.is_none() {
+            let netem = link.netem_args();
+            for port in ["eth0", "eth1"] {
+                let mut args = vec!["netns", "exec", router.as_str(), "tc", "qdisc", "add"];
+                args.extend(["dev", port, "root", "netem"]);
+                args.extend(netem.iter().map(String::as_str));
+                command("ip", args)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes the namespaces, and with them the links. Namespaces that do
+    /// not exist are skipped.
+    pub fn down(&self) {
+        for node in Node::ALL {
+            let namespace = self.namespace(node);
+            if let Err(e) = command("ip", ["netns", "del", namespace.as_str()]) {
+                debug!("Not removing {}: {}", namespace, e);
+            }
+        }
+    }
+
+    /// Command that runs `program` with `args` in the namespace of the node.
+    pub fn exec<P, I, S>(&self, node: Node, program: P, args: I) -> Command
+    where
+        P: AsRef<OsStr>,
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        let mut command = Command::new("ip");
+        command
+            .args(["netns", "exec", self.namespace(node).as_str()])
+            .arg(program)
+            .args(args);
+        command
+    }
+
+    /// Command that runs a shell command line in the namespace of the node.
+    pub fn shell(&self, node: Node, line: &str) -> Command {
+        self.exec(node, "sh", ["-c", line])
+    }
+}
+
+/// Exit code to pass on for a finished child: its own, or 128 plus the
+/// signal that killed it, as shells do.
+pub fn exit_code(status: ExitStatus) -> i32 {
+    status
+        .code()
+        .or_else(|| status.signal().map(|signal| 128 + signal))
+        .unwrap_or(1)
+}
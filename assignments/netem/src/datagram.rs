@@ -0,0 +1,105 @@
+use std::{
+    io::{self, ErrorKind},
+    net::SocketAddr,
+    time::Duration,
+};
+
+use crate::{Clock, Impairment, Link};
+
+/// Anything that sends and receives whole datagrams.
+pub trait Datagram {
+    fn send_to(&mut self, buf: &[u8], dest: SocketAddr) -> io::Result<usize>;
+    fn recv_from(&mut self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)>;
+}
+
+impl Datagram for std::net::UdpSocket {
+    fn send_to(&mut self, buf: &[u8], dest: SocketAddr) -> io::Result<usize> {
+        std::net::UdpSocket::send_to(self, buf, dest)
+    }
+
+    fn recv_from(&mut self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        std::net::UdpSocket::recv_from(self, buf)
+    }
+}
+
+#[cfg(feature = "mio")]
+impl Datagram for mio::net::UdpSocket {
+    fn send_to(&mut self, buf: &[u8], dest: SocketAddr) -> io::Result<usize> {
+        mio::net::UdpSocket::send_to(self, buf, dest)
+    }
+
+    fn recv_from(&mut self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        mio::net::UdpSocket::recv_from(self, buf)
+    }
+}
+
+/// A socket whose outgoing datagrams go through an emulated link. Received
+/// datagrams are passed through unchanged; to impair both directions, impair
+/// the sending side at both ends.
+///
+/// Delayed datagrams are sent on later calls to `send_to` or [`flush`], so an
+/// event loop should wake up after [`poll_timeout`] and call `flush`.
+///
+/// [`flush`]: ImpairedSocket::flush
+/// [`poll_timeout`]: ImpairedSocket::poll_timeout
+pub struct ImpairedSocket<D: Datagram, C: Clock> {
+    inner: D,
+    link: Link<C>,
+}
+
+impl<D: Datagram, C: Clock> ImpairedSocket<D, C> {
+    pub fn new(inner: D, impairment: Impairment, clock: C) -> Self {
+        Self {
+            inner,
+            link: Link::new(impairment, clock),
+        }
+    }
+
+    pub fn get_ref(&self) -> &D {
+        &self.inner
+    }
+
+    /// The wrapped socket, e.g. for registering it with a poller.
+    pub fn get_mut(&mut self) -> &mut D {
+        &mut self.inner
+    }
+
+    pub fn link(&self) -> &Link<C> {
+        &self.link
+    }
+
+    /// Sends the datagrams that are due. Returns how many were sent. A
+    /// datagram that does not fit in the socket buffer is lost, as it would
+    /// be on a congested link.
+    pub fn flush(&mut self) -> io::Result<usize> {
+        let mut sent = 0;
+        while let Some((data, dest)) = self.link.pop_ready() {
+            match self.inner.send_to(&data, dest) {
+                Ok(_) => sent += 1,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(sent)
+    }
+
+    /// How long until the next delayed datagram is due.
+    pub fn poll_timeout(&self) -> Option<Duration> {
+        self.link.next_release()
+    }
+}
+
+impl<D: Datagram, C: Clock> Datagram for ImpairedSocket<D, C> {
+    fn send_to(&mut self, buf: &[u8], dest: SocketAddr) -> io::Result<usize> {
+        if self.link.impairment().is_none() {
+            return self.inner.send_to(buf, dest);
+        }
+        self.link.push(buf, dest);
+        self.flush()?;
+        Ok(buf.len())
+    }
+
+    fn recv_from(&mut self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        self.inner.recv_from(buf)
+    }
+}
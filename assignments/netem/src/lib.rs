@@ -0,0 +1,453 @@
+//! Network emulation in the spirit of Linux `tc netem`, but inside the
+//! program: datagrams sent through a [`Link`] can be lost, delayed with
+//! jitter, reordered and duplicated. Randomness comes from a seeded generator
+//! and time from a [`Clock`], so that with a [`VirtualClock`] the outcome is
+//! fully deterministic, e.g. for simulation tests.
+//!
+//! [`ImpairedSocket`] applies a link to anything implementing [`Datagram`],
+//! such as a UDP socket.
+
+mod clock;
+mod datagram;
+mod rng;
+
+use std::{cmp::Reverse, collections::BinaryHeap, net::SocketAddr, time::Duration};
+
+pub use clock::{Clock, SystemClock, VirtualClock};
+pub use datagram::{Datagram, ImpairedSocket};
+use rng::Rng;
+
+/// What happens to the datagrams on the link. Probabilities are percentages.
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "clap", derive(clap::Args))]
+pub struct Impairment {
+    /// Percentage of datagrams to drop
+    #[cfg_attr(
+        feature = "clap",
+        arg(long, default_value = "0", value_name = "PERCENT")
+    )]
+    pub loss: f64,
+
+    /// Delay added to every datagram, in milliseconds
+    #[cfg_attr(
+        feature = "clap",
+        arg(long, default_value = "0", value_name = "MS", value_parser = parse_millis)
+    )]
+    pub delay: Duration,
+
+    /// Random variation of the delay, up to this many milliseconds either way
+    #[cfg_attr(
+        feature = "clap",
+        arg(long, default_value = "0", value_name = "MS", value_parser = parse_millis)
+    )]
+    pub jitter: Duration,
+
+    /// Percentage of datagrams sent without the delay, ahead of earlier ones
+    #[cfg_attr(
+        feature = "clap",
+        arg(long, default_value = "0", value_name = "PERCENT")
+    )]
+    pub reorder: f64,
+
+    /// Percentage of datagrams sent twice
+    #[cfg_attr(
+        feature = "clap",
+        arg(long, default_value = "0", value_name = "PERCENT")
+    )]
+    pub duplicate: f64,
+
+    /// Seed for the random choices, for repeatable runs
+    #[cfg_attr(feature = "clap", arg(long, default_value = "1"))]
+    pub seed: u64,
+}
+
+impl Impairment {
+    /// True if the link passes everything through unchanged.
+    pub fn is_none(&self) -> bool {
+        self.loss <= 0.0
+            && self.delay.is_zero()
+            && self.jitter.is_zero()
+            && self.reorder <= 0.0
+            && self.duplicate <= 0.0
+    }
+}
+
+#[cfg(feature = "clap")]
+fn parse_millis(s: &str) -> Result<Duration, String> {
+    let ms: f64 = s.parse().map_err(|e| format!("{}", e))?;
+    Duration::try_from_secs_f64(ms / 1000.0).map_err(|e| format!("{}", e))
+}
+
+/// Counts of what the link has done.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Stats {
+    pub sent: u64,
+    pub dropped: u64,
+    pub duplicated: u64,
+    pub reordered: u64,
+    pub delivered: u64,
+}
+
+/// A datagram held by the link until its release time.
+struct Scheduled {
+    release: Duration,
+    // Keeps datagrams with the same release time in sending order
+    seq: u64,
+    dest: SocketAddr,
+    data: Vec<u8>,
+}
+
+impl PartialEq for Scheduled {
+    fn eq(&self, other: &Self) -> bool {
+        (self.release, self.seq) == (other.release, other.seq)
+    }
+}
+
+impl Eq for Scheduled {}
+
+impl PartialOrd for Scheduled {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Scheduled {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        (self.release, self.seq).cmp(&(other.release, other.seq))
+    }
+}
+
+/// One direction of an emulated link: decides the fate of each datagram and
+/// holds the delayed ones until they are due.
+pub struct Link<C: Clock> {
+    impairment: Impairment,
+    clock: C,
+    rng: Rng,
+    queue: BinaryHeap<Reverse<Scheduled>>,
+    seq: u64,
+    stats: Stats,
+}
+
+impl<C: Clock> Link<C> {
+    pub fn new(impairment: Impairment, clock: C) -> Self {
+        Self {
+            rng: Rng::new(impairment.seed),
+            impairment,
+            clock,
+            queue: BinaryHeap::new(),
+            seq: 0,
+            stats: Stats::default(),
+        }
+    }
+
+    pub fn impairment(&self) -> &Impairment {
+        &self.impairment
+    }
+
+    pub fn clock(&self) -> &C {
+        &self.clock
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.stats
+    }
+
+    /// Number of datagrams waiting for their release time.
+    pub fn queued(&self) -> usize {
+        self.queue.len()
+    }
+
+    /// Hands a datagram to the link. It becomes available from
+    /// [`Link::pop_ready`] when its delay has passed, unless it is lost.
+    pub fn push(&mut self, data: &[u8], dest: SocketAddr) {
+        self.stats.sent += 1;
+        if self.rng.chance(self.impairment.loss) {
+            self.stats.dropped += 1;
+            return;
+        }
+
+        let copies = if self.rng.chance(self.impairment.duplicate) {
+            self.stats.duplicated += 1;
+            2
+        } else {
+            1
+        };
+        let now = self.clock.now();
+        for _ in 0..copies {
+            let release = now + self.delay();
+            self.schedule(release, data.to_vec(), dest);
+        }
+    }
+
+    /// Delay of one datagram. Like netem, a reordered datagram skips the
+    /// delay, so it overtakes the ones sent before it.
+    fn delay(&mut self) -> Duration {
+        if !self.impairment.delay.is_zero() && self.rng.chance(self.impairment.reorder) {
+            self.stats.reordered += 1;
+            return Duration::ZERO;
+        }
+        let jitter = self.impairment.jitter.as_secs_f64();
+        let offset = (self.rng.next_f64() * 2.0 - 1.0) * jitter;
+        Duration::from_secs_f64((self.impairment.delay.as_secs_f64() + offset).max(0.0))
+    }
+
+    fn schedule(&mut self, release: Duration, data: Vec<u8>, dest: SocketAddr) {
+        self.seq += 1;
+        self.queue.push(Reverse(Scheduled {
+            release,
+            seq: self.seq,
+            dest,
+            data,
+        }));
+    }
+
+    /// Takes the next datagram whose release time has come.
+    pub fn pop_ready(&mut self) -> Option<(Vec<u8>, SocketAddr)> {
+        let Reverse(next) = self.queue.peek()?;
+        if next.release > self.clock.now() {
+            return None;
+        }
+        let Reverse(next) = self.queue.pop()?;
+        self.stats.delivered += 1;
+        Some((next.data, next.dest))
+    }
+
+    /// Time until the next datagram is due, e.g. for a poll timeout. None if
+    /// nothing is queued.
+    pub fn next_release(&self) -> Option<Duration> {
+        let Reverse(next) = self.queue.peek()?;
+        Some(next.release.saturating_sub(self.clock.now()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const N: u64 = 10_000;
+    const MS: Duration = Duration::from_millis(1);
+
+    fn dest() -> SocketAddr {
+        "127.0.0.1:9".parse().unwrap()
+    }
+
+    fn link(impairment: Impairment) -> Link<VirtualClock> {
+        Link::new(impairment, VirtualClock::new())
+    }
+
+    /// Sends N datagrams numbered from 0 at the current time.
+    fn push_all(link: &mut Link<VirtualClock>) {
+        for i in 0..N {
+            link.push(&i.to_be_bytes(), dest());
+        }
+    }
+
+    /// The numbers of the datagrams released so far, in release order.
+    fn drain(link: &mut Link<VirtualClock>) -> Vec<u64> {
+        std::iter::from_fn(|| link.pop_ready())
+            .map(|(data, _)| u64::from_be_bytes(data.try_into().unwrap()))
+            .collect()
+    }
+
+    /// Percentage of the datagrams sent.
+    fn percent(count: u64) -> f64 {
+        count as f64 * 100.0 / N as f64
+    }
+
+    #[test]
+    fn no_impairment_passes_everything_in_order() {
+        let mut link = link(Impairment::default());
+        push_all(&mut link);
+        assert_eq!(drain(&mut link), (0..N).collect::<Vec<_>>());
+        assert_eq!(
+            link.stats(),
+            Stats {
+                sent: N,
+                delivered: N,
+                ..Stats::default()
+            }
+        );
+    }
+
+    #[test]
+    fn loss() {
+        let mut link = link(Impairment {
+            loss: 10.0,
+            ..Impairment::default()
+        });
+        push_all(&mut link);
+        let delivered = drain(&mut link);
+        let stats = link.stats();
+        assert!((9.0..11.0).contains(&percent(stats.dropped)), "{:?}", stats);
+        assert_eq!(stats.delivered, N - stats.dropped);
+        assert_eq!(delivered.len() as u64, stats.delivered);
+        assert!(delivered.windows(2).all(|w| w[0] < w[1]), "in order");
+    }
+
+    #[test]
+    fn duplication() {
+        let mut link = link(Impairment {
+            duplicate: 20.0,
+            ..Impairment::default()
+        });
+        push_all(&mut link);
+        let delivered = drain(&mut link);
+        let stats = link.stats();
+        assert!(
+            (18.5..21.5).contains(&percent(stats.duplicated)),
+            "{:?}",
+            stats
+        );
+        assert_eq!(stats.delivered, N + stats.duplicated);
+        let twice = delivered.windows(2).filter(|w| w[0] == w[1]).count();
+        assert_eq!(twice as u64, stats.duplicated, "copies follow each other");
+    }
+
+    #[test]
+    fn same_seed_same_outcome() {
+        let impairment = Impairment {
+            loss: 5.0,
+            duplicate: 5.0,
+            delay: 20 * MS,
+            jitter: 10 * MS,
+            reorder: 5.0,
+            seed: 42,
+        };
+        let run = |impairment: &Impairment| {
+            let mut link = link(impairment.clone());
+            push_all(&mut link);
+            link.clock().advance(30 * MS);
+            (drain(&mut link), link.stats())
+        };
+        assert_eq!(run(&impairment), run(&impairment));
+        let other = Impairment {
+            seed: 43,
+            ..impairment.clone()
+        };
+        assert_ne!(run(&impairment).1, run(&other).1);
+    }
+
+    #[test]
+    fn delay_holds_datagrams_until_due() {
+        let mut link = link(Impairment {
+            delay: 50 * MS,
+            ..Impairment::default()
+        });
+        link.push(b"a", dest());
+        link.clock().advance(10 * MS);
+        link.push(b"b", dest());
+        assert_eq!(link.queued(), 2);
+        assert_eq!(link.next_release(), Some(40 * MS));
+
+        link.clock().advance(39 * MS);
+        assert_eq!(link.pop_ready(), None);
+        link.clock().advance(MS);
+        assert_eq!(link.pop_ready(), Some((b"a".to_vec(), dest())));
+        assert_eq!(link.pop_ready(), None);
+        assert_eq!(link.next_release(), Some(10 * MS));
+
+        link.clock().advance_to(100 * MS);
+        assert_eq!(link.pop_ready(), Some((b"b".to_vec(), dest())));
+        assert_eq!(link.next_release(), None);
+    }
+
+    #[test]
+    fn jitter_releases_by_time_within_bounds() {
+        let mut link = link(Impairment {
+            delay: 50 * MS,
+            jitter: 20 * MS,
+            ..Impairment::default()
+        });
+        push_all(&mut link);
+        link.clock().advance(30 * MS);
+        assert_eq!(link.pop_ready(), None, "none before delay - jitter");
+
+        // Step through the window a millisecond at a time: whatever is
+        // released was due by then
+        let mut released = Vec::new();
+        for _ in 30..70 {
+            link.clock().advance(MS);
+            released.extend(drain(&mut link));
+            if let Some(next) = link.next_release() {
+                assert!(next > Duration::ZERO);
+            }
+        }
+        assert_eq!(released.len() as u64, N, "all by delay + jitter");
+        let mut sorted = released.clone();
+        sorted.sort();
+        assert_eq!(sorted, (0..N).collect::<Vec<_>>());
+        assert_ne!(released, sorted, "jitter reorders");
+    }
+
+    #[test]
+    fn reordered_datagrams_overtake() {
+        let mut link = link(Impairment {
+            delay: 10 * MS,
+            reorder: 25.0,
+            ..Impairment::default()
+        });
+        push_all(&mut link);
+        let early = drain(&mut link);
+        let stats = link.stats();
+        assert!(
+            (23.5..26.5).contains(&percent(stats.reordered)),
+            "{:?}",
+            stats
+        );
+        assert_eq!(early.len() as u64, stats.reordered);
+
+        link.clock().advance(10 * MS);
+        let late = drain(&mut link);
+        assert_eq!(late.len() as u64, N - stats.reordered);
+        for batch in [&early, &late] {
+            assert!(batch.windows(2).all(|w| w[0] < w[1]), "in sending order");
+        }
+    }
+
+    #[test]
+    fn reorder_needs_a_delay() {
+        let mut link = link(Impairment {
+            reorder: 50.0,
+            ..Impairment::default()
+        });
+        push_all(&mut link);
+        assert_eq!(drain(&mut link), (0..N).collect::<Vec<_>>());
+        assert_eq!(link.stats().reordered, 0);
+    }
+
+    /// Keeps what is sent through it.
+    #[derive(Default)]
+    struct Recorder(Vec<Vec<u8>>);
+
+    impl Datagram for Recorder {
+        fn send_to(&mut self, buf: &[u8], _dest: SocketAddr) -> std::io::Result<usize> {
+            self.0.push(buf.to_vec());
+            Ok(buf.len())
+        }
+
+        fn recv_from(&mut self, _buf: &mut [u8]) -> std::io::Result<(usize, SocketAddr)> {
+            Err(std::io::ErrorKind::WouldBlock.into())
+        }
+    }
+
+    #[test]
+    fn impaired_socket_sends_on_flush() {
+        let clock = VirtualClock::new();
+        let impairment = Impairment {
+            delay: 5 * MS,
+            ..Impairment::default()
+        };
+        let mut socket = ImpairedSocket::new(Recorder::default(), impairment, clock.clone());
+        socket.send_to(b"one", dest()).unwrap();
+        assert!(socket.get_ref().0.is_empty());
+        assert_eq!(socket.poll_timeout(), Some(5 * MS));
+
+        clock.advance(5 * MS);
+        socket.send_to(b"two", dest()).unwrap();
+        assert_eq!(socket.get_ref().0, [b"one"]);
+        clock.advance(5 * MS);
+        assert_eq!(socket.flush().unwrap(), 1);
+        assert_eq!(socket.get_ref().0, [b"one", b"two"]);
+        assert_eq!(socket.poll_timeout(), None);
+    }
+}
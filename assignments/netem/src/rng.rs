@@ -0,0 +1,29 @@
+/// SplitMix64: small and fast, and the sequence for a seed never changes
+/// between versions, unlike with a general purpose random crate.
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Rng(seed)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in [0, 1)
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// True with the given probability in percent. Does not consume a random
+    /// number when the probability is zero, so that enabling one impairment
+    /// does not change the outcome of the others.
+    pub fn chance(&mut self, percent: f64) -> bool {
+        percent > 0.0 && self.next_f64() * 100.0 < percent
+    }
+}
@@ -0,0 +1,256 @@
+//! Classic BPF filters, the kind the kernel runs on a packet socket before a
+//! packet is queued to it. Programs come either from `tcpdump -ddd`, which
+//! compiles the full pcap filter language, or from [`compile`] for a small
+//! subset of it that is enough for most assignments.
+
+use std::{io, net::Ipv4Addr};
+
+use crate::pcap::LinkType;
+
+// Instruction classes, sizes, modes and operations of <linux/filter.h>
+const LD: u16 = 0x00;
+const LDX: u16 = 0x01;
+const ALU: u16 = 0x04;
+const JMP: u16 = 0x05;
+const RET: u16 = 0x06;
+const W: u16 = 0x00;
+const H: u16 = 0x08;
+const B: u16 = 0x10;
+const ABS: u16 = 0x20;
+const IND: u16 = 0x40;
+const MSH: u16 = 0xa0;
+const AND: u16 = 0x50;
+const JEQ: u16 = 0x10;
+const JSET: u16 = 0x40;
+const K: u16 = 0x00;
+
+const ETHERTYPE_IPV4: u32 = 0x0800;
+const ETHERTYPE_ARP: u32 = 0x0806;
+const ETHERNET_HEADER: u32 = 14;
+const PROTO_ICMP: u32 = 1;
+const PROTO_TCP: u32 = 6;
+const PROTO_UDP: u32 = 17;
+
+/// One instruction, laid out as `struct sock_filter`.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Insn {
+    pub code: u16,
+    pub jt: u8,
+    pub jf: u8,
+    pub k: u32,
+}
+
+impl Insn {
+    fn stmt(code: u16, k: u32) -> Self {
+        Insn {
+            code,
+            jt: 0,
+            jf: 0,
+            k,
+        }
+    }
+
+    fn jump(code: u16, k: u32, jt: u8, jf: u8) -> Self {
+        Insn { code, jt, jf, k }
+    }
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+/// Parses the output of `tcpdump -ddd`: the number of instructions on the
+/// first line, then one instruction per line as four decimal numbers.
+pub fn parse_ddd(text: &str) -> io::Result<Vec<Insn>> {
+    let mut lines = text.lines().map(str::trim).filter(|l| !l.is_empty());
+    let count: usize = lines
+        .next()
+        .ok_or_else(|| invalid("Empty filter program".to_string()))?
+        .parse()
+        .map_err(|e| invalid(format!("Invalid instruction count: {}", e)))?;
+
+    let program = lines
+        .map(|line| {
+            let fields: Vec<u32> = line
+                .split_whitespace()
+                .map(str::parse)
+                .collect::<Result<_, _>>()
+                .map_err(|e| invalid(format!("Invalid instruction {:?}: {}", line, e)))?;
+            match fields[..] {
+                [code, jt, jf, k] if code <= 0xffff && jt <= 0xff && jf <= 0xff => {
+                    Ok(Insn::jump(code as u16, k, jt as u8, jf as u8))
+                }
+                _ => Err(invalid(format!("Invalid instruction {:?}", line))),
+            }
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+    if program.len() != count {
+        return Err(invalid(format!(
+            "Filter program has {} instructions instead of {}",
+            program.len(),
+            count
+        )));
+    }
+    Ok(program)
+}
+
+/// One term of a filter expression.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Term {
+    Arp,
+    Ip,
+    Protocol(u32),
+    Host(Ipv4Addr),
+    Port(u16),
+}
+
+fn parse_terms(expression: &str) -> io::Result<Vec<Term>> {
+    let words: Vec<&str> = expression.split_whitespace().collect();
+    let mut terms = Vec::new();
+    for (i, term) in words.split(|&w| w == "and").enumerate() {
+        let term = match term {
+            ["arp"] => Term::Arp,
+            ["ip"] => Term::Ip,
+            ["icmp"] => Term::Protocol(PROTO_ICMP),
+            ["tcp"] => Term::Protocol(PROTO_TCP),
+            ["udp"] => Term::Protocol(PROTO_UDP),
+            ["host", address] => Term::Host(
+                address
+                    .parse()
+                    .map_err(|e| invalid(format!("Invalid host {:?}: {}", address, e)))?,
+            ),
+            ["port", port] => Term::Port(
+                port.parse()
+                    .map_err(|e| invalid(format!("Invalid port {:?}: {}", port, e)))?,
+            ),
+            [] if words.is_empty() => continue,
+            _ => {
+                return Err(invalid(format!(
+                    "Cannot parse term {} of filter {:?}",
+                    i + 1,
+                    expression
+                )))
+            }
+        };
+        terms.push(term);
+    }
+    Ok(terms)
+}
+
+/// Builds a program in which every check jumps to the final `ret #0` when
+/// it fails. The jump offsets to it are filled in by [`Builder::finish`].
+struct Builder {
+    program: Vec<Insn>,
+    // Jumps to the reject instruction, and whether they jump when true
+    rejects: Vec<(usize, bool)>,
+}
+
+impl Builder {
+    fn push(&mut self, insn: Insn) {
+        self.program.push(insn);
+    }
+
+    /// Conditional jump that goes on to the next instruction when the test
+    /// is true and rejects the packet otherwise.
+    fn expect(&mut self, code: u16, k: u32) {
+        self.rejects.push((self.program.len(), false));
+        self.push(Insn::jump(JMP | code | K, k, 0, 0));
+    }
+
+    /// Like [`Builder::expect`], but rejects when the test is true.
+    fn expect_not(&mut self, code: u16, k: u32) {
+        self.rejects.push((self.program.len(), true));
+        self.push(Insn::jump(JMP | code | K, k, 0, 0));
+    }
+
+    fn finish(mut self, snaplen: u32) -> io::Result<Vec<Insn>> {
+        self.push(Insn::stmt(RET | K, snaplen));
+        let reject = self.program.len();
+        self.push(Insn::stmt(RET | K, 0));
+        for (at, when_true) in self.rejects {
+            let offset = u8::try_from(reject - at - 1)
+                .map_err(|_| invalid("Filter expression is too long".to_string()))?;
+            match when_true {
+                true => self.program[at].jt = offset,
+                false => self.program[at].jf = offset,
+            }
+        }
+        Ok(self.program)
+    }
+}
+
+/// Compiles a filter expression for IPv4 packets on a link of the given type.
+/// An expression is a list of terms joined with `and`, each one of `ip`,
+/// `icmp`, `tcp`, `udp`, `host ADDRESS` (source or destination) or
+/// `port PORT` (source or destination, TCP or UDP). Alone, `arp` accepts the
+/// ARP packets of an Ethernet link instead. Accepted packets are cut to
+/// `snaplen` bytes. An empty expression accepts all packets.
+pub fn compile(expression: &str, link_type: LinkType, snaplen: u32) -> io::Result<Vec<Insn>> {
+    let terms = parse_terms(expression)?;
+    let mut builder = Builder {
+        program: Vec::new(),
+        rejects: Vec::new(),
+    };
+    if terms.is_empty() {
+        return builder.finish(snaplen);
+    }
+    if terms.contains(&Term::Arp) {
+        if terms.len() > 1 || link_type != LinkType::Ethernet {
+            return Err(invalid(format!(
+                "arp must be the only term, on an Ethernet link: {:?}",
+                expression
+            )));
+        }
+        builder.push(Insn::stmt(LD | H | ABS, 12));
+        builder.expect(JEQ, ETHERTYPE_ARP);
+        return builder.finish(snaplen);
+    }
+
+    // Offset of the IP header, after checking that there is one
+    let ip = match link_type {
+        LinkType::Ethernet => {
+            builder.push(Insn::stmt(LD | H | ABS, 12));
+            builder.expect(JEQ, ETHERTYPE_IPV4);
+            ETHERNET_HEADER
+        }
+        LinkType::Raw => {
+            builder.push(Insn::stmt(LD | B | ABS, 0));
+            builder.push(Insn::stmt(ALU | AND | K, 0xf0));
+            builder.expect(JEQ, 0x40);
+            0
+        }
+    };
+
+    for term in terms {
+        match term {
+            Term::Arp | Term::Ip => {}
+            Term::Protocol(protocol) => {
+                builder.push(Insn::stmt(LD | B | ABS, ip + 9));
+                builder.expect(JEQ, protocol);
+            }
+            Term::Host(address) => {
+                let address = u32::from(address);
+                builder.push(Insn::stmt(LD | W | ABS, ip + 12));
+                builder.push(Insn::jump(JMP | JEQ | K, address, 2, 0));
+                builder.push(Insn::stmt(LD | W | ABS, ip + 16));
+                builder.expect(JEQ, address);
+            }
+            Term::Port(port) => {
+                builder.push(Insn::stmt(LD | B | ABS, ip + 9));
+                builder.push(Insn::jump(JMP | JEQ | K, PROTO_TCP, 1, 0));
+                builder.expect(JEQ, PROTO_UDP);
+                // Only the first fragment has the ports
+                builder.push(Insn::stmt(LD | H | ABS, ip + 6));
+                builder.expect_not(JSET, 0x1fff);
+                // X = length of the IP header
+                builder.push(Insn::stmt(LDX | B | MSH, ip));
+                builder.push(Insn::stmt(LD | H | IND, ip));
+                builder.push(Insn::jump(JMP | JEQ | K, port as u32, 2, 0));
+                builder.push(Insn::stmt(LD | H | IND, ip + 2));
+                builder.expect(JEQ, port as u32);
+            }
+        }
+    }
+    builder.finish(snaplen)
+}
@@ -0,0 +1,223 @@
+//! The AF_PACKET socket. It is created without a protocol, so that no
+//! packets are queued to it until [`Capture::start`]: a filter attached
+//! before that applies to every packet the socket returns.
+
+use std::{
+    ffi::CString,
+    io, mem,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    time::{Duration, SystemTime},
+};
+
+use crate::{bpf::Insn, pcap::LinkType};
+
+/// One captured packet, the data of which is at the start of the buffer
+/// given to [`Capture::recv`].
+#[derive(Clone, Copy, Debug)]
+pub struct Packet {
+    /// Bytes in the buffer
+    pub len: usize,
+    /// Length of the packet on the wire, more than `len` if it was cut
+    pub original: usize,
+    pub timestamp: SystemTime,
+    /// Sent by this host rather than received
+    pub outgoing: bool,
+}
+
+/// A packet socket capturing on one interface.
+pub struct Capture {
+    fd: OwnedFd,
+    interface: String,
+    index: i32,
+    link_type: LinkType,
+    loopback: bool,
+}
+
+fn check(result: libc::c_int) -> io::Result<libc::c_int> {
+    match result {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(result),
+    }
+}
+
+fn setsockopt<T>(fd: RawFd, level: libc::c_int, name: libc::c_int, value: &T) -> io::Result<()> {
+    check(unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            value as *const T as *const libc::c_void,
+            mem::size_of::<T>() as libc::socklen_t,
+        )
+    })
+    .map(drop)
+}
+
+impl Capture {
+    /// Opens a packet socket on the interface. Requires root privileges or
+    /// the CAP_NET_RAW capability.
+    pub fn open(interface: &str) -> io::Result<Self> {
+        let name = CString::new(interface)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Invalid interface name"))?;
+        let index = unsafe { libc::if_nametoindex(name.as_ptr()) };
+        if index == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("No interface {}", interface),
+            ));
+        }
+
+        let fd = check(unsafe {
+            libc::socket(libc::AF_PACKET, libc::SOCK_RAW | libc::SOCK_CLOEXEC, 0)
+        })?;
+        let mut capture = Capture {
+            fd: unsafe { OwnedFd::from_raw_fd(fd) },
+            interface: interface.to_string(),
+            index: index as i32,
+            link_type: LinkType::Raw,
+            loopback: false,
+        };
+        capture.bind(0)?;
+
+        // The bound address tells the hardware type of the interface
+        let mut address: libc::sockaddr_ll = unsafe { mem::zeroed() };
+        let mut len = mem::size_of::<libc::sockaddr_ll>() as libc::socklen_t;
+        check(unsafe {
+            libc::getsockname(
+                capture.fd.as_raw_fd(),
+                &mut address as *mut _ as *mut libc::sockaddr,
+                &mut len,
+            )
+        })?;
+        capture.link_type = match address.sll_hatype {
+            libc::ARPHRD_ETHER | libc::ARPHRD_LOOPBACK => LinkType::Ethernet,
+            libc::ARPHRD_NONE => LinkType::Raw,
+            other => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    format!("Cannot capture on {}: hardware type {}", interface, other),
+                ))
+            }
+        };
+        capture.loopback = address.sll_hatype == libc::ARPHRD_LOOPBACK;
+        Ok(capture)
+    }
+
+    fn bind(&self, protocol: u16) -> io::Result<()> {
+        let mut address: libc::sockaddr_ll = unsafe { mem::zeroed() };
+        address.sll_family = libc::AF_PACKET as u16;
+        address.sll_protocol = protocol.to_be();
+        address.sll_ifindex = self.index;
+        check(unsafe {
+            libc::bind(
+                self.fd.as_raw_fd(),
+                &address as *const _ as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_ll>() as libc::socklen_t,
+            )
+        })
+        .map(drop)
+    }
+
+    pub fn interface(&self) -> &str {
+        &self.interface
+    }
+
+    /// The header the captured packets start with, which filters must expect.
+    pub fn link_type(&self) -> LinkType {
+        self.link_type
+    }
+
+    /// Attaches a classic BPF program. Packets for which it returns zero are
+    /// dropped in the kernel, and others are cut to the length it returns.
+    pub fn set_filter(&self, program: &[Insn]) -> io::Result<()> {
+        let len = u16::try_from(program.len()).map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidInput, "Filter program is too long")
+        })?;
+        let fprog = libc::sock_fprog {
+            len,
+            filter: program.as_ptr() as *mut libc::sock_filter,
+        };
+        setsockopt(
+            self.fd.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_ATTACH_FILTER,
+            &fprog,
+        )
+    }
+
+    /// Receives also the packets not addressed to this host.
+    pub fn set_promiscuous(&self) -> io::Result<()> {
+        let mut request: libc::packet_mreq = unsafe { mem::zeroed() };
+        request.mr_ifindex = self.index;
+        request.mr_type = libc::PACKET_MR_PROMISC as u16;
+        setsockopt(
+            self.fd.as_raw_fd(),
+            libc::SOL_PACKET,
+            libc::PACKET_ADD_MEMBERSHIP,
+            &request,
+        )
+    }
+
+    /// Makes [`Capture::recv`] fail with `WouldBlock` when no packet arrives
+    /// in this time.
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        let timeout = timeout.unwrap_or(Duration::ZERO);
+        let value = libc::timeval {
+            tv_sec: timeout.as_secs() as libc::time_t,
+            tv_usec: timeout.subsec_micros() as libc::suseconds_t,
+        };
+        setsockopt(
+            self.fd.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_RCVTIMEO,
+            &value,
+        )
+    }
+
+    /// Starts queueing packets of all protocols to the socket.
+    pub fn start(&self) -> io::Result<()> {
+        self.bind(libc::ETH_P_ALL as u16)
+    }
+
+    /// Receives the next packet into `buf`, cutting it to the size of the buffer.
+    pub fn recv(&self, buf: &mut [u8]) -> io::Result<Packet> {
+        loop {
+            let mut address: libc::sockaddr_ll = unsafe { mem::zeroed() };
+            let mut len = mem::size_of::<libc::sockaddr_ll>() as libc::socklen_t;
+            // With MSG_TRUNC the result is the length before cutting
+            let n = unsafe {
+                libc::recvfrom(
+                    self.fd.as_raw_fd(),
+                    buf.as_mut_ptr() as *mut libc::c_void,
+                    buf.len(),
+                    libc::MSG_TRUNC,
+                    &mut address as *mut _ as *mut libc::sockaddr,
+                    &mut len,
+                )
+            };
+            if n < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let timestamp = SystemTime::now();
+
+            let outgoing = address.sll_pkttype == libc::PACKET_OUTGOING;
+            // Loopback packets are seen both when sent and when received, so
+            // keep only one copy as tcpdump does
+            if outgoing && self.loopback {
+                continue;
+            }
+            return Ok(Packet {
+                len: (n as usize).min(buf.len()),
+                original: n as usize,
+                timestamp,
+                outgoing,
+            });
+        }
+    }
+}
+
+impl AsRawFd for Capture {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
@@ -0,0 +1,224 @@
+//! The `adnet capture` subcommand: tcpdump for the assignments, printing a
+//! line per packet or writing the packets to a pcap file. Filters beyond
+//! what [`bpf::compile`] understands can be compiled with tcpdump:
+//!
+//! ```text
+//! tcpdump -i eth0 -ddd 'tcp[tcpflags] & tcp-syn != 0' > syn.ddd
+//! adnet capture --interface eth0 --filter-file syn.ddd
+//! ```
+
+use std::{
+    error::Error,
+    fs, io,
+    net::IpAddr,
+    path::PathBuf,
+    time::{Duration, SystemTime},
+};
+
+use adnet_core::{
+    config::{self, ConfigArgs},
+    logging::{self, LogArgs},
+    shutdown::StopAfter,
+};
+use clap::Parser;
+use etherparse::{InternetSlice, SlicedPacket, TransportSlice};
+use serde::Deserialize;
+use tracing::info;
+
+use crate::{
+    bpf,
+    pcap::{self, LinkType, DEFAULT_SNAPLEN},
+    Capture, Packet,
+};
+
+// How often to check whether the capture should end while no packets arrive
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Captures packets on an interface, like a small tcpdump.
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+pub struct Args {
+    /// Interface to capture on, e.g. eth0 or tun0
+    #[arg(short, long)]
+    interface: Option<String>,
+
+    /// Filter expression: terms such as tcp, udp, icmp, host 10.0.0.1 or
+    /// port 80, joined with "and", or arp alone
+    #[arg(short, long)]
+    filter: Option<String>,
+
+    /// File with a filter program from `tcpdump -ddd`, instead of --filter
+    #[arg(long, conflicts_with = "filter")]
+    filter_file: Option<PathBuf>,
+
+    /// Write the packets to this pcap file instead of printing them
+    #[arg(short, long)]
+    write: Option<PathBuf>,
+
+    /// Stop after this many packets
+    #[arg(short, long)]
+    count: Option<u64>,
+
+    /// Stop after this many seconds
+    #[arg(short, long, value_parser = config::parse_secs)]
+    duration: Option<Duration>,
+
+    /// Bytes to keep of each packet [default: 262144]
+    #[arg(short, long)]
+    snaplen: Option<u32>,
+
+    /// Capture also packets not addressed to this host
+    #[arg(short, long)]
+    promiscuous: bool,
+
+    #[command(flatten)]
+    config: ConfigArgs,
+
+    #[command(flatten)]
+    log: LogArgs,
+}
+
+/// The [pktcap] section of the config file
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct FileConfig {
+    interface: Option<String>,
+    filter: Option<String>,
+    filter_file: Option<PathBuf>,
+    write: Option<PathBuf>,
+    count: Option<u64>,
+    #[serde(deserialize_with = "config::secs")]
+    duration: Option<Duration>,
+    snaplen: Option<u32>,
+    promiscuous: bool,
+}
+
+/// Runs the capture with the given arguments, as `adnet capture` does.
+pub fn run(args: Args) -> Result<(), Box<dyn Error>> {
+    logging::init(&args.log)?;
+
+    // Command line options take precedence over the config file
+    let file: FileConfig = args.config.section("pktcap")?;
+    let interface = args
+        .interface
+        .or(file.interface)
+        .ok_or("Interface is required (--interface)")?;
+    let snaplen = args.snaplen.or(file.snaplen).unwrap_or(DEFAULT_SNAPLEN);
+    let count = args.count.or(file.count);
+    let duration = args.duration.or(file.duration);
+
+    let capture = Capture::open(&interface)?;
+    let program = match (
+        args.filter.or(file.filter),
+        args.filter_file.or(file.filter_file),
+    ) {
+        (_, Some(path)) => bpf::parse_ddd(&fs::read_to_string(path)?)?,
+        (expression, None) => bpf::compile(
+            expression.as_deref().unwrap_or(""),
+            capture.link_type(),
+            snaplen,
+        )?,
+    };
+    capture.set_filter(&program)?;
+    if args.promiscuous || file.promiscuous {
+        capture.set_promiscuous()?;
+    }
+    capture.set_read_timeout(Some(POLL_INTERVAL))?;
+    capture.start()?;
+
+    let mut writer = match args.write.or(file.write) {
+        Some(path) => {
+            info!("Writing packets from {} to {}", interface, path.display());
+            Some(pcap::create(path, capture.link_type(), snaplen)?)
+        }
+        None => None,
+    };
+
+    let stop = StopAfter::new(count, duration);
+    let started = SystemTime::now();
+    let mut buf = vec![0u8; snaplen as usize];
+    let mut captured = 0;
+    while !stop.reached(captured) {
+        let packet = match capture.recv(&mut buf) {
+            Ok(packet) => packet,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+        captured += 1;
+        let data = &buf[..packet.len];
+        match &mut writer {
+            // Flush every packet, so that the file is complete when the
+            // capture is stopped with Ctrl-C
+            Some(writer) => {
+                writer.write(packet.timestamp, data, packet.original)?;
+                writer.flush()?;
+            }
+            None => println!("{}", summary(&packet, data, capture.link_type(), started)),
+        }
+    }
+    eprintln!("{} packets captured", captured);
+    Ok(())
+}
+
+/// One line about the packet: time since the start of the capture,
+/// direction, addresses and protocol.
+fn summary(packet: &Packet, data: &[u8], link_type: LinkType, started: SystemTime) -> String {
+    let elapsed = packet
+        .timestamp
+        .duration_since(started)
+        .unwrap_or(Duration::ZERO);
+    let direction = match packet.outgoing {
+        true => "Out",
+        false => "In ",
+    };
+    let sliced = match link_type {
+        LinkType::Ethernet => SlicedPacket::from_ethernet(data),
+        LinkType::Raw => SlicedPacket::from_ip(data),
+    };
+    let (source, destination): (IpAddr, IpAddr) =
+        match sliced.as_ref().ok().and_then(|s| s.net.as_ref()) {
+            Some(InternetSlice::Ipv4(ipv4)) => (
+                ipv4.header().source_addr().into(),
+                ipv4.header().destination_addr().into(),
+            ),
+            Some(InternetSlice::Ipv6(ipv6)) => (
+                ipv6.header().source_addr().into(),
+                ipv6.header().destination_addr().into(),
+            ),
+            None => {
+                return format!(
+                    "{:10.6} {} non-IP packet, {} bytes",
+                    elapsed.as_secs_f64(),
+                    direction,
+                    packet.original
+                )
+            }
+        };
+    let transport = match sliced.as_ref().ok().and_then(|s| s.transport.as_ref()) {
+        Some(TransportSlice::Tcp(tcp)) => format!(
+            "{}.{} > {}.{} TCP",
+            source,
+            tcp.source_port(),
+            destination,
+            tcp.destination_port()
+        ),
+        Some(TransportSlice::Udp(udp)) => format!(
+            "{}.{} > {}.{} UDP",
+            source,
+            udp.source_port(),
+            destination,
+            udp.destination_port()
+        ),
+        Some(TransportSlice::Icmpv4(_)) => format!("{} > {} ICMP", source, destination),
+        Some(TransportSlice::Icmpv6(_)) => format!("{} > {} ICMPv6", source, destination),
+        None => format!("{} > {}", source, destination),
+    };
+    format!(
+        "{:10.6} {} {}, {} bytes",
+        elapsed.as_secs_f64(),
+        direction,
+        transport,
+        packet.original
+    )
+}
@@ -0,0 +1,22 @@
+//! Packet capture for the assignments: an AF_PACKET socket on one interface
+//! ([`Capture`]), classic BPF filters that the kernel runs on it ([`bpf`]),
+//! and writing packets to pcap files for tcpdump and Wireshark ([`pcap`]).
+//! Programs that already have the packets at hand, such as task-tun, can use
+//! [`pcap::Writer`] alone, and [`record`] rebuilds the packets of a
+//! program's own traffic from the data it sends and receives.
+//!
+//! With the `cli` feature, the crate also has the options and main loop of
+//! the `adnet capture` subcommand, which is a thin wrapper around [`run`].
+
+pub mod bpf;
+mod capture;
+pub mod pcap;
+pub mod record;
+
+pub use capture::{Capture, Packet};
+
+#[cfg(feature = "cli")]
+mod cli;
+
+#[cfg(feature = "cli")]
+pub use cli::{run, Args};
@@ -0,0 +1,95 @@
+//! Writing the classic pcap file format that tcpdump and Wireshark read.
+//! Timestamps have microsecond precision and all fields are written in the
+//! byte order of the host, which readers detect from the magic number.
+
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+const MAGIC: u32 = 0xa1b2_c3d4;
+const VERSION_MAJOR: u16 = 2;
+const VERSION_MINOR: u16 = 4;
+
+/// Largest packet tcpdump captures by default
+pub const DEFAULT_SNAPLEN: u32 = 262_144;
+
+/// The link layer header the packets in a capture start with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LinkType {
+    /// Ethernet header, also on loopback devices
+    Ethernet,
+    /// No link layer header, the packet starts with the IPv4 or IPv6 header,
+    /// as on TUN devices
+    Raw,
+}
+
+impl LinkType {
+    /// The LINKTYPE_ value in the file header
+    pub fn code(self) -> u32 {
+        match self {
+            LinkType::Ethernet => 1,
+            LinkType::Raw => 101,
+        }
+    }
+}
+
+/// Writes packets to a pcap file, or to any other writer.
+pub struct Writer<W: Write> {
+    out: W,
+    snaplen: u32,
+}
+
+/// Creates a pcap file, replacing any existing file at `path`.
+pub fn create(
+    path: impl AsRef<Path>,
+    link_type: LinkType,
+    snaplen: u32,
+) -> io::Result<Writer<BufWriter<File>>> {
+    Writer::new(BufWriter::new(File::create(path)?), link_type, snaplen)
+}
+
+impl<W: Write> Writer<W> {
+    /// Writes the file header. Packets longer than `snaplen` are cut when written.
+    pub fn new(mut out: W, link_type: LinkType, snaplen: u32) -> io::Result<Self> {
+        let mut header = Vec::with_capacity(24);
+        header.extend_from_slice(&MAGIC.to_ne_bytes());
+        header.extend_from_slice(&VERSION_MAJOR.to_ne_bytes());
+        header.extend_from_slice(&VERSION_MINOR.to_ne_bytes());
+        // Time zone offset and timestamp accuracy, always zero in practice
+        header.extend_from_slice(&0i32.to_ne_bytes());
+        header.extend_from_slice(&0u32.to_ne_bytes());
+        header.extend_from_slice(&snaplen.to_ne_bytes());
+        header.extend_from_slice(&link_type.code().to_ne_bytes());
+        out.write_all(&header)?;
+        Ok(Writer { out, snaplen })
+    }
+
+    /// Writes one packet. `data` may already be cut shorter than the
+    /// `original` length of the packet on the wire.
+    pub fn write(&mut self, timestamp: SystemTime, data: &[u8], original: usize) -> io::Result<()> {
+        let since = timestamp
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or(Duration::ZERO);
+        let captured = data.len().min(self.snaplen as usize);
+        let original = original.max(data.len());
+
+        let mut header = [0u8; 16];
+        header[0..4].copy_from_slice(&(since.as_secs() as u32).to_ne_bytes());
+        header[4..8].copy_from_slice(&since.subsec_micros().to_ne_bytes());
+        header[8..12].copy_from_slice(&(captured as u32).to_ne_bytes());
+        header[12..16].copy_from_slice(&(original as u32).to_ne_bytes());
+        self.out.write_all(&header)?;
+        self.out.write_all(&data[..captured])
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
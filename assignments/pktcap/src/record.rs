@@ -0,0 +1,411 @@
+//! Recording the traffic of the program itself, without a packet socket and
+//! so without root privileges. The program tells a [`Recorder`] what it sends
+//! and receives, and the recorder writes the IP packets that carried it to a
+//! pcap file of link type [`LinkType::Raw`]:
+//!
+//! ```no_run
+//! # use std::net::TcpStream;
+//! # use std::io::Write;
+//! # use pktcap::record::{Recorded, Recorder};
+//! let recorder = Recorder::create("out.pcap")?;
+//! let stream = TcpStream::connect("10.0.0.3:12345")?;
+//! let mut stream = Recorded::connected(stream, Some(&recorder))?;
+//! stream.write_all(b"TASK-CLI secret\n")?;
+//! drop(stream);
+//! recorder.finish()?;
+//! # Ok::<(), std::io::Error>(())
+//! ```
+//!
+//! UDP datagrams are written as they were sent. TCP connections are rebuilt
+//! from the data: a handshake at the start, segments of at most
+//! [`MAX_SEGMENT`] bytes each acknowledged at once, and FINs when the
+//! recording is dropped. What the kernel did underneath, such as
+//! retransmissions, window updates or the actual segment sizes, is not
+//! visible; for that, capture with [`crate::Capture`].
+
+use std::{
+    fs::File,
+    io::{self, BufWriter, Read, Write},
+    net::{IpAddr, Ipv6Addr, SocketAddr, TcpStream, UdpSocket},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard},
+    time::SystemTime,
+};
+
+use crate::pcap::{self, LinkType, Writer, DEFAULT_SNAPLEN};
+
+/// Largest TCP payload in a recorded segment, the MSS of Ethernet
+pub const MAX_SEGMENT: usize = 1460;
+
+const TTL: u8 = 64;
+const PROTOCOL_TCP: u8 = 6;
+const PROTOCOL_UDP: u8 = 17;
+// Window announced in every recorded segment
+const WINDOW: u16 = 65535;
+
+const FIN: u8 = 0x01;
+const SYN: u8 = 0x02;
+const PSH: u8 = 0x08;
+const ACK: u8 = 0x10;
+
+/// Options of the programs that can record their own traffic.
+#[cfg(feature = "args")]
+#[derive(clap::Args, Debug, Clone, Default)]
+pub struct RecordArgs {
+    /// Record the traffic of this program to a pcap file, without root privileges
+    #[arg(long, global = true, value_name = "FILE")]
+    pub pcap: Option<PathBuf>,
+}
+
+#[cfg(feature = "args")]
+impl RecordArgs {
+    /// Recorder writing to the file given with `--pcap`, if any.
+    pub fn recorder(&self) -> io::Result<Option<Recorder>> {
+        self.pcap.as_ref().map(Recorder::create).transpose()
+    }
+}
+
+/// Writes the packets of the program to a pcap file. Clones write to the
+/// same file, so that every connection can have its own.
+#[derive(Clone)]
+pub struct Recorder(Arc<Mutex<Output>>);
+
+struct Output {
+    writer: Writer<BufWriter<File>>,
+    path: PathBuf,
+    packets: u64,
+    // IPv4 identification of the next packet
+    id: u16,
+    // First error in writing, returned by `finish`
+    error: Option<io::Error>,
+}
+
+impl Recorder {
+    /// Creates the pcap file, replacing any existing file at `path`.
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        let writer = pcap::create(path, LinkType::Raw, DEFAULT_SNAPLEN).map_err(|e| {
+            io::Error::new(e.kind(), format!("Cannot create {}: {}", path.display(), e))
+        })?;
+        Ok(Recorder(Arc::new(Mutex::new(Output {
+            writer,
+            path: path.to_path_buf(),
+            packets: 0,
+            id: 0,
+            error: None,
+        }))))
+    }
+
+    fn output(&self) -> MutexGuard<'_, Output> {
+        self.0.lock().unwrap()
+    }
+
+    /// Path of the pcap file
+    pub fn path(&self) -> PathBuf {
+        self.output().path.clone()
+    }
+
+    /// Number of packets written so far
+    pub fn packets(&self) -> u64 {
+        self.output().packets
+    }
+
+    /// Records a UDP datagram.
+    pub fn udp(&self, from: SocketAddr, to: SocketAddr, payload: &[u8]) {
+        let mut datagram = Vec::with_capacity(8 + payload.len());
+        datagram.extend_from_slice(&from.port().to_be_bytes());
+        datagram.extend_from_slice(&to.port().to_be_bytes());
+        datagram.extend_from_slice(&((8 + payload.len()) as u16).to_be_bytes());
+        datagram.extend_from_slice(&[0, 0]);
+        datagram.extend_from_slice(payload);
+        self.write(from.ip(), to.ip(), PROTOCOL_UDP, datagram, 6);
+    }
+
+    /// Starts the recording of a connection the program opened, with the
+    /// handshake sent from `local`.
+    pub fn connect(&self, local: SocketAddr, peer: SocketAddr) -> TcpRecording {
+        let mut recording = TcpRecording::new(self.clone(), local, peer);
+        recording.handshake(true);
+        recording
+    }
+
+    /// Starts the recording of a connection the program accepted, with the
+    /// handshake sent from `peer`.
+    pub fn accept(&self, local: SocketAddr, peer: SocketAddr) -> TcpRecording {
+        let mut recording = TcpRecording::new(self.clone(), local, peer);
+        recording.handshake(false);
+        recording
+    }
+
+    /// Flushes the file, and returns the first error in writing it, if any.
+    /// Recording goes on after errors, so that they do not disturb the
+    /// program, and only this reports them.
+    pub fn finish(&self) -> io::Result<()> {
+        let mut output = self.output();
+        let flushed = output.writer.flush();
+        match output.error.take() {
+            Some(e) => Err(e),
+            None => flushed,
+        }
+    }
+
+    /// Writes the IP packet with the given transport header and payload.
+    /// `checksum_at` is the offset of the checksum in `transport`.
+    fn write(
+        &self,
+        source: IpAddr,
+        destination: IpAddr,
+        protocol: u8,
+        mut transport: Vec<u8>,
+        checksum_at: usize,
+    ) {
+        let mut output = self.output();
+        let mut pseudo = Vec::with_capacity(40);
+        let mut packet = Vec::with_capacity(40 + transport.len());
+        match (source, destination) {
+            (IpAddr::V4(source), IpAddr::V4(destination)) => {
+                pseudo.extend_from_slice(&source.octets());
+                pseudo.extend_from_slice(&destination.octets());
+                pseudo.extend_from_slice(&[0, protocol]);
+                pseudo.extend_from_slice(&(transport.len() as u16).to_be_bytes());
+
+                packet.extend_from_slice(&[0x45, 0]);
+                packet.extend_from_slice(&((20 + transport.len()) as u16).to_be_bytes());
+                packet.extend_from_slice(&output.id.to_be_bytes());
+                // Don't fragment
+                packet.extend_from_slice(&[0x40, 0, TTL, protocol, 0, 0]);
+                packet.extend_from_slice(&source.octets());
+                packet.extend_from_slice(&destination.octets());
+                let header = checksum(&[&packet[..]]);
+                packet[10..12].copy_from_slice(&header.to_be_bytes());
+                output.id = output.id.wrapping_add(1);
+            }
+            // Mixed families only occur with IPv4-mapped addresses
+            (source, destination) => {
+                let (source, destination) = (to_v6(source), to_v6(destination));
+                pseudo.extend_from_slice(&source.octets());
+                pseudo.extend_from_slice(&destination.octets());
+                pseudo.extend_from_slice(&(transport.len() as u32).to_be_bytes());
+                pseudo.extend_from_slice(&[0, 0, 0, protocol]);
+
+                packet.extend_from_slice(&[0x60, 0, 0, 0]);
+                packet.extend_from_slice(&(transport.len() as u16).to_be_bytes());
+                packet.extend_from_slice(&[protocol, TTL]);
+                packet.extend_from_slice(&source.octets());
+                packet.extend_from_slice(&destination.octets());
+            }
+        }
+
+        let sum = match checksum(&[&pseudo[..], &transport[..]]) {
+            // Zero means no checksum in UDP, and is sent as all ones instead
+            0 if protocol == PROTOCOL_UDP => 0xffff,
+            sum => sum,
+        };
+        transport[checksum_at..checksum_at + 2].copy_from_slice(&sum.to_be_bytes());
+        packet.extend_from_slice(&transport);
+
+        let len = packet.len();
+        let result = output
+            .writer
+            .write(SystemTime::now(), &packet, len)
+            .and_then(|_| output.writer.flush());
+        match result {
+            Ok(()) => output.packets += 1,
+            Err(e) => {
+                output.error.get_or_insert(e);
+            }
+        }
+    }
+}
+
+fn to_v6(address: IpAddr) -> Ipv6Addr {
+    match address {
+        IpAddr::V4(address) => address.to_ipv6_mapped(),
+        IpAddr::V6(address) => address,
+    }
+}
+
+/// The Internet checksum of RFC 1071 over the concatenated parts.
+fn checksum(parts: &[&[u8]]) -> u16 {
+    let mut sum: u32 = 0;
+    let mut odd = None;
+    for &byte in parts.iter().flat_map(|part| part.iter()) {
+        match odd.take() {
+            None => odd = Some(byte),
+            Some(high) => sum += u16::from_be_bytes([high, byte]) as u32,
+        }
+    }
+    if let Some(high) = odd {
+        sum += u16::from_be_bytes([high, 0]) as u32;
+    }
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// The address the kernel sends from to `peer`, for sockets bound to the
+/// unspecified address. No packet is sent to find out.
+pub fn source_address(local: SocketAddr, peer: SocketAddr) -> SocketAddr {
+    if !local.ip().is_unspecified() {
+        return local;
+    }
+    let probe = UdpSocket::bind(SocketAddr::new(local.ip(), 0))
+        .and_then(|probe| probe.connect(peer).map(|_| probe))
+        .and_then(|probe| probe.local_addr());
+    match probe {
+        Ok(address) => SocketAddr::new(address.ip(), local.port()),
+        Err(_) => local,
+    }
+}
+
+/// One TCP connection in the recording. The FINs are recorded when it is
+/// dropped.
+pub struct TcpRecording {
+    recorder: Recorder,
+    local: SocketAddr,
+    peer: SocketAddr,
+    // Next sequence numbers of both ends
+    local_seq: u32,
+    peer_seq: u32,
+    closed: bool,
+}
+
+impl TcpRecording {
+    fn new(recorder: Recorder, local: SocketAddr, peer: SocketAddr) -> Self {
+        TcpRecording {
+            recorder,
+            local,
+            peer,
+            local_seq: 0,
+            peer_seq: 0,
+            closed: false,
+        }
+    }
+
+    fn handshake(&mut self, outgoing: bool) {
+        self.segment(outgoing, SYN, &[]);
+        self.segment(!outgoing, SYN | ACK, &[]);
+        self.segment(outgoing, ACK, &[]);
+    }
+
+    /// Records data the program sent, and its acknowledgement.
+    pub fn sent(&mut self, data: &[u8]) {
+        self.data(true, data);
+    }
+
+    /// Records data the program received, and its acknowledgement.
+    pub fn received(&mut self, data: &[u8]) {
+        self.data(false, data);
+    }
+
+    fn data(&mut self, outgoing: bool, data: &[u8]) {
+        for chunk in data.chunks(MAX_SEGMENT) {
+            self.segment(outgoing, PSH | ACK, chunk);
+            self.segment(!outgoing, ACK, &[]);
+        }
+    }
+
+    /// Records the closing of the connection by the program. Does nothing
+    /// after the first call.
+    pub fn close(&mut self) {
+        if std::mem::replace(&mut self.closed, true) {
+            return;
+        }
+        self.segment(true, FIN | ACK, &[]);
+        self.segment(false, FIN | ACK, &[]);
+        self.segment(true, ACK, &[]);
+    }
+
+    /// Records one segment, from the program if `outgoing`, and advances
+    /// the sequence number of the sender.
+    fn segment(&mut self, outgoing: bool, flags: u8, payload: &[u8]) {
+        let (from, to, seq, ack) = match outgoing {
+            true => (self.local, self.peer, self.local_seq, self.peer_seq),
+            false => (self.peer, self.local, self.peer_seq, self.local_seq),
+        };
+        let mut segment = Vec::with_capacity(20 + payload.len());
+        segment.extend_from_slice(&from.port().to_be_bytes());
+        segment.extend_from_slice(&to.port().to_be_bytes());
+        segment.extend_from_slice(&seq.to_be_bytes());
+        let ack = if flags & ACK != 0 { ack } else { 0 };
+        segment.extend_from_slice(&ack.to_be_bytes());
+        // Header of five 32-bit words, no options
+        segment.extend_from_slice(&[5 << 4, flags]);
+        segment.extend_from_slice(&WINDOW.to_be_bytes());
+        // Checksum and urgent pointer
+        segment.extend_from_slice(&[0, 0, 0, 0]);
+        segment.extend_from_slice(payload);
+        self.recorder
+            .write(from.ip(), to.ip(), PROTOCOL_TCP, segment, 16);
+
+        // SYN and FIN take up a sequence number like a byte of data
+        let advance = payload.len() as u32 + (flags & (SYN | FIN) != 0) as u32;
+        match outgoing {
+            true => self.local_seq = self.local_seq.wrapping_add(advance),
+            false => self.peer_seq = self.peer_seq.wrapping_add(advance),
+        }
+    }
+}
+
+impl Drop for TcpRecording {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+/// A stream whose reads and writes are recorded, if it has a recording.
+pub struct Recorded<S> {
+    inner: S,
+    recording: Option<TcpRecording>,
+}
+
+impl<S> Recorded<S> {
+    pub fn new(inner: S, recording: Option<TcpRecording>) -> Self {
+        Recorded { inner, recording }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+}
+
+impl Recorded<TcpStream> {
+    /// Wraps a connection the program opened, recorded by `recorder` if
+    /// there is one.
+    pub fn connected(stream: TcpStream, recorder: Option<&Recorder>) -> io::Result<Self> {
+        let recording = match recorder {
+            Some(recorder) => Some(recorder.connect(stream.local_addr()?, stream.peer_addr()?)),
+            None => None,
+        };
+        Ok(Recorded::new(stream, recording))
+    }
+}
+
+impl<S: Read> Read for Recorded<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if let Some(recording) = &mut self.recording {
+            recording.received(&buf[..n]);
+        }
+        Ok(n)
+    }
+}
+
+impl<S: Write> Write for Recorded<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        if let Some(recording) = &mut self.recording {
+            recording.sent(&buf[..n]);
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
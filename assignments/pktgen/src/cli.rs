@@ -0,0 +1,229 @@
+//! The `adnet gen` subcommand: a traffic generator that sends crafted UDP,
+//! TCP or ICMP packets on an interface at a fixed rate, e.g. to check the
+//! counters of the XDP program without the applications of the assignment:
+//!
+//! ```text
+//! adnet gen 192.168.76.1 --interface veth1 --protocol tcp --port 80 --count 10
+//! adnet gen 10.100.0.2 --interface tun0 --protocol udp --port 443 --rate 1000
+//! ```
+
+use std::{
+    error::Error,
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+use adnet_core::{
+    config::{self, ConfigArgs},
+    logging::{self, LogArgs},
+    shutdown::{self, StopAfter},
+};
+use clap::{Parser, ValueEnum};
+use serde::Deserialize;
+use tracing::{debug, info};
+
+use crate::{
+    packet::{self, Mac, BROADCAST},
+    Injector, Spec, TcpFlags, Transport,
+};
+
+const DEFAULT_RATE: f64 = 10.0;
+const DEFAULT_PORT: u16 = 9;
+const DEFAULT_SOURCE_PORT: u16 = 40000;
+const DEFAULT_SIZE: usize = 32;
+const DEFAULT_TTL: u8 = 64;
+
+#[derive(Clone, Copy, Debug, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+enum Protocol {
+    Udp,
+    Tcp,
+    Icmp,
+}
+
+/// Sends crafted packets on an interface at a given rate.
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+pub struct Args {
+    /// Destination address of the packets
+    destination: Option<IpAddr>,
+
+    /// Interface to send on, e.g. veth0 or tun0
+    #[arg(short, long)]
+    interface: Option<String>,
+
+    /// Source address [default: the interface's]
+    #[arg(short = 'S', long)]
+    source: Option<IpAddr>,
+
+    /// Transport of the packets [default: udp]
+    #[arg(short, long)]
+    protocol: Option<Protocol>,
+
+    /// Destination port of UDP and TCP [default: 9]
+    #[arg(long)]
+    port: Option<u16>,
+
+    /// Source port of UDP and TCP [default: 40000]
+    #[arg(long)]
+    source_port: Option<u16>,
+
+    /// TCP flags as letters, e.g. S, SA or PA [default: S]
+    #[arg(long)]
+    flags: Option<TcpFlags>,
+
+    /// Bytes of payload in each packet [default: 32]
+    #[arg(short, long)]
+    size: Option<usize>,
+
+    /// TTL or hop limit [default: 64]
+    #[arg(long)]
+    ttl: Option<u8>,
+
+    /// Destination MAC address on Ethernet interfaces [default:
+    /// ff:ff:ff:ff:ff:ff]
+    #[arg(long, value_parser = packet::parse_mac)]
+    mac: Option<Mac>,
+
+    /// Packets per second [default: 10]
+    #[arg(short, long)]
+    rate: Option<f64>,
+
+    /// Stop after this many packets
+    #[arg(short, long)]
+    count: Option<u64>,
+
+    /// Stop after this many seconds
+    #[arg(short, long, value_parser = config::parse_secs)]
+    duration: Option<Duration>,
+
+    #[command(flatten)]
+    config: ConfigArgs,
+
+    #[command(flatten)]
+    log: LogArgs,
+}
+
+/// The [pktgen] section of the config file
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct FileConfig {
+    destination: Option<IpAddr>,
+    interface: Option<String>,
+    source: Option<IpAddr>,
+    protocol: Option<Protocol>,
+    port: Option<u16>,
+    source_port: Option<u16>,
+    flags: Option<String>,
+    size: Option<usize>,
+    ttl: Option<u8>,
+    mac: Option<String>,
+    rate: Option<f64>,
+    count: Option<u64>,
+    #[serde(deserialize_with = "config::secs")]
+    duration: Option<Duration>,
+}
+
+/// Runs the generator with the given arguments, as `adnet gen` does.
+pub fn run(args: Args) -> Result<(), Box<dyn Error>> {
+    logging::init(&args.log)?;
+
+    // Command line options take precedence over the config file
+    let file: FileConfig = args.config.section("pktgen")?;
+    let interface = args
+        .interface
+        .or(file.interface)
+        .ok_or("Interface is required (--interface)")?;
+    let destination = args
+        .destination
+        .or(file.destination)
+        .ok_or("Destination address is required")?;
+    let flags = match (args.flags, file.flags) {
+        (Some(flags), _) => flags,
+        (None, Some(flags)) => flags.parse()?,
+        (None, None) => TcpFlags::SYN,
+    };
+    let mac = match (args.mac, file.mac) {
+        (Some(mac), _) => mac,
+        (None, Some(mac)) => packet::parse_mac(&mac)?,
+        (None, None) => BROADCAST,
+    };
+    let rate = args.rate.or(file.rate).unwrap_or(DEFAULT_RATE);
+    if !(rate > 0.0 && rate.is_finite()) {
+        return Err(format!("Invalid rate {}", rate).into());
+    }
+    let count = args.count.or(file.count);
+    let duration = args.duration.or(file.duration);
+
+    let injector = Injector::open(&interface)?;
+    let source = match args.source.or(file.source) {
+        Some(source) => source,
+        None => injector
+            .address(destination.is_ipv6())?
+            .ok_or_else(|| format!("No address on {}, give one with --source", interface))?,
+    };
+    let port = args.port.or(file.port).unwrap_or(DEFAULT_PORT);
+    let source_port = args
+        .source_port
+        .or(file.source_port)
+        .unwrap_or(DEFAULT_SOURCE_PORT);
+    let transport = match args.protocol.or(file.protocol).unwrap_or(Protocol::Udp) {
+        Protocol::Udp => Transport::Udp {
+            source: source_port,
+            destination: port,
+        },
+        Protocol::Tcp => Transport::Tcp {
+            source: source_port,
+            destination: port,
+            seq: 0,
+            ack: 0,
+            flags,
+        },
+        Protocol::Icmp => Transport::Echo {
+            id: std::process::id() as u16,
+            seq: 0,
+        },
+    };
+    let mut spec = Spec::new(source, destination, transport);
+    spec.ttl = args.ttl.or(file.ttl).unwrap_or(DEFAULT_TTL);
+    if injector.is_ethernet() {
+        spec = spec.with_ethernet(injector.mac(), mac);
+    }
+    let size = args.size.or(file.size).unwrap_or(DEFAULT_SIZE);
+    let payload: Vec<u8> = (0..size).map(|i| i as u8).collect();
+    // Fails early on a payload too large for the packet
+    spec.build(&payload)?;
+
+    let shutdown = shutdown::install()?;
+    let interval = Duration::from_secs_f64(1.0 / rate);
+    info!(
+        "Sending {:?} from {} to {} on {}, {} per second",
+        transport, source, destination, interface, rate
+    );
+
+    let start = Instant::now();
+    let stop = StopAfter::new(count, duration);
+    let mut sent = 0u64;
+    let mut bytes = 0u64;
+    while !stop.reached(sent) {
+        // Each packet has its own time, so that slow sends do not lower the rate
+        let due = start + interval.mul_f64(sent as f64);
+        if shutdown.wait_timeout(due.saturating_duration_since(Instant::now())) {
+            break;
+        }
+        if let Transport::Echo { seq, .. } = &mut spec.transport {
+            *seq = sent as u16;
+        }
+        let packet = spec.build(&payload)?;
+        bytes += injector.send(&packet)? as u64;
+        sent += 1;
+        debug!("Sent packet {} of {} bytes", sent, packet.len());
+    }
+    eprintln!(
+        "{} packets sent, {} bytes in {:.3} s",
+        sent,
+        bytes,
+        start.elapsed().as_secs_f64()
+    );
+    Ok(())
+}
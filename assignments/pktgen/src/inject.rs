@@ -0,0 +1,175 @@
+//! The AF_PACKET socket that sends the packets. It is bound with no
+//! protocol, so that no packets are queued to it, and sends each packet as
+//! it is: with the Ethernet header on Ethernet and loopback interfaces, and
+//! as a bare IP packet on TUN devices.
+
+use std::{
+    ffi::{CStr, CString},
+    io, mem,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    ptr,
+};
+
+use crate::packet::Mac;
+
+/// A packet socket sending on one interface.
+pub struct Injector {
+    fd: OwnedFd,
+    interface: String,
+    index: i32,
+    ethernet: bool,
+    mac: Mac,
+}
+
+fn check(result: libc::c_int) -> io::Result<libc::c_int> {
+    match result {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(result),
+    }
+}
+
+impl Injector {
+    /// Opens a packet socket on the interface. Requires root privileges or
+    /// the CAP_NET_RAW capability.
+    pub fn open(interface: &str) -> io::Result<Self> {
+        let name = CString::new(interface)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Invalid interface name"))?;
+        let index = unsafe { libc::if_nametoindex(name.as_ptr()) };
+        if index == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("No interface {}", interface),
+            ));
+        }
+
+        let fd = check(unsafe {
+            libc::socket(libc::AF_PACKET, libc::SOCK_RAW | libc::SOCK_CLOEXEC, 0)
+        })?;
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        let mut address: libc::sockaddr_ll = unsafe { mem::zeroed() };
+        address.sll_family = libc::AF_PACKET as u16;
+        address.sll_ifindex = index as i32;
+        check(unsafe {
+            libc::bind(
+                fd.as_raw_fd(),
+                &address as *const _ as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_ll>() as libc::socklen_t,
+            )
+        })?;
+
+        // The bound address tells the hardware type and address of the interface
+        let mut len = mem::size_of::<libc::sockaddr_ll>() as libc::socklen_t;
+        check(unsafe {
+            libc::getsockname(
+                fd.as_raw_fd(),
+                &mut address as *mut _ as *mut libc::sockaddr,
+                &mut len,
+            )
+        })?;
+        let ethernet = match address.sll_hatype {
+            libc::ARPHRD_ETHER | libc::ARPHRD_LOOPBACK => true,
+            libc::ARPHRD_NONE => false,
+            other => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    format!("Cannot send on {}: hardware type {}", interface, other),
+                ))
+            }
+        };
+        let mut mac = [0u8; 6];
+        if address.sll_halen == 6 {
+            mac.copy_from_slice(&address.sll_addr[..6]);
+        }
+        Ok(Injector {
+            fd,
+            interface: interface.to_string(),
+            index: index as i32,
+            ethernet,
+            mac,
+        })
+    }
+
+    pub fn interface(&self) -> &str {
+        &self.interface
+    }
+
+    /// Whether the packets must start with an Ethernet header. TUN devices
+    /// take bare IP packets.
+    pub fn is_ethernet(&self) -> bool {
+        self.ethernet
+    }
+
+    /// The hardware address of the interface, zeros if it has none.
+    pub fn mac(&self) -> Mac {
+        self.mac
+    }
+
+    /// The first address of the given version on the interface, the usual
+    /// source of the packets.
+    pub fn address(&self, ipv6: bool) -> io::Result<Option<IpAddr>> {
+        let mut addresses: *mut libc::ifaddrs = ptr::null_mut();
+        check(unsafe { libc::getifaddrs(&mut addresses) })?;
+        let mut found = None;
+        let mut cursor = addresses;
+        while !cursor.is_null() && found.is_none() {
+            let entry = unsafe { &*cursor };
+            cursor = entry.ifa_next;
+            if entry.ifa_addr.is_null()
+                || unsafe { CStr::from_ptr(entry.ifa_name) }.to_bytes() != self.interface.as_bytes()
+            {
+                continue;
+            }
+            found = match (unsafe { (*entry.ifa_addr).sa_family } as i32, ipv6) {
+                (libc::AF_INET, false) => {
+                    let address = unsafe { &*(entry.ifa_addr as *const libc::sockaddr_in) };
+                    Some(Ipv4Addr::from(u32::from_be(address.sin_addr.s_addr)).into())
+                }
+                (libc::AF_INET6, true) => {
+                    let address = unsafe { &*(entry.ifa_addr as *const libc::sockaddr_in6) };
+                    Some(Ipv6Addr::from(address.sin6_addr.s6_addr).into())
+                }
+                _ => None,
+            };
+        }
+        unsafe { libc::freeifaddrs(addresses) };
+        Ok(found)
+    }
+
+    /// Sends one packet, which must start with the header the interface
+    /// expects, see [`Injector::is_ethernet`].
+    pub fn send(&self, packet: &[u8]) -> io::Result<usize> {
+        // The kernel takes the protocol of the packet from the address
+        let protocol = match self.ethernet {
+            true => packet.get(12..14).map(|t| u16::from_be_bytes([t[0], t[1]])),
+            false => packet.first().map(|first| match first >> 4 {
+                6 => libc::ETH_P_IPV6 as u16,
+                _ => libc::ETH_P_IP as u16,
+            }),
+        }
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Packet is too short for its header",
+            )
+        })?;
+        let mut address: libc::sockaddr_ll = unsafe { mem::zeroed() };
+        address.sll_family = libc::AF_PACKET as u16;
+        address.sll_protocol = protocol.to_be();
+        address.sll_ifindex = self.index;
+        let n = unsafe {
+            libc::sendto(
+                self.fd.as_raw_fd(),
+                packet.as_ptr() as *const libc::c_void,
+                packet.len(),
+                0,
+                &address as *const _ as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_ll>() as libc::socklen_t,
+            )
+        };
+        match n {
+            -1 => Err(io::Error::last_os_error()),
+            n => Ok(n as usize),
+        }
+    }
+}
@@ -0,0 +1,22 @@
+//! Packet generation for the assignments: packets described by a [`Spec`]
+//! are built with the builders of etherparse, which fill in the lengths and
+//! checksums of every header, and an [`Injector`] sends them as they are on
+//! an interface through an AF_PACKET socket. Tests and benchmarks use the
+//! packets to feed the tunnel or an XDP program without a real application
+//! behind them.
+//!
+//! With the `cli` feature, the crate also has the options and main loop of
+//! the `adnet gen` subcommand, a traffic generator that is a thin wrapper
+//! around [`run`].
+
+mod inject;
+pub mod packet;
+
+pub use inject::Injector;
+pub use packet::{Ethernet, Spec, TcpFlags, Transport};
+
+#[cfg(feature = "cli")]
+mod cli;
+
+#[cfg(feature = "cli")]
+pub use cli::{run, Args};
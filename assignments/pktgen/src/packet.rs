@@ -0,0 +1,278 @@
+//! Packets built from a [`Spec`]. The headers come from the builders of
+//! etherparse, so the IP and transport checksums are correct and the packets
+//! pass the checks of the kernel and of XDP programs alike.
+
+use std::{fmt, io, net::IpAddr, str::FromStr};
+
+use etherparse::{IpHeaders, PacketBuilder, PacketBuilderStep};
+
+/// A hardware address.
+pub type Mac = [u8; 6];
+
+/// The Ethernet broadcast address, which every host on the link accepts.
+pub const BROADCAST: Mac = [0xff; 6];
+
+const DEFAULT_TTL: u8 = 64;
+const DEFAULT_WINDOW: u16 = 64240;
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+/// Parses a hardware address written as aa:bb:cc:dd:ee:ff.
+pub fn parse_mac(s: &str) -> Result<Mac, String> {
+    let octets: Vec<&str> = s.split(':').collect();
+    if octets.len() != 6 {
+        return Err(format!("Invalid MAC address {}", s));
+    }
+    let mut mac = [0u8; 6];
+    for (octet, text) in mac.iter_mut().zip(octets) {
+        *octet = u8::from_str_radix(text, 16).map_err(|_| format!("Invalid MAC address {}", s))?;
+    }
+    Ok(mac)
+}
+
+/// The Ethernet header of a frame. The EtherType follows from the IP version.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Ethernet {
+    pub source: Mac,
+    pub destination: Mac,
+}
+
+/// The flags of a TCP segment.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TcpFlags {
+    pub syn: bool,
+    pub ack: bool,
+    pub fin: bool,
+    pub rst: bool,
+    pub psh: bool,
+    pub urg: bool,
+}
+
+impl TcpFlags {
+    pub const SYN: TcpFlags = TcpFlags {
+        syn: true,
+        ack: false,
+        fin: false,
+        rst: false,
+        psh: false,
+        urg: false,
+    };
+}
+
+/// Flags as letters in the style of hping, e.g. "S" for a SYN or "PA" for
+/// data with an acknowledgment, in any case and order.
+impl FromStr for TcpFlags {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut flags = TcpFlags::default();
+        for letter in s.chars() {
+            let flag = match letter.to_ascii_uppercase() {
+                'S' => &mut flags.syn,
+                'A' => &mut flags.ack,
+                'F' => &mut flags.fin,
+                'R' => &mut flags.rst,
+                'P' => &mut flags.psh,
+                'U' => &mut flags.urg,
+                other => return Err(format!("Unknown TCP flag {} (use S, A, F, R, P, U)", other)),
+            };
+            *flag = true;
+        }
+        Ok(flags)
+    }
+}
+
+impl fmt::Display for TcpFlags {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (set, letter) in [
+            (self.syn, 'S'),
+            (self.ack, 'A'),
+            (self.fin, 'F'),
+            (self.rst, 'R'),
+            (self.psh, 'P'),
+            (self.urg, 'U'),
+        ] {
+            if set {
+                write!(f, "{}", letter)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The header after the IP header.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Transport {
+    Udp {
+        source: u16,
+        destination: u16,
+    },
+    Tcp {
+        source: u16,
+        destination: u16,
+        seq: u32,
+        /// Acknowledgment number, sent only with the ACK flag
+        ack: u32,
+        flags: TcpFlags,
+    },
+    /// An echo request, ICMP or ICMPv6 depending on the addresses
+    Echo {
+        id: u16,
+        seq: u16,
+    },
+}
+
+/// A packet to build: the optional Ethernet header, the IP addresses and the
+/// transport header. The payload is given to [`Spec::build`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Spec {
+    /// Ethernet header, or `None` for a bare IP packet as TUN devices take
+    pub ethernet: Option<Ethernet>,
+    pub source: IpAddr,
+    pub destination: IpAddr,
+    /// TTL of IPv4 or hop limit of IPv6
+    pub ttl: u8,
+    pub transport: Transport,
+}
+
+impl Spec {
+    /// A bare IP packet with a TTL of 64.
+    pub fn new(source: IpAddr, destination: IpAddr, transport: Transport) -> Self {
+        Spec {
+            ethernet: None,
+            source,
+            destination,
+            ttl: DEFAULT_TTL,
+            transport,
+        }
+    }
+
+    /// A UDP datagram without the Ethernet header.
+    pub fn udp(source: (IpAddr, u16), destination: (IpAddr, u16)) -> Self {
+        Self::new(
+            source.0,
+            destination.0,
+            Transport::Udp {
+                source: source.1,
+                destination: destination.1,
+            },
+        )
+    }
+
+    /// A TCP segment with the given flags, without the Ethernet header.
+    pub fn tcp(source: (IpAddr, u16), destination: (IpAddr, u16), flags: TcpFlags) -> Self {
+        Self::new(
+            source.0,
+            destination.0,
+            Transport::Tcp {
+                source: source.1,
+                destination: destination.1,
+                seq: 0,
+                ack: 0,
+                flags,
+            },
+        )
+    }
+
+    /// An ICMP echo request without the Ethernet header.
+    pub fn echo(source: IpAddr, destination: IpAddr, id: u16, seq: u16) -> Self {
+        Self::new(source, destination, Transport::Echo { id, seq })
+    }
+
+    /// The same packet in an Ethernet frame.
+    pub fn with_ethernet(self, source: Mac, destination: Mac) -> Self {
+        Spec {
+            ethernet: Some(Ethernet {
+                source,
+                destination,
+            }),
+            ..self
+        }
+    }
+
+    /// The headers up to IP. Both addresses must be of the same version.
+    fn ip(&self) -> io::Result<PacketBuilderStep<IpHeaders>> {
+        Ok(match (self.ethernet, self.source, self.destination) {
+            (Some(eth), IpAddr::V4(source), IpAddr::V4(destination)) => PacketBuilder::ethernet2(
+                eth.source,
+                eth.destination,
+            )
+            .ipv4(source.octets(), destination.octets(), self.ttl),
+            (Some(eth), IpAddr::V6(source), IpAddr::V6(destination)) => PacketBuilder::ethernet2(
+                eth.source,
+                eth.destination,
+            )
+            .ipv6(source.octets(), destination.octets(), self.ttl),
+            (None, IpAddr::V4(source), IpAddr::V4(destination)) => {
+                PacketBuilder::ipv4(source.octets(), destination.octets(), self.ttl)
+            }
+            (None, IpAddr::V6(source), IpAddr::V6(destination)) => {
+                PacketBuilder::ipv6(source.octets(), destination.octets(), self.ttl)
+            }
+            _ => {
+                return Err(invalid(format!(
+                    "Cannot send from {} to {}: both must be IPv4 or IPv6",
+                    self.source, self.destination
+                )))
+            }
+        })
+    }
+
+    /// Builds the packet with `payload` after the transport header, with
+    /// the lengths and checksums filled in.
+    pub fn build(&self, payload: &[u8]) -> io::Result<Vec<u8>> {
+        // The builders of each transport are different types
+        macro_rules! finish {
+            ($builder:expr) => {{
+                let builder = $builder;
+                let mut packet = Vec::with_capacity(builder.size(payload.len()));
+                builder
+                    .write(&mut packet, payload)
+                    .map_err(|e| invalid(e.to_string()))?;
+                Ok(packet)
+            }};
+        }
+
+        let ip = self.ip()?;
+        match self.transport {
+            Transport::Udp {
+                source,
+                destination,
+            } => finish!(ip.udp(source, destination)),
+            Transport::Tcp {
+                source,
+                destination,
+                seq,
+                ack,
+                flags,
+            } => {
+                let mut tcp = ip.tcp(source, destination, seq, DEFAULT_WINDOW);
+                if flags.syn {
+                    tcp = tcp.syn();
+                }
+                if flags.ack {
+                    tcp = tcp.ack(ack);
+                }
+                if flags.fin {
+                    tcp = tcp.fin();
+                }
+                if flags.rst {
+                    tcp = tcp.rst();
+                }
+                if flags.psh {
+                    tcp = tcp.psh();
+                }
+                if flags.urg {
+                    tcp = tcp.urg(0);
+                }
+                finish!(tcp)
+            }
+            Transport::Echo { id, seq } if self.source.is_ipv4() => {
+                finish!(ip.icmpv4_echo_request(id, seq))
+            }
+            Transport::Echo { id, seq } => finish!(ip.icmpv6_echo_request(id, seq)),
+        }
+    }
+}
@@ -0,0 +1,126 @@
+//! ARP packets for IPv4 over Ethernet (RFC 826), as they arrive on a packet
+//! socket with the Ethernet header in front:
+//!
+//! ```text
+//! | dst MAC (6) | src MAC (6) | type 0x0806 (2) |
+//! | htype 1 (2) | ptype 0x0800 (2) | hlen 6 | plen 4 | operation (2) |
+//! | sender MAC (6) | sender IP (4) | target MAC (6) | target IP (4) |
+//! ```
+
+use std::{fmt, net::Ipv4Addr};
+
+use serde::{Serialize, Serializer};
+
+const ETHERNET_HEADER: usize = 14;
+const ETHERTYPE_ARP: u16 = 0x0806;
+const ARP_LENGTH: usize = 28;
+
+/// An Ethernet address, printed as aa:bb:cc:dd:ee:ff.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Mac(pub [u8; 6]);
+
+impl fmt::Display for Mac {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(
+            f,
+            "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+            a, b, c, d, e, g
+        )
+    }
+}
+
+impl fmt::Debug for Mac {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl Serialize for Mac {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Operation {
+    Request,
+    Reply,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ArpPacket {
+    /// Source address of the Ethernet frame, which should be the sender's
+    pub source: Mac,
+    pub operation: Operation,
+    pub sender_mac: Mac,
+    pub sender_ip: Ipv4Addr,
+    pub target_mac: Mac,
+    pub target_ip: Ipv4Addr,
+}
+
+impl ArpPacket {
+    /// Parses an Ethernet frame, or returns `None` if it is not ARP for IPv4
+    /// over Ethernet.
+    pub fn parse(frame: &[u8]) -> Option<Self> {
+        if frame.len() < ETHERNET_HEADER + ARP_LENGTH {
+            return None;
+        }
+        let u16_at = |at: usize| u16::from_be_bytes([frame[at], frame[at + 1]]);
+        let mac_at = |at: usize| Mac(frame[at..at + 6].try_into().unwrap());
+        let ip_at =
+            |at: usize| Ipv4Addr::new(frame[at], frame[at + 1], frame[at + 2], frame[at + 3]);
+
+        if u16_at(12) != ETHERTYPE_ARP {
+            return None;
+        }
+        let arp = ETHERNET_HEADER;
+        // Ethernet hardware, IPv4 protocol, and their address lengths
+        if u16_at(arp) != 1
+            || u16_at(arp + 2) != 0x0800
+            || frame[arp + 4] != 6
+            || frame[arp + 5] != 4
+        {
+            return None;
+        }
+        let operation = match u16_at(arp + 6) {
+            1 => Operation::Request,
+            2 => Operation::Reply,
+            _ => return None,
+        };
+        Some(ArpPacket {
+            source: mac_at(6),
+            operation,
+            sender_mac: mac_at(arp + 8),
+            sender_ip: ip_at(arp + 14),
+            target_mac: mac_at(arp + 18),
+            target_ip: ip_at(arp + 24),
+        })
+    }
+
+    /// A probe of RFC 5227, which asks whether an address is in use before
+    /// taking it, and announces no mapping.
+    pub fn is_probe(&self) -> bool {
+        self.sender_ip.is_unspecified()
+    }
+
+    /// An announcement of the sender's own mapping that nobody asked for:
+    /// a request or reply for the sender's own address.
+    pub fn is_gratuitous(&self) -> bool {
+        !self.is_probe() && self.sender_ip == self.target_ip
+    }
+}
+
+impl fmt::Display for ArpPacket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.operation {
+            Operation::Request => write!(
+                f,
+                "who-has {} tell {} ({})",
+                self.target_ip, self.sender_ip, self.sender_mac
+            ),
+            Operation::Reply => write!(f, "{} is-at {}", self.sender_ip, self.sender_mac),
+        }
+    }
+}
@@ -0,0 +1,34 @@
+use std::io;
+
+use adnet_core::exit::{Code, HasCode};
+use thiserror::Error;
+
+/// Errors of task-arp. Each kind has its own exit code, see
+/// [`adnet_core::exit`].
+#[derive(Debug, Error)]
+pub enum ArpError {
+    /// Invalid or missing options
+    #[error("{0}")]
+    Usage(String),
+    /// The config file could not be read
+    #[error(transparent)]
+    Config(io::Error),
+    /// The packet socket could not be set up, e.g. without CAP_NET_RAW
+    #[error("Cannot capture on {interface}: {source}")]
+    Capture {
+        interface: String,
+        source: io::Error,
+    },
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+impl HasCode for ArpError {
+    fn exit_code(&self) -> Code {
+        match self {
+            ArpError::Usage(_) | ArpError::Config(_) => Code::Usage,
+            ArpError::Capture { source, .. } => source.exit_code(),
+            ArpError::Io(e) => e.exit_code(),
+        }
+    }
+}
@@ -0,0 +1,320 @@
+//! ARP monitor. It watches the ARP traffic of an interface on an AF_PACKET
+//! socket, learns the IP to MAC mapping of every sender, and reports
+//! addresses that move to another MAC address, ARP senders that differ from
+//! the Ethernet source, and storms of gratuitous ARP, which are the marks
+//! of ARP spoofing. Events are printed as lines of text, or as JSON lines
+//! for other programs to consume.
+//!
+//! The task-arp binary and the `adnet arp` subcommand are thin wrappers
+//! around [`run`].
+
+pub mod arp;
+mod error;
+pub mod table;
+
+use std::{
+    io,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use adnet_core::{
+    config::{self, ConfigArgs},
+    logging::{self, LogArgs},
+    metrics::{self, Counter, Gauge, MetricsArgs},
+    report::{Report, ReportArgs},
+    shutdown,
+};
+use arp::ArpPacket;
+use clap::Parser;
+use pktcap::{bpf, Capture};
+use serde::{Deserialize, Serialize};
+use table::{Event, Table};
+use tracing::{debug, info};
+
+pub use error::ArpError;
+
+const DEFAULT_STORM_THRESHOLD: usize = 10;
+const DEFAULT_STORM_WINDOW: Duration = Duration::from_secs(5);
+// Enough for the Ethernet header and an ARP packet, with room for padding
+const SNAPLEN: u32 = 64;
+// How often to check for Ctrl-C while no packets arrive
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Watches ARP traffic and alerts on conflicting mappings and gratuitous ARP storms.
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+pub struct Args {
+    /// Ethernet interface to watch, e.g. eth0
+    #[arg(short, long)]
+    interface: Option<String>,
+
+    /// Print events as JSON lines
+    #[arg(short, long)]
+    json: bool,
+
+    /// Print every ARP packet, not only the events
+    #[arg(long)]
+    packets: bool,
+
+    /// Gratuitous ARPs from one MAC address within the window that make a
+    /// storm [default: 10]
+    #[arg(long)]
+    storm_threshold: Option<usize>,
+
+    /// Seconds of the storm window [default: 5]
+    #[arg(long, value_parser = config::parse_secs)]
+    storm_window: Option<Duration>,
+
+    /// Watch also the ARP replies not addressed to this host
+    #[arg(short, long)]
+    promiscuous: bool,
+
+    /// Stop after this many seconds
+    #[arg(short, long, value_parser = config::parse_secs)]
+    duration: Option<Duration>,
+
+    #[command(flatten)]
+    config: ConfigArgs,
+
+    #[command(flatten)]
+    log: LogArgs,
+
+    #[command(flatten)]
+    metrics: MetricsArgs,
+
+    #[command(flatten)]
+    report: ReportArgs,
+}
+
+/// The [task-arp] section of the config file
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct FileConfig {
+    interface: Option<String>,
+    json: bool,
+    packets: bool,
+    storm_threshold: Option<usize>,
+    #[serde(deserialize_with = "config::secs")]
+    storm_window: Option<Duration>,
+    promiscuous: bool,
+    #[serde(deserialize_with = "config::secs")]
+    duration: Option<Duration>,
+}
+
+/// The monitor's metrics in the global registry.
+struct Metrics {
+    packets: Counter,
+    entries: Gauge,
+    conflicts: Counter,
+    mismatches: Counter,
+    storms: Counter,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        Self {
+            packets: metrics::counter("arp_packets_total", "ARP packets seen"),
+            entries: metrics::gauge("arp_table_entries", "Addresses in the IP to MAC table"),
+            conflicts: metrics::counter(
+                "arp_conflicts_total",
+                "Addresses that moved to another MAC address",
+            ),
+            mismatches: metrics::counter(
+                "arp_mismatches_total",
+                "ARP senders that differ from the Ethernet source",
+            ),
+            storms: metrics::counter("arp_storms_total", "Storms of gratuitous ARP"),
+        }
+    }
+
+    fn count(&self, event: &Event) {
+        match event {
+            Event::New { .. } => self.entries.inc(),
+            Event::Conflict { .. } => self.conflicts.inc(),
+            Event::Mismatch { .. } => self.mismatches.inc(),
+            Event::Storm { .. } => self.storms.inc(),
+        }
+    }
+
+    fn report(&self, report: &mut Report) {
+        report.detail("packets", self.packets.get());
+        report.detail("conflicts", self.conflicts.get());
+        report.detail("mismatches", self.mismatches.get());
+        report.detail("storms", self.storms.get());
+    }
+}
+
+/// An event as printed with `--json`.
+#[derive(Serialize)]
+struct Record<'a> {
+    /// Seconds since the Unix epoch
+    time: f64,
+    interface: &'a str,
+    #[serde(flatten)]
+    event: &'a Event,
+}
+
+/// One entry of the table in the report.
+#[derive(Serialize)]
+struct Mapping {
+    ip: String,
+    mac: String,
+    packets: u64,
+    changes: u64,
+}
+
+/// Runs the monitor with the given arguments, as the task-arp binary does.
+pub fn run(args: Args) -> Result<(), ArpError> {
+    logging::init(&args.log)?;
+    metrics::init(&args.metrics)?;
+    let report_args = args.report.clone();
+    let mut report = Report::new("task-arp");
+    let result = monitor(args, &mut report);
+    report_args.finish(&mut report, result)
+}
+
+fn monitor(args: Args, report: &mut Report) -> Result<(), ArpError> {
+    // Command line options take precedence over the config file
+    let file: FileConfig = args.config.section("task-arp").map_err(ArpError::Config)?;
+    let interface = args
+        .interface
+        .or(file.interface)
+        .ok_or_else(|| ArpError::Usage("Interface is required (--interface)".to_string()))?;
+    let json = args.json || file.json;
+    let packets = args.packets || file.packets;
+    let duration = args.duration.or(file.duration);
+    let mut table = Table::new(
+        args.storm_threshold
+            .or(file.storm_threshold)
+            .unwrap_or(DEFAULT_STORM_THRESHOLD),
+        args.storm_window
+            .or(file.storm_window)
+            .unwrap_or(DEFAULT_STORM_WINDOW),
+    );
+
+    let capture_error = |source| ArpError::Capture {
+        interface: interface.clone(),
+        source,
+    };
+    let capture = Capture::open(&interface).map_err(capture_error)?;
+    let program = bpf::compile("arp", capture.link_type(), SNAPLEN)
+        .map_err(|_| ArpError::Usage(format!("{} is not an Ethernet interface", interface)))?;
+    capture.set_filter(&program).map_err(capture_error)?;
+    if args.promiscuous || file.promiscuous {
+        capture.set_promiscuous().map_err(capture_error)?;
+    }
+    capture.set_read_timeout(Some(POLL_INTERVAL))?;
+    capture.start().map_err(capture_error)?;
+    info!("Watching ARP on {}", interface);
+    report.detail("interface", &interface);
+
+    let metrics = Metrics::new();
+    let shutdown = shutdown::install()?;
+    let start = Instant::now();
+    let mut buf = vec![0u8; SNAPLEN as usize];
+    while !shutdown.is_requested() && duration.is_none_or(|d| start.elapsed() < d) {
+        let packet = match capture.recv(&mut buf) {
+            Ok(packet) => packet,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+        let Some(arp) = ArpPacket::parse(&buf[..packet.len]) else {
+            debug!("Ignoring ARP packet that is not IPv4 over Ethernet");
+            continue;
+        };
+        metrics.packets.inc();
+        let elapsed = start.elapsed();
+        if packets && !json {
+            println!("{:10.3} {}", elapsed.as_secs_f64(), arp);
+        }
+        for event in table.observe(&arp, Instant::now()) {
+            metrics.count(&event);
+            print_event(&event, &interface, elapsed, json)?;
+        }
+    }
+
+    if !json {
+        print_table(&table);
+    }
+    metrics.report(report);
+    report.detail(
+        "table",
+        table
+            .entries()
+            .map(|(ip, entry)| Mapping {
+                ip: ip.to_string(),
+                mac: entry.mac.to_string(),
+                packets: entry.packets,
+                changes: entry.changes,
+            })
+            .collect::<Vec<_>>(),
+    );
+    Ok(())
+}
+
+fn print_event(event: &Event, interface: &str, elapsed: Duration, json: bool) -> io::Result<()> {
+    if json {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+        let record = Record {
+            time,
+            interface,
+            event,
+        };
+        println!("{}", serde_json::to_string(&record)?);
+        return Ok(());
+    }
+    let text = match event {
+        Event::New { ip, mac } => format!("new      {} is-at {}", ip, mac),
+        Event::Conflict {
+            ip,
+            old_mac,
+            new_mac,
+            gratuitous,
+        } => format!(
+            "CONFLICT {} moved from {} to {}{}",
+            ip,
+            old_mac,
+            new_mac,
+            if *gratuitous { " (gratuitous)" } else { "" }
+        ),
+        Event::Mismatch {
+            ip,
+            sender_mac,
+            source,
+        } => format!(
+            "MISMATCH {} claimed by {} in a frame from {}",
+            ip, sender_mac, source
+        ),
+        Event::Storm {
+            mac,
+            count,
+            window_secs,
+        } => format!(
+            "STORM    {} gratuitous ARPs from {} in {} s",
+            count, mac, window_secs
+        ),
+    };
+    println!("{:10.3} {}", elapsed.as_secs_f64(), text);
+    Ok(())
+}
+
+fn print_table(table: &Table) {
+    println!("--- {} addresses ---", table.len());
+    for (ip, entry) in table.entries() {
+        println!(
+            "{:15} {} {:6} packets, last {:.0?} ago{}",
+            ip.to_string(),
+            entry.mac,
+            entry.packets,
+            entry.last_seen.elapsed(),
+            match entry.changes {
+                0 => String::new(),
+                n => format!(", {} changes", n),
+            }
+        );
+    }
+}
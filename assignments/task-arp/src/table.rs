@@ -0,0 +1,177 @@
+//! The IP to MAC table learned from the senders of ARP packets, and the
+//! events that changes to it raise. A host answers for its address with the
+//! same MAC address for as long as it runs, so an address that moves to
+//! another MAC is either a replaced host or someone answering for it: the
+//! ARP spoofing of man-in-the-middle attacks.
+
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    net::Ipv4Addr,
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+
+use crate::arp::{ArpPacket, Mac};
+
+/// Something worth telling about the ARP traffic.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    /// The first mapping seen for an address
+    New { ip: Ipv4Addr, mac: Mac },
+    /// An address that moved to another MAC address
+    Conflict {
+        ip: Ipv4Addr,
+        old_mac: Mac,
+        new_mac: Mac,
+        gratuitous: bool,
+    },
+    /// An ARP sender address that differs from the Ethernet source, which
+    /// a host sending its own ARP never does
+    Mismatch {
+        ip: Ipv4Addr,
+        sender_mac: Mac,
+        source: Mac,
+    },
+    /// More gratuitous ARPs from one MAC address than the threshold within
+    /// the window
+    Storm {
+        mac: Mac,
+        count: usize,
+        window_secs: f64,
+    },
+}
+
+/// What is known about one address.
+#[derive(Clone, Debug)]
+pub struct Entry {
+    pub mac: Mac,
+    pub first_seen: Instant,
+    pub last_seen: Instant,
+    pub packets: u64,
+    /// Times the address moved to another MAC
+    pub changes: u64,
+}
+
+/// Recent gratuitous ARPs of one MAC address.
+#[derive(Default)]
+struct Burst {
+    times: VecDeque<Instant>,
+    // Whether the current storm has been reported, so that it is reported
+    // once rather than for every packet
+    reported: bool,
+}
+
+pub struct Table {
+    entries: BTreeMap<Ipv4Addr, Entry>,
+    bursts: HashMap<Mac, Burst>,
+    storm_threshold: usize,
+    storm_window: Duration,
+}
+
+impl Table {
+    pub fn new(storm_threshold: usize, storm_window: Duration) -> Self {
+        Self {
+            entries: BTreeMap::new(),
+            bursts: HashMap::new(),
+            storm_threshold,
+            storm_window,
+        }
+    }
+
+    /// The entries in the order of their addresses.
+    pub fn entries(&self) -> impl Iterator<Item = (&Ipv4Addr, &Entry)> {
+        self.entries.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Learns the sender's mapping from a packet received at `now`, and
+    /// returns the events it raises.
+    pub fn observe(&mut self, packet: &ArpPacket, now: Instant) -> Vec<Event> {
+        let mut events = Vec::new();
+        // Probes announce no mapping
+        if packet.is_probe() {
+            return events;
+        }
+        if packet.sender_mac != packet.source {
+            events.push(Event::Mismatch {
+                ip: packet.sender_ip,
+                sender_mac: packet.sender_mac,
+                source: packet.source,
+            });
+        }
+        if packet.is_gratuitous() {
+            events.extend(self.gratuitous(packet.sender_mac, now));
+        }
+
+        let ip = packet.sender_ip;
+        match self.entries.get_mut(&ip) {
+            Some(entry) => {
+                if entry.mac != packet.sender_mac {
+                    events.push(Event::Conflict {
+                        ip,
+                        old_mac: entry.mac,
+                        new_mac: packet.sender_mac,
+                        gratuitous: packet.is_gratuitous(),
+                    });
+                    entry.mac = packet.sender_mac;
+                    entry.changes += 1;
+                }
+                entry.last_seen = now;
+                entry.packets += 1;
+            }
+            None => {
+                events.push(Event::New {
+                    ip,
+                    mac: packet.sender_mac,
+                });
+                self.entries.insert(
+                    ip,
+                    Entry {
+                        mac: packet.sender_mac,
+                        first_seen: now,
+                        last_seen: now,
+                        packets: 1,
+                        changes: 0,
+                    },
+                );
+            }
+        }
+        events
+    }
+
+    /// Counts a gratuitous ARP in the window of its sender, and reports a
+    /// storm when the count first goes over the threshold.
+    fn gratuitous(&mut self, mac: Mac, now: Instant) -> Option<Event> {
+        let burst = self.bursts.entry(mac).or_default();
+        burst.times.push_back(now);
+        while burst
+            .times
+            .front()
+            .is_some_and(|&at| now.duration_since(at) > self.storm_window)
+        {
+            burst.times.pop_front();
+        }
+        if burst.times.len() <= self.storm_threshold {
+            burst.reported = false;
+            return None;
+        }
+        if burst.reported {
+            return None;
+        }
+        burst.reported = true;
+        Some(Event::Storm {
+            mac,
+            count: burst.times.len(),
+            window_secs: self.storm_window.as_secs_f64(),
+        })
+    }
+}
@@ -0,0 +1,65 @@
+use std::time::{Duration, Instant};
+
+use adnet_core::{agent::send_command, AgentClient, Command};
+use pktcap::record::{Recorded, Recorder};
+
+use crate::{
+    conn::{Connection, TlsOptions},
+    CliError,
+};
+
+/// Time spent in each phase of setting up the connection.
+pub struct Timings {
+    pub dns: Duration,
+    pub connect: Duration,
+    pub tls: Option<Duration>,
+    /// When the control message was written to the socket
+    pub sent: Instant,
+}
+
+/// Every assignment starts the same way: open a TCP connection to adnet-agent
+/// and send a control message of the form `TASK-XXX keyword [arguments]`.
+/// Returns the connected socket so that the caller can continue with the
+/// task-specific part of the protocol. With `tls`, the connection is wrapped
+/// in TLS before the control message is sent, and with `recorder`, the
+/// connection is recorded.
+pub fn handshake(
+    agent: &AgentClient,
+    command: &Command,
+    tls: Option<&TlsOptions>,
+    recorder: Option<&Recorder>,
+) -> Result<(Connection, Timings), CliError> {
+    let start = Instant::now();
+
+    // Resolve separately from connecting to measure the phases separately
+    let addrs = agent.resolve()?;
+    let dns = start.elapsed();
+
+    let (socket, addr) = agent.connect_to(addrs)?;
+    let connect = start.elapsed() - dns;
+
+    println!("Connected to {} ({})", agent.address(), addr);
+    let socket = Recorded::connected(socket, recorder)?;
+
+    let (mut socket, tls) = match tls {
+        Some(options) => {
+            let tls_start = Instant::now();
+            let socket = Connection::tls(socket, agent.host(), options)?;
+            (socket, Some(tls_start.elapsed()))
+        }
+        None => (Connection::Plain(socket), None),
+    };
+
+    send_command(&mut socket, command)?;
+    let sent = Instant::now();
+    println!("Sent control message: {}", command);
+
+    let timings = Timings {
+        dns,
+        connect,
+        tls,
+        sent,
+    };
+    Ok((socket, timings))
+}
+
@@ -0,0 +1,162 @@
+use std::{
+    io::{self, Read, Write},
+    net::TcpStream,
+    sync::Arc,
+};
+
+use pktcap::record::Recorded;
+use rustls::{
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::{ring, CryptoProvider},
+    pki_types::{pem::PemObject, CertificateDer, ServerName, UnixTime},
+    ClientConfig, ClientConnection, DigitallySignedStruct, RootCertStore, SignatureScheme,
+    StreamOwned,
+};
+
+use crate::CliError;
+
+/// TLS settings given on the command line.
+#[derive(Debug, Clone, Default)]
+pub struct TlsOptions {
+    /// Accept any server certificate. Only for testing against self-signed servers.
+    pub insecure: bool,
+    /// Server name to send in SNI and verify the certificate against
+    pub sni: Option<String>,
+    /// PEM file with additional trusted CA certificates
+    pub ca_file: Option<String>,
+}
+
+/// A connection to the agent or server, either plain TCP or TLS on top of TCP.
+/// With --pcap, the TCP traffic is recorded, so TLS appears encrypted.
+pub enum Connection {
+    Plain(Recorded<TcpStream>),
+    Tls(Box<StreamOwned<ClientConnection, Recorded<TcpStream>>>),
+}
+
+impl Connection {
+    /// The underlying TCP socket, e.g. for setting timeouts.
+    pub fn tcp(&self) -> &TcpStream {
+        match self {
+            Connection::Plain(s) => s.get_ref(),
+            Connection::Tls(s) => s.sock.get_ref(),
+        }
+    }
+
+    /// Performs the TLS handshake on top of a connected TCP socket. `host` is
+    /// used as the server name unless SNI is given explicitly.
+    pub fn tls(
+        mut socket: Recorded<TcpStream>,
+        host: &str,
+        options: &TlsOptions,
+    ) -> Result<Connection, CliError> {
+        let name = options.sni.as_deref().unwrap_or(host);
+        let server_name = ServerName::try_from(name.to_string())
+            .map_err(|e| CliError::Usage(format!("Invalid TLS server name {:?}: {}", name, e)))?;
+
+        let mut conn = ClientConnection::new(Arc::new(client_config(options)?), server_name)?;
+        while conn.is_handshaking() {
+            conn.complete_io(&mut socket)
+                .map_err(|source| CliError::Handshake {
+                    name: name.to_string(),
+                    source,
+                })?;
+        }
+
+        if let Some(suite) = conn.negotiated_cipher_suite() {
+            println!("TLS established with {} using {:?}", name, suite.suite());
+        }
+
+        Ok(Connection::Tls(Box::new(StreamOwned::new(conn, socket))))
+    }
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Connection::Plain(s) => s.read(buf),
+            Connection::Tls(s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Connection::Plain(s) => s.write(buf),
+            Connection::Tls(s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Connection::Plain(s) => s.flush(),
+            Connection::Tls(s) => s.flush(),
+        }
+    }
+}
+
+fn client_config(options: &TlsOptions) -> Result<ClientConfig, CliError> {
+    let provider = Arc::new(ring::default_provider());
+    let builder = ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()?;
+
+    if options.insecure {
+        println!("WARNING: TLS server certificate is not verified");
+        return Ok(builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(NoVerification(provider)))
+            .with_no_client_auth());
+    }
+
+    let mut roots = RootCertStore::empty();
+    roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    if let Some(path) = &options.ca_file {
+        let invalid = |e: &dyn std::fmt::Display| {
+            CliError::Usage(format!("Cannot read CA file {}: {}", path, e))
+        };
+        for cert in CertificateDer::pem_file_iter(path).map_err(|e| invalid(&e))? {
+            roots.add(cert.map_err(|e| invalid(&e))?)?;
+        }
+    }
+
+    Ok(builder.with_root_certificates(roots).with_no_client_auth())
+}
+
+/// Certificate verifier for --insecure that accepts any server certificate.
+#[derive(Debug)]
+struct NoVerification(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for NoVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        _message: &[u8],
+        _cert: &CertificateDer<'_>,
+        _dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        Ok(HandshakeSignatureValid::assertion())
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        _message: &[u8],
+        _cert: &CertificateDer<'_>,
+        _dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        Ok(HandshakeSignatureValid::assertion())
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
@@ -0,0 +1,54 @@
+use std::{io, net::SocketAddr};
+
+use adnet_core::{
+    exit::{Code, HasCode},
+    AgentError,
+};
+use thiserror::Error;
+
+/// Errors of adnet-cli. Each kind has its own exit code, see
+/// [`adnet_core::exit`].
+#[derive(Debug, Error)]
+pub enum CliError {
+    /// Invalid or missing options
+    #[error("{0}")]
+    Usage(String),
+    /// The config file could not be read
+    #[error(transparent)]
+    Config(io::Error),
+    #[error(transparent)]
+    Agent(#[from] AgentError),
+    /// The server to test did not accept the connection
+    #[error("Connection to {address} failed: {source}")]
+    Connect {
+        address: SocketAddr,
+        source: io::Error,
+    },
+    #[error("TLS handshake with {name} failed: {source}")]
+    Handshake { name: String, source: io::Error },
+    #[error(transparent)]
+    Tls(#[from] rustls::Error),
+    /// The peer closed the connection early or answered something unexpected
+    #[error("{0}")]
+    Protocol(String),
+    #[error("{0}")]
+    Timeout(String),
+    #[error("Received data failed verification")]
+    Verify,
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+impl HasCode for CliError {
+    fn exit_code(&self) -> Code {
+        match self {
+            CliError::Usage(_) | CliError::Config(_) => Code::Usage,
+            CliError::Agent(e) => e.exit_code(),
+            CliError::Connect { .. } => Code::Connect,
+            CliError::Handshake { .. } | CliError::Tls(_) | CliError::Protocol(_) => Code::Protocol,
+            CliError::Timeout(_) => Code::Timeout,
+            CliError::Verify => Code::Verify,
+            CliError::Io(e) => e.exit_code(),
+        }
+    }
+}
@@ -0,0 +1,649 @@
+//! adnet-cli as a library, so that the `adnet` multi-tool can run it as a
+//! subcommand. The adnet-cli binary is a thin wrapper around [`run`].
+
+mod agent;
+mod conn;
+mod error;
+mod receive;
+mod streams;
+mod udp;
+mod verify;
+
+use adnet_core::{
+    agent::read_udp_task,
+    config::{self, ConfigArgs},
+    report::{Report, ReportArgs},
+    AgentClient, Command as AgentCommand,
+};
+use clap::{parser::ValueSource, ArgMatches, Args, Parser, Subcommand};
+use pktcap::record::{RecordArgs, Recorded, Recorder};
+use serde::Deserialize;
+use std::{
+    fs::OpenOptions,
+    io::{self, BufWriter, Read, Write},
+    net::{SocketAddr, TcpStream},
+    time::{Duration, Instant},
+};
+use conn::{Connection, TlsOptions};
+pub use error::CliError;
+use receive::{Limits, Throttle};
+use streams::Streams;
+use udp::UdpClient;
+use verify::Verifier;
+use wire::srv::{Crc32, Mode, Request, CRC_SIZE};
+
+/// Drives the control interaction of the course assignments with adnet-agent.
+#[derive(Parser, Debug)]
+#[command(name = "adnet-cli", version, about, long_about = None)]
+pub struct Cli {
+    /// Address of the adnet-agent server
+    #[arg(short, long, default_value = "10.0.0.3:12345", global = true)]
+    agent: String,
+
+    /// Keyword given in the MyCourses assignment. With task-cli --count, a
+    /// comma-separated list of keywords is used in turns.
+    #[arg(short, long, global = true)]
+    keyword: Option<String>,
+
+    // Timeouts prevent the program from hanging forever if the server is unresponsive
+    /// Timeout for connecting to the agent or server, in seconds
+    #[arg(long, default_value = "5", value_parser = config::parse_secs, global = true)]
+    connect_timeout: Duration,
+
+    /// Timeout for a single read from the socket, in seconds
+    #[arg(long, default_value = "30", value_parser = config::parse_secs, global = true)]
+    read_timeout: Duration,
+
+    /// Use TLS on the connection before sending the keyword, or with
+    /// task-srv-request the requests
+    #[arg(long, global = true)]
+    tls: bool,
+
+    /// With --tls, do not verify the server certificate
+    #[arg(long, requires = "tls", global = true)]
+    insecure: bool,
+
+    /// With --tls, server name to use instead of the host in the address
+    #[arg(long, requires = "tls", global = true)]
+    sni: Option<String>,
+
+    /// With --tls, PEM file with additional trusted CA certificates
+    #[arg(long, requires = "tls", global = true)]
+    ca_file: Option<String>,
+
+    #[command(flatten)]
+    config: ConfigArgs,
+
+    #[command(flatten)]
+    report: ReportArgs,
+
+    #[command(flatten)]
+    pcap: RecordArgs,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+/// The [adnet-cli] section of the config file
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct FileConfig {
+    agent: Option<String>,
+    keyword: Option<String>,
+    #[serde(deserialize_with = "config::secs")]
+    connect_timeout: Option<Duration>,
+    #[serde(deserialize_with = "config::secs")]
+    read_timeout: Option<Duration>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Simple client: send TASK-CLI and read everything the agent sends
+    #[command(name = "task-cli", alias = "task-001")]
+    Cli(TaskCliArgs),
+
+    /// TCP server: check that the server is reachable and send TASK-SRV
+    #[command(name = "task-srv-check")]
+    SrvCheck {
+        /// Address of your server, as the agent should connect to it
+        #[arg(short, long)]
+        server: SocketAddr,
+
+        /// Send the control message even if the server does not accept connections
+        #[arg(long)]
+        no_check: bool,
+    },
+
+    /// Data transfer using UDP: send TASK-UDP and show the requested transfer
+    #[command(name = "task-udp")]
+    Udp,
+
+    /// Act like the agent towards a TCP server: send 5-byte requests and time the responses
+    #[command(name = "task-srv-request")]
+    SrvRequest(SrvRequestArgs),
+}
+
+#[derive(Args, Debug)]
+struct SrvRequestArgs {
+    /// Address of the server
+    #[arg(short, long)]
+    server: SocketAddr,
+
+    /// Number of bytes to request
+    #[arg(long, default_value_t = 100_000)]
+    size: u32,
+
+    /// Value of the requested bytes
+    #[arg(long, default_value_t = b'A')]
+    byte: u8,
+
+    /// Number of requests to send
+    #[arg(short, long, default_value_t = 1)]
+    count: usize,
+
+    /// Open a new connection for every request instead of reusing one
+    #[arg(long)]
+    reconnect: bool,
+
+    /// Limit the receive rate, in bytes per second (k and M suffixes allowed)
+    #[arg(long, value_parser = parse_rate)]
+    max_rate: Option<u64>,
+
+    /// Send the requests over UDP, to the --udp-port of task-srv, and
+    /// acknowledge the response packets as adnet-agent does those of task-udp
+    #[arg(long, conflicts_with_all = ["reconnect", "max_rate"])]
+    udp: bool,
+
+    /// Send extended requests for bytes in this mode (repeat, increment or
+    /// random, from --byte) and check the CRC-32 that ends each response
+    #[arg(long, value_parser = parse_mode, conflicts_with = "udp")]
+    mode: Option<Mode>,
+}
+
+#[derive(Args, Debug)]
+struct TaskCliArgs {
+    /// Compute SHA-256 of the received data
+    #[arg(long)]
+    verify: bool,
+
+    /// With --verify, check that every received byte is this character
+    #[arg(long, requires = "verify")]
+    expect_byte: Option<char>,
+
+    /// Write the received data to this file (truncated unless --append is given)
+    #[arg(short, long)]
+    output: Option<String>,
+
+    /// Append to the output file instead of truncating it
+    #[arg(long, requires = "output")]
+    append: bool,
+
+    /// Size of the receive buffer used for each read, in bytes
+    #[arg(long, default_value_t = 64 * 1024)]
+    chunk_size: usize,
+
+    /// Abort if the whole transfer has not finished in this many seconds
+    #[arg(long, value_parser = config::parse_secs)]
+    deadline: Option<Duration>,
+
+    /// Number of requests to make. The agent closes the connection after
+    /// sending the data, so each request uses a new connection.
+    #[arg(short, long, default_value_t = 1)]
+    count: usize,
+
+    /// Limit the receive rate, in bytes per second (k and M suffixes allowed).
+    /// With --streams, the limit is for each stream
+    #[arg(long, value_parser = parse_rate)]
+    max_rate: Option<u64>,
+
+    /// Keep this many connections to the agent open at once, sharing the
+    /// requests of --count between them with at least one for each, and show
+    /// their combined progress and the statistics of each at the end
+    #[arg(long, conflicts_with = "output")]
+    streams: Option<usize>,
+
+    /// With --streams, how many times a request that fails midway is started
+    /// over on a new connection, waiting twice as long each time
+    #[arg(long, default_value_t = 3)]
+    retries: usize,
+}
+
+impl Cli {
+    fn agent(&self) -> AgentClient {
+        AgentClient::new(&self.agent)
+            .connect_timeout(self.connect_timeout)
+            .response_timeout(self.read_timeout)
+    }
+
+    fn tls_options(&self) -> Option<TlsOptions> {
+        self.tls.then(|| TlsOptions {
+            insecure: self.insecure,
+            sni: self.sni.clone(),
+            ca_file: self.ca_file.clone(),
+        })
+    }
+}
+
+fn parse_rate(s: &str) -> Result<u64, String> {
+    let (number, multiplier) = match s.strip_suffix(['k', 'K']) {
+        Some(n) => (n, 1_000.0),
+        None => match s.strip_suffix('M') {
+            Some(n) => (n, 1_000_000.0),
+            None => (s, 1.0),
+        },
+    };
+    let rate: f64 = number.parse().map_err(|e| format!("{}", e))?;
+    let rate = (rate * multiplier) as u64;
+    if rate == 0 {
+        return Err("rate must be positive".to_string());
+    }
+    Ok(rate)
+}
+
+fn parse_mode(s: &str) -> Result<Mode, String> {
+    match s {
+        "repeat" => Ok(Mode::Repeat),
+        "increment" => Ok(Mode::Increment),
+        "random" => Ok(Mode::Random),
+        _ => Err("expected repeat, increment or random".to_string()),
+    }
+}
+
+impl Cli {
+    fn keyword(&self) -> Result<&str, CliError> {
+        self.keyword
+            .as_deref()
+            .ok_or_else(|| CliError::Usage("Keyword is required (--keyword)".to_string()))
+    }
+
+    /// Takes the options that were not given on the command line from the
+    /// config file, if there is one.
+    fn apply_config(&mut self, matches: &ArgMatches) -> Result<(), CliError> {
+        let file: FileConfig = self.config.section("adnet-cli").map_err(CliError::Config)?;
+        let given = |id| matches.value_source(id) == Some(ValueSource::CommandLine);
+
+        if let (false, Some(agent)) = (given("agent"), file.agent) {
+            self.agent = agent;
+        }
+        if let (false, Some(keyword)) = (given("keyword"), file.keyword) {
+            self.keyword = Some(keyword);
+        }
+        if let (false, Some(timeout)) = (given("connect_timeout"), file.connect_timeout) {
+            self.connect_timeout = timeout;
+        }
+        if let (false, Some(timeout)) = (given("read_timeout"), file.read_timeout) {
+            self.read_timeout = timeout;
+        }
+        Ok(())
+    }
+}
+
+/// Runs the command given on the command line. The matches are those the
+/// arguments were parsed from, and tell which options to take from the config file.
+pub fn run(mut cli: Cli, matches: &ArgMatches) -> Result<(), CliError> {
+    cli.apply_config(matches)?;
+
+    // The report is named after the subcommand, e.g. task-srv-check
+    let task = matches.subcommand_name().unwrap_or("adnet-cli");
+    let recorder = cli.pcap.recorder()?;
+    let recorder = recorder.as_ref();
+    let result = cli.report.run(task, |report| {
+        report.keyword = cli.keyword.clone();
+        match cli.command {
+            Command::Cli(ref args) => task_cli(&cli, args, recorder, report),
+            Command::SrvCheck { server, no_check } => {
+                task_srv_check(&cli, cli.keyword()?, server, no_check, recorder)
+            }
+            Command::Udp => task_udp(&cli, cli.keyword()?, recorder, report),
+            Command::SrvRequest(ref args) => task_srv_request(&cli, args, recorder, report),
+        }
+    });
+    if let Some(recorder) = recorder {
+        let path = recorder.path();
+        match recorder.finish() {
+            Ok(()) => println!(
+                "Recorded {} packets to {}",
+                recorder.packets(),
+                path.display()
+            ),
+            Err(e) => eprintln!("Recording to {} failed: {}", path.display(), e),
+        }
+    }
+    result
+}
+
+fn task_cli(
+    cli: &Cli,
+    args: &TaskCliArgs,
+    recorder: Option<&Recorder>,
+    report: &mut Report,
+) -> Result<(), CliError> {
+    println!("Task-CLI starting");
+
+    if args.chunk_size == 0 || args.count == 0 {
+        return Err(CliError::Usage(
+            "Chunk size and count must be positive".to_string(),
+        ));
+    }
+    if args.streams == Some(0) {
+        return Err(CliError::Usage("--streams must be positive".to_string()));
+    }
+    let keywords: Vec<&str> = cli.keyword()?.split(',').collect();
+
+    let expected = match args.expect_byte {
+        Some(c) if c.is_ascii() => Some(c as u8),
+        Some(c) => {
+            return Err(CliError::Usage(format!(
+                "Expected byte must be ASCII, got {:?}",
+                c
+            )))
+        }
+        None => None,
+    };
+
+    let mut output = match &args.output {
+        Some(path) => {
+            let file = OpenOptions::new()
+                .create(true)
+                .write(true)
+                .append(args.append)
+                .truncate(!args.append)
+                .open(path)
+                .map_err(|e| {
+                    io::Error::new(e.kind(), format!("Cannot open output file {}: {}", path, e))
+                })?;
+            Some(BufWriter::new(file))
+        }
+        None => None,
+    };
+
+    // Start clock to measure the time it takes to finish transmission
+    let start = Instant::now();
+
+    let limits = Limits {
+        chunk_size: args.chunk_size,
+        read_timeout: cli.read_timeout,
+        deadline: args.deadline.map(|d| start + d),
+        max_rate: args.max_rate,
+    };
+
+    if let Some(streams) = args.streams {
+        let shared = Streams {
+            cli,
+            args,
+            keywords: &keywords,
+            expected,
+            limits: &limits,
+            recorder,
+        };
+        return shared.run(streams, report);
+    }
+
+    let tls = cli.tls_options();
+    let mut durations = Vec::with_capacity(args.count);
+
+    for i in 0..args.count {
+        let keyword = keywords[i % keywords.len()];
+        let mut verifier = args.verify.then(|| Verifier::new(expected));
+        let request_start = Instant::now();
+
+        let command = AgentCommand::Cli {
+            keyword: keyword.to_string(),
+        };
+        let (mut socket, timings) =
+            agent::handshake(&cli.agent(), &command, tls.as_ref(), recorder)?;
+
+        let progress = receive::receive(
+            &mut socket,
+            &limits,
+            verifier.as_mut(),
+            output.as_mut().map(|w| w as &mut dyn Write),
+            None,
+        )?;
+
+        let end = Instant::now();
+        let duration = end - request_start;
+        let last_bytes = String::from_utf8_lossy(&progress.tail);
+        report.bytes += progress.total as u64;
+        report.last_bytes = Some(last_bytes.to_string());
+
+        println!(
+            "Total size: {} bytes -- Last 8 bytes: {:?} -- Duration: {:.2?}",
+            progress.total, last_bytes, duration
+        );
+        print_breakdown(&timings, progress.first_byte, end);
+
+        if let Some(verifier) = verifier {
+            if !verifier.finish() {
+                return Err(CliError::Verify);
+            }
+        }
+        durations.push(duration);
+    }
+
+    if let (Some(mut output), Some(path)) = (output, &args.output) {
+        output.flush()?;
+        println!("Wrote received data to {}", path);
+    }
+    if args.count > 1 {
+        print_timings(&durations, start.elapsed());
+    }
+
+    Ok(())
+}
+
+/// Sends requests in the task-srv format (4-byte length + 1-byte value) and
+/// measures how long each response takes, either reusing one connection for
+/// all requests or opening a new one for each.
+fn task_srv_request(
+    cli: &Cli,
+    args: &SrvRequestArgs,
+    recorder: Option<&Recorder>,
+    report: &mut Report,
+) -> Result<(), CliError> {
+    if args.udp {
+        return task_srv_request_udp(cli, args, report);
+    }
+    let request = match args.mode {
+        Some(mode) => Request::extended(args.size, args.byte, mode),
+        None => Request::repeat(args.size, args.byte),
+    };
+    let request = request
+        .encode()
+        .map_err(|e| CliError::Usage(format!("--size: {}", e)))?;
+    let start = Instant::now();
+    let tls = cli.tls_options();
+    let host = args.server.ip().to_string();
+    let mut socket: Option<Connection> = None;
+    let mut buf = vec![0u8; 64 * 1024];
+    let mut durations = Vec::with_capacity(args.count);
+    let mut throttle = args.max_rate.map(Throttle::new);
+    let read_size = throttle.as_ref().map_or(buf.len(), |t| t.read_size(buf.len()));
+
+    for i in 0..args.count {
+        let request_start = Instant::now();
+        let stream = match socket.as_mut() {
+            Some(stream) if !args.reconnect => stream,
+            _ => {
+                let stream = TcpStream::connect_timeout(&args.server, cli.connect_timeout)
+                    .map_err(|source| CliError::Connect {
+                        address: args.server,
+                        source,
+                    })?;
+                stream.set_read_timeout(Some(cli.read_timeout))?;
+                let stream = Recorded::connected(stream, recorder)?;
+                let connection = match &tls {
+                    Some(options) => Connection::tls(stream, &host, options)?,
+                    None => Connection::Plain(stream),
+                };
+                socket.insert(connection)
+            }
+        };
+
+        stream.write_all(&request)?;
+
+        let mut remaining = args.size as usize;
+        let mut first_byte = None;
+        let mut crc = Crc32::new();
+        while remaining > 0 {
+            let n = stream.read(&mut buf[..remaining.min(read_size)])?;
+            if n == 0 {
+                return Err(CliError::Protocol(format!(
+                    "Server closed the connection with {} bytes missing",
+                    remaining
+                )));
+            }
+            first_byte.get_or_insert_with(|| request_start.elapsed());
+            crc.update(&buf[..n]);
+            remaining -= n;
+            if let Some(throttle) = throttle.as_mut() {
+                throttle.consumed(n);
+            }
+        }
+        let mut checked = String::new();
+        if args.mode.is_some() {
+            let mut expected = [0u8; CRC_SIZE];
+            stream.read_exact(&mut expected)?;
+            if crc.finish() != u32::from_be_bytes(expected) {
+                return Err(CliError::Verify);
+            }
+            checked = format!(" -- CRC32: {:08x}", crc.finish());
+        }
+
+        let duration = request_start.elapsed();
+        report.bytes += args.size as u64;
+        println!(
+            "Request {}: {} bytes -- First byte: {:.2?} -- Duration: {:.2?}{}",
+            i + 1,
+            args.size,
+            first_byte.unwrap_or_default(),
+            duration,
+            checked
+        );
+        durations.push(duration);
+    }
+
+    print_timings(&durations, start.elapsed());
+    Ok(())
+}
+
+/// task-srv-request with --udp. A pause of the read timeout in the response
+/// fails the request.
+fn task_srv_request_udp(
+    cli: &Cli,
+    args: &SrvRequestArgs,
+    report: &mut Report,
+) -> Result<(), CliError> {
+    if cli.tls {
+        return Err(CliError::Usage("--tls does not go with --udp".to_string()));
+    }
+    let start = Instant::now();
+    let mut client = UdpClient::bind(args.server)?;
+    let mut durations = Vec::with_capacity(args.count);
+    for i in 0..args.count {
+        let response = client.request(args.size, args.byte, cli.read_timeout)?;
+        report.bytes += args.size as u64;
+        report.checknum = Some(response.checknum);
+        println!(
+            "Request {}: {} bytes -- First byte: {:.2?} -- Duration: {:.2?} -- Checknum: {}",
+            i + 1,
+            args.size,
+            response.first_byte.unwrap_or_default(),
+            response.duration,
+            response.checknum
+        );
+        durations.push(response.duration);
+    }
+
+    print_timings(&durations, start.elapsed());
+    Ok(())
+}
+
+/// Splits the request latency into phases: name resolution, TCP handshake, TLS
+/// handshake, time from sending the control message to the first response
+/// byte, and the rest of the transfer.
+fn print_breakdown(timings: &agent::Timings, first_byte: Option<Instant>, end: Instant) {
+    let mut line = format!("DNS: {:.2?} -- Connect: {:.2?}", timings.dns, timings.connect);
+    if let Some(tls) = timings.tls {
+        line += &format!(" -- TLS: {:.2?}", tls);
+    }
+    if let Some(first_byte) = first_byte {
+        line += &format!(
+            " -- First byte: {:.2?} -- Transfer: {:.2?}",
+            first_byte - timings.sent,
+            end - first_byte
+        );
+    }
+    println!("{}", line);
+}
+
+fn print_timings(durations: &[Duration], total: Duration) {
+    let min = durations.iter().min().copied().unwrap_or_default();
+    let max = durations.iter().max().copied().unwrap_or_default();
+    let avg = durations.iter().sum::<Duration>() / durations.len().max(1) as u32;
+    println!(
+        "{} requests in {:.2?} -- min {:.2?} / avg {:.2?} / max {:.2?}",
+        durations.len(),
+        total,
+        min,
+        avg,
+        max
+    );
+}
+
+fn task_srv_check(
+    cli: &Cli,
+    keyword: &str,
+    server: SocketAddr,
+    no_check: bool,
+    recorder: Option<&Recorder>,
+) -> Result<(), CliError> {
+    // The agent connects to the server only after receiving the control message,
+    // so it is better to find out now if nobody is listening at the address.
+    if !no_check {
+        let check = TcpStream::connect_timeout(&server, cli.connect_timeout).map_err(|source| {
+            CliError::Connect {
+                address: server,
+                source,
+            }
+        })?;
+        Recorded::connected(check, recorder)?;
+        println!("Server at {} accepts connections", server);
+    }
+
+    let command = AgentCommand::Srv {
+        keyword: keyword.to_string(),
+        server,
+    };
+    let (_, timings) = agent::handshake(&cli.agent(), &command, None, recorder)?;
+    print_breakdown(&timings, None, timings.sent);
+    println!("The agent should now open connections to {}", server);
+
+    Ok(())
+}
+
+fn task_udp(
+    cli: &Cli,
+    keyword: &str,
+    recorder: Option<&Recorder>,
+    report: &mut Report,
+) -> Result<(), CliError> {
+    let command = AgentCommand::Udp {
+        keyword: keyword.to_string(),
+    };
+    let tls = cli.tls_options();
+    let (mut socket, timings) =
+        agent::handshake(&cli.agent(), &command, tls.as_ref(), recorder)?;
+    socket.tcp().set_read_timeout(Some(cli.read_timeout))?;
+
+    let task = read_udp_task(&mut socket)?;
+    let end = Instant::now();
+    print_breakdown(&timings, Some(end), end);
+    println!(
+        "Agent requests {} bytes of '{}' to UDP port 20000",
+        task.size, task.character as char
+    );
+    report.detail("requested_size", task.size);
+    report.detail("requested_byte", (task.character as char).to_string());
+    Ok(())
+}
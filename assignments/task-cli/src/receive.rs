@@ -0,0 +1,190 @@
+use std::{
+    io::{ErrorKind, Read, Write},
+    sync::atomic::{AtomicU64, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{conn::Connection, verify::Verifier, CliError};
+
+const TAIL_LEN: usize = 8;
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Keeps track of the received stream without storing it: total byte count,
+/// the last TAIL_LEN bytes, and the state needed for periodic progress reports.
+pub struct Progress {
+    pub total: usize,
+    pub tail: Vec<u8>,
+    pub first_byte: Option<Instant>,
+    // Without the reports, another part of the program shows the progress
+    reports: bool,
+    start: Instant,
+    last_report: Instant,
+    last_total: usize,
+}
+
+impl Progress {
+    fn new(reports: bool) -> Self {
+        let now = Instant::now();
+        Self {
+            total: 0,
+            tail: Vec::with_capacity(2 * TAIL_LEN),
+            first_byte: None,
+            reports,
+            start: now,
+            last_report: now,
+            last_total: 0,
+        }
+    }
+
+    fn update(&mut self, chunk: &[u8]) {
+        self.first_byte.get_or_insert_with(Instant::now);
+        self.total += chunk.len();
+
+        // Append at most TAIL_LEN bytes of the new chunk and drop what no longer fits
+        self.tail
+            .extend_from_slice(&chunk[chunk.len().saturating_sub(TAIL_LEN)..]);
+        if self.tail.len() > TAIL_LEN {
+            self.tail.drain(..self.tail.len() - TAIL_LEN);
+        }
+
+        if self.reports && self.last_report.elapsed() >= PROGRESS_INTERVAL {
+            self.report();
+        }
+    }
+
+    fn report(&mut self) {
+        let interval = self.last_report.elapsed().as_secs_f64();
+        let rate = (self.total - self.last_total) as f64 / interval / 1000.0;
+        println!(
+            "Received {} bytes in {:.2?} -- {:.1} kB/s",
+            self.total,
+            self.start.elapsed(),
+            rate
+        );
+        self.last_report = Instant::now();
+        self.last_total = self.total;
+    }
+}
+
+/// Limits applied while receiving. Data is read in chunks of `chunk_size`
+/// bytes, so memory use does not grow with the transfer size.
+pub struct Limits {
+    pub chunk_size: usize,
+    pub read_timeout: Duration,
+    /// The transfer is aborted if the server has not finished by this time
+    pub deadline: Option<Instant>,
+    /// Maximum average receive rate in bytes per second
+    pub max_rate: Option<u64>,
+}
+
+/// Emulates a slow consumer by sleeping between reads so that the average rate
+/// stays below the limit. The data the application does not read stays in the
+/// socket receive buffer, which eventually closes the TCP receive window and
+/// makes the sender stall.
+pub struct Throttle {
+    rate: u64,
+    start: Instant,
+    total: u64,
+}
+
+impl Throttle {
+    pub fn new(rate: u64) -> Self {
+        Self {
+            rate,
+            start: Instant::now(),
+            total: 0,
+        }
+    }
+
+    /// Limits single reads to about 50 ms worth of data, so that the rate is
+    /// smooth also when it is small compared to the buffer size.
+    pub fn read_size(&self, chunk_size: usize) -> usize {
+        chunk_size.min((self.rate / 20).max(1) as usize)
+    }
+
+    pub fn consumed(&mut self, n: usize) {
+        self.total += n as u64;
+        let target = Duration::from_secs_f64(self.total as f64 / self.rate as f64);
+        let elapsed = self.start.elapsed();
+        if target > elapsed {
+            thread::sleep(target - elapsed);
+        }
+    }
+}
+
+/// Reads the socket until the server closes the connection, passing each chunk
+/// also to the verifier and the output file, if they are given. The progress
+/// is printed every second, unless the byte count goes to `received` for the
+/// caller to show.
+pub fn receive(
+    socket: &mut Connection,
+    limits: &Limits,
+    mut verifier: Option<&mut Verifier>,
+    mut output: Option<&mut dyn Write>,
+    received: Option<&AtomicU64>,
+) -> Result<Progress, CliError> {
+    let mut buf = vec![0u8; limits.chunk_size];
+    let mut progress = Progress::new(received.is_none());
+    let mut throttle = limits.max_rate.map(Throttle::new);
+    let read_size = throttle
+        .as_ref()
+        .map_or(limits.chunk_size, |t| t.read_size(limits.chunk_size));
+
+    loop {
+        // A blocking read must not wait past the deadline
+        let mut timeout = limits.read_timeout;
+        if let Some(deadline) = limits.deadline {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(CliError::Timeout(format!(
+                    "Transfer deadline exceeded after {} bytes",
+                    progress.total
+                )));
+            }
+            timeout = timeout.min(remaining);
+        }
+        socket.tcp().set_read_timeout(Some(timeout))?;
+
+        let n = match socket.read(&mut buf[..read_size]) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            // The server closed the TCP connection without closing the TLS session first.
+            // We still have all the data it sent, but it could have been truncated.
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+                println!("Warning: TLS connection closed without close_notify");
+                break;
+            }
+            // The deadline is checked on the next round
+            Err(e)
+                if (e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut)
+                    && timeout < limits.read_timeout =>
+            {
+                continue
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+                return Err(CliError::Timeout(format!(
+                    "No data received in {:?} after {} bytes",
+                    limits.read_timeout, progress.total
+                )));
+            }
+            Err(e) => return Err(e.into()),
+        };
+        progress.update(&buf[..n]);
+        if let Some(received) = received {
+            received.fetch_add(n as u64, Ordering::Relaxed);
+        }
+        if let Some(throttle) = throttle.as_mut() {
+            throttle.consumed(n);
+        }
+        if let Some(verifier) = verifier.as_deref_mut() {
+            verifier.update(&buf[..n]);
+        }
+        if let Some(output) = output.as_deref_mut() {
+            output.write_all(&buf[..n])?;
+        }
+    }
+
+    Ok(progress)
+}
@@ -0,0 +1,251 @@
+//! task-cli with `--streams N`: N connections to the agent at once, which
+//! share the `--count` transfers between them, stream 1 making transfers 1,
+//! N+1, 2N+1 and so on. The agent sends the whole data on every connection,
+//! so a transfer that fails midway starts over on a new connection, after a
+//! pause that doubles from [`FIRST_BACKOFF`] up to [`MAX_BACKOFF`], at most
+//! `--retries` times. While the streams run, the bytes they have received
+//! together and the throughput are printed every second, and the statistics
+//! of each stream at the end.
+
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
+
+use adnet_core::{report::Report, Command as AgentCommand};
+use pktcap::record::Recorder;
+
+use crate::{
+    agent,
+    conn::TlsOptions,
+    receive::{self, Limits},
+    verify::Verifier,
+    Cli, CliError, TaskCliArgs,
+};
+
+pub const FIRST_BACKOFF: Duration = Duration::from_millis(500);
+pub const MAX_BACKOFF: Duration = Duration::from_secs(8);
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+// How often the progress thread checks whether the streams have finished
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// What is shared by the streams of one run.
+pub struct Streams<'a> {
+    pub cli: &'a Cli,
+    pub args: &'a TaskCliArgs,
+    pub keywords: &'a [&'a str],
+    pub expected: Option<u8>,
+    pub limits: &'a Limits,
+    pub recorder: Option<&'a Recorder>,
+}
+
+/// What one stream did, for the statistics at the end.
+#[derive(Default)]
+struct StreamStats {
+    transfers: usize,
+    /// Of the completed transfers
+    bytes: u64,
+    retries: usize,
+    /// Of each completed transfer, from its first attempt
+    durations: Vec<Duration>,
+    /// The last bytes of the last transfer
+    tail: Vec<u8>,
+    elapsed: Duration,
+}
+
+impl Streams<'_> {
+    /// Runs `streams` streams to the end, even if some fail, and returns the
+    /// first error.
+    pub fn run(&self, streams: usize, report: &mut Report) -> Result<(), CliError> {
+        // Every stream makes at least one transfer
+        let count = self.args.count.max(streams);
+        let tls = self.cli.tls_options();
+        let received = AtomicU64::new(0);
+        let start = Instant::now();
+
+        let results = thread::scope(|scope| {
+            let handles: Vec<_> = (0..streams)
+                .map(|index| {
+                    let (tls, received) = (tls.as_ref(), &received);
+                    scope.spawn(move || {
+                        let transfers = (index..count).step_by(streams);
+                        self.stream(index, transfers, tls, received)
+                    })
+                })
+                .collect();
+
+            let (mut last_report, mut last_total) = (Instant::now(), 0);
+            while !handles.iter().all(|handle| handle.is_finished()) {
+                thread::sleep(POLL_INTERVAL);
+                if last_report.elapsed() >= PROGRESS_INTERVAL {
+                    let total = received.load(Ordering::Relaxed);
+                    let active = handles.iter().filter(|h| !h.is_finished()).count();
+                    println!(
+                        "Received {} bytes in {:.2?} -- {:.1} kB/s -- {} of {} streams active",
+                        total,
+                        start.elapsed(),
+                        rate(total - last_total, last_report.elapsed()),
+                        active,
+                        streams
+                    );
+                    (last_report, last_total) = (Instant::now(), total);
+                }
+            }
+            handles
+                .into_iter()
+                .map(|handle| handle.join().expect("stream panicked"))
+                .collect::<Vec<_>>()
+        });
+
+        let elapsed = start.elapsed();
+        let mut durations = Vec::with_capacity(count);
+        let mut first_error = None;
+        for (index, (stats, result)) in results.into_iter().enumerate() {
+            println!(
+                "Stream {}: {} transfers, {} bytes in {:.2?} -- {:.1} kB/s -- {} retries{}",
+                index + 1,
+                stats.transfers,
+                stats.bytes,
+                stats.elapsed,
+                rate(stats.bytes, stats.elapsed),
+                stats.retries,
+                if result.is_err() { " -- failed" } else { "" }
+            );
+            report.bytes += stats.bytes;
+            if !stats.tail.is_empty() {
+                report.last_bytes = Some(String::from_utf8_lossy(&stats.tail).to_string());
+            }
+            durations.extend(stats.durations);
+            if let Err(e) = result {
+                first_error.get_or_insert(e);
+            }
+        }
+        println!(
+            "{} bytes over {} streams in {:.2?} -- {:.1} kB/s",
+            report.bytes,
+            streams,
+            elapsed,
+            rate(report.bytes, elapsed)
+        );
+        if durations.len() > 1 {
+            crate::print_timings(&durations, elapsed);
+        }
+        first_error.map_or(Ok(()), Err)
+    }
+
+    fn stream(
+        &self,
+        index: usize,
+        transfers: impl Iterator<Item = usize>,
+        tls: Option<&TlsOptions>,
+        received: &AtomicU64,
+    ) -> (StreamStats, Result<(), CliError>) {
+        let start = Instant::now();
+        let mut stats = StreamStats::default();
+        let mut result = Ok(());
+        for transfer in transfers {
+            if let Err(e) = self.transfer(index, transfer, tls, received, &mut stats) {
+                result = Err(e);
+                break;
+            }
+        }
+        stats.elapsed = start.elapsed();
+        (stats, result)
+    }
+
+    /// Makes one transfer, starting it over after the failures that a new
+    /// connection may get past.
+    fn transfer(
+        &self,
+        index: usize,
+        transfer: usize,
+        tls: Option<&TlsOptions>,
+        received: &AtomicU64,
+        stats: &mut StreamStats,
+    ) -> Result<(), CliError> {
+        let keyword = self.keywords[transfer % self.keywords.len()];
+        let command = AgentCommand::Cli {
+            keyword: keyword.to_string(),
+        };
+        let start = Instant::now();
+        let mut backoff = FIRST_BACKOFF;
+        let mut attempts = 0;
+
+        let (progress, verifier) = loop {
+            let mut verifier = self.args.verify.then(|| Verifier::new(self.expected));
+            let attempt = agent::handshake(&self.cli.agent(), &command, tls, self.recorder)
+                .and_then(|(mut socket, _)| {
+                    receive::receive(
+                        &mut socket,
+                        self.limits,
+                        verifier.as_mut(),
+                        None,
+                        Some(received),
+                    )
+                });
+            match attempt {
+                Ok(progress) => break (progress, verifier),
+                Err(e) if attempts < self.args.retries && self.can_retry(&e, backoff) => {
+                    attempts += 1;
+                    stats.retries += 1;
+                    println!(
+                        "Stream {}: {}, retrying in {:.2?} ({}/{})",
+                        index + 1,
+                        e,
+                        backoff,
+                        attempts,
+                        self.args.retries
+                    );
+                    thread::sleep(backoff);
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                }
+                Err(e) => return Err(e),
+            }
+        };
+
+        let duration = start.elapsed();
+        println!(
+            "Stream {}: Total size: {} bytes -- Last 8 bytes: {:?} -- Duration: {:.2?}",
+            index + 1,
+            progress.total,
+            String::from_utf8_lossy(&progress.tail),
+            duration
+        );
+        if let Some(verifier) = verifier {
+            if !verifier.finish() {
+                return Err(CliError::Verify);
+            }
+        }
+        stats.transfers += 1;
+        stats.bytes += progress.total as u64;
+        stats.durations.push(duration);
+        stats.tail = progress.tail;
+        Ok(())
+    }
+
+    /// Whether a new connection may get past the error, in time for the
+    /// deadline: the agent or the network failing may pass, invalid options
+    /// or data do not.
+    fn can_retry(&self, e: &CliError, backoff: Duration) -> bool {
+        let transient = matches!(
+            e,
+            CliError::Agent(_)
+                | CliError::Connect { .. }
+                | CliError::Handshake { .. }
+                | CliError::Protocol(_)
+                | CliError::Timeout(_)
+                | CliError::Io(_)
+        );
+        let in_time = match self.limits.deadline {
+            Some(deadline) => Instant::now() + backoff < deadline,
+            None => true,
+        };
+        transient && in_time
+    }
+}
+
+/// In kB/s
+fn rate(bytes: u64, duration: Duration) -> f64 {
+    bytes as f64 / duration.as_secs_f64().max(f64::EPSILON) / 1000.0
+}
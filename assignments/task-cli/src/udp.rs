@@ -0,0 +1,142 @@
+//! task-srv-request over UDP, for the `--udp-port` of task-srv. The request
+//! is the same 5 bytes as on TCP, in one datagram, and the response arrives
+//! in the packets of task-udp, each acknowledged with the highest sequence
+//! number received in order and a checknum, as adnet-agent acknowledges the
+//! packets of task-udp. The checknum is the sum of the payload bytes
+//! received in order, as in the receiver of task-udp.
+
+use std::{
+    collections::BTreeMap,
+    io::ErrorKind,
+    net::{SocketAddr, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use wire::Ack;
+
+use crate::CliError;
+
+// The request is sent again this often until the response starts, as it may
+// have been lost
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+// After the response has arrived, keep acknowledging retransmissions until
+// the server has been quiet this long, in case the last acknowledgements were
+// lost
+const LINGER: Duration = Duration::from_millis(500);
+// How long a receive waits, for the timers above
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// One response, for the timings.
+pub struct Response {
+    pub first_byte: Option<Duration>,
+    /// Until the last byte arrived
+    pub duration: Duration,
+    pub checknum: u8,
+}
+
+pub struct UdpClient {
+    socket: UdpSocket,
+    server: SocketAddr,
+    // Where the previous response came from, as its retransmissions may still
+    // arrive
+    previous: Option<SocketAddr>,
+}
+
+impl UdpClient {
+    pub fn bind(server: SocketAddr) -> Result<Self, CliError> {
+        let any: SocketAddr = match server {
+            SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+            SocketAddr::V6(_) => ([0u16; 8], 0).into(),
+        };
+        let socket = UdpSocket::bind(any)?;
+        socket.set_read_timeout(Some(POLL_INTERVAL))?;
+        Ok(Self {
+            socket,
+            server,
+            previous: None,
+        })
+    }
+
+    /// Requests `size` copies of `byte` and receives them, failing if nothing
+    /// arrives in `timeout`.
+    pub fn request(
+        &mut self,
+        size: u32,
+        byte: u8,
+        timeout: Duration,
+    ) -> Result<Response, CliError> {
+        let mut request = [0u8; 5];
+        request[..4].copy_from_slice(&size.to_be_bytes());
+        request[4] = byte;
+        let start = Instant::now();
+        self.socket.send_to(&request, self.server)?;
+        let mut response = Response {
+            first_byte: None,
+            duration: Duration::ZERO,
+            checknum: 0,
+        };
+        if size == 0 {
+            return Ok(response);
+        }
+
+        let mut sender: Option<SocketAddr> = None;
+        let mut pending: BTreeMap<u32, Vec<u8>> = BTreeMap::new();
+        let mut next_seq = 1;
+        let mut received = 0;
+        let mut completed = false;
+        let (mut last_heard, mut last_request) = (start, start);
+        let mut buf = [0u8; 65536];
+        while !completed || last_heard.elapsed() < LINGER {
+            if !completed && last_heard.elapsed() >= timeout {
+                return Err(CliError::Timeout(format!(
+                    "Received {} of {} bytes from {} before a pause of {:?}",
+                    received, size, self.server, timeout
+                )));
+            }
+            if sender.is_none() && last_request.elapsed() >= RETRY_INTERVAL {
+                self.socket.send_to(&request, self.server)?;
+                last_request = Instant::now();
+            }
+
+            let (n, from) = match self.socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+                    continue
+                }
+                Err(e) => return Err(e.into()),
+            };
+            if Some(from) == self.previous || sender.is_some_and(|s| s != from) {
+                continue;
+            }
+            let Ok((header, payload)) = wire::decode(&buf[..n]) else {
+                continue;
+            };
+            sender = Some(from);
+            last_heard = Instant::now();
+            response.first_byte.get_or_insert_with(|| start.elapsed());
+
+            if header.seq >= next_seq {
+                pending.insert(header.seq, payload.to_vec());
+            }
+            while let Some(payload) = pending.remove(&next_seq) {
+                response.checknum = payload
+                    .iter()
+                    .fold(response.checknum, |c, &b| c.wrapping_add(b));
+                received += payload.len();
+                next_seq += 1;
+            }
+            if !completed && received >= size as usize {
+                completed = true;
+                response.duration = start.elapsed();
+            }
+
+            let ack = Ack {
+                seq: next_seq - 1,
+                checknum: response.checknum,
+            };
+            self.socket.send_to(&ack.encode(), from)?;
+        }
+        self.previous = sender;
+        Ok(response)
+    }
+}
@@ -0,0 +1,59 @@
+use sha2::{Digest, Sha256};
+
+/// Checks the integrity of the received stream chunk by chunk, so that the
+/// data never needs to be stored in full.
+pub struct Verifier {
+    hasher: Sha256,
+    expected: Option<u8>,
+    offset: usize,
+    corruption: Option<(usize, u8)>,
+}
+
+impl Verifier {
+    /// If `expected` is given, every received byte must have that value.
+    pub fn new(expected: Option<u8>) -> Self {
+        Self {
+            hasher: Sha256::new(),
+            expected,
+            offset: 0,
+            corruption: None,
+        }
+    }
+
+    pub fn update(&mut self, chunk: &[u8]) {
+        self.hasher.update(chunk);
+
+        if let (Some(expected), None) = (self.expected, self.corruption) {
+            if let Some(pos) = chunk.iter().position(|&b| b != expected) {
+                self.corruption = Some((self.offset + pos, chunk[pos]));
+            }
+        }
+        self.offset += chunk.len();
+    }
+
+    /// Prints the digest and the result of the pattern check. Returns false if
+    /// unexpected data was found.
+    pub fn finish(self) -> bool {
+        let digest = self.hasher.finalize();
+        let hex: String = digest.iter().map(|b| format!("{:02x}", b)).collect();
+        println!("SHA-256: {}", hex);
+
+        match (self.expected, self.corruption) {
+            (Some(expected), None) => {
+                println!(
+                    "Verification OK: all {} bytes are {:?}",
+                    self.offset, expected as char
+                );
+                true
+            }
+            (Some(expected), Some((offset, found))) => {
+                println!(
+                    "Verification FAILED: byte at offset {} is {:#04x}, expected {:#04x}",
+                    offset, found, expected
+                );
+                false
+            }
+            (None, _) => true,
+        }
+    }
+}
@@ -0,0 +1,287 @@
+//! The client side of the DHCP exchange: DISCOVER, OFFER, REQUEST, ACK.
+//! Until it has an address, the client can only broadcast, and asks the
+//! servers to broadcast their answers, too; the transaction ID and the
+//! hardware address tell which answers are its own.
+
+use std::{
+    cmp,
+    collections::hash_map::RandomState,
+    fs,
+    hash::{BuildHasher, Hasher},
+    io,
+    net::{Ipv4Addr, SocketAddr, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use adnet_core::shutdown::Shutdown;
+use socket2::{Domain, Protocol, Socket, Type};
+use tracing::{debug, info, warn};
+
+use crate::{
+    packet::{
+        DhcpOption, Lease, Message, MessageType, CLIENT_PORT, REQUESTED_PARAMETERS, SERVER_PORT,
+    },
+    DhcpError,
+};
+
+// Longest the client waits for an answer before checking for Ctrl-C
+const SHUTDOWN_POLL: Duration = Duration::from_millis(200);
+const HTYPE_ETHERNET: u8 = 1;
+
+/// Retransmission of requests: each retransmission waits twice as long as
+/// the one before, as RFC 2131 suggests.
+#[derive(Clone, Copy, Debug)]
+pub struct Settings {
+    /// Wait for the answer to the first request
+    pub timeout: Duration,
+    pub retries: u32,
+}
+
+/// A new random transaction ID.
+pub fn transaction_id() -> u32 {
+    RandomState::new().build_hasher().finish() as u32
+}
+
+/// Parses a hardware address given as aa:bb:cc:dd:ee:ff.
+pub fn parse_mac(s: &str) -> Result<[u8; 6], String> {
+    let octets = s
+        .trim()
+        .split(':')
+        .map(|octet| u8::from_str_radix(octet, 16))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("{}: {}", s, e))?;
+    octets
+        .try_into()
+        .map_err(|_| format!("{}: a MAC address has 6 octets", s))
+}
+
+/// The hardware address of an interface, from sysfs.
+pub fn interface_mac(interface: &str) -> io::Result<[u8; 6]> {
+    let path = format!("/sys/class/net/{}/address", interface);
+    let text = fs::read_to_string(&path)?;
+    parse_mac(&text).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+pub struct Client {
+    socket: UdpSocket,
+    chaddr: [u8; 6],
+    hostname: Option<String>,
+    settings: Settings,
+    shutdown: Shutdown,
+    start: Instant,
+}
+
+impl Client {
+    /// Opens the client's socket on port 68 of the interface. Needs root or
+    /// CAP_NET_BIND_SERVICE and CAP_NET_RAW.
+    pub fn open(
+        interface: &str,
+        chaddr: [u8; 6],
+        hostname: Option<String>,
+        settings: Settings,
+        shutdown: Shutdown,
+    ) -> Result<Self, DhcpError> {
+        let socket = open_socket(interface).map_err(|source| DhcpError::Socket {
+            interface: interface.to_string(),
+            source,
+        })?;
+        Ok(Self {
+            socket,
+            chaddr,
+            hostname,
+            settings,
+            shutdown,
+            start: Instant::now(),
+        })
+    }
+
+    /// A message of the given type with the options every message of this
+    /// client has.
+    fn message(&self, kind: MessageType, xid: u32) -> Message {
+        let mut message = Message::client(kind, xid, self.chaddr);
+        message.secs = self.start.elapsed().as_secs().min(u16::MAX as u64) as u16;
+        let mut client_id = vec![HTYPE_ETHERNET];
+        client_id.extend_from_slice(&self.chaddr);
+        message.options.push(DhcpOption::ClientId(client_id));
+        if let Some(hostname) = &self.hostname {
+            message.options.push(DhcpOption::HostName(hostname.clone()));
+        }
+        message
+    }
+
+    fn broadcast(&self, message: &Message) -> io::Result<()> {
+        let to = SocketAddr::from((Ipv4Addr::BROADCAST, SERVER_PORT));
+        debug!("Sending {:?} ({})", message.message_type(), message.xid);
+        self.socket.send_to(&message.encode(), to)?;
+        Ok(())
+    }
+
+    /// Waits until `deadline` for the next server message of transaction
+    /// `xid` for this client, and returns `None` when the time is up.
+    fn receive(&self, xid: u32, deadline: Instant) -> Result<Option<Message>, DhcpError> {
+        let mut buf = [0u8; 1500];
+        loop {
+            if self.shutdown.is_requested() {
+                return Err(DhcpError::Interrupted);
+            }
+            let Some(left) = deadline
+                .checked_duration_since(Instant::now())
+                .filter(|left| !left.is_zero())
+            else {
+                return Ok(None);
+            };
+            self.socket
+                .set_read_timeout(Some(cmp::min(left, SHUTDOWN_POLL)))?;
+            let (n, from) = match self.socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock
+                            | io::ErrorKind::TimedOut
+                            | io::ErrorKind::Interrupted
+                    ) =>
+                {
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
+            };
+            match Message::decode(&buf[..n]) {
+                // Other clients' transactions are broadcast, too
+                Ok(message) if !message.request && message.xid == xid => {
+                    if message.chaddr == self.chaddr {
+                        return Ok(Some(message));
+                    }
+                    debug!("Ignoring answer for another client from {}", from);
+                }
+                Ok(_) => {}
+                Err(e) => debug!("Ignoring datagram from {}: {}", from, e),
+            }
+        }
+    }
+
+    /// Broadcasts `request` and retransmits it until `accept` takes an
+    /// answer, which it returns, or until the retries run out.
+    fn exchange<T>(
+        &self,
+        request: &Message,
+        waiting_for: &'static str,
+        mut accept: impl FnMut(Message) -> Option<Result<T, DhcpError>>,
+    ) -> Result<T, DhcpError> {
+        let mut timeout = self.settings.timeout;
+        for attempt in 0..=self.settings.retries {
+            if attempt > 0 {
+                info!("No {} yet, retransmitting", waiting_for);
+            }
+            self.broadcast(request)?;
+            let deadline = Instant::now() + timeout;
+            while let Some(answer) = self.receive(request.xid, deadline)? {
+                if let Some(result) = accept(answer) {
+                    return result;
+                }
+            }
+            timeout *= 2;
+        }
+        Err(DhcpError::Timeout(waiting_for))
+    }
+
+    /// Broadcasts a DISCOVER, asking for `requested` if given, and returns
+    /// the first OFFER.
+    pub fn discover(&self, xid: u32, requested: Option<Ipv4Addr>) -> Result<Message, DhcpError> {
+        let mut discover = self.message(MessageType::Discover, xid);
+        if let Some(address) = requested {
+            discover.options.push(DhcpOption::RequestedAddress(address));
+        }
+        discover
+            .options
+            .push(DhcpOption::ParameterList(REQUESTED_PARAMETERS.to_vec()));
+        self.exchange(&discover, "OFFER", |answer| {
+            match (answer.message_type(), answer.server_id()) {
+                (Some(MessageType::Offer), Some(_)) => Some(Ok(answer)),
+                (kind, _) => {
+                    debug!("Ignoring {:?} while waiting for an OFFER", kind);
+                    None
+                }
+            }
+        })
+    }
+
+    /// Broadcasts a DISCOVER and returns all OFFERs that arrive within
+    /// `window` of the first one, to find every server on the link. Only
+    /// retransmits while no server has answered.
+    pub fn offers(&self, xid: u32, window: Duration) -> Result<Vec<Message>, DhcpError> {
+        let mut offers = vec![self.discover(xid, None)?];
+        let deadline = Instant::now() + window;
+        while let Some(answer) = self.receive(xid, deadline)? {
+            // A server answers each retransmission of the DISCOVER
+            let known = offers.iter().any(|o| o.server_id() == answer.server_id());
+            if answer.message_type() == Some(MessageType::Offer) && !known {
+                offers.push(answer);
+            }
+        }
+        Ok(offers)
+    }
+
+    /// Broadcasts a REQUEST for the address of `offer`, which also tells
+    /// the other servers that their offers were declined, and returns the
+    /// ACK.
+    pub fn request(&self, offer: &Message) -> Result<Message, DhcpError> {
+        let server = offer.server_id().unwrap_or(Ipv4Addr::UNSPECIFIED);
+        let mut request = self.message(MessageType::Request, offer.xid);
+        request
+            .options
+            .push(DhcpOption::RequestedAddress(offer.yiaddr));
+        request.options.push(DhcpOption::ServerId(server));
+        request
+            .options
+            .push(DhcpOption::ParameterList(REQUESTED_PARAMETERS.to_vec()));
+
+        self.exchange(&request, "ACK", |answer| {
+            if answer.server_id() != Some(server) {
+                return None;
+            }
+            match answer.message_type() {
+                Some(MessageType::Ack) => Some(Ok(answer)),
+                Some(MessageType::Nak) => {
+                    let message = answer
+                        .options
+                        .iter()
+                        .find_map(|o| match o {
+                            DhcpOption::Message(text) => Some(text.clone()),
+                            _ => None,
+                        })
+                        .unwrap_or_else(|| "no reason given".to_string());
+                    Some(Err(DhcpError::Nak { server, message }))
+                }
+                _ => None,
+            }
+        })
+    }
+
+    /// Gives the address of the lease back to its server. The client has
+    /// no address configured, so the RELEASE is broadcast rather than sent
+    /// to the server as RFC 2131 says; servers accept it either way.
+    pub fn release(&self, lease: &Lease) -> Result<(), DhcpError> {
+        let (Some(address), Some(server)) = (lease.address, lease.server) else {
+            warn!("Lease without an address or server, not releasing it");
+            return Ok(());
+        };
+        let mut release = self.message(MessageType::Release, transaction_id());
+        release.ciaddr = address;
+        release.flags = 0;
+        release.options.push(DhcpOption::ServerId(server));
+        self.broadcast(&release)?;
+        Ok(())
+    }
+}
+
+fn open_socket(interface: &str) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    // Next to a DHCP client that already holds the port
+    socket.set_reuse_address(true)?;
+    socket.set_broadcast(true)?;
+    // Send and receive only on this interface, whatever the routes say
+    socket.bind_device(Some(interface.as_bytes()))?;
+    socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, CLIENT_PORT)).into())?;
+    Ok(socket.into())
+}
@@ -0,0 +1,46 @@
+use std::{io, net::Ipv4Addr};
+
+use adnet_core::exit::{Code, HasCode};
+use thiserror::Error;
+
+/// Errors of task-dhcp. Each kind has its own exit code, see
+/// [`adnet_core::exit`].
+#[derive(Debug, Error)]
+pub enum DhcpError {
+    /// Invalid or missing options
+    #[error("{0}")]
+    Usage(String),
+    /// The config file could not be read
+    #[error(transparent)]
+    Config(io::Error),
+    /// The socket could not be bound to port 68 on the interface, e.g.
+    /// without root
+    #[error("Cannot open a DHCP socket on {interface}: {source}")]
+    Socket {
+        interface: String,
+        source: io::Error,
+    },
+    /// The server refused the request
+    #[error("{server} sent NAK: {message}")]
+    Nak { server: Ipv4Addr, message: String },
+    /// No server answered
+    #[error("No {0} after all retransmissions")]
+    Timeout(&'static str),
+    #[error("Cancelled")]
+    Interrupted,
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+impl HasCode for DhcpError {
+    fn exit_code(&self) -> Code {
+        match self {
+            DhcpError::Usage(_) | DhcpError::Config(_) => Code::Usage,
+            DhcpError::Socket { source, .. } => source.exit_code(),
+            DhcpError::Nak { .. } => Code::Protocol,
+            DhcpError::Timeout(_) => Code::Timeout,
+            DhcpError::Interrupted => Code::Interrupted,
+            DhcpError::Io(e) => e.exit_code(),
+        }
+    }
+}
@@ -0,0 +1,196 @@
+//! Minimal DHCP client (RFC 2131). It goes through DISCOVER, OFFER, REQUEST
+//! and ACK on an interface and prints the lease the server grants, without
+//! configuring the interface, so it can run on a test interface next to the
+//! system's own DHCP client. With `--offers`, it only collects the offers of
+//! all servers on the link, which finds rogue DHCP servers.
+//!
+//! The task-dhcp binary and the `adnet dhcp` subcommand are thin wrappers
+//! around [`run`].
+
+pub mod client;
+mod error;
+pub mod packet;
+
+use std::{net::Ipv4Addr, time::Duration};
+
+use adnet_core::{
+    config::{self, ConfigArgs},
+    logging::{self, LogArgs},
+    report::{Report, ReportArgs},
+    shutdown,
+};
+use clap::Parser;
+use client::{Client, Settings};
+use packet::Lease;
+use serde::Deserialize;
+use tracing::info;
+
+pub use error::DhcpError;
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(2);
+const DEFAULT_RETRIES: u32 = 3;
+const DEFAULT_OFFER_WINDOW: Duration = Duration::from_secs(3);
+
+/// Gets a lease from a DHCP server and prints it, without configuring the interface.
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+pub struct Args {
+    /// Interface to send the requests on, e.g. a veth of a test network
+    #[arg(short, long)]
+    interface: Option<String>,
+
+    /// Hardware address to ask for a lease for, as aa:bb:cc:dd:ee:ff
+    /// [default: the interface's]
+    #[arg(long, value_parser = client::parse_mac)]
+    mac: Option<[u8; 6]>,
+
+    /// Host name to send to the server
+    #[arg(long)]
+    hostname: Option<String>,
+
+    /// Address to ask the server for
+    #[arg(long)]
+    requested: Option<Ipv4Addr>,
+
+    /// Only collect the offers of all servers, without requesting any
+    #[arg(long)]
+    offers: bool,
+
+    /// Seconds to wait for more offers after the first, with --offers
+    /// [default: 3]
+    #[arg(long, value_parser = config::parse_secs)]
+    offer_window: Option<Duration>,
+
+    /// Give the address back to the server after getting it
+    #[arg(long)]
+    release: bool,
+
+    /// Seconds to wait for the first answer, doubled on each
+    /// retransmission [default: 2]
+    #[arg(short, long, value_parser = config::parse_secs)]
+    timeout: Option<Duration>,
+
+    /// Retransmissions of a message before giving up [default: 3]
+    #[arg(short, long)]
+    retries: Option<u32>,
+
+    #[command(flatten)]
+    config: ConfigArgs,
+
+    #[command(flatten)]
+    log: LogArgs,
+
+    #[command(flatten)]
+    report: ReportArgs,
+}
+
+/// The [task-dhcp] section of the config file
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct FileConfig {
+    interface: Option<String>,
+    mac: Option<String>,
+    hostname: Option<String>,
+    requested: Option<Ipv4Addr>,
+    offers: bool,
+    #[serde(deserialize_with = "config::secs")]
+    offer_window: Option<Duration>,
+    release: bool,
+    #[serde(deserialize_with = "config::secs")]
+    timeout: Option<Duration>,
+    retries: Option<u32>,
+}
+
+fn format_mac(mac: [u8; 6]) -> String {
+    mac.iter()
+        .map(|octet| format!("{:02x}", octet))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// Runs the client with the given arguments, as the task-dhcp binary does.
+pub fn run(args: Args) -> Result<(), DhcpError> {
+    logging::init(&args.log)?;
+    let report_args = args.report.clone();
+    let mut report = Report::new("task-dhcp");
+    let result = lease(args, &mut report);
+    report_args.finish(&mut report, result)
+}
+
+fn lease(args: Args, report: &mut Report) -> Result<(), DhcpError> {
+    // Command line options take precedence over the config file
+    let file: FileConfig = args
+        .config
+        .section("task-dhcp")
+        .map_err(DhcpError::Config)?;
+    let interface = args
+        .interface
+        .or(file.interface)
+        .ok_or_else(|| DhcpError::Usage("Interface is required (--interface)".to_string()))?;
+    let mac = match (args.mac, file.mac) {
+        (Some(mac), _) => mac,
+        (None, Some(mac)) => client::parse_mac(&mac).map_err(DhcpError::Usage)?,
+        (None, None) => client::interface_mac(&interface).map_err(|source| DhcpError::Socket {
+            interface: interface.clone(),
+            source,
+        })?,
+    };
+    let settings = Settings {
+        timeout: args.timeout.or(file.timeout).unwrap_or(DEFAULT_TIMEOUT),
+        retries: args.retries.or(file.retries).unwrap_or(DEFAULT_RETRIES),
+    };
+    report.detail("interface", &interface);
+    report.detail("mac", format_mac(mac));
+
+    let client = Client::open(
+        &interface,
+        mac,
+        args.hostname.or(file.hostname),
+        settings,
+        shutdown::install()?,
+    )?;
+    let xid = client::transaction_id();
+    info!(
+        "Asking for a lease for {} on {} (xid {:08x})",
+        format_mac(mac),
+        interface,
+        xid
+    );
+
+    if args.offers || file.offers {
+        let window = args
+            .offer_window
+            .or(file.offer_window)
+            .unwrap_or(DEFAULT_OFFER_WINDOW);
+        let offers: Vec<Lease> = client
+            .offers(xid, window)?
+            .iter()
+            .map(Lease::from_message)
+            .collect();
+        for offer in &offers {
+            println!("OFFER");
+            print!("{}", offer);
+        }
+        println!("--- {} servers answered ---", offers.len());
+        report.detail("offers", &offers);
+        return Ok(());
+    }
+
+    let offer = client.discover(xid, args.requested.or(file.requested))?;
+    println!(
+        "OFFER of {} from {}",
+        offer.yiaddr,
+        offer.server_id().unwrap_or(Ipv4Addr::UNSPECIFIED)
+    );
+    let ack = client.request(&offer)?;
+    let lease = Lease::from_message(&ack);
+    println!("ACK");
+    print!("{}", lease);
+    report.detail("lease", &lease);
+
+    if args.release || file.release {
+        client.release(&lease)?;
+        println!("Released {}", ack.yiaddr);
+    }
+    Ok(())
+}
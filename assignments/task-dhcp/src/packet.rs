@@ -0,0 +1,683 @@
+//! DHCP messages (RFC 2131) and the options of RFC 2132 that a client
+//! needs. A message is the fixed BOOTP header followed by options:
+//!
+//! ```text
+//! | op | htype | hlen | hops | xid (4) | secs (2) | flags (2) |
+//! | ciaddr (4) | yiaddr (4) | siaddr (4) | giaddr (4) |
+//! | chaddr (16) | sname (64) | file (128) | magic cookie 99.130.83.99 |
+//! | code | length | data ... | ... | 255 (end)
+//! ```
+
+use std::{fmt, net::Ipv4Addr};
+
+use serde::Serialize;
+
+pub const SERVER_PORT: u16 = 67;
+pub const CLIENT_PORT: u16 = 68;
+/// Flag that asks the server to broadcast its answers, since the client
+/// cannot receive unicast before it has an address
+pub const FLAG_BROADCAST: u16 = 0x8000;
+
+const BOOTREQUEST: u8 = 1;
+const BOOTREPLY: u8 = 2;
+const HTYPE_ETHERNET: u8 = 1;
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+// Offset of the magic cookie, after the fixed header
+const OPTIONS_OFFSET: usize = 236;
+// Some servers drop messages shorter than a BOOTP message of RFC 951
+const MIN_SIZE: usize = 300;
+
+const PAD: u8 = 0;
+const SUBNET_MASK: u8 = 1;
+const ROUTER: u8 = 3;
+const DNS_SERVER: u8 = 6;
+const HOST_NAME: u8 = 12;
+const DOMAIN_NAME: u8 = 15;
+const MTU: u8 = 26;
+const BROADCAST_ADDRESS: u8 = 28;
+const REQUESTED_ADDRESS: u8 = 50;
+const LEASE_TIME: u8 = 51;
+const MESSAGE_TYPE: u8 = 53;
+const SERVER_ID: u8 = 54;
+const PARAMETER_LIST: u8 = 55;
+const MESSAGE: u8 = 56;
+const RENEWAL_TIME: u8 = 58;
+const REBINDING_TIME: u8 = 59;
+const CLIENT_ID: u8 = 61;
+const END: u8 = 255;
+
+/// The options a client asks for in its parameter request list.
+pub const REQUESTED_PARAMETERS: &[u8] = &[
+    SUBNET_MASK,
+    ROUTER,
+    DNS_SERVER,
+    DOMAIN_NAME,
+    MTU,
+    BROADCAST_ADDRESS,
+    LEASE_TIME,
+    RENEWAL_TIME,
+    REBINDING_TIME,
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MessageType {
+    Discover = 1,
+    Offer = 2,
+    Request = 3,
+    Decline = 4,
+    Ack = 5,
+    Nak = 6,
+    Release = 7,
+    Inform = 8,
+}
+
+impl MessageType {
+    fn from_u8(value: u8) -> Option<Self> {
+        Some(match value {
+            1 => MessageType::Discover,
+            2 => MessageType::Offer,
+            3 => MessageType::Request,
+            4 => MessageType::Decline,
+            5 => MessageType::Ack,
+            6 => MessageType::Nak,
+            7 => MessageType::Release,
+            8 => MessageType::Inform,
+            _ => return None,
+        })
+    }
+}
+
+impl fmt::Display for MessageType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            MessageType::Discover => "DISCOVER",
+            MessageType::Offer => "OFFER",
+            MessageType::Request => "REQUEST",
+            MessageType::Decline => "DECLINE",
+            MessageType::Ack => "ACK",
+            MessageType::Nak => "NAK",
+            MessageType::Release => "RELEASE",
+            MessageType::Inform => "INFORM",
+        };
+        f.write_str(name)
+    }
+}
+
+/// One option. Options this client does not know are kept as they are.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DhcpOption {
+    SubnetMask(Ipv4Addr),
+    Router(Vec<Ipv4Addr>),
+    DnsServer(Vec<Ipv4Addr>),
+    HostName(String),
+    DomainName(String),
+    Mtu(u16),
+    BroadcastAddress(Ipv4Addr),
+    RequestedAddress(Ipv4Addr),
+    /// Seconds
+    LeaseTime(u32),
+    MessageType(MessageType),
+    ServerId(Ipv4Addr),
+    ParameterList(Vec<u8>),
+    /// The reason of a NAK
+    Message(String),
+    RenewalTime(u32),
+    RebindingTime(u32),
+    ClientId(Vec<u8>),
+    Other {
+        code: u8,
+        data: Vec<u8>,
+    },
+}
+
+/// Why a datagram is not a valid DHCP message.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    Truncated,
+    /// Not a BOOTP message for Ethernet, or without the magic cookie
+    NotDhcp,
+    /// An option with a length that does not fit its type
+    Option(u8),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::Truncated => write!(f, "truncated message"),
+            DecodeError::NotDhcp => write!(f, "not a DHCP message"),
+            DecodeError::Option(code) => write!(f, "invalid option {}", code),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Message {
+    /// Whether the message goes from a client to a server
+    pub request: bool,
+    pub xid: u32,
+    /// Seconds since the client started, which some servers use to let
+    /// another server answer first
+    pub secs: u16,
+    pub flags: u16,
+    /// The client's address, when it already has one
+    pub ciaddr: Ipv4Addr,
+    /// "Your" address: the one offered to the client
+    pub yiaddr: Ipv4Addr,
+    /// The next server in the boot process, e.g. a TFTP server
+    pub siaddr: Ipv4Addr,
+    /// The relay agent, if the message was relayed
+    pub giaddr: Ipv4Addr,
+    pub chaddr: [u8; 6],
+    pub options: Vec<DhcpOption>,
+}
+
+impl Message {
+    /// A client message of the given type, with no other options yet.
+    pub fn client(kind: MessageType, xid: u32, chaddr: [u8; 6]) -> Self {
+        Self {
+            request: true,
+            xid,
+            secs: 0,
+            flags: FLAG_BROADCAST,
+            ciaddr: Ipv4Addr::UNSPECIFIED,
+            yiaddr: Ipv4Addr::UNSPECIFIED,
+            siaddr: Ipv4Addr::UNSPECIFIED,
+            giaddr: Ipv4Addr::UNSPECIFIED,
+            chaddr,
+            options: vec![DhcpOption::MessageType(kind)],
+        }
+    }
+
+    pub fn message_type(&self) -> Option<MessageType> {
+        self.options.iter().find_map(|o| match o {
+            DhcpOption::MessageType(kind) => Some(*kind),
+            _ => None,
+        })
+    }
+
+    pub fn server_id(&self) -> Option<Ipv4Addr> {
+        self.options.iter().find_map(|o| match o {
+            DhcpOption::ServerId(id) => Some(*id),
+            _ => None,
+        })
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(MIN_SIZE);
+        buf.push(if self.request { BOOTREQUEST } else { BOOTREPLY });
+        buf.extend_from_slice(&[HTYPE_ETHERNET, 6, 0]);
+        buf.extend_from_slice(&self.xid.to_be_bytes());
+        buf.extend_from_slice(&self.secs.to_be_bytes());
+        buf.extend_from_slice(&self.flags.to_be_bytes());
+        for address in [self.ciaddr, self.yiaddr, self.siaddr, self.giaddr] {
+            buf.extend_from_slice(&address.octets());
+        }
+        buf.extend_from_slice(&self.chaddr);
+        // Rest of chaddr, sname and file
+        buf.resize(OPTIONS_OFFSET, 0);
+        buf.extend_from_slice(&MAGIC_COOKIE);
+
+        for option in &self.options {
+            let (code, data) = encode_option(option);
+            // Longer data would need splitting over several options (RFC
+            // 3396), which nothing this client sends needs
+            buf.push(code);
+            buf.push(data.len().min(255) as u8);
+            buf.extend_from_slice(&data[..data.len().min(255)]);
+        }
+        buf.push(END);
+        if buf.len() < MIN_SIZE {
+            buf.resize(MIN_SIZE, PAD);
+        }
+        buf
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Self, DecodeError> {
+        if buf.len() < OPTIONS_OFFSET + MAGIC_COOKIE.len() {
+            return Err(DecodeError::Truncated);
+        }
+        if buf[1] != HTYPE_ETHERNET || buf[2] != 6 || buf[236..240] != MAGIC_COOKIE {
+            return Err(DecodeError::NotDhcp);
+        }
+        let request = match buf[0] {
+            BOOTREQUEST => true,
+            BOOTREPLY => false,
+            _ => return Err(DecodeError::NotDhcp),
+        };
+        let u16_at = |at: usize| u16::from_be_bytes([buf[at], buf[at + 1]]);
+        let address_at = |at: usize| Ipv4Addr::new(buf[at], buf[at + 1], buf[at + 2], buf[at + 3]);
+        let mut message = Message {
+            request,
+            xid: u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]),
+            secs: u16_at(8),
+            flags: u16_at(10),
+            ciaddr: address_at(12),
+            yiaddr: address_at(16),
+            siaddr: address_at(20),
+            giaddr: address_at(24),
+            chaddr: buf[28..34].try_into().unwrap(),
+            options: Vec::new(),
+        };
+
+        let mut at = OPTIONS_OFFSET + MAGIC_COOKIE.len();
+        while at < buf.len() {
+            let code = buf[at];
+            match code {
+                PAD => {
+                    at += 1;
+                    continue;
+                }
+                END => break,
+                _ => {}
+            }
+            let length = *buf.get(at + 1).ok_or(DecodeError::Truncated)? as usize;
+            let data = buf
+                .get(at + 2..at + 2 + length)
+                .ok_or(DecodeError::Truncated)?;
+            message.options.push(decode_option(code, data)?);
+            at += 2 + length;
+        }
+        Ok(message)
+    }
+}
+
+fn encode_option(option: &DhcpOption) -> (u8, Vec<u8>) {
+    let addresses = |addresses: &[Ipv4Addr]| addresses.iter().flat_map(|a| a.octets()).collect();
+    match option {
+        DhcpOption::SubnetMask(mask) => (SUBNET_MASK, mask.octets().to_vec()),
+        DhcpOption::Router(routers) => (ROUTER, addresses(routers)),
+        DhcpOption::DnsServer(servers) => (DNS_SERVER, addresses(servers)),
+        DhcpOption::HostName(name) => (HOST_NAME, name.as_bytes().to_vec()),
+        DhcpOption::DomainName(name) => (DOMAIN_NAME, name.as_bytes().to_vec()),
+        DhcpOption::Mtu(mtu) => (MTU, mtu.to_be_bytes().to_vec()),
+        DhcpOption::BroadcastAddress(address) => (BROADCAST_ADDRESS, address.octets().to_vec()),
+        DhcpOption::RequestedAddress(address) => (REQUESTED_ADDRESS, address.octets().to_vec()),
+        DhcpOption::LeaseTime(secs) => (LEASE_TIME, secs.to_be_bytes().to_vec()),
+        DhcpOption::MessageType(kind) => (MESSAGE_TYPE, vec![*kind as u8]),
+        DhcpOption::ServerId(id) => (SERVER_ID, id.octets().to_vec()),
+        DhcpOption::ParameterList(codes) => (PARAMETER_LIST, codes.clone()),
+        DhcpOption::Message(text) => (MESSAGE, text.as_bytes().to_vec()),
+        DhcpOption::RenewalTime(secs) => (RENEWAL_TIME, secs.to_be_bytes().to_vec()),
+        DhcpOption::RebindingTime(secs) => (REBINDING_TIME, secs.to_be_bytes().to_vec()),
+        DhcpOption::ClientId(id) => (CLIENT_ID, id.clone()),
+        DhcpOption::Other { code, data } => (*code, data.clone()),
+    }
+}
+
+fn decode_option(code: u8, data: &[u8]) -> Result<DhcpOption, DecodeError> {
+    let invalid = DecodeError::Option(code);
+    let address = || -> Result<Ipv4Addr, DecodeError> {
+        let octets: [u8; 4] = data.try_into().map_err(|_| invalid)?;
+        Ok(Ipv4Addr::from(octets))
+    };
+    let addresses = || -> Result<Vec<Ipv4Addr>, DecodeError> {
+        if data.is_empty() || !data.len().is_multiple_of(4) {
+            return Err(invalid);
+        }
+        Ok(data
+            .chunks(4)
+            .map(|c| Ipv4Addr::new(c[0], c[1], c[2], c[3]))
+            .collect())
+    };
+    let secs = || -> Result<u32, DecodeError> {
+        let bytes: [u8; 4] = data.try_into().map_err(|_| invalid)?;
+        Ok(u32::from_be_bytes(bytes))
+    };
+    // Servers sometimes end strings with a NUL
+    let text = || {
+        String::from_utf8_lossy(data)
+            .trim_end_matches('\0')
+            .to_string()
+    };
+    Ok(match code {
+        SUBNET_MASK => DhcpOption::SubnetMask(address()?),
+        ROUTER => DhcpOption::Router(addresses()?),
+        DNS_SERVER => DhcpOption::DnsServer(addresses()?),
+        HOST_NAME => DhcpOption::HostName(text()),
+        DOMAIN_NAME => DhcpOption::DomainName(text()),
+        MTU => {
+            let bytes: [u8; 2] = data.try_into().map_err(|_| invalid)?;
+            DhcpOption::Mtu(u16::from_be_bytes(bytes))
+        }
+        BROADCAST_ADDRESS => DhcpOption::BroadcastAddress(address()?),
+        REQUESTED_ADDRESS => DhcpOption::RequestedAddress(address()?),
+        LEASE_TIME => DhcpOption::LeaseTime(secs()?),
+        MESSAGE_TYPE => match data {
+            &[kind] => DhcpOption::MessageType(MessageType::from_u8(kind).ok_or(invalid)?),
+            _ => return Err(invalid),
+        },
+        SERVER_ID => DhcpOption::ServerId(address()?),
+        PARAMETER_LIST => DhcpOption::ParameterList(data.to_vec()),
+        MESSAGE => DhcpOption::Message(text()),
+        RENEWAL_TIME => DhcpOption::RenewalTime(secs()?),
+        REBINDING_TIME => DhcpOption::RebindingTime(secs()?),
+        CLIENT_ID => DhcpOption::ClientId(data.to_vec()),
+        _ => DhcpOption::Other {
+            code,
+            data: data.to_vec(),
+        },
+    })
+}
+
+/// What an OFFER or ACK gives the client, from its header and options.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Lease {
+    pub address: Option<Ipv4Addr>,
+    pub server: Option<Ipv4Addr>,
+    pub subnet_mask: Option<Ipv4Addr>,
+    pub routers: Vec<Ipv4Addr>,
+    pub dns_servers: Vec<Ipv4Addr>,
+    pub domain_name: Option<String>,
+    pub broadcast: Option<Ipv4Addr>,
+    pub mtu: Option<u16>,
+    /// Seconds
+    pub lease_time: Option<u32>,
+    pub renewal_time: Option<u32>,
+    pub rebinding_time: Option<u32>,
+    /// Options without a field of their own, by code
+    pub other: Vec<u8>,
+}
+
+impl Lease {
+    pub fn from_message(message: &Message) -> Self {
+        let mut lease = Lease {
+            address: Some(message.yiaddr).filter(|a| !a.is_unspecified()),
+            ..Default::default()
+        };
+        for option in &message.options {
+            match option {
+                DhcpOption::SubnetMask(mask) => lease.subnet_mask = Some(*mask),
+                DhcpOption::Router(routers) => lease.routers = routers.clone(),
+                DhcpOption::DnsServer(servers) => lease.dns_servers = servers.clone(),
+                DhcpOption::DomainName(name) => lease.domain_name = Some(name.clone()),
+                DhcpOption::BroadcastAddress(address) => lease.broadcast = Some(*address),
+                DhcpOption::Mtu(mtu) => lease.mtu = Some(*mtu),
+                DhcpOption::LeaseTime(secs) => lease.lease_time = Some(*secs),
+                DhcpOption::RenewalTime(secs) => lease.renewal_time = Some(*secs),
+                DhcpOption::RebindingTime(secs) => lease.rebinding_time = Some(*secs),
+                DhcpOption::ServerId(id) => lease.server = Some(*id),
+                DhcpOption::Other { code, .. } => lease.other.push(*code),
+                _ => {}
+            }
+        }
+        lease
+    }
+
+    /// The prefix length of the subnet mask.
+    pub fn prefix(&self) -> Option<u32> {
+        self.subnet_mask.map(|mask| u32::from(mask).leading_ones())
+    }
+}
+
+/// Seconds as e.g. "1d 2h 3m 4s", leaving out zero units.
+fn duration(secs: u32) -> String {
+    if secs == u32::MAX {
+        return "infinite".to_string();
+    }
+    let units = [
+        (secs / 86400, "d"),
+        (secs / 3600 % 24, "h"),
+        (secs / 60 % 60, "m"),
+    ];
+    let mut text: String = units
+        .iter()
+        .filter(|(n, _)| *n > 0)
+        .map(|(n, unit)| format!("{}{} ", n, unit))
+        .collect();
+    if !secs.is_multiple_of(60) || text.is_empty() {
+        text.push_str(&format!("{}s", secs % 60));
+    }
+    text.trim_end().to_string()
+}
+
+impl fmt::Display for Lease {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let list = |addresses: &[Ipv4Addr]| {
+            addresses
+                .iter()
+                .map(|a| a.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        match (self.address, self.prefix()) {
+            (Some(address), Some(prefix)) => writeln!(f, "  address       {}/{}", address, prefix)?,
+            (Some(address), None) => writeln!(f, "  address       {}", address)?,
+            _ => {}
+        }
+        if let Some(server) = self.server {
+            writeln!(f, "  server        {}", server)?;
+        }
+        if !self.routers.is_empty() {
+            writeln!(f, "  routers       {}", list(&self.routers))?;
+        }
+        if !self.dns_servers.is_empty() {
+            writeln!(f, "  dns servers   {}", list(&self.dns_servers))?;
+        }
+        if let Some(domain) = &self.domain_name {
+            writeln!(f, "  domain        {}", domain)?;
+        }
+        if let Some(broadcast) = self.broadcast {
+            writeln!(f, "  broadcast     {}", broadcast)?;
+        }
+        if let Some(mtu) = self.mtu {
+            writeln!(f, "  mtu           {}", mtu)?;
+        }
+        for (name, secs) in [
+            ("lease time", self.lease_time),
+            ("renewal", self.renewal_time),
+            ("rebinding", self.rebinding_time),
+        ] {
+            if let Some(secs) = secs {
+                writeln!(f, "  {:13} {}", name, duration(secs))?;
+            }
+        }
+        if !self.other.is_empty() {
+            writeln!(f, "  other options {:?}", self.other)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHADDR: [u8; 6] = [0x00, 0x0b, 0x82, 0x01, 0xfc, 0x42];
+
+    /// A message as it comes off the wire: the fixed header, the cookie and
+    /// the raw options.
+    fn raw(op: u8, xid: u32, yiaddr: [u8; 4], siaddr: [u8; 4], options: &[u8]) -> Vec<u8> {
+        let mut buf = vec![op, HTYPE_ETHERNET, 6, 0];
+        buf.extend_from_slice(&xid.to_be_bytes());
+        buf.extend_from_slice(&[0; 8]);
+        buf.extend_from_slice(&yiaddr);
+        buf.extend_from_slice(&siaddr);
+        buf.extend_from_slice(&[0; 4]);
+        buf.extend_from_slice(&CHADDR);
+        buf.resize(OPTIONS_OFFSET, 0);
+        buf.extend_from_slice(&MAGIC_COOKIE);
+        buf.extend_from_slice(options);
+        buf
+    }
+
+    /// The options of a message that has no fixed header of interest.
+    fn options(options: &[u8]) -> Result<Vec<DhcpOption>, DecodeError> {
+        Message::decode(&raw(BOOTREPLY, 1, [0; 4], [0; 4], options)).map(|m| m.options)
+    }
+
+    #[test]
+    fn captured_discover() {
+        let mut buf = raw(
+            BOOTREQUEST,
+            0x3d1d,
+            [0; 4],
+            [0; 4],
+            &[
+                53, 1, 1, // DISCOVER
+                61, 7, 1, 0x00, 0x0b, 0x82, 0x01, 0xfc, 0x42, // client id
+                50, 4, 0, 0, 0, 0, // requested address
+                55, 4, 1, 3, 6, 42,  // parameter list
+                255, // end
+            ],
+        );
+        buf.resize(MIN_SIZE, PAD);
+
+        let message = Message::decode(&buf).unwrap();
+        assert!(message.request);
+        assert_eq!(message.xid, 0x3d1d);
+        assert_eq!(message.chaddr, CHADDR);
+        assert_eq!(message.message_type(), Some(MessageType::Discover));
+        assert_eq!(
+            message.options,
+            [
+                DhcpOption::MessageType(MessageType::Discover),
+                DhcpOption::ClientId(vec![1, 0x00, 0x0b, 0x82, 0x01, 0xfc, 0x42]),
+                DhcpOption::RequestedAddress(Ipv4Addr::UNSPECIFIED),
+                DhcpOption::ParameterList(vec![1, 3, 6, 42]),
+            ]
+        );
+    }
+
+    #[test]
+    fn captured_offer() {
+        let buf = raw(
+            BOOTREPLY,
+            0x3d1d,
+            [192, 168, 0, 10],
+            [192, 168, 0, 1],
+            &[
+                53, 1, 2, // OFFER
+                1, 4, 255, 255, 255, 0, // subnet mask
+                58, 4, 0, 0, 0x07, 0x08, // renewal time
+                59, 4, 0, 0, 0x0c, 0x4e, // rebinding time
+                51, 4, 0, 0, 0x0e, 0x10, // lease time
+                54, 4, 192, 168, 0, 1,   // server id
+                255, // end
+            ],
+        );
+
+        let message = Message::decode(&buf).unwrap();
+        assert!(!message.request);
+        assert_eq!(message.message_type(), Some(MessageType::Offer));
+        assert_eq!(message.server_id(), Some(Ipv4Addr::new(192, 168, 0, 1)));
+        assert_eq!(message.siaddr, Ipv4Addr::new(192, 168, 0, 1));
+
+        let lease = Lease::from_message(&message);
+        assert_eq!(lease.address, Some(Ipv4Addr::new(192, 168, 0, 10)));
+        assert_eq!(lease.prefix(), Some(24));
+        assert_eq!(lease.lease_time, Some(3600));
+        assert_eq!(lease.renewal_time, Some(1800));
+        assert_eq!(lease.rebinding_time, Some(3150));
+        assert_eq!(
+            lease.to_string(),
+            "  address       192.168.0.10/24\n\
+             \x20 server        192.168.0.1\n\
+             \x20 lease time    1h\n\
+             \x20 renewal       30m\n\
+             \x20 rebinding     52m 30s\n"
+        );
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut message = Message::client(MessageType::Request, 0xdead_beef, CHADDR);
+        message.secs = 3;
+        message.options.extend([
+            DhcpOption::RequestedAddress(Ipv4Addr::new(192, 168, 0, 10)),
+            DhcpOption::ServerId(Ipv4Addr::new(192, 168, 0, 1)),
+            DhcpOption::HostName("lab".to_string()),
+            DhcpOption::ParameterList(REQUESTED_PARAMETERS.to_vec()),
+            DhcpOption::Other {
+                code: 224,
+                data: vec![1, 2, 3],
+            },
+        ]);
+        let buf = message.encode();
+        assert_eq!(buf.len(), MIN_SIZE, "padded to the BOOTP size");
+        assert_eq!(Message::decode(&buf), Ok(message));
+    }
+
+    #[test]
+    fn pad_and_end() {
+        assert_eq!(
+            options(&[PAD, PAD, 53, 1, 5, PAD, 26, 2, 0x05, 0xdc, END, 53, 1, 6]),
+            Ok(vec![
+                DhcpOption::MessageType(MessageType::Ack),
+                DhcpOption::Mtu(1500),
+            ]),
+            "pads are skipped and nothing after the end is read"
+        );
+        assert_eq!(
+            options(&[53, 1, 5]),
+            Ok(vec![DhcpOption::MessageType(MessageType::Ack)]),
+            "the end may be missing"
+        );
+        assert_eq!(options(&[]), Ok(vec![]));
+    }
+
+    #[test]
+    fn overlong_options() {
+        assert_eq!(options(&[53, 2, 5]), Err(DecodeError::Truncated));
+        assert_eq!(
+            options(&[15, 255, b'l', b'a', b'b']),
+            Err(DecodeError::Truncated)
+        );
+        // The length itself is missing
+        assert_eq!(options(&[53]), Err(DecodeError::Truncated));
+    }
+
+    #[test]
+    fn options_of_the_wrong_length() {
+        for (code, data) in [
+            (SUBNET_MASK, &[255, 255, 255][..]),
+            (ROUTER, &[10, 0, 0, 1, 10, 0][..]),
+            (DNS_SERVER, &[][..]),
+            (MTU, &[5, 220, 0][..]),
+            (LEASE_TIME, &[0, 0, 14][..]),
+            (MESSAGE_TYPE, &[5, 5][..]),
+            // No such message type
+            (MESSAGE_TYPE, &[9][..]),
+        ] {
+            let mut raw = vec![code, data.len() as u8];
+            raw.extend_from_slice(data);
+            assert_eq!(options(&raw), Err(DecodeError::Option(code)), "{:?}", raw);
+        }
+    }
+
+    #[test]
+    fn strings_lose_trailing_nuls() {
+        assert_eq!(
+            options(&[15, 5, b'l', b'a', b'b', 0, 0]),
+            Ok(vec![DhcpOption::DomainName("lab".to_string())])
+        );
+    }
+
+    #[test]
+    fn not_dhcp() {
+        let buf = raw(BOOTREPLY, 1, [0; 4], [0; 4], &[END]);
+        assert_eq!(
+            Message::decode(&buf[..OPTIONS_OFFSET + 3]),
+            Err(DecodeError::Truncated)
+        );
+        let mut bad_cookie = buf.clone();
+        bad_cookie[OPTIONS_OFFSET + 3] = 0;
+        assert_eq!(Message::decode(&bad_cookie), Err(DecodeError::NotDhcp));
+        let mut bad_op = buf.clone();
+        bad_op[0] = 3;
+        assert_eq!(Message::decode(&bad_op), Err(DecodeError::NotDhcp));
+        let mut not_ethernet = buf;
+        not_ethernet[1] = 6;
+        assert_eq!(Message::decode(&not_ethernet), Err(DecodeError::NotDhcp));
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(duration(0), "0s");
+        assert_eq!(duration(59), "59s");
+        assert_eq!(duration(3600), "1h");
+        assert_eq!(duration(90061), "1d 1h 1m 1s");
+        assert_eq!(duration(86400 + 120), "1d 2m");
+        assert_eq!(duration(u32::MAX), "infinite");
+    }
+}
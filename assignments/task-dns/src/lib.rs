@@ -0,0 +1,349 @@
+//! DNS stub resolver. Queries are built and parsed by hand in [`message`],
+//! sent over UDP with retransmission, and repeated over TCP when the UDP
+//! answer is truncated. The time each query takes is reported, so the
+//! program doubles as a resolver latency probe. The task-dns binary and the
+//! `adnet dns` subcommand are thin wrappers around [`run`].
+
+pub mod message;
+
+use std::{
+    collections::hash_map::RandomState,
+    error::Error,
+    fs,
+    hash::{BuildHasher, Hasher},
+    io::{self, Read, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, ToSocketAddrs, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use adnet_core::{
+    config::{self, ConfigArgs},
+    logging::{self, LogArgs},
+    report::{Report, ReportArgs},
+};
+use clap::{Parser, ValueEnum};
+use message::{Response, TYPE_A, TYPE_AAAA, TYPE_TXT};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info};
+
+const DNS_PORT: u16 = 53;
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(2);
+const DEFAULT_RETRIES: u32 = 2;
+const RESOLV_CONF: &str = "/etc/resolv.conf";
+// Largest UDP answer without EDNS
+const UDP_SIZE: usize = 512;
+
+#[derive(ValueEnum, Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum QueryType {
+    A,
+    Aaaa,
+    Txt,
+}
+
+impl QueryType {
+    fn code(self) -> u16 {
+        match self {
+            QueryType::A => TYPE_A,
+            QueryType::Aaaa => TYPE_AAAA,
+            QueryType::Txt => TYPE_TXT,
+        }
+    }
+}
+
+/// Looks up DNS records and measures how long the resolver takes to answer.
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+pub struct Args {
+    /// Domain name to look up
+    name: Option<String>,
+
+    /// Record types to query, can be given several times [default: a]
+    #[arg(short = 't', long = "type", value_name = "TYPE")]
+    types: Vec<QueryType>,
+
+    /// Resolver as address or address:port [default: first nameserver in /etc/resolv.conf]
+    #[arg(short, long)]
+    server: Option<String>,
+
+    /// Seconds to wait for the first UDP answer, doubled on every retry [default: 2]
+    #[arg(short = 'W', long, value_parser = config::parse_secs)]
+    timeout: Option<Duration>,
+
+    /// Times to resend an unanswered UDP query [default: 2]
+    #[arg(short, long)]
+    retries: Option<u32>,
+
+    /// Query over TCP only
+    #[arg(long)]
+    tcp: bool,
+
+    #[command(flatten)]
+    config: ConfigArgs,
+
+    #[command(flatten)]
+    log: LogArgs,
+
+    #[command(flatten)]
+    report: ReportArgs,
+}
+
+/// The [task-dns] section of the config file
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct FileConfig {
+    name: Option<String>,
+    types: Option<Vec<QueryType>>,
+    server: Option<String>,
+    #[serde(deserialize_with = "config::secs")]
+    timeout: Option<Duration>,
+    retries: Option<u32>,
+    tcp: bool,
+}
+
+/// How the answer to a query was obtained.
+pub struct Outcome {
+    pub response: Response,
+    pub tcp: bool,
+    /// UDP queries sent, including retransmissions
+    pub attempts: u32,
+    pub elapsed: Duration,
+}
+
+/// A query and its answer in the report.
+#[derive(Serialize)]
+struct QueryReport {
+    #[serde(rename = "type")]
+    kind: QueryType,
+    rcode: String,
+    tcp: bool,
+    attempts: u32,
+    elapsed_ms: f64,
+    answers: Vec<String>,
+}
+
+/// Settings for sending queries to one resolver.
+pub struct Resolver {
+    pub server: SocketAddr,
+    pub timeout: Duration,
+    pub retries: u32,
+    pub tcp_only: bool,
+}
+
+impl Resolver {
+    /// Sends the query over UDP, or over TCP if so configured or if the UDP
+    /// answer was truncated.
+    pub fn query(&self, name: &str, kind: u16) -> Result<Outcome, Box<dyn Error>> {
+        let id = random_id();
+        let query = message::encode_query(id, name, kind)?;
+        let start = Instant::now();
+
+        let mut attempts = 0;
+        if !self.tcp_only {
+            let (response, sent) = self.query_udp(&query, id)?;
+            attempts = sent;
+            if !response.truncated {
+                return Ok(Outcome {
+                    response,
+                    tcp: false,
+                    attempts,
+                    elapsed: start.elapsed(),
+                });
+            }
+            info!("UDP answer was truncated, retrying over TCP");
+        }
+
+        let response = self.query_tcp(&query, id)?;
+        Ok(Outcome {
+            response,
+            tcp: true,
+            attempts,
+            elapsed: start.elapsed(),
+        })
+    }
+
+    /// Returns the response and the number of queries sent.
+    fn query_udp(&self, query: &[u8], id: u16) -> Result<(Response, u32), Box<dyn Error>> {
+        let any: IpAddr = match self.server {
+            SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+            SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+        };
+        let socket = UdpSocket::bind(SocketAddr::new(any, 0))?;
+        // Connecting filters out datagrams from other addresses
+        socket.connect(self.server)?;
+
+        let mut buf = [0u8; UDP_SIZE];
+        let mut timeout = self.timeout;
+        for attempt in 1..=self.retries + 1 {
+            socket.send(query)?;
+            let deadline = Instant::now() + timeout;
+
+            while let Some(left) = deadline.checked_duration_since(Instant::now()) {
+                socket.set_read_timeout(Some(left.max(Duration::from_millis(1))))?;
+                let n = match socket.recv(&mut buf) {
+                    Ok(n) => n,
+                    Err(e)
+                        if e.kind() == io::ErrorKind::WouldBlock
+                            || e.kind() == io::ErrorKind::TimedOut =>
+                    {
+                        break;
+                    }
+                    Err(e) => return Err(e.into()),
+                };
+                match Response::parse(&buf[..n]) {
+                    Ok(response) if response.id == id => return Ok((response, attempt)),
+                    Ok(response) => debug!("Ignoring answer with id {}", response.id),
+                    Err(e) => debug!("Ignoring invalid answer: {}", e),
+                }
+            }
+            debug!("No answer in {:?} (attempt {})", timeout, attempt);
+            timeout *= 2;
+        }
+        Err(format!(
+            "No answer from {} after {} attempts",
+            self.server,
+            self.retries + 1
+        )
+        .into())
+    }
+
+    /// Over TCP, messages are preceded by their length in two bytes.
+    fn query_tcp(&self, query: &[u8], id: u16) -> Result<Response, Box<dyn Error>> {
+        let mut stream = TcpStream::connect_timeout(&self.server, self.timeout)
+            .map_err(|e| format!("TCP connection to {} failed: {}", self.server, e))?;
+        stream.set_read_timeout(Some(self.timeout))?;
+
+        let mut request = Vec::with_capacity(2 + query.len());
+        request.extend_from_slice(&(query.len() as u16).to_be_bytes());
+        request.extend_from_slice(query);
+        stream.write_all(&request)?;
+
+        let mut len = [0u8; 2];
+        stream.read_exact(&mut len)?;
+        let mut answer = vec![0u8; u16::from_be_bytes(len) as usize];
+        stream.read_exact(&mut answer)?;
+
+        let response = Response::parse(&answer)?;
+        if response.id != id {
+            return Err(format!("Answer has id {}, expected {}", response.id, id).into());
+        }
+        Ok(response)
+    }
+}
+
+/// An unpredictable query id, so that spoofed answers are harder to guess.
+fn random_id() -> u16 {
+    RandomState::new().build_hasher().finish() as u16
+}
+
+/// Parses the resolver given as address, address:port or host:port.
+fn parse_server(server: &str) -> io::Result<SocketAddr> {
+    if let Ok(addr) = server.parse::<SocketAddr>() {
+        return Ok(addr);
+    }
+    if let Ok(ip) = server.parse::<IpAddr>() {
+        return Ok(SocketAddr::new(ip, DNS_PORT));
+    }
+    server.to_socket_addrs()?.next().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} did not resolve to any address", server),
+        )
+    })
+}
+
+/// The first nameserver of the system configuration.
+fn system_server() -> io::Result<SocketAddr> {
+    let text = fs::read_to_string(RESOLV_CONF)
+        .map_err(|e| io::Error::new(e.kind(), format!("Cannot read {}: {}", RESOLV_CONF, e)))?;
+    text.lines()
+        .filter_map(|line| line.trim().strip_prefix("nameserver"))
+        .find_map(|ip| ip.trim().parse::<IpAddr>().ok())
+        .map(|ip| SocketAddr::new(ip, DNS_PORT))
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("No nameserver in {}, use --server", RESOLV_CONF),
+            )
+        })
+}
+
+fn rcode_name(rcode: u8) -> String {
+    match rcode {
+        0 => "NOERROR".to_string(),
+        1 => "FORMERR".to_string(),
+        2 => "SERVFAIL".to_string(),
+        3 => "NXDOMAIN".to_string(),
+        4 => "NOTIMP".to_string(),
+        5 => "REFUSED".to_string(),
+        code => format!("RCODE{}", code),
+    }
+}
+
+/// Runs the lookup with the given arguments, as the task-dns binary does.
+pub fn run(args: Args) -> Result<(), Box<dyn Error>> {
+    logging::init(&args.log)?;
+    let report = args.report.clone();
+    report.run("task-dns", |report| lookup(args, report))
+}
+
+fn lookup(args: Args, report: &mut Report) -> Result<(), Box<dyn Error>> {
+    // Command line options take precedence over the config file
+    let file: FileConfig = args.config.section("task-dns")?;
+    let name = args.name.or(file.name).ok_or("Domain name is required")?;
+    let mut types = args.types;
+    if types.is_empty() {
+        types = file.types.unwrap_or_else(|| vec![QueryType::A]);
+    }
+    let server = match args.server.or(file.server) {
+        Some(server) => parse_server(&server)?,
+        None => system_server()?,
+    };
+    let resolver = Resolver {
+        server,
+        timeout: args.timeout.or(file.timeout).unwrap_or(DEFAULT_TIMEOUT),
+        retries: args.retries.or(file.retries).unwrap_or(DEFAULT_RETRIES),
+        tcp_only: args.tcp || file.tcp,
+    };
+
+    let mut queries = Vec::new();
+    for kind in types {
+        let outcome = resolver.query(&name, kind.code())?;
+        let transport = match (outcome.tcp, outcome.attempts) {
+            (false, n) => format!("UDP, {} attempt(s)", n),
+            (true, 0) => "TCP".to_string(),
+            (true, n) => format!("TCP after {} UDP attempt(s)", n),
+        };
+        println!(
+            ";; {} {:?} from {} ({}): {} in {:.3} ms",
+            name,
+            kind,
+            server,
+            transport,
+            rcode_name(outcome.response.rcode),
+            outcome.elapsed.as_secs_f64() * 1000.0
+        );
+        for record in &outcome.response.answers {
+            println!("{}\t{}\t{}", record.name, record.ttl, record.data);
+        }
+
+        // Every UDP attempt after the first is a retransmission
+        report.retransmits += outcome.attempts.saturating_sub(1) as u64;
+        queries.push(QueryReport {
+            kind,
+            rcode: rcode_name(outcome.response.rcode),
+            tcp: outcome.tcp,
+            attempts: outcome.attempts,
+            elapsed_ms: outcome.elapsed.as_secs_f64() * 1000.0,
+            answers: outcome
+                .response
+                .answers
+                .iter()
+                .map(|record| record.data.to_string())
+                .collect(),
+        });
+    }
+    report.detail("name", &name);
+    report.detail("queries", queries);
+    Ok(())
+}
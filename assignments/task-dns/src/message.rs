@@ -0,0 +1,616 @@
+//! Encoding of DNS queries and parsing of responses (RFC 1035). Only what a
+//! stub resolver and an mDNS querier need: one question per query, and the
+//! answer and additional sections of the response with A, AAAA, TXT, CNAME,
+//! PTR and SRV records decoded.
+
+use std::{
+    error::Error,
+    fmt,
+    net::{Ipv4Addr, Ipv6Addr},
+};
+
+pub const HEADER_SIZE: usize = 12;
+pub const CLASS_IN: u16 = 1;
+// Recursion desired
+const FLAG_RD: u16 = 0x0100;
+const FLAG_QR: u16 = 0x8000;
+const FLAG_TC: u16 = 0x0200;
+const MAX_LABEL: usize = 63;
+const MAX_NAME: usize = 255;
+// Bound on compression pointers followed in one name, against loops
+const MAX_POINTERS: usize = 64;
+
+pub const TYPE_A: u16 = 1;
+pub const TYPE_CNAME: u16 = 5;
+pub const TYPE_PTR: u16 = 12;
+pub const TYPE_TXT: u16 = 16;
+pub const TYPE_AAAA: u16 = 28;
+pub const TYPE_SRV: u16 = 33;
+
+/// Errors in building a query or parsing a response.
+#[derive(Debug, PartialEq, Eq)]
+pub enum MessageError {
+    /// The name cannot be encoded: empty label, or a label or the whole name too long
+    InvalidName(String),
+    /// The message ended in the middle of a field
+    Truncated,
+    /// A compression pointer points outside the message or loops
+    BadPointer,
+    /// The message is a query, not a response
+    NotResponse,
+}
+
+impl fmt::Display for MessageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MessageError::InvalidName(name) => write!(f, "Invalid domain name {:?}", name),
+            MessageError::Truncated => write!(f, "DNS message is truncated"),
+            MessageError::BadPointer => write!(f, "Invalid compression pointer in DNS message"),
+            MessageError::NotResponse => write!(f, "DNS message is not a response"),
+        }
+    }
+}
+
+impl Error for MessageError {}
+
+/// Builds a recursive query for one name and record type.
+pub fn encode_query(id: u16, name: &str, kind: u16) -> Result<Vec<u8>, MessageError> {
+    encode_question(id, FLAG_RD, name, kind, CLASS_IN)
+}
+
+/// Builds a query with the given header flags and question class, for
+/// protocols that use them differently, such as mDNS.
+pub fn encode_question(
+    id: u16,
+    flags: u16,
+    name: &str,
+    kind: u16,
+    class: u16,
+) -> Result<Vec<u8>, MessageError> {
+    let mut message = Vec::with_capacity(HEADER_SIZE + name.len() + 6);
+    message.extend_from_slice(&id.to_be_bytes());
+    message.extend_from_slice(&flags.to_be_bytes());
+    // One question, no answer, authority or additional records
+    message.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]);
+    encode_name(&mut message, name)?;
+    message.extend_from_slice(&kind.to_be_bytes());
+    message.extend_from_slice(&class.to_be_bytes());
+    Ok(message)
+}
+
+fn encode_name(out: &mut Vec<u8>, name: &str) -> Result<(), MessageError> {
+    let invalid = || MessageError::InvalidName(name.to_string());
+    let trimmed = name.strip_suffix('.').unwrap_or(name);
+    let start = out.len();
+    if !trimmed.is_empty() {
+        for label in trimmed.split('.') {
+            if label.is_empty() || label.len() > MAX_LABEL {
+                return Err(invalid());
+            }
+            out.push(label.len() as u8);
+            out.extend_from_slice(label.as_bytes());
+        }
+    }
+    out.push(0);
+    if out.len() - start > MAX_NAME {
+        return Err(invalid());
+    }
+    Ok(())
+}
+
+/// The data of a resource record.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RecordData {
+    A(Ipv4Addr),
+    Aaaa(Ipv6Addr),
+    Txt(Vec<String>),
+    Cname(String),
+    Ptr(String),
+    Srv {
+        priority: u16,
+        weight: u16,
+        port: u16,
+        target: String,
+    },
+    Other(Vec<u8>),
+}
+
+impl fmt::Display for RecordData {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RecordData::A(ip) => write!(f, "A {}", ip),
+            RecordData::Aaaa(ip) => write!(f, "AAAA {}", ip),
+            RecordData::Txt(strings) => {
+                write!(f, "TXT")?;
+                for s in strings {
+                    write!(f, " {:?}", s)?;
+                }
+                Ok(())
+            }
+            RecordData::Cname(name) => write!(f, "CNAME {}", name),
+            RecordData::Ptr(name) => write!(f, "PTR {}", name),
+            RecordData::Srv {
+                priority,
+                weight,
+                port,
+                target,
+            } => write!(f, "SRV {} {} {} {}", priority, weight, port, target),
+            RecordData::Other(data) => write!(f, "({} bytes)", data.len()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub name: String,
+    pub kind: u16,
+    pub ttl: u32,
+    pub data: RecordData,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Response {
+    pub id: u16,
+    /// The answer did not fit in the UDP datagram and should be asked over TCP
+    pub truncated: bool,
+    /// Response code, 0 for no error and 3 for a name that does not exist
+    pub rcode: u8,
+    pub answers: Vec<Record>,
+    /// Records the server adds because the asker will likely need them,
+    /// e.g. the SRV and A records of the instances in an mDNS answer
+    pub additional: Vec<Record>,
+}
+
+impl Response {
+    pub fn parse(message: &[u8]) -> Result<Self, MessageError> {
+        let mut reader = Reader { message, pos: 0 };
+        let id = reader.u16()?;
+        let flags = reader.u16()?;
+        if flags & FLAG_QR == 0 {
+            return Err(MessageError::NotResponse);
+        }
+        let questions = reader.u16()?;
+        let answers = reader.u16()?;
+        let authority = reader.u16()?;
+        let additional = reader.u16()?;
+
+        for _ in 0..questions {
+            reader.name()?;
+            reader.take(4)?; // type and class
+        }
+
+        // A truncated response may end in the middle of the answers
+        let truncated = flags & FLAG_TC != 0;
+        let mut sections = [
+            Vec::with_capacity(answers as usize),
+            Vec::new(),
+            Vec::with_capacity(additional as usize),
+        ];
+        let counts = [answers, authority, additional];
+        'sections: for (records, count) in sections.iter_mut().zip(counts) {
+            for _ in 0..count {
+                match reader.record() {
+                    Ok(record) => records.push(record),
+                    Err(MessageError::Truncated) if truncated => break 'sections,
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        let [answers, _, additional] = sections;
+
+        Ok(Response {
+            id,
+            truncated,
+            rcode: (flags & 0x000f) as u8,
+            answers,
+            additional,
+        })
+    }
+}
+
+struct Reader<'a> {
+    message: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], MessageError> {
+        let bytes = self
+            .message
+            .get(self.pos..self.pos + n)
+            .ok_or(MessageError::Truncated)?;
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn u16(&mut self) -> Result<u16, MessageError> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, MessageError> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_be_bytes(bytes.try_into().unwrap()))
+    }
+
+    /// Reads a possibly compressed name, leaving the position after it.
+    fn name(&mut self) -> Result<String, MessageError> {
+        let mut labels: Vec<String> = Vec::new();
+        let mut pos = self.pos;
+        let mut end = None;
+        let mut pointers = 0;
+
+        loop {
+            let len = *self.message.get(pos).ok_or(MessageError::Truncated)? as usize;
+            match len {
+                0 => {
+                    end.get_or_insert(pos + 1);
+                    break;
+                }
+                // The two top bits mark a pointer to an earlier name
+                l if l & 0xc0 == 0xc0 => {
+                    let low = *self.message.get(pos + 1).ok_or(MessageError::Truncated)?;
+                    end.get_or_insert(pos + 2);
+                    pointers += 1;
+                    pos = ((l & 0x3f) << 8) | low as usize;
+                    if pointers > MAX_POINTERS || pos >= self.message.len() {
+                        return Err(MessageError::BadPointer);
+                    }
+                }
+                l if l > MAX_LABEL => return Err(MessageError::BadPointer),
+                l => {
+                    let label = self
+                        .message
+                        .get(pos + 1..pos + 1 + l)
+                        .ok_or(MessageError::Truncated)?;
+                    labels.push(String::from_utf8_lossy(label).into_owned());
+                    pos += 1 + l;
+                }
+            }
+        }
+
+        self.pos = end.unwrap_or(pos);
+        match labels.is_empty() {
+            true => Ok(".".to_string()),
+            false => Ok(labels.join(".")),
+        }
+    }
+
+    /// Reads a name at another position, leaving this reader's alone.
+    fn name_at(&self, pos: usize) -> Result<String, MessageError> {
+        Reader {
+            message: self.message,
+            pos,
+        }
+        .name()
+    }
+
+    fn record(&mut self) -> Result<Record, MessageError> {
+        let name = self.name()?;
+        let kind = self.u16()?;
+        let _class = self.u16()?;
+        let ttl = self.u32()?;
+        let len = self.u16()? as usize;
+        let start = self.pos;
+        let rdata = self.take(len)?;
+
+        let data = match (kind, len) {
+            (TYPE_A, 4) => RecordData::A(Ipv4Addr::from(<[u8; 4]>::try_from(rdata).unwrap())),
+            (TYPE_AAAA, 16) => {
+                RecordData::Aaaa(Ipv6Addr::from(<[u8; 16]>::try_from(rdata).unwrap()))
+            }
+            (TYPE_TXT, _) => RecordData::Txt(character_strings(rdata)?),
+            // The names may point anywhere in the message, so read them in place
+            (TYPE_CNAME, _) => RecordData::Cname(self.name_at(start)?),
+            (TYPE_PTR, _) => RecordData::Ptr(self.name_at(start)?),
+            (TYPE_SRV, 7..) => RecordData::Srv {
+                priority: u16::from_be_bytes([rdata[0], rdata[1]]),
+                weight: u16::from_be_bytes([rdata[2], rdata[3]]),
+                port: u16::from_be_bytes([rdata[4], rdata[5]]),
+                target: self.name_at(start + 6)?,
+            },
+            _ => RecordData::Other(rdata.to_vec()),
+        };
+        Ok(Record {
+            name,
+            kind,
+            ttl,
+            data,
+        })
+    }
+}
+
+/// Splits TXT data into its length-prefixed strings.
+fn character_strings(mut data: &[u8]) -> Result<Vec<String>, MessageError> {
+    let mut strings = Vec::new();
+    while let Some((&len, rest)) = data.split_first() {
+        let s = rest.get(..len as usize).ok_or(MessageError::Truncated)?;
+        strings.push(String::from_utf8_lossy(s).into_owned());
+        data = &rest[len as usize..];
+    }
+    Ok(strings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Where the name of the question starts, for compression pointers to it
+    const QUESTION_NAME: [u8; 2] = [0xc0, 12];
+
+    fn record(name: &[u8], kind: u16, ttl: u32, rdata: &[u8]) -> Vec<u8> {
+        let mut out = name.to_vec();
+        out.extend_from_slice(&kind.to_be_bytes());
+        out.extend_from_slice(&CLASS_IN.to_be_bytes());
+        out.extend_from_slice(&ttl.to_be_bytes());
+        out.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+        out.extend_from_slice(rdata);
+        out
+    }
+
+    /// The answer of a server to `query`: the query with the response flag
+    /// and the answers appended.
+    fn response(query: &[u8], flags: u16, answers: &[Vec<u8>]) -> Vec<u8> {
+        let mut out = query.to_vec();
+        out[2..4].copy_from_slice(&(FLAG_QR | flags).to_be_bytes());
+        out[6..8].copy_from_slice(&(answers.len() as u16).to_be_bytes());
+        for answer in answers {
+            out.extend_from_slice(answer);
+        }
+        out
+    }
+
+    #[test]
+    fn query_layout() {
+        let query = encode_query(0x1234, "example.com.", TYPE_AAAA).unwrap();
+        let mut expected = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        expected.extend_from_slice(b"\x07example\x03com\x00");
+        expected.extend_from_slice(&[0, 28, 0, 1]);
+        assert_eq!(query, expected);
+        assert_eq!(
+            encode_query(1, "example.com", TYPE_AAAA).unwrap()[2..],
+            query[2..],
+            "the trailing dot is optional"
+        );
+    }
+
+    #[test]
+    fn invalid_names() {
+        for name in ["a..b", ".a", &"x".repeat(64), &["abc"; 64].join(".")] {
+            assert_eq!(
+                encode_query(1, name, TYPE_A),
+                Err(MessageError::InvalidName(name.to_string()))
+            );
+        }
+        assert!(encode_query(1, &"x".repeat(63), TYPE_A).is_ok());
+    }
+
+    #[test]
+    fn round_trip_with_compression() {
+        let query = encode_query(7, "www.example.com", TYPE_A).unwrap();
+        // www.example.com CNAME web.example.com, whose suffix points into
+        // the question, and an A record for the CNAME target
+        let mut target = b"\x03web".to_vec();
+        target.extend_from_slice(&[0xc0, 16]);
+        let target_at = query.len() + QUESTION_NAME.len() + 10;
+        let answers = [
+            record(&QUESTION_NAME, TYPE_CNAME, 300, &target),
+            record(&[0xc0, target_at as u8], TYPE_A, 60, &[192, 0, 2, 1]),
+        ];
+        let message = response(&query, 0x0180, &answers);
+
+        let response = Response::parse(&message).unwrap();
+        assert_eq!(response.id, 7);
+        assert!(!response.truncated);
+        assert_eq!(response.rcode, 0);
+        assert_eq!(
+            response.answers,
+            [
+                Record {
+                    name: "www.example.com".to_string(),
+                    kind: TYPE_CNAME,
+                    ttl: 300,
+                    data: RecordData::Cname("web.example.com".to_string()),
+                },
+                Record {
+                    name: "web.example.com".to_string(),
+                    kind: TYPE_A,
+                    ttl: 60,
+                    data: RecordData::A(Ipv4Addr::new(192, 0, 2, 1)),
+                },
+            ]
+        );
+        assert!(response.additional.is_empty());
+    }
+
+    #[test]
+    fn rcode() {
+        let query = encode_query(1, "nowhere.example", TYPE_A).unwrap();
+        let response = Response::parse(&response(&query, 0x0183, &[])).unwrap();
+        assert_eq!(response.rcode, 3);
+        assert!(response.answers.is_empty());
+    }
+
+    #[test]
+    fn query_is_not_a_response() {
+        let query = encode_query(1, "example.com", TYPE_A).unwrap();
+        assert_eq!(Response::parse(&query), Err(MessageError::NotResponse));
+    }
+
+    #[test]
+    fn truncated_input() {
+        let query = encode_query(1, "example.com", TYPE_A).unwrap();
+        let answer = record(&QUESTION_NAME, TYPE_A, 60, &[192, 0, 2, 1]);
+        let message = response(&query, 0, &[answer]);
+        for len in 0..message.len() {
+            assert_eq!(
+                Response::parse(&message[..len]),
+                Err(MessageError::Truncated),
+                "{} of {} bytes",
+                len,
+                message.len()
+            );
+        }
+    }
+
+    #[test]
+    fn truncated_flag_keeps_the_answers_that_fit() {
+        let query = encode_query(1, "example.com", TYPE_A).unwrap();
+        let answers = [
+            record(&QUESTION_NAME, TYPE_A, 60, &[192, 0, 2, 1]),
+            record(&QUESTION_NAME, TYPE_A, 60, &[192, 0, 2, 2]),
+        ];
+        let message = response(&query, FLAG_TC, &answers);
+        let response = Response::parse(&message[..message.len() - 3]).unwrap();
+        assert!(response.truncated);
+        assert_eq!(response.answers.len(), 1);
+        assert_eq!(
+            response.answers[0].data,
+            RecordData::A(Ipv4Addr::new(192, 0, 2, 1))
+        );
+    }
+
+    #[test]
+    fn pointer_loops() {
+        let query = encode_query(1, "example.com", TYPE_A).unwrap();
+        let at = query.len() as u8;
+        // A name that points at itself, and two that point at each other
+        let cases: [&[u8]; 2] = [&[0xc0, at], &[0x01, b'a', 0xc0, at + 4, 0xc0, at]];
+        for name in cases {
+            let message = response(&query, 0, &[record(name, TYPE_A, 60, &[0; 4])]);
+            assert_eq!(
+                Response::parse(&message),
+                Err(MessageError::BadPointer),
+                "{:?}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn pointer_outside_the_message() {
+        let query = encode_query(1, "example.com", TYPE_A).unwrap();
+        let message = response(&query, 0, &[record(&[0xc0, 0xff], TYPE_A, 60, &[0; 4])]);
+        assert_eq!(Response::parse(&message), Err(MessageError::BadPointer));
+    }
+
+    /// An mDNS response: no question, and the records in the answer and
+    /// additional sections.
+    fn mdns_response(answers: &[Vec<u8>], additional: &[Vec<u8>]) -> Vec<u8> {
+        let mut out = vec![0, 0, 0x84, 0, 0, 0];
+        out.extend_from_slice(&(answers.len() as u16).to_be_bytes());
+        out.extend_from_slice(&[0, 0]);
+        out.extend_from_slice(&(additional.len() as u16).to_be_bytes());
+        for record in answers.iter().chain(additional) {
+            out.extend_from_slice(record);
+        }
+        out
+    }
+
+    #[test]
+    fn mdns_records() {
+        // _ipp._tcp.local PTR Printer._ipp._tcp.local, whose SRV, TXT and A
+        // records come along, all names but the first compressed
+        let service = b"\x04_ipp\x04_tcp\x05local\x00";
+        let local = HEADER_SIZE as u8 + 10;
+        let ptr = record(service, TYPE_PTR, 4500, b"\x07Printer\xc0\x0c");
+        let instance = (HEADER_SIZE + ptr.len() - 10) as u8;
+        let mut srv = vec![0, 0, 0, 0, 0x02, 0x77];
+        srv.extend_from_slice(b"\x07printer");
+        srv.extend_from_slice(&[0xc0, local]);
+        let srv = record(&[0xc0, instance], TYPE_SRV, 120, &srv);
+        let host = (HEADER_SIZE + ptr.len() + srv.len() - 10) as u8;
+        let mut txt = record(
+            &[0xc0, instance],
+            TYPE_TXT,
+            4500,
+            b"\x09txtvers=1\x06rp=ipp\x00",
+        );
+        // The cache-flush bit of mDNS in the class
+        txt[4..6].copy_from_slice(&0x8001u16.to_be_bytes());
+        let a = record(&[0xc0, host], TYPE_A, 120, &[192, 168, 1, 20]);
+        let message = mdns_response(&[ptr], &[srv, txt, a]);
+
+        let response = Response::parse(&message).unwrap();
+        assert_eq!(response.answers.len(), 1);
+        assert_eq!(response.answers[0].name, "_ipp._tcp.local");
+        assert_eq!(
+            response.answers[0].data,
+            RecordData::Ptr("Printer._ipp._tcp.local".to_string())
+        );
+        let additional: Vec<_> = response
+            .additional
+            .iter()
+            .map(|record| (record.name.as_str(), record.kind, &record.data))
+            .collect();
+        assert_eq!(
+            additional,
+            [
+                (
+                    "Printer._ipp._tcp.local",
+                    TYPE_SRV,
+                    &RecordData::Srv {
+                        priority: 0,
+                        weight: 0,
+                        port: 631,
+                        target: "printer.local".to_string(),
+                    }
+                ),
+                (
+                    "Printer._ipp._tcp.local",
+                    TYPE_TXT,
+                    &RecordData::Txt(vec![
+                        "txtvers=1".to_string(),
+                        "rp=ipp".to_string(),
+                        String::new()
+                    ])
+                ),
+                (
+                    "printer.local",
+                    TYPE_A,
+                    &RecordData::A(Ipv4Addr::new(192, 168, 1, 20))
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn truncated_rdata() {
+        let name = b"\x04host\x05local\x00";
+        // A TXT string longer than the data left
+        let txt = record(name, TYPE_TXT, 120, b"\x05abc");
+        assert_eq!(
+            Response::parse(&mdns_response(&[txt], &[])),
+            Err(MessageError::Truncated)
+        );
+        // Data longer than the message
+        let mut a = record(name, TYPE_A, 120, &[192, 168, 1, 20]);
+        a.truncate(a.len() - 1);
+        assert_eq!(
+            Response::parse(&mdns_response(&[a], &[])),
+            Err(MessageError::Truncated)
+        );
+        // A PTR name cut short
+        let ptr = record(name, TYPE_PTR, 120, b"\x07Printer");
+        assert_eq!(
+            Response::parse(&mdns_response(&[ptr], &[])),
+            Err(MessageError::Truncated)
+        );
+    }
+
+    #[test]
+    fn short_rdata_is_kept_raw() {
+        let name = b"\x04host\x05local\x00";
+        let a = record(name, TYPE_A, 120, &[192, 168, 1]);
+        let srv = record(name, TYPE_SRV, 120, &[0, 0, 0, 0, 0x02]);
+        let response = Response::parse(&mdns_response(&[a, srv], &[])).unwrap();
+        assert_eq!(
+            response.answers[0].data,
+            RecordData::Other(vec![192, 168, 1])
+        );
+        assert_eq!(
+            response.answers[1].data,
+            RecordData::Other(vec![0, 0, 0, 0, 0x02])
+        );
+    }
+}
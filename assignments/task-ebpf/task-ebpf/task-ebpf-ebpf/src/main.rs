@@ -3,9 +3,10 @@
 
 use aya_ebpf::{
     bindings::xdp_action,
-    macros::{map, xdp},
-    maps::Array,
-    programs::XdpContext,
+    helpers::{bpf_get_current_pid_tgid, bpf_ktime_get_ns},
+    macros::{cgroup_skb, map, xdp},
+    maps::{Array, HashMap, LruHashMap},
+    programs::{SkBuffContext, XdpContext},
 };
 use core::mem;
 use network_types::{
@@ -15,14 +16,73 @@ use network_types::{
     udp::UdpHdr,
 };
 
-const TCP_443: u32 = 0;
-const UDP_443: u32 = 1;
-const ICMP: u32 = 2;
-const TCP_80: u32 = 3;
+const ICMP: u32 = 0;
+const TCP_8080_REWRITTEN: u32 = 1;
+const RATE_LIMITED: u32 = 2;
+const SYN_LIMITED: u32 = 3;
+
+// Actions of the RULES map, written by the userspace loader. 0 lets the
+// packets pass uncounted
+const ACTION_DROP: u32 = 1;
+const ACTION_COUNT: u32 = 2;
+
+// Indices of the port rewrite switch and the per-source limits in CONFIG,
+// written by the userspace loader. A limit of 0 is no limit
+const REWRITE_8080: u32 = 0;
+const PPS_LIMIT: u32 = 1;
+const SYN_LIMIT: u32 = 2;
+
+// Offsets of the source address in the IPv4 header and the flags in the TCP header
+const IPV4_SOURCE: usize = 12;
+const TCP_FLAGS: usize = 13;
+const TCP_SYN: u8 = 0x02;
+const TCP_ACK: u8 = 0x10;
+
+const SECOND: u64 = 1_000_000_000;
 
 #[map]
 static COUNTERS: Array<u64> = Array::with_max_entries(4, 0);
 
+#[map]
+static CONFIG: Array<u32> = Array::with_max_entries(3, 0);
+
+// The action for the packets to a port, keyed by rule_key(), and the number of
+// packets each rule has dropped or counted
+#[map]
+static RULES: HashMap<u32, u32> = HashMap::with_max_entries(256, 0);
+
+#[map]
+static RULE_PACKETS: HashMap<u32, u64> = HashMap::with_max_entries(256, 0);
+
+/// The token bucket and SYN count of a source address.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Bucket {
+    /// Nanoseconds of sending time the source has saved up, at most a second
+    credit: u64,
+    /// When the credit was last brought up to date
+    last: u64,
+    /// Start of the second in which the SYNs are counted
+    syn_window: u64,
+    syns: u64,
+}
+
+// The limits are kept for the sources seen most recently, so that a flood
+// from many addresses only forgets the quiet ones
+#[map]
+static BUCKETS: LruHashMap<u32, Bucket> = LruHashMap::with_max_entries(65536, 0);
+
+// The packets dropped by the limits, keyed by source address
+#[map]
+static SOURCE_DROPS: HashMap<u32, u64> = HashMap::with_max_entries(16384, 0);
+
+// Egress traffic of the monitored cgroup, keyed by process ID (tgid)
+#[map]
+static PROC_PACKETS: HashMap<u32, u64> = HashMap::with_max_entries(1024, 0);
+
+#[map]
+static PROC_BYTES: HashMap<u32, u64> = HashMap::with_max_entries(1024, 0);
+
 #[inline(always)]
 fn ptr_at<T>(ctx: &XdpContext, offset: usize) -> Result<*const T, ()> {
     let start = ctx.data();
@@ -33,12 +93,130 @@ fn ptr_at<T>(ctx: &XdpContext, offset: usize) -> Result<*const T, ()> {
     Ok((start + offset) as *const T)
 }
 
+#[inline(always)]
+fn ptr_at_mut<T>(ctx: &XdpContext, offset: usize) -> Result<*mut T, ()> {
+    let ptr: *const T = ptr_at(ctx, offset)?;
+    Ok(ptr as *mut T)
+}
+
+fn rewrite_enabled() -> bool {
+    matches!(CONFIG.get(REWRITE_8080), Some(&v) if v != 0)
+}
+
+fn limit(index: u32) -> u64 {
+    CONFIG.get(index).map_or(0, |&v| v as u64)
+}
+
+/// Checks the packet against the limits of its source and returns the
+/// counter of the limit that it goes over. Each source may send a second's
+/// worth of packets at once, and the SYNs are counted in whole seconds. The
+/// buckets are updated without locking, so packets of one source on several
+/// CPUs at once may be counted loosely.
+fn over_limit(source: u32, syn: bool) -> Option<u32> {
+    let pps_limit = limit(PPS_LIMIT);
+    let syn_limit = if syn { limit(SYN_LIMIT) } else { 0 };
+    if pps_limit == 0 && syn_limit == 0 {
+        return None;
+    }
+
+    let now = unsafe { bpf_ktime_get_ns() };
+    let bucket = match BUCKETS.get_ptr_mut(&source) {
+        Some(bucket) => bucket,
+        None => {
+            let bucket = Bucket {
+                credit: SECOND,
+                last: now,
+                syn_window: now,
+                syns: 0,
+            };
+            BUCKETS.insert(&source, &bucket, 0).ok()?;
+            BUCKETS.get_ptr_mut(&source)?
+        }
+    };
+    let bucket = unsafe { &mut *bucket };
+
+    if syn_limit != 0 {
+        if now.saturating_sub(bucket.syn_window) >= SECOND {
+            bucket.syn_window = now;
+            bucket.syns = 0;
+        }
+        bucket.syns += 1;
+        if bucket.syns > syn_limit {
+            return Some(SYN_LIMITED);
+        }
+    }
+    if pps_limit != 0 {
+        let elapsed = now.saturating_sub(bucket.last);
+        bucket.credit = (bucket.credit + elapsed).min(SECOND);
+        bucket.last = now;
+        let cost = SECOND / pps_limit;
+        if bucket.credit < cost {
+            return Some(RATE_LIMITED);
+        }
+        bucket.credit -= cost;
+    }
+    None
+}
+
+/// Incrementally updates a one's complement checksum after a 16-bit word of the
+/// covered data changed from `old` to `new` (RFC 1624, eqn. 3: HC' = ~(~HC + ~m + m')).
+/// The one's complement sum does not depend on byte order, so all values can be
+/// given as they are in the packet.
+#[inline(always)]
+fn csum_replace2(check: u16, old: u16, new: u16) -> u16 {
+    let mut sum = (!check) as u32 + (!old) as u32 + new as u32;
+    sum = (sum & 0xffff) + (sum >> 16);
+    sum = (sum & 0xffff) + (sum >> 16);
+    !(sum as u16)
+}
+
+/// Replaces the TCP destination port and fixes up the TCP checksum. The port is
+/// not part of the IPv4 header, so the IP checksum stays valid.
+#[inline(always)]
+fn rewrite_dest_port(tcp: *mut TcpHdr, port: u16) {
+    unsafe {
+        let old = (*tcp).dest;
+        let new = port.to_be();
+        (*tcp).dest = new;
+        (*tcp).check = csum_replace2((*tcp).check, old, new);
+    }
+}
+
 fn increment(idx: u32) {
     if let Some(cnt) = COUNTERS.get_ptr_mut(idx) {
         unsafe { *cnt += 1 };
     }
 }
 
+/// The key of the rule for a protocol and destination port: the IP protocol
+/// number in the upper 16 bits and the port in the lower.
+#[inline(always)]
+fn rule_key(proto: IpProto, port: u16) -> u32 {
+    ((proto as u32) << 16) | port as u32
+}
+
+/// Applies the rule for the port, if there is one. Packets to ports without
+/// a rule pass uncounted.
+fn apply_rule(proto: IpProto, port: u16) -> u32 {
+    let key = rule_key(proto, port);
+    let action = match unsafe { RULES.get(&key) } {
+        Some(&action) => action,
+        None => return xdp_action::XDP_PASS,
+    };
+    match action {
+        ACTION_DROP => {
+            add(&RULE_PACKETS, key, 1);
+            xdp_action::XDP_DROP
+        }
+        ACTION_COUNT => {
+            add(&RULE_PACKETS, key, 1);
+            xdp_action::XDP_PASS
+        }
+        // ACTION_PASS, to let through what another rule would catch
+        _ => xdp_action::XDP_PASS,
+    }
+}
+
 #[xdp]
 pub fn task_ebpf(ctx: XdpContext) -> u32 {
     match try_task_ebpf(ctx) {
@@ -57,29 +235,39 @@ fn try_task_ebpf(ctx: XdpContext) -> Result<u32, ()> {
     let proto = unsafe { (*ip).proto };
     let transport_offset = EthHdr::LEN + Ipv4Hdr::LEN;
 
+    let source: *const [u8; 4] = ptr_at(&ctx, EthHdr::LEN + IPV4_SOURCE)?;
+    let source = u32::from_be_bytes(unsafe { *source });
+    // A SYN without ACK opens a connection
+    let syn = match proto {
+        IpProto::Tcp => {
+            let flags: *const u8 = ptr_at(&ctx, transport_offset + TCP_FLAGS)?;
+            let flags = unsafe { *flags };
+            flags & (TCP_SYN | TCP_ACK) == TCP_SYN
+        }
+        _ => false,
+    };
+    if let Some(counter) = over_limit(source, syn) {
+        increment(counter);
+        add(&SOURCE_DROPS, source, 1);
+        return Ok(xdp_action::XDP_DROP);
+    }
+
     match proto {
         IpProto::Tcp => {
-            let tcp: *const TcpHdr = ptr_at(&ctx, transport_offset)?;
-            let dest = u16::from_be(unsafe { (*tcp).dest });
-            match dest {
-                443 => {
-                    increment(TCP_443);
-                    Ok(xdp_action::XDP_PASS)
-                }
-                80 => {
-                    increment(TCP_80);
-                    Ok(xdp_action::XDP_DROP)
-                }
-                _ => Ok(xdp_action::XDP_PASS),
+            let tcp: *mut TcpHdr = ptr_at_mut(&ctx, transport_offset)?;
+            let mut dest = u16::from_be(unsafe { (*tcp).dest });
+            if dest == 8080 && rewrite_enabled() {
+                // Rewrite 8080 -> 80 and classify the packet again with the new port
+                rewrite_dest_port(tcp, 80);
+                increment(TCP_8080_REWRITTEN);
+                dest = 80;
             }
+            Ok(apply_rule(proto, dest))
         }
         IpProto::Udp => {
             let udp: *const UdpHdr = ptr_at(&ctx, transport_offset)?;
             let dest = u16::from_be(unsafe { (*udp).dest });
-            if dest == 443 {
-                increment(UDP_443);
-            }
-            Ok(xdp_action::XDP_PASS)
+            Ok(apply_rule(proto, dest))
         }
         IpProto::Icmp => {
             increment(ICMP);
@@ -89,6 +277,31 @@ fn try_task_ebpf(ctx: XdpContext) -> Result<u32, ()> {
     }
 }
 
+fn add(map: &HashMap<u32, u64>, key: u32, value: u64) {
+    match map.get_ptr_mut(&key) {
+        Some(cnt) => unsafe { *cnt += value },
+        None => {
+            // The map may be full, in which case the key is just not accounted
+            let _ = map.insert(&key, &value, 0);
+        }
+    }
+}
+
+/// Attributes packets leaving the cgroup to the process that is running when the
+/// packet is sent. For most packets this is the process calling send(), but
+/// packets generated by the kernel (e.g. TCP retransmissions and pure ACKs sent
+/// from softirq context) may end up accounted to whichever process happened to
+/// be running, or to PID 0.
+#[cgroup_skb]
+pub fn task_ebpf_cgroup(ctx: SkBuffContext) -> i32 {
+    let pid = (bpf_get_current_pid_tgid() >> 32) as u32;
+    add(&PROC_PACKETS, pid, 1);
+    add(&PROC_BYTES, pid, ctx.len() as u64);
+
+    // 1 lets the packet through, we only observe
+    1
+}
+
 #[cfg(not(test))]
 #[panic_handler]
 fn panic(_info: &core::panic::PanicInfo) -> ! {
@@ -1,20 +1,157 @@
-use std::time::Duration;
+mod rules;
 
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io,
+    net::Ipv4Addr,
+    path::{Path, PathBuf},
+    process::ExitCode,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    thread,
+    time::Duration,
+};
+
+use adnet_core::{
+    config::ConfigArgs,
+    exit,
+    logging::{self, LogArgs},
+    metrics::{self, Counter, MetricsArgs},
+    report::{Report, ReportArgs},
+    shutdown::{self, Shutdown},
+};
 use anyhow::Context as _;
-use aya::maps::Array;
-use aya::programs::{Xdp, XdpFlags};
+use aya::maps::{Array, HashMap, MapData};
+use aya::programs::{CgroupAttachMode, CgroupSkb, CgroupSkbAttachType, Xdp, XdpFlags};
 use clap::Parser;
-use tokio::{signal, time};
+use pktcap::{
+    Capture,
+    pcap::{self, DEFAULT_SNAPLEN},
+};
+use serde::Deserialize;
+use tokio::{
+    io::{AsyncBufReadExt, BufReader},
+    time,
+};
+use tracing::{info, warn};
+
+use crate::rules::{Command, DEFAULT_RULES, Proto, Rule, Rules};
 
+/// Counts and filters packets with an XDP program.
 #[derive(Debug, Parser)]
 struct Opt {
-    #[clap(short, long, default_value = "veth0")]
-    iface: String,
+    /// Interface to attach the XDP program to [default: veth0]
+    #[clap(short, long)]
+    iface: Option<String>,
+
+    /// Rewrite TCP destination port 8080 to 80 before the packet is classified
+    #[clap(long)]
+    rewrite_8080: bool,
+
+    /// Port rule to apply besides those of the assignment, as PROTO PORT
+    /// ACTION with the action drop, count or pass, e.g. "tcp 8080 drop". Can
+    /// be repeated, and a rule for the same port replaces the earlier one
+    #[clap(long = "rule")]
+    rules: Vec<Rule>,
+
+    /// Drop the packets of each source IPv4 address beyond this many per
+    /// second, allowing bursts of a second's worth
+    #[clap(long, value_parser = clap::value_parser!(u32).range(1..))]
+    pps_limit: Option<u32>,
+
+    /// Drop the TCP SYNs of each source IPv4 address beyond this many per
+    /// second, against SYN floods
+    #[clap(long, value_parser = clap::value_parser!(u32).range(1..))]
+    syn_limit: Option<u32>,
+
+    /// Also attach a cgroup program that accounts egress traffic per process,
+    /// e.g. /sys/fs/cgroup for the whole system
+    #[clap(long)]
+    cgroup: Option<PathBuf>,
+
+    /// Write the packets that pass the XDP program to this pcap file, to
+    /// check what the program dropped or rewrote
+    #[clap(long)]
+    capture: Option<PathBuf>,
+
+    #[command(flatten)]
+    config: ConfigArgs,
+
+    #[command(flatten)]
+    log: LogArgs,
+
+    #[command(flatten)]
+    metrics: MetricsArgs,
+
+    #[command(flatten)]
+    report: ReportArgs,
 }
 
+/// The [task-ebpf] section of the config file
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct FileConfig {
+    iface: Option<String>,
+    rewrite_8080: bool,
+    rules: Vec<String>,
+    pps_limit: Option<u32>,
+    syn_limit: Option<u32>,
+    cgroup: Option<PathBuf>,
+    capture: Option<PathBuf>,
+}
+
+// Indices of the port rewrite switch and the per-source limits in the CONFIG
+// map of the XDP program
+const REWRITE_8080: u32 = 0;
+const PPS_LIMIT: u32 = 1;
+const SYN_LIMIT: u32 = 2;
+
+// Sources logged each second for going over the limits
+const LIMITED_SOURCES_LOGGED: usize = 10;
+
 #[tokio::main]
-async fn main() -> anyhow::Result<()> {
+async fn main() -> ExitCode {
     let opt = Opt::parse();
+    let report_args = opt.report.clone();
+    let mut report = Report::new("task-ebpf");
+    let result = match logging::init(&opt.log) {
+        Ok(()) => monitor(opt, &mut report).await,
+        Err(e) => Err(e.into()),
+    };
+    match report_args.finish(&mut report, result) {
+        Ok(()) => ExitCode::SUCCESS,
+        // With the causes, as returning the error from main would show them
+        Err(e) => exit::fail(&format!("{e:#}"), exit::classify(e.as_ref())),
+    }
+}
+
+async fn monitor(mut opt: Opt, report: &mut Report) -> anyhow::Result<()> {
+    let shutdown = shutdown::install()?;
+    metrics::init(&opt.metrics)?;
+
+    // Command line options take precedence over the config file
+    let file: FileConfig = opt.config.section("task-ebpf")?;
+    let iface = opt.iface.take().or(file.iface).unwrap_or_else(|| "veth0".to_string());
+    opt.rewrite_8080 |= file.rewrite_8080;
+    opt.pps_limit = opt.pps_limit.or(file.pps_limit);
+    opt.syn_limit = opt.syn_limit.or(file.syn_limit);
+    if opt.pps_limit == Some(0) || opt.syn_limit == Some(0) {
+        anyhow::bail!("the limits must be at least 1 per second");
+    }
+    if opt.rules.is_empty() {
+        opt.rules = file
+            .rules
+            .iter()
+            .map(|rule| rule.parse())
+            .collect::<Result<_, String>>()
+            .map_err(anyhow::Error::msg)
+            .context("invalid rule in the config file")?;
+    }
+    opt.cgroup = opt.cgroup.or(file.cgroup);
+    opt.capture = opt.capture.or(file.capture);
 
     let rlim = libc::rlimit {
         rlim_cur: libc::RLIM_INFINITY,
@@ -22,7 +159,7 @@ async fn main() -> anyhow::Result<()> {
     };
     let ret = unsafe { libc::setrlimit(libc::RLIMIT_MEMLOCK, &rlim) };
     if ret != 0 {
-        eprintln!("Failed to remove limit on locked memory, ret is: {ret}");
+        warn!("Failed to remove limit on locked memory, ret is: {ret}");
     }
 
     let mut ebpf = aya::Ebpf::load(aya::include_bytes_aligned!(concat!(
@@ -30,32 +167,249 @@ async fn main() -> anyhow::Result<()> {
         "/task-ebpf"
     )))?;
 
+    let mut config: Array<_, u32> = Array::try_from(ebpf.map_mut("CONFIG").unwrap())?;
+    if opt.rewrite_8080 {
+        config.set(REWRITE_8080, 1, 0)?;
+        info!("Rewriting TCP destination port 8080 to 80");
+    }
+    if let Some(limit) = opt.pps_limit {
+        config.set(PPS_LIMIT, limit, 0)?;
+        info!("Limiting each source to {limit} packets per second");
+    }
+    if let Some(limit) = opt.syn_limit {
+        config.set(SYN_LIMIT, limit, 0)?;
+        info!("Limiting each source to {limit} SYNs per second");
+    }
+    let limited = opt.pps_limit.is_some() || opt.syn_limit.is_some();
+    let source_drops: HashMap<_, u32, u64> =
+        HashMap::try_from(ebpf.take_map("SOURCE_DROPS").unwrap())?;
+    let mut seen_drops = BTreeMap::new();
+
+    // The rules are in place before the program sees the first packet
+    let mut rules = Rules::take(&mut ebpf)?;
+    let defaults = DEFAULT_RULES.map(|rule| rule.parse::<Rule>().unwrap());
+    for rule in defaults.iter().chain(&opt.rules) {
+        rules.add(rule)?;
+    }
+
     let program: &mut Xdp = ebpf.program_mut("task_ebpf").unwrap().try_into()?;
     program.load()?;
     program
-        .attach(&opt.iface, XdpFlags::SKB_MODE)
+        .attach(&iface, XdpFlags::SKB_MODE)
         .context("failed to attach XDP program")?;
 
-    println!("Attached XDP on {}. Press Ctrl-C to stop.", opt.iface);
+    // Per-process maps are taken out of ebpf so that they can be read alongside COUNTERS
+    let mut processes = None;
+    if let Some(path) = &opt.cgroup {
+        let cgroup = File::open(path)
+            .with_context(|| format!("failed to open cgroup {}", path.display()))?;
+        let program: &mut CgroupSkb = ebpf.program_mut("task_ebpf_cgroup").unwrap().try_into()?;
+        program.load()?;
+        program
+            .attach(cgroup, CgroupSkbAttachType::Egress, CgroupAttachMode::Single)
+            .context("failed to attach cgroup program")?;
+        info!("Attached cgroup program on {}.", path.display());
+
+        let packets: HashMap<_, u32, u64> =
+            HashMap::try_from(ebpf.take_map("PROC_PACKETS").unwrap())?;
+        let bytes: HashMap<_, u32, u64> = HashMap::try_from(ebpf.take_map("PROC_BYTES").unwrap())?;
+        processes = Some((packets, bytes));
+    }
+
+    if let Some(path) = &opt.capture {
+        start_capture(&iface, path, &shutdown)?;
+    }
+
+    info!("Attached XDP on {iface}. Press Ctrl-C to stop, or type help for commands.");
 
     let counters: Array<_, u64> = Array::try_from(ebpf.map_mut("COUNTERS").unwrap())?;
+    let exported = counter_metrics();
     let mut interval = time::interval(Duration::from_secs(1));
+    let mut commands = BufReader::new(tokio::io::stdin()).lines();
+    // Without a terminal, e.g. under a service manager, stdin ends right away
+    let mut stdin_open = true;
 
     loop {
         tokio::select! {
-            _ = signal::ctrl_c() => break,
+            _ = shutdown.requested() => break,
             _ = interval.tick() => {
-                let tcp_443 = counters.get(&0, 0).unwrap_or(0);
-                let udp_443 = counters.get(&1, 0).unwrap_or(0);
-                let icmp = counters.get(&2, 0).unwrap_or(0);
-                let tcp_80 = counters.get(&3, 0).unwrap_or(0);
-                println!(
-                    "TCP/443={tcp_443}  UDP/443={udp_443}  ICMP={icmp}  dropped:TCP/80={tcp_80}"
-                );
+                let counts = counts(&counters, &rules);
+                log_counters(&counts, opt.rewrite_8080, "Counters");
+                export_counters(&counts, &exported);
+                if let Some((packets, bytes)) = &processes {
+                    log_processes(packets, bytes);
+                }
+                if limited {
+                    log_limited(&source_drops, &mut seen_drops);
+                }
             }
+            line = commands.next_line(), if stdin_open => match line {
+                Ok(Some(line)) if line.trim().is_empty() => {}
+                Ok(Some(line)) => match line.parse::<Command>() {
+                    Ok(command) => match rules.execute(command) {
+                        Ok(answer) => println!("{answer}"),
+                        Err(e) => warn!("{e:#}"),
+                    },
+                    Err(e) => warn!("{e}"),
+                },
+                Ok(None) => stdin_open = false,
+                Err(e) => {
+                    warn!("Cannot read commands: {e}");
+                    stdin_open = false;
+                }
+            },
         }
     }
 
-    println!("Exiting...");
+    info!("Exiting...");
+    // The counters borrow from the loaded programs, so they are logged here
+    // rather than in an exit hook
+    let counts = counts(&counters, &rules);
+    log_counters(&counts, opt.rewrite_8080, "Final counters");
+    if let Some((packets, bytes)) = &processes {
+        log_processes(packets, bytes);
+    }
+    report.detail("iface", &iface);
+    report_counters(&counts, report);
+    shutdown.finish();
     Ok(())
 }
+
+/// The packet counts of the assignment: those of the rules for TCP/443,
+/// UDP/443 and TCP/80, and the ICMP and rewritten packets of the COUNTERS
+/// map. A port without a rule counts 0. After them come the packets dropped
+/// by the per-source limits.
+fn counts(counters: &Array<&mut MapData, u64>, rules: &Rules) -> [u64; 7] {
+    [
+        rules.packets(Proto::Tcp, 443),
+        rules.packets(Proto::Udp, 443),
+        counters.get(&0, 0).unwrap_or(0),
+        rules.packets(Proto::Tcp, 80),
+        counters.get(&1, 0).unwrap_or(0),
+        counters.get(&2, 0).unwrap_or(0),
+        counters.get(&3, 0).unwrap_or(0),
+    ]
+}
+
+/// Logs the packet counts of the XDP program.
+fn log_counters(counts: &[u64; 7], rewrite_8080: bool, message: &str) {
+    let [tcp_443, udp_443, icmp, tcp_80, tcp_8080, ..] = *counts;
+    if rewrite_8080 {
+        info!(
+            tcp_443, udp_443, icmp, dropped_tcp_80 = tcp_80, rewritten_tcp_8080 = tcp_8080,
+            "{message}"
+        );
+    } else {
+        info!(tcp_443, udp_443, icmp, dropped_tcp_80 = tcp_80, "{message}");
+    }
+}
+
+/// The packet counts of the XDP program in the global registry, in the order
+/// of [`counts`].
+fn counter_metrics() -> [Counter; 7] {
+    [
+        metrics::counter("xdp_tcp_443_packets_total", "TCP packets to port 443"),
+        metrics::counter("xdp_udp_443_packets_total", "UDP packets to port 443"),
+        metrics::counter("xdp_icmp_packets_total", "ICMP packets"),
+        metrics::counter("xdp_dropped_total", "TCP packets to port 80 dropped"),
+        metrics::counter("xdp_rewritten_total", "TCP packets rewritten from port 8080 to 80"),
+        metrics::counter("xdp_rate_limited_total", "Packets dropped by --pps-limit"),
+        metrics::counter("xdp_syn_limited_total", "SYNs dropped by --syn-limit"),
+    ]
+}
+
+/// Brings the metrics up to the packet counts of the XDP program.
+fn export_counters(counts: &[u64; 7], exported: &[Counter; 7]) {
+    for (&count, counter) in counts.iter().zip(exported) {
+        // The maps hold totals, and the metrics only ever add
+        counter.add(count.saturating_sub(counter.get()));
+    }
+}
+
+/// Adds the packet counts of the XDP program to the report.
+fn report_counters(counts: &[u64; 7], report: &mut Report) {
+    let names = [
+        "tcp_443",
+        "udp_443",
+        "icmp",
+        "dropped_tcp_80",
+        "rewritten_tcp_8080",
+        "rate_limited",
+        "syn_limited",
+    ];
+    for (&count, name) in counts.iter().zip(names) {
+        report.detail(name, count);
+    }
+}
+
+/// Logs the sources whose packets the limits dropped since the last call,
+/// most dropped first, given the totals of the previous call in `seen`.
+fn log_limited(drops: &HashMap<MapData, u32, u64>, seen: &mut BTreeMap<u32, u64>) {
+    let mut limited: Vec<(u32, u64, u64)> = drops
+        .iter()
+        .filter_map(Result::ok)
+        .filter_map(|(source, total)| {
+            let previous = seen.insert(source, total).unwrap_or(0);
+            (total > previous).then_some((source, total - previous, total))
+        })
+        .collect();
+    limited.sort_by_key(|&(_, dropped, _)| std::cmp::Reverse(dropped));
+
+    for &(source, dropped, total) in limited.iter().take(LIMITED_SOURCES_LOGGED) {
+        let source = Ipv4Addr::from(source);
+        warn!(source = %source, dropped, total, "Over the limit");
+    }
+    if limited.len() > LIMITED_SOURCES_LOGGED {
+        let others = limited.len() - LIMITED_SOURCES_LOGGED;
+        warn!("{others} more sources over the limit");
+    }
+}
+
+/// Captures the packets on the interface in a thread of its own. XDP runs
+/// before packet sockets see received packets, so the capture shows the
+/// packets after the XDP program: dropped ones are missing and rewritten
+/// ones appear rewritten. The number of packets captured is logged at exit.
+fn start_capture(iface: &str, path: &Path, shutdown: &Shutdown) -> anyhow::Result<()> {
+    let capture = Capture::open(iface).context("failed to open capture socket")?;
+    capture.start()?;
+    let mut writer = pcap::create(path, capture.link_type(), DEFAULT_SNAPLEN)
+        .with_context(|| format!("failed to create {}", path.display()))?;
+    info!("Capturing packets on {} to {}.", iface, path.display());
+
+    let captured = Arc::new(AtomicU64::new(0));
+    let (count, path) = (captured.clone(), path.to_path_buf());
+    shutdown.on_exit(move || {
+        let count = count.load(Ordering::Relaxed);
+        info!("Captured {count} packets to {}.", path.display());
+    });
+
+    thread::spawn(move || -> io::Result<()> {
+        let mut buf = vec![0u8; DEFAULT_SNAPLEN as usize];
+        loop {
+            let packet = capture.recv(&mut buf)?;
+            writer.write(packet.timestamp, &buf[..packet.len], packet.original)?;
+            writer.flush()?;
+            captured.fetch_add(1, Ordering::Relaxed);
+        }
+    });
+    Ok(())
+}
+
+/// Logs the processes that have sent the most bytes out of the monitored cgroup.
+fn log_processes(packets: &HashMap<MapData, u32, u64>, bytes: &HashMap<MapData, u32, u64>) {
+    let mut senders: Vec<(u32, u64)> = bytes.iter().filter_map(Result::ok).collect();
+    senders.sort_by_key(|&(_, sent)| std::cmp::Reverse(sent));
+
+    for (pid, sent) in senders.into_iter().take(5) {
+        let count = packets.get(&pid, 0).unwrap_or(0);
+        let comm = if pid == 0 {
+            "kernel".to_string()
+        } else {
+            // The process may already have exited
+            std::fs::read_to_string(format!("/proc/{pid}/comm"))
+                .map(|c| c.trim().to_string())
+                .unwrap_or_else(|_| "?".to_string())
+        };
+        info!(pid, comm = %comm, packets = count, bytes = sent, "Sender");
+    }
+}
@@ -0,0 +1,272 @@
+//! The port rules of the XDP program. A rule is a protocol, a destination
+//! port and what to do with the packets to that port, written
+//! `tcp 8080 drop`:
+//!
+//! - `drop` drops the packets and counts them
+//! - `count` lets the packets pass and counts them
+//! - `pass` lets the packets pass uncounted, e.g. to stop dropping port 80
+//!
+//! The rules live in the RULES map of the XDP program, so they can change
+//! while it runs. The loader starts with [`DEFAULT_RULES`], those of the
+//! assignment, and the ones given with `--rule`, and then reads [`Command`]s
+//! from its standard input, one per line.
+
+use std::{fmt, str::FromStr};
+
+use anyhow::Context as _;
+use aya::{
+    Ebpf,
+    maps::{HashMap, MapData},
+};
+
+/// The rules of the assignment: count HTTPS and QUIC, drop plaintext HTTP.
+pub const DEFAULT_RULES: [&str; 3] = ["tcp 443 count", "udp 443 count", "tcp 80 drop"];
+
+pub const HELP: &str = "\
+add-rule PROTO PORT ACTION   add a rule or change its action (drop, count or pass)
+del-rule PROTO PORT          remove a rule
+list                         the rules and their packet counts
+help                         this text";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Proto {
+    Tcp,
+    Udp,
+}
+
+impl Proto {
+    /// The IP protocol number
+    fn number(self) -> u32 {
+        match self {
+            Proto::Tcp => 6,
+            Proto::Udp => 17,
+        }
+    }
+}
+
+impl fmt::Display for Proto {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Proto::Tcp => write!(f, "tcp"),
+            Proto::Udp => write!(f, "udp"),
+        }
+    }
+}
+
+impl FromStr for Proto {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s.to_ascii_lowercase().as_str() {
+            "tcp" => Ok(Proto::Tcp),
+            "udp" => Ok(Proto::Udp),
+            _ => Err(format!("Unknown protocol {s:?}, expected tcp or udp")),
+        }
+    }
+}
+
+// The values of the actions in the RULES map of the XDP program
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Pass = 0,
+    Drop = 1,
+    Count = 2,
+}
+
+impl Action {
+    fn from_value(value: u32) -> Option<Self> {
+        match value {
+            0 => Some(Action::Pass),
+            1 => Some(Action::Drop),
+            2 => Some(Action::Count),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Action::Pass => write!(f, "pass"),
+            Action::Drop => write!(f, "drop"),
+            Action::Count => write!(f, "count"),
+        }
+    }
+}
+
+impl FromStr for Action {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s.to_ascii_lowercase().as_str() {
+            "pass" => Ok(Action::Pass),
+            "drop" => Ok(Action::Drop),
+            "count" => Ok(Action::Count),
+            _ => Err(format!(
+                "Unknown action {s:?}, expected drop, count or pass"
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rule {
+    pub proto: Proto,
+    pub port: u16,
+    pub action: Action,
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {} {}", self.proto, self.port, self.action)
+    }
+}
+
+impl FromStr for Rule {
+    type Err = String;
+
+    /// Parses `PROTO PORT ACTION`.
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s.split_whitespace().collect::<Vec<_>>()[..] {
+            [proto, port, action] => Ok(Rule {
+                proto: proto.parse()?,
+                port: parse_port(port)?,
+                action: action.parse()?,
+            }),
+            _ => Err(format!("Invalid rule {s:?}, expected PROTO PORT ACTION")),
+        }
+    }
+}
+
+fn parse_port(s: &str) -> Result<u16, String> {
+    s.parse().map_err(|_| format!("Invalid port {s:?}"))
+}
+
+/// A command read from the standard input of the loader.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    Add(Rule),
+    Delete(Proto, u16),
+    List,
+    Help,
+}
+
+impl FromStr for Command {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let s = s.trim();
+        let (command, rest) = s.split_once(char::is_whitespace).unwrap_or((s, ""));
+        match command {
+            "add-rule" => Ok(Command::Add(rest.parse()?)),
+            "del-rule" => match rest.split_whitespace().collect::<Vec<_>>()[..] {
+                [proto, port] => Ok(Command::Delete(proto.parse()?, parse_port(port)?)),
+                _ => Err("Usage: del-rule PROTO PORT".to_string()),
+            },
+            "list" => Ok(Command::List),
+            "help" => Ok(Command::Help),
+            _ => Err(format!("Unknown command {command:?}, try help")),
+        }
+    }
+}
+
+/// The key of a rule in the maps of the XDP program: the IP protocol number
+/// in the upper 16 bits and the port in the lower.
+fn key(proto: Proto, port: u16) -> u32 {
+    (proto.number() << 16) | port as u32
+}
+
+/// The RULES and RULE_PACKETS maps of the XDP program, taken out of it.
+pub struct Rules {
+    actions: HashMap<MapData, u32, u32>,
+    packets: HashMap<MapData, u32, u64>,
+}
+
+impl Rules {
+    pub fn take(ebpf: &mut Ebpf) -> anyhow::Result<Self> {
+        let actions = HashMap::try_from(ebpf.take_map("RULES").unwrap())?;
+        let packets = HashMap::try_from(ebpf.take_map("RULE_PACKETS").unwrap())?;
+        Ok(Self { actions, packets })
+    }
+
+    /// Adds the rule, or changes the action of the rule for its port. The
+    /// packet count carries over.
+    pub fn add(&mut self, rule: &Rule) -> anyhow::Result<()> {
+        self.actions
+            .insert(key(rule.proto, rule.port), rule.action as u32, 0)
+            .with_context(|| format!("failed to add rule {rule}"))
+    }
+
+    /// Removes the rule for the port and its packet count, and returns
+    /// whether there was one.
+    pub fn remove(&mut self, proto: Proto, port: u16) -> anyhow::Result<bool> {
+        let key = key(proto, port);
+        if self.actions.get(&key, 0).is_err() {
+            return Ok(false);
+        }
+        self.actions
+            .remove(&key)
+            .with_context(|| format!("failed to remove rule for {proto} {port}"))?;
+        // The XDP program creates the count with the first packet
+        let _ = self.packets.remove(&key);
+        Ok(true)
+    }
+
+    /// The packets that the rule for the port has dropped or counted.
+    pub fn packets(&self, proto: Proto, port: u16) -> u64 {
+        self.packets.get(&key(proto, port), 0).unwrap_or(0)
+    }
+
+    /// The rules in effect with their packet counts, by protocol and port.
+    pub fn list(&self) -> Vec<(Rule, u64)> {
+        let mut rules: Vec<(Rule, u64)> = self
+            .actions
+            .iter()
+            .filter_map(Result::ok)
+            .filter_map(|(key, action)| {
+                let proto = match key >> 16 {
+                    6 => Proto::Tcp,
+                    17 => Proto::Udp,
+                    _ => return None,
+                };
+                let rule = Rule {
+                    proto,
+                    port: key as u16,
+                    action: Action::from_value(action)?,
+                };
+                Some((rule, self.packets(proto, rule.port)))
+            })
+            .collect();
+        rules.sort_by_key(|(rule, _)| (rule.proto, rule.port));
+        rules
+    }
+
+    /// Carries out the command and returns the answer to it.
+    pub fn execute(&mut self, command: Command) -> anyhow::Result<String> {
+        match command {
+            Command::Add(rule) => {
+                self.add(&rule)?;
+                Ok(format!("Added rule {rule}"))
+            }
+            Command::Delete(proto, port) => {
+                if self.remove(proto, port)? {
+                    Ok(format!("Removed rule for {proto} {port}"))
+                } else {
+                    Ok(format!("No rule for {proto} {port}"))
+                }
+            }
+            Command::List => {
+                let rules = self.list();
+                if rules.is_empty() {
+                    return Ok("No rules, all packets pass".to_string());
+                }
+                let lines: Vec<String> = rules
+                    .iter()
+                    .map(|(rule, packets)| format!("{rule}  packets={packets}"))
+                    .collect();
+                Ok(lines.join("\n"))
+            }
+            Command::Help => Ok(HELP.to_string()),
+        }
+    }
+}
@@ -0,0 +1,33 @@
+use std::{io, net::SocketAddr};
+
+use adnet_core::exit::{Code, HasCode};
+use thiserror::Error;
+
+/// Errors of task-fwd. Each kind has its own exit code, see
+/// [`adnet_core::exit`].
+#[derive(Debug, Error)]
+pub enum FwdError {
+    /// Invalid or missing options
+    #[error("{0}")]
+    Usage(String),
+    /// The config file could not be read
+    #[error(transparent)]
+    Config(io::Error),
+    #[error("Cannot listen at {address}: {source}")]
+    Bind {
+        address: SocketAddr,
+        source: io::Error,
+    },
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+impl HasCode for FwdError {
+    fn exit_code(&self) -> Code {
+        match self {
+            FwdError::Usage(_) | FwdError::Config(_) => Code::Usage,
+            FwdError::Bind { .. } => Code::Failure,
+            FwdError::Io(e) => e.exit_code(),
+        }
+    }
+}
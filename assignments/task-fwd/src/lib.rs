@@ -0,0 +1,345 @@
+//! TCP port forwarder. Every connection accepted at the listening address is
+//! relayed to a fixed target, and the forwarder logs for each one how long
+//! connecting to the target took, how soon the target answered, and how
+//! many bytes went each way. Each direction can be limited to a rate, and
+//! the connections to the target can be bound to an interface, such as the
+//! TUN device of task-tun, so that they go through the tunnel. The task-fwd
+//! binary and the `adnet fwd` subcommand are thin wrappers around [`run`].
+
+mod error;
+pub mod relay;
+
+use std::{
+    io,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use adnet_core::{
+    config::{self, ConfigArgs},
+    logging::{self, LogArgs},
+    metrics::{self, Counter, Gauge, Histogram, MetricsArgs},
+    report::{Report, ReportArgs},
+    shutdown,
+};
+use clap::Parser;
+use serde::Deserialize;
+use tokio::{
+    net::{self, TcpListener, TcpSocket, TcpStream},
+    task, time,
+};
+use tracing::{debug, info, warn};
+
+pub use error::FwdError;
+
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Forwards TCP connections to a target, optionally through a tunnel.
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+pub struct Args {
+    /// Address to listen at [default: 0.0.0.0:8080]
+    #[arg(short, long)]
+    listen: Option<SocketAddr>,
+
+    /// Address to forward the connections to, as host:port
+    #[arg(short, long)]
+    target: Option<String>,
+
+    /// Seconds to wait for the connection to the target [default: 10]
+    #[arg(long, value_parser = config::parse_secs)]
+    connect_timeout: Option<Duration>,
+
+    /// Limit each direction of each connection to this many bytes per second
+    /// (k and M suffixes allowed)
+    #[arg(long, value_parser = parse_rate)]
+    max_rate: Option<u64>,
+
+    /// Connect to the target through this interface, e.g. tun0 of task-tun
+    /// to chain through the tunnel (needs CAP_NET_RAW)
+    #[arg(short, long)]
+    interface: Option<String>,
+
+    #[command(flatten)]
+    config: ConfigArgs,
+
+    #[command(flatten)]
+    log: LogArgs,
+
+    #[command(flatten)]
+    metrics: MetricsArgs,
+
+    #[command(flatten)]
+    report: ReportArgs,
+}
+
+/// The [task-fwd] section of the config file
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct FileConfig {
+    listen: Option<SocketAddr>,
+    target: Option<String>,
+    #[serde(deserialize_with = "config::secs")]
+    connect_timeout: Option<Duration>,
+    /// In bytes per second
+    max_rate: Option<u64>,
+    interface: Option<String>,
+}
+
+fn parse_rate(s: &str) -> Result<u64, String> {
+    let (number, multiplier) = match s.strip_suffix(['k', 'K']) {
+        Some(n) => (n, 1_000.0),
+        None => match s.strip_suffix('M') {
+            Some(n) => (n, 1_000_000.0),
+            None => (s, 1.0),
+        },
+    };
+    let rate: f64 = number.parse().map_err(|e| format!("{}", e))?;
+    let rate = (rate * multiplier) as u64;
+    if rate == 0 {
+        return Err("rate must be positive".to_string());
+    }
+    Ok(rate)
+}
+
+/// The forwarder's metrics in the global registry.
+#[derive(Clone)]
+struct Metrics {
+    connections: Counter,
+    active_connections: Gauge,
+    failed: Counter,
+    bytes_up: Counter,
+    bytes_down: Counter,
+    connect_duration: Histogram,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        Self {
+            connections: metrics::counter("fwd_connections_total", "Client connections accepted"),
+            active_connections: metrics::gauge(
+                "fwd_active_connections",
+                "Client connections currently open",
+            ),
+            failed: metrics::counter(
+                "fwd_failed_total",
+                "Client connections that ended with an error",
+            ),
+            bytes_up: metrics::counter(
+                "fwd_bytes_up_total",
+                "Bytes relayed from clients to the target",
+            ),
+            bytes_down: metrics::counter(
+                "fwd_bytes_down_total",
+                "Bytes relayed from the target to clients",
+            ),
+            connect_duration: metrics::histogram(
+                "fwd_connect_duration_seconds",
+                "Time to connect to the target",
+                &[0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0],
+            ),
+        }
+    }
+
+    fn report(&self, report: &mut Report) {
+        report.bytes = self.bytes_up.get() + self.bytes_down.get();
+        report.errors = self.failed.get();
+        report.detail("connections", self.connections.get());
+        report.detail("bytes_up", self.bytes_up.get());
+        report.detail("bytes_down", self.bytes_down.get());
+        if self.connect_duration.count() > 0 {
+            let mean = self.connect_duration.sum() / self.connect_duration.count() as f64;
+            report.detail("connect_ms_mean", mean * 1000.0);
+        }
+    }
+}
+
+/// Settings shared by all client connections.
+struct Forwarder {
+    target: String,
+    interface: Option<String>,
+    connect_timeout: Duration,
+    max_rate: Option<u64>,
+    metrics: Metrics,
+}
+
+/// Runs the forwarder with the given arguments, as the task-fwd binary does.
+pub async fn run(args: Args) -> Result<(), FwdError> {
+    logging::init(&args.log)?;
+    metrics::init(&args.metrics)?;
+    let report_args = args.report.clone();
+    let mut report = Report::new("task-fwd");
+    let metrics = Metrics::new();
+    let result = forward(args, metrics.clone(), &mut report).await;
+    metrics.report(&mut report);
+    report_args.finish(&mut report, result)
+}
+
+/// Forwards client connections until the shutdown is requested, and then
+/// gives the open ones the drain timeout to finish.
+async fn forward(args: Args, metrics: Metrics, report: &mut Report) -> Result<(), FwdError> {
+    // Command line options take precedence over the config file
+    let file: FileConfig = args.config.section("task-fwd").map_err(FwdError::Config)?;
+    let listen = args
+        .listen
+        .or(file.listen)
+        .unwrap_or_else(|| SocketAddr::from(([0, 0, 0, 0], 8080)));
+    let target = args
+        .target
+        .or(file.target)
+        .ok_or_else(|| FwdError::Usage("Target is required (--target)".to_string()))?;
+    let interface = args.interface.or(file.interface);
+    if let Some(name) = &interface {
+        // Fail now rather than on every connection
+        bind_device(&TcpSocket::new_v4()?, name)?;
+    }
+    report.detail("target", &target);
+    let fwd = Arc::new(Forwarder {
+        target,
+        interface,
+        connect_timeout: args
+            .connect_timeout
+            .or(file.connect_timeout)
+            .unwrap_or(DEFAULT_CONNECT_TIMEOUT),
+        max_rate: args.max_rate.or(file.max_rate),
+        metrics,
+    });
+
+    let listener = TcpListener::bind(listen)
+        .await
+        .map_err(|source| FwdError::Bind {
+            address: listen,
+            source,
+        })?;
+    info!(
+        "Task-FWD forwarding {} to {}{}",
+        listener.local_addr()?,
+        fwd.target,
+        match &fwd.interface {
+            Some(name) => format!(" through {}", name),
+            None => String::new(),
+        }
+    );
+
+    let shutdown = shutdown::install()?;
+    loop {
+        let (socket, address) = tokio::select! {
+            accepted = listener.accept() => accepted?,
+            _ = shutdown.requested() => break,
+        };
+        fwd.metrics.connections.inc();
+        let fwd = fwd.clone();
+        let in_flight = shutdown.track();
+        task::spawn(async move {
+            fwd.metrics.active_connections.inc();
+            if let Err(e) = handle_client(socket, address, &fwd).await {
+                warn!("{} -> {}: {}", address, fwd.target, e);
+                fwd.metrics.failed.inc();
+            }
+            fwd.metrics.active_connections.dec();
+            drop(in_flight);
+        });
+    }
+
+    if shutdown.in_flight() > 0 {
+        info!("Waiting for {} open connections", shutdown.in_flight());
+        shutdown.drain_async(shutdown::DEFAULT_DRAIN_TIMEOUT).await;
+    }
+    shutdown.finish();
+    Ok(())
+}
+
+/// Connects the client to the target and relays until both have closed
+/// their end.
+async fn handle_client(
+    mut client: TcpStream,
+    address: SocketAddr,
+    fwd: &Forwarder,
+) -> io::Result<()> {
+    let start = Instant::now();
+    let mut upstream = connect(&fwd.target, fwd.interface.as_deref(), fwd.connect_timeout)
+        .await
+        .map_err(|e| io::Error::new(e.kind(), format!("connection failed: {}", e)))?;
+    let connected = Instant::now();
+    fwd.metrics
+        .connect_duration
+        .observe((connected - start).as_secs_f64());
+    debug!(
+        "{} -> {}: connected from {}",
+        address,
+        fwd.target,
+        upstream.local_addr()?
+    );
+
+    let metrics = &fwd.metrics;
+    let (up, down) = relay::relay(
+        &mut client,
+        &mut upstream,
+        fwd.max_rate,
+        &metrics.bytes_up,
+        &metrics.bytes_down,
+    )
+    .await?;
+
+    // How long the target took to answer the client's first bytes, or to
+    // send its own if it speaks first
+    let answer = down
+        .first_byte
+        .map(|at| at.saturating_duration_since(up.first_byte.unwrap_or(connected)));
+    info!(
+        "{} -> {}: connect {:.2?}, first answer {}, {} bytes up, {} bytes down in {:.2?}",
+        address,
+        fwd.target,
+        connected - start,
+        answer.map_or("none".to_string(), |d| format!("{:.2?}", d)),
+        up.bytes,
+        down.bytes,
+        start.elapsed()
+    );
+    Ok(())
+}
+
+fn bind_device(socket: &TcpSocket, interface: &str) -> io::Result<()> {
+    socket.bind_device(Some(interface.as_bytes())).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("Cannot bind to interface {}: {}", interface, e),
+        )
+    })
+}
+
+/// Connects to the target, trying each of its addresses in turn. With an
+/// interface, the sockets are bound to it, so that the connections leave
+/// through it whatever the routing table says.
+async fn connect(
+    target: &str,
+    interface: Option<&str>,
+    timeout: Duration,
+) -> io::Result<TcpStream> {
+    let connecting = async {
+        let mut error = None;
+        for addr in net::lookup_host(target).await? {
+            let socket = match addr {
+                SocketAddr::V4(_) => TcpSocket::new_v4()?,
+                SocketAddr::V6(_) => TcpSocket::new_v6()?,
+            };
+            if let Some(name) = interface {
+                bind_device(&socket, name)?;
+            }
+            match socket.connect(addr).await {
+                Ok(stream) => return Ok(stream),
+                Err(e) => error = Some(e),
+            }
+        }
+        Err(error.unwrap_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} did not resolve to any address", target),
+            )
+        }))
+    };
+    time::timeout(timeout, connecting)
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "Connection timed out"))?
+}
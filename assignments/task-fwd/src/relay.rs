@@ -0,0 +1,107 @@
+//! Copying the bytes of a forwarded connection in both directions, with an
+//! optional limit on the rate of each direction.
+
+use std::{
+    io,
+    time::{Duration, Instant},
+};
+
+use adnet_core::metrics::Counter;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+    time,
+};
+
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// Keeps the bytes copied at or below a rate, in bytes per second.
+pub struct Throttle {
+    rate: u64,
+    start: Instant,
+    total: u64,
+}
+
+impl Throttle {
+    pub fn new(rate: u64) -> Self {
+        Self {
+            rate,
+            start: Instant::now(),
+            total: 0,
+        }
+    }
+
+    /// Limits single reads to about 50 ms worth of data, so that the rate is
+    /// smooth also when it is small compared to the buffer size.
+    pub fn read_size(&self, buffer_size: usize) -> usize {
+        buffer_size.min((self.rate / 20).max(1) as usize)
+    }
+
+    /// Sleeps until copying `n` more bytes is within the rate.
+    pub async fn consumed(&mut self, n: usize) {
+        self.total += n as u64;
+        let due = Duration::from_secs_f64(self.total as f64 / self.rate as f64);
+        time::sleep_until((self.start + due).into()).await;
+    }
+}
+
+/// What went through one direction of a connection.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Transfer {
+    pub bytes: u64,
+    pub first_byte: Option<Instant>,
+}
+
+/// Copies from `reader` to `writer` until the end of the stream, and then
+/// closes the writing side, so that the other end sees the end too. The
+/// counter is updated as the bytes go, so that the metrics show the
+/// throughput of long connections while they are open.
+pub async fn pipe<R, W>(
+    mut reader: R,
+    mut writer: W,
+    rate: Option<u64>,
+    counter: &Counter,
+) -> io::Result<Transfer>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0u8; BUFFER_SIZE];
+    let mut throttle = rate.map(Throttle::new);
+    let read_size = throttle
+        .as_ref()
+        .map_or(buf.len(), |t| t.read_size(buf.len()));
+    let mut transfer = Transfer::default();
+    loop {
+        let n = reader.read(&mut buf[..read_size]).await?;
+        if n == 0 {
+            break;
+        }
+        transfer.first_byte.get_or_insert_with(Instant::now);
+        writer.write_all(&buf[..n]).await?;
+        transfer.bytes += n as u64;
+        counter.add(n as u64);
+        if let Some(throttle) = throttle.as_mut() {
+            throttle.consumed(n).await;
+        }
+    }
+    writer.shutdown().await?;
+    Ok(transfer)
+}
+
+/// Relays between the client and the target until both have closed their
+/// end, and returns the transfers up (from the client) and down.
+pub async fn relay(
+    client: &mut TcpStream,
+    target: &mut TcpStream,
+    rate: Option<u64>,
+    up: &Counter,
+    down: &Counter,
+) -> io::Result<(Transfer, Transfer)> {
+    let (client_read, client_write) = client.split();
+    let (target_read, target_write) = target.split();
+    tokio::try_join!(
+        pipe(client_read, target_write, rate, up),
+        pipe(target_read, client_write, rate, down),
+    )
+}
@@ -0,0 +1,425 @@
+//! Multicast sender and receiver. The sender sends sequenced, timestamped
+//! datagrams to an IPv4 or IPv6 multicast group; receivers join the group
+//! and report, for every sender they hear, the loss, reordering and jitter
+//! of its datagrams. The datagrams and the statistics are those of the UDP
+//! test of task-perf, with the sender id in place of the stream id.
+//!
+//! The task-mcast binary and the `adnet mcast` subcommand are thin wrappers
+//! around [`run`].
+
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6, UdpSocket},
+    process,
+    time::{Duration, Instant},
+};
+
+use adnet_core::{
+    config::{self, ConfigArgs},
+    logging::{self, LogArgs},
+    report::{Report, ReportArgs},
+    shutdown::{self, Shutdown},
+};
+use clap::{Parser, Subcommand};
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Protocol, Socket, Type};
+use task_perf::{
+    protocol::{Datagram, UDP_HEADER},
+    stats::StreamStats,
+};
+use tracing::{debug, info};
+
+const DEFAULT_GROUP: &str = "239.1.2.3:5000";
+const DEFAULT_TTL: u32 = 1;
+const DEFAULT_COUNT: u64 = 100;
+const DEFAULT_INTERVAL: Duration = Duration::from_millis(100);
+const DEFAULT_SIZE: usize = 64;
+const DEFAULT_REPORT_INTERVAL: Duration = Duration::from_secs(5);
+// Largest UDP payload over IPv4
+const MAX_SIZE: usize = 65507;
+// Longest the receiver waits before checking for Ctrl-C
+const SHUTDOWN_POLL: Duration = Duration::from_millis(200);
+
+/// Sends and receives sequenced datagrams over IP multicast.
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+pub struct Args {
+    #[command(subcommand)]
+    mode: Mode,
+
+    /// Multicast group and port [default: 239.1.2.3:5000]
+    #[arg(short, long, global = true)]
+    group: Option<SocketAddr>,
+
+    /// Interface to use: its IPv4 address for an IPv4 group, its index for IPv6
+    /// [default: chosen by the routing table]
+    #[arg(short, long, global = true)]
+    interface: Option<String>,
+
+    #[command(flatten)]
+    config: ConfigArgs,
+
+    #[command(flatten)]
+    log: LogArgs,
+
+    #[command(flatten)]
+    report: ReportArgs,
+}
+
+#[derive(Subcommand, Debug)]
+enum Mode {
+    /// Send datagrams to the group
+    Send {
+        /// Time to live (hop limit for IPv6) of the datagrams [default: 1]
+        #[arg(short, long)]
+        ttl: Option<u32>,
+
+        /// Do not deliver the datagrams to receivers on this host
+        #[arg(long)]
+        no_loopback: bool,
+
+        /// Number of datagrams to send, 0 for no limit [default: 100]
+        #[arg(short, long)]
+        count: Option<u64>,
+
+        /// Seconds between datagrams [default: 0.1]
+        #[arg(short = 'n', long, value_parser = config::parse_secs)]
+        interval: Option<Duration>,
+
+        /// Bytes in each datagram, at least 20 [default: 64]
+        #[arg(short, long)]
+        size: Option<usize>,
+
+        /// Id of this sender [default: process id]
+        #[arg(long)]
+        id: Option<u32>,
+    },
+
+    /// Join the group and report what arrives
+    Receive {
+        /// Seconds between reports [default: 5]
+        #[arg(short, long, value_parser = config::parse_secs)]
+        report_interval: Option<Duration>,
+
+        /// Stop after this many seconds [default: run until interrupted]
+        #[arg(short, long, value_parser = config::parse_secs)]
+        duration: Option<Duration>,
+    },
+}
+
+/// The [task-mcast] section of the config file. Each mode uses its own options.
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct FileConfig {
+    group: Option<SocketAddr>,
+    interface: Option<String>,
+    ttl: Option<u32>,
+    no_loopback: bool,
+    count: Option<u64>,
+    #[serde(deserialize_with = "config::secs")]
+    interval: Option<Duration>,
+    size: Option<usize>,
+    #[serde(deserialize_with = "config::secs")]
+    report_interval: Option<Duration>,
+    #[serde(deserialize_with = "config::secs")]
+    duration: Option<Duration>,
+}
+
+/// The interface multicast is sent and received on. IPv4 names interfaces by
+/// address and IPv6 by index; unspecified lets the routing table decide.
+#[derive(Clone, Copy, Debug)]
+enum Interface {
+    V4(Ipv4Addr),
+    V6(u32),
+}
+
+impl Interface {
+    fn parse(interface: Option<&str>, group: IpAddr) -> Result<Self, String> {
+        match (group, interface) {
+            (IpAddr::V4(_), None) => Ok(Interface::V4(Ipv4Addr::UNSPECIFIED)),
+            (IpAddr::V6(_), None) => Ok(Interface::V6(0)),
+            (IpAddr::V4(_), Some(s)) => s
+                .parse()
+                .map(Interface::V4)
+                .map_err(|_| format!("Interface of an IPv4 group must be an address: {}", s)),
+            (IpAddr::V6(_), Some(s)) => s
+                .parse()
+                .map(Interface::V6)
+                .map_err(|_| format!("Interface of an IPv6 group must be an index: {}", s)),
+        }
+    }
+}
+
+/// Runs the sender or receiver with the given arguments, as the task-mcast binary does.
+pub fn run(args: Args) -> Result<(), Box<dyn Error>> {
+    logging::init(&args.log)?;
+    let report = args.report.clone();
+    report.run("task-mcast", |report| multicast(args, report))
+}
+
+fn multicast(args: Args, report: &mut Report) -> Result<(), Box<dyn Error>> {
+    // Command line options take precedence over the config file
+    let file: FileConfig = args.config.section("task-mcast")?;
+    let group = match args.group.or(file.group) {
+        Some(group) => group,
+        None => DEFAULT_GROUP.parse()?,
+    };
+    if !group.ip().is_multicast() {
+        return Err(format!("{} is not a multicast address", group.ip()).into());
+    }
+    let interface = Interface::parse(args.interface.or(file.interface).as_deref(), group.ip())?;
+    let shutdown = shutdown::install()?;
+    report.detail("group", group);
+
+    match args.mode {
+        Mode::Send {
+            ttl,
+            no_loopback,
+            count,
+            interval,
+            size,
+            id,
+        } => {
+            let size = size.or(file.size).unwrap_or(DEFAULT_SIZE);
+            if !(UDP_HEADER..=MAX_SIZE).contains(&size) {
+                return Err(format!("Size must be between {} and {}", UDP_HEADER, MAX_SIZE).into());
+            }
+            let socket = sender_socket(
+                group,
+                interface,
+                ttl.or(file.ttl).unwrap_or(DEFAULT_TTL),
+                !(no_loopback || file.no_loopback),
+            )?;
+            let sent = send(
+                &socket,
+                group,
+                id.unwrap_or_else(process::id),
+                count.or(file.count).unwrap_or(DEFAULT_COUNT),
+                interval.or(file.interval).unwrap_or(DEFAULT_INTERVAL),
+                size,
+                &shutdown,
+            )?;
+            report.bytes = sent * size as u64;
+            report.detail("mode", "send");
+            report.detail("datagrams", sent);
+            Ok(())
+        }
+        Mode::Receive {
+            report_interval,
+            duration,
+        } => {
+            let socket = receiver_socket(group, interface)?;
+            let senders = receive(
+                &socket,
+                group,
+                report_interval
+                    .or(file.report_interval)
+                    .unwrap_or(DEFAULT_REPORT_INTERVAL),
+                duration.or(file.duration),
+                &shutdown,
+            )?;
+            report.bytes = senders.values().map(|stats| stats.bytes).sum();
+            report.detail("mode", "receive");
+            let senders: Vec<SenderReport> = senders
+                .into_iter()
+                .map(|((from, id), stats)| SenderReport { from, id, stats })
+                .collect();
+            report.detail("senders", senders);
+            Ok(())
+        }
+    }
+}
+
+/// What the receiver got from one sender, in the report.
+#[derive(Serialize)]
+struct SenderReport {
+    from: SocketAddr,
+    id: u32,
+    #[serde(flatten)]
+    stats: StreamStats,
+}
+
+/// A socket that sends to the group with the given time to live, and also
+/// to receivers on this host if `loopback` is set.
+fn sender_socket(
+    group: SocketAddr,
+    interface: Interface,
+    ttl: u32,
+    loopback: bool,
+) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::for_address(group), Type::DGRAM, Some(Protocol::UDP))?;
+    let any: SocketAddr = match interface {
+        Interface::V4(address) => {
+            socket.set_multicast_ttl_v4(ttl)?;
+            socket.set_multicast_loop_v4(loopback)?;
+            if !address.is_unspecified() {
+                socket.set_multicast_if_v4(&address)?;
+            }
+            (Ipv4Addr::UNSPECIFIED, 0).into()
+        }
+        Interface::V6(index) => {
+            socket.set_multicast_hops_v6(ttl)?;
+            socket.set_multicast_loop_v6(loopback)?;
+            if index != 0 {
+                socket.set_multicast_if_v6(index)?;
+            }
+            (Ipv6Addr::UNSPECIFIED, 0).into()
+        }
+    };
+    socket.bind(&any.into())?;
+    Ok(socket.into())
+}
+
+/// A socket bound to the group's port that has joined the group.
+fn receiver_socket(group: SocketAddr, interface: Interface) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::for_address(group), Type::DGRAM, Some(Protocol::UDP))?;
+    // Several receivers on the same host can bind to the same port
+    socket.set_reuse_address(true)?;
+
+    // Binding to the group address rather than the unspecified address
+    // filters out unicast datagrams and other groups on the same port
+    let bind = match (group, interface) {
+        (SocketAddr::V6(group), Interface::V6(index)) => {
+            socket.set_only_v6(true)?;
+            let scope = match group.scope_id() {
+                0 => index,
+                scope => scope,
+            };
+            SocketAddr::V6(SocketAddrV6::new(*group.ip(), group.port(), 0, scope))
+        }
+        _ => group,
+    };
+    socket.bind(&bind.into())?;
+
+    match (group.ip(), interface) {
+        (IpAddr::V4(ip), Interface::V4(address)) => socket.join_multicast_v4(&ip, &address)?,
+        (IpAddr::V6(ip), Interface::V6(index)) => socket.join_multicast_v6(&ip, index)?,
+        _ => unreachable!("interface is parsed for the family of the group"),
+    }
+    Ok(socket.into())
+}
+
+/// Sends until `count` datagrams have been sent, or until Ctrl-C if `count`
+/// is 0, and returns the number sent.
+fn send(
+    socket: &UdpSocket,
+    group: SocketAddr,
+    id: u32,
+    count: u64,
+    interval: Duration,
+    size: usize,
+    shutdown: &Shutdown,
+) -> io::Result<u64> {
+    info!("Sending to {} as sender {}", group, id);
+    let start = Instant::now();
+    let mut buf = vec![0u8; size];
+    let mut seq = 0;
+    while count == 0 || seq < count {
+        // Keep to the schedule even if sending takes time
+        let due = start + interval.mul_f64(seq as f64);
+        let wait = due.saturating_duration_since(Instant::now());
+        if shutdown.wait_timeout(wait) {
+            break;
+        }
+        let datagram = Datagram {
+            stream: id,
+            seq,
+            sent_us: start.elapsed().as_micros() as u64,
+        };
+        datagram.write(&mut buf);
+        socket.send_to(&buf, group)?;
+        seq += 1;
+        debug!("Sent datagram {}", seq);
+    }
+    info!("Sent {} datagrams of {} bytes", seq, size);
+    Ok(seq)
+}
+
+/// Counts the datagrams of every sender, keyed by its address and id, and
+/// prints reports until the duration is over or Ctrl-C.
+fn receive(
+    socket: &UdpSocket,
+    group: SocketAddr,
+    report_interval: Duration,
+    duration: Option<Duration>,
+    shutdown: &Shutdown,
+) -> io::Result<BTreeMap<(SocketAddr, u32), StreamStats>> {
+    info!("Joined {}", group);
+    let start = Instant::now();
+    let end = duration.map(|duration| start + duration);
+    let mut next_report = start + report_interval;
+    let mut senders: BTreeMap<(SocketAddr, u32), StreamStats> = BTreeMap::new();
+    let mut buf = vec![0u8; MAX_SIZE];
+
+    loop {
+        let now = Instant::now();
+        if end.is_some_and(|end| now >= end) || shutdown.is_requested() {
+            break;
+        }
+        if now >= next_report {
+            print_report(&senders, start.elapsed());
+            next_report += report_interval;
+        }
+        let wake = end
+            .map_or(next_report, |end| end.min(next_report))
+            .min(now + SHUTDOWN_POLL);
+        socket.set_read_timeout(Some(
+            wake.saturating_duration_since(now)
+                .max(Duration::from_millis(1)),
+        ))?;
+
+        let (n, from) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock
+                        | io::ErrorKind::TimedOut
+                        | io::ErrorKind::Interrupted
+                ) =>
+            {
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        let arrival_us = start.elapsed().as_micros() as u64;
+        let Some(datagram) = Datagram::read(&buf[..n]) else {
+            debug!("Ignoring short datagram from {}", from);
+            continue;
+        };
+        let stats = senders.entry((from, datagram.stream)).or_insert_with(|| {
+            info!("New sender {} with id {}", from, datagram.stream);
+            StreamStats::default()
+        });
+        stats.record_datagram(n, datagram.seq, datagram.sent_us, arrival_us);
+    }
+
+    print_report(&senders, start.elapsed());
+    Ok(senders)
+}
+
+fn print_report(senders: &BTreeMap<(SocketAddr, u32), StreamStats>, elapsed: Duration) {
+    println!(
+        "--- {:.1} s, {} sender(s) ---",
+        elapsed.as_secs_f64(),
+        senders.len()
+    );
+    for ((from, id), stats) in senders {
+        let percent = match stats.expected {
+            0 => 0.0,
+            expected => stats.lost() as f64 * 100.0 / expected as f64,
+        };
+        println!(
+            "{} id {}: {} received, {} lost ({:.2}%), {} out of order, jitter {:.3} ms",
+            from,
+            id,
+            stats.packets,
+            stats.lost(),
+            percent,
+            stats.out_of_order,
+            stats.jitter_ms
+        );
+    }
+}
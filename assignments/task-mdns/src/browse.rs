@@ -0,0 +1,211 @@
+//! DNS-SD browsing (RFC 6763) over the records that mDNS responders send.
+//! A service type such as `_http._tcp.local` has a PTR record for each
+//! instance, an instance has an SRV record with its host and port and a TXT
+//! record with its attributes, and the host has A and AAAA records. The
+//! records arrive in any order and from several responders; the browser
+//! keeps them, and tells which ones to ask for next.
+
+use std::{collections::HashSet, net::IpAddr, time::Instant};
+
+use serde::Serialize;
+use task_dns::message::{Record, RecordData, TYPE_A, TYPE_PTR, TYPE_SRV, TYPE_TXT};
+
+/// The name whose PTR records list the service types on the link
+pub const SERVICE_TYPES: &str = "_services._dns-sd._udp.local";
+
+struct Entry {
+    record: Record,
+    received: Instant,
+}
+
+impl Entry {
+    /// Seconds of the TTL that are left at `now`.
+    fn ttl_left(&self, now: Instant) -> u32 {
+        let elapsed = now.duration_since(self.received).as_secs();
+        self.record
+            .ttl
+            .saturating_sub(elapsed.min(u32::MAX as u64) as u32)
+    }
+}
+
+/// One instance of a service, as far as its records have arrived.
+#[derive(Clone, Debug, Serialize)]
+pub struct Service {
+    pub service_type: String,
+    /// The instance name without the service type
+    pub instance: String,
+    pub host: Option<String>,
+    pub port: Option<u16>,
+    pub addresses: Vec<IpAddr>,
+    pub txt: Vec<String>,
+    /// Seconds until the PTR record of the instance expires
+    pub ttl: u32,
+}
+
+fn same_name(a: &str, b: &str) -> bool {
+    a.trim_end_matches('.')
+        .eq_ignore_ascii_case(b.trim_end_matches('.'))
+}
+
+/// The instance name without the service type, e.g. "Printer" of
+/// "Printer._ipp._tcp.local".
+fn instance_label(instance: &str, service_type: &str) -> String {
+    let name = instance.trim_end_matches('.');
+    let suffix = format!(".{}", service_type.trim_end_matches('.'));
+    match name.len().checked_sub(suffix.len()) {
+        Some(at)
+            if name
+                .get(at..)
+                .is_some_and(|s| s.eq_ignore_ascii_case(&suffix)) =>
+        {
+            name[..at].to_string()
+        }
+        _ => name.to_string(),
+    }
+}
+
+#[derive(Default)]
+pub struct Browser {
+    entries: Vec<Entry>,
+    asked: HashSet<(String, u16)>,
+}
+
+impl Browser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Notes that a question was asked, so that [`questions`](Self::questions)
+    /// does not return it again. Returns whether it is new.
+    pub fn ask(&mut self, name: &str, kind: u16) -> bool {
+        self.asked.insert((name.to_ascii_lowercase(), kind))
+    }
+
+    /// All questions asked so far.
+    pub fn asked(&self) -> impl Iterator<Item = &(String, u16)> {
+        self.asked.iter()
+    }
+
+    /// Keeps a record received at `now`, replacing an equal one. A TTL of
+    /// zero is a goodbye: the responder withdraws the record.
+    pub fn insert(&mut self, record: Record, now: Instant) {
+        self.entries.retain(|entry| {
+            !(entry.record.kind == record.kind
+                && entry.record.data == record.data
+                && same_name(&entry.record.name, &record.name))
+        });
+        if record.ttl > 0 {
+            self.entries.push(Entry {
+                record,
+                received: now,
+            });
+        }
+    }
+
+    fn records<'a>(&'a self, name: &'a str, kind: u16) -> impl Iterator<Item = &'a Entry> {
+        self.entries
+            .iter()
+            .filter(move |entry| entry.record.kind == kind && same_name(&entry.record.name, name))
+    }
+
+    /// The questions that would complete what is known and have not been
+    /// asked yet: the instances of new service types, the SRV and TXT
+    /// records of new instances and the addresses of new hosts.
+    pub fn questions(&mut self) -> Vec<(String, u16)> {
+        let mut wanted = Vec::new();
+        for entry in &self.entries {
+            match &entry.record.data {
+                RecordData::Ptr(target) if same_name(&entry.record.name, SERVICE_TYPES) => {
+                    wanted.push((target.clone(), TYPE_PTR));
+                }
+                RecordData::Ptr(instance) => {
+                    if self.records(instance, TYPE_SRV).next().is_none() {
+                        wanted.push((instance.clone(), TYPE_SRV));
+                    }
+                    if self.records(instance, TYPE_TXT).next().is_none() {
+                        wanted.push((instance.clone(), TYPE_TXT));
+                    }
+                }
+                RecordData::Srv { target, .. } if self.addresses(target).is_empty() => {
+                    wanted.push((target.clone(), TYPE_A));
+                }
+                _ => {}
+            }
+        }
+        wanted.retain(|(name, kind)| self.asked.insert((name.to_ascii_lowercase(), *kind)));
+        wanted
+    }
+
+    fn addresses(&self, host: &str) -> Vec<IpAddr> {
+        self.entries
+            .iter()
+            .filter(|entry| same_name(&entry.record.name, host))
+            .filter_map(|entry| match entry.record.data {
+                RecordData::A(ip) => Some(IpAddr::V4(ip)),
+                RecordData::Aaaa(ip) => Some(IpAddr::V6(ip)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// The service types learned, from the enumeration or from instances.
+    pub fn service_types(&self) -> Vec<String> {
+        let mut types: Vec<String> = self
+            .entries
+            .iter()
+            .filter_map(|entry| match &entry.record.data {
+                RecordData::Ptr(_) if !same_name(&entry.record.name, SERVICE_TYPES) => {
+                    Some(entry.record.name.clone())
+                }
+                _ => None,
+            })
+            .collect();
+        types.sort_by_key(|name| name.to_ascii_lowercase());
+        types.dedup_by(|a, b| same_name(a, b));
+        types
+    }
+
+    /// The instances known at `now`, by service type and name.
+    pub fn services(&self, now: Instant) -> Vec<Service> {
+        let mut services = Vec::new();
+        for entry in &self.entries {
+            let RecordData::Ptr(instance) = &entry.record.data else {
+                continue;
+            };
+            let service_type = &entry.record.name;
+            if same_name(service_type, SERVICE_TYPES) {
+                continue;
+            }
+            let srv = self
+                .records(instance, TYPE_SRV)
+                .find_map(|e| match &e.record.data {
+                    RecordData::Srv { target, port, .. } => Some((target.clone(), *port)),
+                    _ => None,
+                });
+            let txt = self
+                .records(instance, TYPE_TXT)
+                .find_map(|e| match &e.record.data {
+                    RecordData::Txt(strings) => Some(strings.clone()),
+                    _ => None,
+                })
+                .unwrap_or_default();
+            services.push(Service {
+                service_type: service_type.clone(),
+                instance: instance_label(instance, service_type),
+                addresses: srv
+                    .as_ref()
+                    .map(|(host, _)| self.addresses(host))
+                    .unwrap_or_default(),
+                host: srv.as_ref().map(|(host, _)| host.clone()),
+                port: srv.map(|(_, port)| port),
+                txt: txt.into_iter().filter(|s| !s.is_empty()).collect(),
+                ttl: entry.ttl_left(now),
+            });
+        }
+        services.sort_by(|a, b| {
+            (a.service_type.to_ascii_lowercase(), &a.instance)
+                .cmp(&(b.service_type.to_ascii_lowercase(), &b.instance))
+        });
+        services
+    }
+}
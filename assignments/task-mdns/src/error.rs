@@ -0,0 +1,31 @@
+use std::io;
+
+use adnet_core::exit::{Code, HasCode};
+use thiserror::Error;
+
+/// Errors of task-mdns. Each kind has its own exit code, see
+/// [`adnet_core::exit`].
+#[derive(Debug, Error)]
+pub enum MdnsError {
+    /// Invalid or missing options
+    #[error("{0}")]
+    Usage(String),
+    /// The config file could not be read
+    #[error(transparent)]
+    Config(io::Error),
+    /// Port 5353 or the mDNS group could not be joined
+    #[error("Cannot join the mDNS group: {0}")]
+    Socket(io::Error),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+impl HasCode for MdnsError {
+    fn exit_code(&self) -> Code {
+        match self {
+            MdnsError::Usage(_) | MdnsError::Config(_) => Code::Usage,
+            MdnsError::Socket(_) => Code::Failure,
+            MdnsError::Io(e) => e.exit_code(),
+        }
+    }
+}
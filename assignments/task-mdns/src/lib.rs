@@ -0,0 +1,233 @@
+//! mDNS service discovery probe. It joins the mDNS group 224.0.0.251 and
+//! asks with multicast DNS (RFC 6762) which services the hosts on the link
+//! offer, following DNS-SD (RFC 6763) from the service types to their
+//! instances, hosts, ports and addresses. The queries are built and the
+//! answers parsed with the DNS messages of task-dns.
+//!
+//! The task-mdns binary and the `adnet mdns` subcommand are thin wrappers
+//! around [`run`].
+
+pub mod browse;
+mod error;
+
+use std::{
+    collections::BTreeSet,
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use adnet_core::{
+    config::{self, ConfigArgs},
+    logging::{self, LogArgs},
+    report::{Report, ReportArgs},
+    shutdown,
+};
+use browse::{Browser, Service, SERVICE_TYPES};
+use clap::Parser;
+use serde::Deserialize;
+use socket2::{Domain, Protocol, Socket, Type};
+use task_dns::message::{self, Response, CLASS_IN, TYPE_PTR};
+use tracing::{debug, info, warn};
+
+pub use error::MdnsError;
+
+pub const MDNS_GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+pub const MDNS_PORT: u16 = 5353;
+const DEFAULT_DURATION: Duration = Duration::from_secs(3);
+// The second query goes out after a second, in case the first was lost
+const REPEAT_AFTER: Duration = Duration::from_secs(1);
+// Longest the probe waits for a packet before checking for Ctrl-C
+const SHUTDOWN_POLL: Duration = Duration::from_millis(200);
+
+/// Lists the services that hosts on the local network announce with mDNS.
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+pub struct Args {
+    /// Service type to browse, e.g. _http._tcp [default: all types]
+    #[arg(short, long)]
+    service: Option<String>,
+
+    /// IPv4 address of the interface to use [default: chosen by the
+    /// routing table]
+    #[arg(short, long)]
+    interface: Option<Ipv4Addr>,
+
+    /// Seconds to listen for answers [default: 3]
+    #[arg(short, long, value_parser = config::parse_secs)]
+    duration: Option<Duration>,
+
+    #[command(flatten)]
+    config: ConfigArgs,
+
+    #[command(flatten)]
+    log: LogArgs,
+
+    #[command(flatten)]
+    report: ReportArgs,
+}
+
+/// The [task-mdns] section of the config file
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct FileConfig {
+    service: Option<String>,
+    interface: Option<Ipv4Addr>,
+    #[serde(deserialize_with = "config::secs")]
+    duration: Option<Duration>,
+}
+
+/// A socket on the mDNS port that has joined the group. Queries sent from
+/// port 5353 are answered to the group, with the full TTLs, rather than to
+/// the asker alone.
+fn mdns_socket(interface: Ipv4Addr) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    // Next to the responder of this host, e.g. avahi-daemon
+    socket.set_reuse_address(true)?;
+    socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, MDNS_PORT)).into())?;
+    socket.join_multicast_v4(&MDNS_GROUP, &interface)?;
+    if !interface.is_unspecified() {
+        socket.set_multicast_if_v4(&interface)?;
+    }
+    // Packets with a TTL of 255 can only come from the link itself
+    socket.set_multicast_ttl_v4(255)?;
+    // So that the services of this host are found, too
+    socket.set_multicast_loop_v4(true)?;
+    Ok(socket.into())
+}
+
+/// Sends a query for one name and type to the group. The id is zero, as in
+/// all multicast queries.
+fn query(socket: &UdpSocket, name: &str, kind: u16) -> Result<(), MdnsError> {
+    let packet = message::encode_question(0, 0, name, kind, CLASS_IN)
+        .map_err(|e| MdnsError::Usage(e.to_string()))?;
+    debug!("Asking for {} records of {}", kind, name);
+    socket.send_to(&packet, (MDNS_GROUP, MDNS_PORT))?;
+    Ok(())
+}
+
+/// Runs the probe with the given arguments, as the task-mdns binary does.
+pub fn run(args: Args) -> Result<(), MdnsError> {
+    logging::init(&args.log)?;
+    let report_args = args.report.clone();
+    let mut report = Report::new("task-mdns");
+    let result = browse(args, &mut report);
+    report_args.finish(&mut report, result)
+}
+
+fn browse(args: Args, report: &mut Report) -> Result<(), MdnsError> {
+    // Command line options take precedence over the config file
+    let file: FileConfig = args
+        .config
+        .section("task-mdns")
+        .map_err(MdnsError::Config)?;
+    let interface = args
+        .interface
+        .or(file.interface)
+        .unwrap_or(Ipv4Addr::UNSPECIFIED);
+    let duration = args.duration.or(file.duration).unwrap_or(DEFAULT_DURATION);
+    // Service types are given without the domain, which is always local
+    let first = match args.service.or(file.service) {
+        Some(service) if service.trim_end_matches('.').ends_with(".local") => service,
+        Some(service) => format!("{}.local", service.trim_end_matches('.')),
+        None => SERVICE_TYPES.to_string(),
+    };
+
+    let socket = mdns_socket(interface).map_err(MdnsError::Socket)?;
+    socket.set_read_timeout(Some(SHUTDOWN_POLL))?;
+    let shutdown = shutdown::install()?;
+    let mut browser = Browser::new();
+    browser.ask(&first, TYPE_PTR);
+    query(&socket, &first, TYPE_PTR)?;
+    info!("Browsing {} for {:?}", first, duration);
+
+    let start = Instant::now();
+    let mut repeated = false;
+    let mut responders = BTreeSet::new();
+    let mut responses = 0u64;
+    let mut buf = [0u8; 9000];
+    while !shutdown.is_requested() && start.elapsed() < duration {
+        if !repeated && start.elapsed() >= REPEAT_AFTER {
+            let asked: Vec<(String, u16)> = browser.asked().cloned().collect();
+            for (name, kind) in asked {
+                query(&socket, &name, kind)?;
+            }
+            repeated = true;
+        }
+        let (n, from) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock
+                        | io::ErrorKind::TimedOut
+                        | io::ErrorKind::Interrupted
+                ) =>
+            {
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        // Responses come from the mDNS port; anything else answers a
+        // one-shot query of another program
+        if from.port() != MDNS_PORT {
+            continue;
+        }
+        let response = match Response::parse(&buf[..n]) {
+            Ok(response) => response,
+            // The queries of other hosts arrive, too
+            Err(message::MessageError::NotResponse) => continue,
+            Err(e) => {
+                warn!("Ignoring packet from {}: {}", from, e);
+                continue;
+            }
+        };
+        responses += 1;
+        responders.insert(from.ip());
+        let now = Instant::now();
+        for record in response.answers.into_iter().chain(response.additional) {
+            browser.insert(record, now);
+        }
+        for (name, kind) in browser.questions() {
+            query(&socket, &name, kind)?;
+        }
+    }
+
+    let services = browser.services(Instant::now());
+    print_services(&browser.service_types(), &services);
+    println!(
+        "--- {} services from {} responders ---",
+        services.len(),
+        responders.len()
+    );
+    report.detail("responses", responses);
+    report.detail("responders", &responders);
+    report.detail("services", &services);
+    Ok(())
+}
+
+fn print_services(types: &[String], services: &[Service]) {
+    for service_type in types {
+        println!("{}", service_type);
+        for service in services
+            .iter()
+            .filter(|s| s.service_type.eq_ignore_ascii_case(service_type))
+        {
+            let location = match (&service.host, service.port) {
+                (Some(host), Some(port)) => format!("{}:{}", host, port),
+                _ => "(no SRV record)".to_string(),
+            };
+            let addresses: Vec<String> = service.addresses.iter().map(IpAddr::to_string).collect();
+            println!(
+                "  {:30} {} {} ttl {} s",
+                service.instance,
+                location,
+                addresses.join(", "),
+                service.ttl
+            );
+            if !service.txt.is_empty() {
+                println!("  {:30} {}", "", service.txt.join(" "));
+            }
+        }
+    }
+}
@@ -0,0 +1,8 @@
+use adnet_core::exit;
+use clap::Parser;
+use std::process::ExitCode;
+use task_mdns::Args;
+
+fn main() -> ExitCode {
+    exit::exit(task_mdns::run(Args::parse()))
+}
@@ -0,0 +1,571 @@
+//! Userspace NAT box. Packets that the kernel routes into the inside TUN
+//! device get their source address and port translated to the public
+//! address, and are sent out through the uplink: a second TUN device, or a
+//! UDP tunnel to a task-tun peer in the format of task-tun. Answers to the
+//! public address are translated back and written to the inside device.
+//! TCP, UDP and ICMP echo are translated; the mappings are kept in
+//! [`table::NatTable`] and expire when they are not used.
+//!
+//! The task-nat binary and the `adnet nat` subcommand are thin wrappers
+//! around [`run`].
+
+pub mod table;
+pub mod translate;
+
+use std::{
+    io::{self, Read, Write},
+    net::{Ipv4Addr, SocketAddr},
+    ops::RangeInclusive,
+    os::unix::io::AsRawFd,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use adnet_core::{
+    config::{self, ConfigArgs},
+    logging::{self, LogArgs},
+    metrics::{self, Counter, Gauge, MetricsArgs},
+    report::{Report, ReportArgs},
+    shutdown,
+};
+use clap::Parser;
+use mio::{net::UdpSocket, unix::SourceFd, Events, Interest, Poll, Token};
+use serde::Deserialize;
+use table::{Endpoint, NatTable, Timeouts};
+use task_tun::{
+    fragment::{Fragmenter, Reassembler},
+    keepalive,
+    packet::{self, Cipher},
+};
+use tracing::{debug, info, warn};
+use translate::Side;
+
+const INSIDE_TOKEN: Token = Token(0);
+const UPLINK_TOKEN: Token = Token(1);
+const MTU: usize = 1500;
+// The path MTU of the tunnel to task-tun, the default of task-tun
+const TUNNEL_MTU: usize = 1500;
+// How often timed out mappings are removed
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(1);
+const DEFAULT_PORTS: RangeInclusive<u16> = 20000..=29999;
+const DEFAULT_TCP_TIMEOUT: Duration = Duration::from_secs(300);
+const DEFAULT_UDP_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_ICMP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Source NAT between a TUN device and an uplink.
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+pub struct Args {
+    /// Name of the inside TUN device [default: tun0]
+    #[arg(long)]
+    inside: Option<String>,
+
+    /// Address of this host on the inside TUN device
+    #[arg(short, long)]
+    address: Option<Ipv4Addr>,
+
+    /// Netmask of the inside network [default: 255.255.255.0]
+    #[arg(short, long)]
+    netmask: Option<Ipv4Addr>,
+
+    /// Public address the inside hosts are translated to
+    #[arg(short, long)]
+    public: Option<Ipv4Addr>,
+
+    /// Name of the uplink TUN device [default: tun1]
+    #[arg(long)]
+    uplink: Option<String>,
+
+    /// Address of this host on the uplink TUN device, the peer of the public address
+    #[arg(long)]
+    uplink_address: Option<Ipv4Addr>,
+
+    /// Instead of an uplink device, tunnel over UDP to a task-tun peer from this address
+    #[arg(short = 'b', long, requires = "udpdest")]
+    udpbind: Option<SocketAddr>,
+
+    /// Address of the task-tun peer
+    #[arg(short = 'u', long, requires = "udpbind")]
+    udpdest: Option<SocketAddr>,
+
+    /// Key file of the task-tun peer, for encrypting the tunnel as it does
+    #[arg(long, requires = "udpbind")]
+    key_file: Option<PathBuf>,
+
+    /// Range of public ports, as FIRST-LAST [default: 20000-29999]
+    #[arg(long, value_parser = parse_ports)]
+    ports: Option<RangeInclusive<u16>>,
+
+    /// Seconds before an unused TCP mapping expires [default: 300]
+    #[arg(long, value_parser = config::parse_secs)]
+    tcp_timeout: Option<Duration>,
+
+    /// Seconds before an unused UDP mapping expires [default: 30]
+    #[arg(long, value_parser = config::parse_secs)]
+    udp_timeout: Option<Duration>,
+
+    /// Seconds before an unused ICMP echo mapping expires [default: 30]
+    #[arg(long, value_parser = config::parse_secs)]
+    icmp_timeout: Option<Duration>,
+
+    #[command(flatten)]
+    config: ConfigArgs,
+
+    #[command(flatten)]
+    log: LogArgs,
+
+    #[command(flatten)]
+    metrics: MetricsArgs,
+
+    #[command(flatten)]
+    report: ReportArgs,
+}
+
+/// The [task-nat] section of the config file
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct FileConfig {
+    inside: Option<String>,
+    address: Option<Ipv4Addr>,
+    netmask: Option<Ipv4Addr>,
+    public: Option<Ipv4Addr>,
+    uplink: Option<String>,
+    uplink_address: Option<Ipv4Addr>,
+    udpbind: Option<SocketAddr>,
+    udpdest: Option<SocketAddr>,
+    key_file: Option<PathBuf>,
+    ports: Option<String>,
+    #[serde(deserialize_with = "config::secs")]
+    tcp_timeout: Option<Duration>,
+    #[serde(deserialize_with = "config::secs")]
+    udp_timeout: Option<Duration>,
+    #[serde(deserialize_with = "config::secs")]
+    icmp_timeout: Option<Duration>,
+}
+
+fn parse_ports(s: &str) -> Result<RangeInclusive<u16>, String> {
+    let (first, last) = s.split_once('-').ok_or("Expected FIRST-LAST")?;
+    let first: u16 = first.trim().parse().map_err(|e| format!("{}", e))?;
+    let last: u16 = last.trim().parse().map_err(|e| format!("{}", e))?;
+    if first == 0 || first > last {
+        return Err(format!("Invalid port range {}", s));
+    }
+    Ok(first..=last)
+}
+
+/// Takes the option from the command line or else from the config file.
+fn required<T>(cli: Option<T>, file: Option<T>, name: &str) -> io::Result<T> {
+    cli.or(file).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("--{} is required", name),
+        )
+    })
+}
+
+/// The NAT's metrics in the global registry. "Out" is from the inside device
+/// to the uplink and "in" the other way.
+#[derive(Clone)]
+struct Metrics {
+    packets_out: Counter,
+    bytes_out: Counter,
+    packets_in: Counter,
+    bytes_in: Counter,
+    dropped: Counter,
+    mappings: Gauge,
+    mappings_created: Counter,
+    mappings_expired: Counter,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        Self {
+            packets_out: metrics::counter("nat_packets_out_total", "Packets sent to the uplink"),
+            bytes_out: metrics::counter("nat_bytes_out_total", "Bytes sent to the uplink"),
+            packets_in: metrics::counter(
+                "nat_packets_in_total",
+                "Packets written to the inside device",
+            ),
+            bytes_in: metrics::counter("nat_bytes_in_total", "Bytes written to the inside device"),
+            dropped: metrics::counter("nat_dropped_total", "Packets that could not be translated"),
+            mappings: metrics::gauge("nat_mappings", "Mappings in the translation table"),
+            mappings_created: metrics::counter(
+                "nat_mappings_created_total",
+                "Mappings added to the translation table",
+            ),
+            mappings_expired: metrics::counter(
+                "nat_mappings_expired_total",
+                "Mappings removed after their timeout",
+            ),
+        }
+    }
+
+    /// Fills in the totals of the report.
+    fn report(&self, report: &mut Report) {
+        report.bytes = self.bytes_out.get() + self.bytes_in.get();
+        report.errors = self.dropped.get();
+        report.detail("packets_out", self.packets_out.get());
+        report.detail("packets_in", self.packets_in.get());
+        report.detail("mappings_created", self.mappings_created.get());
+        report.detail("mappings_expired", self.mappings_expired.get());
+    }
+}
+
+/// Where translated packets are sent and answers come from.
+enum Uplink {
+    Tun(tun::Device),
+    /// Packets tunneled to a task-tun peer, in its format
+    Udp {
+        socket: UdpSocket,
+        peer: SocketAddr,
+        cipher: Option<Cipher>,
+        fragmenter: Fragmenter,
+        reassembler: Reassembler,
+    },
+}
+
+impl Uplink {
+    fn register(&mut self, poll: &Poll) -> io::Result<()> {
+        match self {
+            Uplink::Tun(dev) => {
+                let raw_fd = dev.as_raw_fd();
+                poll.registry()
+                    .register(&mut SourceFd(&raw_fd), UPLINK_TOKEN, Interest::READABLE)
+            }
+            Uplink::Udp { socket, .. } => {
+                poll.registry()
+                    .register(socket, UPLINK_TOKEN, Interest::READABLE)
+            }
+        }
+    }
+
+    fn send(&mut self, buf: &[u8]) -> io::Result<()> {
+        match self {
+            Uplink::Tun(dev) => dev.write_all(buf),
+            Uplink::Udp {
+                socket,
+                peer,
+                cipher,
+                fragmenter,
+                ..
+            } => {
+                let mut sealed = [0u8; MTU + packet::OVERHEAD];
+                let datagram = match cipher {
+                    Some(cipher) => {
+                        let len = cipher.seal(buf, &mut sealed);
+                        &sealed[..len]
+                    }
+                    None => buf,
+                };
+                fragmenter.split(datagram, |fragment| {
+                    socket.send_to(fragment, *peer).map(|_| ())
+                })?;
+                Ok(())
+            }
+        }
+    }
+
+    /// Passes the packets that have arrived to `deliver`.
+    fn receive(
+        &mut self,
+        buf: &mut [u8],
+        mut deliver: impl FnMut(&mut [u8]) -> io::Result<()>,
+    ) -> io::Result<()> {
+        match self {
+            Uplink::Tun(dev) => {
+                let n = dev.read(buf)?;
+                if n > 0 {
+                    deliver(&mut buf[..n])?;
+                }
+                Ok(())
+            }
+            // The socket is non-blocking, so read everything that is queued
+            Uplink::Udp {
+                socket,
+                peer,
+                cipher,
+                reassembler,
+                ..
+            } => loop {
+                let (n, from) = match socket.recv_from(buf) {
+                    Ok(received) => received,
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                    Err(e) => return Err(e),
+                };
+                if from != *peer {
+                    debug!("Ignoring datagram from {}", from);
+                    continue;
+                }
+                // task-nat does not keep the tunnel alive itself
+                if keepalive::parse_header(&buf[..n]).is_some() {
+                    continue;
+                }
+                let now = Instant::now();
+                reassembler.expire(now);
+                let datagram = match reassembler.push(&buf[..n], now) {
+                    Ok(Some(datagram)) => datagram,
+                    Ok(None) => continue,
+                    Err(e) => {
+                        debug!("Dropping datagram: {}", e);
+                        continue;
+                    }
+                };
+                match cipher {
+                    Some(cipher) => match cipher.open(datagram) {
+                        Ok(ip_packet) => deliver(ip_packet)?,
+                        Err(_) => debug!("Dropping packet that failed authentication"),
+                    },
+                    None => deliver(datagram)?,
+                }
+            },
+        }
+    }
+}
+
+/// Translates packets in both directions.
+struct Translator {
+    table: NatTable,
+    network: Ipv4Addr,
+    netmask: Ipv4Addr,
+    public: Ipv4Addr,
+    metrics: Metrics,
+}
+
+impl Translator {
+    fn is_inside(&self, address: Ipv4Addr) -> bool {
+        let mask = u32::from(self.netmask);
+        u32::from(address) & mask == u32::from(self.network) & mask
+    }
+
+    /// Translates the source of a packet from the inside device. Returns
+    /// false if the packet is to be dropped.
+    fn outbound(&mut self, packet: &mut [u8]) -> bool {
+        let Some(flow) = translate::flow(packet, true).filter(|f| self.is_inside(f.source)) else {
+            debug!("Dropping untranslatable packet from the inside device");
+            self.metrics.dropped.inc();
+            return false;
+        };
+        let inside = Endpoint {
+            protocol: flow.protocol,
+            address: flow.source,
+            port: flow.source_port,
+        };
+        let Some((port, new)) = self.table.outbound(inside, Instant::now()) else {
+            warn!("No free public port for {:?}, dropping", inside);
+            self.metrics.dropped.inc();
+            return false;
+        };
+        if new {
+            info!(
+                "New {:?} mapping {}:{} -> {}:{}",
+                inside.protocol, inside.address, inside.port, self.public, port
+            );
+            self.metrics.mappings_created.inc();
+            self.metrics.mappings.set(self.table.len() as i64);
+        }
+
+        translate::rewrite(packet, Side::Source, self.public, port);
+        self.metrics.packets_out.inc();
+        self.metrics.bytes_out.add(packet.len() as u64);
+        true
+    }
+
+    /// Translates the destination of a packet from the uplink. Returns false
+    /// if the packet is to be dropped.
+    fn inbound(&mut self, packet: &mut [u8]) -> bool {
+        let Some(flow) = translate::flow(packet, false).filter(|f| f.destination == self.public)
+        else {
+            debug!("Dropping untranslatable packet from the uplink");
+            self.metrics.dropped.inc();
+            return false;
+        };
+        let now = Instant::now();
+        let Some(inside) = self
+            .table
+            .inbound(flow.protocol, flow.destination_port, now)
+        else {
+            debug!(
+                "No {:?} mapping for port {}, dropping packet from {}",
+                flow.protocol, flow.destination_port, flow.source
+            );
+            self.metrics.dropped.inc();
+            return false;
+        };
+
+        translate::rewrite(packet, Side::Destination, inside.address, inside.port);
+        self.metrics.packets_in.inc();
+        self.metrics.bytes_in.add(packet.len() as u64);
+        true
+    }
+
+    fn expire(&mut self) {
+        for (inside, port) in self.table.expire(Instant::now()) {
+            info!(
+                "Expired {:?} mapping {}:{} -> {}:{}",
+                inside.protocol, inside.address, inside.port, self.public, port
+            );
+            self.metrics.mappings_expired.inc();
+        }
+        self.metrics.mappings.set(self.table.len() as i64);
+    }
+}
+
+/// Runs the NAT with the given arguments, as the task-nat binary does,
+/// until Ctrl-C.
+pub fn run(args: Args) -> io::Result<()> {
+    logging::init(&args.log)?;
+    metrics::init(&args.metrics)?;
+    let report = args.report.clone();
+    report.run("task-nat", |report| {
+        let metrics = Metrics::new();
+        let result = run_nat(args, metrics.clone());
+        metrics.report(report);
+        result
+    })
+}
+
+fn run_nat(args: Args, metrics: Metrics) -> io::Result<()> {
+    // Command line options take precedence over the config file
+    let file: FileConfig = args.config.section("task-nat")?;
+    let address = required(args.address, file.address, "address")?;
+    let public = required(args.public, file.public, "public")?;
+    let netmask = args
+        .netmask
+        .or(file.netmask)
+        .unwrap_or(Ipv4Addr::new(255, 255, 255, 0));
+    let ports = match (args.ports, file.ports) {
+        (Some(ports), _) => ports,
+        (None, Some(ports)) => {
+            parse_ports(&ports).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?
+        }
+        (None, None) => DEFAULT_PORTS,
+    };
+    let timeouts = Timeouts {
+        tcp: args
+            .tcp_timeout
+            .or(file.tcp_timeout)
+            .unwrap_or(DEFAULT_TCP_TIMEOUT),
+        udp: args
+            .udp_timeout
+            .or(file.udp_timeout)
+            .unwrap_or(DEFAULT_UDP_TIMEOUT),
+        icmp: args
+            .icmp_timeout
+            .or(file.icmp_timeout)
+            .unwrap_or(DEFAULT_ICMP_TIMEOUT),
+    };
+
+    let inside_name = args
+        .inside
+        .or(file.inside)
+        .unwrap_or_else(|| "tun0".to_string());
+    let mut inside = task_tun::create_device(&inside_name, address, None, netmask)?;
+
+    let mut uplink = match (args.udpbind.or(file.udpbind), args.udpdest.or(file.udpdest)) {
+        (Some(udpbind), Some(udpdest)) => {
+            info!(
+                "Tunneling translated packets from {} to {}",
+                udpbind, udpdest
+            );
+            let cipher = match args.key_file.or(file.key_file) {
+                Some(path) => Some(Cipher::from_key_file(&path)?),
+                None => None,
+            };
+            // The IP and UDP headers of the datagrams
+            let headers = if udpdest.is_ipv4() { 28 } else { 48 };
+            Uplink::Udp {
+                socket: UdpSocket::bind(udpbind)?,
+                peer: udpdest,
+                cipher,
+                fragmenter: Fragmenter::new(TUNNEL_MTU - headers),
+                reassembler: Reassembler::new(),
+            }
+        }
+        (None, None) => {
+            let name = args
+                .uplink
+                .or(file.uplink)
+                .unwrap_or_else(|| "tun1".to_string());
+            let uplink_address =
+                required(args.uplink_address, file.uplink_address, "uplink-address")?;
+            // The public address is the peer of the point-to-point uplink,
+            // so that the kernel routes the answers into the device
+            let dev = task_tun::create_device(
+                &name,
+                uplink_address,
+                Some(public),
+                Ipv4Addr::new(255, 255, 255, 255),
+            )?;
+            info!("Sending translated packets to {}", name);
+            Uplink::Tun(dev)
+        }
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "--udpbind and --udpdest must be given together",
+            ))
+        }
+    };
+
+    info!(
+        "Translating {}/{} on {} to {} ports {}-{}",
+        address,
+        netmask,
+        inside_name,
+        public,
+        ports.start(),
+        ports.end()
+    );
+    let mut translator = Translator {
+        table: NatTable::new(ports, timeouts),
+        network: address,
+        netmask,
+        public,
+        metrics,
+    };
+    let shutdown = shutdown::install()?;
+
+    let mut poll = Poll::new()?;
+    let mut events = Events::with_capacity(128);
+    let raw_fd = inside.as_raw_fd();
+    poll.registry()
+        .register(&mut SourceFd(&raw_fd), INSIDE_TOKEN, Interest::READABLE)?;
+    uplink.register(&poll)?;
+
+    // Room for the datagrams of an encrypted tunnel
+    let mut buf = [0u8; MTU + packet::OVERHEAD];
+    let mut last_expiry = Instant::now();
+    // The poll timeout also bounds how long a shutdown request waits
+    while !shutdown.is_requested() {
+        match poll.poll(&mut events, Some(EXPIRY_INTERVAL)) {
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            result => result?,
+        }
+
+        for event in events.iter() {
+            match event.token() {
+                INSIDE_TOKEN if event.is_readable() => {
+                    let n = inside.read(&mut buf)?;
+                    if n > 0 && translator.outbound(&mut buf[..n]) {
+                        uplink.send(&buf[..n])?;
+                    }
+                }
+                UPLINK_TOKEN if event.is_readable() => {
+                    uplink.receive(&mut buf, |packet| {
+                        if translator.inbound(packet) {
+                            inside.write_all(packet)?;
+                        }
+                        Ok(())
+                    })?;
+                }
+                _ => {}
+            }
+        }
+
+        if last_expiry.elapsed() >= EXPIRY_INTERVAL {
+            translator.expire();
+            last_expiry = Instant::now();
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,8 @@
+use adnet_core::exit;
+use clap::Parser;
+use std::process::ExitCode;
+use task_nat::Args;
+
+fn main() -> ExitCode {
+    exit::exit(task_nat::run(Args::parse()))
+}
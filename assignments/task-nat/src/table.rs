@@ -0,0 +1,143 @@
+//! The translation table: which inside address and port each public port
+//! stands for. Mappings are endpoint independent, so every remote host can
+//! answer to a mapped port, and expire when they have not been used for the
+//! timeout of their protocol.
+
+use std::{
+    collections::HashMap,
+    net::Ipv4Addr,
+    ops::RangeInclusive,
+    time::{Duration, Instant},
+};
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Protocol {
+    Tcp,
+    Udp,
+    /// ICMP echo, with the identifier in place of the port
+    Icmp,
+}
+
+/// An address and port of one protocol.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct Endpoint {
+    pub protocol: Protocol,
+    pub address: Ipv4Addr,
+    pub port: u16,
+}
+
+/// How long unused mappings are kept, for each protocol.
+#[derive(Clone, Copy, Debug)]
+pub struct Timeouts {
+    pub tcp: Duration,
+    pub udp: Duration,
+    pub icmp: Duration,
+}
+
+impl Timeouts {
+    fn of(&self, protocol: Protocol) -> Duration {
+        match protocol {
+            Protocol::Tcp => self.tcp,
+            Protocol::Udp => self.udp,
+            Protocol::Icmp => self.icmp,
+        }
+    }
+}
+
+struct Mapping {
+    inside: Endpoint,
+    last_used: Instant,
+}
+
+pub struct NatTable {
+    outbound: HashMap<Endpoint, u16>,
+    inbound: HashMap<(Protocol, u16), Mapping>,
+    ports: RangeInclusive<u16>,
+    next_port: u16,
+    timeouts: Timeouts,
+}
+
+impl NatTable {
+    /// A table that hands out public ports from the given range.
+    pub fn new(ports: RangeInclusive<u16>, timeouts: Timeouts) -> Self {
+        NatTable {
+            outbound: HashMap::new(),
+            inbound: HashMap::new(),
+            next_port: *ports.start(),
+            ports,
+            timeouts,
+        }
+    }
+
+    /// The public port of an inside endpoint, and whether the mapping is
+    /// new. None if every port of the range is taken.
+    pub fn outbound(&mut self, inside: Endpoint, now: Instant) -> Option<(u16, bool)> {
+        if let Some(&port) = self.outbound.get(&inside) {
+            if let Some(mapping) = self.inbound.get_mut(&(inside.protocol, port)) {
+                mapping.last_used = now;
+            }
+            return Some((port, false));
+        }
+
+        let port = self.free_port(inside.protocol)?;
+        self.outbound.insert(inside, port);
+        self.inbound.insert(
+            (inside.protocol, port),
+            Mapping {
+                inside,
+                last_used: now,
+            },
+        );
+        Some((port, true))
+    }
+
+    /// The inside endpoint a public port is mapped to, if any.
+    pub fn inbound(&mut self, protocol: Protocol, port: u16, now: Instant) -> Option<Endpoint> {
+        let mapping = self.inbound.get_mut(&(protocol, port))?;
+        mapping.last_used = now;
+        Some(mapping.inside)
+    }
+
+    /// Removes the mappings that have timed out and returns them with their
+    /// public ports.
+    pub fn expire(&mut self, now: Instant) -> Vec<(Endpoint, u16)> {
+        let timeouts = self.timeouts;
+        let mut expired = Vec::new();
+        self.inbound.retain(|&(protocol, port), mapping| {
+            let alive = now.duration_since(mapping.last_used) < timeouts.of(protocol);
+            if !alive {
+                expired.push((mapping.inside, port));
+            }
+            alive
+        });
+        for (inside, _) in &expired {
+            self.outbound.remove(inside);
+        }
+        expired
+    }
+
+    /// Number of mappings.
+    pub fn len(&self) -> usize {
+        self.inbound.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inbound.is_empty()
+    }
+
+    /// The next port of the range not in use for the protocol, searching
+    /// from after the previously allocated one so that ports are not reused
+    /// right away.
+    fn free_port(&mut self, protocol: Protocol) -> Option<u16> {
+        let (first, last) = (*self.ports.start(), *self.ports.end());
+        let size = (last - first) as u32 + 1;
+        for _ in 0..size {
+            let port = self.next_port;
+            self.next_port = if port == last { first } else { port + 1 };
+            if !self.inbound.contains_key(&(protocol, port)) {
+                return Some(port);
+            }
+        }
+        None
+    }
+}
@@ -0,0 +1,138 @@
+//! Reading the addresses and ports of IPv4 packets and rewriting them. The
+//! checksums are updated incrementally (RFC 1624) rather than recomputed, so
+//! that the first fragment of a fragmented datagram can be translated too,
+//! without the rest of the transport payload.
+
+use std::net::Ipv4Addr;
+
+use crate::table::Protocol;
+
+const ICMP: u8 = 1;
+const TCP: u8 = 6;
+const UDP: u8 = 17;
+const ECHO_REPLY: u8 = 0;
+const ECHO_REQUEST: u8 = 8;
+
+/// Addresses and ports of a packet. For ICMP echo both ports are the
+/// identifier.
+#[derive(Clone, Copy, Debug)]
+pub struct Flow {
+    pub protocol: Protocol,
+    pub source: Ipv4Addr,
+    pub destination: Ipv4Addr,
+    pub source_port: u16,
+    pub destination_port: u16,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Side {
+    Source,
+    Destination,
+}
+
+fn u16_at(buf: &[u8], at: usize) -> u16 {
+    u16::from_be_bytes([buf[at], buf[at + 1]])
+}
+
+fn set_u16(buf: &mut [u8], at: usize, value: u16) {
+    buf[at..at + 2].copy_from_slice(&value.to_be_bytes());
+}
+
+/// The flow of an IPv4 TCP, UDP or ICMP echo packet. None for other packets,
+/// and for fragments other than the first, which carry no ports. Only echo
+/// requests are accepted with `outbound` and only echo replies without.
+pub fn flow(packet: &[u8], outbound: bool) -> Option<Flow> {
+    if packet.len() < 20 || packet[0] >> 4 != 4 {
+        return None;
+    }
+    let header_len = (packet[0] & 0x0f) as usize * 4;
+    let fragment_offset = u16_at(packet, 6) & 0x1fff;
+    if header_len < 20 || packet.len() < header_len || fragment_offset != 0 {
+        return None;
+    }
+    let source = Ipv4Addr::new(packet[12], packet[13], packet[14], packet[15]);
+    let destination = Ipv4Addr::new(packet[16], packet[17], packet[18], packet[19]);
+    let transport = &packet[header_len..];
+
+    let (protocol, source_port, destination_port) = match packet[9] {
+        TCP if transport.len() >= 20 => (Protocol::Tcp, u16_at(transport, 0), u16_at(transport, 2)),
+        UDP if transport.len() >= 8 => (Protocol::Udp, u16_at(transport, 0), u16_at(transport, 2)),
+        ICMP if transport.len() >= 8 => {
+            let expected = if outbound { ECHO_REQUEST } else { ECHO_REPLY };
+            if transport[0] != expected {
+                return None;
+            }
+            let id = u16_at(transport, 4);
+            (Protocol::Icmp, id, id)
+        }
+        _ => return None,
+    };
+    Some(Flow {
+        protocol,
+        source,
+        destination,
+        source_port,
+        destination_port,
+    })
+}
+
+/// Replaces the source or destination address and port of a packet that
+/// [`flow`] accepted, and updates the checksums.
+pub fn rewrite(packet: &mut [u8], side: Side, address: Ipv4Addr, port: u16) {
+    let header_len = (packet[0] & 0x0f) as usize * 4;
+    let protocol = packet[9];
+    let (address_at, port_at) = match side {
+        Side::Source => (12, 0),
+        Side::Destination => (16, 2),
+    };
+
+    let mut old_address = [0u8; 4];
+    old_address.copy_from_slice(&packet[address_at..address_at + 4]);
+    let new_address = address.octets();
+    packet[address_at..address_at + 4].copy_from_slice(&new_address);
+    let checksum = adjust(u16_at(packet, 10), &old_address, &new_address);
+    set_u16(packet, 10, checksum);
+
+    let transport = &mut packet[header_len..];
+    let new_port = port.to_be_bytes();
+    match protocol {
+        TCP | UDP => {
+            let old_port = u16_at(transport, port_at).to_be_bytes();
+            set_u16(transport, port_at, port);
+            let checksum_at = if protocol == TCP { 16 } else { 6 };
+            let checksum = u16_at(transport, checksum_at);
+            // Zero means that the UDP datagram has no checksum
+            if protocol == UDP && checksum == 0 {
+                return;
+            }
+            // The pseudo header includes the addresses
+            let checksum = adjust(checksum, &old_address, &new_address);
+            let mut checksum = adjust(checksum, &old_port, &new_port);
+            if protocol == UDP && checksum == 0 {
+                checksum = 0xffff;
+            }
+            set_u16(transport, checksum_at, checksum);
+        }
+        ICMP => {
+            let old_id = u16_at(transport, 4).to_be_bytes();
+            set_u16(transport, 4, port);
+            let checksum = adjust(u16_at(transport, 2), &old_id, &new_port);
+            set_u16(transport, 2, checksum);
+        }
+        _ => {}
+    }
+}
+
+/// Updates an Internet checksum for 16-bit words changed from `old` to `new`:
+/// HC' = ~(~HC + ~m + m').
+fn adjust(checksum: u16, old: &[u8], new: &[u8]) -> u16 {
+    let mut sum = (!checksum) as u32;
+    for (old, new) in old.chunks_exact(2).zip(new.chunks_exact(2)) {
+        sum += (!u16::from_be_bytes([old[0], old[1]])) as u32;
+        sum += u16::from_be_bytes([new[0], new[1]]) as u32;
+    }
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
@@ -0,0 +1,206 @@
+//! The client side: sends on every stream for the duration of the test and
+//! prints what it sent next to what the server received.
+
+use std::{
+    error::Error,
+    io::{self, BufReader, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, UdpSocket},
+    thread,
+    time::{Duration, Instant},
+};
+
+use adnet_core::report::Report;
+use tracing::info;
+
+use crate::{
+    protocol::{Control, Datagram, TestSpec, Transport},
+    stats::{self, Shared},
+};
+
+// Time to wait for the server at every step other than the test itself
+const CONTROL_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Runs a test against the server. `bitrate` limits every stream to this
+/// many bits per second.
+pub fn client(
+    server: SocketAddr,
+    spec: TestSpec,
+    bitrate: Option<f64>,
+    report: &mut Report,
+) -> Result<(), Box<dyn Error>> {
+    let control = TcpStream::connect_timeout(&server, CONTROL_TIMEOUT)
+        .map_err(|e| format!("Cannot connect to {}: {}", server, e))?;
+    control.set_read_timeout(Some(CONTROL_TIMEOUT))?;
+    let mut reader = BufReader::new(control.try_clone()?);
+
+    Control::Start(spec.clone()).send(&control)?;
+    let port = match Control::receive(&mut reader)? {
+        Control::Accepted { port } => port,
+        Control::Rejected { reason } => {
+            return Err(format!("Server rejected the test: {}", reason).into())
+        }
+        other => return Err(format!("Unexpected answer from server: {:?}", other).into()),
+    };
+    let data = SocketAddr::new(server.ip(), port);
+    let udp = spec.transport == Transport::Udp;
+    info!(
+        "Testing {:?} to {} with {} stream(s) for {} ms",
+        spec.transport, data, spec.streams, spec.duration_ms
+    );
+
+    let stats = stats::shared(spec.streams);
+    let start = Instant::now();
+    let duration = Duration::from_millis(spec.duration_ms);
+    let senders: Vec<_> = (0..spec.streams)
+        .map(|id| {
+            let stats = stats.clone();
+            let transport = spec.transport;
+            let length = spec.length;
+            thread::spawn(move || {
+                let stream = Stream {
+                    id,
+                    data,
+                    length,
+                    start,
+                    duration,
+                    bitrate,
+                    stats,
+                };
+                match transport {
+                    Transport::Tcp => stream.send_tcp(),
+                    Transport::Udp => stream.send_udp(),
+                }
+            })
+        })
+        .collect();
+
+    stats::print_header(udp);
+    let interval = Duration::from_millis(spec.interval_ms);
+    let reporter = match interval.is_zero() {
+        true => None,
+        // The sender does not see loss, only the server does
+        false => Some(stats::report_intervals(
+            stats.clone(),
+            start,
+            interval,
+            false,
+        )),
+    };
+    let mut result = Ok(());
+    for sender in senders {
+        let sent = sender.join().expect("sender thread panicked");
+        result = result.and(sent);
+    }
+    let seconds = start.elapsed().as_secs_f64();
+    if let Some((stop_reports, handle)) = reporter {
+        drop(stop_reports);
+        let _ = handle.join();
+    }
+    result?;
+
+    Control::Done.send(&control)?;
+    let (received_seconds, received) = match Control::receive(&mut reader)? {
+        Control::Results { seconds, streams } => (seconds, streams),
+        other => return Err(format!("Unexpected answer from server: {:?}", other).into()),
+    };
+
+    let sent = stats.lock().unwrap().clone();
+    println!("- - - - - - - - - - - - - - - - - - - - - - - - -");
+    stats::print_lines(
+        &sent,
+        Duration::ZERO,
+        Duration::from_secs_f64(seconds),
+        false,
+        "sender",
+    );
+    stats::print_lines(
+        &received,
+        Duration::ZERO,
+        Duration::from_secs_f64(received_seconds),
+        udp,
+        "receiver",
+    );
+
+    report.bytes = sent.iter().map(|stream| stream.bytes).sum();
+    report.detail("server", server);
+    report.detail("transport", format!("{:?}", spec.transport));
+    report.detail("seconds", seconds);
+    report.detail("sent", &sent);
+    report.detail("received_seconds", received_seconds);
+    report.detail("received", &received);
+    Ok(())
+}
+
+/// One stream of the test, run in its own thread.
+struct Stream {
+    id: u32,
+    data: SocketAddr,
+    length: usize,
+    start: Instant,
+    duration: Duration,
+    bitrate: Option<f64>,
+    stats: Shared,
+}
+
+impl Stream {
+    fn send_tcp(&self) -> io::Result<()> {
+        let mut socket = TcpStream::connect_timeout(&self.data, CONTROL_TIMEOUT)?;
+        let buf = vec![0u8; self.length];
+        let mut sent = 0;
+        while self.pace(sent) {
+            socket.write_all(&buf)?;
+            sent += buf.len() as u64;
+            self.stats.lock().unwrap()[self.id as usize].record(buf.len());
+        }
+        // Closing tells the server that the stream is complete
+        socket.shutdown(std::net::Shutdown::Write)
+    }
+
+    fn send_udp(&self) -> io::Result<()> {
+        let any: IpAddr = match self.data {
+            SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+            SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+        };
+        let socket = UdpSocket::bind(SocketAddr::new(any, 0))?;
+        socket.connect(self.data)?;
+
+        let mut buf = vec![0u8; self.length];
+        let mut sent = 0;
+        let mut seq = 0;
+        while self.pace(sent) {
+            let datagram = Datagram {
+                stream: self.id,
+                seq,
+                sent_us: self.start.elapsed().as_micros() as u64,
+            };
+            datagram.write(&mut buf);
+            match socket.send(&buf) {
+                Ok(_) => {}
+                // An ICMP error for an earlier datagram, or a full send
+                // buffer: the datagram is lost like any other
+                Err(e)
+                    if e.kind() == io::ErrorKind::ConnectionRefused
+                        || e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+            seq += 1;
+            sent += buf.len() as u64;
+            self.stats.lock().unwrap()[self.id as usize].record(buf.len());
+        }
+        Ok(())
+    }
+
+    /// Waits until the next write is due under the bitrate limit. Returns
+    /// false when the test is over.
+    fn pace(&self, sent: u64) -> bool {
+        let end = self.start + self.duration;
+        if let Some(bitrate) = self.bitrate {
+            let due = self.start + Duration::from_secs_f64(sent as f64 * 8.0 / bitrate);
+            let now = Instant::now();
+            if due > now {
+                thread::sleep(due.min(end) - now);
+            }
+        }
+        Instant::now() < end
+    }
+}
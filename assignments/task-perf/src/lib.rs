@@ -0,0 +1,191 @@
+//! Throughput measurement in the style of iperf. The client sends over one
+//! or more parallel TCP or UDP streams for a given time, and both ends print
+//! the throughput of every interval; for UDP the server also counts lost and
+//! reordered datagrams and the jitter. At the end the client prints what it
+//! sent next to what the server received.
+//!
+//! The task-perf binary and the `adnet perf` subcommand are thin wrappers
+//! around [`run`].
+
+pub mod client;
+pub mod protocol;
+pub mod server;
+pub mod stats;
+
+use std::{
+    error::Error,
+    io,
+    net::{SocketAddr, ToSocketAddrs},
+    time::Duration,
+};
+
+use adnet_core::{
+    config::{self, ConfigArgs},
+    logging::{self, LogArgs},
+    report::{Report, ReportArgs},
+    shutdown,
+};
+use clap::{Parser, Subcommand};
+use protocol::{TestSpec, Transport, DEFAULT_PORT};
+use serde::Deserialize;
+
+const DEFAULT_STREAMS: u32 = 1;
+const DEFAULT_TIME: Duration = Duration::from_secs(10);
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(1);
+const DEFAULT_TCP_LENGTH: usize = 128 * 1024;
+// Fits in a 1500-byte packet with the IP and UDP headers
+const DEFAULT_UDP_LENGTH: usize = 1400;
+const DEFAULT_UDP_BITRATE: f64 = 1.0;
+
+/// Measures TCP and UDP throughput between a client and a server.
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+pub struct Args {
+    #[command(subcommand)]
+    mode: Mode,
+
+    #[command(flatten)]
+    config: ConfigArgs,
+
+    #[command(flatten)]
+    log: LogArgs,
+
+    #[command(flatten)]
+    report: ReportArgs,
+}
+
+#[derive(Subcommand, Debug)]
+enum Mode {
+    /// Receive the tests of clients, one at a time
+    Server {
+        /// Address to listen at [default: 0.0.0.0:5201]
+        #[arg(short, long)]
+        listen: Option<SocketAddr>,
+    },
+
+    /// Run a test against a server
+    Client {
+        /// Address of the server as host:port [default port: 5201]
+        #[arg(short, long)]
+        server: Option<String>,
+
+        /// Test UDP instead of TCP
+        #[arg(short, long)]
+        udp: bool,
+
+        /// Number of parallel streams [default: 1]
+        #[arg(short = 'P', long)]
+        streams: Option<u32>,
+
+        /// Seconds to send for [default: 10]
+        #[arg(short, long, value_parser = config::parse_secs)]
+        time: Option<Duration>,
+
+        /// Seconds between reports, 0 for none [default: 1]
+        #[arg(short, long, value_parser = config::parse_secs)]
+        interval: Option<Duration>,
+
+        /// Bytes in each write or datagram [default: 131072 for TCP, 1400 for UDP]
+        #[arg(short, long)]
+        length: Option<usize>,
+
+        /// Mbit/s per stream, 0 for unlimited [default: 1 for UDP, unlimited for TCP]
+        #[arg(short, long)]
+        bitrate: Option<f64>,
+    },
+}
+
+/// The [task-perf] section of the config file. Each mode uses its own options.
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct FileConfig {
+    listen: Option<SocketAddr>,
+    server: Option<String>,
+    udp: bool,
+    streams: Option<u32>,
+    #[serde(deserialize_with = "config::secs")]
+    time: Option<Duration>,
+    #[serde(deserialize_with = "config::secs")]
+    interval: Option<Duration>,
+    length: Option<usize>,
+    bitrate: Option<f64>,
+}
+
+/// Resolves the server given as host or host:port.
+fn resolve(server: &str) -> io::Result<SocketAddr> {
+    let with_port = match server.rsplit_once(':') {
+        Some((_, port)) if port.parse::<u16>().is_ok() && !server.ends_with(']') => {
+            server.to_string()
+        }
+        _ => format!("{}:{}", server, DEFAULT_PORT),
+    };
+    with_port.to_socket_addrs()?.next().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} did not resolve to any address", server),
+        )
+    })
+}
+
+/// Runs the server or client with the given arguments, as the task-perf binary does.
+pub fn run(args: Args) -> Result<(), Box<dyn Error>> {
+    logging::init(&args.log)?;
+    let report = args.report.clone();
+    report.run("task-perf", |report| measure(args, report))
+}
+
+fn measure(args: Args, report: &mut Report) -> Result<(), Box<dyn Error>> {
+    // Command line options take precedence over the config file
+    let file: FileConfig = args.config.section("task-perf")?;
+    match args.mode {
+        Mode::Server { listen } => {
+            let listen = listen
+                .or(file.listen)
+                .unwrap_or_else(|| SocketAddr::from(([0, 0, 0, 0], DEFAULT_PORT)));
+            report.detail("mode", "server");
+            server::serve(listen, &shutdown::install()?, report)
+        }
+        Mode::Client {
+            server,
+            udp,
+            streams,
+            time,
+            interval,
+            length,
+            bitrate,
+        } => {
+            let server = server
+                .or(file.server)
+                .ok_or("Server is required (--server)")?;
+            let transport = match udp || file.udp {
+                true => Transport::Udp,
+                false => Transport::Tcp,
+            };
+            let length = length.or(file.length).unwrap_or(match transport {
+                Transport::Tcp => DEFAULT_TCP_LENGTH,
+                Transport::Udp => DEFAULT_UDP_LENGTH,
+            });
+            let bitrate = match (bitrate.or(file.bitrate), transport) {
+                (Some(mbits), _) if mbits < 0.0 || !mbits.is_finite() => {
+                    return Err("Bitrate must be a positive number".into())
+                }
+                (Some(0.0), _) => None,
+                (Some(mbits), _) => Some(mbits * 1e6),
+                (None, Transport::Udp) => Some(DEFAULT_UDP_BITRATE * 1e6),
+                (None, Transport::Tcp) => None,
+            };
+            let spec = TestSpec {
+                transport,
+                streams: streams.or(file.streams).unwrap_or(DEFAULT_STREAMS),
+                duration_ms: time.or(file.time).unwrap_or(DEFAULT_TIME).as_millis() as u64,
+                interval_ms: interval
+                    .or(file.interval)
+                    .unwrap_or(DEFAULT_INTERVAL)
+                    .as_millis() as u64,
+                length,
+            };
+            report.detail("mode", "client");
+            client::client(resolve(&server)?, spec, bitrate, report)
+        }
+    }
+}
@@ -0,0 +1,8 @@
+use adnet_core::exit;
+use clap::Parser;
+use std::process::ExitCode;
+use task_perf::Args;
+
+fn main() -> ExitCode {
+    exit::exit(task_perf::run(Args::parse()))
+}
@@ -0,0 +1,101 @@
+//! The control connection and the UDP test datagrams.
+//!
+//! The client opens a TCP control connection to the server and sends
+//! [`Control::Start`] with the test parameters. The server opens a data port
+//! and answers with [`Control::Accepted`], after which the client connects its
+//! streams to the data port and sends for the duration of the test. It then
+//! sends [`Control::Done`], and the server answers with what it received in
+//! [`Control::Results`]. Every message is one line of JSON.
+
+use std::io::{self, BufRead, Write};
+
+use serde::{Deserialize, Serialize};
+
+use crate::stats::StreamStats;
+
+pub const DEFAULT_PORT: u16 = 5201;
+
+/// Stream id, sequence number and send time in microseconds since the start
+/// of the test, in network byte order.
+pub const UDP_HEADER: usize = 4 + 8 + 8;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Transport {
+    Tcp,
+    Udp,
+}
+
+/// Parameters of a test, chosen by the client.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TestSpec {
+    pub transport: Transport,
+    pub streams: u32,
+    pub duration_ms: u64,
+    pub interval_ms: u64,
+    /// Bytes in each write or datagram
+    pub length: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Control {
+    Start(TestSpec),
+    Accepted {
+        port: u16,
+    },
+    Rejected {
+        reason: String,
+    },
+    Done,
+    Results {
+        seconds: f64,
+        streams: Vec<StreamStats>,
+    },
+}
+
+impl Control {
+    pub fn send(&self, mut writer: impl Write) -> io::Result<()> {
+        let mut line = serde_json::to_vec(self)?;
+        line.push(b'\n');
+        writer.write_all(&line)
+    }
+
+    pub fn receive(reader: &mut impl BufRead) -> io::Result<Control> {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "Control connection closed",
+            ));
+        }
+        Ok(serde_json::from_str(&line)?)
+    }
+}
+
+/// Header of a UDP test datagram.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Datagram {
+    pub stream: u32,
+    pub seq: u64,
+    pub sent_us: u64,
+}
+
+impl Datagram {
+    pub fn write(&self, buf: &mut [u8]) {
+        buf[..4].copy_from_slice(&self.stream.to_be_bytes());
+        buf[4..12].copy_from_slice(&self.seq.to_be_bytes());
+        buf[12..20].copy_from_slice(&self.sent_us.to_be_bytes());
+    }
+
+    pub fn read(buf: &[u8]) -> Option<Datagram> {
+        if buf.len() < UDP_HEADER {
+            return None;
+        }
+        Some(Datagram {
+            stream: u32::from_be_bytes(buf[..4].try_into().ok()?),
+            seq: u64::from_be_bytes(buf[4..12].try_into().ok()?),
+            sent_us: u64::from_be_bytes(buf[12..20].try_into().ok()?),
+        })
+    }
+}
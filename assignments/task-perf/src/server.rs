@@ -0,0 +1,269 @@
+//! The server side: runs one test at a time, counting what the streams of
+//! the client send, and reports the results back over the control connection.
+
+use std::{
+    error::Error,
+    io::{self, BufReader, Read},
+    net::{SocketAddr, TcpListener, TcpStream, UdpSocket},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use adnet_core::{report::Report, shutdown::Shutdown};
+use tracing::{debug, info, warn};
+
+use crate::{
+    protocol::{Control, Datagram, TestSpec, Transport, UDP_HEADER},
+    stats::{self, Shared},
+};
+
+// Time to wait for the client at every step other than the test itself
+const CONTROL_TIMEOUT: Duration = Duration::from_secs(10);
+// Time to wait for the last UDP datagrams after the client is done
+const UDP_GRACE: Duration = Duration::from_millis(250);
+const MAX_STREAMS: u32 = 128;
+const MAX_DURATION_MS: u64 = 3600 * 1000;
+const MAX_TCP_LENGTH: usize = 1 << 20;
+const MAX_UDP_LENGTH: usize = 65507;
+// Longest the server waits for a client before checking for Ctrl-C
+const SHUTDOWN_POLL: Duration = Duration::from_millis(200);
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Accepts control connections and runs their tests one after another,
+/// until the shutdown is requested. The test running at that point is
+/// finished first.
+pub fn serve(
+    listen: SocketAddr,
+    shutdown: &Shutdown,
+    report: &mut Report,
+) -> Result<(), Box<dyn Error>> {
+    let listener = TcpListener::bind(listen)?;
+    listener.set_nonblocking(true)?;
+    info!("Task-perf server listening on {}", listener.local_addr()?);
+
+    let mut tests = 0u64;
+    while !shutdown.is_requested() {
+        let control = match listener.accept() {
+            Ok((control, _)) => control,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                shutdown.wait_timeout(SHUTDOWN_POLL);
+                continue;
+            }
+            Err(e) => {
+                warn!("Accepting a control connection failed: {}", e);
+                continue;
+            }
+        };
+        let peer = control
+            .peer_addr()
+            .map_or("unknown client".to_string(), |a| a.to_string());
+        // The connection need not inherit the mode of the listener
+        if let Err(e) = control.set_nonblocking(false) {
+            warn!("Setting up the connection of {} failed: {}", peer, e);
+            continue;
+        }
+        match run_test(control, &peer) {
+            Ok(bytes) => {
+                tests += 1;
+                report.bytes += bytes;
+            }
+            Err(e) => {
+                warn!("Test with {} failed: {}", peer, e);
+                report.errors += 1;
+            }
+        }
+    }
+
+    info!("Ran {} tests", tests);
+    report.detail("tests", tests);
+    Ok(())
+}
+
+/// The reason to reject a test, if any.
+fn check(spec: &TestSpec) -> Result<(), String> {
+    if spec.streams == 0 || spec.streams > MAX_STREAMS {
+        return Err(format!("Streams must be between 1 and {}", MAX_STREAMS));
+    }
+    if spec.duration_ms == 0 || spec.duration_ms > MAX_DURATION_MS {
+        return Err("Duration must be between 1 ms and an hour".to_string());
+    }
+    let (min, max) = match spec.transport {
+        Transport::Tcp => (1, MAX_TCP_LENGTH),
+        Transport::Udp => (UDP_HEADER, MAX_UDP_LENGTH),
+    };
+    if spec.length < min || spec.length > max {
+        return Err(format!("Length must be between {} and {}", min, max));
+    }
+    Ok(())
+}
+
+/// Runs the test the client asks for, and returns the bytes received.
+fn run_test(control: TcpStream, peer: &str) -> io::Result<u64> {
+    control.set_read_timeout(Some(CONTROL_TIMEOUT))?;
+    let mut reader = BufReader::new(control.try_clone()?);
+    let spec = match Control::receive(&mut reader)? {
+        Control::Start(spec) => spec,
+        other => return Err(invalid(format!("Expected start, got {:?}", other))),
+    };
+    if let Err(reason) = check(&spec) {
+        warn!("Rejecting test from {}: {}", peer, reason);
+        return Control::Rejected { reason }.send(&control).map(|_| 0);
+    }
+    info!("Starting test from {}: {:?}", peer, spec);
+
+    // The data port is on the same address as the control connection
+    let ip = control.local_addr()?.ip();
+    let stats = stats::shared(spec.streams);
+    let stop = Arc::new(AtomicBool::new(false));
+    let (receivers, start) = match spec.transport {
+        Transport::Tcp => {
+            let data = TcpListener::bind((ip, 0))?;
+            let port = data.local_addr()?.port();
+            Control::Accepted { port }.send(&control)?;
+            let start = Instant::now();
+            (accept_streams(data, spec.streams, &stats)?, start)
+        }
+        Transport::Udp => {
+            let socket = UdpSocket::bind((ip, 0))?;
+            let port = socket.local_addr()?.port();
+            Control::Accepted { port }.send(&control)?;
+            let start = Instant::now();
+            let receiver = receive_datagrams(socket, start, stats.clone(), stop.clone())?;
+            (vec![receiver], start)
+        }
+    };
+
+    let udp = spec.transport == Transport::Udp;
+    stats::print_header(udp);
+    let interval = Duration::from_millis(spec.interval_ms);
+    let reporter = match interval.is_zero() {
+        true => None,
+        false => Some(stats::report_intervals(stats.clone(), start, interval, udp)),
+    };
+
+    let test_timeout = Duration::from_millis(spec.duration_ms) + CONTROL_TIMEOUT;
+    control.set_read_timeout(Some(test_timeout))?;
+    let done = Control::receive(&mut reader);
+    let seconds = start.elapsed().as_secs_f64();
+    if udp {
+        thread::sleep(UDP_GRACE);
+    }
+    stop.store(true, Ordering::Relaxed);
+    for receiver in receivers {
+        let _ = receiver.join();
+    }
+    if let Some((stop_reports, handle)) = reporter {
+        drop(stop_reports);
+        let _ = handle.join();
+    }
+    match done? {
+        Control::Done => {}
+        other => return Err(invalid(format!("Expected done, got {:?}", other))),
+    }
+
+    let streams = stats.lock().unwrap().clone();
+    stats::print_lines(
+        &streams,
+        Duration::ZERO,
+        Duration::from_secs_f64(seconds),
+        udp,
+        "receiver",
+    );
+    info!("Test from {} finished", peer);
+    let bytes = streams.iter().map(|stream| stream.bytes).sum();
+    Control::Results { seconds, streams }.send(&control)?;
+    Ok(bytes)
+}
+
+/// Accepts a connection for every stream, and counts what arrives on each
+/// in its own thread.
+fn accept_streams(
+    listener: TcpListener,
+    streams: u32,
+    stats: &Shared,
+) -> io::Result<Vec<JoinHandle<()>>> {
+    // Do not wait forever for a client that never connects
+    listener.set_nonblocking(true)?;
+    let deadline = Instant::now() + CONTROL_TIMEOUT;
+
+    let mut handles = Vec::new();
+    for id in 0..streams as usize {
+        let mut stream = loop {
+            match listener.accept() {
+                Ok((stream, _)) => break stream,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    if Instant::now() >= deadline {
+                        return Err(io::Error::new(
+                            io::ErrorKind::TimedOut,
+                            "Client did not connect its streams",
+                        ));
+                    }
+                    thread::sleep(Duration::from_millis(10));
+                }
+                Err(e) => return Err(e),
+            }
+        };
+        stream.set_nonblocking(false)?;
+        stream.set_read_timeout(Some(CONTROL_TIMEOUT))?;
+
+        let stats = stats.clone();
+        handles.push(thread::spawn(move || {
+            let mut buf = vec![0u8; MAX_TCP_LENGTH];
+            loop {
+                match stream.read(&mut buf) {
+                    Ok(0) => return,
+                    Ok(n) => stats.lock().unwrap()[id].record(n),
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                    Err(e) => {
+                        debug!("Stream {} ended: {}", id + 1, e);
+                        return;
+                    }
+                }
+            }
+        }));
+    }
+    Ok(handles)
+}
+
+/// Counts the datagrams of all streams on one socket until `stop` is set.
+fn receive_datagrams(
+    socket: UdpSocket,
+    start: Instant,
+    stats: Shared,
+    stop: Arc<AtomicBool>,
+) -> io::Result<JoinHandle<()>> {
+    // Wake up regularly to check whether to stop
+    socket.set_read_timeout(Some(Duration::from_millis(100)))?;
+    Ok(thread::spawn(move || {
+        let mut buf = vec![0u8; MAX_UDP_LENGTH];
+        while !stop.load(Ordering::Relaxed) {
+            let n = match socket.recv(&mut buf) {
+                Ok(n) => n,
+                Err(e)
+                    if e.kind() == io::ErrorKind::WouldBlock
+                        || e.kind() == io::ErrorKind::TimedOut =>
+                {
+                    continue;
+                }
+                Err(e) => {
+                    warn!("Receiving datagrams failed: {}", e);
+                    return;
+                }
+            };
+            let arrival_us = start.elapsed().as_micros() as u64;
+            let Some(datagram) = Datagram::read(&buf[..n]) else {
+                continue;
+            };
+            if let Some(stream) = stats.lock().unwrap().get_mut(datagram.stream as usize) {
+                stream.record_datagram(n, datagram.seq, datagram.sent_us, arrival_us);
+            }
+        }
+    }))
+}
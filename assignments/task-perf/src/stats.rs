@@ -0,0 +1,194 @@
+//! Counting what the streams transfer and printing it in the style of iperf,
+//! per interval and for the whole test.
+
+use std::{
+    sync::{mpsc, Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+/// What one stream has sent or received. Loss and jitter are only counted
+/// for UDP, by the receiver.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct StreamStats {
+    pub bytes: u64,
+    pub packets: u64,
+    /// Datagrams the sender had sent up to the highest sequence number seen
+    pub expected: u64,
+    pub out_of_order: u64,
+    /// Interarrival jitter of RFC 3550, in milliseconds
+    pub jitter_ms: f64,
+    #[serde(skip)]
+    last_transit_ms: Option<f64>,
+}
+
+impl StreamStats {
+    pub fn record(&mut self, bytes: usize) {
+        self.bytes += bytes as u64;
+        self.packets += 1;
+    }
+
+    /// Counts a UDP datagram with the send and arrival times in microseconds
+    /// since the start of the test at the sender and the receiver.
+    pub fn record_datagram(&mut self, bytes: usize, seq: u64, sent_us: u64, arrival_us: u64) {
+        self.record(bytes);
+        if seq < self.expected {
+            self.out_of_order += 1;
+        } else {
+            self.expected = seq + 1;
+        }
+
+        // The clocks need not be synchronized, only the changes in transit
+        // time matter
+        let transit = (arrival_us as f64 - sent_us as f64) / 1000.0;
+        if let Some(last) = self.last_transit_ms {
+            let d = (transit - last).abs();
+            self.jitter_ms += (d - self.jitter_ms) / 16.0;
+        }
+        self.last_transit_ms = Some(transit);
+    }
+
+    pub fn lost(&self) -> u64 {
+        self.expected.saturating_sub(self.packets)
+    }
+}
+
+/// Stats of every stream of a test, shared with the threads that count.
+pub type Shared = Arc<Mutex<Vec<StreamStats>>>;
+
+pub fn shared(streams: u32) -> Shared {
+    Arc::new(Mutex::new(vec![StreamStats::default(); streams as usize]))
+}
+
+/// Prints the header of the report table.
+pub fn print_header(udp: bool) {
+    match udp {
+        true => println!(
+            "[ ID] Interval           Transfer     Bitrate            Jitter    Lost/Total Datagrams"
+        ),
+        false => println!("[ ID] Interval           Transfer     Bitrate"),
+    }
+}
+
+/// Prints one line for every stream and a sum line if there are several.
+/// `loss` adds the jitter and loss columns.
+pub fn print_lines(streams: &[StreamStats], from: Duration, to: Duration, loss: bool, label: &str) {
+    let seconds = (to - from).as_secs_f64();
+    let line = |id: &str, stats: &StreamStats| {
+        let mut line = format!(
+            "[{:>3}] {:6.2}-{:<6.2} sec  {}  {}",
+            id,
+            from.as_secs_f64(),
+            to.as_secs_f64(),
+            format_bytes(stats.bytes),
+            format_rate(stats.bytes, seconds)
+        );
+        if loss {
+            let percent = match stats.expected {
+                0 => 0.0,
+                expected => stats.lost() as f64 * 100.0 / expected as f64,
+            };
+            line += &format!(
+                "  {:6.3} ms  {}/{} ({:.2}%)",
+                stats.jitter_ms,
+                stats.lost(),
+                stats.expected,
+                percent
+            );
+        }
+        if !label.is_empty() {
+            line += "  ";
+            line += label;
+        }
+        println!("{}", line);
+    };
+
+    for (id, stats) in streams.iter().enumerate() {
+        line(&(id + 1).to_string(), stats);
+    }
+    if streams.len() > 1 {
+        line("SUM", &sum(streams));
+    }
+}
+
+/// Totals over all streams, with the mean jitter.
+pub fn sum(streams: &[StreamStats]) -> StreamStats {
+    let mut total = StreamStats::default();
+    for stats in streams {
+        total.bytes += stats.bytes;
+        total.packets += stats.packets;
+        total.expected += stats.expected;
+        total.out_of_order += stats.out_of_order;
+        total.jitter_ms += stats.jitter_ms / streams.len() as f64;
+    }
+    total
+}
+
+/// The change of the counters since `previous`, with the current jitter.
+fn delta(current: &StreamStats, previous: &StreamStats) -> StreamStats {
+    StreamStats {
+        bytes: current.bytes - previous.bytes,
+        packets: current.packets - previous.packets,
+        expected: current.expected - previous.expected,
+        out_of_order: current.out_of_order - previous.out_of_order,
+        jitter_ms: current.jitter_ms,
+        last_transit_ms: None,
+    }
+}
+
+/// Prints interval reports from a thread until the returned sender is
+/// dropped.
+pub fn report_intervals(
+    stats: Shared,
+    start: Instant,
+    interval: Duration,
+    loss: bool,
+) -> (mpsc::Sender<()>, thread::JoinHandle<()>) {
+    let (stop, stopped) = mpsc::channel::<()>();
+    let handle = thread::spawn(move || {
+        let mut previous = stats.lock().unwrap().clone();
+        let mut from = Duration::ZERO;
+        loop {
+            let next = from + interval;
+            let wait = next.saturating_sub(start.elapsed());
+            if stopped.recv_timeout(wait) != Err(mpsc::RecvTimeoutError::Timeout) {
+                return;
+            }
+            let current = stats.lock().unwrap().clone();
+            let deltas: Vec<_> = current
+                .iter()
+                .zip(&previous)
+                .map(|(current, previous)| delta(current, previous))
+                .collect();
+            print_lines(&deltas, from, next, loss, "");
+            previous = current;
+            from = next;
+        }
+    });
+    (stop, handle)
+}
+
+pub fn format_bytes(bytes: u64) -> String {
+    let bytes = bytes as f64;
+    if bytes >= 1024.0 * 1024.0 * 1024.0 {
+        format!("{:6.2} GBytes", bytes / (1024.0 * 1024.0 * 1024.0))
+    } else if bytes >= 1024.0 * 1024.0 {
+        format!("{:6.2} MBytes", bytes / (1024.0 * 1024.0))
+    } else {
+        format!("{:6.2} KBytes", bytes / 1024.0)
+    }
+}
+
+pub fn format_rate(bytes: u64, seconds: f64) -> String {
+    let bits = match seconds {
+        s if s > 0.0 => bytes as f64 * 8.0 / s,
+        _ => 0.0,
+    };
+    if bits >= 1e9 {
+        format!("{:7.2} Gbits/sec", bits / 1e9)
+    } else {
+        format!("{:7.2} Mbits/sec", bits / 1e6)
+    }
+}
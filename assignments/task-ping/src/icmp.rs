@@ -0,0 +1,251 @@
+//! ICMP messages and the sockets to send and receive them. task-trace uses
+//! this module as well.
+//!
+//! Linux lets unprivileged users open ICMP datagram sockets when their group
+//! is in `net.ipv4.ping_group_range`. The kernel then fills in the echo
+//! identifier and delivers only the replies to our own requests, but no
+//! error messages. Raw sockets need root or CAP_NET_RAW and receive every
+//! ICMP message of the host, so the caller has to pick out its own.
+
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    time::Duration,
+};
+
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
+
+/// Type, code, checksum, identifier and sequence number
+pub const ECHO_HEADER: usize = 8;
+
+const IPV6_HEADER: usize = 40;
+
+const V4_ECHO_REPLY: u8 = 0;
+const V4_UNREACHABLE: u8 = 3;
+const V4_ECHO_REQUEST: u8 = 8;
+const V4_TIME_EXCEEDED: u8 = 11;
+const V6_UNREACHABLE: u8 = 1;
+const V6_TIME_EXCEEDED: u8 = 3;
+const V6_ECHO_REQUEST: u8 = 128;
+const V6_ECHO_REPLY: u8 = 129;
+
+/// ICMP socket for either IPv4 or IPv6.
+pub struct IcmpSocket {
+    socket: UdpSocket,
+    ipv6: bool,
+    raw: bool,
+}
+
+impl IcmpSocket {
+    /// Opens an unprivileged datagram socket, or a raw socket if that is not
+    /// allowed, for sending to addresses of the same family as `ip`.
+    pub fn new(ip: IpAddr) -> io::Result<Self> {
+        Self::open(ip, Type::DGRAM).or_else(|_| Self::raw(ip))
+    }
+
+    /// Opens a raw socket, which also receives ICMP error messages.
+    pub fn raw(ip: IpAddr) -> io::Result<Self> {
+        Self::open(ip, Type::RAW).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!(
+                    "Cannot open raw ICMP socket (needs root or CAP_NET_RAW): {}",
+                    e
+                ),
+            )
+        })
+    }
+
+    fn open(ip: IpAddr, kind: Type) -> io::Result<Self> {
+        let (domain, protocol) = match ip {
+            IpAddr::V4(_) => (Domain::IPV4, Protocol::ICMPV4),
+            IpAddr::V6(_) => (Domain::IPV6, Protocol::ICMPV6),
+        };
+        let socket = Socket::new(domain, kind, Some(protocol))?;
+        // The std socket gives us send_to and recv_from with plain buffers;
+        // for ICMP sockets the port of the address is ignored
+        Ok(IcmpSocket {
+            socket: UdpSocket::from(socket),
+            ipv6: ip.is_ipv6(),
+            raw: kind == Type::RAW,
+        })
+    }
+
+    pub fn is_ipv6(&self) -> bool {
+        self.ipv6
+    }
+
+    /// Whether this is a raw socket, which sees the ICMP messages of others too.
+    pub fn is_raw(&self) -> bool {
+        self.raw
+    }
+
+    /// Sets the time to live, or the hop limit for IPv6, of sent packets.
+    pub fn set_ttl(&self, ttl: u32) -> io::Result<()> {
+        match self.ipv6 {
+            true => SockRef::from(&self.socket).set_unicast_hops_v6(ttl),
+            false => self.socket.set_ttl(ttl),
+        }
+    }
+
+    pub fn send_to(&self, message: &[u8], ip: IpAddr) -> io::Result<()> {
+        self.socket.send_to(message, SocketAddr::new(ip, 0))?;
+        Ok(())
+    }
+
+    /// Waits up to `timeout` for a message. Returns the ICMP message without
+    /// the IP header and the address it came from, or None on timeout.
+    pub fn recv_from<'a>(
+        &self,
+        buf: &'a mut [u8],
+        timeout: Duration,
+    ) -> io::Result<Option<(&'a [u8], IpAddr)>> {
+        // A zero timeout would mean blocking forever
+        self.socket
+            .set_read_timeout(Some(timeout.max(Duration::from_millis(1))))?;
+        let (n, from) = match self.socket.recv_from(buf) {
+            Ok(received) => received,
+            Err(e)
+                if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut =>
+            {
+                return Ok(None);
+            }
+            Err(e) => return Err(e),
+        };
+        // Raw IPv4 sockets deliver the IP header as well, IPv6 ones never do
+        let start = match self.raw && !self.ipv6 {
+            true => ipv4_header_len(&buf[..n]).unwrap_or(n),
+            false => 0,
+        };
+        Ok(Some((&buf[start..n], from.ip())))
+    }
+}
+
+/// Builds an echo request with `size` bytes of payload.
+pub fn echo_request(ipv6: bool, id: u16, seq: u16, size: usize) -> Vec<u8> {
+    let kind = match ipv6 {
+        true => V6_ECHO_REQUEST,
+        false => V4_ECHO_REQUEST,
+    };
+    let mut message = Vec::with_capacity(ECHO_HEADER + size);
+    message.extend_from_slice(&[kind, 0, 0, 0]);
+    message.extend_from_slice(&id.to_be_bytes());
+    message.extend_from_slice(&seq.to_be_bytes());
+    message.extend((0..size).map(|i| i as u8));
+
+    // The kernel computes the ICMPv6 checksum, as it covers the IPv6 addresses
+    if !ipv6 {
+        let sum = checksum(&message);
+        message[2..4].copy_from_slice(&sum.to_be_bytes());
+    }
+    message
+}
+
+/// The Internet checksum of RFC 1071.
+pub fn checksum(data: &[u8]) -> u16 {
+    let mut sum: u32 = data
+        .chunks(2)
+        .map(|pair| match pair {
+            [a, b] => u16::from_be_bytes([*a, *b]) as u32,
+            [a] => u16::from_be_bytes([*a, 0]) as u32,
+            _ => unreachable!(),
+        })
+        .sum();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// The ICMP messages we are interested in.
+#[derive(Debug)]
+pub enum Message<'a> {
+    EchoReply {
+        id: u16,
+        seq: u16,
+        payload: &'a [u8],
+    },
+    /// A router dropped the packet because its time to live ran out.
+    /// `original` is the start of the dropped IP packet.
+    TimeExceeded {
+        original: &'a [u8],
+    },
+    /// The destination or a router could not deliver the packet
+    Unreachable {
+        code: u8,
+        original: &'a [u8],
+    },
+    Other {
+        kind: u8,
+        code: u8,
+    },
+}
+
+impl<'a> Message<'a> {
+    /// Parses a message as returned by [`IcmpSocket::recv_from`].
+    pub fn parse(data: &'a [u8], ipv6: bool) -> Option<Self> {
+        if data.len() < ECHO_HEADER {
+            return None;
+        }
+        let (kind, code) = (data[0], data[1]);
+        let rest = &data[ECHO_HEADER..];
+        let message = match (ipv6, kind) {
+            (false, V4_ECHO_REPLY) | (true, V6_ECHO_REPLY) => Message::EchoReply {
+                id: u16::from_be_bytes([data[4], data[5]]),
+                seq: u16::from_be_bytes([data[6], data[7]]),
+                payload: rest,
+            },
+            (false, V4_TIME_EXCEEDED) | (true, V6_TIME_EXCEEDED) => {
+                Message::TimeExceeded { original: rest }
+            }
+            (false, V4_UNREACHABLE) | (true, V6_UNREACHABLE) => Message::Unreachable {
+                code,
+                original: rest,
+            },
+            _ => Message::Other { kind, code },
+        };
+        Some(message)
+    }
+}
+
+/// Splits the start of an IP packet quoted in an ICMP error message into the
+/// destination address, protocol and the transport header that follows.
+pub fn quoted_packet(original: &[u8], ipv6: bool) -> Option<(IpAddr, u8, &[u8])> {
+    if ipv6 {
+        if original.len() < IPV6_HEADER {
+            return None;
+        }
+        let dst: [u8; 16] = original[24..40].try_into().unwrap();
+        Some((
+            Ipv6Addr::from(dst).into(),
+            original[6],
+            &original[IPV6_HEADER..],
+        ))
+    } else {
+        let len = ipv4_header_len(original)?;
+        let dst: [u8; 4] = original[16..20].try_into().unwrap();
+        Some((Ipv4Addr::from(dst).into(), original[9], &original[len..]))
+    }
+}
+
+/// Returns the echo identifier and sequence number of an echo request quoted
+/// in an ICMP error message.
+pub fn quoted_echo(original: &[u8], ipv6: bool) -> Option<(u16, u16)> {
+    let (_, _, icmp) = quoted_packet(original, ipv6)?;
+    let request = match ipv6 {
+        true => V6_ECHO_REQUEST,
+        false => V4_ECHO_REQUEST,
+    };
+    if icmp.len() < ECHO_HEADER || icmp[0] != request {
+        return None;
+    }
+    Some((
+        u16::from_be_bytes([icmp[4], icmp[5]]),
+        u16::from_be_bytes([icmp[6], icmp[7]]),
+    ))
+}
+
+fn ipv4_header_len(packet: &[u8]) -> Option<usize> {
+    let len = (*packet.first()? & 0x0f) as usize * 4;
+    (len >= 20 && packet.len() >= len).then_some(len)
+}
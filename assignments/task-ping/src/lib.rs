@@ -0,0 +1,229 @@
+//! ICMP echo client. Sends a series of echo requests and reports the RTT of
+//! every reply, followed by loss and jitter statistics. The task-ping binary
+//! and the `adnet ping` subcommand are thin wrappers around [`run`].
+
+pub mod icmp;
+pub mod stats;
+
+use std::{
+    collections::HashMap,
+    error::Error,
+    io,
+    net::{IpAddr, ToSocketAddrs},
+    process,
+    time::{Duration, Instant},
+};
+
+use adnet_core::{
+    config::{self, ConfigArgs},
+    logging::{self, LogArgs},
+    report::{Report, ReportArgs},
+};
+use clap::Parser;
+use icmp::{IcmpSocket, Message};
+use serde::Deserialize;
+use stats::{ms, Stats};
+use tracing::debug;
+
+const DEFAULT_COUNT: u32 = 5;
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(1);
+const DEFAULT_SIZE: usize = 56;
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(2);
+// Largest payload that fits in an IPv4 packet with the IP and ICMP headers
+const MAX_SIZE: usize = 65535 - 20 - icmp::ECHO_HEADER;
+
+/// Sends ICMP echo requests and reports round-trip times, loss and jitter.
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+pub struct Args {
+    /// Host name or address to ping
+    destination: Option<String>,
+
+    /// Number of echo requests to send [default: 5]
+    #[arg(short, long)]
+    count: Option<u32>,
+
+    /// Seconds between echo requests [default: 1]
+    #[arg(short, long, value_parser = config::parse_secs)]
+    interval: Option<Duration>,
+
+    /// Bytes of payload in each request [default: 56]
+    #[arg(short, long)]
+    size: Option<usize>,
+
+    /// Seconds to wait for replies after the last request [default: 2]
+    #[arg(short = 'W', long, value_parser = config::parse_secs)]
+    timeout: Option<Duration>,
+
+    /// Use a raw socket even if an unprivileged ICMP socket is available
+    #[arg(long)]
+    raw: bool,
+
+    #[command(flatten)]
+    config: ConfigArgs,
+
+    #[command(flatten)]
+    log: LogArgs,
+
+    #[command(flatten)]
+    report: ReportArgs,
+}
+
+/// The [task-ping] section of the config file
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct FileConfig {
+    destination: Option<String>,
+    count: Option<u32>,
+    #[serde(deserialize_with = "config::secs")]
+    interval: Option<Duration>,
+    size: Option<usize>,
+    #[serde(deserialize_with = "config::secs")]
+    timeout: Option<Duration>,
+}
+
+/// Resolves a host name or address, preferring IPv4 like the other tasks.
+pub fn resolve(host: &str) -> io::Result<IpAddr> {
+    let addrs: Vec<_> = (host, 0).to_socket_addrs()?.map(|a| a.ip()).collect();
+    addrs
+        .iter()
+        .find(|ip| ip.is_ipv4())
+        .or(addrs.first())
+        .copied()
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} did not resolve to any address", host),
+            )
+        })
+}
+
+/// Probes sent but not answered yet
+struct Pending {
+    socket: IcmpSocket,
+    id: u16,
+    sent: HashMap<u16, Instant>,
+    stats: Stats,
+    buf: Vec<u8>,
+}
+
+impl Pending {
+    /// Handles incoming messages until `deadline`, or until every probe is
+    /// answered if `done` is set.
+    fn receive_until(&mut self, deadline: Instant, done: bool) -> io::Result<()> {
+        loop {
+            let now = Instant::now();
+            if now >= deadline || (done && self.sent.is_empty()) {
+                return Ok(());
+            }
+            let Some((data, from)) = self.socket.recv_from(&mut self.buf, deadline - now)? else {
+                return Ok(());
+            };
+            let ipv6 = self.socket.is_ipv6();
+            // Datagram sockets get only our own replies, with the identifier
+            // chosen by the kernel, so it is checked on raw sockets only
+            let ours = |id: u16| !self.socket.is_raw() || id == self.id;
+
+            match Message::parse(data, ipv6) {
+                Some(Message::EchoReply { id, seq, payload }) if ours(id) => {
+                    match self.sent.remove(&seq) {
+                        Some(sent) => {
+                            let rtt = sent.elapsed();
+                            self.stats.add(rtt);
+                            println!(
+                                "{} bytes from {}: seq={} time={:.3} ms",
+                                payload.len() + icmp::ECHO_HEADER,
+                                from,
+                                seq,
+                                ms(rtt)
+                            );
+                        }
+                        None => debug!("Duplicate or late reply seq={} from {}", seq, from),
+                    }
+                }
+                Some(Message::Unreachable { code, original }) => {
+                    if let Some((id, seq)) = icmp::quoted_echo(original, ipv6) {
+                        if ours(id) && self.sent.remove(&seq).is_some() {
+                            println!(
+                                "From {}: seq={} destination unreachable (code {})",
+                                from, seq, code
+                            );
+                        }
+                    }
+                }
+                Some(Message::TimeExceeded { original }) => {
+                    if let Some((id, seq)) = icmp::quoted_echo(original, ipv6) {
+                        if ours(id) && self.sent.remove(&seq).is_some() {
+                            println!("From {}: seq={} time to live exceeded", from, seq);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Runs the ping with the given arguments, as the task-ping binary does.
+pub fn run(args: Args) -> Result<(), Box<dyn Error>> {
+    logging::init(&args.log)?;
+    let report = args.report.clone();
+    report.run("task-ping", |report| ping(args, report))
+}
+
+fn ping(args: Args, report: &mut Report) -> Result<(), Box<dyn Error>> {
+    // Command line options take precedence over the config file
+    let file: FileConfig = args.config.section("task-ping")?;
+    let destination = args
+        .destination
+        .or(file.destination)
+        .ok_or("Destination is required")?;
+    let count = args.count.or(file.count).unwrap_or(DEFAULT_COUNT);
+    let interval = args.interval.or(file.interval).unwrap_or(DEFAULT_INTERVAL);
+    let size = args.size.or(file.size).unwrap_or(DEFAULT_SIZE);
+    let timeout = args.timeout.or(file.timeout).unwrap_or(DEFAULT_TIMEOUT);
+
+    if count == 0 {
+        return Err("Count must be positive".into());
+    }
+    if size > MAX_SIZE {
+        return Err(format!("Payload size {} is too large", size).into());
+    }
+
+    let ip = resolve(&destination)?;
+    let socket = match args.raw {
+        true => IcmpSocket::raw(ip)?,
+        false => IcmpSocket::new(ip)?,
+    };
+    debug!(
+        "Using a {} socket",
+        if socket.is_raw() { "raw" } else { "datagram" }
+    );
+    println!("PING {} ({}): {} bytes of payload", destination, ip, size);
+
+    let mut pending = Pending {
+        socket,
+        id: process::id() as u16,
+        sent: HashMap::new(),
+        stats: Stats::new(),
+        buf: vec![0u8; 65536],
+    };
+    let start = Instant::now();
+
+    for i in 0..count {
+        // Sequence numbers wrap around after 65535 probes
+        let seq = i as u16;
+        pending.receive_until(start + interval * i, false)?;
+
+        let request = icmp::echo_request(pending.socket.is_ipv6(), pending.id, seq, size);
+        pending.socket.send_to(&request, ip)?;
+        pending.sent.insert(seq, Instant::now());
+        pending.stats.sent += 1;
+    }
+    pending.receive_until(Instant::now() + timeout, true)?;
+
+    println!("--- {} ping statistics ---", destination);
+    println!("{}", pending.stats);
+    pending.stats.report(report);
+    Ok(())
+}
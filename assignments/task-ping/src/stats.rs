@@ -0,0 +1,98 @@
+use std::{fmt, time::Duration};
+
+use adnet_core::report::Report;
+
+/// Round-trip times of a series of probes.
+#[derive(Debug, Default)]
+pub struct Stats {
+    pub sent: u32,
+    rtts: Vec<Duration>,
+}
+
+impl Stats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records the RTT of a probe that was answered. Replies are expected in
+    /// arrival order, as jitter is computed between consecutive ones.
+    pub fn add(&mut self, rtt: Duration) {
+        self.rtts.push(rtt);
+    }
+
+    pub fn received(&self) -> u32 {
+        self.rtts.len() as u32
+    }
+
+    /// Percentage of probes that were not answered.
+    pub fn loss(&self) -> f64 {
+        match self.sent {
+            0 => 0.0,
+            sent => 100.0 * sent.saturating_sub(self.received()) as f64 / sent as f64,
+        }
+    }
+
+    pub fn min(&self) -> Option<Duration> {
+        self.rtts.iter().min().copied()
+    }
+
+    pub fn max(&self) -> Option<Duration> {
+        self.rtts.iter().max().copied()
+    }
+
+    pub fn avg(&self) -> Option<Duration> {
+        let n = self.rtts.len() as u32;
+        (n > 0).then(|| self.rtts.iter().sum::<Duration>() / n)
+    }
+
+    /// Mean difference between consecutive RTTs, like the interarrival
+    /// jitter of RTP but without smoothing.
+    pub fn jitter(&self) -> Option<Duration> {
+        let n = self.rtts.len().checked_sub(1).filter(|&n| n > 0)? as u32;
+        let total: Duration = self
+            .rtts
+            .windows(2)
+            .map(|pair| pair[0].max(pair[1]) - pair[0].min(pair[1]))
+            .sum();
+        Some(total / n)
+    }
+
+    /// Adds the statistics to the details of the report, times in milliseconds.
+    pub fn report(&self, report: &mut Report) {
+        report.detail("sent", self.sent);
+        report.detail("received", self.received());
+        report.detail("loss_percent", self.loss());
+        report.detail("min_ms", self.min().map(ms));
+        report.detail("avg_ms", self.avg().map(ms));
+        report.detail("max_ms", self.max().map(ms));
+        report.detail("jitter_ms", self.jitter().map(ms));
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} probes sent, {} received, {:.1}% loss",
+            self.sent,
+            self.received(),
+            self.loss()
+        )?;
+        if let (Some(min), Some(avg), Some(max)) = (self.min(), self.avg(), self.max()) {
+            write!(
+                f,
+                "\nrtt min/avg/max/jitter = {:.3}/{:.3}/{:.3}/{:.3} ms",
+                ms(min),
+                ms(avg),
+                ms(max),
+                ms(self.jitter().unwrap_or_default())
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Milliseconds with a fraction, for printing RTTs.
+pub fn ms(d: Duration) -> f64 {
+    d.as_secs_f64() * 1000.0
+}
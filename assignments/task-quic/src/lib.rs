@@ -0,0 +1,429 @@
+//! The keyword-then-bulk-transfer exchange of the assignments over QUIC,
+//! using quinn. The client opens a bidirectional stream and sends the
+//! keyword; the server answers on the same stream with the configured number
+//! of bytes and finishes it. The client then reports the handshake time, the
+//! throughput and the loss recovery statistics of the connection, for
+//! comparison with the hand-rolled reliable UDP of task-udp.
+//!
+//! The task-quic binary and the `adnet quic` subcommand are thin wrappers
+//! around [`run`].
+
+pub mod tls;
+
+use std::{
+    error::Error,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use adnet_core::{
+    config::{self, ConfigArgs},
+    logging::{self, LogArgs},
+    report::{Report, ReportArgs},
+    shutdown::{self, Shutdown},
+};
+use clap::{Parser, Subcommand};
+use quinn::{
+    crypto::rustls::{QuicClientConfig, QuicServerConfig},
+    ClientConfig, ConnectionError, Endpoint, Incoming, RecvStream, SendStream, ServerConfig,
+    VarInt,
+};
+use serde::Deserialize;
+use tls::ServerCert;
+use tokio::{net::lookup_host, task, time};
+use tracing::{info, warn};
+
+const DEFAULT_SIZE: u64 = 1_000_000;
+const DEFAULT_PORT: u16 = 4433;
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
+// Keywords are short, anything longer is not a valid request
+const MAX_REQUEST: usize = 1024;
+const CHUNK_SIZE: usize = 64 * 1024;
+// Stream error code for a wrong keyword
+const REJECTED: u32 = 1;
+
+/// Bulk transfer over QUIC.
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+pub struct Args {
+    #[command(subcommand)]
+    mode: Mode,
+
+    #[command(flatten)]
+    config: ConfigArgs,
+
+    #[command(flatten)]
+    log: LogArgs,
+
+    #[command(flatten)]
+    report: ReportArgs,
+}
+
+#[derive(Subcommand, Debug)]
+enum Mode {
+    /// Answer keywords with bulk data
+    Server {
+        /// Address to listen at [default: 0.0.0.0:4433]
+        #[arg(short, long)]
+        listen: Option<SocketAddr>,
+
+        /// Only accept this keyword [default: accept any]
+        #[arg(short, long)]
+        keyword: Option<String>,
+
+        /// Number of bytes to send for each request [default: 1000000]
+        #[arg(short, long)]
+        size: Option<u64>,
+
+        /// Value of the bytes to send
+        #[arg(long, default_value_t = b'A')]
+        byte: u8,
+
+        /// PEM certificate chain; without it a self-signed certificate is generated
+        #[arg(long, requires = "key")]
+        cert: Option<PathBuf>,
+
+        /// PEM private key of --cert
+        #[arg(long, requires = "cert")]
+        key: Option<PathBuf>,
+
+        /// Names in the generated certificate
+        #[arg(long, default_value = "localhost")]
+        cert_name: Vec<String>,
+
+        /// Write the generated certificate to this file, for the client's --ca-file
+        #[arg(long, conflicts_with = "cert")]
+        write_cert: Option<PathBuf>,
+    },
+
+    /// Send the keyword and receive the data
+    Client {
+        /// Address of the server as host:port [default port: 4433]
+        #[arg(short, long)]
+        server: Option<String>,
+
+        #[arg(short, long)]
+        keyword: Option<String>,
+
+        /// Name to verify the certificate against [default: host of --server]
+        #[arg(long)]
+        sni: Option<String>,
+
+        /// PEM file with the CA certificates to trust, e.g. the server's --write-cert
+        #[arg(long)]
+        ca_file: Option<PathBuf>,
+
+        /// Do not verify the server certificate
+        #[arg(long)]
+        insecure: bool,
+
+        /// Abort if the transfer has not finished in this many seconds [default: 60]
+        #[arg(long, value_parser = config::parse_secs)]
+        timeout: Option<Duration>,
+    },
+}
+
+/// The [task-quic] section of the config file. Each mode uses its own options.
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct FileConfig {
+    listen: Option<SocketAddr>,
+    server: Option<String>,
+    keyword: Option<String>,
+    size: Option<u64>,
+    #[serde(deserialize_with = "config::secs")]
+    timeout: Option<Duration>,
+}
+
+/// Runs the server or client with the given arguments, as the task-quic binary does.
+pub async fn run(args: Args) -> Result<(), Box<dyn Error>> {
+    logging::init(&args.log)?;
+    let report_args = args.report.clone();
+    let mut report = Report::new("task-quic");
+    let result = transfer(args, &mut report).await;
+    report_args.finish(&mut report, result)
+}
+
+async fn transfer(args: Args, report: &mut Report) -> Result<(), Box<dyn Error>> {
+    // Command line options take precedence over the config file
+    let file: FileConfig = args.config.section("task-quic")?;
+    match args.mode {
+        Mode::Server {
+            listen,
+            keyword,
+            size,
+            byte,
+            cert,
+            key,
+            cert_name,
+            write_cert,
+        } => {
+            let listen = listen
+                .or(file.listen)
+                .unwrap_or_else(|| SocketAddr::from(([0, 0, 0, 0], DEFAULT_PORT)));
+            let cert = match (&cert, &key) {
+                (Some(cert), Some(key)) => ServerCert::Files { cert, key },
+                _ => ServerCert::SelfSigned {
+                    names: &cert_name,
+                    write_to: write_cert.as_deref(),
+                },
+            };
+            let server = Server {
+                keyword: keyword.or(file.keyword),
+                size: size.or(file.size).unwrap_or(DEFAULT_SIZE),
+                byte,
+                sent: AtomicU64::new(0),
+                streams: AtomicU64::new(0),
+                failed: AtomicU64::new(0),
+            };
+            report.keyword = server.keyword.clone();
+            report.detail("mode", "server");
+            let shutdown = shutdown::install()?;
+            serve(listen, tls::server_config(cert)?, server, &shutdown, report).await
+        }
+        Mode::Client {
+            server,
+            keyword,
+            sni,
+            ca_file,
+            insecure,
+            timeout,
+        } => {
+            let server = server
+                .or(file.server)
+                .ok_or("Server is required (--server)")?;
+            let keyword = keyword
+                .or(file.keyword)
+                .ok_or("Keyword is required (--keyword)")?;
+            if !insecure && ca_file.is_none() {
+                return Err("Give the server certificate with --ca-file, or use --insecure".into());
+            }
+            let crypto = tls::client_config(insecure, ca_file.as_deref())?;
+            let timeout = timeout.or(file.timeout).unwrap_or(DEFAULT_TIMEOUT);
+            report.keyword = Some(keyword.clone());
+            report.detail("mode", "client");
+            time::timeout(timeout, client(&server, sni, &keyword, crypto, report))
+                .await
+                .map_err(|_| format!("Transfer did not finish in {:?}", timeout))?
+        }
+    }
+}
+
+/// What the server sends, and how much it has.
+struct Server {
+    keyword: Option<String>,
+    size: u64,
+    byte: u8,
+    sent: AtomicU64,
+    streams: AtomicU64,
+    failed: AtomicU64,
+}
+
+/// Serves clients until the shutdown is requested, then closes the
+/// connections of the clients still there.
+async fn serve(
+    listen: SocketAddr,
+    crypto: rustls::ServerConfig,
+    server: Server,
+    shutdown: &Shutdown,
+    report: &mut Report,
+) -> Result<(), Box<dyn Error>> {
+    let config = ServerConfig::with_crypto(Arc::new(QuicServerConfig::try_from(crypto)?));
+    let endpoint = Endpoint::server(config, listen)?;
+    info!("Task-QUIC server listening on {}", endpoint.local_addr()?);
+
+    let server = Arc::new(server);
+    let mut connections = 0u64;
+    loop {
+        let incoming = tokio::select! {
+            incoming = endpoint.accept() => match incoming {
+                Some(incoming) => incoming,
+                None => break,
+            },
+            _ = shutdown.requested() => break,
+        };
+        connections += 1;
+        let server = server.clone();
+        task::spawn(async move {
+            let address = incoming.remote_address();
+            if let Err(e) = handle_connection(incoming, server.clone()).await {
+                warn!("Connection from {} failed: {}", address, e);
+                server.failed.fetch_add(1, Ordering::Relaxed);
+            }
+        });
+    }
+    endpoint.close(VarInt::from_u32(0), b"shutdown");
+    endpoint.wait_idle().await;
+
+    report.bytes = server.sent.load(Ordering::Relaxed);
+    report.errors = server.failed.load(Ordering::Relaxed);
+    report.detail("connections", connections);
+    report.detail("streams", server.streams.load(Ordering::Relaxed));
+    Ok(())
+}
+
+/// Serves every stream the client opens until it closes the connection.
+async fn handle_connection(incoming: Incoming, server: Arc<Server>) -> Result<(), ConnectionError> {
+    let address = incoming.remote_address();
+    let connection = incoming.await?;
+    info!("Accepted QUIC connection from {}", address);
+
+    loop {
+        let (send, recv) = match connection.accept_bi().await {
+            Ok(stream) => stream,
+            Err(ConnectionError::ApplicationClosed(_)) => {
+                info!("Client {} closed connection", address);
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        };
+        let server = server.clone();
+        task::spawn(async move {
+            match handle_stream(send, recv, &server).await {
+                Ok(()) => server.streams.fetch_add(1, Ordering::Relaxed),
+                Err(e) => {
+                    warn!("Stream from {} failed: {}", address, e);
+                    server.failed.fetch_add(1, Ordering::Relaxed)
+                }
+            };
+        });
+    }
+}
+
+async fn handle_stream(
+    mut send: SendStream,
+    mut recv: RecvStream,
+    server: &Server,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let request = recv.read_to_end(MAX_REQUEST).await?;
+    let keyword = String::from_utf8_lossy(&request).trim().to_string();
+    if server.keyword.as_ref().is_some_and(|k| *k != keyword) {
+        warn!("Rejecting wrong keyword {:?}", keyword);
+        send.reset(VarInt::from_u32(REJECTED))?;
+        return Ok(());
+    }
+
+    let buffer = vec![server.byte; CHUNK_SIZE];
+    let mut remaining = server.size;
+    while remaining > 0 {
+        let n = remaining.min(CHUNK_SIZE as u64) as usize;
+        send.write_all(&buffer[..n]).await?;
+        remaining -= n as u64;
+    }
+    send.finish()?;
+    server.sent.fetch_add(server.size, Ordering::Relaxed);
+    // Wait until the client has everything, so closing does not cut it short
+    let _ = send.stopped().await;
+    info!(
+        "Wrote {} bytes of byte {} for keyword {:?}",
+        server.size, server.byte, keyword
+    );
+    Ok(())
+}
+
+async fn client(
+    server: &str,
+    sni: Option<String>,
+    keyword: &str,
+    crypto: rustls::ClientConfig,
+    report: &mut Report,
+) -> Result<(), Box<dyn Error>> {
+    let with_port = match server.rsplit_once(':') {
+        Some((_, port)) if port.parse::<u16>().is_ok() && !server.ends_with(']') => {
+            server.to_string()
+        }
+        _ => format!("{}:{}", server, DEFAULT_PORT),
+    };
+    let address = lookup_host(&with_port)
+        .await?
+        .next()
+        .ok_or_else(|| format!("{} did not resolve to any address", server))?;
+    let host = with_port.rsplit_once(':').map_or(server, |(host, _)| host);
+    let name = sni.unwrap_or_else(|| host.trim_matches(['[', ']']).to_string());
+
+    let bind: SocketAddr = match address {
+        SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+        SocketAddr::V6(_) => ([0u16; 8], 0).into(),
+    };
+    let mut endpoint = Endpoint::client(bind)?;
+    endpoint.set_default_client_config(ClientConfig::new(Arc::new(QuicClientConfig::try_from(
+        crypto,
+    )?)));
+
+    info!("Task-QUIC client connecting to {} ({})", server, address);
+    let start = Instant::now();
+    let connection = endpoint.connect(address, &name)?.await?;
+    let handshake = start.elapsed();
+
+    let (mut send, mut recv) = connection.open_bi().await?;
+    send.write_all(keyword.as_bytes()).await?;
+    send.finish()?;
+    let sent = Instant::now();
+
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    let mut total: u64 = 0;
+    let mut tail = Vec::new();
+    let mut first_byte = None;
+    while let Some(n) = recv.read(&mut buf).await.map_err(|e| match e {
+        quinn::ReadError::Reset(code) if code == VarInt::from_u32(REJECTED) => {
+            "Server rejected the keyword".to_string()
+        }
+        e => format!("Receiving failed: {}", e),
+    })? {
+        first_byte.get_or_insert_with(Instant::now);
+        total += n as u64;
+        tail.extend_from_slice(&buf[..n]);
+        let keep = tail.len().saturating_sub(8);
+        tail.drain(..keep);
+    }
+    let end = Instant::now();
+
+    let stats = connection.stats();
+    connection.close(VarInt::from_u32(0), b"done");
+    endpoint.wait_idle().await;
+
+    let transfer = end - first_byte.unwrap_or(end);
+    let mbits = match transfer.as_secs_f64() {
+        secs if secs > 0.0 => total as f64 * 8.0 / secs / 1e6,
+        _ => 0.0,
+    };
+    info!(
+        "Total size: {} bytes -- Last 8 bytes: {:?} -- Duration: {:.2?}",
+        total,
+        String::from_utf8_lossy(&tail),
+        end - start
+    );
+    info!(
+        "Handshake: {:.2?} -- First byte: {:.2?} -- Transfer: {:.2?} -- Throughput: {:.2} Mbit/s",
+        handshake,
+        first_byte.map_or(Duration::ZERO, |t| t - sent),
+        transfer,
+        mbits
+    );
+    info!(
+        "RTT: {:.2?} -- Cwnd: {} bytes -- Packets sent: {} -- Lost: {} packets / {} bytes -- Congestion events: {}",
+        stats.path.rtt,
+        stats.path.cwnd,
+        stats.path.sent_packets,
+        stats.path.lost_packets,
+        stats.path.lost_bytes,
+        stats.path.congestion_events
+    );
+
+    report.bytes = total;
+    report.last_bytes = Some(String::from_utf8_lossy(&tail).into_owned());
+    report.retransmits = stats.path.lost_packets;
+    report.detail("handshake_ms", handshake.as_secs_f64() * 1000.0);
+    report.detail("throughput_mbits", mbits);
+    report.detail("rtt_ms", stats.path.rtt.as_secs_f64() * 1000.0);
+    report.detail("cwnd", stats.path.cwnd);
+    report.detail("packets_sent", stats.path.sent_packets);
+    report.detail("lost_bytes", stats.path.lost_bytes);
+    report.detail("congestion_events", stats.path.congestion_events);
+    Ok(())
+}
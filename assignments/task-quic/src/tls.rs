@@ -0,0 +1,126 @@
+use std::{error::Error, fs, path::Path, sync::Arc};
+
+use rustls::{
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::{ring, CryptoProvider},
+    pki_types::{
+        pem::PemObject, CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName, UnixTime,
+    },
+    version::TLS13,
+    ClientConfig, DigitallySignedStruct, RootCertStore, ServerConfig, SignatureScheme,
+};
+use tracing::{info, warn};
+
+/// Application protocol negotiated in the handshake
+pub const ALPN: &[u8] = b"adnet-quic";
+
+/// Where the server gets its certificate.
+pub enum ServerCert<'a> {
+    /// PEM files with the certificate chain and the private key
+    Files { cert: &'a Path, key: &'a Path },
+    /// Self-signed for these names, optionally written to a PEM file so that
+    /// clients can trust it with --ca-file
+    SelfSigned {
+        names: &'a [String],
+        write_to: Option<&'a Path>,
+    },
+}
+
+pub fn server_config(cert: ServerCert) -> Result<ServerConfig, Box<dyn Error>> {
+    let (chain, key) = match cert {
+        ServerCert::Files { cert, key } => {
+            let chain = CertificateDer::pem_file_iter(cert)
+                .map_err(|e| format!("Cannot read certificate {}: {}", cert.display(), e))?
+                .collect::<Result<Vec<_>, _>>()?;
+            let key = PrivateKeyDer::from_pem_file(key)
+                .map_err(|e| format!("Cannot read private key {}: {}", key.display(), e))?;
+            (chain, key)
+        }
+        ServerCert::SelfSigned { names, write_to } => {
+            let generated = rcgen::generate_simple_self_signed(names.to_vec())?;
+            if let Some(path) = write_to {
+                fs::write(path, generated.cert.pem())
+                    .map_err(|e| format!("Cannot write certificate {}: {}", path.display(), e))?;
+                info!("Wrote self-signed certificate to {}", path.display());
+            }
+            let key = PrivatePkcs8KeyDer::from(generated.key_pair.serialize_der());
+            (vec![generated.cert.der().clone()], key.into())
+        }
+    };
+
+    let mut config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_protocol_versions(&[&TLS13])?
+        .with_no_client_auth()
+        .with_single_cert(chain, key)?;
+    config.alpn_protocols = vec![ALPN.to_vec()];
+    Ok(config)
+}
+
+/// TLS settings of the client. QUIC always uses TLS 1.3.
+pub fn client_config(
+    insecure: bool,
+    ca_file: Option<&Path>,
+) -> Result<ClientConfig, Box<dyn Error>> {
+    let provider = Arc::new(ring::default_provider());
+    let builder =
+        ClientConfig::builder_with_provider(provider.clone()).with_protocol_versions(&[&TLS13])?;
+
+    let mut config = if insecure {
+        warn!("QUIC server certificate is not verified");
+        builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(NoVerification(provider)))
+            .with_no_client_auth()
+    } else {
+        let mut roots = RootCertStore::empty();
+        if let Some(path) = ca_file {
+            for cert in CertificateDer::pem_file_iter(path)
+                .map_err(|e| format!("Cannot read CA file {}: {}", path.display(), e))?
+            {
+                roots.add(cert?)?;
+            }
+        }
+        builder.with_root_certificates(roots).with_no_client_auth()
+    };
+    config.alpn_protocols = vec![ALPN.to_vec()];
+    Ok(config)
+}
+
+/// Certificate verifier for --insecure that accepts any server certificate.
+#[derive(Debug)]
+struct NoVerification(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for NoVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        _message: &[u8],
+        _cert: &CertificateDer<'_>,
+        _dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        Ok(HandshakeSignatureValid::assertion())
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        _message: &[u8],
+        _cert: &CertificateDer<'_>,
+        _dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        Ok(HandshakeSignatureValid::assertion())
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
@@ -0,0 +1,343 @@
+//! Port scanner. TCP ports are probed with ordinary connection attempts, so
+//! no raw sockets or privileges are needed, and UDP ports with empty
+//! datagrams. Many probes run concurrently, with an optional limit on the
+//! rate at which they are started. The task-scan binary and the `adnet scan`
+//! subcommand are thin wrappers around [`run`].
+
+use std::{
+    error::Error,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use adnet_core::{
+    config::{self, ConfigArgs},
+    logging::{self, LogArgs},
+    report::{Report, ReportArgs},
+};
+use clap::{Parser, ValueEnum};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    net::{lookup_host, TcpStream, UdpSocket},
+    sync::Semaphore,
+    task::JoinSet,
+    time::{self, MissedTickBehavior},
+};
+use tracing::debug;
+
+const DEFAULT_PORTS: &str = "1-1024";
+const DEFAULT_CONCURRENCY: usize = 100;
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(1);
+const DEFAULT_RETRIES: u32 = 1;
+
+#[derive(ValueEnum, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+/// What a probe found out about a port.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum State {
+    /// TCP connection accepted, or UDP answer received
+    Open,
+    /// TCP connection refused, or ICMP port unreachable for UDP
+    Closed,
+    /// No answer, or an ICMP error other than port unreachable
+    Filtered,
+    /// No answer to UDP, which open ports often do not give either
+    OpenFiltered,
+}
+
+/// Scans the TCP or UDP ports of a host.
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+pub struct Args {
+    /// Host name or address to scan
+    target: Option<String>,
+
+    /// Ports to scan, e.g. 22,80,8000-8100 [default: 1-1024]
+    #[arg(short, long)]
+    ports: Option<String>,
+
+    /// Protocol to scan [default: tcp]
+    #[arg(short = 'P', long)]
+    protocol: Option<Protocol>,
+
+    /// Probes in progress at the same time [default: 100]
+    #[arg(short, long)]
+    concurrency: Option<usize>,
+
+    /// Probes started per second at most [default: unlimited]
+    #[arg(short, long)]
+    rate: Option<u32>,
+
+    /// Seconds to wait for an answer to a probe [default: 1]
+    #[arg(short = 'W', long, value_parser = config::parse_secs)]
+    timeout: Option<Duration>,
+
+    /// Times to resend an unanswered UDP probe [default: 1]
+    #[arg(long)]
+    retries: Option<u32>,
+
+    /// Print the result as JSON instead of a table
+    #[arg(long)]
+    json: bool,
+
+    #[command(flatten)]
+    config: ConfigArgs,
+
+    #[command(flatten)]
+    log: LogArgs,
+
+    #[command(flatten)]
+    report: ReportArgs,
+}
+
+/// The [task-scan] section of the config file
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct FileConfig {
+    target: Option<String>,
+    ports: Option<String>,
+    protocol: Option<Protocol>,
+    concurrency: Option<usize>,
+    rate: Option<u32>,
+    #[serde(deserialize_with = "config::secs")]
+    timeout: Option<Duration>,
+    retries: Option<u32>,
+}
+
+/// Parses a comma-separated list of ports and FIRST-LAST ranges, in the
+/// given order without duplicates.
+pub fn parse_ports(s: &str) -> Result<Vec<u16>, String> {
+    let mut seen = vec![false; 65536];
+    let mut ports = Vec::new();
+    for part in s.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        let (first, last) = part.split_once('-').unwrap_or((part, part));
+        let parse = |p: &str| {
+            p.trim()
+                .parse::<u16>()
+                .map_err(|e| format!("Invalid port {:?}: {}", p, e))
+        };
+        let (first, last) = (parse(first)?, parse(last)?);
+        if first == 0 || first > last {
+            return Err(format!("Invalid port range {:?}", part));
+        }
+        for port in first..=last {
+            if !seen[port as usize] {
+                seen[port as usize] = true;
+                ports.push(port);
+            }
+        }
+    }
+    match ports.is_empty() {
+        true => Err("No ports to scan".to_string()),
+        false => Ok(ports),
+    }
+}
+
+/// Result of the whole scan, also the format of the JSON output.
+#[derive(Serialize, Debug)]
+pub struct Scan {
+    pub target: String,
+    pub address: IpAddr,
+    pub protocol: Protocol,
+    pub elapsed_ms: f64,
+    pub ports: Vec<Port>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Port {
+    pub port: u16,
+    pub state: State,
+}
+
+/// Settings of the probes of one scan.
+struct Prober {
+    address: IpAddr,
+    protocol: Protocol,
+    timeout: Duration,
+    retries: u32,
+}
+
+impl Prober {
+    async fn probe(&self, port: u16) -> State {
+        let target = SocketAddr::new(self.address, port);
+        match self.protocol {
+            Protocol::Tcp => self.probe_tcp(target).await,
+            Protocol::Udp => match self.probe_udp(target).await {
+                Ok(state) => state,
+                Err(e) => {
+                    debug!("UDP probe of {} failed: {}", target, e);
+                    State::Filtered
+                }
+            },
+        }
+    }
+
+    async fn probe_tcp(&self, target: SocketAddr) -> State {
+        match time::timeout(self.timeout, TcpStream::connect(target)).await {
+            Ok(Ok(_)) => State::Open,
+            Ok(Err(e)) if e.kind() == io::ErrorKind::ConnectionRefused => State::Closed,
+            Ok(Err(e)) => {
+                debug!("Connection to {} failed: {}", target, e);
+                State::Filtered
+            }
+            Err(_) => State::Filtered,
+        }
+    }
+
+    /// A connected UDP socket reports the ICMP port unreachable answer of a
+    /// closed port as a refused connection.
+    async fn probe_udp(&self, target: SocketAddr) -> io::Result<State> {
+        let any: IpAddr = match target {
+            SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+            SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+        };
+        let socket = UdpSocket::bind(SocketAddr::new(any, 0)).await?;
+        socket.connect(target).await?;
+
+        let mut buf = [0u8; 512];
+        for _ in 0..=self.retries {
+            if let Err(e) = socket.send(&[]).await {
+                return Ok(unreachable_state(&e));
+            }
+            match time::timeout(self.timeout, socket.recv(&mut buf)).await {
+                Ok(Ok(_)) => return Ok(State::Open),
+                Ok(Err(e)) => return Ok(unreachable_state(&e)),
+                Err(_) => {}
+            }
+        }
+        Ok(State::OpenFiltered)
+    }
+}
+
+fn unreachable_state(error: &io::Error) -> State {
+    match error.kind() {
+        io::ErrorKind::ConnectionRefused => State::Closed,
+        _ => State::Filtered,
+    }
+}
+
+/// Runs the scan with the given arguments, as the task-scan binary does.
+pub async fn run(args: Args) -> Result<(), Box<dyn Error>> {
+    logging::init(&args.log)?;
+    let report_args = args.report.clone();
+    let mut report = Report::new("task-scan");
+    let result = scan(args, &mut report).await;
+    report_args.finish(&mut report, result)
+}
+
+async fn scan(args: Args, report: &mut Report) -> Result<(), Box<dyn Error>> {
+    // Command line options take precedence over the config file
+    let file: FileConfig = args.config.section("task-scan")?;
+    let target = args.target.or(file.target).ok_or("Target is required")?;
+    let ports = parse_ports(
+        args.ports
+            .or(file.ports)
+            .as_deref()
+            .unwrap_or(DEFAULT_PORTS),
+    )?;
+    let protocol = args.protocol.or(file.protocol).unwrap_or(Protocol::Tcp);
+    let concurrency = args
+        .concurrency
+        .or(file.concurrency)
+        .unwrap_or(DEFAULT_CONCURRENCY);
+    if concurrency == 0 {
+        return Err("Concurrency must be positive".into());
+    }
+    let rate = args.rate.or(file.rate).filter(|&rate| rate > 0);
+
+    let address = lookup_host((target.as_str(), 0))
+        .await?
+        .next()
+        .ok_or_else(|| format!("{} did not resolve to any address", target))?
+        .ip();
+    let prober = Arc::new(Prober {
+        address,
+        protocol,
+        timeout: args.timeout.or(file.timeout).unwrap_or(DEFAULT_TIMEOUT),
+        retries: args.retries.or(file.retries).unwrap_or(DEFAULT_RETRIES),
+    });
+    if !args.json {
+        println!(
+            "Scanning {} {:?} port(s) of {} ({})",
+            ports.len(),
+            protocol,
+            target,
+            address
+        );
+    }
+
+    let start = Instant::now();
+    let permits = Arc::new(Semaphore::new(concurrency));
+    let mut ticker = rate.map(|rate| {
+        let period = (Duration::from_secs(1) / rate).max(Duration::from_nanos(1));
+        let mut ticker = time::interval(period);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        ticker
+    });
+    let mut probes = JoinSet::new();
+    for port in ports {
+        if let Some(ticker) = &mut ticker {
+            ticker.tick().await;
+        }
+        let permit = permits.clone().acquire_owned().await?;
+        let prober = prober.clone();
+        probes.spawn(async move {
+            let state = prober.probe(port).await;
+            drop(permit);
+            Port { port, state }
+        });
+    }
+    let mut results = Vec::new();
+    while let Some(result) = probes.join_next().await {
+        results.push(result?);
+    }
+    results.sort_by_key(|p| p.port);
+
+    let scan = Scan {
+        target,
+        address,
+        protocol,
+        elapsed_ms: start.elapsed().as_secs_f64() * 1000.0,
+        ports: results,
+    };
+    match args.json {
+        true => println!("{}", serde_json::to_string_pretty(&scan)?),
+        false => print_scan(&scan),
+    }
+    report.detail("scan", &scan);
+    Ok(())
+}
+
+/// Prints the ports that may be open and a count of the others.
+fn print_scan(scan: &Scan) {
+    let count = |state| scan.ports.iter().filter(|p| p.state == state).count();
+    println!("PORT      STATE");
+    for port in &scan.ports {
+        let state = match port.state {
+            State::Open => "open",
+            State::OpenFiltered => "open|filtered",
+            _ => continue,
+        };
+        println!(
+            "{:<9} {}",
+            format!("{}/{:?}", port.port, scan.protocol).to_lowercase(),
+            state
+        );
+    }
+    println!(
+        "{} open, {} open|filtered, {} closed, {} filtered in {:.2} s",
+        count(State::Open),
+        count(State::OpenFiltered),
+        count(State::Closed),
+        count(State::Filtered),
+        scan.elapsed_ms / 1000.0
+    );
+}
@@ -0,0 +1,291 @@
+//! SOCKS5 proxy server. Clients negotiate with the handshake of RFC 1928,
+//! optionally authenticating with a username and password (RFC 1929), and
+//! ask the proxy to CONNECT to an IPv4, IPv6 or domain name target. The
+//! proxy then relays bytes in both directions and logs how many went each
+//! way when the connection closes. The task-socks binary and the
+//! `adnet socks` subcommand are thin wrappers around [`run`].
+
+pub mod socks;
+
+use std::{
+    error::Error,
+    io,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use adnet_core::{
+    config::{self, ConfigArgs},
+    logging::{self, LogArgs},
+    metrics::{self, Counter, Gauge, MetricsArgs},
+    report::{Report, ReportArgs},
+    shutdown,
+};
+use clap::Parser;
+use serde::Deserialize;
+use socks::{Credentials, Target};
+use tokio::{
+    io::copy_bidirectional,
+    net::{TcpListener, TcpStream},
+    task, time,
+};
+use tracing::{debug, info, warn};
+
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+// Clients that do not finish the handshake in this time are dropped
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// SOCKS5 proxy with CONNECT and optional username/password authentication.
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+pub struct Args {
+    /// Address to listen at [default: 0.0.0.0:1080]
+    #[arg(short, long)]
+    listen: Option<SocketAddr>,
+
+    /// Require clients to authenticate with this username
+    #[arg(short, long, requires = "password")]
+    username: Option<String>,
+
+    /// Password for --username
+    #[arg(short, long, requires = "username")]
+    password: Option<String>,
+
+    /// Seconds to wait for the connection to a target [default: 10]
+    #[arg(long, value_parser = config::parse_secs)]
+    connect_timeout: Option<Duration>,
+
+    #[command(flatten)]
+    config: ConfigArgs,
+
+    #[command(flatten)]
+    log: LogArgs,
+
+    #[command(flatten)]
+    metrics: MetricsArgs,
+
+    #[command(flatten)]
+    report: ReportArgs,
+}
+
+/// The [task-socks] section of the config file
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct FileConfig {
+    listen: Option<SocketAddr>,
+    username: Option<String>,
+    password: Option<String>,
+    #[serde(deserialize_with = "config::secs")]
+    connect_timeout: Option<Duration>,
+}
+
+/// The proxy's metrics in the global registry.
+#[derive(Clone)]
+struct Metrics {
+    connections: Counter,
+    active_connections: Gauge,
+    auth_failures: Counter,
+    refused: Counter,
+    failed: Counter,
+    bytes_up: Counter,
+    bytes_down: Counter,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        Self {
+            connections: metrics::counter("socks_connections_total", "Client connections accepted"),
+            active_connections: metrics::gauge(
+                "socks_active_connections",
+                "Client connections currently open",
+            ),
+            auth_failures: metrics::counter(
+                "socks_auth_failures_total",
+                "Clients that failed to authenticate",
+            ),
+            refused: metrics::counter(
+                "socks_refused_total",
+                "Requests answered with an error reply",
+            ),
+            failed: metrics::counter(
+                "socks_failed_total",
+                "Client connections that ended with an error",
+            ),
+            bytes_up: metrics::counter(
+                "socks_bytes_up_total",
+                "Bytes relayed from clients to targets",
+            ),
+            bytes_down: metrics::counter(
+                "socks_bytes_down_total",
+                "Bytes relayed from targets to clients",
+            ),
+        }
+    }
+
+    fn report(&self, report: &mut Report) {
+        report.bytes = self.bytes_up.get() + self.bytes_down.get();
+        report.errors = self.failed.get();
+        report.detail("connections", self.connections.get());
+        report.detail("auth_failures", self.auth_failures.get());
+        report.detail("refused", self.refused.get());
+        report.detail("bytes_up", self.bytes_up.get());
+        report.detail("bytes_down", self.bytes_down.get());
+    }
+}
+
+/// Settings shared by all client connections.
+struct Proxy {
+    credentials: Option<Credentials>,
+    connect_timeout: Duration,
+    metrics: Metrics,
+}
+
+/// Runs the proxy with the given arguments, as the task-socks binary does.
+pub async fn run(args: Args) -> Result<(), Box<dyn Error>> {
+    logging::init(&args.log)?;
+    metrics::init(&args.metrics)?;
+    let report_args = args.report.clone();
+    let mut report = Report::new("task-socks");
+    let metrics = Metrics::new();
+    let result = proxy(args, metrics.clone()).await;
+    metrics.report(&mut report);
+    report_args.finish(&mut report, result)
+}
+
+/// Relays client connections until the shutdown is requested. Connections
+/// still open then are cut off.
+async fn proxy(args: Args, metrics: Metrics) -> Result<(), Box<dyn Error>> {
+    // Command line options take precedence over the config file
+    let file: FileConfig = args.config.section("task-socks")?;
+    let listen = args
+        .listen
+        .or(file.listen)
+        .unwrap_or_else(|| SocketAddr::from(([0, 0, 0, 0], 1080)));
+    let credentials = match (
+        args.username.or(file.username),
+        args.password.or(file.password),
+    ) {
+        (Some(username), Some(password)) => Some(Credentials { username, password }),
+        (None, None) => None,
+        _ => return Err("Username and password must be given together".into()),
+    };
+    if credentials
+        .as_ref()
+        .is_some_and(|c| c.username.len() > 255 || c.password.len() > 255)
+    {
+        return Err("Username and password can be at most 255 bytes".into());
+    }
+    let proxy = Arc::new(Proxy {
+        credentials,
+        connect_timeout: args
+            .connect_timeout
+            .or(file.connect_timeout)
+            .unwrap_or(DEFAULT_CONNECT_TIMEOUT),
+        metrics,
+    });
+
+    let listener = TcpListener::bind(listen).await?;
+    info!(
+        "Task-SOCKS proxy listening on {} ({})",
+        listener.local_addr()?,
+        match proxy.credentials {
+            Some(_) => "username/password authentication",
+            None => "no authentication",
+        }
+    );
+
+    let shutdown = shutdown::install()?;
+    loop {
+        let (socket, address) = tokio::select! {
+            accepted = listener.accept() => accepted?,
+            _ = shutdown.requested() => break,
+        };
+        proxy.metrics.connections.inc();
+        let proxy = proxy.clone();
+        task::spawn(async move {
+            proxy.metrics.active_connections.inc();
+            if let Err(e) = handle_client(socket, address, &proxy).await {
+                warn!("Client {} failed: {}", address, e);
+                proxy.metrics.failed.inc();
+            }
+            proxy.metrics.active_connections.dec();
+        });
+    }
+    info!(
+        "Stopped with {} connections open",
+        proxy.metrics.active_connections.get()
+    );
+    Ok(())
+}
+
+/// Runs the handshake with one client and relays its connection.
+async fn handle_client(
+    mut client: TcpStream,
+    address: SocketAddr,
+    proxy: &Proxy,
+) -> io::Result<()> {
+    let handshake = async {
+        if !socks::negotiate(&mut client, proxy.credentials.as_ref()).await? {
+            return Ok(None);
+        }
+        socks::read_request(&mut client).await.map(Some)
+    };
+    let request = time::timeout(HANDSHAKE_TIMEOUT, handshake)
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "Handshake timed out"))??;
+
+    let target = match request {
+        None => {
+            proxy.metrics.auth_failures.inc();
+            info!("Client {} did not authenticate", address);
+            return Ok(());
+        }
+        Some(Err(code)) => {
+            proxy.metrics.refused.inc();
+            info!("Refusing request of {} with reply {}", address, code);
+            return socks::write_reply(&mut client, code, None).await;
+        }
+        Some(Ok(target)) => target,
+    };
+
+    let mut upstream = match connect(&target, proxy.connect_timeout).await {
+        Ok(upstream) => upstream,
+        Err(e) => {
+            proxy.metrics.refused.inc();
+            info!("{} -> {}: connection failed: {}", address, target, e);
+            return socks::write_reply(&mut client, socks::reply_code(&e), None).await;
+        }
+    };
+    let bound = upstream.local_addr()?;
+    socks::write_reply(&mut client, socks::SUCCEEDED, Some(bound)).await?;
+    debug!("{} -> {}: connected from {}", address, target, bound);
+
+    let start = Instant::now();
+    let result = copy_bidirectional(&mut client, &mut upstream).await;
+    let (up, down) = *result.as_ref().unwrap_or(&(0, 0));
+    proxy.metrics.bytes_up.add(up);
+    proxy.metrics.bytes_down.add(down);
+    info!(
+        "{} -> {}: {} bytes up, {} bytes down in {:.2?}",
+        address,
+        target,
+        up,
+        down,
+        start.elapsed()
+    );
+    result.map(|_| ())
+}
+
+/// Connects to the target, trying every address of a domain name in turn.
+async fn connect(target: &Target, timeout: Duration) -> io::Result<TcpStream> {
+    let connecting = async {
+        match target {
+            Target::Ip(addr) => TcpStream::connect(addr).await,
+            Target::Domain(host, port) => TcpStream::connect((host.as_str(), *port)).await,
+        }
+    };
+    time::timeout(timeout, connecting)
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "Connection timed out"))?
+}
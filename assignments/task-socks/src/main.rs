@@ -0,0 +1,9 @@
+use adnet_core::exit;
+use clap::Parser;
+use std::process::ExitCode;
+use task_socks::Args;
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    exit::exit(task_socks::run(Args::parse()).await)
+}
@@ -0,0 +1,185 @@
+//! The SOCKS5 handshake of RFC 1928, with username/password authentication
+//! from RFC 1929. Only the CONNECT command is supported.
+
+use std::{
+    fmt, io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+const VERSION: u8 = 5;
+const AUTH_VERSION: u8 = 1;
+
+const METHOD_NONE: u8 = 0x00;
+const METHOD_PASSWORD: u8 = 0x02;
+const METHOD_UNACCEPTABLE: u8 = 0xff;
+
+const CMD_CONNECT: u8 = 1;
+
+const ATYP_IPV4: u8 = 1;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_IPV6: u8 = 4;
+
+/// Reply codes
+pub const SUCCEEDED: u8 = 0x00;
+pub const GENERAL_FAILURE: u8 = 0x01;
+pub const NETWORK_UNREACHABLE: u8 = 0x03;
+pub const HOST_UNREACHABLE: u8 = 0x04;
+pub const CONNECTION_REFUSED: u8 = 0x05;
+pub const COMMAND_NOT_SUPPORTED: u8 = 0x07;
+pub const ADDRESS_NOT_SUPPORTED: u8 = 0x08;
+
+/// Username and password the clients must give, if any.
+#[derive(Debug, Clone)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+/// Where the client wants to connect.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Target {
+    Ip(SocketAddr),
+    Domain(String, u16),
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Target::Ip(addr) => write!(f, "{}", addr),
+            Target::Domain(host, port) => write!(f, "{}:{}", host, port),
+        }
+    }
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Agrees on the authentication method and authenticates the client.
+/// Returns false if the client could not be authenticated, after telling it so.
+pub async fn negotiate<S>(stream: &mut S, credentials: Option<&Credentials>) -> io::Result<bool>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut header = [0u8; 2];
+    stream.read_exact(&mut header).await?;
+    if header[0] != VERSION {
+        return Err(invalid(format!("Unsupported SOCKS version {}", header[0])));
+    }
+    let mut methods = vec![0u8; header[1] as usize];
+    stream.read_exact(&mut methods).await?;
+
+    let wanted = match credentials {
+        Some(_) => METHOD_PASSWORD,
+        None => METHOD_NONE,
+    };
+    if !methods.contains(&wanted) {
+        stream.write_all(&[VERSION, METHOD_UNACCEPTABLE]).await?;
+        return Ok(false);
+    }
+    stream.write_all(&[VERSION, wanted]).await?;
+
+    let Some(credentials) = credentials else {
+        return Ok(true);
+    };
+    // VER, ULEN, UNAME, PLEN, PASSWD
+    let mut version_len = [0u8; 2];
+    stream.read_exact(&mut version_len).await?;
+    if version_len[0] != AUTH_VERSION {
+        return Err(invalid(format!(
+            "Unsupported authentication version {}",
+            version_len[0]
+        )));
+    }
+    let mut username = vec![0u8; version_len[1] as usize];
+    stream.read_exact(&mut username).await?;
+    let mut password = vec![0u8; stream.read_u8().await? as usize];
+    stream.read_exact(&mut password).await?;
+
+    let ok =
+        username == credentials.username.as_bytes() && password == credentials.password.as_bytes();
+    let status = if ok { 0 } else { 1 };
+    stream.write_all(&[AUTH_VERSION, status]).await?;
+    Ok(ok)
+}
+
+/// Reads the request. Returns the target, or the reply code to refuse the
+/// request with.
+pub async fn read_request<S>(stream: &mut S) -> io::Result<Result<Target, u8>>
+where
+    S: AsyncRead + Unpin,
+{
+    // VER, CMD, RSV, ATYP
+    let mut header = [0u8; 4];
+    stream.read_exact(&mut header).await?;
+    if header[0] != VERSION {
+        return Err(invalid(format!("Unsupported SOCKS version {}", header[0])));
+    }
+
+    // The address has to be read in any case to know where the request ends
+    let target = match header[3] {
+        ATYP_IPV4 => {
+            let mut ip = [0u8; 4];
+            stream.read_exact(&mut ip).await?;
+            let port = stream.read_u16().await?;
+            Target::Ip(SocketAddr::new(Ipv4Addr::from(ip).into(), port))
+        }
+        ATYP_IPV6 => {
+            let mut ip = [0u8; 16];
+            stream.read_exact(&mut ip).await?;
+            let port = stream.read_u16().await?;
+            Target::Ip(SocketAddr::new(Ipv6Addr::from(ip).into(), port))
+        }
+        ATYP_DOMAIN => {
+            let mut host = vec![0u8; stream.read_u8().await? as usize];
+            stream.read_exact(&mut host).await?;
+            let port = stream.read_u16().await?;
+            match String::from_utf8(host) {
+                Ok(host) => Target::Domain(host, port),
+                Err(_) => return Ok(Err(ADDRESS_NOT_SUPPORTED)),
+            }
+        }
+        _ => return Ok(Err(ADDRESS_NOT_SUPPORTED)),
+    };
+
+    match header[1] {
+        CMD_CONNECT => Ok(Ok(target)),
+        _ => Ok(Err(COMMAND_NOT_SUPPORTED)),
+    }
+}
+
+/// Sends the reply with the address the proxy connected from, which is
+/// all zeros when the request failed.
+pub async fn write_reply<S>(stream: &mut S, code: u8, bound: Option<SocketAddr>) -> io::Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    let bound = bound.unwrap_or_else(|| SocketAddr::from(([0, 0, 0, 0], 0)));
+    let mut reply = vec![VERSION, code, 0];
+    match bound {
+        SocketAddr::V4(addr) => {
+            reply.push(ATYP_IPV4);
+            reply.extend_from_slice(&addr.ip().octets());
+        }
+        SocketAddr::V6(addr) => {
+            reply.push(ATYP_IPV6);
+            reply.extend_from_slice(&addr.ip().octets());
+        }
+    }
+    reply.extend_from_slice(&bound.port().to_be_bytes());
+    stream.write_all(&reply).await
+}
+
+/// The reply code for a failed connection attempt.
+pub fn reply_code(error: &io::Error) -> u8 {
+    match error.kind() {
+        io::ErrorKind::ConnectionRefused => CONNECTION_REFUSED,
+        io::ErrorKind::NetworkUnreachable => NETWORK_UNREACHABLE,
+        io::ErrorKind::HostUnreachable | io::ErrorKind::TimedOut | io::ErrorKind::NotFound => {
+            HOST_UNREACHABLE
+        }
+        _ => GENERAL_FAILURE,
+    }
+}
@@ -0,0 +1,65 @@
+//! Benchmarks for the server's write loop: answering a request with the
+//! requested number of bytes, both into a sink to measure the loop itself and
+//! over a loopback TCP connection to include the socket writes.
+//!
+//! Run with `cargo bench -p task-srv`.
+
+use std::hint::black_box;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use tokio::{
+    io::{self, AsyncReadExt},
+    net::{TcpListener, TcpStream},
+    runtime::Runtime,
+};
+use task_srv::response;
+
+const SIZES: [u32; 3] = [1024, 64 * 1024, 1024 * 1024];
+
+fn sink(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let mut group = c.benchmark_group("write_response/sink");
+    for size in SIZES {
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &size, |b, &size| {
+            let mut sink = io::sink();
+            b.iter(|| {
+                rt.block_on(response::write_response(&mut sink, black_box(size), b'A'))
+                    .unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
+fn loopback(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+
+    // A client that reads and discards everything it is sent
+    let (mut server, client) = rt.block_on(async {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        (server, client)
+    });
+    rt.spawn(async move {
+        let mut client = client;
+        let mut buf = vec![0u8; 64 * 1024];
+        while matches!(client.read(&mut buf).await, Ok(n) if n > 0) {}
+    });
+
+    let mut group = c.benchmark_group("write_response/loopback");
+    for size in SIZES {
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &size, |b, &size| {
+            b.iter(|| {
+                rt.block_on(response::write_response(&mut server, black_box(size), b'A'))
+                    .unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, sink, loopback);
+criterion_main!(benches);
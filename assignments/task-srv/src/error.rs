@@ -0,0 +1,49 @@
+use std::{io, net::SocketAddr};
+
+use adnet_core::{
+    exit::{Code, HasCode},
+    AgentError,
+};
+use thiserror::Error;
+use tokio::task::JoinError;
+
+/// Errors of task-srv. Each kind has its own exit code, see
+/// [`adnet_core::exit`].
+#[derive(Debug, Error)]
+pub enum SrvError {
+    /// Invalid or missing options
+    #[error("{0}")]
+    Usage(String),
+    /// The config file could not be read
+    #[error(transparent)]
+    Config(io::Error),
+    #[error("Cannot listen at {address}: {source}")]
+    Bind {
+        address: SocketAddr,
+        source: io::Error,
+    },
+    #[error(transparent)]
+    Agent(#[from] AgentError),
+    #[error("Failed to send message to agent server at {agent}: {source}")]
+    Send { agent: String, source: io::Error },
+    /// The certificate or key of the TLS listeners is unusable
+    #[error("{0}")]
+    Tls(String),
+    /// The HTTP or TLS server task panicked
+    #[error("Server task failed: {0}")]
+    Task(#[from] JoinError),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+impl HasCode for SrvError {
+    fn exit_code(&self) -> Code {
+        match self {
+            SrvError::Usage(_) | SrvError::Config(_) | SrvError::Tls(_) => Code::Usage,
+            SrvError::Agent(e) => e.exit_code(),
+            SrvError::Send { .. } => Code::Connect,
+            SrvError::Bind { .. } | SrvError::Task(_) => Code::Failure,
+            SrvError::Io(e) => e.exit_code(),
+        }
+    }
+}
@@ -0,0 +1,455 @@
+//! HTTP/1.1 front end to the same byte generation as the task protocol, for
+//! testing with curl or a browser:
+//!
+//! ```text
+//! curl -v 'http://10.0.0.1:8080/bytes?n=100000&b=A'
+//! curl -v 'http://10.0.0.1:8080/bytes?n=100000&b=%41&chunked=1'
+//! ```
+//!
+//! `n` is the number of bytes and `b` the byte, given as one character or
+//! percent-encoded. With `chunked=1` the body is sent with chunked transfer
+//! encoding instead of a Content-Length. Connections are kept alive as
+//! HTTP/1.1 specifies, unless the client asks otherwise.
+//!
+//! `/metrics` answers with the metrics of the server in the Prometheus text
+//! format, as `--metrics-listen` does on an address of its own.
+
+use std::{
+    io,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use adnet_core::{metrics, shutdown::Shutdown};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+    net::TcpListener,
+    sync::Semaphore,
+    task, time,
+};
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, info, info_span, warn, Instrument};
+
+use crate::{response, Metrics};
+
+// Limits for the request line and each header line, and the number of headers
+const MAX_LINE: usize = 8192;
+const MAX_HEADERS: usize = 100;
+
+/// Accepts HTTP connections until the listener fails or the shutdown is
+/// requested, with TLS if there is an acceptor. The connections are tracked
+/// for draining, and count towards the connection limit shared with the agent
+/// connections.
+pub(crate) async fn serve(
+    listener: TcpListener,
+    tls: Option<TlsAcceptor>,
+    client_timeout: Duration,
+    metrics: Metrics,
+    shutdown: Shutdown,
+    slots: Arc<Semaphore>,
+) -> io::Result<()> {
+    loop {
+        let Some(slot) = crate::connection_slot(&slots, &shutdown).await else {
+            return Ok(());
+        };
+        let (socket, address) = tokio::select! {
+            accepted = listener.accept() => accepted?,
+            _ = shutdown.requested() => return Ok(()),
+        };
+        match tls {
+            Some(_) => info!("Accepting HTTPS connection from {}", address),
+            None => info!("Accepting HTTP connection from {}", address),
+        }
+        metrics.connections.inc();
+
+        let span = info_span!("http_client", peer = %address, tls = tls.is_some());
+        let tls = tls.clone();
+        let metrics = metrics.clone();
+        let in_flight = shutdown.track();
+        let connection = async move {
+            metrics.active_connections.inc();
+            let client = async {
+                match tls {
+                    Some(tls) => handle(tls.accept(socket).await?, address, &metrics).await,
+                    None => handle(socket, address, &metrics).await,
+                }
+            };
+            let result = time::timeout(client_timeout, client).await;
+            match result {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => {
+                    warn!("Error with HTTP client {}: {}", address, e);
+                    metrics.errors.inc();
+                }
+                Err(_) => {
+                    warn!(
+                        "HTTP client {} timed out after {:?}",
+                        address, client_timeout
+                    );
+                    metrics.timeouts.inc();
+                }
+            }
+            metrics.active_connections.dec();
+            drop(slot);
+            drop(in_flight);
+        };
+        task::spawn(connection.instrument(span));
+    }
+}
+
+struct Request {
+    method: String,
+    target: String,
+    keep_alive: bool,
+}
+
+/// What to answer, before the body is written.
+enum Reply {
+    Bytes {
+        n: u32,
+        byte: u8,
+        chunked: bool,
+    },
+    Error {
+        status: &'static str,
+        message: String,
+    },
+    /// The metrics in the Prometheus text format
+    Metrics(String),
+}
+
+/// Serves requests on one connection until either side closes it.
+async fn handle<S>(socket: S, address: SocketAddr, metrics: &Metrics) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (reader, mut writer) = tokio::io::split(socket);
+    let mut reader = BufReader::new(reader);
+
+    loop {
+        let request = match read_request(&mut reader).await? {
+            Some(Ok(request)) => request,
+            Some(Err(message)) => {
+                let reply = Reply::Error {
+                    status: "400 Bad Request",
+                    message,
+                };
+                write_reply(&mut writer, &reply, false, false).await?;
+                writer.flush().await?;
+                return Ok(());
+            }
+            None => {
+                debug!("HTTP client {} closed connection", address);
+                return Ok(());
+            }
+        };
+        info!(
+            "HTTP {} {} from {}",
+            request.method, request.target, address
+        );
+
+        let head = request.method == "HEAD";
+        let reply = match request.method.as_str() {
+            "GET" | "HEAD" => route(&request.target),
+            _ => Reply::Error {
+                status: "405 Method Not Allowed",
+                message: format!("Method {} is not supported", request.method),
+            },
+        };
+
+        let start = Instant::now();
+        let written = write_reply(&mut writer, &reply, head, request.keep_alive).await?;
+        writer.flush().await?;
+        if let Reply::Bytes { n, byte, .. } = reply {
+            metrics.requests.inc();
+            metrics.bytes_written.add(written);
+            metrics
+                .request_duration
+                .observe(start.elapsed().as_secs_f64());
+            if !head {
+                info!("Wrote {} bytes of byte {} over HTTP", n, byte);
+            }
+        }
+        if !request.keep_alive {
+            return Ok(());
+        }
+    }
+}
+
+/// Reads the request line and headers. Returns None if the connection was
+/// closed before a request, and an error message for a malformed request.
+async fn read_request<R>(reader: &mut BufReader<R>) -> io::Result<Option<Result<Request, String>>>
+where
+    R: AsyncRead + Unpin,
+{
+    let Some(line) = read_line(reader).await? else {
+        return Ok(None);
+    };
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(target), Some(version), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Ok(Some(Err(format!("Invalid request line {:?}", line))));
+    };
+    // HTTP/1.1 connections persist by default, HTTP/1.0 ones do not
+    let mut keep_alive = match version {
+        "HTTP/1.1" => true,
+        "HTTP/1.0" => false,
+        _ => return Ok(Some(Err(format!("Unsupported version {}", version)))),
+    };
+
+    let mut content_length: u64 = 0;
+    for _ in 0..=MAX_HEADERS {
+        let Some(header) = read_line(reader).await? else {
+            return Ok(None);
+        };
+        if header.is_empty() {
+            // The requests we serve have no body, but skip one if sent
+            if content_length > 0 {
+                let mut body = reader.take(content_length);
+                tokio::io::copy(&mut body, &mut tokio::io::sink()).await?;
+            }
+            return Ok(Some(Ok(Request {
+                method: method.to_string(),
+                target: target.to_string(),
+                keep_alive,
+            })));
+        }
+
+        let Some((name, value)) = header.split_once(':') else {
+            return Ok(Some(Err(format!("Invalid header {:?}", header))));
+        };
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("connection") {
+            for option in value.split(',').map(str::trim) {
+                if option.eq_ignore_ascii_case("close") {
+                    keep_alive = false;
+                } else if option.eq_ignore_ascii_case("keep-alive") {
+                    keep_alive = true;
+                }
+            }
+        } else if name.eq_ignore_ascii_case("content-length") {
+            match value.parse() {
+                Ok(length) => content_length = length,
+                Err(_) => return Ok(Some(Err(format!("Invalid Content-Length {:?}", value)))),
+            }
+        } else if name.eq_ignore_ascii_case("transfer-encoding") {
+            return Ok(Some(Err("Request bodies are not supported".to_string())));
+        }
+    }
+    Ok(Some(Err("Too many headers".to_string())))
+}
+
+/// Reads one CRLF (or LF) terminated line, without the terminator.
+async fn read_line<R>(reader: &mut BufReader<R>) -> io::Result<Option<String>>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut line = Vec::new();
+    let n = (&mut *reader)
+        .take(MAX_LINE as u64)
+        .read_until(b'\n', &mut line)
+        .await?;
+    if n == 0 {
+        return Ok(None);
+    }
+    if line.last() != Some(&b'\n') {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Request line too long or connection closed mid-line",
+        ));
+    }
+    line.pop();
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    String::from_utf8(line)
+        .map(Some)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Request is not valid UTF-8"))
+}
+
+fn route(target: &str) -> Reply {
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    if path == "/metrics" {
+        return Reply::Metrics(metrics::global().render_prometheus());
+    }
+    if path != "/bytes" {
+        return Reply::Error {
+            status: "404 Not Found",
+            message: format!("No such resource {}, try /bytes?n=100&b=A", path),
+        };
+    }
+
+    let mut n = None;
+    let mut byte = None;
+    let mut chunked = false;
+    for pair in query.split('&').filter(|p| !p.is_empty()) {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        let value = percent_decode(value);
+        match (key, value.as_deref()) {
+            ("n", Some(v)) => {
+                n = std::str::from_utf8(v)
+                    .ok()
+                    .and_then(|v| v.parse::<u32>().ok())
+            }
+            ("b", Some([b])) => byte = Some(*b),
+            ("chunked", Some(v)) => chunked = v == b"1" || v == b"true",
+            _ => {
+                return Reply::Error {
+                    status: "400 Bad Request",
+                    message: format!("Invalid query parameter {:?}", pair),
+                }
+            }
+        }
+    }
+
+    match (n, byte) {
+        (Some(n), Some(byte)) => Reply::Bytes { n, byte, chunked },
+        _ => Reply::Error {
+            status: "400 Bad Request",
+            message: "Parameters n (number of bytes) and b (one byte) are required".to_string(),
+        },
+    }
+}
+
+/// Decodes %XX escapes and `+`, or returns None for an invalid escape.
+fn percent_decode(s: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(s.len());
+    let mut bytes = s.bytes();
+    while let Some(b) = bytes.next() {
+        match b {
+            b'%' => {
+                let hex = [bytes.next()?, bytes.next()?];
+                let hex = std::str::from_utf8(&hex).ok()?;
+                out.push(u8::from_str_radix(hex, 16).ok()?);
+            }
+            b'+' => out.push(b' '),
+            b => out.push(b),
+        }
+    }
+    Some(out)
+}
+
+/// Writes the status line, headers and unless `head` is set, the body.
+/// Returns the number of body bytes written.
+async fn write_reply<W>(
+    writer: &mut W,
+    reply: &Reply,
+    head: bool,
+    keep_alive: bool,
+) -> io::Result<u64>
+where
+    W: AsyncWrite + Unpin,
+{
+    let connection = if keep_alive { "keep-alive" } else { "close" };
+    let mut headers = format!(
+        "Date: {}\r\nServer: task-srv\r\nConnection: {}\r\n",
+        http_date(SystemTime::now()),
+        connection
+    );
+
+    match reply {
+        Reply::Bytes { n, byte, chunked } => {
+            headers += "Content-Type: application/octet-stream\r\n";
+            match chunked {
+                true => headers += "Transfer-Encoding: chunked\r\n",
+                false => headers += &format!("Content-Length: {}\r\n", n),
+            }
+            writer
+                .write_all(format!("HTTP/1.1 200 OK\r\n{}\r\n", headers).as_bytes())
+                .await?;
+            if head {
+                return Ok(0);
+            }
+            let written = match chunked {
+                true => write_chunked(writer, *n, *byte).await?,
+                false => response::write_response(writer, *n, *byte).await?,
+            };
+            Ok(written as u64)
+        }
+        Reply::Error { status, message } => {
+            let body = format!("{}\n", message);
+            headers += &format!(
+                "Content-Type: text/plain; charset=utf-8\r\nContent-Length: {}\r\n",
+                body.len()
+            );
+            if status.starts_with("405") {
+                headers += "Allow: GET, HEAD\r\n";
+            }
+            let mut out = format!("HTTP/1.1 {}\r\n{}\r\n", status, headers);
+            if !head {
+                out += &body;
+            }
+            writer.write_all(out.as_bytes()).await?;
+            Ok(0)
+        }
+        Reply::Metrics(body) => {
+            headers += &format!(
+                "Content-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n",
+                body.len()
+            );
+            let mut out = format!("HTTP/1.1 200 OK\r\n{}\r\n", headers);
+            if !head {
+                out += body;
+            }
+            writer.write_all(out.as_bytes()).await?;
+            Ok(0)
+        }
+    }
+}
+
+/// Writes `total` copies of `byte` as chunks of at most [`response::CHUNK_SIZE`].
+async fn write_chunked<W>(writer: &mut W, total: u32, byte: u8) -> io::Result<u32>
+where
+    W: AsyncWrite + Unpin,
+{
+    let mut written: u32 = 0;
+    let buffer = [byte; response::CHUNK_SIZE];
+    while written < total {
+        let size = (total - written).min(buffer.len() as u32) as usize;
+        writer
+            .write_all(format!("{:x}\r\n", size).as_bytes())
+            .await?;
+        writer.write_all(&buffer[..size]).await?;
+        writer.write_all(b"\r\n").await?;
+        written += size as u32;
+    }
+    writer.write_all(b"0\r\n\r\n").await?;
+    Ok(written)
+}
+
+/// Formats a time as an HTTP date, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`.
+fn http_date(time: SystemTime) -> String {
+    const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let (days, rest) = (secs / 86400, secs % 86400);
+
+    // Civil date from days since 1970-01-01, after Howard Hinnant's algorithm
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        DAYS[(days % 7) as usize],
+        day,
+        MONTHS[(month - 1) as usize],
+        year,
+        rest / 3600,
+        rest % 3600 / 60,
+        rest % 60
+    )
+}
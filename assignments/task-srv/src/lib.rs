@@ -0,0 +1,730 @@
+//! The task-srv server as a library, so that the `adnet` multi-tool can run it
+//! as a subcommand. The task-srv binary is a thin wrapper around [`run`].
+
+mod error;
+mod http;
+pub mod response;
+mod tls;
+mod udp;
+
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use adnet_core::{
+    async_client::send_command_async,
+    config::{self, ConfigArgs},
+    logging::{self, LogArgs},
+    metrics::{self, Counter, Gauge, Histogram, MetricsArgs},
+    report::{Report, ReportArgs},
+    shutdown::{self, Shutdown},
+    AgentClient, Command,
+};
+use clap::Parser;
+use pktcap::record::{RecordArgs, Recorder, TcpRecording, MAX_SEGMENT};
+use serde::Deserialize;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, UdpSocket},
+    sync::{OwnedSemaphorePermit, Semaphore},
+    task::{self, JoinSet},
+    time,
+};
+use tokio_rustls::TlsAcceptor;
+use tracing::{info, info_span, warn, Instrument};
+use wire::srv::{Crc32, Request, RequestError, EXTENDED_REQUEST_SIZE, REQUEST_SIZE};
+
+pub use error::SrvError;
+
+// Timeout for connecting to and sending message to adnet-agent server
+const AGENT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+// Default timeout for handling each client connection
+const CLIENT_HANDLE_TIMEOUT: Duration = Duration::from_secs(120);
+// The mode byte of an extended request comes with the rest of it. A request
+// of the assignment for 2 GiB or more looks like the start of one, and
+// without the timeout its connection would wait for a mode byte that never comes
+const MODE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// TCP server that answers the requests of adnet-agent.
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+pub struct Args {
+    #[arg(short, long)]
+    keyword: Option<String>,
+
+    /// [default: 0.0.0.0]
+    #[arg(short, long)]
+    ip: Option<IpAddr>,
+
+    #[arg(short, long)]
+    port: Option<u16>,
+
+    /// [default: 10.0.0.3:12345]
+    #[arg(short, long)]
+    agent: Option<String>,
+
+    /// Seconds after which a client connection is closed [default: 120]
+    #[arg(long, value_parser = config::parse_secs)]
+    client_timeout: Option<Duration>,
+
+    /// Also serve the bytes over HTTP at this port: GET /bytes?n=100&b=A
+    #[arg(long)]
+    http_port: Option<u16>,
+
+    /// Serve only HTTP, without contacting the agent
+    #[arg(long)]
+    http_only: bool,
+
+    /// Also serve the requests of the agent protocol over TLS at this port
+    #[arg(long)]
+    tls_port: Option<u16>,
+
+    /// Also serve HTTP over TLS at this port
+    #[arg(long)]
+    https_port: Option<u16>,
+
+    /// Also answer the requests of the agent protocol over UDP at this port,
+    /// with the response in task-udp packets
+    #[arg(long)]
+    udp_port: Option<u16>,
+
+    /// PEM certificate chain of the TLS listeners [default: self-signed]
+    #[arg(long)]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM private key of --tls-cert
+    #[arg(long)]
+    tls_key: Option<PathBuf>,
+
+    /// Seconds to let open connections finish after Ctrl-C [default: 5]
+    #[arg(long, value_parser = config::parse_secs)]
+    drain_timeout: Option<Duration>,
+
+    /// Most client connections open at once, agent and HTTP together.
+    /// Further connections wait until one closes [default: no limit]
+    #[arg(long)]
+    max_connections: Option<usize>,
+
+    #[command(flatten)]
+    config: ConfigArgs,
+
+    #[command(flatten)]
+    log: LogArgs,
+
+    #[command(flatten)]
+    metrics: MetricsArgs,
+
+    #[command(flatten)]
+    report: ReportArgs,
+
+    #[command(flatten)]
+    pcap: RecordArgs,
+}
+
+/// The [task-srv] section of the config file
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct FileConfig {
+    keyword: Option<String>,
+    ip: Option<IpAddr>,
+    port: Option<u16>,
+    agent: Option<String>,
+    #[serde(deserialize_with = "config::secs")]
+    client_timeout: Option<Duration>,
+    http_port: Option<u16>,
+    http_only: bool,
+    tls_port: Option<u16>,
+    https_port: Option<u16>,
+    udp_port: Option<u16>,
+    tls_cert: Option<PathBuf>,
+    tls_key: Option<PathBuf>,
+    #[serde(deserialize_with = "config::secs")]
+    drain_timeout: Option<Duration>,
+    max_connections: Option<usize>,
+}
+
+/// The server's metrics in the global registry.
+#[derive(Clone)]
+struct Metrics {
+    connections: Counter,
+    active_connections: Gauge,
+    requests: Counter,
+    bytes_written: Counter,
+    request_duration: Histogram,
+    timeouts: Counter,
+    errors: Counter,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        Self {
+            connections: metrics::counter("srv_connections_total", "Client connections accepted"),
+            active_connections: metrics::gauge(
+                "srv_active_connections",
+                "Client connections currently open",
+            ),
+            requests: metrics::counter("srv_requests_total", "Requests answered in full"),
+            bytes_written: metrics::counter("srv_bytes_written_total", "Response bytes written"),
+            request_duration: metrics::histogram(
+                "srv_request_duration_seconds",
+                "Time to write the response to a request",
+                &[0.001, 0.01, 0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0],
+            ),
+            timeouts: metrics::counter(
+                "srv_timeouts_total",
+                "Client connections closed by the client timeout",
+            ),
+            errors: metrics::counter(
+                "srv_errors_total",
+                "Client connections closed because of an I/O error",
+            ),
+        }
+    }
+
+    /// Logs the totals, for the end of the program.
+    fn log_summary(&self) {
+        info!(
+            "Served {} requests and {} bytes on {} connections ({} timed out, {} failed)",
+            self.requests.get(),
+            self.bytes_written.get(),
+            self.connections.get(),
+            self.timeouts.get(),
+            self.errors.get()
+        );
+    }
+
+    /// Fills in the totals of the report.
+    fn report(&self, report: &mut Report) {
+        report.bytes = self.bytes_written.get();
+        report.errors = self.errors.get() + self.timeouts.get();
+        report.detail("connections", self.connections.get());
+        report.detail("requests", self.requests.get());
+        report.detail("timeouts", self.timeouts.get());
+    }
+}
+
+/// Settings after combining the command line with the config file.
+struct Settings {
+    keyword: String,
+    ip: IpAddr,
+    port: u16,
+    agent: String,
+    client_timeout: Duration,
+    http_port: Option<u16>,
+    http_only: bool,
+    tls_port: Option<u16>,
+    https_port: Option<u16>,
+    udp_port: Option<u16>,
+    tls_cert: Option<PathBuf>,
+    tls_key: Option<PathBuf>,
+    drain_timeout: Duration,
+    max_connections: Option<usize>,
+}
+
+impl Settings {
+    /// Command line options take precedence over the config file.
+    fn new(args: Args) -> Result<Self, SrvError> {
+        let file: FileConfig = args.config.section("task-srv").map_err(SrvError::Config)?;
+        let http_port = args.http_port.or(file.http_port);
+        let https_port = args.https_port.or(file.https_port);
+        let http_only = args.http_only || file.http_only;
+        if http_only && http_port.is_none() && https_port.is_none() {
+            return Err(SrvError::Usage(
+                "HTTP port is required with --http-only (--http-port or --https-port)".to_string(),
+            ));
+        }
+        let tls_port = args.tls_port.or(file.tls_port);
+        let udp_port = args.udp_port.or(file.udp_port);
+        if http_only && (tls_port.is_some() || udp_port.is_some()) {
+            return Err(SrvError::Usage(
+                "--tls-port and --udp-port serve the agent protocol, which --http-only turns off"
+                    .to_string(),
+            ));
+        }
+        let tls_cert = args.tls_cert.or(file.tls_cert);
+        let tls_key = args.tls_key.or(file.tls_key);
+        if tls_cert.is_some() != tls_key.is_some() {
+            return Err(SrvError::Usage(
+                "Certificate and key go together (--tls-cert and --tls-key)".to_string(),
+            ));
+        }
+
+        // Without the agent there is no need for a keyword or the agent port
+        let keyword = match args.keyword.or(file.keyword) {
+            Some(keyword) => keyword,
+            None if http_only => String::new(),
+            None => {
+                return Err(SrvError::Usage(
+                    "Keyword is required (--keyword)".to_string(),
+                ))
+            }
+        };
+        let port = match args.port.or(file.port) {
+            Some(port) => port,
+            None if http_only => 0,
+            None => return Err(SrvError::Usage("Port is required (--port)".to_string())),
+        };
+        let max_connections = args.max_connections.or(file.max_connections);
+        if max_connections == Some(0) {
+            return Err(SrvError::Usage(
+                "Connection limit must be at least 1 (--max-connections)".to_string(),
+            ));
+        }
+        Ok(Settings {
+            keyword,
+            ip: args.ip.or(file.ip).unwrap_or(IpAddr::from([0, 0, 0, 0])),
+            port,
+            agent: args
+                .agent
+                .or(file.agent)
+                .unwrap_or_else(|| "10.0.0.3:12345".to_string()),
+            client_timeout: args
+                .client_timeout
+                .or(file.client_timeout)
+                .unwrap_or(CLIENT_HANDLE_TIMEOUT),
+            http_port,
+            http_only,
+            tls_port,
+            https_port,
+            udp_port,
+            tls_cert,
+            tls_key,
+            drain_timeout: args
+                .drain_timeout
+                .or(file.drain_timeout)
+                .unwrap_or(shutdown::DEFAULT_DRAIN_TIMEOUT),
+            max_connections,
+        })
+    }
+}
+
+/// Main entry point for the TCP server.
+///
+/// Binds to the specified address, sends a control message to the agent server,
+/// and then listens for incoming client connections until Ctrl-C or SIGTERM.
+/// Open connections then get the drain timeout to finish before the totals
+/// are printed.
+pub async fn run(args: Args) -> Result<(), SrvError> {
+    logging::init(&args.log)?;
+    metrics::init(&args.metrics)?;
+    let report_args = args.report.clone();
+    let mut report = Report::new("task-srv");
+    let metrics = Metrics::new();
+    let recorder = args.pcap.recorder()?;
+    let result = run_server(args, &metrics, recorder.clone(), &mut report).await;
+    metrics.report(&mut report);
+    if let Some(recorder) = recorder {
+        let path = recorder.path();
+        match recorder.finish() {
+            Ok(()) => info!(
+                "Recorded {} packets to {}",
+                recorder.packets(),
+                path.display()
+            ),
+            Err(e) => warn!("Recording to {} failed: {}", path.display(), e),
+        }
+    }
+    report_args.finish(&mut report, result)
+}
+
+async fn run_server(
+    args: Args,
+    metrics: &Metrics,
+    recorder: Option<Recorder>,
+    report: &mut Report,
+) -> Result<(), SrvError> {
+    let settings = Settings::new(args)?;
+    if !settings.http_only {
+        report.keyword = Some(settings.keyword.clone());
+    }
+    let shutdown = shutdown::install()?;
+    let slots = Arc::new(Semaphore::new(
+        settings.max_connections.unwrap_or(Semaphore::MAX_PERMITS),
+    ));
+    let summary = metrics.clone();
+    shutdown.on_exit(move || summary.log_summary());
+
+    info!("Task-SRV starting");
+
+    // Some light static validation for the port range
+    let valid_port = |port| (1024..=49151).contains(&port);
+    if !settings.http_only && !valid_port(settings.port) {
+        return Err(SrvError::Usage(
+            "Port must be between 1024 and 49151".to_string(),
+        ));
+    }
+
+    for (port, name) in [
+        (settings.http_port, "HTTP port"),
+        (settings.https_port, "HTTPS port"),
+        (settings.tls_port, "TLS port"),
+        (settings.udp_port, "UDP port"),
+    ] {
+        if port.is_some_and(|port| !valid_port(port)) {
+            return Err(SrvError::Usage(format!(
+                "{} must be between 1024 and 49151",
+                name
+            )));
+        }
+    }
+    let tls = match (settings.tls_port, settings.https_port) {
+        (None, None) => None,
+        _ => Some(tls::acceptor(
+            settings.tls_cert.as_deref(),
+            settings.tls_key.as_deref(),
+        )?),
+    };
+
+    let mut listeners = JoinSet::new();
+    for (port, tls) in [
+        (settings.http_port, None),
+        (settings.https_port, tls.clone()),
+    ] {
+        let Some(port) = port else {
+            continue;
+        };
+        let bind_addr = SocketAddr::new(settings.ip, port);
+        let listener = bind(bind_addr).await?;
+        let scheme = if tls.is_some() { "HTTPS" } else { "HTTP" };
+        info!("Serving {} on {}", scheme, bind_addr);
+        let serve = http::serve(
+            listener,
+            tls,
+            settings.client_timeout,
+            metrics.clone(),
+            shutdown.clone(),
+            slots.clone(),
+        );
+        listeners.spawn(serve);
+    }
+    if settings.http_only {
+        join_listeners(&mut listeners).await?;
+        return finish(&shutdown, settings.drain_timeout).await;
+    }
+
+    if let Some(port) = settings.tls_port {
+        let bind_addr = SocketAddr::new(settings.ip, port);
+        let listener = bind(bind_addr).await?;
+        info!("Listening for TLS on {}", bind_addr);
+        listeners.spawn(serve(
+            listener,
+            tls,
+            settings.client_timeout,
+            metrics.clone(),
+            shutdown.clone(),
+            slots.clone(),
+            None,
+        ));
+    }
+
+    if let Some(port) = settings.udp_port {
+        let bind_addr = SocketAddr::new(settings.ip, port);
+        let socket = UdpSocket::bind(bind_addr)
+            .await
+            .map_err(|source| SrvError::Bind {
+                address: bind_addr,
+                source,
+            })?;
+        info!("Listening for UDP requests on {}", bind_addr);
+        listeners.spawn(udp::serve(
+            socket,
+            settings.client_timeout,
+            metrics.clone(),
+            shutdown.clone(),
+            slots.clone(),
+        ));
+    }
+
+    let bind_addr = SocketAddr::new(settings.ip, settings.port);
+    info!("Binding to {}", bind_addr);
+
+    let server = bind(bind_addr).await?;
+    info!("Listening on {}", bind_addr);
+
+    // Send control message to adnet-agent server
+    info!("Connecting to agent server at {}...", settings.agent);
+    let agent = AgentClient::new(&settings.agent).connect_timeout(AGENT_CONNECT_TIMEOUT);
+    let mut agent_socket = agent.connect_async().await?;
+
+    // The agent cannot connect to the unspecified address, so when listening on
+    // all interfaces, tell it the address it sees us at
+    let server_ip = match settings.ip.is_unspecified() {
+        true => agent_socket.local_addr()?.ip(),
+        false => settings.ip,
+    };
+    let command = Command::Srv {
+        keyword: settings.keyword.clone(),
+        server: SocketAddr::new(server_ip, settings.port),
+    };
+    send_command_async(&mut agent_socket, &command)
+        .await
+        .map_err(|source| SrvError::Send {
+            agent: settings.agent.clone(),
+            source,
+        })?;
+    info!("Sent control message: {}", command);
+    if let Some(recorder) = &recorder {
+        let mut recording = recorder.connect(agent_socket.local_addr()?, agent_socket.peer_addr()?);
+        recording.sent(&command.to_bytes());
+    }
+    drop(agent_socket);
+
+    // Our TCP server loop, next to the other listeners
+    let server = serve(
+        server,
+        None,
+        settings.client_timeout,
+        metrics.clone(),
+        shutdown.clone(),
+        slots,
+        recorder,
+    );
+    tokio::try_join!(
+        async { server.await.map_err(SrvError::from) },
+        join_listeners(&mut listeners),
+    )?;
+    finish(&shutdown, settings.drain_timeout).await
+}
+
+/// Accepts connections of the agent protocol until the listener fails or the
+/// shutdown is requested, with TLS if there is an acceptor. The connections
+/// are tracked for draining, and recorded if there is a recorder.
+async fn serve(
+    listener: TcpListener,
+    tls: Option<TlsAcceptor>,
+    client_timeout: Duration,
+    metrics: Metrics,
+    shutdown: Shutdown,
+    slots: Arc<Semaphore>,
+    recorder: Option<Recorder>,
+) -> io::Result<()> {
+    loop {
+        let Some(slot) = connection_slot(&slots, &shutdown).await else {
+            return Ok(());
+        };
+        let (socket, address) = tokio::select! {
+            accepted = listener.accept() => accepted?,
+            _ = shutdown.requested() => return Ok(()),
+        };
+        match tls {
+            Some(_) => info!("Accepting TLS connection from {}", address),
+            None => info!("Accepting connection from {}", address),
+        }
+        metrics.connections.inc();
+
+        let span = info_span!("client", peer = %address, tls = tls.is_some());
+        let tls = tls.clone();
+        let metrics = metrics.clone();
+        let in_flight = shutdown.track();
+        let recording = match (&recorder, socket.local_addr()) {
+            (Some(recorder), Ok(local)) => Some(recorder.accept(local, address)),
+            _ => None,
+        };
+        let connection = async move {
+            metrics.active_connections.inc();
+            let client = async {
+                match tls {
+                    Some(tls) => match tls.accept(socket).await {
+                        Ok(stream) => process_client(stream, address, &metrics, recording).await,
+                        Err(e) => {
+                            warn!("TLS handshake with {} failed: {}", address, e);
+                            metrics.errors.inc();
+                        }
+                    },
+                    None => process_client(socket, address, &metrics, recording).await,
+                }
+            };
+            match time::timeout(client_timeout, client).await {
+                Ok(_) => {
+                    // Client handling completed normally
+                }
+                Err(_) => {
+                    warn!(
+                        "Client {} connection timed out after {:?}",
+                        address, client_timeout
+                    );
+                    metrics.timeouts.inc();
+                }
+            }
+            metrics.active_connections.dec();
+            drop(slot);
+            drop(in_flight);
+        };
+        task::spawn(connection.instrument(span));
+    }
+}
+
+async fn bind(address: SocketAddr) -> Result<TcpListener, SrvError> {
+    TcpListener::bind(address)
+        .await
+        .map_err(|source| SrvError::Bind { address, source })
+}
+
+/// Waits until fewer than `--max-connections` connections are open, and
+/// returns the slot for the next one, or `None` if the shutdown is requested
+/// first. Meanwhile new connections wait in the listen backlog.
+pub(crate) async fn connection_slot(
+    slots: &Arc<Semaphore>,
+    shutdown: &Shutdown,
+) -> Option<OwnedSemaphorePermit> {
+    if let Ok(slot) = slots.clone().try_acquire_owned() {
+        return Some(slot);
+    }
+    info!("Connection limit reached, waiting for a connection to close");
+    tokio::select! {
+        slot = slots.clone().acquire_owned() => slot.ok(),
+        _ = shutdown.requested() => None,
+    }
+}
+
+/// Waits for the listeners, which return at the shutdown, and fails as soon as
+/// one of them fails.
+async fn join_listeners(listeners: &mut JoinSet<io::Result<()>>) -> Result<(), SrvError> {
+    while let Some(result) = listeners.join_next().await {
+        result??;
+    }
+    Ok(())
+}
+
+/// Lets the open connections finish, and prints the totals.
+async fn finish(shutdown: &Shutdown, drain_timeout: Duration) -> Result<(), SrvError> {
+    if shutdown.in_flight() > 0 {
+        info!("Waiting for {} open connections", shutdown.in_flight());
+        shutdown.drain_async(drain_timeout).await;
+    }
+    shutdown.finish();
+    Ok(())
+}
+
+/// Handles communication with a single client connection.
+///
+/// Reads 5-byte requests (4 bytes for length, 1 byte for value) and sends the requested number of bytes.
+/// Extended requests have a mode byte after those, see [`wire::srv`], and their responses end with a CRC.
+/// Continues until the client closes the connection (so can hang unless the timeout is set on the caller side, which we do in run).
+/// With a recording, the requests and responses are recorded.
+async fn process_client<S>(
+    mut socket: S,
+    address: SocketAddr,
+    metrics: &Metrics,
+    mut recording: Option<TcpRecording>,
+) where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    loop {
+        let mut length_bytes = [0u8; 4];
+        if let Err(e) = socket.read_exact(&mut length_bytes).await {
+            if e.kind() == std::io::ErrorKind::UnexpectedEof {
+                info!("Client {} closed connection", address);
+            } else {
+                warn!("Error reading length from {}: {}", address, e);
+                metrics.errors.inc();
+            }
+            return;
+        }
+
+        let mut byte_value = [0u8; 1];
+        if let Err(e) = socket.read_exact(&mut byte_value).await {
+            if e.kind() == std::io::ErrorKind::UnexpectedEof {
+                info!("Client {} closed connection", address);
+            } else {
+                warn!("Error reading byte value from {}: {}", address, e);
+                metrics.errors.inc();
+            }
+            return;
+        }
+
+        let mut request_bytes = [0u8; EXTENDED_REQUEST_SIZE];
+        request_bytes[..4].copy_from_slice(&length_bytes);
+        request_bytes[4] = byte_value[0];
+        let request = match Request::decode(&request_bytes[..REQUEST_SIZE]) {
+            // An extended request, with the mode byte still to come
+            Err(RequestError::Truncated { needed, .. }) => {
+                let mode = &mut request_bytes[REQUEST_SIZE..needed];
+                match time::timeout(MODE_TIMEOUT, socket.read_exact(mode)).await {
+                    Ok(Ok(_)) => {}
+                    Ok(Err(e)) => {
+                        warn!("Error reading mode from {}: {}", address, e);
+                        metrics.errors.inc();
+                        return;
+                    }
+                    Err(_) => {
+                        warn!(
+                            "No mode byte from {} in {:?}, closing: requests for 2 GiB or more are not supported",
+                            address, MODE_TIMEOUT
+                        );
+                        metrics.errors.inc();
+                        return;
+                    }
+                }
+                Request::decode(&request_bytes[..needed]).map(|request| (request, needed))
+            }
+            decoded => decoded.map(|request| (request, REQUEST_SIZE)),
+        };
+        let request = match request {
+            Ok((request, size)) => {
+                if let Some(recording) = &mut recording {
+                    recording.received(&request_bytes[..size]);
+                }
+                request
+            }
+            Err(e) => {
+                warn!("Invalid request from {}: {}", address, e);
+                metrics.errors.inc();
+                return;
+            }
+        };
+
+        let request_start = Instant::now();
+        // TLS holds back the end of the response until flushed
+        let response = async {
+            let written = response::respond(&mut socket, &request).await?;
+            socket.flush().await?;
+            Ok::<_, std::io::Error>(written)
+        };
+        let written = match response.await {
+            Ok(written) => written,
+            Err(e) => {
+                warn!("Error writing to client {}: {}", address, e);
+                metrics.errors.inc();
+                return;
+            }
+        };
+        if let Some(recording) = &mut recording {
+            record_response(recording, &request);
+        }
+        metrics.requests.inc();
+        metrics.bytes_written.add(written as u64);
+        metrics
+            .request_duration
+            .observe(request_start.elapsed().as_secs_f64());
+
+        if request.extended {
+            info!(
+                "Wrote {} bytes of value {} in {} mode, and the CRC",
+                written, request.value, request.mode
+            );
+        } else {
+            info!("Wrote {} bytes of byte {}", written, request.value);
+        }
+    }
+}
+
+/// Records a response without building it in memory, as it may be large.
+fn record_response(recording: &mut TcpRecording, request: &Request) {
+    let mut pattern = request.pattern();
+    let mut crc = Crc32::new();
+    let mut segment = [0u8; MAX_SEGMENT];
+    let mut remaining = request.length as usize;
+    while remaining > 0 {
+        let n = remaining.min(MAX_SEGMENT);
+        pattern.fill(&mut segment[..n]);
+        crc.update(&segment[..n]);
+        recording.sent(&segment[..n]);
+        remaining -= n;
+    }
+    if request.extended {
+        recording.sent(&crc.finish().to_be_bytes());
+    }
+}
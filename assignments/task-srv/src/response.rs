@@ -0,0 +1,53 @@
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use wire::srv::{Crc32, Request};
+
+/// Size of the buffer the response is written from.
+pub const CHUNK_SIZE: usize = 8192;
+
+/// Writes `total` copies of `byte` to `writer` in chunks of [`CHUNK_SIZE`].
+///
+/// Returns the number of bytes written, which equals `total` unless writing fails.
+pub async fn write_response<W>(writer: &mut W, total: u32, byte: u8) -> std::io::Result<u32>
+where
+    W: AsyncWrite + Unpin,
+{
+    let mut written: u32 = 0;
+    let buffer = [byte; CHUNK_SIZE];
+
+    while written < total {
+        let remaining = total - written;
+        let to_write = remaining.min(buffer.len() as u32) as usize;
+        writer.write_all(&buffer[..to_write]).await?;
+        written += to_write as u32;
+    }
+
+    Ok(written)
+}
+
+/// Writes the response to `request`: the bytes in chunks of [`CHUNK_SIZE`],
+/// and with an extended request the CRC of them after.
+///
+/// Returns the number of bytes written before the CRC.
+pub async fn respond<W>(writer: &mut W, request: &Request) -> std::io::Result<u32>
+where
+    W: AsyncWrite + Unpin,
+{
+    if !request.extended {
+        return write_response(writer, request.length, request.value).await;
+    }
+
+    let mut written: u32 = 0;
+    let mut pattern = request.pattern();
+    let mut crc = Crc32::new();
+    let mut buffer = [0u8; CHUNK_SIZE];
+    while written < request.length {
+        let to_write = (request.length - written).min(buffer.len() as u32) as usize;
+        pattern.fill(&mut buffer[..to_write]);
+        crc.update(&buffer[..to_write]);
+        writer.write_all(&buffer[..to_write]).await?;
+        written += to_write as u32;
+    }
+    writer.write_all(&crc.finish().to_be_bytes()).await?;
+
+    Ok(written)
+}
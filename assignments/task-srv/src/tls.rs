@@ -0,0 +1,52 @@
+//! TLS for the listeners given with `--tls-port` and `--https-port`. The
+//! requests and responses are the same as on the plaintext listeners, inside
+//! the TLS stream. The certificate comes from `--tls-cert` and `--tls-key`, or
+//! without them is generated and self-signed at start, so that clients have to
+//! skip the verification (`adnet-cli --tls --insecure`).
+
+use std::{path::Path, sync::Arc};
+
+use rustls::{
+    crypto::ring,
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer},
+    ServerConfig,
+};
+use tokio_rustls::TlsAcceptor;
+use tracing::warn;
+
+use crate::SrvError;
+
+/// Names in the generated certificate
+const SELF_SIGNED_NAMES: [&str; 3] = ["localhost", "127.0.0.1", "::1"];
+
+/// Builds the acceptor for the TLS listeners from the PEM files of the
+/// certificate chain and its private key, or a self-signed certificate.
+pub(crate) fn acceptor(cert: Option<&Path>, key: Option<&Path>) -> Result<TlsAcceptor, SrvError> {
+    let (chain, key) = match (cert, key) {
+        (Some(cert), Some(key)) => {
+            let chain = CertificateDer::pem_file_iter(cert)
+                .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+                .map_err(|e| {
+                    SrvError::Tls(format!("Cannot read certificate {}: {}", cert.display(), e))
+                })?;
+            let key = PrivateKeyDer::from_pem_file(key).map_err(|e| {
+                SrvError::Tls(format!("Cannot read private key {}: {}", key.display(), e))
+            })?;
+            (chain, key)
+        }
+        _ => {
+            warn!("No --tls-cert given, using a self-signed certificate");
+            let names = SELF_SIGNED_NAMES.map(String::from).to_vec();
+            let generated = rcgen::generate_simple_self_signed(names)
+                .map_err(|e| SrvError::Tls(format!("Cannot generate certificate: {}", e)))?;
+            let key = PrivatePkcs8KeyDer::from(generated.key_pair.serialize_der());
+            (vec![generated.cert.der().clone()], key.into())
+        }
+    };
+
+    let config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .and_then(|builder| builder.with_no_client_auth().with_single_cert(chain, key))
+        .map_err(|e| SrvError::Tls(e.to_string()))?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
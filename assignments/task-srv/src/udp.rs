@@ -0,0 +1,137 @@
+//! The requests of the agent protocol over UDP, with `--udp-port`. A request
+//! is one datagram of the same 5 bytes as on TCP: the number of bytes (4
+//! bytes, network byte order) and the byte. The response goes in the packets
+//! of task-udp, a 4-byte sequence number from 1 and a 2-byte payload length
+//! before the payload, and the client acknowledges each packet as adnet-agent
+//! does those of task-udp. The packets are sent by task-udp's [`Sender`],
+//! from a socket of its own for each request, so the window, the pacing and
+//! the retransmissions follow the acknowledgements of that request alone.
+//!
+//! A request from a client whose previous response is still being sent is
+//! ignored, as it is most likely the same request sent again. Extended
+//! requests are ignored too, as the packets have no room for the CRC.
+
+use std::{
+    collections::HashSet,
+    io,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use adnet_core::shutdown::Shutdown;
+use netem::Impairment;
+use task_udp::{
+    proto::{Checknum, Payload, ProtocolConfig, Sender},
+    UdpError,
+};
+use tokio::{net::UdpSocket, sync::Semaphore, task};
+use tracing::{debug, info, info_span, warn, Instrument};
+use wire::srv::{Request, EXTENDED_REQUEST_SIZE};
+
+use crate::Metrics;
+
+/// Answers requests until receiving fails or the shutdown is requested. The
+/// responses are tracked for draining, and count towards the connection
+/// limit.
+pub(crate) async fn serve(
+    socket: UdpSocket,
+    client_timeout: Duration,
+    metrics: Metrics,
+    shutdown: Shutdown,
+    slots: Arc<Semaphore>,
+) -> io::Result<()> {
+    let sending: Arc<Mutex<HashSet<SocketAddr>>> = Arc::default();
+    let mut buf = [0u8; EXTENDED_REQUEST_SIZE + 1];
+    loop {
+        let Some(slot) = crate::connection_slot(&slots, &shutdown).await else {
+            return Ok(());
+        };
+        let (n, client) = tokio::select! {
+            received = socket.recv_from(&mut buf) => received?,
+            _ = shutdown.requested() => return Ok(()),
+        };
+        let (total, byte) = match Request::decode(&buf[..n]) {
+            Ok(request) if !request.extended => (request.length, request.value),
+            Ok(request) => {
+                warn!(
+                    "Ignoring extended request in {} mode from {}",
+                    request.mode, client
+                );
+                continue;
+            }
+            Err(e) => {
+                warn!(
+                    "Ignoring UDP datagram of {} bytes from {}: {}",
+                    n, client, e
+                );
+                continue;
+            }
+        };
+        if !sending.lock().unwrap().insert(client) {
+            debug!("Ignoring repeated request from {}", client);
+            continue;
+        }
+        info!(
+            "UDP request for {} bytes of byte {} from {}",
+            total, byte, client
+        );
+
+        let span = info_span!("udp_client", peer = %client);
+        let metrics = metrics.clone();
+        let sending = sending.clone();
+        let in_flight = shutdown.track();
+        let response = async move {
+            metrics.active_connections.inc();
+            let start = Instant::now();
+            match respond(client, total, byte, client_timeout).await {
+                Ok(checknum) => {
+                    metrics.requests.inc();
+                    metrics.bytes_written.add(total as u64);
+                    metrics
+                        .request_duration
+                        .observe(start.elapsed().as_secs_f64());
+                    info!(
+                        "Wrote {} bytes of byte {} over UDP, checknum {}",
+                        total, byte, checknum
+                    );
+                }
+                Err(UdpError::Timeout(timeout)) => {
+                    warn!("UDP client {} timed out after {:?}", client, timeout);
+                    metrics.timeouts.inc();
+                }
+                Err(e) => {
+                    warn!("Error sending to UDP client {}: {}", client, e);
+                    metrics.errors.inc();
+                }
+            }
+            metrics.active_connections.dec();
+            sending.lock().unwrap().remove(&client);
+            drop(slot);
+            drop(in_flight);
+        };
+        task::spawn(response.instrument(span));
+    }
+}
+
+/// Sends `total` copies of `byte` to the client until all are acknowledged,
+/// and returns the checknum of the last acknowledgement.
+async fn respond(
+    client: SocketAddr,
+    total: u32,
+    byte: u8,
+    timeout: Duration,
+) -> Result<Checknum, UdpError> {
+    let config = ProtocolConfig {
+        timeout,
+        ..ProtocolConfig::default()
+    };
+    let mut sender = Sender::bind(client, Impairment::default(), config).await?;
+    let payload = Payload::Repeated {
+        size: total as usize,
+        character: byte,
+    };
+    // Without a shutdown of its own, the response continues while the open
+    // connections drain, as on TCP
+    sender.send_all(&payload, &Shutdown::new()).await
+}
@@ -0,0 +1,251 @@
+//! STUN client: asks servers for the address a socket is seen from, and
+//! tells from the answers what kind of NAT is in between.
+
+use std::{
+    io,
+    net::{SocketAddr, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use adnet_core::report::Report;
+use serde::Serialize;
+use tracing::{debug, info};
+
+use crate::{
+    message::{self, Class, Message, BINDING},
+    StunError,
+};
+
+/// Retransmission of requests, in the manner of RFC 5389: each
+/// retransmission waits twice as long as the one before.
+#[derive(Clone, Copy, Debug)]
+pub struct Settings {
+    /// Wait for the answer to the first request
+    pub timeout: Duration,
+    pub retries: u32,
+}
+
+/// The answer of one server.
+#[derive(Clone, Debug, Serialize)]
+pub struct Binding {
+    pub server: SocketAddr,
+    /// Where the server saw the request come from
+    pub mapped: SocketAddr,
+    /// Seconds from the last request sent to the answer
+    pub rtt: f64,
+    pub software: Option<String>,
+}
+
+/// Sends a Binding request from `socket` to `server` and returns the mapped
+/// address of the socket. The socket may carry other traffic, too: packets
+/// that are not the answer are skipped.
+pub fn binding(
+    socket: &UdpSocket,
+    server: SocketAddr,
+    settings: Settings,
+) -> Result<Binding, StunError> {
+    let request = Message::binding_request();
+    let encoded = request.encode();
+    let mut timeout = settings.timeout;
+    let mut buf = [0u8; 1500];
+    for attempt in 0..=settings.retries {
+        if attempt > 0 {
+            debug!("Retransmitting to {} ({})", server, attempt);
+        }
+        socket.send_to(&encoded, server)?;
+        let sent = Instant::now();
+        let deadline = sent + timeout;
+        while let Some(left) = deadline
+            .checked_duration_since(Instant::now())
+            .filter(|left| !left.is_zero())
+        {
+            socket.set_read_timeout(Some(left))?;
+            let (n, from) = match socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    break;
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            };
+            if from != server || !message::is_stun(&buf[..n]) {
+                debug!("Ignoring {} bytes from {}", n, from);
+                continue;
+            }
+            let answer = match Message::decode(&buf[..n]) {
+                Ok(answer) => answer,
+                Err(e) => {
+                    debug!("Ignoring message from {}: {}", from, e);
+                    continue;
+                }
+            };
+            // Answers to earlier transactions, or retransmissions of them
+            if answer.transaction != request.transaction || answer.method != BINDING {
+                continue;
+            }
+            match answer.class {
+                Class::Success => {
+                    let mapped = answer.mapped_address().ok_or_else(|| {
+                        StunError::Protocol(format!("{} answered without an address", server))
+                    })?;
+                    return Ok(Binding {
+                        server,
+                        mapped,
+                        rtt: sent.elapsed().as_secs_f64(),
+                        software: answer.software().map(str::to_string),
+                    });
+                }
+                Class::Error => {
+                    let (code, reason) = answer.error_code().unwrap_or((0, "no error code"));
+                    return Err(StunError::Rejected {
+                        server,
+                        code,
+                        reason: reason.to_string(),
+                    });
+                }
+                _ => debug!("Ignoring {:?} from {}", answer.class, from),
+            }
+        }
+        timeout *= 2;
+    }
+    Err(StunError::Timeout {
+        server,
+        attempts: settings.retries + 1,
+    })
+}
+
+/// The address of the interface that traffic to `server` leaves from, for
+/// a socket bound to the unspecified address. Connecting a UDP socket sends
+/// nothing, it only looks up the route.
+fn local_address(socket: &UdpSocket, server: SocketAddr) -> io::Result<SocketAddr> {
+    let local = socket.local_addr()?;
+    if !local.ip().is_unspecified() {
+        return Ok(local);
+    }
+    let probe = UdpSocket::bind(SocketAddr::new(local.ip(), 0))?;
+    probe.connect(server)?;
+    Ok(SocketAddr::new(probe.local_addr()?.ip(), local.port()))
+}
+
+/// What the mappings tell about the path to the servers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Nat {
+    /// The servers see the local address itself
+    None,
+    /// All servers see the same mapped address, so that a peer can reach
+    /// the socket at it once the socket has sent to the peer
+    EndpointIndependent,
+    /// Each server sees another mapped address, as behind a symmetric NAT,
+    /// where hole punching does not work
+    EndpointDependent,
+    /// A single server cannot tell the last two apart
+    Unknown,
+}
+
+impl Nat {
+    fn classify(local: SocketAddr, bindings: &[Binding]) -> Self {
+        let Some(first) = bindings.first() else {
+            return Nat::Unknown;
+        };
+        if bindings.iter().any(|b| b.mapped != first.mapped) {
+            Nat::EndpointDependent
+        } else if first.mapped == local {
+            Nat::None
+        } else if bindings.len() > 1 {
+            Nat::EndpointIndependent
+        } else {
+            Nat::Unknown
+        }
+    }
+
+    fn describe(&self, mapped: SocketAddr) -> String {
+        match self {
+            Nat::None => format!("No NAT: peers can reach this socket at {}", mapped),
+            Nat::EndpointIndependent => format!(
+                "Endpoint-independent mapping: peers can reach this socket at {} once it has sent to them",
+                mapped
+            ),
+            Nat::EndpointDependent => {
+                "Endpoint-dependent mapping (symmetric NAT): each peer sees another address, \
+                 so hole punching will not work"
+                    .to_string()
+            }
+            Nat::Unknown => format!(
+                "Mapped to {}; ask two servers at different addresses to classify the NAT",
+                mapped
+            ),
+        }
+    }
+}
+
+/// Asks each server in turn for the mapping of a socket bound at `bind`,
+/// and prints the mappings and what they tell. With `quiet`, prints only
+/// the first mapped address, for scripts.
+pub fn discover(
+    bind: SocketAddr,
+    servers: &[SocketAddr],
+    settings: Settings,
+    quiet: bool,
+    report: &mut Report,
+) -> Result<(), StunError> {
+    let socket = UdpSocket::bind(bind).map_err(|source| StunError::Bind {
+        address: bind,
+        source,
+    })?;
+    let local = local_address(&socket, servers[0])?;
+    info!("Discovering the mapping of {}", local);
+    report.detail("local", local);
+
+    let mut bindings = Vec::new();
+    let mut last_error = None;
+    for &server in servers {
+        match binding(&socket, server, settings) {
+            Ok(binding) => {
+                if !quiet {
+                    println!(
+                        "{}: mapped {} in {:.1} ms{}",
+                        server,
+                        binding.mapped,
+                        binding.rtt * 1000.0,
+                        match &binding.software {
+                            Some(software) => format!(" ({})", software),
+                            None => String::new(),
+                        }
+                    );
+                }
+                bindings.push(binding);
+            }
+            Err(e) => {
+                if !quiet {
+                    println!("{}: {}", server, e);
+                }
+                report.errors += 1;
+                last_error = Some(e);
+            }
+        }
+    }
+    report.detail("bindings", &bindings);
+
+    let Some(first) = bindings.first() else {
+        return Err(last_error.expect("no servers"));
+    };
+    let nat = Nat::classify(local, &bindings);
+    report.detail("mapped", first.mapped);
+    report.detail("nat", nat);
+    if quiet {
+        println!("{}", first.mapped);
+        return Ok(());
+    }
+    println!("--- {} ---", local);
+    println!("{}", nat.describe(first.mapped));
+    if nat != Nat::None && first.mapped.port() == local.port() {
+        println!("The NAT preserves the port");
+    }
+    Ok(())
+}
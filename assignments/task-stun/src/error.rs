@@ -0,0 +1,48 @@
+use std::{io, net::SocketAddr};
+
+use adnet_core::exit::{Code, HasCode};
+use thiserror::Error;
+
+/// Errors of task-stun. Each kind has its own exit code, see
+/// [`adnet_core::exit`].
+#[derive(Debug, Error)]
+pub enum StunError {
+    /// Invalid or missing options
+    #[error("{0}")]
+    Usage(String),
+    /// The config file could not be read
+    #[error(transparent)]
+    Config(io::Error),
+    #[error("Cannot bind {address}: {source}")]
+    Bind {
+        address: SocketAddr,
+        source: io::Error,
+    },
+    /// The server answered with an error response
+    #[error("{server} answered with error {code}: {reason}")]
+    Rejected {
+        server: SocketAddr,
+        code: u16,
+        reason: String,
+    },
+    /// The server answered without a mapped address
+    #[error("{0}")]
+    Protocol(String),
+    /// The server did not answer any of the retransmissions
+    #[error("No answer from {server} to {attempts} requests")]
+    Timeout { server: SocketAddr, attempts: u32 },
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+impl HasCode for StunError {
+    fn exit_code(&self) -> Code {
+        match self {
+            StunError::Usage(_) | StunError::Config(_) => Code::Usage,
+            StunError::Bind { .. } => Code::Failure,
+            StunError::Rejected { .. } | StunError::Protocol(_) => Code::Protocol,
+            StunError::Timeout { .. } => Code::Timeout,
+            StunError::Io(e) => e.exit_code(),
+        }
+    }
+}
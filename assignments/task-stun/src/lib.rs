@@ -0,0 +1,191 @@
+//! STUN (RFC 5389) client for NAT discovery, and a server to try it against.
+//! A host behind a NAT does not know the public address and port its UDP
+//! socket is seen from; a STUN server tells it. Asking two servers also
+//! shows whether the NAT keeps one mapping for all destinations, which is
+//! what lets two hosts behind NATs reach each other by hole punching.
+//!
+//! Other programs can use [`client::binding`] on their own socket, e.g. a
+//! tunnel that has to learn its public address before giving it to its
+//! peer. The task-stun binary and the `adnet stun` subcommand are thin
+//! wrappers around [`run`].
+
+pub mod client;
+mod error;
+pub mod message;
+mod server;
+
+use std::{
+    io,
+    net::{SocketAddr, ToSocketAddrs},
+    time::Duration,
+};
+
+use adnet_core::{
+    config::{self, ConfigArgs},
+    logging::{self, LogArgs},
+    metrics::{self, MetricsArgs},
+    report::{Report, ReportArgs},
+    shutdown,
+};
+use clap::{Parser, Subcommand};
+use client::Settings;
+use serde::Deserialize;
+
+pub use error::StunError;
+
+const STUN_PORT: u16 = 3478;
+const DEFAULT_TIMEOUT: Duration = Duration::from_millis(500);
+const DEFAULT_RETRIES: u32 = 4;
+/// The SOFTWARE attribute of the messages sent
+const SOFTWARE: &str = concat!("task-stun ", env!("CARGO_PKG_VERSION"));
+
+/// Discovers the public address of a UDP socket with STUN, or answers STUN requests.
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+pub struct Args {
+    #[command(subcommand)]
+    mode: Mode,
+
+    #[command(flatten)]
+    config: ConfigArgs,
+
+    #[command(flatten)]
+    log: LogArgs,
+
+    #[command(flatten)]
+    metrics: MetricsArgs,
+
+    #[command(flatten)]
+    report: ReportArgs,
+}
+
+#[derive(Subcommand, Debug)]
+enum Mode {
+    /// Answer Binding requests with the address they came from
+    Server {
+        /// Address to listen at [default: 0.0.0.0:3478]
+        #[arg(short, long)]
+        listen: Option<SocketAddr>,
+    },
+
+    /// Ask STUN servers for the mapped address of a socket
+    Client {
+        /// STUN server as host or host:port [default port: 3478]; give two
+        /// at different addresses to classify the NAT
+        #[arg(short, long)]
+        server: Vec<String>,
+
+        /// Local address of the socket, e.g. the --udpbind of task-tun
+        /// [default: 0.0.0.0:0]
+        #[arg(short, long)]
+        bind: Option<SocketAddr>,
+
+        /// Seconds to wait for the first answer, doubled on each
+        /// retransmission [default: 0.5]
+        #[arg(short, long, value_parser = config::parse_secs)]
+        timeout: Option<Duration>,
+
+        /// Retransmissions of a request before giving up [default: 4]
+        #[arg(short, long)]
+        retries: Option<u32>,
+
+        /// Print only the mapped address
+        #[arg(short, long)]
+        quiet: bool,
+    },
+}
+
+/// The [task-stun] section of the config file. Each mode uses its own options.
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct FileConfig {
+    listen: Option<SocketAddr>,
+    servers: Vec<String>,
+    bind: Option<SocketAddr>,
+    #[serde(deserialize_with = "config::secs")]
+    timeout: Option<Duration>,
+    retries: Option<u32>,
+}
+
+/// Resolves the server given as host or host:port to an address of the
+/// family of the local socket.
+fn resolve(server: &str, ipv6: bool) -> io::Result<SocketAddr> {
+    let with_port = match server.rsplit_once(':') {
+        Some((_, port)) if port.parse::<u16>().is_ok() && !server.ends_with(']') => {
+            server.to_string()
+        }
+        _ => format!("{}:{}", server, STUN_PORT),
+    };
+    with_port
+        .to_socket_addrs()?
+        .find(|address| address.is_ipv6() == ipv6)
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!(
+                    "{} did not resolve to any IPv{} address",
+                    server,
+                    if ipv6 { 6 } else { 4 }
+                ),
+            )
+        })
+}
+
+/// Runs the client or server with the given arguments, as the task-stun binary does.
+pub fn run(args: Args) -> Result<(), StunError> {
+    logging::init(&args.log)?;
+    metrics::init(&args.metrics)?;
+    let report_args = args.report.clone();
+    let mut report = Report::new("task-stun");
+    let result = stun(args, &mut report);
+    report_args.finish(&mut report, result)
+}
+
+fn stun(args: Args, report: &mut Report) -> Result<(), StunError> {
+    // Command line options take precedence over the config file
+    let file: FileConfig = args
+        .config
+        .section("task-stun")
+        .map_err(StunError::Config)?;
+    match args.mode {
+        Mode::Server { listen } => {
+            let listen = listen
+                .or(file.listen)
+                .unwrap_or_else(|| SocketAddr::from(([0, 0, 0, 0], STUN_PORT)));
+            report.detail("mode", "server");
+            server::serve(listen, &shutdown::install()?, report)
+        }
+        Mode::Client {
+            server,
+            bind,
+            timeout,
+            retries,
+            quiet,
+        } => {
+            let servers = if server.is_empty() {
+                file.servers
+            } else {
+                server
+            };
+            if servers.is_empty() {
+                return Err(StunError::Usage(
+                    "At least one server is required (--server)".to_string(),
+                ));
+            }
+            let bind = bind
+                .or(file.bind)
+                .unwrap_or_else(|| SocketAddr::from(([0, 0, 0, 0], 0)));
+            let servers = servers
+                .iter()
+                .map(|server| resolve(server, bind.is_ipv6()))
+                .collect::<io::Result<Vec<_>>>()?;
+            let settings = Settings {
+                timeout: timeout.or(file.timeout).unwrap_or(DEFAULT_TIMEOUT),
+                retries: retries.or(file.retries).unwrap_or(DEFAULT_RETRIES),
+            };
+            report.detail("mode", "client");
+            report.detail("bind", bind);
+            client::discover(bind, &servers, settings, quiet, report)
+        }
+    }
+}
@@ -0,0 +1,8 @@
+use adnet_core::exit;
+use clap::Parser;
+use std::process::ExitCode;
+use task_stun::Args;
+
+fn main() -> ExitCode {
+    exit::exit(task_stun::run(Args::parse()))
+}
@@ -0,0 +1,537 @@
+//! STUN messages of RFC 5389, as far as Binding requests and their answers
+//! need them:
+//!
+//! ```text
+//! |0 0| message type (14) | message length (16)      |
+//! |           magic cookie 0x2112A442 (32)           |
+//! |           transaction ID (96)                    |
+//! | attribute type (16)   | attribute length (16)    |
+//! | value, padded to a multiple of 4 bytes ...       |
+//! ```
+//!
+//! Every message sent ends with a FINGERPRINT, which tells STUN apart from
+//! other traffic on the same socket, such as the packets of a tunnel.
+
+use std::{
+    collections::hash_map::RandomState,
+    fmt,
+    hash::{BuildHasher, Hasher},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+pub const MAGIC_COOKIE: u32 = 0x2112_a442;
+pub const HEADER_SIZE: usize = 20;
+/// The only method of RFC 5389
+pub const BINDING: u16 = 0x001;
+
+const MAPPED_ADDRESS: u16 = 0x0001;
+const ERROR_CODE: u16 = 0x0009;
+const XOR_MAPPED_ADDRESS: u16 = 0x0020;
+const SOFTWARE: u16 = 0x8022;
+const FINGERPRINT: u16 = 0x8028;
+const FINGERPRINT_XOR: u32 = 0x5354_554e;
+const FAMILY_IPV4: u8 = 1;
+const FAMILY_IPV6: u8 = 2;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Class {
+    Request = 0b00,
+    Indication = 0b01,
+    Success = 0b10,
+    Error = 0b11,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Attribute {
+    MappedAddress(SocketAddr),
+    /// The mapped address XORed with the magic cookie, so that NATs that
+    /// rewrite addresses in payloads leave it alone
+    XorMappedAddress(SocketAddr),
+    ErrorCode {
+        code: u16,
+        reason: String,
+    },
+    Software(String),
+    /// Any other attribute, kept as it is
+    Other {
+        kind: u16,
+        value: Vec<u8>,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Message {
+    pub class: Class,
+    pub method: u16,
+    pub transaction: [u8; 12],
+    pub attributes: Vec<Attribute>,
+}
+
+/// Why a datagram is not a valid STUN message.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    /// Not STUN at all: too short, or without the magic cookie
+    NotStun,
+    Length,
+    Attribute(u16),
+    Fingerprint,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::NotStun => write!(f, "not a STUN message"),
+            DecodeError::Length => write!(f, "message length does not match"),
+            DecodeError::Attribute(kind) => write!(f, "invalid attribute 0x{:04x}", kind),
+            DecodeError::Fingerprint => write!(f, "wrong fingerprint"),
+        }
+    }
+}
+
+/// Whether a datagram looks like STUN, for sockets that carry other traffic,
+/// too. [`Message::decode`] checks the rest.
+pub fn is_stun(buf: &[u8]) -> bool {
+    buf.len() >= HEADER_SIZE
+        && buf[0] & 0xc0 == 0
+        && u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]) == MAGIC_COOKIE
+}
+
+/// A new random transaction ID.
+pub fn transaction_id() -> [u8; 12] {
+    let mut id = [0u8; 12];
+    for chunk in id.chunks_mut(8) {
+        let random = RandomState::new().build_hasher().finish().to_be_bytes();
+        chunk.copy_from_slice(&random[..chunk.len()]);
+    }
+    id
+}
+
+/// The class and method bits interleave in the message type:
+/// M11-M7, C1, M6-M4, C0, M3-M0.
+fn message_type(class: Class, method: u16) -> u16 {
+    let class = class as u16;
+    ((method & 0xf80) << 2)
+        | ((class & 0b10) << 7)
+        | ((method & 0x070) << 1)
+        | ((class & 0b01) << 4)
+        | (method & 0x00f)
+}
+
+fn split_type(kind: u16) -> (Class, u16) {
+    let method = ((kind >> 2) & 0xf80) | ((kind >> 1) & 0x070) | (kind & 0x00f);
+    let class = match ((kind >> 7) & 0b10) | ((kind >> 4) & 0b01) {
+        0b00 => Class::Request,
+        0b01 => Class::Indication,
+        0b10 => Class::Success,
+        _ => Class::Error,
+    };
+    (class, method)
+}
+
+/// CRC-32 of ISO HDLC, as used by Ethernet and zlib.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+impl Message {
+    pub fn new(class: Class, method: u16, transaction: [u8; 12]) -> Self {
+        Self {
+            class,
+            method,
+            transaction,
+            attributes: Vec::new(),
+        }
+    }
+
+    /// A Binding request with a new transaction ID.
+    pub fn binding_request() -> Self {
+        Self::new(Class::Request, BINDING, transaction_id())
+    }
+
+    /// The mapped address of a response, preferring XOR-MAPPED-ADDRESS over
+    /// the MAPPED-ADDRESS of old servers.
+    pub fn mapped_address(&self) -> Option<SocketAddr> {
+        let xor = self.attributes.iter().find_map(|a| match a {
+            Attribute::XorMappedAddress(address) => Some(*address),
+            _ => None,
+        });
+        xor.or_else(|| {
+            self.attributes.iter().find_map(|a| match a {
+                Attribute::MappedAddress(address) => Some(*address),
+                _ => None,
+            })
+        })
+    }
+
+    pub fn error_code(&self) -> Option<(u16, &str)> {
+        self.attributes.iter().find_map(|a| match a {
+            Attribute::ErrorCode { code, reason } => Some((*code, reason.as_str())),
+            _ => None,
+        })
+    }
+
+    pub fn software(&self) -> Option<&str> {
+        self.attributes.iter().find_map(|a| match a {
+            Attribute::Software(software) => Some(software.as_str()),
+            _ => None,
+        })
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(128);
+        buf.extend_from_slice(&message_type(self.class, self.method).to_be_bytes());
+        buf.extend_from_slice(&[0, 0]);
+        buf.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+        buf.extend_from_slice(&self.transaction);
+        for attribute in &self.attributes {
+            let (kind, value) = self.encode_attribute(attribute);
+            push_attribute(&mut buf, kind, &value);
+        }
+
+        // The fingerprint covers the header with the length including the
+        // fingerprint itself
+        let length = (buf.len() - HEADER_SIZE + 8) as u16;
+        buf[2..4].copy_from_slice(&length.to_be_bytes());
+        let fingerprint = crc32(&buf) ^ FINGERPRINT_XOR;
+        push_attribute(&mut buf, FINGERPRINT, &fingerprint.to_be_bytes());
+        buf
+    }
+
+    fn encode_attribute(&self, attribute: &Attribute) -> (u16, Vec<u8>) {
+        match attribute {
+            Attribute::MappedAddress(address) => (MAPPED_ADDRESS, encode_address(*address)),
+            Attribute::XorMappedAddress(address) => (
+                XOR_MAPPED_ADDRESS,
+                encode_address(self.xor_address(*address)),
+            ),
+            Attribute::ErrorCode { code, reason } => {
+                let mut value = vec![0, 0, (code / 100) as u8, (code % 100) as u8];
+                value.extend_from_slice(reason.as_bytes());
+                (ERROR_CODE, value)
+            }
+            Attribute::Software(software) => (SOFTWARE, software.as_bytes().to_vec()),
+            Attribute::Other { kind, value } => (*kind, value.clone()),
+        }
+    }
+
+    /// XORs the port with the top half of the magic cookie and the address
+    /// with the cookie, followed by the transaction ID for IPv6. Its own
+    /// inverse.
+    fn xor_address(&self, address: SocketAddr) -> SocketAddr {
+        let port = address.port() ^ (MAGIC_COOKIE >> 16) as u16;
+        let ip: IpAddr = match address.ip() {
+            IpAddr::V4(ip) => Ipv4Addr::from(u32::from(ip) ^ MAGIC_COOKIE).into(),
+            IpAddr::V6(ip) => {
+                let mut key = [0u8; 16];
+                key[..4].copy_from_slice(&MAGIC_COOKIE.to_be_bytes());
+                key[4..].copy_from_slice(&self.transaction);
+                let mut octets = ip.octets();
+                for (octet, key) in octets.iter_mut().zip(key) {
+                    *octet ^= key;
+                }
+                Ipv6Addr::from(octets).into()
+            }
+        };
+        SocketAddr::new(ip, port)
+    }
+
+    /// Decodes a message, checking its fingerprint if it has one.
+    pub fn decode(buf: &[u8]) -> Result<Self, DecodeError> {
+        if !is_stun(buf) {
+            return Err(DecodeError::NotStun);
+        }
+        let length = u16::from_be_bytes([buf[2], buf[3]]) as usize;
+        if !length.is_multiple_of(4) || buf.len() != HEADER_SIZE + length {
+            return Err(DecodeError::Length);
+        }
+        let (class, method) = split_type(u16::from_be_bytes([buf[0], buf[1]]));
+        let mut message = Message::new(class, method, buf[8..20].try_into().unwrap());
+
+        let mut at = HEADER_SIZE;
+        while at < buf.len() {
+            if buf.len() - at < 4 {
+                return Err(DecodeError::Length);
+            }
+            let kind = u16::from_be_bytes([buf[at], buf[at + 1]]);
+            let size = u16::from_be_bytes([buf[at + 2], buf[at + 3]]) as usize;
+            let start = at + 4;
+            let end = start + size;
+            if end > buf.len() {
+                return Err(DecodeError::Length);
+            }
+            let value = &buf[start..end];
+            let invalid = || DecodeError::Attribute(kind);
+            match kind {
+                MAPPED_ADDRESS => {
+                    let address = decode_address(value).ok_or_else(invalid)?;
+                    message.attributes.push(Attribute::MappedAddress(address));
+                }
+                XOR_MAPPED_ADDRESS => {
+                    let address = decode_address(value).ok_or_else(invalid)?;
+                    let address = message.xor_address(address);
+                    message
+                        .attributes
+                        .push(Attribute::XorMappedAddress(address));
+                }
+                ERROR_CODE => {
+                    if value.len() < 4 {
+                        return Err(invalid());
+                    }
+                    let code = (value[2] & 0x07) as u16 * 100 + value[3] as u16;
+                    let reason = String::from_utf8_lossy(&value[4..]).into_owned();
+                    message
+                        .attributes
+                        .push(Attribute::ErrorCode { code, reason });
+                }
+                SOFTWARE => {
+                    let software = String::from_utf8_lossy(value).into_owned();
+                    message.attributes.push(Attribute::Software(software));
+                }
+                FINGERPRINT => {
+                    // The fingerprint is the last attribute, so the length
+                    // in the header already covers it
+                    if value.len() != 4 || end != buf.len() {
+                        return Err(invalid());
+                    }
+                    let expected = crc32(&buf[..at]) ^ FINGERPRINT_XOR;
+                    if u32::from_be_bytes(value.try_into().unwrap()) != expected {
+                        return Err(DecodeError::Fingerprint);
+                    }
+                }
+                _ => message.attributes.push(Attribute::Other {
+                    kind,
+                    value: value.to_vec(),
+                }),
+            }
+            at = end.next_multiple_of(4);
+        }
+        Ok(message)
+    }
+}
+
+fn push_attribute(buf: &mut Vec<u8>, kind: u16, value: &[u8]) {
+    buf.extend_from_slice(&kind.to_be_bytes());
+    buf.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    buf.extend_from_slice(value);
+    buf.resize(buf.len().next_multiple_of(4), 0);
+}
+
+fn encode_address(address: SocketAddr) -> Vec<u8> {
+    let mut value = vec![0];
+    match address.ip() {
+        IpAddr::V4(ip) => {
+            value.push(FAMILY_IPV4);
+            value.extend_from_slice(&address.port().to_be_bytes());
+            value.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            value.push(FAMILY_IPV6);
+            value.extend_from_slice(&address.port().to_be_bytes());
+            value.extend_from_slice(&ip.octets());
+        }
+    }
+    value
+}
+
+fn decode_address(value: &[u8]) -> Option<SocketAddr> {
+    if value.len() < 4 {
+        return None;
+    }
+    let port = u16::from_be_bytes([value[2], value[3]]);
+    let ip: IpAddr = match (value[1], &value[4..]) {
+        (FAMILY_IPV4, &[a, b, c, d]) => Ipv4Addr::new(a, b, c, d).into(),
+        (FAMILY_IPV6, octets) if octets.len() == 16 => {
+            Ipv6Addr::from(<[u8; 16]>::try_from(octets).ok()?).into()
+        }
+        _ => return None,
+    };
+    Some(SocketAddr::new(ip, port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRANSACTION: [u8; 12] = [
+        0xb7, 0xe7, 0xa7, 0x01, 0xbc, 0x34, 0xd6, 0x86, 0xfa, 0x87, 0xdf, 0xae,
+    ];
+
+    // The IPv4 response of RFC 5769 2.2, with a MESSAGE-INTEGRITY that this
+    // client keeps as it is
+    const RFC5769_IPV4: [u8; 80] = [
+        0x01, 0x01, 0x00, 0x3c, 0x21, 0x12, 0xa4, 0x42, 0xb7, 0xe7, 0xa7, 0x01, 0xbc, 0x34, 0xd6,
+        0x86, 0xfa, 0x87, 0xdf, 0xae, 0x80, 0x22, 0x00, 0x0b, 0x74, 0x65, 0x73, 0x74, 0x20, 0x76,
+        0x65, 0x63, 0x74, 0x6f, 0x72, 0x20, 0x00, 0x20, 0x00, 0x08, 0x00, 0x01, 0xa1, 0x47, 0xe1,
+        0x12, 0xa6, 0x43, 0x00, 0x08, 0x00, 0x14, 0x2b, 0x91, 0xf5, 0x99, 0xfd, 0x9e, 0x90, 0xc3,
+        0x8c, 0x74, 0x89, 0xf9, 0x2a, 0xf9, 0xba, 0x53, 0xf0, 0x6b, 0xe7, 0xd7, 0x80, 0x28, 0x00,
+        0x04, 0xc0, 0x7d, 0x4c, 0x96,
+    ];
+
+    // The IPv6 response of RFC 5769 2.3
+    const RFC5769_IPV6: [u8; 92] = [
+        0x01, 0x01, 0x00, 0x48, 0x21, 0x12, 0xa4, 0x42, 0xb7, 0xe7, 0xa7, 0x01, 0xbc, 0x34, 0xd6,
+        0x86, 0xfa, 0x87, 0xdf, 0xae, 0x80, 0x22, 0x00, 0x0b, 0x74, 0x65, 0x73, 0x74, 0x20, 0x76,
+        0x65, 0x63, 0x74, 0x6f, 0x72, 0x20, 0x00, 0x20, 0x00, 0x14, 0x00, 0x02, 0xa1, 0x47, 0x01,
+        0x13, 0xa9, 0xfa, 0xa5, 0xd3, 0xf1, 0x79, 0xbc, 0x25, 0xf4, 0xb5, 0xbe, 0xd2, 0xb9, 0xd9,
+        0x00, 0x08, 0x00, 0x14, 0xa3, 0x82, 0x95, 0x4e, 0x4b, 0xe6, 0x7b, 0xf1, 0x17, 0x84, 0xc9,
+        0x7c, 0x82, 0x92, 0xc2, 0x75, 0xbf, 0xe3, 0xed, 0x41, 0x80, 0x28, 0x00, 0x04, 0xc8, 0xfb,
+        0x0b, 0x4c,
+    ];
+
+    /// A message of the given attributes, as raw bytes without a
+    /// fingerprint.
+    fn raw(attributes: &[u8]) -> Vec<u8> {
+        let mut buf = vec![0x01, 0x01];
+        buf.extend_from_slice(&(attributes.len() as u16).to_be_bytes());
+        buf.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+        buf.extend_from_slice(&TRANSACTION);
+        buf.extend_from_slice(attributes);
+        buf
+    }
+
+    #[test]
+    fn rfc5769_ipv4_response() {
+        let message = Message::decode(&RFC5769_IPV4).unwrap();
+        assert_eq!(message.class, Class::Success);
+        assert_eq!(message.method, BINDING);
+        assert_eq!(message.transaction, TRANSACTION);
+        assert_eq!(message.software(), Some("test vector"));
+        assert_eq!(
+            message.mapped_address(),
+            Some("192.0.2.1:32853".parse().unwrap())
+        );
+        assert!(matches!(
+            &message.attributes[2],
+            Attribute::Other { kind: 0x0008, value } if value.len() == 20
+        ));
+    }
+
+    #[test]
+    fn rfc5769_ipv6_response() {
+        let message = Message::decode(&RFC5769_IPV6).unwrap();
+        assert_eq!(
+            message.mapped_address(),
+            Some(
+                "[2001:db8:1234:5678:11:2233:4455:6677]:32853"
+                    .parse()
+                    .unwrap()
+            )
+        );
+    }
+
+    #[test]
+    fn message_types() {
+        assert_eq!(message_type(Class::Request, BINDING), 0x0001);
+        assert_eq!(message_type(Class::Indication, BINDING), 0x0011);
+        assert_eq!(message_type(Class::Success, BINDING), 0x0101);
+        assert_eq!(message_type(Class::Error, BINDING), 0x0111);
+        for kind in [0x0001, 0x0011, 0x0101, 0x0111, 0x3eef] {
+            let (class, method) = split_type(kind);
+            assert_eq!(message_type(class, method), kind);
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut message = Message::new(Class::Success, BINDING, TRANSACTION);
+        message.attributes = vec![
+            Attribute::XorMappedAddress("203.0.113.7:40000".parse().unwrap()),
+            Attribute::XorMappedAddress("[2001:db8::7]:40000".parse().unwrap()),
+            Attribute::MappedAddress("203.0.113.7:40000".parse().unwrap()),
+            // Lengths that need padding
+            Attribute::Software("adnet".to_string()),
+            Attribute::Other {
+                kind: 0x8023,
+                value: vec![1, 2, 3],
+            },
+        ];
+        let buf = message.encode();
+        assert_eq!(buf.len() % 4, 0);
+        assert_eq!(Message::decode(&buf), Ok(message));
+
+        let mut error = Message::new(Class::Error, BINDING, TRANSACTION);
+        error.attributes = vec![Attribute::ErrorCode {
+            code: 420,
+            reason: "Unknown Attribute".to_string(),
+        }];
+        let decoded = Message::decode(&error.encode()).unwrap();
+        assert_eq!(decoded.error_code(), Some((420, "Unknown Attribute")));
+    }
+
+    #[test]
+    fn xor_mapped_address_on_the_wire() {
+        let mut message = Message::new(Class::Success, BINDING, TRANSACTION);
+        message.attributes = vec![Attribute::XorMappedAddress(
+            "192.0.2.1:32853".parse().unwrap(),
+        )];
+        // The same bytes as in the RFC 5769 vector
+        assert_eq!(message.encode()[20..32], RFC5769_IPV4[36..48]);
+    }
+
+    #[test]
+    fn truncated_attribute() {
+        // An XOR-MAPPED-ADDRESS header that promises more than is left
+        let buf = raw(&[0x00, 0x20, 0x00, 0x08, 0x00, 0x01, 0xa1, 0x47]);
+        assert_eq!(Message::decode(&buf), Err(DecodeError::Length));
+        // An address without its last octet
+        let buf = raw(&[
+            0x00, 0x20, 0x00, 0x07, 0x00, 0x01, 0xa1, 0x47, 0xe1, 0x12, 0xa6, 0,
+        ]);
+        assert_eq!(Message::decode(&buf), Err(DecodeError::Attribute(0x0020)));
+        // An error code without its number
+        let buf = raw(&[0x00, 0x09, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00]);
+        assert_eq!(Message::decode(&buf), Err(DecodeError::Attribute(0x0009)));
+    }
+
+    #[test]
+    fn header_length() {
+        let buf = Message::binding_request().encode();
+        assert_eq!(
+            Message::decode(&buf[..buf.len() - 4]),
+            Err(DecodeError::Length)
+        );
+        let mut odd = raw(&[0x80, 0x22, 0x00, 0x01, b'x', 0, 0, 0]);
+        odd[3] = 5;
+        odd.truncate(HEADER_SIZE + 5);
+        assert_eq!(Message::decode(&odd), Err(DecodeError::Length));
+    }
+
+    #[test]
+    fn fingerprint() {
+        let mut buf = RFC5769_IPV4;
+        // In the padding of SOFTWARE, which only the fingerprint covers
+        buf[35] ^= 1;
+        assert_eq!(Message::decode(&buf), Err(DecodeError::Fingerprint));
+
+        // A fingerprint followed by another attribute
+        let mut buf = Message::binding_request().encode();
+        buf.extend_from_slice(&[0x80, 0x22, 0x00, 0x00]);
+        let length = (buf.len() - HEADER_SIZE) as u16;
+        buf[2..4].copy_from_slice(&length.to_be_bytes());
+        assert_eq!(
+            Message::decode(&buf),
+            Err(DecodeError::Attribute(FINGERPRINT))
+        );
+    }
+
+    #[test]
+    fn magic_cookie() {
+        assert!(is_stun(&RFC5769_IPV4));
+        let mut buf = RFC5769_IPV4;
+        buf[7] ^= 1;
+        assert!(!is_stun(&buf));
+        assert_eq!(Message::decode(&buf), Err(DecodeError::NotStun));
+        // The two top bits tell STUN apart from e.g. RTP
+        let mut buf = RFC5769_IPV4;
+        buf[0] |= 0x80;
+        assert!(!is_stun(&buf));
+        assert!(!is_stun(&RFC5769_IPV4[..HEADER_SIZE - 1]));
+    }
+}
@@ -0,0 +1,124 @@
+//! STUN server: answers Binding requests with the address they came from.
+//! Public STUN servers are out of reach in a lab network, so this lets the
+//! client be tried out between namespaces with a NAT in between.
+
+use std::{
+    io,
+    net::{SocketAddr, UdpSocket},
+    time::Duration,
+};
+
+use adnet_core::{
+    metrics::{self, Counter},
+    report::Report,
+    shutdown::Shutdown,
+};
+use tracing::{debug, info, warn};
+
+use crate::{
+    message::{Attribute, Class, Message, BINDING},
+    StunError, SOFTWARE,
+};
+
+// Longest the server waits for a request before checking for Ctrl-C
+const SHUTDOWN_POLL: Duration = Duration::from_millis(200);
+const BAD_REQUEST: u16 = 400;
+
+/// The server's metrics in the global registry.
+struct Metrics {
+    requests: Counter,
+    invalid: Counter,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        Self {
+            requests: metrics::counter("stun_requests_total", "Requests answered"),
+            invalid: metrics::counter(
+                "stun_invalid_total",
+                "Datagrams that were not valid STUN requests",
+            ),
+        }
+    }
+
+    fn report(&self, report: &mut Report) {
+        report.detail("answered", self.requests.get());
+        report.detail("invalid", self.invalid.get());
+    }
+}
+
+/// Answers Binding requests at `listen` until Ctrl-C.
+pub fn serve(
+    listen: SocketAddr,
+    shutdown: &Shutdown,
+    report: &mut Report,
+) -> Result<(), StunError> {
+    let socket = UdpSocket::bind(listen).map_err(|source| StunError::Bind {
+        address: listen,
+        source,
+    })?;
+    socket.set_read_timeout(Some(SHUTDOWN_POLL))?;
+    info!("Task-stun server listening on {}", socket.local_addr()?);
+
+    let metrics = Metrics::new();
+    let mut buf = [0u8; 1500];
+    while !shutdown.is_requested() {
+        let (n, from) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock
+                        | io::ErrorKind::TimedOut
+                        | io::ErrorKind::Interrupted
+                ) =>
+            {
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let request = match Message::decode(&buf[..n]) {
+            Ok(request) if request.class == Class::Request => request,
+            Ok(_) => {
+                debug!("Ignoring a STUN message that is no request from {}", from);
+                metrics.invalid.inc();
+                continue;
+            }
+            Err(e) => {
+                debug!("Ignoring datagram from {}: {}", from, e);
+                metrics.invalid.inc();
+                continue;
+            }
+        };
+
+        let mut answer = match request.method {
+            BINDING => {
+                let mut answer = Message::new(Class::Success, BINDING, request.transaction);
+                answer.attributes.push(Attribute::XorMappedAddress(from));
+                answer
+            }
+            // Binding is the only method this server knows
+            method => {
+                let mut answer = Message::new(Class::Error, method, request.transaction);
+                answer.attributes.push(Attribute::ErrorCode {
+                    code: BAD_REQUEST,
+                    reason: "Unknown method".to_string(),
+                });
+                answer
+            }
+        };
+        answer
+            .attributes
+            .push(Attribute::Software(SOFTWARE.to_string()));
+        if let Err(e) = socket.send_to(&answer.encode(), from) {
+            warn!("Answering {} failed: {}", from, e);
+            report.errors += 1;
+            continue;
+        }
+        metrics.requests.inc();
+        debug!("Answered {}", from);
+    }
+
+    metrics.report(report);
+    Ok(())
+}
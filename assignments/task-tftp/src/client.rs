@@ -0,0 +1,110 @@
+//! TFTP client: reads a file from a server (get) or writes one to it (put).
+
+use std::{
+    fs::{self, File},
+    io::{BufReader, BufWriter},
+    net::{SocketAddr, UdpSocket},
+    path::Path,
+    time::Instant,
+};
+
+use adnet_core::{report::Report, shutdown::Shutdown};
+use tracing::debug;
+
+use crate::{
+    packet::{Mode, Packet},
+    transfer::{Settings, Stats, Transfer},
+    TftpError,
+};
+
+fn bind(server: SocketAddr) -> Result<UdpSocket, TftpError> {
+    let any: SocketAddr = match server {
+        SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+        SocketAddr::V6(_) => ([0u16; 8], 0).into(),
+    };
+    Ok(UdpSocket::bind(any)?)
+}
+
+/// Reads `remote` from the server into the file at `local`, which is
+/// removed again if the transfer fails.
+pub fn get(
+    server: SocketAddr,
+    remote: &str,
+    local: &Path,
+    settings: Settings,
+    shutdown: &Shutdown,
+    report: &mut Report,
+) -> Result<(), TftpError> {
+    let file = File::create(local).map_err(|source| TftpError::File {
+        path: local.to_path_buf(),
+        source,
+    })?;
+    let socket = bind(server)?;
+    let mut transfer = Transfer::request(&socket, server, settings, shutdown.clone());
+    let request = Packet::Rrq {
+        filename: remote.to_string(),
+        mode: Mode::Octet,
+    };
+    let start = Instant::now();
+    let result = transfer.receive_data(request, BufWriter::new(file));
+    if let Err(e) = &result {
+        transfer.fail(e);
+        if let Err(e) = fs::remove_file(local) {
+            debug!("Removing {} failed: {}", local.display(), e);
+        }
+    }
+    finish("Received", &transfer, start, report);
+    result
+}
+
+/// Writes the file at `local` to the server as `remote`.
+pub fn put(
+    server: SocketAddr,
+    local: &Path,
+    remote: &str,
+    settings: Settings,
+    shutdown: &Shutdown,
+    report: &mut Report,
+) -> Result<(), TftpError> {
+    let file = File::open(local).map_err(|source| TftpError::File {
+        path: local.to_path_buf(),
+        source,
+    })?;
+    let socket = bind(server)?;
+    let mut transfer = Transfer::request(&socket, server, settings, shutdown.clone());
+    let request = Packet::Wrq {
+        filename: remote.to_string(),
+        mode: Mode::Octet,
+    };
+    let start = Instant::now();
+    let result = transfer
+        .request_write(&request)
+        .and_then(|()| transfer.send_data(BufReader::new(file)));
+    if let Err(e) = &result {
+        transfer.fail(e);
+    }
+    finish("Sent", &transfer, start, report);
+    result
+}
+
+/// Prints and reports what went through the transfer, also if it failed.
+fn finish(verb: &str, transfer: &Transfer, start: Instant, report: &mut Report) {
+    let Stats {
+        bytes,
+        blocks,
+        retransmissions,
+    } = transfer.stats;
+    let elapsed = start.elapsed();
+    println!(
+        "{} {} bytes in {:.2?} ({:.1} kB/s), {} retransmissions",
+        verb,
+        bytes,
+        elapsed,
+        bytes as f64 / elapsed.as_secs_f64().max(1e-6) / 1000.0,
+        retransmissions
+    );
+    report.bytes = bytes;
+    report.detail("peer", transfer.peer().to_string());
+    report.detail("blocks", blocks);
+    report.detail("retransmissions", retransmissions);
+}
@@ -0,0 +1,51 @@
+use std::{io, net::SocketAddr, path::PathBuf};
+
+use adnet_core::exit::{Code, HasCode};
+use thiserror::Error;
+
+/// Errors of task-tftp. Each kind has its own exit code, see
+/// [`adnet_core::exit`].
+#[derive(Debug, Error)]
+pub enum TftpError {
+    /// Invalid or missing options
+    #[error("{0}")]
+    Usage(String),
+    /// The config file could not be read
+    #[error(transparent)]
+    Config(io::Error),
+    #[error("Cannot listen at {address}: {source}")]
+    Bind {
+        address: SocketAddr,
+        source: io::Error,
+    },
+    /// A local file could not be opened, read or written
+    #[error("{}: {source}", .path.display())]
+    File { path: PathBuf, source: io::Error },
+    /// The peer answered with an ERROR packet
+    #[error("Peer sent error {code}: {message}")]
+    Remote { code: u16, message: String },
+    /// The peer sent a packet that makes no sense at this point
+    #[error("{0}")]
+    Protocol(String),
+    /// The peer stopped answering
+    #[error("No answer after {0} retransmissions")]
+    Timeout(u32),
+    #[error("Transfer cancelled")]
+    Interrupted,
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+impl HasCode for TftpError {
+    fn exit_code(&self) -> Code {
+        match self {
+            TftpError::Usage(_) | TftpError::Config(_) => Code::Usage,
+            TftpError::Bind { .. } => Code::Failure,
+            TftpError::File { source, .. } => source.exit_code(),
+            TftpError::Remote { .. } | TftpError::Protocol(_) => Code::Protocol,
+            TftpError::Timeout(_) => Code::Timeout,
+            TftpError::Interrupted => Code::Interrupted,
+            TftpError::Io(e) => e.exit_code(),
+        }
+    }
+}
@@ -0,0 +1,254 @@
+//! TFTP (RFC 1350) client and server. TFTP moves files over UDP with the
+//! simplest reliable protocol there is: one block of 512 bytes at a time,
+//! each acknowledged before the next, with retransmission on timeout. Next
+//! to the sliding window of task-udp, it shows what a window buys.
+//!
+//! The task-tftp binary and the `adnet tftp` subcommand are thin wrappers
+//! around [`run`].
+
+mod client;
+mod error;
+pub mod packet;
+mod server;
+pub mod transfer;
+
+use std::{
+    io,
+    net::{SocketAddr, ToSocketAddrs},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use adnet_core::{
+    config::{self, ConfigArgs},
+    logging::{self, LogArgs},
+    metrics::{self, MetricsArgs},
+    report::{Report, ReportArgs},
+    shutdown,
+};
+use clap::{Parser, Subcommand};
+use serde::Deserialize;
+use server::{Metrics, Server};
+use transfer::Settings;
+
+pub use error::TftpError;
+
+const TFTP_PORT: u16 = 69;
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(1);
+const DEFAULT_RETRIES: u32 = 5;
+
+/// Transfers files with TFTP, as client or server.
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+pub struct Args {
+    #[command(subcommand)]
+    mode: Mode,
+
+    /// Seconds to wait for an answer before retransmitting [default: 1]
+    #[arg(short, long, global = true, value_parser = config::parse_secs)]
+    timeout: Option<Duration>,
+
+    /// Retransmissions of a packet before giving up [default: 5]
+    #[arg(short, long, global = true)]
+    retries: Option<u32>,
+
+    #[command(flatten)]
+    config: ConfigArgs,
+
+    #[command(flatten)]
+    log: LogArgs,
+
+    #[command(flatten)]
+    metrics: MetricsArgs,
+
+    #[command(flatten)]
+    report: ReportArgs,
+}
+
+#[derive(Subcommand, Debug)]
+enum Mode {
+    /// Serve the files of a directory
+    Server {
+        /// Address to listen at [default: 0.0.0.0:69]
+        #[arg(short, long)]
+        listen: Option<SocketAddr>,
+
+        /// Directory to serve [default: current directory]
+        #[arg(long)]
+        root: Option<PathBuf>,
+
+        /// Accept write requests for files that do not exist yet
+        #[arg(short, long)]
+        writable: bool,
+    },
+
+    /// Read a file from a server
+    Get {
+        /// Address of the server as host or host:port [default port: 69]
+        #[arg(short, long)]
+        server: Option<String>,
+
+        /// Name of the file on the server
+        remote: String,
+
+        /// Where to write it [default: the file name of the remote]
+        local: Option<PathBuf>,
+    },
+
+    /// Write a file to a server
+    Put {
+        /// Address of the server as host or host:port [default port: 69]
+        #[arg(short, long)]
+        server: Option<String>,
+
+        /// File to send
+        local: PathBuf,
+
+        /// Name for it on the server [default: its file name]
+        remote: Option<String>,
+    },
+}
+
+/// The [task-tftp] section of the config file. Each mode uses its own options.
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct FileConfig {
+    listen: Option<SocketAddr>,
+    root: Option<PathBuf>,
+    writable: bool,
+    server: Option<String>,
+    #[serde(deserialize_with = "config::secs")]
+    timeout: Option<Duration>,
+    retries: Option<u32>,
+}
+
+/// Resolves the server given as host or host:port.
+fn resolve(server: &str) -> io::Result<SocketAddr> {
+    let with_port = match server.rsplit_once(':') {
+        Some((_, port)) if port.parse::<u16>().is_ok() && !server.ends_with(']') => {
+            server.to_string()
+        }
+        _ => format!("{}:{}", server, TFTP_PORT),
+    };
+    with_port.to_socket_addrs()?.next().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} did not resolve to any address", server),
+        )
+    })
+}
+
+fn server_address(server: Option<String>, report: &mut Report) -> Result<SocketAddr, TftpError> {
+    let server =
+        server.ok_or_else(|| TftpError::Usage("Server is required (--server)".to_string()))?;
+    report.detail("server", &server);
+    Ok(resolve(&server)?)
+}
+
+/// The last component of a path or file name, for the other end's default.
+fn file_name(path: &Path) -> Option<String> {
+    Some(path.file_name()?.to_string_lossy().into_owned())
+}
+
+/// Runs the client or server with the given arguments, as the task-tftp binary does.
+pub fn run(args: Args) -> Result<(), TftpError> {
+    logging::init(&args.log)?;
+    metrics::init(&args.metrics)?;
+    let report_args = args.report.clone();
+    let mut report = Report::new("task-tftp");
+    let result = transfer(args, &mut report);
+    report_args.finish(&mut report, result)
+}
+
+fn transfer(args: Args, report: &mut Report) -> Result<(), TftpError> {
+    // Command line options take precedence over the config file
+    let file: FileConfig = args
+        .config
+        .section("task-tftp")
+        .map_err(TftpError::Config)?;
+    let settings = Settings {
+        timeout: args.timeout.or(file.timeout).unwrap_or(DEFAULT_TIMEOUT),
+        retries: args.retries.or(file.retries).unwrap_or(DEFAULT_RETRIES),
+    };
+
+    match args.mode {
+        Mode::Server {
+            listen,
+            root,
+            writable,
+        } => {
+            let listen = listen
+                .or(file.listen)
+                .unwrap_or_else(|| SocketAddr::from(([0, 0, 0, 0], TFTP_PORT)));
+            let root = root.or(file.root).unwrap_or_else(|| PathBuf::from("."));
+            if !root.is_dir() {
+                return Err(TftpError::Usage(format!(
+                    "{} is not a directory",
+                    root.display()
+                )));
+            }
+            report.detail("mode", "server");
+            let metrics = Metrics::new();
+            let server = Server {
+                root,
+                writable: writable || file.writable,
+                settings,
+                metrics: metrics.clone(),
+            };
+            let result = server.serve(listen, &shutdown::install()?);
+            metrics.report(report);
+            result
+        }
+        Mode::Get {
+            server,
+            remote,
+            local,
+        } => {
+            let server = server_address(server.or(file.server), report)?;
+            let local = match local {
+                Some(local) => local,
+                None => file_name(Path::new(&remote))
+                    .map(PathBuf::from)
+                    .ok_or_else(|| {
+                        TftpError::Usage(format!("No file name in {}, give a local path", remote))
+                    })?,
+            };
+            report.detail("mode", "get");
+            report.detail("file", &remote);
+            client::get(
+                server,
+                &remote,
+                &local,
+                settings,
+                &shutdown::install()?,
+                report,
+            )
+        }
+        Mode::Put {
+            server,
+            local,
+            remote,
+        } => {
+            let server = server_address(server.or(file.server), report)?;
+            let remote = match remote {
+                Some(remote) => remote,
+                None => file_name(&local).ok_or_else(|| {
+                    TftpError::Usage(format!(
+                        "No file name in {}, give a remote name",
+                        local.display()
+                    ))
+                })?,
+            };
+            report.detail("mode", "put");
+            report.detail("file", &remote);
+            client::put(
+                server,
+                &local,
+                &remote,
+                settings,
+                &shutdown::install()?,
+                report,
+            )
+        }
+    }
+}
@@ -0,0 +1,304 @@
+//! The TFTP packets of RFC 1350. Every packet starts with a 2-byte opcode:
+//!
+//! ```text
+//! RRQ/WRQ  | 01/02 | filename | 0 | mode | 0 |
+//! DATA     | 03    | block #  | data (0-512 bytes) |
+//! ACK      | 04    | block #  |
+//! ERROR    | 05    | code     | message | 0 |
+//! ```
+//!
+//! A DATA packet shorter than 512 bytes ends the transfer.
+
+use std::fmt;
+
+/// Data bytes in every DATA packet but the last
+pub const BLOCK_SIZE: usize = 512;
+/// Longest packet, a full DATA packet
+pub const MAX_PACKET: usize = 4 + BLOCK_SIZE;
+
+const RRQ: u16 = 1;
+const WRQ: u16 = 2;
+const DATA: u16 = 3;
+const ACK: u16 = 4;
+const ERROR: u16 = 5;
+
+/// Error codes of ERROR packets
+pub const ERR_UNDEFINED: u16 = 0;
+pub const ERR_NOT_FOUND: u16 = 1;
+pub const ERR_ACCESS: u16 = 2;
+pub const ERR_DISK_FULL: u16 = 3;
+pub const ERR_ILLEGAL: u16 = 4;
+pub const ERR_UNKNOWN_TID: u16 = 5;
+pub const ERR_EXISTS: u16 = 6;
+
+/// Transfer mode of a request. Both are transferred byte for byte; netascii
+/// line ends are left to the applications at the ends.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    Netascii,
+    Octet,
+}
+
+impl Mode {
+    fn parse(s: &str) -> Option<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "netascii" => Some(Mode::Netascii),
+            "octet" => Some(Mode::Octet),
+            _ => None,
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Mode::Netascii => "netascii",
+            Mode::Octet => "octet",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Packet {
+    /// Read request
+    Rrq {
+        filename: String,
+        mode: Mode,
+    },
+    /// Write request
+    Wrq {
+        filename: String,
+        mode: Mode,
+    },
+    Data {
+        block: u16,
+        data: Vec<u8>,
+    },
+    Ack {
+        block: u16,
+    },
+    Error {
+        code: u16,
+        message: String,
+    },
+}
+
+impl Packet {
+    pub fn error(code: u16, message: impl Into<String>) -> Self {
+        Packet::Error {
+            code,
+            message: message.into(),
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(MAX_PACKET);
+        match self {
+            Packet::Rrq { filename, mode } | Packet::Wrq { filename, mode } => {
+                let opcode = match self {
+                    Packet::Rrq { .. } => RRQ,
+                    _ => WRQ,
+                };
+                buf.extend_from_slice(&opcode.to_be_bytes());
+                put_str(&mut buf, filename);
+                put_str(&mut buf, mode.as_str());
+            }
+            Packet::Data { block, data } => {
+                buf.extend_from_slice(&DATA.to_be_bytes());
+                buf.extend_from_slice(&block.to_be_bytes());
+                buf.extend_from_slice(data);
+            }
+            Packet::Ack { block } => {
+                buf.extend_from_slice(&ACK.to_be_bytes());
+                buf.extend_from_slice(&block.to_be_bytes());
+            }
+            Packet::Error { code, message } => {
+                buf.extend_from_slice(&ERROR.to_be_bytes());
+                buf.extend_from_slice(&code.to_be_bytes());
+                put_str(&mut buf, message);
+            }
+        }
+        buf
+    }
+
+    /// Parses a packet, or returns `None` if it is not valid TFTP.
+    pub fn decode(buf: &[u8]) -> Option<Self> {
+        if buf.len() < 4 {
+            return None;
+        }
+        let opcode = u16::from_be_bytes([buf[0], buf[1]]);
+        let number = u16::from_be_bytes([buf[2], buf[3]]);
+        match opcode {
+            RRQ | WRQ => {
+                let mut fields = buf[2..].split(|&b| b == 0);
+                let filename = std::str::from_utf8(fields.next()?).ok()?.to_string();
+                let mode = Mode::parse(std::str::from_utf8(fields.next()?).ok()?)?;
+                // The mode must be terminated too; options of RFC 2347 may follow
+                fields.next()?;
+                if filename.is_empty() {
+                    return None;
+                }
+                Some(match opcode {
+                    RRQ => Packet::Rrq { filename, mode },
+                    _ => Packet::Wrq { filename, mode },
+                })
+            }
+            DATA if buf.len() <= MAX_PACKET => Some(Packet::Data {
+                block: number,
+                data: buf[4..].to_vec(),
+            }),
+            ACK => Some(Packet::Ack { block: number }),
+            ERROR => {
+                let message = buf[4..].split(|&b| b == 0).next().unwrap_or_default();
+                Some(Packet::Error {
+                    code: number,
+                    message: String::from_utf8_lossy(message).into_owned(),
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+fn put_str(buf: &mut Vec<u8>, s: &str) {
+    buf.extend_from_slice(s.as_bytes());
+    buf.push(0);
+}
+
+impl fmt::Display for Packet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Packet::Rrq { filename, mode } => write!(f, "RRQ {} ({})", filename, mode.as_str()),
+            Packet::Wrq { filename, mode } => write!(f, "WRQ {} ({})", filename, mode.as_str()),
+            Packet::Data { block, data } => write!(f, "DATA {} ({} bytes)", block, data.len()),
+            Packet::Ack { block } => write!(f, "ACK {}", block),
+            Packet::Error { code, message } => write!(f, "ERROR {}: {}", code, message),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wire_format() {
+        let cases: [(Packet, &[u8]); 5] = [
+            (
+                Packet::Rrq {
+                    filename: "boot.img".to_string(),
+                    mode: Mode::Octet,
+                },
+                b"\0\x01boot.img\0octet\0",
+            ),
+            (
+                Packet::Wrq {
+                    filename: "notes.txt".to_string(),
+                    mode: Mode::Netascii,
+                },
+                b"\0\x02notes.txt\0netascii\0",
+            ),
+            (
+                Packet::Data {
+                    block: 258,
+                    data: b"abc".to_vec(),
+                },
+                b"\0\x03\x01\x02abc",
+            ),
+            (Packet::Ack { block: 65535 }, b"\0\x04\xff\xff"),
+            (
+                Packet::error(ERR_NOT_FOUND, "File not found"),
+                b"\0\x05\0\x01File not found\0",
+            ),
+        ];
+        for (packet, bytes) in cases {
+            assert_eq!(packet.encode(), bytes, "{packet}");
+            assert_eq!(Packet::decode(bytes), Some(packet));
+        }
+    }
+
+    #[test]
+    fn data_sizes() {
+        for len in [0, 1, BLOCK_SIZE] {
+            let packet = Packet::Data {
+                block: 1,
+                data: vec![7; len],
+            };
+            let bytes = packet.encode();
+            assert_eq!(bytes.len(), 4 + len);
+            assert_eq!(Packet::decode(&bytes), Some(packet), "{len} bytes");
+        }
+        let mut too_long = b"\0\x03\0\x01".to_vec();
+        too_long.resize(MAX_PACKET + 1, 0);
+        assert_eq!(Packet::decode(&too_long), None, "over a block");
+    }
+
+    #[test]
+    fn requests() {
+        let rrq = |mode| Packet::Rrq {
+            filename: "f".to_string(),
+            mode,
+        };
+        assert_eq!(
+            Packet::decode(b"\0\x01f\0OCTET\0"),
+            Some(rrq(Mode::Octet)),
+            "case"
+        );
+        assert_eq!(
+            Packet::decode(b"\0\x01f\0NetAscii\0"),
+            Some(rrq(Mode::Netascii))
+        );
+        let options = b"\0\x01f\0octet\0blksize\x001428\0";
+        assert_eq!(
+            Packet::decode(options),
+            Some(rrq(Mode::Octet)),
+            "RFC 2347 options"
+        );
+
+        assert_eq!(Packet::decode(b"\0\x01f\0octet"), None, "unterminated mode");
+        assert_eq!(Packet::decode(b"\0\x01f\0"), None, "no mode");
+        assert_eq!(Packet::decode(b"\0\x01f\0mail\0"), None, "mail mode");
+        assert_eq!(Packet::decode(b"\0\x02\0octet\0"), None, "empty filename");
+        assert_eq!(Packet::decode(b"\0\x01\xff\0octet\0"), None, "not UTF-8");
+    }
+
+    #[test]
+    fn errors() {
+        let unterminated = Packet::decode(b"\0\x05\0\x04Illegal");
+        assert_eq!(unterminated, Some(Packet::error(ERR_ILLEGAL, "Illegal")));
+        let empty = Packet::decode(b"\0\x05\0\x00");
+        assert_eq!(empty, Some(Packet::error(ERR_UNDEFINED, "")));
+        let lossy = Packet::decode(b"\0\x05\0\x00bad \xff\0");
+        assert_eq!(lossy, Some(Packet::error(ERR_UNDEFINED, "bad \u{fffd}")));
+    }
+
+    #[test]
+    fn invalid() {
+        for bytes in [
+            &b""[..],
+            b"\0",
+            b"\0\x04\0",
+            b"\0\x00\0\x01",
+            b"\0\x06\0\x01",
+            b"\x01\x04\0\x01",
+        ] {
+            assert_eq!(Packet::decode(bytes), None, "{bytes:?}");
+        }
+    }
+
+    #[test]
+    fn display() {
+        let data = Packet::Data {
+            block: 3,
+            data: vec![0; 512],
+        };
+        assert_eq!(data.to_string(), "DATA 3 (512 bytes)");
+        let rrq = Packet::Rrq {
+            filename: "a.bin".to_string(),
+            mode: Mode::Octet,
+        };
+        assert_eq!(rrq.to_string(), "RRQ a.bin (octet)");
+        assert_eq!(
+            Packet::error(ERR_EXISTS, "File exists").to_string(),
+            "ERROR 6: File exists"
+        );
+    }
+}
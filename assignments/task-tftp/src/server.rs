@@ -0,0 +1,255 @@
+//! TFTP server. Requests arrive at the listening port, and each transfer
+//! runs on a thread of its own with a new socket, whose port is the
+//! server's TID for that transfer. Files are served from a root directory,
+//! and written there only if the server is writable.
+
+use std::{
+    fs::{self, File},
+    io,
+    net::{SocketAddr, UdpSocket},
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use adnet_core::{
+    metrics::{self, Counter, Gauge},
+    report::Report,
+    shutdown::{self, Shutdown},
+};
+use tracing::{debug, info, warn};
+
+use crate::{
+    packet::{Packet, ERR_ACCESS, ERR_EXISTS, ERR_NOT_FOUND, MAX_PACKET},
+    transfer::{Settings, Transfer},
+    TftpError,
+};
+
+// Longest the server waits for a request before checking for Ctrl-C
+const SHUTDOWN_POLL: Duration = Duration::from_millis(200);
+
+/// The server's metrics in the global registry.
+#[derive(Clone)]
+pub struct Metrics {
+    transfers: Counter,
+    active_transfers: Gauge,
+    failed: Counter,
+    bytes_sent: Counter,
+    bytes_received: Counter,
+    retransmissions: Counter,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self {
+            transfers: metrics::counter("tftp_transfers_total", "Transfers requested"),
+            active_transfers: metrics::gauge(
+                "tftp_active_transfers",
+                "Transfers currently in progress",
+            ),
+            failed: metrics::counter(
+                "tftp_failed_total",
+                "Transfers that were refused or ended with an error",
+            ),
+            bytes_sent: metrics::counter("tftp_bytes_sent_total", "Data bytes sent to clients"),
+            bytes_received: metrics::counter(
+                "tftp_bytes_received_total",
+                "Data bytes received from clients",
+            ),
+            retransmissions: metrics::counter(
+                "tftp_retransmissions_total",
+                "Packets sent again after a timeout",
+            ),
+        }
+    }
+
+    pub fn report(&self, report: &mut Report) {
+        report.bytes = self.bytes_sent.get() + self.bytes_received.get();
+        report.errors = self.failed.get();
+        report.detail("transfers", self.transfers.get());
+        report.detail("bytes_sent", self.bytes_sent.get());
+        report.detail("bytes_received", self.bytes_received.get());
+        report.detail("retransmissions", self.retransmissions.get());
+    }
+}
+
+/// What the server serves, and how.
+pub struct Server {
+    pub root: PathBuf,
+    pub writable: bool,
+    pub settings: Settings,
+    pub metrics: Metrics,
+}
+
+impl Server {
+    /// Serves requests until the shutdown is requested, and then gives the
+    /// transfers in progress the drain timeout to finish.
+    pub fn serve(self, listen: SocketAddr, shutdown: &Shutdown) -> Result<(), TftpError> {
+        let socket = UdpSocket::bind(listen).map_err(|source| TftpError::Bind {
+            address: listen,
+            source,
+        })?;
+        socket.set_read_timeout(Some(SHUTDOWN_POLL))?;
+        info!(
+            "Task-TFTP serving {} at {}{}",
+            self.root.display(),
+            socket.local_addr()?,
+            if self.writable { " (writable)" } else { "" }
+        );
+
+        let server = Arc::new(self);
+        let mut buf = vec![0u8; MAX_PACKET];
+        while !shutdown.is_requested() {
+            let (n, from) = match socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock
+                            | io::ErrorKind::TimedOut
+                            | io::ErrorKind::Interrupted
+                    ) =>
+                {
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
+            };
+            let request = match Packet::decode(&buf[..n]) {
+                Some(request @ (Packet::Rrq { .. } | Packet::Wrq { .. })) => request,
+                Some(Packet::Error { .. }) | None => {
+                    debug!("Ignoring invalid request from {}", from);
+                    continue;
+                }
+                // A late packet of a finished transfer
+                Some(packet) => {
+                    debug!("Ignoring {} from {} at the request port", packet, from);
+                    continue;
+                }
+            };
+
+            server.metrics.transfers.inc();
+            let server = server.clone();
+            let in_flight = shutdown.track();
+            let bind = SocketAddr::new(listen.ip(), 0);
+            thread::spawn(move || {
+                server.metrics.active_transfers.inc();
+                if let Err(e) = server.handle(request, from, bind) {
+                    warn!("{}: {}", from, e);
+                    server.metrics.failed.inc();
+                }
+                server.metrics.active_transfers.dec();
+                drop(in_flight);
+            });
+        }
+
+        if shutdown.in_flight() > 0 {
+            info!("Waiting for {} transfers", shutdown.in_flight());
+            shutdown.drain(shutdown::DEFAULT_DRAIN_TIMEOUT);
+        }
+        shutdown.finish();
+        Ok(())
+    }
+
+    /// Runs one transfer from a new socket.
+    fn handle(
+        &self,
+        request: Packet,
+        client: SocketAddr,
+        bind: SocketAddr,
+    ) -> Result<(), TftpError> {
+        let socket = UdpSocket::bind(bind)?;
+        let mut transfer = Transfer::new(&socket, client, self.settings);
+        let start = Instant::now();
+        let result = match &request {
+            Packet::Rrq { filename, .. } => self.read(&mut transfer, filename),
+            Packet::Wrq { filename, .. } => self.write(&mut transfer, filename),
+            _ => unreachable!("only requests are handled"),
+        };
+        let stats = transfer.stats;
+        self.metrics.retransmissions.add(stats.retransmissions);
+        result?;
+        info!(
+            "{}: {} done, {} bytes in {:.2?}, {} retransmissions",
+            client,
+            request,
+            stats.bytes,
+            start.elapsed(),
+            stats.retransmissions
+        );
+        Ok(())
+    }
+
+    fn read(&self, transfer: &mut Transfer, filename: &str) -> Result<(), TftpError> {
+        let Some(path) = self.resolve(transfer, filename) else {
+            return Err(TftpError::Protocol(format!("Refused to read {}", filename)));
+        };
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(e) => {
+                let code = match e.kind() {
+                    io::ErrorKind::NotFound => ERR_NOT_FOUND,
+                    _ => ERR_ACCESS,
+                };
+                transfer.abort(code, &e.to_string());
+                return Err(TftpError::File { path, source: e });
+            }
+        };
+        let result = transfer.send_data(io::BufReader::new(file));
+        self.metrics.bytes_sent.add(transfer.stats.bytes);
+        if let Err(e) = &result {
+            transfer.fail(e);
+        }
+        result
+    }
+
+    fn write(&self, transfer: &mut Transfer, filename: &str) -> Result<(), TftpError> {
+        if !self.writable {
+            transfer.abort(ERR_ACCESS, "Server is read-only");
+            return Err(TftpError::Protocol(format!(
+                "Refused to write {}",
+                filename
+            )));
+        }
+        let Some(path) = self.resolve(transfer, filename) else {
+            return Err(TftpError::Protocol(format!(
+                "Refused to write {}",
+                filename
+            )));
+        };
+        // Never overwrite, so that clients cannot replace what is served
+        let file = match File::create_new(&path) {
+            Ok(file) => file,
+            Err(e) => {
+                let code = match e.kind() {
+                    io::ErrorKind::AlreadyExists => ERR_EXISTS,
+                    _ => ERR_ACCESS,
+                };
+                transfer.abort(code, &e.to_string());
+                return Err(TftpError::File { path, source: e });
+            }
+        };
+        let result = transfer.receive_data(Packet::Ack { block: 0 }, io::BufWriter::new(file));
+        self.metrics.bytes_received.add(transfer.stats.bytes);
+        if let Err(e) = &result {
+            transfer.fail(e);
+            // Leave no partial files behind
+            let _ = fs::remove_file(&path);
+        }
+        result
+    }
+
+    /// The path of a requested file under the root. Absolute names are taken
+    /// relative to the root, and names that would leave it are refused.
+    fn resolve(&self, transfer: &Transfer, filename: &str) -> Option<PathBuf> {
+        let relative = Path::new(filename.trim_start_matches('/'));
+        let inside = relative
+            .components()
+            .all(|c| matches!(c, Component::Normal(_) | Component::CurDir));
+        if !inside || relative.as_os_str().is_empty() {
+            transfer.abort(ERR_ACCESS, "Invalid file name");
+            return None;
+        }
+        Some(self.root.join(relative))
+    }
+}
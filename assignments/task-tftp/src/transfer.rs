@@ -0,0 +1,326 @@
+//! The lock-step transfer of RFC 1350, shared by the client and the server.
+//! The sender sends one DATA block and waits for its ACK before the next;
+//! whoever waits in vain retransmits its last packet. Unlike the sliding
+//! window of task-udp, at most one block is ever in flight, so a transfer
+//! takes at least one round trip per 512 bytes.
+//!
+//! Each end of a transfer is identified by its port (the transfer ID, TID).
+//! The client sends its request to port 69, and the server answers from a
+//! new port that the rest of the transfer uses. Packets from any other
+//! port get an ERROR and are otherwise ignored.
+
+use std::{
+    io::{self, Read, Write},
+    net::{SocketAddr, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use adnet_core::shutdown::Shutdown;
+use tracing::{debug, trace};
+
+use crate::{
+    packet::{Packet, BLOCK_SIZE, ERR_DISK_FULL, ERR_UNDEFINED, ERR_UNKNOWN_TID, MAX_PACKET},
+    TftpError,
+};
+
+// Longest a wait lasts before checking for Ctrl-C
+const SHUTDOWN_POLL: Duration = Duration::from_millis(200);
+
+/// Retransmission settings of a transfer.
+#[derive(Clone, Copy, Debug)]
+pub struct Settings {
+    /// Time to wait for an answer before retransmitting
+    pub timeout: Duration,
+    /// Retransmissions of a packet before giving up
+    pub retries: u32,
+}
+
+/// What went through a transfer.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Stats {
+    /// Data bytes, not counting headers
+    pub bytes: u64,
+    pub blocks: u64,
+    pub retransmissions: u64,
+}
+
+/// How a received packet fits the exchange in progress.
+enum Fit {
+    /// The expected answer
+    Accept,
+    /// A duplicate or stale packet
+    Ignore,
+    /// The duplicate of the packet that our last one answers, which means
+    /// that our answer was lost, so it is sent again right away
+    Repeat,
+}
+
+/// One side of a transfer with a peer.
+pub struct Transfer<'a> {
+    socket: &'a UdpSocket,
+    peer: SocketAddr,
+    // Whether the peer's TID is known; the client learns it from the first
+    // answer to its request
+    locked: bool,
+    settings: Settings,
+    // Set on the client, which gives up on Ctrl-C
+    shutdown: Option<Shutdown>,
+    pub stats: Stats,
+    buf: Vec<u8>,
+}
+
+impl<'a> Transfer<'a> {
+    /// A transfer with a known peer, as on the server.
+    pub fn new(socket: &'a UdpSocket, peer: SocketAddr, settings: Settings) -> Self {
+        Self {
+            socket,
+            peer,
+            locked: true,
+            settings,
+            shutdown: None,
+            stats: Stats::default(),
+            buf: vec![0u8; MAX_PACKET + 1],
+        }
+    }
+
+    /// A transfer that starts with a request to the server's well-known
+    /// port, and continues with the port the server answers from.
+    pub fn request(
+        socket: &'a UdpSocket,
+        server: SocketAddr,
+        settings: Settings,
+        shutdown: Shutdown,
+    ) -> Self {
+        Self {
+            locked: false,
+            shutdown: Some(shutdown),
+            ..Self::new(socket, server, settings)
+        }
+    }
+
+    pub fn peer(&self) -> SocketAddr {
+        self.peer
+    }
+
+    fn send(&self, packet: &Packet) -> io::Result<()> {
+        trace!("-> {} {}", self.peer, packet);
+        self.socket.send_to(&packet.encode(), self.peer)?;
+        Ok(())
+    }
+
+    /// Tells the peer why the transfer ends, if it is still listening.
+    pub fn abort(&self, code: u16, message: &str) {
+        if let Err(e) = self.send(&Packet::error(code, message)) {
+            debug!("Sending the error to {} failed: {}", self.peer, e);
+        }
+    }
+
+    /// Tells the peer about a local failure. Errors that came from the peer
+    /// or its silence need no answer.
+    pub fn fail(&self, error: &TftpError) {
+        match error {
+            TftpError::Remote { .. } | TftpError::Timeout(_) | TftpError::Interrupted => {}
+            TftpError::File { source, .. } | TftpError::Io(source)
+                if source.kind() == io::ErrorKind::StorageFull =>
+            {
+                self.abort(ERR_DISK_FULL, "Disk full")
+            }
+            error => self.abort(ERR_UNDEFINED, &error.to_string()),
+        }
+    }
+
+    /// Sends `packet` and waits for the answer that `fit` accepts,
+    /// retransmitting on timeout.
+    fn exchange(
+        &mut self,
+        packet: &Packet,
+        fit: impl Fn(&Packet) -> Fit,
+    ) -> Result<Packet, TftpError> {
+        self.send(packet)?;
+        let mut attempts = 0;
+        let mut deadline = Instant::now() + self.settings.timeout;
+        loop {
+            let Some(answer) = self.receive(deadline)? else {
+                if attempts == self.settings.retries {
+                    return Err(TftpError::Timeout(attempts));
+                }
+                attempts += 1;
+                self.stats.retransmissions += 1;
+                debug!("Timeout, retransmitting {}", packet);
+                self.send(packet)?;
+                deadline = Instant::now() + self.settings.timeout;
+                continue;
+            };
+            match answer {
+                Packet::Error { code, message } => return Err(TftpError::Remote { code, message }),
+                answer => match fit(&answer) {
+                    Fit::Accept => return Ok(answer),
+                    // Answering duplicates of our own packet's answer would
+                    // double every packet from then on (the Sorcerer's
+                    // Apprentice bug), so only those of the peer's are
+                    Fit::Ignore => debug!("Ignoring {} from {}", answer, self.peer),
+                    Fit::Repeat => {
+                        debug!("Duplicate {}, answering again", answer);
+                        self.send(packet)?;
+                    }
+                },
+            }
+        }
+    }
+
+    /// Waits until the deadline for a packet from the peer. Packets from
+    /// other TIDs are refused, and invalid ones are skipped.
+    fn receive(&mut self, deadline: Instant) -> Result<Option<Packet>, TftpError> {
+        loop {
+            if self.shutdown.as_ref().is_some_and(|s| s.is_requested()) {
+                self.abort(ERR_UNDEFINED, "Transfer cancelled");
+                return Err(TftpError::Interrupted);
+            }
+            let Some(left) = deadline
+                .checked_duration_since(Instant::now())
+                .filter(|left| !left.is_zero())
+            else {
+                return Ok(None);
+            };
+            self.socket
+                .set_read_timeout(Some(left.min(SHUTDOWN_POLL)))?;
+            let (n, from) = match self.socket.recv_from(&mut self.buf) {
+                Ok(received) => received,
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock
+                            | io::ErrorKind::TimedOut
+                            | io::ErrorKind::Interrupted
+                    ) =>
+                {
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
+            };
+
+            let known = match self.locked {
+                true => from == self.peer,
+                false => from.ip() == self.peer.ip(),
+            };
+            if !known {
+                debug!("Packet from unknown TID {}", from);
+                let refusal = Packet::error(ERR_UNKNOWN_TID, "Unknown transfer ID").encode();
+                self.socket.send_to(&refusal, from)?;
+                continue;
+            }
+            let Some(packet) = Packet::decode(&self.buf[..n]) else {
+                debug!("Ignoring invalid packet of {} bytes from {}", n, from);
+                continue;
+            };
+            trace!("<- {} {}", from, packet);
+            if !self.locked {
+                self.peer = from;
+                self.locked = true;
+            }
+            return Ok(Some(packet));
+        }
+    }
+
+    /// Sends a write request and waits for the server to accept it with the
+    /// ACK of block 0.
+    pub fn request_write(&mut self, request: &Packet) -> Result<(), TftpError> {
+        self.exchange(request, |answer| match answer {
+            Packet::Ack { block: 0 } => Fit::Accept,
+            _ => Fit::Ignore,
+        })?;
+        Ok(())
+    }
+
+    /// Sends the data of `reader` block by block. The transfer must already
+    /// be at the point where block 1 goes out: on the server after a read
+    /// request, on the client after the ACK of its write request.
+    pub fn send_data(&mut self, mut reader: impl Read) -> Result<(), TftpError> {
+        let mut block: u16 = 1;
+        let mut data = vec![0u8; BLOCK_SIZE];
+        loop {
+            let n = read_block(&mut reader, &mut data)?;
+            let packet = Packet::Data {
+                block,
+                data: data[..n].to_vec(),
+            };
+            self.exchange(&packet, |answer| match answer {
+                Packet::Ack { block: acked } if *acked == block => Fit::Accept,
+                _ => Fit::Ignore,
+            })?;
+            self.stats.bytes += n as u64;
+            self.stats.blocks += 1;
+            if n < BLOCK_SIZE {
+                return Ok(());
+            }
+            // RFC 1350 ends at block 65535; wrapping around to 0 is the
+            // common extension for larger files
+            block = block.wrapping_add(1);
+        }
+    }
+
+    /// Receives data into `writer` until a short block, starting by sending
+    /// `first`: the read request on the client, the ACK of the write request
+    /// on the server.
+    pub fn receive_data(&mut self, first: Packet, mut writer: impl Write) -> Result<(), TftpError> {
+        let mut expected: u16 = 1;
+        let mut outgoing = first;
+        loop {
+            let previous = expected.wrapping_sub(1);
+            // Before the first block, what we send is a request or the ACK
+            // of one, which no block duplicates
+            let started = self.stats.blocks > 0;
+            let answer = self.exchange(&outgoing, |answer| match answer {
+                Packet::Data { block, .. } if *block == expected => Fit::Accept,
+                // Our ACK of the previous block was lost
+                Packet::Data { block, .. } if *block == previous && started => Fit::Repeat,
+                _ => Fit::Ignore,
+            })?;
+            let Packet::Data { data, .. } = answer else {
+                unreachable!("only DATA is accepted");
+            };
+            writer.write_all(&data)?;
+            self.stats.bytes += data.len() as u64;
+            self.stats.blocks += 1;
+            outgoing = Packet::Ack { block: expected };
+            if data.len() < BLOCK_SIZE {
+                // Acknowledge only what is safely written
+                writer.flush()?;
+                self.send(&outgoing)?;
+                self.dally(&outgoing, expected);
+                return Ok(());
+            }
+            expected = expected.wrapping_add(1);
+        }
+    }
+
+    /// After the final ACK, waits one timeout for the sender to retransmit
+    /// the last block, which means that the ACK was lost and must be sent
+    /// again. Otherwise the sender would time out although all the data
+    /// arrived.
+    fn dally(&mut self, ack: &Packet, last: u16) {
+        let deadline = Instant::now() + self.settings.timeout;
+        while let Ok(Some(packet)) = self.receive(deadline) {
+            if matches!(packet, Packet::Data { block, .. } if block == last) {
+                debug!("Final block repeated, sending the last ACK again");
+                if self.send(ack).is_err() {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// Fills `buf` from the reader, short only at the end of the data.
+fn read_block(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
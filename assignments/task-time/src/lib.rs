@@ -0,0 +1,356 @@
+//! Clock offset and path delay measurement in the manner of NTP. The client
+//! records when it sends a request and when the answer arrives, the server
+//! when it received the request and when it sent the answer, and from these
+//! four timestamps the client computes how far the server's clock is from
+//! its own and how long the network took. The server answers in the NTP
+//! format, so the client works against real NTP servers too.
+//!
+//! Other programs can use [`query`] to find the offset of their clock from a
+//! task-time server, for example to line up logs written on different
+//! machines. The task-time binary and the `adnet time` subcommand are thin
+//! wrappers around [`run`].
+
+pub mod ntp;
+
+use std::{
+    error::Error,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket},
+    thread,
+    time::{Duration, Instant},
+};
+
+use adnet_core::{
+    config::{self, ConfigArgs},
+    logging::{self, LogArgs},
+    report::{Report, ReportArgs},
+    shutdown::{self, Shutdown},
+};
+use clap::{Parser, Subcommand};
+use ntp::{Packet, Sample, Timestamp, MODE_CLIENT, MODE_SERVER, VERSION};
+use serde::Deserialize;
+use tracing::{debug, info, warn};
+
+const NTP_PORT: u16 = 123;
+const DEFAULT_COUNT: u32 = 4;
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(1);
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(2);
+const DEFAULT_STRATUM: u8 = 1;
+// About a microsecond, as a power of two
+const PRECISION: i8 = -20;
+// Reference id of a stratum 1 server without a reference clock
+const REFERENCE_ID: [u8; 4] = *b"LOCL";
+// Longest the server waits for a request before checking for Ctrl-C
+const SHUTDOWN_POLL: Duration = Duration::from_millis(200);
+
+/// Measures clock offset and path delay against a peer or an NTP server.
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+pub struct Args {
+    #[command(subcommand)]
+    mode: Mode,
+
+    #[command(flatten)]
+    config: ConfigArgs,
+
+    #[command(flatten)]
+    log: LogArgs,
+
+    #[command(flatten)]
+    report: ReportArgs,
+}
+
+#[derive(Subcommand, Debug)]
+enum Mode {
+    /// Answer time requests with the local clock
+    Server {
+        /// Address to listen at [default: 0.0.0.0:123]
+        #[arg(short, long)]
+        listen: Option<SocketAddr>,
+
+        /// Stratum to announce [default: 1]
+        #[arg(long)]
+        stratum: Option<u8>,
+    },
+
+    /// Query a task-time or NTP server
+    Client {
+        /// Address of the server as host or host:port [default port: 123]
+        #[arg(short, long)]
+        server: Option<String>,
+
+        /// Number of requests to send [default: 4]
+        #[arg(short, long)]
+        count: Option<u32>,
+
+        /// Seconds between requests [default: 1]
+        #[arg(short, long, value_parser = config::parse_secs)]
+        interval: Option<Duration>,
+
+        /// Seconds to wait for each answer [default: 2]
+        #[arg(short = 'W', long, value_parser = config::parse_secs)]
+        timeout: Option<Duration>,
+    },
+}
+
+/// The [task-time] section of the config file. Each mode uses its own options.
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct FileConfig {
+    listen: Option<SocketAddr>,
+    stratum: Option<u8>,
+    server: Option<String>,
+    count: Option<u32>,
+    #[serde(deserialize_with = "config::secs")]
+    interval: Option<Duration>,
+    #[serde(deserialize_with = "config::secs")]
+    timeout: Option<Duration>,
+}
+
+/// Resolves the server given as host or host:port.
+fn resolve(server: &str) -> io::Result<SocketAddr> {
+    let with_port = match server.rsplit_once(':') {
+        Some((_, port)) if port.parse::<u16>().is_ok() && !server.ends_with(']') => {
+            server.to_string()
+        }
+        _ => format!("{}:{}", server, NTP_PORT),
+    };
+    with_port.to_socket_addrs()?.next().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} did not resolve to any address", server),
+        )
+    })
+}
+
+/// Runs the server or client with the given arguments, as the task-time binary does.
+pub fn run(args: Args) -> Result<(), Box<dyn Error>> {
+    logging::init(&args.log)?;
+    let report = args.report.clone();
+    report.run("task-time", |report| measure(args, report))
+}
+
+fn measure(args: Args, report: &mut Report) -> Result<(), Box<dyn Error>> {
+    // Command line options take precedence over the config file
+    let file: FileConfig = args.config.section("task-time")?;
+    match args.mode {
+        Mode::Server { listen, stratum } => {
+            let listen = listen
+                .or(file.listen)
+                .unwrap_or_else(|| SocketAddr::from(([0, 0, 0, 0], NTP_PORT)));
+            let stratum = stratum.or(file.stratum).unwrap_or(DEFAULT_STRATUM);
+            if stratum == 0 || stratum > 15 {
+                return Err("Stratum must be between 1 and 15".into());
+            }
+            report.detail("mode", "server");
+            Ok(serve(listen, stratum, &shutdown::install()?, report)?)
+        }
+        Mode::Client {
+            server,
+            count,
+            interval,
+            timeout,
+        } => {
+            let server = server
+                .or(file.server)
+                .ok_or("Server is required (--server)")?;
+            report.detail("mode", "client");
+            client(
+                resolve(&server)?,
+                count.or(file.count).unwrap_or(DEFAULT_COUNT),
+                interval.or(file.interval).unwrap_or(DEFAULT_INTERVAL),
+                timeout.or(file.timeout).unwrap_or(DEFAULT_TIMEOUT),
+                report,
+            )
+        }
+    }
+}
+
+/// Answers every client request with the local clock.
+fn serve(
+    listen: SocketAddr,
+    stratum: u8,
+    shutdown: &Shutdown,
+    report: &mut Report,
+) -> io::Result<()> {
+    let socket = UdpSocket::bind(listen)?;
+    socket.set_read_timeout(Some(SHUTDOWN_POLL))?;
+    info!("Task-time server listening on {}", socket.local_addr()?);
+
+    let mut buf = [0u8; 1024];
+    let mut answered = 0u64;
+    while !shutdown.is_requested() {
+        let (n, from) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock
+                        | io::ErrorKind::TimedOut
+                        | io::ErrorKind::Interrupted
+                ) =>
+            {
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        // Take the receive timestamp before anything else
+        let receive = Timestamp::now();
+        let request = match Packet::decode(&buf[..n]) {
+            Some(request) if request.mode == MODE_CLIENT => request,
+            _ => {
+                debug!("Ignoring invalid request from {}", from);
+                continue;
+            }
+        };
+
+        let mut reply = Packet {
+            leap: 0,
+            // Answer with the version of the request, as NTP servers do
+            version: request.version.clamp(1, VERSION),
+            mode: MODE_SERVER,
+            stratum,
+            poll: request.poll,
+            precision: PRECISION,
+            root_delay: 0,
+            root_dispersion: 0,
+            reference_id: REFERENCE_ID,
+            reference: receive,
+            origin: request.transmit,
+            receive,
+            transmit: Timestamp::default(),
+        };
+        reply.transmit = Timestamp::now();
+        if let Err(e) = socket.send_to(&reply.encode(), from) {
+            warn!("Answering {} failed: {}", from, e);
+            report.errors += 1;
+            continue;
+        }
+        answered += 1;
+        debug!("Answered {}", from);
+    }
+
+    info!("Answered {} requests", answered);
+    report.detail("answered", answered);
+    Ok(())
+}
+
+/// Sends one request from `socket` and returns the answer with the offset
+/// and delay it gives. Answers to earlier requests are skipped.
+pub fn query(
+    socket: &UdpSocket,
+    server: SocketAddr,
+    timeout: Duration,
+) -> io::Result<(Packet, Sample)> {
+    let t1 = Timestamp::now();
+    socket.send_to(&Packet::request(t1).encode(), server)?;
+    let deadline = Instant::now() + timeout;
+
+    let mut buf = [0u8; 1024];
+    loop {
+        let left = deadline
+            .checked_duration_since(Instant::now())
+            .filter(|left| !left.is_zero())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::TimedOut, "No answer"))?;
+        socket.set_read_timeout(Some(left))?;
+        let (n, from) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(e)
+                if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut =>
+            {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "No answer"))
+            }
+            Err(e) => return Err(e),
+        };
+        let t4 = Timestamp::now();
+
+        // The origin must be our transmit timestamp, so that stale or
+        // spoofed answers are not taken for this one
+        match Packet::decode(&buf[..n]) {
+            Some(reply) if from == server && reply.mode == MODE_SERVER && reply.origin == t1 => {
+                let sample = Sample::new(t1, reply.receive, reply.transmit, t4);
+                return Ok((reply, sample));
+            }
+            _ => debug!("Ignoring unexpected packet from {}", from),
+        }
+    }
+}
+
+fn client(
+    server: SocketAddr,
+    count: u32,
+    interval: Duration,
+    timeout: Duration,
+    report: &mut Report,
+) -> Result<(), Box<dyn Error>> {
+    let any: IpAddr = match server {
+        SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    };
+    let socket = UdpSocket::bind(SocketAddr::new(any, 0))?;
+
+    let mut samples = Vec::new();
+    for i in 0..count {
+        if i > 0 {
+            thread::sleep(interval);
+        }
+        match query(&socket, server, timeout) {
+            // A stratum of zero is a "kiss-o'-death" with the reason in the reference id
+            Ok((reply, _)) if reply.stratum == 0 => {
+                let code = String::from_utf8_lossy(&reply.reference_id).to_string();
+                println!("{}: kiss-o'-death {:?}", server, code);
+                if code == "RATE" || code == "DENY" || code == "RSTR" {
+                    break;
+                }
+            }
+            Ok((reply, sample)) => {
+                if reply.leap == ntp::LEAP_UNSYNCHRONIZED {
+                    warn!("{} reports that its clock is not synchronized", server);
+                }
+                println!("{}: stratum {}, {}", server, reply.stratum, sample);
+                samples.push(sample);
+            }
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => {
+                println!("{}: no answer in {:?}", server, timeout);
+                report.errors += 1;
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    print_summary(server, count, &samples);
+    report.detail("server", server);
+    report.detail("requests", count);
+    report.detail("samples", &samples);
+    Ok(())
+}
+
+/// The offset of the sample with the smallest delay is the most accurate,
+/// since the offset is off by at most half of the delay.
+fn print_summary(server: SocketAddr, count: u32, samples: &[Sample]) {
+    println!("--- {} time statistics ---", server);
+    println!("{} requests, {} answers", count, samples.len());
+    let Some(best) = samples.iter().min_by(|a, b| a.delay.total_cmp(&b.delay)) else {
+        return;
+    };
+    let n = samples.len() as f64;
+    let mean = samples.iter().map(|s| s.offset).sum::<f64>() / n;
+    let deviation = (samples
+        .iter()
+        .map(|s| (s.offset - mean).powi(2))
+        .sum::<f64>()
+        / n)
+        .sqrt();
+    let delays = samples.iter().map(|s| s.delay);
+    let min_delay = delays.clone().fold(f64::INFINITY, f64::min);
+    let max_delay = delays.clone().fold(f64::NEG_INFINITY, f64::max);
+    let mean_delay = delays.sum::<f64>() / n;
+    println!(
+        "offset {:+.6} s (best sample), mean {:+.6} s, stddev {:.6} s",
+        best.offset, mean, deviation
+    );
+    println!(
+        "delay min/avg/max = {:.6}/{:.6}/{:.6} s",
+        min_delay, mean_delay, max_delay
+    );
+}
@@ -0,0 +1,8 @@
+use adnet_core::exit;
+use clap::Parser;
+use std::process::ExitCode;
+use task_time::Args;
+
+fn main() -> ExitCode {
+    exit::exit(task_time::run(Args::parse()))
+}
@@ -0,0 +1,144 @@
+//! The NTP packet format of RFC 5905, as far as a client and a simple server
+//! need it. Both ends of task-time use it, so the client also works against
+//! real NTP servers.
+
+use std::{
+    fmt,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+
+pub const PACKET_SIZE: usize = 48;
+pub const VERSION: u8 = 4;
+pub const MODE_CLIENT: u8 = 3;
+pub const MODE_SERVER: u8 = 4;
+/// Leap indicator of a server whose clock is not synchronized
+pub const LEAP_UNSYNCHRONIZED: u8 = 3;
+
+// Seconds from 1900, the NTP epoch, to 1970
+const UNIX_OFFSET: u64 = 2_208_988_800;
+
+/// Seconds since 1900 as a 32.32 fixed point number.
+#[derive(Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub struct Timestamp(pub u64);
+
+impl Timestamp {
+    pub fn now() -> Self {
+        Self::from_system(SystemTime::now())
+    }
+
+    pub fn from_system(time: SystemTime) -> Self {
+        let since_unix = time.duration_since(UNIX_EPOCH).unwrap_or(Duration::ZERO);
+        let secs = since_unix.as_secs() + UNIX_OFFSET;
+        let fraction = ((since_unix.subsec_nanos() as u64) << 32) / 1_000_000_000;
+        // Wraps around in 2036, at the end of the first NTP era
+        Timestamp((secs << 32) | fraction)
+    }
+
+    /// Seconds from `earlier` to `self`, correct across an era boundary as
+    /// long as they are less than 68 years apart.
+    pub fn since(self, earlier: Timestamp) -> f64 {
+        self.0.wrapping_sub(earlier.0) as i64 as f64 / (1u64 << 32) as f64
+    }
+}
+
+/// The fields of an NTP packet, without extensions.
+#[derive(Clone, Debug, Default)]
+pub struct Packet {
+    pub leap: u8,
+    pub version: u8,
+    pub mode: u8,
+    pub stratum: u8,
+    pub poll: i8,
+    pub precision: i8,
+    /// 16.16 fixed point seconds
+    pub root_delay: u32,
+    pub root_dispersion: u32,
+    pub reference_id: [u8; 4],
+    pub reference: Timestamp,
+    /// The transmit timestamp of the request this packet answers
+    pub origin: Timestamp,
+    pub receive: Timestamp,
+    pub transmit: Timestamp,
+}
+
+impl Packet {
+    /// A client request. The transmit timestamp is what the server copies
+    /// to the origin of its answer.
+    pub fn request(transmit: Timestamp) -> Self {
+        Packet {
+            version: VERSION,
+            mode: MODE_CLIENT,
+            transmit,
+            ..Default::default()
+        }
+    }
+
+    pub fn encode(&self) -> [u8; PACKET_SIZE] {
+        let mut buf = [0u8; PACKET_SIZE];
+        buf[0] = (self.leap << 6) | ((self.version & 0x07) << 3) | (self.mode & 0x07);
+        buf[1] = self.stratum;
+        buf[2] = self.poll as u8;
+        buf[3] = self.precision as u8;
+        buf[4..8].copy_from_slice(&self.root_delay.to_be_bytes());
+        buf[8..12].copy_from_slice(&self.root_dispersion.to_be_bytes());
+        buf[12..16].copy_from_slice(&self.reference_id);
+        buf[16..24].copy_from_slice(&self.reference.0.to_be_bytes());
+        buf[24..32].copy_from_slice(&self.origin.0.to_be_bytes());
+        buf[32..40].copy_from_slice(&self.receive.0.to_be_bytes());
+        buf[40..48].copy_from_slice(&self.transmit.0.to_be_bytes());
+        buf
+    }
+
+    /// Parses a packet, ignoring any extension fields after the header.
+    pub fn decode(buf: &[u8]) -> Option<Self> {
+        if buf.len() < PACKET_SIZE {
+            return None;
+        }
+        let u32_at =
+            |at: usize| u32::from_be_bytes([buf[at], buf[at + 1], buf[at + 2], buf[at + 3]]);
+        let timestamp_at =
+            |at: usize| Timestamp(((u32_at(at) as u64) << 32) | u32_at(at + 4) as u64);
+        Some(Packet {
+            leap: buf[0] >> 6,
+            version: (buf[0] >> 3) & 0x07,
+            mode: buf[0] & 0x07,
+            stratum: buf[1],
+            poll: buf[2] as i8,
+            precision: buf[3] as i8,
+            root_delay: u32_at(4),
+            root_dispersion: u32_at(8),
+            reference_id: [buf[12], buf[13], buf[14], buf[15]],
+            reference: timestamp_at(16),
+            origin: timestamp_at(24),
+            receive: timestamp_at(32),
+            transmit: timestamp_at(40),
+        })
+    }
+}
+
+/// One exchange of the four timestamps: the client sends at t1, the server
+/// receives at t2 and answers at t3, and the client receives at t4.
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct Sample {
+    /// Seconds the server's clock is ahead of the client's
+    pub offset: f64,
+    /// Round-trip time of the network, without the time spent in the server
+    pub delay: f64,
+}
+
+impl Sample {
+    pub fn new(t1: Timestamp, t2: Timestamp, t3: Timestamp, t4: Timestamp) -> Self {
+        Sample {
+            offset: (t2.since(t1) + t3.since(t4)) / 2.0,
+            delay: t4.since(t1) - t3.since(t2),
+        }
+    }
+}
+
+impl fmt::Display for Sample {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "offset {:+.6} s, delay {:.6} s", self.offset, self.delay)
+    }
+}
@@ -0,0 +1,344 @@
+//! Traceroute with UDP or ICMP echo probes. Probes are sent with increasing
+//! time to live, and the routers that drop them answer with ICMP Time
+//! Exceeded, revealing the path hop by hop. The ICMP socket and message
+//! parsing come from task-ping. The task-trace binary and the `adnet trace`
+//! subcommand are thin wrappers around [`run`].
+
+use std::{
+    error::Error,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    process,
+    time::{Duration, Instant},
+};
+
+use adnet_core::{
+    config::{self, ConfigArgs},
+    logging::{self, LogArgs},
+    report::{Report, ReportArgs},
+};
+use clap::{Parser, ValueEnum};
+use serde::{Deserialize, Serialize};
+use socket2::SockRef;
+use task_ping::{
+    icmp::{self, IcmpSocket, Message},
+    resolve,
+    stats::ms,
+};
+use tracing::debug;
+
+const DEFAULT_MAX_HOPS: u8 = 30;
+const DEFAULT_PROBES: u32 = 3;
+const DEFAULT_PORT: u16 = 33434;
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(1);
+const PROBE_SIZE: usize = 32;
+const UDP: u8 = 17;
+// Port unreachable, the answer of the destination to a UDP probe
+const V4_PORT_UNREACHABLE: u8 = 3;
+const V6_PORT_UNREACHABLE: u8 = 4;
+
+#[derive(ValueEnum, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    /// UDP datagrams to unused ports, answered with Port Unreachable
+    Udp,
+    /// ICMP echo requests, answered with Echo Reply
+    Icmp,
+}
+
+/// Prints the route packets take to a host.
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+pub struct Args {
+    /// Host name or address to trace the route to
+    destination: Option<String>,
+
+    /// Kind of probe packets [default: udp]
+    #[arg(short, long)]
+    mode: Option<Mode>,
+
+    /// Time to live of the first probes
+    #[arg(short, long, default_value_t = 1)]
+    first_ttl: u8,
+
+    /// Largest time to live to try [default: 30]
+    #[arg(short = 'H', long)]
+    max_hops: Option<u8>,
+
+    /// Probes sent to every hop [default: 3]
+    #[arg(short, long)]
+    probes: Option<u32>,
+
+    /// First destination port of UDP probes, increased for every probe [default: 33434]
+    #[arg(long)]
+    port: Option<u16>,
+
+    /// Seconds to wait for the answer to a probe [default: 1]
+    #[arg(short = 'W', long, value_parser = config::parse_secs)]
+    timeout: Option<Duration>,
+
+    /// Print the result as JSON instead of a table
+    #[arg(long)]
+    json: bool,
+
+    #[command(flatten)]
+    config: ConfigArgs,
+
+    #[command(flatten)]
+    log: LogArgs,
+
+    #[command(flatten)]
+    report: ReportArgs,
+}
+
+/// The [task-trace] section of the config file
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct FileConfig {
+    destination: Option<String>,
+    mode: Option<Mode>,
+    max_hops: Option<u8>,
+    probes: Option<u32>,
+    port: Option<u16>,
+    #[serde(deserialize_with = "config::secs")]
+    timeout: Option<Duration>,
+}
+
+/// Result of the whole trace, also the format of the JSON output.
+#[derive(Serialize, Debug)]
+pub struct Trace {
+    pub destination: String,
+    pub address: IpAddr,
+    pub mode: Mode,
+    pub reached: bool,
+    pub hops: Vec<Hop>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Hop {
+    pub ttl: u8,
+    pub probes: Vec<Probe>,
+}
+
+/// One probe: who answered and when, or nothing if it timed out.
+#[derive(Serialize, Debug, Default)]
+pub struct Probe {
+    pub from: Option<IpAddr>,
+    pub rtt_ms: Option<f64>,
+    /// Code of a Destination Unreachable answer, other than the port
+    /// unreachable expected from the destination of UDP probes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unreachable: Option<u8>,
+}
+
+/// Sends probes and waits for their answers.
+struct Tracer {
+    icmp: IcmpSocket,
+    udp: Option<UdpSocket>,
+    destination: IpAddr,
+    port: u16,
+    timeout: Duration,
+    id: u16,
+    seq: u16,
+    buf: Vec<u8>,
+}
+
+impl Tracer {
+    fn new(destination: IpAddr, mode: Mode, port: u16, timeout: Duration) -> io::Result<Self> {
+        // Time Exceeded messages are only delivered to raw sockets
+        let icmp = IcmpSocket::raw(destination)?;
+        let udp = match mode {
+            Mode::Udp => {
+                let any: IpAddr = match destination {
+                    IpAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+                    IpAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+                };
+                Some(UdpSocket::bind(SocketAddr::new(any, 0))?)
+            }
+            Mode::Icmp => None,
+        };
+        Ok(Tracer {
+            icmp,
+            udp,
+            destination,
+            port,
+            timeout,
+            id: process::id() as u16,
+            seq: 0,
+            buf: vec![0u8; 65536],
+        })
+    }
+
+    /// Sends one probe with the given time to live and waits for its answer.
+    /// Returns the probe and whether it came from the destination.
+    fn probe(&mut self, ttl: u8) -> io::Result<(Probe, bool)> {
+        self.seq = self.seq.wrapping_add(1);
+        let seq = self.seq;
+        let ipv6 = self.destination.is_ipv6();
+
+        // The destination port tells the UDP probes apart
+        let probe_port = self.port.wrapping_add(seq);
+        let local_port = match &self.udp {
+            Some(udp) => {
+                match ipv6 {
+                    true => SockRef::from(udp).set_unicast_hops_v6(ttl as u32)?,
+                    false => udp.set_ttl(ttl as u32)?,
+                }
+                udp.send_to(
+                    &[0u8; PROBE_SIZE],
+                    SocketAddr::new(self.destination, probe_port),
+                )?;
+                udp.local_addr()?.port()
+            }
+            None => {
+                self.icmp.set_ttl(ttl as u32)?;
+                let request = icmp::echo_request(ipv6, self.id, seq, PROBE_SIZE);
+                self.icmp.send_to(&request, self.destination)?;
+                0
+            }
+        };
+        let sent = Instant::now();
+        let deadline = sent + self.timeout;
+
+        // Only the answers to this probe count, anything else is skipped
+        let matches = |original: &[u8]| match self.udp {
+            Some(_) => icmp::quoted_packet(original, ipv6).is_some_and(|(dst, proto, udp)| {
+                dst == self.destination
+                    && proto == UDP
+                    && udp.len() >= 4
+                    && u16::from_be_bytes([udp[0], udp[1]]) == local_port
+                    && u16::from_be_bytes([udp[2], udp[3]]) == probe_port
+            }),
+            None => icmp::quoted_echo(original, ipv6) == Some((self.id, seq)),
+        };
+
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok((Probe::default(), false));
+            }
+            let Some((data, from)) = self.icmp.recv_from(&mut self.buf, deadline - now)? else {
+                return Ok((Probe::default(), false));
+            };
+            let rtt = Some(ms(sent.elapsed()));
+
+            let port_unreachable = match ipv6 {
+                true => V6_PORT_UNREACHABLE,
+                false => V4_PORT_UNREACHABLE,
+            };
+            let answer = |unreachable| Probe {
+                from: Some(from),
+                rtt_ms: rtt,
+                unreachable,
+            };
+            match Message::parse(data, ipv6) {
+                Some(Message::TimeExceeded { original }) if matches(original) => {
+                    return Ok((answer(None), false));
+                }
+                Some(Message::Unreachable { code, original }) if matches(original) => {
+                    let reached = from == self.destination;
+                    let expected = reached && self.udp.is_some() && code == port_unreachable;
+                    return Ok((answer((!expected).then_some(code)), reached));
+                }
+                Some(Message::EchoReply { id, seq: reply, .. })
+                    if self.udp.is_none() && id == self.id && reply == seq =>
+                {
+                    return Ok((answer(None), true));
+                }
+                other => debug!("Skipping ICMP message from {}: {:?}", from, other),
+            }
+        }
+    }
+}
+
+/// Runs the trace with the given arguments, as the task-trace binary does.
+pub fn run(args: Args) -> Result<(), Box<dyn Error>> {
+    logging::init(&args.log)?;
+    let report = args.report.clone();
+    report.run("task-trace", |report| traceroute(args, report))
+}
+
+fn traceroute(args: Args, report: &mut Report) -> Result<(), Box<dyn Error>> {
+    // Command line options take precedence over the config file
+    let file: FileConfig = args.config.section("task-trace")?;
+    let destination = args
+        .destination
+        .or(file.destination)
+        .ok_or("Destination is required")?;
+    let mode = args.mode.or(file.mode).unwrap_or(Mode::Udp);
+    let max_hops = args.max_hops.or(file.max_hops).unwrap_or(DEFAULT_MAX_HOPS);
+    let probes = args.probes.or(file.probes).unwrap_or(DEFAULT_PROBES);
+    let port = args.port.or(file.port).unwrap_or(DEFAULT_PORT);
+    let timeout = args.timeout.or(file.timeout).unwrap_or(DEFAULT_TIMEOUT);
+
+    if args.first_ttl == 0 || args.first_ttl > max_hops {
+        return Err("First TTL must be between 1 and the maximum number of hops".into());
+    }
+    if probes == 0 {
+        return Err("Number of probes must be positive".into());
+    }
+
+    let address = resolve(&destination)?;
+    let mut tracer = Tracer::new(address, mode, port, timeout)?;
+    if !args.json {
+        println!(
+            "traceroute to {} ({}), {} hops max, {:?} probes",
+            destination, address, max_hops, mode
+        );
+    }
+
+    let mut trace = Trace {
+        destination,
+        address,
+        mode,
+        reached: false,
+        hops: Vec::new(),
+    };
+    for ttl in args.first_ttl..=max_hops {
+        let mut hop = Hop {
+            ttl,
+            probes: Vec::new(),
+        };
+        for _ in 0..probes {
+            let (probe, reached) = tracer.probe(ttl)?;
+            trace.reached |= reached;
+            hop.probes.push(probe);
+        }
+        if !args.json {
+            print_hop(&hop);
+        }
+        trace.hops.push(hop);
+        if trace.reached {
+            break;
+        }
+    }
+
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&trace)?);
+    }
+    report.detail("trace", &trace);
+    Ok(())
+}
+
+/// Prints a hop in the style of traceroute: the address whenever it differs
+/// from the previous probe, the RTTs, and `*` for probes without an answer.
+fn print_hop(hop: &Hop) {
+    let mut line = format!("{:2} ", hop.ttl);
+    let mut previous = None;
+    for probe in &hop.probes {
+        match (probe.from, probe.rtt_ms) {
+            (Some(from), Some(rtt)) => {
+                if previous != Some(from) {
+                    line += &format!(" {}", from);
+                    previous = Some(from);
+                }
+                line += &format!("  {:.3} ms", rtt);
+                if let Some(code) = probe.unreachable {
+                    line += &format!(" !{}", code);
+                }
+            }
+            _ => line += "  *",
+        }
+    }
+    println!("{}", line);
+}
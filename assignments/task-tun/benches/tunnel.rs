@@ -0,0 +1,167 @@
+//! Benchmarks for the per-packet work of the tunnel: parsing the tunneled IP
+//! packet, scanning its payload for filtered words, evaluating the rules and
+//! the ChaCha20-Poly1305 sealing and opening, both on their own and as the
+//! full outgoing and incoming pipelines that `handle_tun_event` and
+//! `handle_socket_event` run for each packet, with the functions of
+//! `task_tun::pipeline`.
+//!
+//! Run with `cargo bench -p task-tun`.
+
+use std::{hint::black_box, net::SocketAddr, time::Instant};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use etherparse::{InternetSlice, SlicedPacket};
+use pktgen::Spec;
+use task_tun::fragment::{Fragmenter, Reassembler};
+use task_tun::packet::{self, Cipher};
+use task_tun::pipeline;
+use task_tun::rules::{Direction, Rules};
+use task_tun::Metrics;
+
+const TAYLOR: &[u8; 6] = b"taylor";
+const PAYLOAD_SIZES: [usize; 3] = [64, 512, 1400];
+const KEY: [u8; packet::KEY_SIZE] = [7; packet::KEY_SIZE];
+// A path MTU of 1500 less the IP and UDP headers
+const MAX_DATAGRAM: usize = 1472;
+
+/// An IPv4/UDP packet between the tunnel addresses with a payload that
+/// contains none of the filtered words.
+fn ip_packet(payload_size: usize) -> Vec<u8> {
+    let payload: Vec<u8> = (0..payload_size).map(|i| b'a' + (i % 20) as u8).collect();
+    Spec::udp(
+        ([10, 100, 0, 1].into(), 40000),
+        ([10, 100, 0, 2].into(), 5000),
+    )
+    .build(&payload)
+    .unwrap()
+}
+
+fn transforms(c: &mut Criterion) {
+    let cipher = Cipher::new(&KEY);
+    let mut group = c.benchmark_group("transform");
+    for size in PAYLOAD_SIZES {
+        let plain = ip_packet(size);
+        let mut sealed = vec![0u8; plain.len() + packet::OVERHEAD];
+        cipher.seal(&plain, &mut sealed);
+        group.throughput(Throughput::Bytes(plain.len() as u64));
+
+        let mut buf = sealed.clone();
+        group.bench_function(BenchmarkId::new("seal", size), |b| {
+            b.iter(|| cipher.seal(black_box(&plain), black_box(&mut buf)))
+        });
+        // Opening decrypts in place, so start from the sealed datagram each time
+        group.bench_function(BenchmarkId::new("open", size), |b| {
+            b.iter(|| {
+                buf.copy_from_slice(&sealed);
+                black_box(cipher.open(black_box(&mut buf)).is_ok())
+            })
+        });
+    }
+    group.finish();
+}
+
+fn filtering(c: &mut Criterion) {
+    let mut group = c.benchmark_group("you_shall_not_pass");
+    for size in PAYLOAD_SIZES {
+        let buf = ip_packet(size);
+        let sliced = SlicedPacket::from_ip(&buf).unwrap();
+        let Some(InternetSlice::Ipv4(ipv4)) = &sliced.net else {
+            unreachable!("benchmark packet is IPv4");
+        };
+        let payload = ipv4.payload();
+        group.throughput(Throughput::Bytes(payload.payload.len() as u64));
+        group.bench_function(BenchmarkId::from_parameter(size), |b| {
+            b.iter(|| packet::you_shall_not_pass(black_box(TAYLOR), black_box(payload)))
+        });
+    }
+    group.finish();
+}
+
+fn rules(c: &mut Criterion) {
+    let rules = Rules::default();
+    let metrics = Metrics::new();
+    let mut group = c.benchmark_group("rules");
+    for size in PAYLOAD_SIZES {
+        let plain = ip_packet(size);
+        let sliced = SlicedPacket::from_ip(&plain).unwrap();
+        group.throughput(Throughput::Bytes(plain.len() as u64));
+        group.bench_function(BenchmarkId::new("evaluate", size), |b| {
+            b.iter(|| rules.evaluate(Direction::Out, black_box(&sliced)))
+        });
+        // Parsing and all, as the tunnel does. The filter may rewrite the
+        // packet, so start from the original each time
+        let mut buf = plain.clone();
+        group.bench_function(BenchmarkId::new("filter", size), |b| {
+            b.iter(|| {
+                buf.copy_from_slice(&plain);
+                pipeline::filter(Direction::Out, black_box(&mut buf), &rules, &metrics)
+            })
+        });
+    }
+    group.finish();
+}
+
+fn pipelines(c: &mut Criterion) {
+    let cipher = Cipher::new(&KEY);
+    let rules = Rules::default();
+    let metrics = Metrics::new();
+    let mut fragmenter = Fragmenter::new(MAX_DATAGRAM);
+    let mut reassembler = Reassembler::new();
+    let peer: SocketAddr = "192.0.2.2:5000".parse().unwrap();
+    let mut group = c.benchmark_group("pipeline");
+    for size in PAYLOAD_SIZES {
+        let plain = ip_packet(size);
+        // The datagram that the peer sends for the same packet
+        let mut datagram = Vec::new();
+        pipeline::split_packet(&plain, Some(&cipher), &mut fragmenter, &metrics, |d| {
+            datagram = d.to_vec();
+            Ok(())
+        })
+        .unwrap();
+        group.throughput(Throughput::Bytes(plain.len() as u64));
+
+        // Copy into a fresh buffer each time, as the tunnel reads into one
+        let mut buf = [0u8; 1500];
+        let n = plain.len();
+        group.bench_function(BenchmarkId::new("outgoing", size), |b| {
+            b.iter(|| {
+                buf[..n].copy_from_slice(&plain);
+                pipeline::forward_out(&mut buf[..n], &rules, &None, &metrics, |ip_packet| {
+                    pipeline::split_packet(
+                        ip_packet,
+                        Some(&cipher),
+                        &mut fragmenter,
+                        &metrics,
+                        |datagram| {
+                            black_box(datagram);
+                            Ok(())
+                        },
+                    )
+                })
+                .unwrap()
+            })
+        });
+        group.bench_function(BenchmarkId::new("incoming", size), |b| {
+            b.iter(|| {
+                let ip_packet = pipeline::join_packet(
+                    black_box(&datagram),
+                    peer,
+                    Some(&cipher),
+                    &mut reassembler,
+                    Instant::now(),
+                    &metrics,
+                )
+                .expect("the datagram carries a whole packet");
+                pipeline::forward_in(ip_packet, &rules, &None, &metrics, |ip_packet| {
+                    black_box(ip_packet);
+                    Ok(())
+                })
+                .unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, transforms, filtering, rules, pipelines);
+criterion_main!(benches);
@@ -0,0 +1,130 @@
+//! Batched reads from the UDP socket of the tunnel. On Linux, one
+//! `recvmmsg` call receives up to [`BATCH`] datagrams, so that a burst of
+//! packets costs one system call rather than one each. Elsewhere a batch is
+//! one datagram.
+
+use std::{io, mem, net::SocketAddr};
+
+use crate::{TunnelSocket, MAX_DATAGRAM};
+
+pub const BATCH: usize = 16;
+
+/// The buffers of a batch, and the datagrams received into them.
+pub struct RecvBatch {
+    bufs: Vec<Vec<u8>>,
+    // The length and sender of the datagram in each buffer
+    received: Vec<(usize, SocketAddr)>,
+    next: usize,
+}
+
+impl RecvBatch {
+    pub fn new() -> Self {
+        Self {
+            bufs: (0..BATCH).map(|_| vec![0; MAX_DATAGRAM]).collect(),
+            received: Vec::with_capacity(BATCH),
+            next: 0,
+        }
+    }
+
+    /// Returns the length and sender of the next datagram, which is then at
+    /// the start of `buf`: the buffers are swapped rather than copied, so
+    /// `buf` must be [`MAX_DATAGRAM`] bytes like those of the batch. Reads a
+    /// new batch when this one is used up, and fails with `WouldBlock` when
+    /// there is nothing to read.
+    pub fn recv_from(
+        &mut self,
+        socket: &mut TunnelSocket,
+        buf: &mut Vec<u8>,
+    ) -> io::Result<(usize, SocketAddr)> {
+        if self.next == self.received.len() {
+            self.received.clear();
+            self.next = 0;
+            self.fill(socket)?;
+        }
+        let i = self.next;
+        self.next += 1;
+        mem::swap(buf, &mut self.bufs[i]);
+        Ok(self.received[i])
+    }
+
+    #[cfg(target_os = "linux")]
+    fn fill(&mut self, socket: &mut TunnelSocket) -> io::Result<()> {
+        use std::os::unix::io::AsRawFd;
+
+        let mut addresses: [libc::sockaddr_storage; BATCH] = unsafe { mem::zeroed() };
+        let mut iovecs: Vec<libc::iovec> = self
+            .bufs
+            .iter_mut()
+            .map(|buf| libc::iovec {
+                iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+                iov_len: buf.len(),
+            })
+            .collect();
+        let mut messages: [libc::mmsghdr; BATCH] = unsafe { mem::zeroed() };
+        for ((message, iovec), address) in messages
+            .iter_mut()
+            .zip(iovecs.iter_mut())
+            .zip(addresses.iter_mut())
+        {
+            message.msg_hdr.msg_name = address as *mut _ as *mut libc::c_void;
+            message.msg_hdr.msg_namelen =
+                mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+            message.msg_hdr.msg_iov = iovec;
+            message.msg_hdr.msg_iovlen = 1;
+        }
+
+        let n = unsafe {
+            libc::recvmmsg(
+                socket.get_mut().as_raw_fd(),
+                messages.as_mut_ptr(),
+                BATCH as libc::c_uint,
+                libc::MSG_DONTWAIT as _,
+                std::ptr::null_mut(),
+            )
+        };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        for (message, address) in messages.iter().zip(&addresses).take(n as usize) {
+            // A datagram of another family is empty, and skipped
+            let received = match socket_addr(address) {
+                Some(src) => (message.msg_len as usize, src),
+                None => (0, SocketAddr::from(([0, 0, 0, 0], 0))),
+            };
+            self.received.push(received);
+        }
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn fill(&mut self, socket: &mut TunnelSocket) -> io::Result<()> {
+        use netem::Datagram;
+
+        let received = socket.recv_from(&mut self.bufs[0])?;
+        self.received.push(received);
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn socket_addr(address: &libc::sockaddr_storage) -> Option<SocketAddr> {
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV6};
+
+    match address.ss_family as libc::c_int {
+        libc::AF_INET => {
+            let address = unsafe { &*(address as *const _ as *const libc::sockaddr_in) };
+            let ip = Ipv4Addr::from(u32::from_be(address.sin_addr.s_addr));
+            Some(SocketAddr::from((ip, u16::from_be(address.sin_port))))
+        }
+        libc::AF_INET6 => {
+            let address = unsafe { &*(address as *const _ as *const libc::sockaddr_in6) };
+            Some(SocketAddr::V6(SocketAddrV6::new(
+                Ipv6Addr::from(address.sin6_addr.s6_addr),
+                u16::from_be(address.sin6_port),
+                address.sin6_flowinfo,
+                address.sin6_scope_id,
+            )))
+        }
+        _ => None,
+    }
+}
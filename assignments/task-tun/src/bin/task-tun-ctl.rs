@@ -0,0 +1,8 @@
+use adnet_core::exit;
+use clap::Parser;
+use std::process::ExitCode;
+use task_tun::control::{self, CtlArgs};
+
+fn main() -> ExitCode {
+    exit::exit(control::run(CtlArgs::parse()))
+}
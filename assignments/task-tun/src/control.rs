@@ -0,0 +1,159 @@
+//! The control socket of a running tunnel. With `--control PATH`, the tunnel
+//! listens on a Unix domain socket at PATH for one command per connection,
+//! a line of text, and answers it with lines of text before closing the
+//! connection:
+//!
+//! - `stats`: the counters of the tunnel, one `name value` per line
+//! - `rules`: the filtering rules in effect, in the order they apply
+//! - `state`: the peer, the encryption, the MTU and the keepalives
+//! - `verbose on|off`: logs every packet, or goes back to the usual level
+//! - `log FILTER`: sets the log filter, in the syntax of RUST_LOG
+//! - `help`: the commands
+//!
+//! The tunnel answers between packets, so a client that connects and sends
+//! nothing holds up the tunnel for at most [`CLIENT_TIMEOUT`]. The
+//! task-tun-ctl program, or `adnet tun-ctl`, sends a command and prints the
+//! answer.
+
+use std::{
+    fs,
+    io::{self, BufRead, BufReader, Read, Write},
+    os::unix::{
+        fs::FileTypeExt,
+        io::{AsRawFd, RawFd},
+        net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use clap::Parser;
+use tracing::{debug, warn};
+
+pub const CLIENT_TIMEOUT: Duration = Duration::from_secs(1);
+// The longest command accepted
+const MAX_COMMAND: u64 = 1024;
+
+pub const HELP: &str = "\
+stats            counters of the tunnel
+rules            filtering rules in effect
+state            peer, encryption, MTU and keepalives
+verbose on|off   log every packet, or go back to the usual level
+log FILTER       set the log filter, e.g. task_tun=debug
+help             this list
+";
+
+/// The listening control socket, removed when dropped.
+pub struct ControlServer {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+impl ControlServer {
+    /// Listens at `path`, replacing a socket that was left behind by a
+    /// tunnel that no longer runs.
+    pub fn bind(path: &Path) -> io::Result<Self> {
+        let stale = fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_socket())
+            && UnixStream::connect(path).is_err();
+        if stale {
+            fs::remove_file(path)?;
+        }
+        let listener = UnixListener::bind(path).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("Cannot listen on control socket {}: {}", path.display(), e),
+            )
+        })?;
+        listener.set_nonblocking(true)?;
+        Ok(Self {
+            listener,
+            path: path.to_path_buf(),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Answers each waiting client with what `answer` returns for its
+    /// command.
+    pub fn serve(&self, mut answer: impl FnMut(&str) -> String) {
+        loop {
+            let stream = match self.listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(e) => {
+                    warn!("Control socket: {}", e);
+                    return;
+                }
+            };
+            if let Err(e) = serve_client(stream, &mut answer) {
+                debug!("Control client: {}", e);
+            }
+        }
+    }
+}
+
+impl AsRawFd for ControlServer {
+    fn as_raw_fd(&self) -> RawFd {
+        self.listener.as_raw_fd()
+    }
+}
+
+impl Drop for ControlServer {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+fn serve_client(stream: UnixStream, answer: &mut impl FnMut(&str) -> String) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+    let mut command = String::new();
+    BufReader::new((&stream).take(MAX_COMMAND)).read_line(&mut command)?;
+    debug!("Control command '{}'", command.trim());
+    (&stream).write_all(answer(command.trim()).as_bytes())
+}
+
+/// Sends `command` to the control socket at `path` and returns the answer.
+pub fn request(path: &Path, command: &str) -> io::Result<String> {
+    let mut stream = UnixStream::connect(path).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("Cannot connect to control socket {}: {}", path.display(), e),
+        )
+    })?;
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT * 5))?;
+    writeln!(stream, "{}", command)?;
+    let mut answer = String::new();
+    stream.read_to_string(&mut answer)?;
+    Ok(answer)
+}
+
+/// Sends a command to the control socket of a running task-tun.
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None, after_help = HELP)]
+pub struct CtlArgs {
+    /// Control socket of the tunnel, as given to its --control
+    #[arg(short, long, default_value = "tun0.ctl")]
+    control: PathBuf,
+
+    /// The command and its arguments
+    #[arg(required = true)]
+    command: Vec<String>,
+}
+
+/// Runs the command of the arguments, as the task-tun-ctl binary does, and
+/// prints the answer.
+pub fn run(args: CtlArgs) -> io::Result<()> {
+    let answer = request(&args.control, &args.command.join(" "))?;
+    print!("{}", answer);
+    if answer.starts_with("error: ") {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "The tunnel rejected the command",
+        ));
+    }
+    Ok(())
+}
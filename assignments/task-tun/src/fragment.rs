@@ -0,0 +1,395 @@
+//! Fragmentation of the tunneled packets over UDP. The packets read from the
+//! TUN device can be as large as its MTU, and with the encryption the
+//! datagrams would be larger than the MTU of the path between the tunnel
+//! ends, so a [`Fragmenter`] splits them into datagrams that fit. Each
+//! datagram starts with a header of [`HEADER_SIZE`] bytes: the number of the
+//! packet (4 bytes, network byte order), the index of the fragment and the
+//! number of fragments. A packet that fits in one datagram is one fragment
+//! of one.
+//!
+//! A [`Reassembler`] collects the fragments in any order, and gives up on a
+//! packet when a fragment has not arrived in [`REASSEMBLY_TIMEOUT`].
+
+use std::{
+    collections::HashMap,
+    fmt,
+    time::{Duration, Instant},
+};
+
+pub const HEADER_SIZE: usize = 6;
+pub const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(2);
+/// The most fragments of a packet, as the header has one byte for them
+pub const MAX_FRAGMENTS: usize = u8::MAX as usize;
+// Packets reassembled at the same time, beyond which the oldest is dropped
+const MAX_PENDING: usize = 64;
+
+/// A datagram too short for the header, or with a header that does not add up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidFragment;
+
+impl fmt::Display for InvalidFragment {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid fragment header")
+    }
+}
+
+impl std::error::Error for InvalidFragment {}
+
+pub struct Fragmenter {
+    next_id: u32,
+    max_datagram: usize,
+    datagram: Vec<u8>,
+}
+
+impl Fragmenter {
+    /// Splits packets into datagrams of at most `max_datagram` bytes, which
+    /// must leave room for some data after the header.
+    pub fn new(max_datagram: usize) -> Self {
+        assert!(
+            max_datagram > HEADER_SIZE,
+            "datagrams too small to fragment"
+        );
+        Self {
+            next_id: 0,
+            max_datagram,
+            datagram: Vec::with_capacity(max_datagram),
+        }
+    }
+
+    pub fn max_datagram(&self) -> usize {
+        self.max_datagram
+    }
+
+    /// The largest packet that can be sent.
+    pub fn max_packet(&self) -> usize {
+        (self.max_datagram - HEADER_SIZE) * MAX_FRAGMENTS
+    }
+
+    /// Passes the datagrams of `packet` to `send` in order, and returns how
+    /// many there were. Panics if the packet is larger than
+    /// [`Fragmenter::max_packet`].
+    pub fn split<E>(
+        &mut self,
+        packet: &[u8],
+        mut send: impl FnMut(&[u8]) -> Result<(), E>,
+    ) -> Result<usize, E> {
+        assert!(packet.len() <= self.max_packet(), "packet too large");
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+
+        let chunk = self.max_datagram - HEADER_SIZE;
+        let count = packet.len().div_ceil(chunk).max(1);
+        for index in 0..count {
+            let start = index * chunk;
+            let data = &packet[start..(start + chunk).min(packet.len())];
+            self.datagram.clear();
+            self.datagram.extend_from_slice(&id.to_be_bytes());
+            self.datagram.push(index as u8);
+            self.datagram.push(count as u8);
+            self.datagram.extend_from_slice(data);
+            send(&self.datagram)?;
+        }
+        Ok(count)
+    }
+}
+
+struct Partial {
+    started: Instant,
+    fragments: Vec<Option<Vec<u8>>>,
+    missing: usize,
+}
+
+#[derive(Default)]
+pub struct Reassembler {
+    pending: HashMap<u32, Partial>,
+    packet: Vec<u8>,
+}
+
+impl Reassembler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a datagram that arrived at `now`, and returns the packet if the
+    /// datagram completes one.
+    pub fn push(
+        &mut self,
+        datagram: &[u8],
+        now: Instant,
+    ) -> Result<Option<&mut [u8]>, InvalidFragment> {
+        if datagram.len() < HEADER_SIZE {
+            return Err(InvalidFragment);
+        }
+        let id = u32::from_be_bytes([datagram[0], datagram[1], datagram[2], datagram[3]]);
+        let (index, count) = (datagram[4] as usize, datagram[5] as usize);
+        let data = &datagram[HEADER_SIZE..];
+        if index >= count {
+            return Err(InvalidFragment);
+        }
+
+        if count == 1 {
+            self.packet.clear();
+            self.packet.extend_from_slice(data);
+            return Ok(Some(self.packet.as_mut_slice()));
+        }
+
+        if !self.pending.contains_key(&id) && self.pending.len() >= MAX_PENDING {
+            self.drop_oldest();
+        }
+        let partial = self.pending.entry(id).or_insert_with(|| Partial {
+            started: now,
+            fragments: vec![None; count],
+            missing: count,
+        });
+        if partial.fragments.len() != count {
+            // A packet of another sender, or of a restarted one
+            self.pending.remove(&id);
+            return Err(InvalidFragment);
+        }
+        let slot = &mut partial.fragments[index];
+        if slot.is_none() {
+            *slot = Some(data.to_vec());
+            partial.missing -= 1;
+        }
+        if partial.missing > 0 {
+            return Ok(None);
+        }
+
+        let partial = self.pending.remove(&id).expect("the packet is pending");
+        self.packet.clear();
+        for fragment in partial.fragments.into_iter().flatten() {
+            self.packet.extend_from_slice(&fragment);
+        }
+        Ok(Some(self.packet.as_mut_slice()))
+    }
+
+    /// The packets waiting for more fragments.
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Drops the packets that have waited for their fragments for longer
+    /// than [`REASSEMBLY_TIMEOUT`] at `now`, and returns how many.
+    pub fn expire(&mut self, now: Instant) -> usize {
+        let before = self.pending.len();
+        self.pending
+            .retain(|_, partial| now.duration_since(partial.started) < REASSEMBLY_TIMEOUT);
+        before - self.pending.len()
+    }
+
+    fn drop_oldest(&mut self) {
+        let oldest = self
+            .pending
+            .iter()
+            .min_by_key(|(_, partial)| partial.started)
+            .map(|(&id, _)| id);
+        if let Some(id) = oldest {
+            self.pending.remove(&id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Room for 10 bytes of the packet in each datagram
+    const MAX_DATAGRAM: usize = HEADER_SIZE + 10;
+
+    fn packet(len: usize) -> Vec<u8> {
+        (0..len).map(|i| i as u8).collect()
+    }
+
+    fn split(fragmenter: &mut Fragmenter, packet: &[u8]) -> Vec<Vec<u8>> {
+        let mut datagrams = Vec::new();
+        fragmenter
+            .split(packet, |datagram| {
+                datagrams.push(datagram.to_vec());
+                Ok::<_, ()>(())
+            })
+            .unwrap();
+        datagrams
+    }
+
+    /// Pushes the datagrams in order and returns the packet that the last
+    /// one completes, checking that none completes it earlier.
+    fn reassemble(reassembler: &mut Reassembler, datagrams: &[Vec<u8>], now: Instant) -> Vec<u8> {
+        let (last, rest) = datagrams.split_last().unwrap();
+        for datagram in rest {
+            assert_eq!(reassembler.push(datagram, now), Ok(None), "too early");
+        }
+        reassembler
+            .push(last, now)
+            .unwrap()
+            .expect("complete")
+            .to_vec()
+    }
+
+    #[test]
+    fn header_layout() {
+        let mut fragmenter = Fragmenter::new(MAX_DATAGRAM);
+        let datagrams = split(&mut fragmenter, &packet(25));
+        assert_eq!(datagrams.len(), 3);
+        for (index, datagram) in datagrams.iter().enumerate() {
+            assert_eq!(datagram[..HEADER_SIZE], [0, 0, 0, 0, index as u8, 3]);
+        }
+        let lengths: Vec<_> = datagrams.iter().map(Vec::len).collect();
+        assert_eq!(lengths, [MAX_DATAGRAM, MAX_DATAGRAM, HEADER_SIZE + 5]);
+
+        let next = split(&mut fragmenter, &packet(10));
+        assert_eq!(next.len(), 1, "fits in one datagram");
+        assert_eq!(
+            next[0][..HEADER_SIZE],
+            [0, 0, 0, 1, 0, 1],
+            "next packet number"
+        );
+        let empty = split(&mut fragmenter, &[]);
+        assert_eq!(empty, [vec![0, 0, 0, 2, 0, 1]], "empty packet");
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut fragmenter = Fragmenter::new(MAX_DATAGRAM);
+        let mut reassembler = Reassembler::new();
+        let now = Instant::now();
+        for len in [0, 1, 10, 11, 25, fragmenter.max_packet()] {
+            let datagrams = split(&mut fragmenter, &packet(len));
+            assert_eq!(
+                reassemble(&mut reassembler, &datagrams, now),
+                packet(len),
+                "{len} bytes"
+            );
+        }
+        assert_eq!(reassembler.pending(), 0);
+    }
+
+    #[test]
+    fn out_of_order() {
+        let mut fragmenter = Fragmenter::new(MAX_DATAGRAM);
+        let mut reassembler = Reassembler::new();
+        let now = Instant::now();
+        let mut datagrams = split(&mut fragmenter, &packet(45));
+        datagrams.reverse();
+        assert_eq!(
+            reassemble(&mut reassembler, &datagrams, now),
+            packet(45),
+            "reversed"
+        );
+
+        // The fragments of two packets interleaved
+        let a = split(&mut fragmenter, &packet(20));
+        let b = split(&mut fragmenter, &packet(30));
+        assert_eq!(reassembler.push(&b[2], now), Ok(None));
+        assert_eq!(reassembler.push(&a[1], now), Ok(None));
+        assert_eq!(reassembler.push(&b[0], now), Ok(None));
+        assert_eq!(reassembler.pending(), 2);
+        assert_eq!(reassemble(&mut reassembler, &a[..1], now), packet(20));
+        assert_eq!(reassemble(&mut reassembler, &b[1..2], now), packet(30));
+        assert_eq!(reassembler.pending(), 0);
+    }
+
+    #[test]
+    fn duplicates() {
+        let mut fragmenter = Fragmenter::new(MAX_DATAGRAM);
+        let mut reassembler = Reassembler::new();
+        let now = Instant::now();
+        let datagrams = split(&mut fragmenter, &packet(25));
+        let twice = [&datagrams[..2], &datagrams[..]].concat();
+        assert_eq!(reassemble(&mut reassembler, &twice, now), packet(25));
+
+        // A late copy starts the packet over, until it expires
+        assert_eq!(reassembler.push(&datagrams[1], now), Ok(None));
+        assert_eq!(reassembler.pending(), 1);
+        assert_eq!(reassembler.expire(now + REASSEMBLY_TIMEOUT), 1);
+    }
+
+    #[test]
+    fn timeout() {
+        let mut fragmenter = Fragmenter::new(MAX_DATAGRAM);
+        let mut reassembler = Reassembler::new();
+        let start = Instant::now();
+        let datagrams = split(&mut fragmenter, &packet(25));
+        assert_eq!(reassembler.push(&datagrams[0], start), Ok(None));
+
+        let later = start + REASSEMBLY_TIMEOUT - Duration::from_millis(1);
+        assert_eq!(reassembler.expire(later), 0, "before the timeout");
+        // The time of the first fragment counts, not that of the last
+        assert_eq!(reassembler.push(&datagrams[1], later), Ok(None));
+        assert_eq!(
+            reassembler.expire(start + REASSEMBLY_TIMEOUT),
+            1,
+            "at the timeout"
+        );
+        assert_eq!(reassembler.pending(), 0);
+
+        // The last fragment alone does not complete the packet any more
+        let now = start + REASSEMBLY_TIMEOUT;
+        assert_eq!(reassembler.push(&datagrams[2], now), Ok(None));
+    }
+
+    #[test]
+    fn oldest_packet_is_evicted() {
+        let mut fragmenter = Fragmenter::new(MAX_DATAGRAM);
+        let mut reassembler = Reassembler::new();
+        let start = Instant::now();
+        let packets: Vec<_> = (0..=MAX_PENDING)
+            .map(|_| split(&mut fragmenter, &packet(20)))
+            .collect();
+        for (i, datagrams) in packets.iter().enumerate() {
+            let now = start + Duration::from_millis(i as u64);
+            assert_eq!(reassembler.push(&datagrams[0], now), Ok(None));
+        }
+        assert_eq!(reassembler.pending(), MAX_PENDING);
+
+        let now = start + Duration::from_secs(1);
+        assert_eq!(reassembler.push(&packets[0][1], now), Ok(None), "evicted");
+        // Which evicted the second packet in turn, and not the last
+        assert_eq!(reassembler.push(&packets[1][1], now), Ok(None), "evicted");
+        let last = &packets[MAX_PENDING];
+        assert_eq!(reassemble(&mut reassembler, &last[1..], now), packet(20));
+    }
+
+    #[test]
+    fn invalid_headers() {
+        let mut reassembler = Reassembler::new();
+        let now = Instant::now();
+        assert_eq!(
+            reassembler.push(&[0; HEADER_SIZE - 1], now),
+            Err(InvalidFragment),
+            "short"
+        );
+        assert_eq!(
+            reassembler.push(&[0, 0, 0, 0, 2, 2], now),
+            Err(InvalidFragment),
+            "index"
+        );
+        assert_eq!(
+            reassembler.push(&[0, 0, 0, 0, 0, 0], now),
+            Err(InvalidFragment),
+            "count"
+        );
+
+        // A fragment count that changes drops the packet
+        assert_eq!(reassembler.push(&[0, 0, 0, 7, 0, 2, 1], now), Ok(None));
+        assert_eq!(
+            reassembler.push(&[0, 0, 0, 7, 1, 3, 2], now),
+            Err(InvalidFragment)
+        );
+        assert_eq!(reassembler.pending(), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "packet too large")]
+    fn oversized_packet() {
+        let mut fragmenter = Fragmenter::new(MAX_DATAGRAM);
+        assert_eq!(fragmenter.max_packet(), 10 * MAX_FRAGMENTS);
+        let len = fragmenter.max_packet() + 1;
+        split(&mut fragmenter, &packet(len));
+    }
+
+    #[test]
+    #[should_panic(expected = "datagrams too small")]
+    fn datagrams_without_room_for_data() {
+        Fragmenter::new(HEADER_SIZE);
+    }
+}
@@ -0,0 +1,203 @@
+//! Keepalives between the tunnel ends. Each end sends one every interval
+//! over the tunnel socket, so that it notices when the other stops
+//! answering, and so that it can follow the other to a new address, e.g.
+//! after the NAT in between has rebound it.
+//!
+//! A keepalive is a control message: it has the header of a fragment with
+//! zero fragments, which no packet has, with [`KEEPALIVE`] in place of the
+//! index. The body is the sequence number of the keepalive (4 bytes), the
+//! time it was sent, in milliseconds since the Unix epoch (8 bytes), both in
+//! network byte order, and the token of the tunnel. With a key, the body is
+//! sealed like the packets, so that only the peer can send keepalives.
+//! Without one, the token travels in the clear.
+//!
+//! A keepalive counts only if it is newer than the last one accepted, by
+//! [`Keepalive::stamp`], so that a captured keepalive cannot be replayed to
+//! move the peer elsewhere.
+
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::fragment::HEADER_SIZE;
+
+/// The type of a keepalive control message
+pub const KEEPALIVE: u8 = 1;
+
+/// The header of a control message.
+pub fn header(kind: u8) -> [u8; HEADER_SIZE] {
+    let mut header = [0u8; HEADER_SIZE];
+    header[4] = kind;
+    header
+}
+
+/// The type of a control message, or None if the datagram is a fragment.
+pub fn parse_header(datagram: &[u8]) -> Option<u8> {
+    if datagram.len() < HEADER_SIZE || datagram[5] != 0 {
+        return None;
+    }
+    Some(datagram[4])
+}
+
+/// The body of a keepalive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Keepalive<'a> {
+    pub seq: u32,
+    /// Milliseconds since the Unix epoch
+    pub sent: u64,
+    pub token: &'a [u8],
+}
+
+impl<'a> Keepalive<'a> {
+    /// Keepalive number `seq`, sent now.
+    pub fn new(seq: u32, token: &'a [u8]) -> Self {
+        let sent = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        Self { seq, sent, token }
+    }
+
+    /// The order of the keepalives: by the time they were sent, and by the
+    /// sequence number within the same millisecond. A restarted peer starts
+    /// the numbers over, but later.
+    pub fn stamp(&self) -> (u64, u32) {
+        (self.sent, self.seq)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut body = Vec::with_capacity(12 + self.token.len());
+        body.extend_from_slice(&self.seq.to_be_bytes());
+        body.extend_from_slice(&self.sent.to_be_bytes());
+        body.extend_from_slice(self.token);
+        body
+    }
+
+    pub fn decode(body: &'a [u8]) -> Option<Self> {
+        let (seq, body) = body.split_first_chunk::<4>()?;
+        let (sent, token) = body.split_first_chunk::<8>()?;
+        Some(Self {
+            seq: u32::from_be_bytes(*seq),
+            sent: u64::from_be_bytes(*sent),
+            token,
+        })
+    }
+}
+
+/// Whether the peer is alive, and when the next keepalive is due.
+pub struct Liveness {
+    interval: Duration,
+    dead_after: u32,
+    seq: u32,
+    next_send: Instant,
+    last_heard: Instant,
+    dead: bool,
+}
+
+impl Liveness {
+    /// Sends a keepalive every `interval`, starting right away, and
+    /// considers the peer dead after `dead_after` intervals of silence.
+    pub fn new(interval: Duration, dead_after: u32, now: Instant) -> Self {
+        Self {
+            interval,
+            dead_after,
+            seq: 0,
+            next_send: now,
+            last_heard: now,
+            dead: false,
+        }
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// When the peer was last heard from.
+    pub fn last_heard(&self) -> Instant {
+        self.last_heard
+    }
+
+    /// How long the peer may be silent before it is dead.
+    pub fn dead_time(&self) -> Duration {
+        self.interval * self.dead_after
+    }
+
+    pub fn is_dead(&self) -> bool {
+        self.dead
+    }
+
+    /// Records that the peer was heard from at `now`, and returns whether
+    /// it had been dead.
+    pub fn heard(&mut self, now: Instant) -> bool {
+        self.last_heard = now;
+        std::mem::replace(&mut self.dead, false)
+    }
+
+    /// The sequence number of the next keepalive, if one is due at `now`.
+    pub fn due(&mut self, now: Instant) -> Option<u32> {
+        if now < self.next_send {
+            return None;
+        }
+        self.next_send = now + self.interval;
+        self.seq = self.seq.wrapping_add(1);
+        Some(self.seq)
+    }
+
+    /// Returns whether the peer has died by `now`, only the first time.
+    pub fn check(&mut self, now: Instant) -> bool {
+        if self.dead || now.duration_since(self.last_heard) < self.dead_time() {
+            return false;
+        }
+        self.dead = true;
+        true
+    }
+
+    /// When to send the next keepalive or check the peer.
+    pub fn next_timer(&self) -> Instant {
+        if self.dead {
+            self.next_send
+        } else {
+            self.next_send.min(self.last_heard + self.dead_time())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn body_round_trip() {
+        let keepalive = Keepalive {
+            seq: 0x01020304,
+            sent: 1_700_000_000_000,
+            token: b"secret",
+        };
+        let body = keepalive.encode();
+        assert_eq!(body[..4], [1, 2, 3, 4]);
+        assert_eq!(body[4..12], 1_700_000_000_000u64.to_be_bytes());
+        assert_eq!(&body[12..], b"secret");
+        assert_eq!(Keepalive::decode(&body), Some(keepalive));
+        assert_eq!(Keepalive::decode(&body[..11]), None, "short");
+    }
+
+    #[test]
+    fn control_header() {
+        let header = header(KEEPALIVE);
+        assert_eq!(header, [0, 0, 0, 0, KEEPALIVE, 0]);
+        assert_eq!(parse_header(&header), Some(KEEPALIVE));
+        assert_eq!(parse_header(&[0, 0, 0, 9, 0, 1, 0xaa]), None, "a fragment");
+        assert_eq!(parse_header(&header[..5]), None, "short");
+    }
+
+    #[test]
+    fn stamps_order_by_time_then_seq() {
+        let keepalive = |seq, sent| Keepalive {
+            seq,
+            sent,
+            token: b"",
+        };
+        assert!(keepalive(2, 1000).stamp() > keepalive(1, 1000).stamp());
+        // A restarted peer numbers from 1 again
+        assert!(keepalive(1, 2000).stamp() > keepalive(50, 1000).stamp());
+        assert!(keepalive(9, 999).stamp() < keepalive(1, 1000).stamp());
+    }
+}
@@ -0,0 +1,1139 @@
+//! The task-tun tunnel as a library, so that the `adnet` multi-tool can run it
+//! as a subcommand. The task-tun binary is a thin wrapper around [`run`].
+
+mod batch;
+pub mod control;
+pub mod fragment;
+pub mod keepalive;
+mod outbound;
+pub mod packet;
+#[doc(hidden)]
+pub mod pipeline;
+pub mod rules;
+mod tcp;
+
+use adnet_core::{
+    config::{self, ConfigArgs},
+    logging::{self, LogArgs},
+    metrics::{self, Counter, MetricsArgs},
+    report::{Report, ReportArgs},
+    shutdown,
+};
+use batch::RecvBatch;
+use clap::{Parser, ValueEnum};
+use control::ControlServer;
+use fragment::{Fragmenter, Reassembler};
+use keepalive::{Keepalive, Liveness};
+use mio::{
+    net::{TcpListener, UdpSocket},
+    unix::SourceFd,
+    Events, Interest, Poll, Token, Waker,
+};
+use netem::{Datagram, ImpairedSocket, Impairment, SystemClock};
+use outbound::Outbound;
+use packet::Cipher;
+use pipeline::{forward_in, forward_out, join_packet, split_packet};
+use pktcap::pcap::{self, LinkType};
+use rules::Rules;
+use serde::Deserialize;
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::os::unix::io::{AsRawFd, BorrowedFd, RawFd};
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tcp::TcpLink;
+use tracing::{debug, info, warn};
+
+const TUN_TOKEN: Token = Token(0);
+const SOCKET_TOKEN: Token = Token(1);
+// Woken up for the shutdown and for reloading the rules
+const WAKER_TOKEN: Token = Token(2);
+const CONTROL_TOKEN: Token = Token(3);
+const LISTENER_TOKEN: Token = Token(4);
+const NETMASK: Ipv4Addr = Ipv4Addr::new(255, 255, 255, 0);
+const MTU: usize = 1500;
+const DEFAULT_PATH_MTU: usize = 1500;
+// The minimum MTU of IPv4 that every host reassembles
+const MIN_PATH_MTU: usize = 576;
+const MAX_DATAGRAM: usize = 65535;
+const DEFAULT_KEEPALIVE: Duration = Duration::from_secs(10);
+const DEFAULT_DEAD_AFTER: u32 = 3;
+// The log filter of --verbose, which logs every packet
+const VERBOSE_FILTER: &str = "info,task_tun=debug";
+
+/// How the packets travel between the tunnel ends.
+#[derive(ValueEnum, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Transport {
+    /// A UDP datagram or more per packet
+    Udp,
+    /// Length-prefixed frames on a TCP connection
+    Tcp,
+}
+
+type TunnelSocket = ImpairedSocket<UdpSocket, SystemClock>;
+type CaptureFile = pcap::Writer<BufWriter<File>>;
+// Shared by the threads with --threaded
+type Capture = Option<Arc<Mutex<CaptureFile>>>;
+
+/// IP tunnel over UDP between two TUN devices.
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+pub struct Args {
+    #[arg(short, long)]
+    address: Option<Ipv4Addr>,
+
+    #[arg(short, long)]
+    destination: Option<Ipv4Addr>,
+
+    /// IPv6 address of the TUN device alongside the IPv4 one, as
+    /// ADDRESS/PREFIX, e.g. fd00:100::1/64 [default prefix: 64]
+    #[arg(long, value_parser = parse_address6)]
+    address6: Option<(Ipv6Addr, u8)>,
+
+    #[arg(short = 'b', long)]
+    udpbind: Option<SocketAddr>,
+
+    #[arg(short = 'u', long)]
+    udpdest: Option<SocketAddr>,
+
+    /// Carry the packets in UDP datagrams, or on a TCP connection for
+    /// networks that block UDP [default: udp]
+    #[arg(long)]
+    transport: Option<Transport>,
+
+    /// With --transport tcp, listen for the peer on --udpbind rather than
+    /// connect to it at --udpdest
+    #[arg(long)]
+    tcp_listen: bool,
+
+    /// Write the unencrypted packets read from and written to the TUN
+    /// device to this pcap file
+    #[arg(long)]
+    capture: Option<PathBuf>,
+
+    /// Encrypt and authenticate the tunneled packets with the key in this
+    /// file, 32 bytes or 64 hexadecimal digits. Both ends need the same key
+    #[arg(long)]
+    key_file: Option<PathBuf>,
+
+    /// Filter the tunneled packets with the rules in this TOML file instead
+    /// of the built-in ones. SIGHUP reloads the file
+    #[arg(long)]
+    rules: Option<PathBuf>,
+
+    /// MTU of the path between the tunnel ends. Packets that do not fit in
+    /// a UDP datagram of this size are fragmented [default: 1500]
+    #[arg(long)]
+    mtu: Option<usize>,
+
+    /// Seconds between keepalives to the peer, 0 for none [default: 10]
+    #[arg(long, value_parser = config::parse_secs)]
+    keepalive: Option<Duration>,
+
+    /// Keepalive intervals without a word from the peer before it is
+    /// considered dead [default: 3]
+    #[arg(long)]
+    dead_after: Option<u32>,
+
+    /// Exit when the peer is dead, rather than keep waiting for it
+    #[arg(long)]
+    exit_on_dead_peer: bool,
+
+    /// Token that the keepalives of the peer must carry for the tunnel to
+    /// follow the peer to a new address. With --key-file, the key
+    /// authenticates the keepalives and the token is optional
+    #[arg(long)]
+    token: Option<String>,
+
+    /// Listen for commands, such as stats, on a Unix domain socket at this
+    /// path. task-tun-ctl sends them
+    #[arg(long)]
+    control: Option<PathBuf>,
+
+    /// Forward the packets from the TUN device on a thread of their own, in
+    /// parallel with those from the peer. Only with the UDP transport and
+    /// without impairments
+    #[arg(long)]
+    threaded: bool,
+
+    /// Log every tunneled packet and the rules it matches
+    #[arg(short, long)]
+    verbose: bool,
+
+    /// Impairments applied to the tunnel packets sent to the UDP socket
+    #[command(flatten, next_help_heading = "Impairment")]
+    impairment: Impairment,
+
+    #[command(flatten)]
+    config: ConfigArgs,
+
+    #[command(flatten)]
+    log: LogArgs,
+
+    #[command(flatten)]
+    metrics: MetricsArgs,
+
+    #[command(flatten)]
+    report: ReportArgs,
+}
+
+/// The [task-tun] section of the config file
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct FileConfig {
+    address: Option<Ipv4Addr>,
+    destination: Option<Ipv4Addr>,
+    address6: Option<String>,
+    udpbind: Option<SocketAddr>,
+    udpdest: Option<SocketAddr>,
+    transport: Option<Transport>,
+    tcp_listen: bool,
+    capture: Option<PathBuf>,
+    key_file: Option<PathBuf>,
+    rules: Option<PathBuf>,
+    mtu: Option<usize>,
+    #[serde(deserialize_with = "config::secs")]
+    keepalive: Option<Duration>,
+    dead_after: Option<u32>,
+    exit_on_dead_peer: bool,
+    token: Option<String>,
+    control: Option<PathBuf>,
+    threaded: bool,
+    verbose: bool,
+}
+
+/// The tunnel's metrics in the global registry. "Out" is from the TUN device
+/// to the UDP socket and "in" the other way. Public only for the
+/// [`pipeline`] functions.
+#[doc(hidden)]
+#[derive(Clone)]
+pub struct Metrics {
+    packets_out: Counter,
+    bytes_out: Counter,
+    packets_in: Counter,
+    bytes_in: Counter,
+    dropped: Counter,
+    duplicated: Counter,
+    rewritten: Counter,
+    fragmented: Counter,
+    reassembly_timeouts: Counter,
+    keepalives_sent: Counter,
+    keepalives_received: Counter,
+    peer_changes: Counter,
+    connections: Counter,
+    unsent: Counter,
+    parse_errors: Counter,
+    auth_failures: Counter,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self {
+            packets_out: metrics::counter(
+                "tun_packets_out_total",
+                "Packets sent to the UDP socket",
+            ),
+            bytes_out: metrics::counter("tun_bytes_out_total", "Bytes sent to the UDP socket"),
+            packets_in: metrics::counter(
+                "tun_packets_in_total",
+                "Packets written to the TUN device",
+            ),
+            bytes_in: metrics::counter("tun_bytes_in_total", "Bytes written to the TUN device"),
+            dropped: metrics::counter("tun_dropped_total", "Packets dropped by the filter"),
+            duplicated: metrics::counter(
+                "tun_duplicated_total",
+                "Packets sent twice by the filter",
+            ),
+            rewritten: metrics::counter(
+                "tun_rewritten_total",
+                "Packets whose DSCP the filter rewrote",
+            ),
+            fragmented: metrics::counter(
+                "tun_fragmented_total",
+                "Packets sent in more than one datagram",
+            ),
+            reassembly_timeouts: metrics::counter(
+                "tun_reassembly_timeouts_total",
+                "Packets dropped because their fragments did not all arrive",
+            ),
+            keepalives_sent: metrics::counter(
+                "tun_keepalives_sent_total",
+                "Keepalives sent to the peer",
+            ),
+            keepalives_received: metrics::counter(
+                "tun_keepalives_received_total",
+                "Valid keepalives received from the peer",
+            ),
+            peer_changes: metrics::counter(
+                "tun_peer_changes_total",
+                "Times the peer moved to a new address",
+            ),
+            connections: metrics::counter(
+                "tun_connections_total",
+                "TCP connections established with the peer",
+            ),
+            unsent: metrics::counter(
+                "tun_unsent_total",
+                "Packets dropped because the socket or TCP connection could not take them",
+            ),
+            parse_errors: metrics::counter(
+                "tun_parse_errors_total",
+                "Tunneled packets that could not be parsed",
+            ),
+            auth_failures: metrics::counter(
+                "tun_auth_failures_total",
+                "Datagrams dropped because they failed authentication",
+            ),
+        }
+    }
+
+    /// Logs the totals, for the end of the program.
+    fn log_summary(&self) {
+        info!(
+            "Tunneled {} packets ({} bytes) out and {} packets ({} bytes) in, dropped {}, duplicated {}, fragmented {}, {} failed authentication",
+            self.packets_out.get(),
+            self.bytes_out.get(),
+            self.packets_in.get(),
+            self.bytes_in.get(),
+            self.dropped.get(),
+            self.duplicated.get(),
+            self.fragmented.get(),
+            self.auth_failures.get()
+        );
+    }
+
+    /// The totals for the stats command of the control socket.
+    fn stats(&self) -> String {
+        let counters = [
+            ("packets_out", &self.packets_out),
+            ("bytes_out", &self.bytes_out),
+            ("packets_in", &self.packets_in),
+            ("bytes_in", &self.bytes_in),
+            ("dropped", &self.dropped),
+            ("duplicated", &self.duplicated),
+            ("rewritten", &self.rewritten),
+            ("fragmented", &self.fragmented),
+            ("reassembly_timeouts", &self.reassembly_timeouts),
+            ("keepalives_sent", &self.keepalives_sent),
+            ("keepalives_received", &self.keepalives_received),
+            ("peer_changes", &self.peer_changes),
+            ("connections", &self.connections),
+            ("unsent", &self.unsent),
+            ("parse_errors", &self.parse_errors),
+            ("auth_failures", &self.auth_failures),
+        ];
+        let mut stats = String::new();
+        for (name, counter) in counters {
+            let _ = writeln!(stats, "{} {}", name, counter.get());
+        }
+        stats
+    }
+
+    /// Fills in the totals of the report.
+    fn report(&self, report: &mut Report) {
+        report.bytes = self.bytes_out.get() + self.bytes_in.get();
+        report.errors =
+            self.parse_errors.get() + self.auth_failures.get() + self.reassembly_timeouts.get();
+        report.detail("packets_out", self.packets_out.get());
+        report.detail("packets_in", self.packets_in.get());
+        report.detail("dropped", self.dropped.get());
+        report.detail("duplicated", self.duplicated.get());
+        report.detail("rewritten", self.rewritten.get());
+        report.detail("fragmented", self.fragmented.get());
+        report.detail("reassembly_timeouts", self.reassembly_timeouts.get());
+        report.detail("keepalives_sent", self.keepalives_sent.get());
+        report.detail("keepalives_received", self.keepalives_received.get());
+        report.detail("peer_changes", self.peer_changes.get());
+        report.detail("connections", self.connections.get());
+        report.detail("unsent", self.unsent.get());
+        report.detail("auth_failures", self.auth_failures.get());
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn parse_address6(s: &str) -> Result<(Ipv6Addr, u8), String> {
+    let (address, prefix) = s.split_once('/').unwrap_or((s, "64"));
+    let address = address.parse().map_err(|e| format!("{}", e))?;
+    match prefix.parse() {
+        Ok(prefix) if prefix <= 128 => Ok((address, prefix)),
+        _ => Err(format!("Invalid prefix length {}", prefix)),
+    }
+}
+
+/// Takes the option from the command line or else from the config file.
+fn required<T>(cli: Option<T>, file: Option<T>, name: &str) -> std::io::Result<T> {
+    cli.or(file).ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("--{} is required", name),
+        )
+    })
+}
+
+/// The side of the tunnel towards the peer, over the transport of
+/// --transport. Both sides are boxed, as they differ much in size.
+enum Link {
+    Udp(Box<UdpLink>),
+    Tcp(Box<TcpLink>),
+}
+
+impl Link {
+    fn send(&mut self, ip_packet: &[u8], metrics: &Metrics) -> std::io::Result<()> {
+        match self {
+            Link::Udp(link) => link.send(ip_packet, metrics),
+            Link::Tcp(link) => {
+                link.send(ip_packet, metrics);
+                Ok(())
+            }
+        }
+    }
+
+    /// Returns the next packet from the peer, if any. Fails with
+    /// `WouldBlock` when there is nothing more to read.
+    fn receive(&mut self, metrics: &Metrics) -> std::io::Result<Option<&mut [u8]>> {
+        match self {
+            Link::Udp(link) => link.receive(metrics),
+            Link::Tcp(link) => link.receive(metrics),
+        }
+    }
+
+    /// Handles the readiness of the TCP connection.
+    fn ready(&mut self, metrics: &Metrics) {
+        if let Link::Tcp(link) = self {
+            link.ready(metrics);
+        }
+    }
+
+    /// Accepts the peer on the TCP listener.
+    fn accept(&mut self, metrics: &Metrics) -> std::io::Result<()> {
+        match self {
+            Link::Udp(_) => Ok(()),
+            Link::Tcp(link) => link.accept(metrics),
+        }
+    }
+
+    /// How long the poll can wait for the next timer of the link.
+    fn timeout(&self) -> Option<Duration> {
+        let (delayed, timer) = match self {
+            Link::Udp(link) => (link.socket.poll_timeout(), link.next_timer()),
+            Link::Tcp(link) => (None, link.next_timer()),
+        };
+        let timer = timer.map(|at| at.saturating_duration_since(Instant::now()));
+        match (delayed, timer) {
+            (Some(delayed), Some(timer)) => Some(delayed.min(timer)),
+            (delayed, timer) => delayed.or(timer),
+        }
+    }
+
+    fn tick(&mut self, now: Instant, metrics: &Metrics) -> std::io::Result<()> {
+        match self {
+            Link::Udp(link) => link.tick(now, metrics),
+            Link::Tcp(link) => {
+                link.tick(now);
+                Ok(())
+            }
+        }
+    }
+
+    fn state(&self, now: Instant) -> String {
+        match self {
+            Link::Udp(link) => link.state(now),
+            Link::Tcp(link) => link.state(),
+        }
+    }
+}
+
+/// The UDP side of the tunnel. Packets are sealed with the cipher, if there
+/// is one, and split into datagrams that fit the path MTU, and the
+/// datagrams from the peer reassembled and opened. The keepalives go both
+/// ways between the packets.
+struct UdpLink {
+    socket: TunnelSocket,
+    peer: SocketAddr,
+    cipher: Option<Cipher>,
+    fragmenter: Fragmenter,
+    reassembler: Reassembler,
+    batch: RecvBatch,
+    // Room for any UDP datagram, as the peer's MTU can be larger
+    recv_buf: Vec<u8>,
+    // The peer for the outbound thread, with --threaded
+    shared_peer: Option<Arc<Mutex<SocketAddr>>>,
+    token: Vec<u8>,
+    // None without keepalives
+    liveness: Option<Liveness>,
+    // The stamp of the last valid keepalive from the peer
+    last_keepalive: Option<(u64, u32)>,
+    exit_on_dead_peer: bool,
+}
+
+impl UdpLink {
+    fn send(&mut self, ip_packet: &[u8], metrics: &Metrics) -> std::io::Result<()> {
+        let (socket, peer) = (&mut self.socket, self.peer);
+        split_packet(
+            ip_packet,
+            self.cipher.as_ref(),
+            &mut self.fragmenter,
+            metrics,
+            |datagram| socket.send_to(datagram, peer).map(drop),
+        )
+    }
+
+    /// Receives a datagram, and returns the packet if the datagram completes
+    /// one that passes authentication. Fails with `WouldBlock` when there
+    /// are no more datagrams.
+    fn receive(&mut self, metrics: &Metrics) -> std::io::Result<Option<&mut [u8]>> {
+        let (n, src) = self.batch.recv_from(&mut self.socket, &mut self.recv_buf)?;
+        if n == 0 {
+            return Ok(None);
+        }
+
+        let now = Instant::now();
+        if let Some(kind) = keepalive::parse_header(&self.recv_buf[..n]) {
+            self.control(kind, n, src, now, metrics);
+            return Ok(None);
+        }
+
+        let Some(ip_packet) = join_packet(
+            &self.recv_buf[..n],
+            src,
+            self.cipher.as_ref(),
+            &mut self.reassembler,
+            now,
+            metrics,
+        ) else {
+            return Ok(None);
+        };
+        if src == self.peer {
+            if let Some(liveness) = &mut self.liveness {
+                if liveness.heard(now) {
+                    info!("Peer {} is back", src);
+                }
+            }
+        }
+        Ok(Some(ip_packet))
+    }
+
+    /// Handles a control message of `n` bytes in the receive buffer. A
+    /// valid keepalive from a new address moves the peer there, if the
+    /// keepalives are authenticated with the key or a token. Keepalives no
+    /// newer than the last valid one are dropped, wherever they come from.
+    fn control(&mut self, kind: u8, n: usize, src: SocketAddr, now: Instant, metrics: &Metrics) {
+        if kind != keepalive::KEEPALIVE {
+            debug!("Ignoring control message {} from {}", kind, src);
+            return;
+        }
+        let body = &mut self.recv_buf[fragment::HEADER_SIZE..n];
+        let body: &[u8] = match &self.cipher {
+            Some(cipher) => match cipher.open(body) {
+                Ok(body) => body,
+                Err(_) => {
+                    warn!("Dropping keepalive from {} that failed authentication", src);
+                    metrics.auth_failures.inc();
+                    return;
+                }
+            },
+            None => body,
+        };
+        let Some(keepalive) = Keepalive::decode(body) else {
+            warn!("Dropping invalid keepalive from {}", src);
+            metrics.parse_errors.inc();
+            return;
+        };
+        if keepalive.token != self.token.as_slice() {
+            warn!("Dropping keepalive from {} with the wrong token", src);
+            metrics.auth_failures.inc();
+            return;
+        }
+        if self
+            .last_keepalive
+            .is_some_and(|last| keepalive.stamp() <= last)
+        {
+            debug!(
+                "Dropping stale keepalive {} from {}, sent at {} ms",
+                keepalive.seq, src, keepalive.sent
+            );
+            return;
+        }
+        self.last_keepalive = Some(keepalive.stamp());
+        debug!(
+            "Keepalive {} from {}, sent at {} ms",
+            keepalive.seq, src, keepalive.sent
+        );
+        metrics.keepalives_received.inc();
+
+        if src != self.peer {
+            if self.cipher.is_none() && self.token.is_empty() {
+                debug!("Not following the peer to {} without a token", src);
+                return;
+            }
+            info!("Peer moved from {} to {}", self.peer, src);
+            self.peer = src;
+            if let Some(shared_peer) = &self.shared_peer {
+                *shared_peer.lock().unwrap() = src;
+            }
+            metrics.peer_changes.inc();
+        }
+        if let Some(liveness) = &mut self.liveness {
+            if liveness.heard(now) {
+                info!("Peer {} is back", src);
+            }
+        }
+    }
+
+    fn send_keepalive(&mut self, seq: u32, metrics: &Metrics) -> std::io::Result<()> {
+        let datagram = self.keepalive_datagram(&Keepalive::new(seq, &self.token));
+        self.socket.send_to(&datagram, self.peer)?;
+        metrics.keepalives_sent.inc();
+        Ok(())
+    }
+
+    /// The control message of a keepalive, sealed if there is a cipher.
+    fn keepalive_datagram(&self, keepalive: &Keepalive) -> Vec<u8> {
+        let body = keepalive.encode();
+        let mut datagram = keepalive::header(keepalive::KEEPALIVE).to_vec();
+        match &self.cipher {
+            Some(cipher) => {
+                let start = datagram.len();
+                datagram.resize(start + body.len() + packet::OVERHEAD, 0);
+                cipher.seal(&body, &mut datagram[start..]);
+            }
+            None => datagram.extend_from_slice(&body),
+        }
+        datagram
+    }
+
+    /// The state for the state command of the control socket.
+    fn state(&self, now: Instant) -> String {
+        let mut state = String::new();
+        let _ = writeln!(state, "transport udp");
+        let _ = writeln!(state, "peer {}", self.peer);
+        let encryption = if self.cipher.is_some() { "on" } else { "off" };
+        let _ = writeln!(state, "encryption {}", encryption);
+        let _ = writeln!(state, "max_datagram {}", self.fragmenter.max_datagram());
+        let _ = writeln!(state, "reassembling {}", self.reassembler.pending());
+        match &self.liveness {
+            Some(liveness) => {
+                let silence = now.saturating_duration_since(liveness.last_heard());
+                let _ = writeln!(state, "keepalive {:?}", liveness.interval());
+                let _ = writeln!(state, "last_heard {:.1?} ago", silence);
+                let alive = if liveness.is_dead() { "dead" } else { "alive" };
+                let _ = writeln!(state, "peer_state {}", alive);
+            }
+            None => state.push_str("keepalive off\n"),
+        }
+        state
+    }
+
+    /// When the next keepalive is due or the peer may die.
+    fn next_timer(&self) -> Option<Instant> {
+        self.liveness.as_ref().map(Liveness::next_timer)
+    }
+
+    /// Sends the delayed datagrams that are due, and a keepalive if one is
+    /// due, and checks that the peer is alive. Fails with `TimedOut` when the
+    /// peer has died and the tunnel is to exit.
+    fn tick(&mut self, now: Instant, metrics: &Metrics) -> std::io::Result<()> {
+        self.socket.flush()?;
+        let Some(liveness) = &mut self.liveness else {
+            return Ok(());
+        };
+        if liveness.check(now) {
+            let silence = liveness.dead_time();
+            warn!("Nothing from peer {} in {:?}", self.peer, silence);
+            if self.exit_on_dead_peer {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    format!("Peer {} has been silent for {:?}", self.peer, silence),
+                ));
+            }
+        }
+        if let Some(seq) = liveness.due(now) {
+            self.send_keepalive(seq, metrics)?;
+        }
+        Ok(())
+    }
+}
+
+/// Creates a TUN device with the given addresses and brings it up. Requires
+/// root privileges. task-nat creates its devices with this, too.
+pub fn create_device(
+    name: &str,
+    address: Ipv4Addr,
+    destination: Option<Ipv4Addr>,
+    netmask: Ipv4Addr,
+) -> std::io::Result<tun::Device> {
+    let mut config = tun::Configuration::default();
+    config
+        .tun_name(name) // Interface name
+        .address(address) // Local TUN address (10.100.0.x)
+        .netmask(netmask) // Subnet mask
+        .up(); // Bring interface up
+    if let Some(destination) = destination {
+        config.destination(destination); // Peer TUN address (10.100.0.x)
+    }
+
+    #[cfg(target_os = "linux")]
+    config.platform_config(|config| {
+        // requiring root privilege to acquire complete functions
+        config.ensure_root_privileges(true);
+    });
+
+    tun::create(&config)
+        .map_err(|e| std::io::Error::other(format!("Failed to create TUN device {}: {}", name, e)))
+}
+
+/// Adds an IPv6 address to a TUN device, which the tun crate cannot, with
+/// `ip -6 addr add`. Requires root privileges like creating the device.
+pub fn add_ipv6_address(name: &str, address: Ipv6Addr, prefix: u8) -> std::io::Result<()> {
+    let status = Command::new("ip")
+        .args(["-6", "addr", "add", &format!("{}/{}", address, prefix)])
+        .args(["dev", name])
+        .status()?;
+    if !status.success() {
+        return Err(std::io::Error::other(format!(
+            "Failed to add {}/{} to {}: ip {}",
+            address, prefix, name, status
+        )));
+    }
+    Ok(())
+}
+
+/// Runs the tunnel with the given arguments, as the task-tun binary does,
+/// until Ctrl-C.
+pub fn run(args: Args) -> std::io::Result<()> {
+    logging::init(&args.log)?;
+    metrics::init(&args.metrics)?;
+    let report = args.report.clone();
+    report.run("task-tun", |report| {
+        let metrics = Metrics::new();
+        let result = tunnel(args, &metrics);
+        metrics.report(report);
+        result
+    })
+}
+
+fn tunnel(args: Args, metrics: &Metrics) -> std::io::Result<()> {
+    let file: FileConfig = args.config.section("task-tun")?;
+    let address = required(args.address, file.address, "address")?;
+    let destination = required(args.destination, file.destination, "destination")?;
+    let transport = args.transport.or(file.transport).unwrap_or(Transport::Udp);
+    let threaded = args.threaded || file.threaded;
+    if threaded && (transport != Transport::Udp || !args.impairment.is_none()) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "--threaded needs --transport udp and no impairments",
+        ));
+    }
+    if args.verbose || file.verbose {
+        logging::set_filter(VERBOSE_FILTER)?;
+    }
+
+    let mtu = args.mtu.or(file.mtu).unwrap_or(DEFAULT_PATH_MTU);
+    if mtu < MIN_PATH_MTU {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("--mtu must be at least {}", MIN_PATH_MTU),
+        ));
+    }
+    let keepalive = args
+        .keepalive
+        .or(file.keepalive)
+        .unwrap_or(DEFAULT_KEEPALIVE);
+    let dead_after = args
+        .dead_after
+        .or(file.dead_after)
+        .unwrap_or(DEFAULT_DEAD_AFTER);
+
+    let address6 = match (args.address6, file.address6) {
+        (Some(address6), _) => Some(address6),
+        (None, Some(address6)) => Some(
+            parse_address6(&address6)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?,
+        ),
+        (None, None) => None,
+    };
+
+    let mut dev = create_device("tun0", address, Some(destination), NETMASK)?;
+    if let Some((address6, prefix)) = address6 {
+        add_ipv6_address("tun0", address6, prefix)?;
+        info!("Added {}/{} to tun0", address6, prefix);
+    }
+
+    let capture = match args.capture.or(file.capture) {
+        Some(path) => {
+            info!("Capturing tunneled packets to {}", path.display());
+            let writer = pcap::create(path, LinkType::Raw, pcap::DEFAULT_SNAPLEN)?;
+            Some(Arc::new(Mutex::new(writer)))
+        }
+        None => None,
+    };
+
+    let cipher = match args.key_file.or(file.key_file) {
+        Some(path) => {
+            info!(
+                "Encrypting tunneled packets with the key in {}",
+                path.display()
+            );
+            Some(Cipher::from_key_file(&path)?)
+        }
+        None => None,
+    };
+
+    let rules_path = args.rules.or(file.rules);
+    let rules = match &rules_path {
+        Some(path) => {
+            let rules = Rules::load(path)?;
+            info!("Loaded {} rules from {}", rules.len(), path.display());
+            rules
+        }
+        None => Rules::default(),
+    };
+    let rules = Arc::new(RwLock::new(rules));
+
+    let mut poll = Poll::new()?;
+    let mut events = Events::with_capacity(128);
+
+    let mut outbound = None;
+    let mut link = match transport {
+        Transport::Udp => {
+            let udpbind = required(args.udpbind, file.udpbind, "udpbind")?;
+            let udpdest = required(args.udpdest, file.udpdest, "udpdest")?;
+            // The IP and UDP headers of the datagrams
+            let headers = if udpdest.is_ipv4() { 28 } else { 48 };
+            let socket = std::net::UdpSocket::bind(udpbind)?;
+            socket.set_nonblocking(true)?;
+
+            let shared_peer = threaded.then(|| Arc::new(Mutex::new(udpdest)));
+            if let Some(peer) = &shared_peer {
+                // The thread reads a blocking copy of the TUN device
+                let tun =
+                    unsafe { BorrowedFd::borrow_raw(dev.as_raw_fd()) }.try_clone_to_owned()?;
+                outbound = Some(Outbound {
+                    tun: File::from(tun),
+                    socket: socket.try_clone()?,
+                    peer: peer.clone(),
+                    cipher: cipher.clone(),
+                    fragmenter: Fragmenter::new(mtu - headers),
+                    rules: rules.clone(),
+                    capture: capture.clone(),
+                    metrics: metrics.clone(),
+                });
+            }
+            let socket = UdpSocket::from_std(socket);
+
+            if !args.impairment.is_none() {
+                info!("Impairing tunnel traffic: {:?}", args.impairment);
+            }
+            let socket = ImpairedSocket::new(socket, args.impairment, SystemClock::new());
+
+            let mut link = UdpLink {
+                socket,
+                peer: udpdest,
+                cipher,
+                fragmenter: Fragmenter::new(mtu - headers),
+                reassembler: Reassembler::new(),
+                batch: RecvBatch::new(),
+                recv_buf: vec![0; MAX_DATAGRAM],
+                shared_peer,
+                token: args.token.or(file.token).unwrap_or_default().into_bytes(),
+                liveness: (!keepalive.is_zero())
+                    .then(|| Liveness::new(keepalive, dead_after, Instant::now())),
+                last_keepalive: None,
+                exit_on_dead_peer: args.exit_on_dead_peer || file.exit_on_dead_peer,
+            };
+            poll.registry()
+                .register(link.socket.get_mut(), SOCKET_TOKEN, Interest::READABLE)?;
+            Link::Udp(Box::new(link))
+        }
+        Transport::Tcp => {
+            if !args.impairment.is_none() {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "The impairment options need --transport udp",
+                ));
+            }
+            let role = if args.tcp_listen || file.tcp_listen {
+                let udpbind = required(args.udpbind, file.udpbind, "udpbind")?;
+                info!("Listening for the peer on TCP {}", udpbind);
+                tcp::Role::Listen(TcpListener::bind(udpbind)?)
+            } else {
+                tcp::Role::Connect(required(args.udpdest, file.udpdest, "udpdest")?)
+            };
+            let link = TcpLink::new(poll.registry(), role, SOCKET_TOKEN, LISTENER_TOKEN, cipher)?;
+            Link::Tcp(Box::new(link))
+        }
+    };
+
+    let control = match args.control.or(file.control) {
+        Some(path) => {
+            let control = ControlServer::bind(&path)?;
+            info!("Listening for commands on {}", control.path().display());
+            Some(control)
+        }
+        None => None,
+    };
+
+    let shutdown = shutdown::install()?;
+    let summary = metrics.clone();
+    shutdown.on_exit(move || summary.log_summary());
+
+    let raw_fd = dev.as_raw_fd();
+    let outbound = match outbound {
+        Some(outbound) => {
+            info!("Forwarding the packets from tun0 on a thread of their own");
+            let shutdown = shutdown.clone();
+            Some(outbound.spawn(move || shutdown.request())?)
+        }
+        None => {
+            // Read until empty on each event, in batches
+            set_nonblocking(raw_fd)?;
+            poll.registry()
+                .register(&mut SourceFd(&raw_fd), TUN_TOKEN, Interest::READABLE)?;
+            None
+        }
+    };
+    if let Some(control) = &control {
+        poll.registry().register(
+            &mut SourceFd(&control.as_raw_fd()),
+            CONTROL_TOKEN,
+            Interest::READABLE,
+        )?;
+    }
+    let waker = Arc::new(Waker::new(poll.registry(), WAKER_TOKEN)?);
+    let reload = Arc::new(AtomicBool::new(false));
+    if rules_path.is_some() {
+        let (waker, reload) = (waker.clone(), reload.clone());
+        shutdown.on_hangup(move || {
+            reload.store(true, Ordering::SeqCst);
+            let _ = waker.wake();
+        })?;
+    }
+    shutdown.on_request(move || {
+        let _ = waker.wake();
+    });
+
+    while !shutdown.is_requested() {
+        // Wake up when the next delayed packet, keepalive or connection
+        // attempt is due
+        match poll.poll(&mut events, link.timeout()) {
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            result => result?,
+        }
+        if let Err(e) = link.tick(Instant::now(), metrics) {
+            shutdown.finish();
+            return Err(e);
+        }
+
+        if let (true, Some(path)) = (reload.swap(false, Ordering::SeqCst), &rules_path) {
+            match Rules::load(path) {
+                Ok(reloaded) => {
+                    info!("Reloaded {} rules from {}", reloaded.len(), path.display());
+                    *rules.write().unwrap() = reloaded;
+                }
+                Err(e) => warn!("{}, keeping the previous rules", e),
+            }
+        }
+
+        let rules = rules.read().unwrap();
+        for event in events.iter() {
+            match event.token() {
+                TUN_TOKEN if event.is_readable() => {
+                    handle_tun_event(&mut dev, &mut link, &rules, &capture, metrics)?;
+                }
+                SOCKET_TOKEN => {
+                    link.ready(metrics);
+                    if event.is_readable() || event.is_read_closed() || event.is_error() {
+                        handle_socket_event(&mut dev, &mut link, &rules, &capture, metrics)?;
+                    }
+                }
+                LISTENER_TOKEN => {
+                    if let Err(e) = link.accept(metrics) {
+                        warn!("Cannot accept the peer: {}", e);
+                    }
+                }
+                CONTROL_TOKEN => {
+                    if let Some(control) = &control {
+                        control.serve(|command| answer(command, &link, &rules, metrics));
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    shutdown.finish();
+    match outbound {
+        Some(outbound) if outbound.is_finished() => outbound
+            .join()
+            .unwrap_or_else(|_| Err(std::io::Error::other("The outbound thread panicked"))),
+        _ => Ok(()),
+    }
+}
+
+/// Answers a command of the control socket. Errors start with "error: ".
+fn answer(command: &str, link: &Link, rules: &Rules, metrics: &Metrics) -> String {
+    let (name, arg) = match command.split_once(' ') {
+        Some((name, arg)) => (name, arg.trim()),
+        None => (command, ""),
+    };
+    let result = match (name, arg) {
+        ("stats", "") => Ok(metrics.stats()),
+        ("rules", "") if rules.is_empty() => Ok("no rules, everything passes\n".to_string()),
+        ("rules", "") => Ok(rules.iter().map(|rule| format!("{}\n", rule)).collect()),
+        ("state", "") => Ok(link.state(Instant::now())),
+        ("verbose", "on") => set_log_filter(VERBOSE_FILTER),
+        ("verbose", "off") => set_log_filter(logging::DEFAULT_FILTER),
+        ("log", filter) if !filter.is_empty() => set_log_filter(filter),
+        ("help", "") => Ok(control::HELP.to_string()),
+        _ => Err(format!("unknown command '{}', try help", command)),
+    };
+    result.unwrap_or_else(|e| format!("error: {}\n", e))
+}
+
+fn set_log_filter(filter: &str) -> Result<String, String> {
+    logging::set_filter(filter).map_err(|e| e.to_string())?;
+    info!("Log filter set to '{}' from the control socket", filter);
+    Ok(format!("log filter {}\n", filter))
+}
+
+/// If we receive a packet from the TUN device, we need to parse it and send it to the UDP socket.
+/// With a cipher, the packet is sealed after the filter has seen it. The
+/// device is read until it is empty.
+fn handle_tun_event(
+    dev: &mut tun::Device,
+    link: &mut Link,
+    rules: &Rules,
+    capture: &Capture,
+    metrics: &Metrics,
+) -> std::io::Result<()> {
+    let mut buf = [0u8; MTU];
+    loop {
+        let n = match dev.read(&mut buf) {
+            Ok(0) => return Ok(()),
+            Ok(n) => n,
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        forward_out(&mut buf[..n], rules, capture, metrics, |ip_packet| {
+            link.send(ip_packet, metrics)
+        })?;
+    }
+}
+
+fn set_nonblocking(fd: RawFd) -> std::io::Result<()> {
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    if flags < 0 || unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) } < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// If we receive a packet from the UDP socket, we need to parse it and send it to the TUN device.
+/// With a cipher, packets that fail authentication are dropped. The socket is
+/// read until it is empty, as the fragments of a packet often arrive together.
+fn handle_socket_event(
+    dev: &mut tun::Device,
+    link: &mut Link,
+    rules: &Rules,
+    capture: &Capture,
+    metrics: &Metrics,
+) -> std::io::Result<()> {
+    loop {
+        let ip_packet = match link.receive(metrics) {
+            Ok(Some(ip_packet)) => ip_packet,
+            Ok(None) => continue,
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => return Ok(()),
+            Err(e) => return Err(e),
+        };
+
+        forward_in(ip_packet, rules, capture, metrics, |ip_packet| {
+            dev.write_all(ip_packet)
+        })?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PEER: &str = "192.0.2.1:5000";
+    const ATTACKER: &str = "198.51.100.7:5000";
+
+    fn udp_link(cipher: Option<Cipher>, token: &str) -> UdpLink {
+        let socket = UdpSocket::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        UdpLink {
+            socket: ImpairedSocket::new(socket, Impairment::default(), SystemClock::new()),
+            peer: PEER.parse().unwrap(),
+            cipher,
+            fragmenter: Fragmenter::new(1472),
+            reassembler: Reassembler::new(),
+            batch: RecvBatch::new(),
+            recv_buf: vec![0; MAX_DATAGRAM],
+            shared_peer: None,
+            token: token.as_bytes().to_vec(),
+            liveness: None,
+            last_keepalive: None,
+            exit_on_dead_peer: false,
+        }
+    }
+
+    /// Hands the datagram to the link as if it came from `src`.
+    fn deliver(link: &mut UdpLink, datagram: &[u8], src: &str, metrics: &Metrics) {
+        let kind = keepalive::parse_header(datagram).expect("a control message");
+        link.recv_buf[..datagram.len()].copy_from_slice(datagram);
+        let src = src.parse().unwrap();
+        link.control(kind, datagram.len(), src, Instant::now(), metrics);
+    }
+
+    #[test]
+    fn replayed_keepalive_does_not_move_the_peer() {
+        let metrics = Metrics::new();
+        for (cipher, token) in [
+            (Some(Cipher::new(&[7; packet::KEY_SIZE])), ""),
+            (None, "secret"),
+        ] {
+            let mut link = udp_link(cipher, token);
+            let first = link.keepalive_datagram(&Keepalive::new(1, token.as_bytes()));
+            deliver(&mut link, &first, PEER, &metrics);
+            assert_eq!(link.peer, PEER.parse().unwrap());
+
+            deliver(&mut link, &first, ATTACKER, &metrics);
+            assert_eq!(link.peer, PEER.parse().unwrap(), "replayed from elsewhere");
+
+            // An older keepalive of the peer, delivered late
+            let mut older = Keepalive::new(2, token.as_bytes());
+            older.sent -= 1000;
+            let older = link.keepalive_datagram(&older);
+            deliver(&mut link, &older, ATTACKER, &metrics);
+            assert_eq!(link.peer, PEER.parse().unwrap(), "older");
+
+            let mut newer = Keepalive::new(2, token.as_bytes());
+            newer.sent += 1000;
+            let newer = link.keepalive_datagram(&newer);
+            deliver(&mut link, &newer, ATTACKER, &metrics);
+            assert_eq!(link.peer, ATTACKER.parse().unwrap(), "newer");
+        }
+    }
+
+    #[test]
+    fn keepalive_with_another_key_is_dropped() {
+        let metrics = Metrics::new();
+        let mut link = udp_link(Some(Cipher::new(&[7; packet::KEY_SIZE])), "");
+        let other = udp_link(Some(Cipher::new(&[8; packet::KEY_SIZE])), "");
+        let forged = other.keepalive_datagram(&Keepalive::new(1, b""));
+        deliver(&mut link, &forged, ATTACKER, &metrics);
+        assert_eq!(link.peer, PEER.parse().unwrap());
+        assert_eq!(link.last_keepalive, None);
+    }
+}
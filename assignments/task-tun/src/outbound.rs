@@ -0,0 +1,92 @@
+//! The packets from the TUN device on a thread of their own, with
+//! `--threaded`. The thread reads the device, filters the packets and sends
+//! them to the peer, while the main thread handles the packets from the
+//! peer, the keepalives and the control socket, so that the two directions
+//! and their encryption run in parallel. The rules, the capture file and the
+//! address of the peer, which keepalives can change, are shared between the
+//! threads.
+
+use std::{
+    fs::File,
+    io::{self, Read},
+    net::{SocketAddr, UdpSocket},
+    sync::{Arc, Mutex, RwLock},
+    thread::{self, JoinHandle},
+};
+
+use tracing::debug;
+
+use crate::{fragment::Fragmenter, packet::Cipher, pipeline, rules::Rules, Capture, Metrics, MTU};
+
+pub struct Outbound {
+    /// The TUN device, blocking
+    pub tun: File,
+    /// A clone of the socket of the tunnel, which does not block
+    pub socket: UdpSocket,
+    pub peer: Arc<Mutex<SocketAddr>>,
+    pub cipher: Option<Cipher>,
+    pub fragmenter: Fragmenter,
+    pub rules: Arc<RwLock<Rules>>,
+    pub capture: Capture,
+    pub metrics: Metrics,
+}
+
+impl Outbound {
+    /// Runs on a new thread until reading the device or sending to the
+    /// socket fails, and then calls `done`, to stop the main thread too.
+    pub fn spawn(
+        self,
+        done: impl FnOnce() + Send + 'static,
+    ) -> io::Result<JoinHandle<io::Result<()>>> {
+        thread::Builder::new()
+            .name("outbound".to_string())
+            .spawn(move || {
+                let result = self.run();
+                done();
+                result
+            })
+    }
+
+    fn run(self) -> io::Result<()> {
+        let Self {
+            mut tun,
+            socket,
+            peer,
+            cipher,
+            mut fragmenter,
+            rules,
+            capture,
+            metrics,
+        } = self;
+        let mut buf = [0u8; MTU];
+        loop {
+            let n = match tun.read(&mut buf) {
+                Ok(0) => continue,
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            let to = *peer.lock().unwrap();
+            let rules = rules.read().unwrap();
+            let result =
+                pipeline::forward_out(&mut buf[..n], &rules, &capture, &metrics, |ip_packet| {
+                    pipeline::split_packet(
+                        ip_packet,
+                        cipher.as_ref(),
+                        &mut fragmenter,
+                        &metrics,
+                        |datagram| socket.send_to(datagram, to).map(drop),
+                    )
+                });
+            match result {
+                Ok(()) => {}
+                // The socket buffer is full, as a queue would be
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    debug!("Dropping packet of {} bytes, the socket is full", n);
+                    metrics.unsent.inc();
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
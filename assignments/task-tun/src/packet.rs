@@ -1,37 +1,58 @@
+use std::{fs, io, path::Path};
+
+use chacha20poly1305::{
+    aead::{AeadCore, AeadInPlace, KeyInit, OsRng},
+    ChaCha20Poly1305, Error, Key, Nonce, Tag,
+};
 use etherparse::{InternetSlice, IpPayloadSlice, SlicedPacket, TransportSlice};
+use tracing::debug;
+
+pub const KEY_SIZE: usize = 32;
+pub const NONCE_SIZE: usize = 12;
+pub const TAG_SIZE: usize = 16;
+/// The bytes that [`Cipher::seal`] adds to a packet.
+pub const OVERHEAD: usize = NONCE_SIZE + TAG_SIZE;
 
 pub fn print_packet_info(sliced: &SlicedPacket, n: usize) {
-    if let Some(InternetSlice::Ipv4(ipv4)) = &sliced.net {
-        // Use the IPv4 header slice to access destination and protocol
-        let header = ipv4.header();
-        let dst = header.destination_addr();
-        let proto = header.protocol();
-
-        match &sliced.transport {
-            Some(TransportSlice::Udp(udp)) => {
-                println!(
-                    "dst_ip={:?} proto={:?} dst_port={:?} len={:?}",
-                    dst,
-                    proto,
-                    udp.destination_port(),
-                    n
-                );
-            }
-            Some(TransportSlice::Tcp(tcp)) => {
-                println!(
+    let port = match &sliced.transport {
+        Some(TransportSlice::Udp(udp)) => Some(udp.destination_port()),
+        Some(TransportSlice::Tcp(tcp)) => Some(tcp.destination_port()),
+        _ => None,
+    };
+
+    match &sliced.net {
+        Some(InternetSlice::Ipv4(ipv4)) => {
+            // Use the IPv4 header slice to access destination and protocol
+            let header = ipv4.header();
+            let dst = header.destination_addr();
+            let proto = header.protocol();
+
+            match port {
+                Some(port) => debug!(
                     "dst_ip={:?} proto={:?} dst_port={:?} len={:?}",
-                    dst,
-                    proto,
-                    tcp.destination_port(),
-                    n
-                );
+                    dst, proto, port, n
+                ),
+                None => debug!("dst_ip={:?} proto={:?} len={:?}", dst, proto, n),
             }
-            _ => {
-                println!("dst_ip={:?} proto={:?} len={:?}", dst, proto, n);
+        }
+        Some(InternetSlice::Ipv6(ipv6)) => {
+            let header = ipv6.header();
+            let dst = header.destination_addr();
+            let next_header = header.next_header();
+            let flow_label = header.flow_label().value();
+
+            match port {
+                Some(port) => debug!(
+                    "dst_ip={:?} next_header={:?} flow_label={:?} dst_port={:?} len={:?}",
+                    dst, next_header, flow_label, port, n
+                ),
+                None => debug!(
+                    "dst_ip={:?} next_header={:?} flow_label={:?} len={:?}",
+                    dst, next_header, flow_label, n
+                ),
             }
         }
-    } else {
-        println!("Non-IPv4 packet over tunnel");
+        None => debug!("Non-IP packet over tunnel"),
     }
 }
 
@@ -42,14 +63,231 @@ pub fn you_shall_not_pass(you: &[u8], payload: &IpPayloadSlice) -> bool {
         .any(|window| window.eq_ignore_ascii_case(you))
 }
 
-pub fn encrypt(buf: &mut [u8]) {
-    for byte in buf.iter_mut() {
-        *byte = byte.wrapping_add(3);
+/// Rewrites the DSCP, the upper six bits of the traffic class, of an IPv4
+/// or IPv6 packet, and updates the IPv4 header checksum. Returns false for
+/// other packets.
+pub fn set_dscp(buf: &mut [u8], dscp: u8) -> bool {
+    match buf.first().map(|byte| byte >> 4) {
+        Some(4) => {
+            let header_len = usize::from(buf[0] & 0x0f) * 4;
+            if header_len < 20 || buf.len() < header_len {
+                return false;
+            }
+            buf[1] = (dscp << 2) | (buf[1] & 0x03);
+            buf[10..12].fill(0);
+            let checksum = ipv4_checksum(&buf[..header_len]);
+            buf[10..12].copy_from_slice(&checksum.to_be_bytes());
+            true
+        }
+        // The traffic class is between the version and the flow label
+        Some(6) if buf.len() >= 40 => {
+            buf[0] = (buf[0] & 0xf0) | (dscp >> 2);
+            buf[1] = ((dscp & 0x03) << 6) | (buf[1] & 0x3f);
+            true
+        }
+        _ => false,
+    }
+}
+
+fn ipv4_checksum(header: &[u8]) -> u16 {
+    let mut sum: u32 = header
+        .chunks(2)
+        .map(|word| u32::from(u16::from_be_bytes([word[0], word[1]])))
+        .sum();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
     }
+    !(sum as u16)
 }
 
-pub fn decrypt(buf: &mut [u8]) {
-    for byte in buf.iter_mut() {
-        *byte = byte.wrapping_sub(3);
+/// Protection of the tunneled packets with ChaCha20-Poly1305 and a key that
+/// both ends of the tunnel have. A datagram of the tunnel is a random nonce,
+/// the encrypted packet and the authentication tag, [`OVERHEAD`] bytes more
+/// than the packet.
+#[derive(Clone)]
+pub struct Cipher {
+    aead: ChaCha20Poly1305,
+}
+
+impl Cipher {
+    pub fn new(key: &[u8; KEY_SIZE]) -> Self {
+        Self {
+            aead: ChaCha20Poly1305::new(Key::from_slice(key)),
+        }
+    }
+
+    /// Reads the key from a file of 32 bytes, or of 64 hexadecimal digits,
+    /// e.g. from `head -c 32 /dev/urandom > tunnel.key`.
+    pub fn from_key_file(path: &Path) -> io::Result<Self> {
+        let bytes = fs::read(path).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("Failed to read key file {}: {}", path.display(), e),
+            )
+        })?;
+        let key = parse_key(&bytes).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Key file {} must have {} bytes or {} hexadecimal digits",
+                    path.display(),
+                    KEY_SIZE,
+                    2 * KEY_SIZE
+                ),
+            )
+        })?;
+        Ok(Self::new(&key))
+    }
+
+    /// Encrypts `packet` into `datagram` and returns the length of the
+    /// datagram. Panics if `datagram` is not [`OVERHEAD`] bytes longer than
+    /// the packet.
+    pub fn seal(&self, packet: &[u8], datagram: &mut [u8]) -> usize {
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let end = NONCE_SIZE + packet.len();
+        datagram[..NONCE_SIZE].copy_from_slice(&nonce);
+        datagram[NONCE_SIZE..end].copy_from_slice(packet);
+        let tag = self
+            .aead
+            .encrypt_in_place_detached(&nonce, &[], &mut datagram[NONCE_SIZE..end])
+            .expect("packets are far shorter than the ChaCha20 limit");
+        datagram[end..end + TAG_SIZE].copy_from_slice(&tag);
+        end + TAG_SIZE
+    }
+
+    /// Decrypts a datagram sealed by [`Cipher::seal`] in place and returns
+    /// the packet. Fails if the datagram is too short, has been modified or
+    /// was sealed with another key.
+    pub fn open<'a>(&self, datagram: &'a mut [u8]) -> Result<&'a mut [u8], Error> {
+        if datagram.len() < OVERHEAD {
+            return Err(Error);
+        }
+        let (nonce, rest) = datagram.split_at_mut(NONCE_SIZE);
+        let (packet, tag) = rest.split_at_mut(rest.len() - TAG_SIZE);
+        self.aead.decrypt_in_place_detached(
+            Nonce::from_slice(nonce),
+            &[],
+            packet,
+            Tag::from_slice(tag),
+        )?;
+        Ok(packet)
+    }
+}
+
+fn parse_key(bytes: &[u8]) -> Option<[u8; KEY_SIZE]> {
+    if let Ok(key) = bytes.try_into() {
+        return Some(key);
+    }
+    let hex = std::str::from_utf8(bytes).ok()?.trim();
+    if hex.len() != 2 * KEY_SIZE {
+        return None;
     }
-}
\ No newline at end of file
+    let mut key = [0u8; KEY_SIZE];
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(hex.get(2 * i..2 * i + 2)?, 16).ok()?;
+    }
+    Some(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use chacha20poly1305::aead::Aead;
+
+    use super::*;
+
+    const KEY: [u8; KEY_SIZE] = [7; KEY_SIZE];
+    const PACKET: &[u8] = b"E\0\0\x1c an IPv4 packet, more or less";
+
+    fn sealed(cipher: &Cipher, packet: &[u8]) -> Vec<u8> {
+        let mut datagram = vec![0; packet.len() + OVERHEAD];
+        let n = cipher.seal(packet, &mut datagram);
+        assert_eq!(n, datagram.len(), "sealed length");
+        datagram
+    }
+
+    #[test]
+    fn round_trip() {
+        let cipher = Cipher::new(&KEY);
+        for packet in [PACKET, &[]] {
+            let mut datagram = sealed(&cipher, packet);
+            assert_eq!(cipher.open(&mut datagram).unwrap(), packet);
+        }
+    }
+
+    #[test]
+    fn packet_is_encrypted() {
+        let datagram = sealed(&Cipher::new(&KEY), PACKET);
+        let ciphertext = &datagram[NONCE_SIZE..NONCE_SIZE + PACKET.len()];
+        assert_ne!(ciphertext, PACKET);
+    }
+
+    #[test]
+    fn fresh_nonce_for_each_packet() {
+        let cipher = Cipher::new(&KEY);
+        let (a, b) = (sealed(&cipher, PACKET), sealed(&cipher, PACKET));
+        assert_ne!(a[..NONCE_SIZE], b[..NONCE_SIZE], "nonces");
+        assert_ne!(a[NONCE_SIZE..], b[NONCE_SIZE..], "ciphertexts");
+    }
+
+    #[test]
+    fn layout() {
+        // The nonce, then the ciphertext and the tag as the crate puts them
+        let nonce = [3; NONCE_SIZE];
+        let aead = ChaCha20Poly1305::new(Key::from_slice(&KEY));
+        let mut datagram = nonce.to_vec();
+        datagram.extend(aead.encrypt(Nonce::from_slice(&nonce), PACKET).unwrap());
+        assert_eq!(Cipher::new(&KEY).open(&mut datagram).unwrap(), PACKET);
+    }
+
+    #[test]
+    fn tampering_is_detected() {
+        let cipher = Cipher::new(&KEY);
+        let datagram = sealed(&cipher, PACKET);
+        let regions = [
+            ("nonce", 0),
+            ("ciphertext", NONCE_SIZE + 4),
+            ("tag", datagram.len() - 1),
+        ];
+        for (region, i) in regions {
+            let mut tampered = datagram.clone();
+            tampered[i] ^= 0x01;
+            assert!(cipher.open(&mut tampered).is_err(), "{region}");
+        }
+        let mut truncated = datagram[..datagram.len() - 1].to_vec();
+        assert!(cipher.open(&mut truncated).is_err(), "truncated");
+    }
+
+    #[test]
+    fn wrong_key() {
+        let mut datagram = sealed(&Cipher::new(&KEY), PACKET);
+        assert!(Cipher::new(&[8; KEY_SIZE]).open(&mut datagram).is_err());
+    }
+
+    #[test]
+    fn too_short() {
+        let cipher = Cipher::new(&KEY);
+        for len in [0, NONCE_SIZE, OVERHEAD - 1] {
+            assert!(cipher.open(&mut vec![0; len]).is_err(), "{len} bytes");
+        }
+    }
+
+    #[test]
+    fn key_files() {
+        let hex: String = (0..KEY_SIZE).map(|i| format!("{i:02x}")).collect();
+        let key: Vec<u8> = (0..KEY_SIZE as u8).collect();
+        assert_eq!(parse_key(&key).unwrap()[..], key[..], "raw");
+        assert_eq!(parse_key(hex.as_bytes()).unwrap()[..], key[..], "hex");
+        let newline = format!("{}\n", hex.to_uppercase());
+        assert_eq!(
+            parse_key(newline.as_bytes()).unwrap()[..],
+            key[..],
+            "hex and newline"
+        );
+        assert!(parse_key(&key[1..]).is_none(), "short");
+        assert!(parse_key(&hex.as_bytes()[1..]).is_none(), "short hex");
+        assert!(
+            parse_key(hex.replace('0', "g").as_bytes()).is_none(),
+            "not hex"
+        );
+    }
+}
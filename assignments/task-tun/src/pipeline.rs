@@ -0,0 +1,182 @@
+//! The work of the tunnel on each packet between the TUN device and the
+//! link: recording, filtering with the rules, and sealing and splitting into
+//! datagrams on the way out, or reassembling and opening on the way in.
+//! Public, but not part of the documented API, so that the benchmarks run
+//! the same code as the tunnel.
+
+use std::{
+    io,
+    net::SocketAddr,
+    time::{Instant, SystemTime},
+};
+
+use etherparse::SlicedPacket;
+use tracing::{debug, warn, Level};
+
+use crate::{
+    fragment::{Fragmenter, Reassembler},
+    packet::{self, Cipher},
+    rules::{Direction, Rules, Verdict},
+    Capture, Metrics, MTU,
+};
+
+/// Writes a packet that went through the TUN device to the capture file,
+/// if there is one. Flushed right away, so that the file is complete even if
+/// the tunnel is killed.
+pub fn record(capture: &Capture, data: &[u8]) -> io::Result<()> {
+    if let Some(capture) = capture {
+        let mut capture = capture.lock().unwrap();
+        capture.write(SystemTime::now(), data, data.len())?;
+        capture.flush()?;
+    }
+    Ok(())
+}
+
+/// Records and filters a packet from the TUN device, and passes it to `send`
+/// as many times as the rules say.
+pub fn forward_out(
+    ip_packet: &mut [u8],
+    rules: &Rules,
+    capture: &Capture,
+    metrics: &Metrics,
+    mut send: impl FnMut(&[u8]) -> io::Result<()>,
+) -> io::Result<()> {
+    record(capture, ip_packet)?;
+
+    let verdict = filter(Direction::Out, ip_packet, rules, metrics);
+    if verdict.drop {
+        // Do not forward
+        metrics.dropped.inc();
+        return Ok(());
+    }
+
+    for _ in 0..copies(verdict, metrics) {
+        send(ip_packet)?;
+    }
+    Ok(())
+}
+
+/// Filters a packet from the peer, and passes it to `write` and records it
+/// as many times as the rules say.
+pub fn forward_in(
+    ip_packet: &mut [u8],
+    rules: &Rules,
+    capture: &Capture,
+    metrics: &Metrics,
+    mut write: impl FnMut(&[u8]) -> io::Result<()>,
+) -> io::Result<()> {
+    let verdict = filter(Direction::In, ip_packet, rules, metrics);
+    if verdict.drop {
+        metrics.dropped.inc();
+        return Ok(());
+    }
+
+    for _ in 0..copies(verdict, metrics) {
+        write(ip_packet)?;
+        record(capture, ip_packet)?;
+        metrics.packets_in.inc();
+        metrics.bytes_in.add(ip_packet.len() as u64);
+    }
+    Ok(())
+}
+
+/// Seals a packet with the cipher, if there is one, and passes the datagrams
+/// that carry it to `send`.
+pub fn split_packet(
+    ip_packet: &[u8],
+    cipher: Option<&Cipher>,
+    fragmenter: &mut Fragmenter,
+    metrics: &Metrics,
+    mut send: impl FnMut(&[u8]) -> io::Result<()>,
+) -> io::Result<()> {
+    let mut sealed = [0u8; MTU + packet::OVERHEAD];
+    let datagram = match cipher {
+        Some(cipher) => {
+            let len = cipher.seal(ip_packet, &mut sealed);
+            &sealed[..len]
+        }
+        None => ip_packet,
+    };
+    let fragments = fragmenter.split(datagram, |fragment| {
+        send(fragment)?;
+        metrics.bytes_out.add(fragment.len() as u64);
+        Ok::<_, io::Error>(())
+    })?;
+    if fragments > 1 {
+        metrics.fragmented.inc();
+    }
+    metrics.packets_out.inc();
+    Ok(())
+}
+
+/// The reverse of [`split_packet`]: returns the packet that a datagram from
+/// `src` completes, opened with the cipher if there is one. Invalid
+/// datagrams and packets that fail authentication are dropped.
+pub fn join_packet<'a>(
+    datagram: &[u8],
+    src: SocketAddr,
+    cipher: Option<&Cipher>,
+    reassembler: &'a mut Reassembler,
+    now: Instant,
+    metrics: &Metrics,
+) -> Option<&'a mut [u8]> {
+    let expired = reassembler.expire(now);
+    if expired > 0 {
+        debug!("{} packets timed out waiting for fragments", expired);
+        metrics.reassembly_timeouts.add(expired as u64);
+    }
+    let datagram = match reassembler.push(datagram, now) {
+        Ok(datagram) => datagram?,
+        Err(e) => {
+            warn!("Dropping datagram from {}: {}", src, e);
+            metrics.parse_errors.inc();
+            return None;
+        }
+    };
+
+    match cipher {
+        Some(cipher) => match cipher.open(datagram) {
+            Ok(ip_packet) => Some(ip_packet),
+            Err(_) => {
+                warn!("Dropping packet from {} that failed authentication", src);
+                metrics.auth_failures.inc();
+                None
+            }
+        },
+        None => Some(datagram),
+    }
+}
+
+/// Parses a packet going through the tunnel and applies the rules to it,
+/// rewriting the DSCP if they say so.
+pub fn filter(direction: Direction, buf: &mut [u8], rules: &Rules, metrics: &Metrics) -> Verdict {
+    let verdict = match SlicedPacket::from_ip(buf) {
+        Ok(sliced) => {
+            if tracing::enabled!(Level::DEBUG) {
+                packet::print_packet_info(&sliced, buf.len());
+            }
+            rules.evaluate(direction, &sliced)
+        }
+        Err(e) => {
+            warn!("Failed to parse tunneled IP packet: {}", e);
+            metrics.parse_errors.inc();
+            Verdict::default()
+        }
+    };
+    if let Some(dscp) = verdict.dscp.filter(|_| !verdict.drop) {
+        if packet::set_dscp(buf, dscp) {
+            metrics.rewritten.inc();
+        }
+    }
+    verdict
+}
+
+/// How many times the packet is sent on.
+fn copies(verdict: Verdict, metrics: &Metrics) -> usize {
+    if verdict.duplicate {
+        metrics.duplicated.inc();
+        2
+    } else {
+        1
+    }
+}
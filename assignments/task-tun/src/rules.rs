@@ -0,0 +1,602 @@
+//! The filtering rules of the tunnel. A rules file is TOML with a `[[rule]]`
+//! table per rule; every rule that matches a packet applies, in the order of
+//! the file, until one drops it:
+//!
+//! ```toml
+//! [[rule]]
+//! name = "taylor"
+//! payload = "taylor"
+//! action = "drop"
+//!
+//! [[rule]]
+//! direction = "out"
+//! destination = "10.0.0.0/24"
+//! protocol = "udp"
+//! port = 5000
+//! action = { dscp = 46 }
+//! ```
+//!
+//! A rule matches the packets that match all of its criteria: a payload
+//! substring, case insensitive, the source or destination address or
+//! network, IPv4 or IPv6, the protocol (`tcp`, `udp` or `icmp`) and the destination port.
+//! The action is `drop`, `duplicate`, `log` or `{ dscp = N }`, which
+//! rewrites the DSCP of the IP header. `direction` is `out` for the packets
+//! from the TUN device, `in` for the packets from the UDP socket, and both
+//! by default.
+
+use std::{fmt, fs, io, net::IpAddr, path::Path, str::FromStr};
+
+use etherparse::{InternetSlice, SlicedPacket, TransportSlice};
+use serde::Deserialize;
+use tracing::{debug, info};
+
+use crate::packet;
+
+/// The way a packet goes through the tunnel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    /// From the TUN device to the UDP socket
+    Out,
+    /// From the UDP socket to the TUN device
+    In,
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Direction::Out => write!(f, "TUN"),
+            Direction::In => write!(f, "UDP socket"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    Tcp,
+    Udp,
+    Icmp,
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Protocol::Tcp => write!(f, "tcp"),
+            Protocol::Udp => write!(f, "udp"),
+            Protocol::Icmp => write!(f, "icmp"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    Drop,
+    Duplicate,
+    Log,
+    /// Rewrite the DSCP of the packet to this value
+    Dscp(u8),
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Action::Drop => write!(f, "drop"),
+            Action::Duplicate => write!(f, "duplicate"),
+            Action::Log => write!(f, "log"),
+            Action::Dscp(dscp) => write!(f, "dscp {}", dscp),
+        }
+    }
+}
+
+/// An address, or a network as ADDRESS/PREFIX.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, address: IpAddr) -> bool {
+        match (self.network, address) {
+            (IpAddr::V4(network), IpAddr::V4(address)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(network) & mask == u32::from(address) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(address)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(network) & mask == u128::from(address) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix)
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let (address, prefix) = match s.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (s, None),
+        };
+        let network: IpAddr = address.parse().map_err(|e| format!("{}: {}", s, e))?;
+        let bits = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse()
+                .ok()
+                .filter(|&prefix| prefix <= bits)
+                .ok_or_else(|| format!("{}: invalid prefix length", s))?,
+            None => bits,
+        };
+        Ok(Self { network, prefix })
+    }
+}
+
+impl TryFrom<String> for Cidr {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, String> {
+        s.parse()
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    /// For the log, "rule N" by default
+    #[serde(default)]
+    pub name: String,
+    pub direction: Option<Direction>,
+    pub payload: Option<String>,
+    pub source: Option<Cidr>,
+    pub destination: Option<Cidr>,
+    pub protocol: Option<Protocol>,
+    pub port: Option<u16>,
+    pub action: Action,
+}
+
+impl Rule {
+    fn word(name: &str, direction: Option<Direction>, action: Action) -> Self {
+        Self {
+            name: name.to_string(),
+            direction,
+            payload: Some(name.to_string()),
+            source: None,
+            destination: None,
+            protocol: None,
+            port: None,
+            action,
+        }
+    }
+
+    fn matches(&self, direction: Direction, sliced: &SlicedPacket) -> bool {
+        if self.direction.is_some_and(|d| d != direction) {
+            return false;
+        }
+        let (source, destination, payload) = match &sliced.net {
+            Some(InternetSlice::Ipv4(ipv4)) => {
+                let header = ipv4.header();
+                let source = IpAddr::from(header.source_addr());
+                let destination = IpAddr::from(header.destination_addr());
+                (source, destination, ipv4.payload())
+            }
+            Some(InternetSlice::Ipv6(ipv6)) => {
+                let header = ipv6.header();
+                let source = IpAddr::from(header.source_addr());
+                let destination = IpAddr::from(header.destination_addr());
+                (source, destination, ipv6.payload())
+            }
+            None => return false,
+        };
+
+        let port = match &sliced.transport {
+            Some(TransportSlice::Tcp(tcp)) => Some(tcp.destination_port()),
+            Some(TransportSlice::Udp(udp)) => Some(udp.destination_port()),
+            _ => None,
+        };
+        let protocol = match &sliced.transport {
+            Some(TransportSlice::Tcp(_)) => Some(Protocol::Tcp),
+            Some(TransportSlice::Udp(_)) => Some(Protocol::Udp),
+            Some(TransportSlice::Icmpv4(_) | TransportSlice::Icmpv6(_)) => Some(Protocol::Icmp),
+            _ => None,
+        };
+
+        self.source.is_none_or(|cidr| cidr.contains(source))
+            && self
+                .destination
+                .is_none_or(|cidr| cidr.contains(destination))
+            && self.protocol.is_none_or(|p| protocol == Some(p))
+            && self.port.is_none_or(|p| port == Some(p))
+            && self
+                .payload
+                .as_ref()
+                .is_none_or(|s| packet::you_shall_not_pass(s.as_bytes(), payload))
+    }
+}
+
+impl fmt::Display for Rule {
+    /// The rule on one line, with the criteria as in the rules file.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:", self.name)?;
+        match self.direction {
+            Some(Direction::Out) => write!(f, " direction out")?,
+            Some(Direction::In) => write!(f, " direction in")?,
+            None => {}
+        }
+        if let Some(payload) = &self.payload {
+            write!(f, " payload {:?}", payload)?;
+        }
+        if let Some(source) = self.source {
+            write!(f, " source {}", source)?;
+        }
+        if let Some(destination) = self.destination {
+            write!(f, " destination {}", destination)?;
+        }
+        if let Some(protocol) = self.protocol {
+            write!(f, " protocol {}", protocol)?;
+        }
+        if let Some(port) = self.port {
+            write!(f, " port {}", port)?;
+        }
+        write!(f, " -> {}", self.action)
+    }
+}
+
+/// What the rules decided for a packet.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Verdict {
+    pub drop: bool,
+    pub duplicate: bool,
+    pub dscp: Option<u8>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RulesFile {
+    #[serde(default)]
+    rule: Vec<Rule>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Rules {
+    rules: Vec<Rule>,
+}
+
+impl Default for Rules {
+    /// The filter of the assignment: packets that contain "taylor" are
+    /// dropped, and packets from the TUN device that contain "elvis" are
+    /// sent twice.
+    fn default() -> Self {
+        Self {
+            rules: vec![
+                Rule::word("taylor", None, Action::Drop),
+                Rule::word("elvis", Some(Direction::Out), Action::Duplicate),
+            ],
+        }
+    }
+}
+
+impl FromStr for Rules {
+    type Err = String;
+
+    /// Parses the text of a rules file.
+    fn from_str(s: &str) -> Result<Self, String> {
+        let file: RulesFile = toml::from_str(s).map_err(|e| e.to_string())?;
+        let mut rules = file.rule;
+        for (i, rule) in rules.iter_mut().enumerate() {
+            if rule.name.is_empty() {
+                rule.name = format!("rule {}", i + 1);
+            }
+            if let Action::Dscp(dscp) = rule.action {
+                if dscp >= 64 {
+                    return Err(format!("{}: DSCP {} is over 63", rule.name, dscp));
+                }
+            }
+            if rule.payload.as_ref().is_some_and(String::is_empty) {
+                return Err(format!(
+                    "{}: empty payload, which would match every packet",
+                    rule.name
+                ));
+            }
+        }
+        Ok(Self { rules })
+    }
+}
+
+impl Rules {
+    /// Reads a rules file. An empty file has no rules and passes everything.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let text = fs::read_to_string(path).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("Cannot read rules file {}: {}", path.display(), e),
+            )
+        })?;
+        text.parse().map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid rules file {}: {}", path.display(), e),
+            )
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.rules.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// The rules in the order they apply.
+    pub fn iter(&self) -> impl Iterator<Item = &Rule> {
+        self.rules.iter()
+    }
+
+    /// Applies the matching rules to a packet going in `direction`.
+    pub fn evaluate(&self, direction: Direction, sliced: &SlicedPacket) -> Verdict {
+        let mut verdict = Verdict::default();
+        for rule in self.rules.iter().filter(|r| r.matches(direction, sliced)) {
+            match rule.action {
+                Action::Drop => {
+                    debug!(
+                        "Packet from {} matches '{}', dropping",
+                        direction, rule.name
+                    );
+                    verdict.drop = true;
+                    break;
+                }
+                Action::Duplicate => {
+                    debug!(
+                        "Packet from {} matches '{}', duplicating",
+                        direction, rule.name
+                    );
+                    verdict.duplicate = true;
+                }
+                Action::Log => info!("Packet from {} matches '{}'", direction, rule.name),
+                Action::Dscp(dscp) => {
+                    debug!(
+                        "Packet from {} matches '{}', setting DSCP {}",
+                        direction, rule.name, dscp
+                    );
+                    verdict.dscp = Some(dscp);
+                }
+            }
+        }
+        verdict
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv6Addr;
+
+    use pktgen::{Spec, TcpFlags};
+
+    use super::*;
+
+    fn udp(destination: [u8; 4], port: u16, payload: &[u8]) -> Vec<u8> {
+        Spec::udp(([10, 0, 0, 1].into(), 40000), (destination.into(), port))
+            .build(payload)
+            .unwrap()
+    }
+
+    fn verdict(rules: &Rules, direction: Direction, packet: &[u8]) -> Verdict {
+        rules.evaluate(direction, &SlicedPacket::from_ip(packet).unwrap())
+    }
+
+    fn matches(rule: &str, direction: Direction, packet: &[u8]) -> bool {
+        let rules: Rules = format!("[[rule]]\n{rule}\naction = \"drop\"")
+            .parse()
+            .unwrap();
+        verdict(&rules, direction, packet).drop
+    }
+
+    #[test]
+    fn names_and_order() {
+        let rules: Rules = r#"
+            [[rule]]
+            name = "voice"
+            port = 5060
+            action = { dscp = 46 }
+
+            [[rule]]
+            action = "log"
+        "#
+        .parse()
+        .unwrap();
+        let names: Vec<_> = rules.iter().map(|rule| rule.name.as_str()).collect();
+        assert_eq!(names, ["voice", "rule 2"]);
+        assert_eq!(rules.iter().next().unwrap().action, Action::Dscp(46));
+        assert!("".parse::<Rules>().unwrap().is_empty(), "empty file");
+    }
+
+    #[test]
+    fn parse_errors_have_line_numbers() {
+        let cases = [
+            ("[[rule]]\naction = \"drop\"\nport = \"http\"", "line 3"),
+            (
+                "[[rule]]\naction = \"drop\"\n\n[[rule]]\naction = \"reject\"",
+                "line 5",
+            ),
+            ("[[rule]]\naction = \"drop\"\ncolour = \"red\"", "line 3"),
+            (
+                "[[rule]]\ndestination = \"10.0.0.0/33\"\naction = \"drop\"",
+                "line 2",
+            ),
+            ("[[rule]]\nport = 80", "line 1"),
+        ];
+        for (text, line) in cases {
+            let e = text.parse::<Rules>().unwrap_err();
+            assert!(e.contains(line), "{text:?}: {e}");
+        }
+        let e = "[[rule]]\naction = { dscp = 64 }"
+            .parse::<Rules>()
+            .unwrap_err();
+        assert_eq!(e, "rule 1: DSCP 64 is over 63");
+    }
+
+    #[test]
+    fn empty_payload_is_rejected() {
+        let e = "[[rule]]\nname = \"nothing\"\npayload = \"\"\naction = \"drop\""
+            .parse::<Rules>()
+            .unwrap_err();
+        assert_eq!(e, "nothing: empty payload, which would match every packet");
+    }
+
+    #[test]
+    fn cidr() {
+        let network: Cidr = "10.1.0.0/16".parse().unwrap();
+        assert!(network.contains([10, 1, 200, 3].into()));
+        assert!(!network.contains([10, 2, 0, 1].into()));
+        assert!(
+            !network.contains(Ipv6Addr::LOCALHOST.into()),
+            "other version"
+        );
+
+        let address: Cidr = "10.1.2.3".parse().unwrap();
+        assert_eq!(address.to_string(), "10.1.2.3/32");
+        assert!(address.contains([10, 1, 2, 3].into()));
+        assert!(!address.contains([10, 1, 2, 4].into()));
+
+        let everything: Cidr = "0.0.0.0/0".parse().unwrap();
+        assert!(everything.contains([192, 0, 2, 1].into()));
+
+        let network6: Cidr = "fd00:1::/32".parse().unwrap();
+        assert!(network6.contains("fd00:1:ffff::1".parse().unwrap()));
+        assert!(!network6.contains("fd00:2::1".parse().unwrap()));
+
+        for invalid in ["10.1.0.0/33", "fd00::/129", "10.1.0.0/x", "10.1.0/8"] {
+            assert!(invalid.parse::<Cidr>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn criteria() {
+        let packet = udp([10, 0, 1, 2], 5000, b"Hello Taylor");
+        let out = Direction::Out;
+        assert!(matches("", out, &packet), "no criteria");
+        assert!(matches("destination = \"10.0.1.0/24\"", out, &packet));
+        assert!(!matches("destination = \"10.0.2.0/24\"", out, &packet));
+        assert!(matches("source = \"10.0.0.1\"", out, &packet));
+        assert!(!matches("source = \"10.0.1.2\"", out, &packet));
+        assert!(matches("protocol = \"udp\"", out, &packet));
+        assert!(!matches("protocol = \"tcp\"", out, &packet));
+        assert!(matches("port = 5000", out, &packet));
+        assert!(!matches("port = 40000", out, &packet), "source port");
+        assert!(matches("payload = \"TAYLOR\"", out, &packet), "case");
+        assert!(!matches("payload = \"elvis\"", out, &packet));
+        assert!(matches("direction = \"out\"", out, &packet));
+        assert!(!matches("direction = \"in\"", out, &packet));
+        assert!(
+            !matches("protocol = \"udp\"\nport = 5001", out, &packet),
+            "all criteria"
+        );
+    }
+
+    #[test]
+    fn protocols() {
+        let (source, destination) = ([10, 0, 0, 1].into(), [10, 0, 0, 2].into());
+        let tcp = Spec::tcp((source, 40000), (destination, 80), TcpFlags::default())
+            .build(&[])
+            .unwrap();
+        let echo = Spec::echo(source, destination, 1, 1).build(&[]).unwrap();
+        let v6 = Spec::udp(
+            (Ipv6Addr::LOCALHOST.into(), 40000),
+            ("fd00::2".parse().unwrap(), 53),
+        )
+        .build(&[])
+        .unwrap();
+
+        assert!(matches(
+            "protocol = \"tcp\"\nport = 80",
+            Direction::In,
+            &tcp
+        ));
+        assert!(!matches("protocol = \"udp\"", Direction::In, &tcp));
+        assert!(matches("protocol = \"icmp\"", Direction::In, &echo));
+        assert!(!matches("port = 0", Direction::In, &echo), "no port");
+        assert!(matches(
+            "destination = \"fd00::/64\"\nport = 53",
+            Direction::In,
+            &v6
+        ));
+        assert!(!matches("destination = \"0.0.0.0/0\"", Direction::In, &v6));
+    }
+
+    #[test]
+    fn first_drop_wins() {
+        let rules: Rules = r#"
+            [[rule]]
+            port = 5000
+            action = { dscp = 10 }
+
+            [[rule]]
+            port = 5000
+            action = "duplicate"
+
+            [[rule]]
+            payload = "drop me"
+            action = "drop"
+
+            [[rule]]
+            port = 5000
+            action = { dscp = 20 }
+        "#
+        .parse()
+        .unwrap();
+
+        let passed = verdict(
+            &rules,
+            Direction::Out,
+            &udp([10, 0, 0, 2], 5000, b"keep me"),
+        );
+        let expected = Verdict {
+            drop: false,
+            duplicate: true,
+            dscp: Some(20),
+        };
+        assert_eq!(
+            passed, expected,
+            "every matching rule applies, the last DSCP wins"
+        );
+
+        let dropped = verdict(
+            &rules,
+            Direction::Out,
+            &udp([10, 0, 0, 2], 5000, b"drop me"),
+        );
+        let expected = Verdict {
+            drop: true,
+            duplicate: true,
+            dscp: Some(10),
+        };
+        assert_eq!(dropped, expected, "the rules after a drop do not apply");
+    }
+
+    #[test]
+    fn default_rules() {
+        let rules = Rules::default();
+        let packet = |payload| udp([10, 0, 0, 2], 5000, payload);
+        for direction in [Direction::Out, Direction::In] {
+            let v = verdict(&rules, direction, &packet(b"Hello"));
+            assert_eq!(v, Verdict::default(), "{direction}: pass");
+            assert!(
+                verdict(&rules, direction, &packet(b"TayLor")).drop,
+                "{direction}: drop"
+            );
+        }
+        assert!(verdict(&rules, Direction::Out, &packet(b"Elvis")).duplicate);
+        assert!(!verdict(&rules, Direction::In, &packet(b"Elvis")).duplicate);
+    }
+}
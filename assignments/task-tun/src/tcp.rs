@@ -0,0 +1,442 @@
+//! The TCP transport of the tunnel, for networks that block UDP. The packets
+//! go as frames on one TCP connection between the tunnel ends: the length of
+//! the packet (2 bytes, network byte order) and the packet, sealed as in the
+//! datagrams when there is a key. One end listens and the other connects to
+//! it. When the connection breaks, the connecting end connects again after
+//! [`MIN_BACKOFF`], doubling the wait up to [`MAX_BACKOFF`] while it fails,
+//! and the listening end waits for it.
+//!
+//! Packets from the TUN device are dropped while there is no connection, or
+//! when [`MAX_BACKLOG`] bytes are already waiting to be sent, as a full
+//! queue would drop them.
+
+use std::{
+    fmt::{self, Write as _},
+    io::{self, Read, Write},
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use mio::{
+    net::{TcpListener, TcpStream},
+    Interest, Registry, Token,
+};
+use tracing::{debug, info, warn};
+
+use crate::{
+    packet::{self, Cipher},
+    Metrics,
+};
+
+/// The size of the length of a frame
+pub const LENGTH_SIZE: usize = 2;
+pub const MIN_BACKOFF: Duration = Duration::from_secs(1);
+pub const MAX_BACKOFF: Duration = Duration::from_secs(30);
+/// The most bytes of frames waiting to be sent
+pub const MAX_BACKLOG: usize = 256 * 1024;
+// Bytes read from the connection at a time
+const READ_SIZE: usize = 16 * 1024;
+
+/// Which end of the connection the tunnel is.
+pub enum Role {
+    /// Connects to the peer at this address
+    Connect(SocketAddr),
+    /// Accepts the peer on this listener
+    Listen(TcpListener),
+}
+
+pub struct TcpLink {
+    registry: Registry,
+    token: Token,
+    role: Role,
+    stream: Option<TcpStream>,
+    // Whether the stream has finished connecting
+    connected: bool,
+    peer: Option<SocketAddr>,
+    cipher: Option<Cipher>,
+    read_buf: Vec<u8>,
+    // The frame returned by the last receive, removed by the next one
+    consumed: usize,
+    write_buf: Vec<u8>,
+    reconnect_at: Option<Instant>,
+    backoff: Duration,
+}
+
+impl TcpLink {
+    /// Registers the connection with `token` and the listener, if there is
+    /// one, with `listener_token`. A connecting link connects on the first
+    /// [`TcpLink::tick`].
+    pub fn new(
+        registry: &Registry,
+        mut role: Role,
+        token: Token,
+        listener_token: Token,
+        cipher: Option<Cipher>,
+    ) -> io::Result<Self> {
+        let peer = match &mut role {
+            Role::Connect(address) => Some(*address),
+            Role::Listen(listener) => {
+                registry.register(listener, listener_token, Interest::READABLE)?;
+                None
+            }
+        };
+        let reconnect_at = peer.map(|_| Instant::now());
+        Ok(Self {
+            registry: registry.try_clone()?,
+            token,
+            role,
+            stream: None,
+            connected: false,
+            peer,
+            cipher,
+            read_buf: Vec::with_capacity(READ_SIZE),
+            consumed: 0,
+            write_buf: Vec::new(),
+            reconnect_at,
+            backoff: MIN_BACKOFF,
+        })
+    }
+
+    /// Sends a packet to the peer, or drops it if the connection is down or
+    /// backlogged.
+    pub fn send(&mut self, ip_packet: &[u8], metrics: &Metrics) {
+        if !self.connected || self.write_buf.len() >= MAX_BACKLOG {
+            debug!(
+                "Dropping packet of {} bytes, the connection is {}",
+                ip_packet.len(),
+                if self.connected { "backlogged" } else { "down" }
+            );
+            metrics.unsent.inc();
+            return;
+        }
+
+        let start = self.write_buf.len();
+        let len = match self.cipher {
+            Some(_) => ip_packet.len() + packet::OVERHEAD,
+            None => ip_packet.len(),
+        };
+        self.write_buf
+            .extend_from_slice(&(len as u16).to_be_bytes());
+        match &self.cipher {
+            Some(cipher) => {
+                self.write_buf.resize(start + LENGTH_SIZE + len, 0);
+                cipher.seal(ip_packet, &mut self.write_buf[start + LENGTH_SIZE..]);
+            }
+            None => self.write_buf.extend_from_slice(ip_packet),
+        }
+        metrics.packets_out.inc();
+        metrics.bytes_out.add((LENGTH_SIZE + len) as u64);
+        self.flush();
+    }
+
+    /// Reads from the connection, and returns the next packet if a whole
+    /// frame has arrived and it passes authentication. Fails with
+    /// `WouldBlock` when there is nothing more to read.
+    pub fn receive(&mut self, metrics: &Metrics) -> io::Result<Option<&mut [u8]>> {
+        self.read_buf.drain(..self.consumed);
+        self.consumed = 0;
+        if self.next_frame().is_none() {
+            self.fill()?;
+        }
+        let Some(len) = self.next_frame() else {
+            return Ok(None);
+        };
+        self.consumed = LENGTH_SIZE + len;
+        if len == 0 {
+            return Ok(None);
+        }
+
+        let frame = &mut self.read_buf[LENGTH_SIZE..LENGTH_SIZE + len];
+        match &self.cipher {
+            Some(cipher) => match cipher.open(frame) {
+                Ok(ip_packet) => Ok(Some(ip_packet)),
+                Err(_) => {
+                    warn!("Dropping packet that failed authentication");
+                    metrics.auth_failures.inc();
+                    Ok(None)
+                }
+            },
+            None => Ok(Some(frame)),
+        }
+    }
+
+    /// The length of the frame at the start of the read buffer, if all of
+    /// it has arrived.
+    fn next_frame(&self) -> Option<usize> {
+        let (length, rest) = self.read_buf.split_first_chunk::<LENGTH_SIZE>()?;
+        let len = u16::from_be_bytes(*length) as usize;
+        (rest.len() >= len).then_some(len)
+    }
+
+    fn fill(&mut self) -> io::Result<()> {
+        let (true, Some(stream)) = (self.connected, &mut self.stream) else {
+            return Err(io::ErrorKind::WouldBlock.into());
+        };
+        let mut chunk = [0u8; READ_SIZE];
+        match stream.read(&mut chunk) {
+            Ok(0) => {
+                self.disconnect(&"closed by the peer");
+                Err(io::ErrorKind::WouldBlock.into())
+            }
+            Ok(n) => {
+                self.read_buf.extend_from_slice(&chunk[..n]);
+                Ok(())
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Err(e),
+            Err(e) => {
+                self.disconnect(&e);
+                Err(io::ErrorKind::WouldBlock.into())
+            }
+        }
+    }
+
+    /// Finishes connecting and sends the frames that are waiting, when the
+    /// connection is ready.
+    pub fn ready(&mut self, metrics: &Metrics) {
+        let Some(stream) = &self.stream else {
+            return;
+        };
+        if !self.connected {
+            match stream.take_error() {
+                Ok(Some(e)) | Err(e) => {
+                    self.disconnect(&e);
+                    return;
+                }
+                Ok(None) => {}
+            }
+            match stream.peer_addr() {
+                Ok(peer) => {
+                    info!("Connected to {}", peer);
+                    self.connected = true;
+                    self.backoff = MIN_BACKOFF;
+                    metrics.connections.inc();
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotConnected => return,
+                Err(e) => {
+                    self.disconnect(&e);
+                    return;
+                }
+            }
+        }
+        self.flush();
+    }
+
+    /// Accepts the peer. While the connection is up, a new one replaces it
+    /// only from the address of the peer, as the peer connects again when it
+    /// has lost the previous one, and connections from elsewhere are closed.
+    pub fn accept(&mut self, metrics: &Metrics) -> io::Result<()> {
+        loop {
+            let accepted = match &self.role {
+                Role::Listen(listener) => listener.accept(),
+                Role::Connect(_) => return Ok(()),
+            };
+            let (stream, peer) = match accepted {
+                Ok(accepted) => accepted,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => return Err(e),
+            };
+            match self.peer.filter(|_| self.stream.is_some()) {
+                Some(previous) if previous.ip() != peer.ip() => {
+                    warn!(
+                        "Closing connection from {}, the peer is connected from {}",
+                        peer, previous
+                    );
+                    continue;
+                }
+                Some(previous) => info!(
+                    "Peer connected from {}, replacing the connection from {}",
+                    peer, previous
+                ),
+                None => info!("Peer connected from {}", peer),
+            }
+            self.attach(stream, peer)?;
+            self.connected = true;
+            metrics.connections.inc();
+        }
+    }
+
+    /// Connects to the peer when it is time to try again.
+    pub fn tick(&mut self, now: Instant) {
+        let Role::Connect(address) = self.role else {
+            return;
+        };
+        if self.stream.is_some() || self.reconnect_at.is_none_or(|at| now < at) {
+            return;
+        }
+        self.reconnect_at = None;
+        debug!("Connecting to {}", address);
+        let attached = TcpStream::connect(address).and_then(|stream| self.attach(stream, address));
+        if let Err(e) = attached {
+            self.disconnect(&e);
+        }
+    }
+
+    /// When to connect again, if the connection is down.
+    pub fn next_timer(&self) -> Option<Instant> {
+        self.reconnect_at
+    }
+
+    /// The state for the state command of the control socket.
+    pub fn state(&self) -> String {
+        let mut state = String::new();
+        let _ = writeln!(state, "transport tcp");
+        match self.peer {
+            Some(peer) => {
+                let _ = writeln!(state, "peer {}", peer);
+            }
+            None => state.push_str("peer none\n"),
+        }
+        let connection = match (&self.stream, self.connected) {
+            (Some(_), true) => "up",
+            (Some(_), false) => "connecting",
+            (None, _) => "down",
+        };
+        let _ = writeln!(state, "connection {}", connection);
+        let encryption = if self.cipher.is_some() { "on" } else { "off" };
+        let _ = writeln!(state, "encryption {}", encryption);
+        let _ = writeln!(state, "backlog {}", self.write_buf.len());
+        state
+    }
+
+    fn attach(&mut self, mut stream: TcpStream, peer: SocketAddr) -> io::Result<()> {
+        self.detach();
+        stream.set_nodelay(true)?;
+        self.registry.register(
+            &mut stream,
+            self.token,
+            Interest::READABLE | Interest::WRITABLE,
+        )?;
+        self.stream = Some(stream);
+        self.peer = Some(peer);
+        Ok(())
+    }
+
+    fn detach(&mut self) {
+        if let Some(mut stream) = self.stream.take() {
+            let _ = self.registry.deregister(&mut stream);
+        }
+        self.connected = false;
+        self.read_buf.clear();
+        self.consumed = 0;
+        self.write_buf.clear();
+    }
+
+    /// Drops the connection, and with the connecting end, sets the time to
+    /// connect again.
+    fn disconnect(&mut self, reason: &dyn fmt::Display) {
+        let peer = self.peer.map_or("the peer".to_string(), |p| p.to_string());
+        self.detach();
+        match self.role {
+            Role::Connect(_) => {
+                warn!(
+                    "Connection to {} down: {}, connecting again in {:?}",
+                    peer, reason, self.backoff
+                );
+                self.reconnect_at = Some(Instant::now() + self.backoff);
+                self.backoff = (self.backoff * 2).min(MAX_BACKOFF);
+            }
+            Role::Listen(_) => warn!(
+                "Connection from {} down: {}, waiting for the peer",
+                peer, reason
+            ),
+        }
+    }
+
+    /// Writes the waiting frames until the connection takes no more.
+    fn flush(&mut self) {
+        let (true, Some(stream)) = (self.connected, &mut self.stream) else {
+            return;
+        };
+        let mut written = 0;
+        let mut error = None;
+        while written < self.write_buf.len() {
+            match stream.write(&self.write_buf[written..]) {
+                Ok(0) => {
+                    error = Some(io::Error::from(io::ErrorKind::WriteZero));
+                    break;
+                }
+                Ok(n) => written += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    error = Some(e);
+                    break;
+                }
+            }
+        }
+        self.write_buf.drain(..written);
+        if let Some(e) = error {
+            self.disconnect(&e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::TcpStream as StdTcpStream, thread};
+
+    use mio::Poll;
+
+    use super::*;
+
+    /// Listens on all addresses of both IP versions, so that a connection to
+    /// the IPv6 loopback and one to the IPv4 loopback come from different
+    /// addresses.
+    fn listening_link(poll: &Poll) -> (TcpLink, u16) {
+        let listener = TcpListener::bind("[::]:0".parse().unwrap()).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let link = TcpLink::new(
+            poll.registry(),
+            Role::Listen(listener),
+            Token(0),
+            Token(1),
+            None,
+        );
+        (link.unwrap(), port)
+    }
+
+    /// Connects to the link from `ip`, and accepts until the link has handled
+    /// the connection, which is when it is the peer or has been closed.
+    fn connect(link: &mut TcpLink, ip: &str, port: u16, metrics: &Metrics) -> StdTcpStream {
+        let client = StdTcpStream::connect((ip, port)).unwrap();
+        client.set_nonblocking(true).unwrap();
+        let local = client.local_addr().unwrap();
+        for _ in 0..100 {
+            link.accept(metrics).unwrap();
+            let closed = matches!((&client).read(&mut [0; 1]), Ok(0));
+            if link.peer == Some(local) || closed {
+                return client;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("Connection from {} not handled", local);
+    }
+
+    #[test]
+    fn connection_from_elsewhere_does_not_replace_the_peer() {
+        let poll = Poll::new().unwrap();
+        let metrics = Metrics::new();
+        let (mut link, port) = listening_link(&poll);
+
+        let peer = connect(&mut link, "::1", port, &metrics);
+        let intruder = connect(&mut link, "127.0.0.1", port, &metrics);
+
+        assert_eq!(link.peer, Some(peer.local_addr().unwrap()));
+        assert!(link.connected);
+        assert!(matches!((&intruder).read(&mut [0; 1]), Ok(0)));
+    }
+
+    #[test]
+    fn peer_connecting_again_replaces_the_connection() {
+        let poll = Poll::new().unwrap();
+        let metrics = Metrics::new();
+        let (mut link, port) = listening_link(&poll);
+
+        let _lost = connect(&mut link, "::1", port, &metrics);
+        let again = connect(&mut link, "::1", port, &metrics);
+
+        assert_eq!(link.peer, Some(again.local_addr().unwrap()));
+        assert!(link.connected);
+    }
+}
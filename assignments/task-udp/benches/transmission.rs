@@ -0,0 +1,107 @@
+//! Benchmarks for the sender's per-packet work: building packets, processing
+//! acknowledgements with many packets in flight, and the RTT and congestion
+//! window updates done for every acknowledgement.
+//!
+//! Run with `cargo bench -p task-udp`.
+
+use std::hint::black_box;
+
+use adnet_core::rtt::RttEstimator;
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use task_udp::proto::{CongestionController, Reno, TransmissionState, MAX_PAYLOAD};
+
+const IN_FLIGHT: [u32; 3] = [64, 1024, 16384];
+
+fn packet_construction(c: &mut Criterion) {
+    let mut group = c.benchmark_group("create_packet");
+    for size in [64, MAX_PAYLOAD] {
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &size, |b, &size| {
+            b.iter(|| TransmissionState::create_packet(black_box(12345), size, b'A'))
+        });
+    }
+    group.finish();
+}
+
+fn ack_handling(c: &mut Criterion) {
+    let mut group = c.benchmark_group("handle_ack");
+    for n in IN_FLIGHT {
+        group.throughput(Throughput::Elements(n as u64));
+
+        // Every packet acknowledged separately, in order
+        group.bench_with_input(BenchmarkId::new("in_order", n), &n, |b, &n| {
+            b.iter_batched(
+                || TransmissionState::with_in_flight(n),
+                |mut state| {
+                    for seq in 1..=n {
+                        black_box(state.handle_ack(seq, 0, &[]));
+                    }
+                    state
+                },
+                BatchSize::LargeInput,
+            )
+        });
+
+        // One acknowledgement covering everything, e.g. after a lost ACK train
+        group.bench_with_input(BenchmarkId::new("cumulative", n), &n, |b, &n| {
+            b.iter_batched(
+                || TransmissionState::with_in_flight(n),
+                |mut state| {
+                    black_box(state.handle_ack(n, 0, &[]));
+                    state
+                },
+                BatchSize::LargeInput,
+            )
+        });
+
+        // Duplicate acknowledgements while the first packet is missing
+        group.bench_with_input(BenchmarkId::new("duplicate", n), &n, |b, &n| {
+            b.iter_batched(
+                || TransmissionState::with_in_flight(n),
+                |mut state| {
+                    for _ in 0..n {
+                        black_box(state.handle_ack(0, 0, &[]));
+                    }
+                    state
+                },
+                BatchSize::LargeInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+fn control_updates(c: &mut Criterion) {
+    c.bench_function("congestion/on_ack", |b| {
+        let mut cc = Reno::default();
+        b.iter(|| {
+            cc.on_ack(1, None);
+            black_box(cc.window())
+        })
+    });
+    c.bench_function("congestion/ack_and_loss", |b| {
+        let mut cc = Reno::default();
+        let mut i = 0u32;
+        b.iter(|| {
+            i = i.wrapping_add(1);
+            if i.is_multiple_of(32) {
+                cc.on_timeout();
+            } else {
+                cc.on_ack(1, None);
+            }
+            black_box(cc.window())
+        })
+    });
+    c.bench_function("rtt/update", |b| {
+        let mut rtt = RttEstimator::new();
+        let mut sample = 10.0;
+        b.iter(|| {
+            sample = if sample > 30.0 { 10.0 } else { sample + 0.5 };
+            rtt.update(black_box(sample));
+            black_box(rtt.rto)
+        })
+    });
+}
+
+criterion_group!(benches, packet_construction, ack_handling, control_updates);
+criterion_main!(benches);
@@ -1,40 +1,408 @@
-const INITIAL_CWND: f64 = 2.0;
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+/// Congestion window at the start and at most, in packets, unless configured
+pub const INITIAL_WINDOW: usize = 2;
+pub const MAX_WINDOW: usize = 50;
 const MIN_CWND: f64 = 1.0;
-const MAX_CWND: f64 = 50.0;
+// Lowest slow start threshold after a loss, as in RFC 5681
+const MIN_SSTHRESH: f64 = 2.0;
+// Pacing faster than one window per round trip, as in Linux: in slow start the
+// window must be able to double, and later the ACKs must not wait for pacing
+const SLOW_START_PACING_GAIN: f64 = 2.0;
+const PACING_GAIN: f64 = 1.2;
+
+// CUBIC constants of RFC 9438
+const CUBIC_C: f64 = 0.4;
+const CUBIC_BETA: f64 = 0.7;
+
+// BBR gains of the original paper: 2/ln 2 grows the rate as fast as slow start,
+// and the probe bandwidth cycle probes for more for a round trip, then drains
+// the queue that built
+const STARTUP_GAIN: f64 = 2.885;
+const CWND_GAIN: f64 = 2.0;
+const PROBE_BW_GAINS: [f64; 8] = [1.25, 0.75, 1.0, 1.0, 1.0, 1.0, 1.0, 1.0];
+// Round trips the bandwidth estimate is the maximum of
+const BW_WINDOW_ROUNDS: usize = 10;
+const MIN_RTT_WINDOW: Duration = Duration::from_secs(10);
+const BBR_MIN_CWND: f64 = 4.0;
+
+/// The congestion control algorithms to choose from with `--cc`.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Algorithm {
+    /// Window halved on loss and grown by a packet per round trip
+    #[default]
+    Reno,
+    /// Window grown along a cubic curve around its size at the last loss
+    Cubic,
+    /// Window and pacing from the measured bandwidth and lowest RTT instead
+    /// of losses
+    Bbr,
+}
+
+impl Algorithm {
+    /// A controller starting with `initial_window` packets that never allows
+    /// more than `max_window`.
+    pub fn controller(
+        self,
+        initial_window: usize,
+        max_window: usize,
+    ) -> Box<dyn CongestionController> {
+        match self {
+            Algorithm::Reno => Box::new(Reno::new(initial_window, max_window)),
+            Algorithm::Cubic => Box::new(Cubic::new(initial_window, max_window)),
+            Algorithm::Bbr => Box::new(Bbr::new(initial_window, max_window)),
+        }
+    }
+}
+
+/// Controllers are `Send` so that a transfer can run in a spawned task.
+pub trait CongestionController: Send {
+    /// Called for each acknowledgement of new packets, `acked` of them, with
+    /// the RTT sample it gave, if any.
+    fn on_ack(&mut self, acked: usize, rtt: Option<Duration>);
+
+    /// Called when the retransmission timer expires.
+    fn on_timeout(&mut self);
 
-pub struct CongestionControl {
-    cwnd: f64
+    /// Called when duplicate acknowledgements report a loss.
+    fn on_fast_retransmit(&mut self);
+
+    /// Packets that may be in flight.
+    fn window(&self) -> usize;
+
+    /// The slow start threshold, for algorithms that have one.
+    fn ssthresh(&self) -> Option<usize>;
+
+    /// Packets per second to send new packets at, so that a window is spread
+    /// over the round trip instead of going out back to back.
+    fn pacing_rate(&self, srtt: Duration) -> f64;
+}
+
+fn window_pacing_rate(cwnd: f64, ssthresh: f64, srtt: Duration) -> f64 {
+    let gain = match cwnd < ssthresh {
+        true => SLOW_START_PACING_GAIN,
+        false => PACING_GAIN,
+    };
+    gain * cwnd / srtt.as_secs_f64()
+}
+
+pub struct Reno {
+    cwnd: f64,
+    ssthresh: f64,
+    max_cwnd: f64,
 }
 
-impl CongestionControl {
-    pub fn new() -> Self {
+impl Reno {
+    pub fn new(initial_window: usize, max_window: usize) -> Self {
         Self {
-            cwnd: INITIAL_CWND,
+            cwnd: initial_window as f64,
+            ssthresh: max_window as f64,
+            max_cwnd: max_window as f64,
         }
     }
+}
+
+impl Default for Reno {
+    fn default() -> Self {
+        Self::new(INITIAL_WINDOW, MAX_WINDOW)
+    }
+}
+
+impl CongestionController for Reno {
+    /// Below the slow start threshold the window grows by a packet per acknowledged
+    /// packet, doubling every round trip; above it by one packet per window, i.e.
+    /// per round trip (additive increase).
+    fn on_ack(&mut self, acked: usize, _rtt: Option<Duration>) {
+        for _ in 0..acked {
+            if self.cwnd < self.ssthresh {
+                self.cwnd += 1.0;
+            } else {
+                self.cwnd += 1.0 / self.cwnd;
+            }
+        }
+        self.cwnd = self.cwnd.min(self.max_cwnd);
+    }
+
+    /// On timeout the ACK clock is lost: remember half the window as the threshold
+    /// and start over with slow start
+    fn on_timeout(&mut self) {
+        self.ssthresh = (self.cwnd / 2.0).max(MIN_SSTHRESH);
+        self.cwnd = MIN_CWND;
+    }
+
+    /// Duplicate ACKs show that packets still get through, so the window is only
+    /// halved (multiplicative decrease)
+    fn on_fast_retransmit(&mut self) {
+        self.ssthresh = (self.cwnd / 2.0).max(MIN_SSTHRESH);
+        self.cwnd = self.ssthresh;
+    }
+
+    fn window(&self) -> usize {
+        self.cwnd.floor() as usize
+    }
+
+    fn ssthresh(&self) -> Option<usize> {
+        Some(self.ssthresh.floor() as usize)
+    }
+
+    fn pacing_rate(&self, srtt: Duration) -> f64 {
+        window_pacing_rate(self.cwnd, self.ssthresh, srtt)
+    }
+}
+
+/// CUBIC as in RFC 9438, in packets. After a loss the window grows quickly back
+/// towards its size at the loss, stays near it, and then probes beyond it ever
+/// faster. The growth follows the time since the loss instead of the ACKs, so
+/// long round trips are not at a disadvantage.
+pub struct Cubic {
+    cwnd: f64,
+    ssthresh: f64,
+    /// Window at the last loss
+    w_max: f64,
+    /// Start of the growth since the last loss, and the seconds it takes the
+    /// curve to get back to `w_max`
+    epoch: Option<(Instant, f64)>,
+    /// The window Reno would have, as CUBIC grows at least as fast
+    w_est: f64,
+    min_rtt: Option<Duration>,
+    max_cwnd: f64,
+}
+
+impl Cubic {
+    pub fn new(initial_window: usize, max_window: usize) -> Self {
+        Self {
+            cwnd: initial_window as f64,
+            ssthresh: max_window as f64,
+            w_max: 0.0,
+            epoch: None,
+            w_est: 0.0,
+            min_rtt: None,
+            max_cwnd: max_window as f64,
+        }
+    }
+
+    fn reduce(&mut self) {
+        // Fast convergence: a loss below the last maximum means that other flows
+        // need the room, so the next maximum is aimed lower
+        self.w_max = match self.cwnd < self.w_max {
+            true => self.cwnd * (1.0 + CUBIC_BETA) / 2.0,
+            false => self.cwnd,
+        };
+        self.ssthresh = (self.cwnd * CUBIC_BETA).max(MIN_SSTHRESH);
+        self.epoch = None;
+    }
+}
+
+impl Default for Cubic {
+    fn default() -> Self {
+        Self::new(INITIAL_WINDOW, MAX_WINDOW)
+    }
+}
+
+impl CongestionController for Cubic {
+    fn on_ack(&mut self, acked: usize, rtt: Option<Duration>) {
+        if let Some(rtt) = rtt {
+            self.min_rtt = Some(self.min_rtt.map_or(rtt, |min| min.min(rtt)));
+        }
+        let acked = acked as f64;
+        if self.cwnd < self.ssthresh {
+            self.cwnd = (self.cwnd + acked).min(self.max_cwnd);
+            return;
+        }
+
+        let now = Instant::now();
+        let (start, k) = match self.epoch {
+            Some(epoch) => epoch,
+            None => {
+                let k = ((self.w_max - self.cwnd).max(0.0) / CUBIC_C).cbrt();
+                self.epoch = Some((now, k));
+                self.w_est = self.cwnd;
+                (now, k)
+            }
+        };
+        // Aim at where the curve is a round trip from now, growing by at most
+        // half the window per round trip
+        let t = (now - start + self.min_rtt.unwrap_or_default()).as_secs_f64();
+        let target = CUBIC_C * (t - k).powi(3) + self.w_max;
+        let target = target.clamp(self.cwnd, 1.5 * self.cwnd);
+        self.cwnd += (target - self.cwnd) / self.cwnd * acked;
 
-    /// Called when a new ACK is received - we grow the window exponentially up to the maximum window size
-    pub fn on_ack(&mut self) {
-        self.cwnd += 1.0;
-        self.cwnd = self.cwnd.min(MAX_CWND);
+        self.w_est += 3.0 * (1.0 - CUBIC_BETA) / (1.0 + CUBIC_BETA) * acked / self.cwnd;
+        self.cwnd = self.cwnd.max(self.w_est).min(self.max_cwnd);
     }
 
-    /// On timeout we halve the window
-    pub fn on_timeout(&mut self) {
-        self.cwnd = (self.cwnd / 2.0).max(MIN_CWND);
+    fn on_timeout(&mut self) {
+        self.reduce();
+        self.cwnd = MIN_CWND;
     }
 
-    pub fn on_fast_retransmit(&mut self) {
-        self.cwnd = (self.cwnd / 2.0).max(MIN_CWND);
+    fn on_fast_retransmit(&mut self) {
+        self.reduce();
+        self.cwnd = self.ssthresh;
     }
 
-    pub fn window(&self) -> usize {
+    fn window(&self) -> usize {
         self.cwnd.floor() as usize
     }
+
+    fn ssthresh(&self) -> Option<usize> {
+        Some(self.ssthresh.floor() as usize)
+    }
+
+    fn pacing_rate(&self, srtt: Duration) -> f64 {
+        window_pacing_rate(self.cwnd, self.ssthresh, srtt)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Mode {
+    /// Doubling the rate every round trip until the bandwidth stops growing
+    Startup,
+    /// A round trip below the bandwidth, to empty the queue startup built
+    Drain,
+    /// Cycling through [`PROBE_BW_GAINS`], a phase per round trip
+    ProbeBw(usize),
 }
 
-impl Default for CongestionControl {
+/// A simplified BBR. The bottleneck bandwidth is the highest delivery rate of
+/// the last round trips and the propagation delay the lowest RTT; new packets
+/// are paced at the bandwidth, with a window of twice their product. Losses do
+/// not shrink the window, as on a lossy link they need not mean congestion, and
+/// a timeout only does until the next acknowledgement.
+pub struct Bbr {
+    mode: Mode,
+    cwnd: f64,
+    /// Delivery rates of the last round trips, in packets per second
+    bw_samples: VecDeque<f64>,
+    /// Lowest RTT, and when it was measured
+    min_rtt: Option<(Duration, Instant)>,
+    /// Packets acknowledged in total
+    delivered: u64,
+    /// Start of the current round trip, and the packets delivered by then
+    round_start: Option<(Instant, u64)>,
+    /// Highest bandwidth in startup, and the round trips since it last grew by
+    /// a quarter
+    full_bw: f64,
+    full_bw_rounds: u32,
+    max_cwnd: f64,
+}
+
+impl Bbr {
+    pub fn new(initial_window: usize, max_window: usize) -> Self {
+        Self {
+            mode: Mode::Startup,
+            cwnd: initial_window as f64,
+            bw_samples: VecDeque::new(),
+            min_rtt: None,
+            delivered: 0,
+            round_start: None,
+            full_bw: 0.0,
+            full_bw_rounds: 0,
+            max_cwnd: max_window as f64,
+        }
+    }
+
+    fn btl_bw(&self) -> Option<f64> {
+        self.bw_samples.iter().copied().reduce(f64::max)
+    }
+
+    /// Packets the path holds without a queue.
+    fn bdp(&self) -> Option<f64> {
+        Some(self.btl_bw()? * self.min_rtt?.0.as_secs_f64())
+    }
+
+    fn next_round(&mut self) {
+        let bw = self.btl_bw().unwrap_or_default();
+        self.mode = match self.mode {
+            Mode::Startup if bw >= self.full_bw * 1.25 => {
+                self.full_bw = bw;
+                self.full_bw_rounds = 0;
+                Mode::Startup
+            }
+            Mode::Startup => {
+                // Three round trips without growth: the pipe is full
+                self.full_bw_rounds += 1;
+                match self.full_bw_rounds >= 3 {
+                    true => Mode::Drain,
+                    false => Mode::Startup,
+                }
+            }
+            Mode::Drain => Mode::ProbeBw(0),
+            Mode::ProbeBw(phase) => Mode::ProbeBw((phase + 1) % PROBE_BW_GAINS.len()),
+        };
+    }
+}
+
+impl Default for Bbr {
     fn default() -> Self {
-        Self::new()
+        Self::new(INITIAL_WINDOW, MAX_WINDOW)
+    }
+}
+
+impl CongestionController for Bbr {
+    fn on_ack(&mut self, acked: usize, rtt: Option<Duration>) {
+        let now = Instant::now();
+        let before = self.delivered;
+        self.delivered += acked as u64;
+        if let Some(rtt) = rtt {
+            let replaces = |(min, at): (Duration, Instant)| rtt <= min || now - at > MIN_RTT_WINDOW;
+            if self.min_rtt.is_none_or(replaces) {
+                self.min_rtt = Some((rtt, now));
+            }
+        }
+
+        // A round trip ends when the lowest RTT has passed since it started
+        let (start, delivered) = *self.round_start.get_or_insert((now, before));
+        if let Some((min_rtt, _)) = self.min_rtt {
+            let elapsed = now - start;
+            if elapsed >= min_rtt && !elapsed.is_zero() {
+                let rate = (self.delivered - delivered) as f64 / elapsed.as_secs_f64();
+                self.bw_samples.push_back(rate);
+                if self.bw_samples.len() > BW_WINDOW_ROUNDS {
+                    self.bw_samples.pop_front();
+                }
+                self.round_start = Some((now, self.delivered));
+                self.next_round();
+            }
+        }
+
+        match (self.mode, self.bdp()) {
+            (Mode::Startup, _) | (_, None) => self.cwnd += acked as f64,
+            (_, Some(bdp)) => self.cwnd = (CWND_GAIN * bdp).max(BBR_MIN_CWND),
+        }
+        self.cwnd = self.cwnd.min(self.max_cwnd);
+    }
+
+    fn on_timeout(&mut self) {
+        self.cwnd = MIN_CWND;
+    }
+
+    fn on_fast_retransmit(&mut self) {}
+
+    fn window(&self) -> usize {
+        self.cwnd.floor() as usize
+    }
+
+    fn ssthresh(&self) -> Option<usize> {
+        None
+    }
+
+    fn pacing_rate(&self, srtt: Duration) -> f64 {
+        let gain = match self.mode {
+            Mode::Startup => STARTUP_GAIN,
+            Mode::Drain => 1.0 / STARTUP_GAIN,
+            Mode::ProbeBw(phase) => PROBE_BW_GAINS[phase],
+        };
+        match self.btl_bw() {
+            Some(bw) => gain * bw,
+            None => gain * self.cwnd / srtt.as_secs_f64(),
+        }
     }
 }
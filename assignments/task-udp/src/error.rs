@@ -0,0 +1,54 @@
+use std::{io, net::SocketAddr, path::PathBuf, time::Duration};
+
+use adnet_core::{
+    exit::{Code, HasCode},
+    AgentError,
+};
+use thiserror::Error;
+
+/// Errors of task-udp. Each kind has its own exit code, see
+/// [`adnet_core::exit`].
+#[derive(Debug, Error)]
+pub enum UdpError {
+    /// Invalid or missing options
+    #[error("{0}")]
+    Usage(String),
+    /// The config file could not be read
+    #[error(transparent)]
+    Config(io::Error),
+    #[error(transparent)]
+    Agent(#[from] AgentError),
+    /// The receiver could not listen on its ports
+    #[error("Cannot bind {address}: {source}")]
+    Bind {
+        address: SocketAddr,
+        source: io::Error,
+    },
+    /// The file to send could not be read
+    #[error("Cannot read {}: {source}", path.display())]
+    Input { path: PathBuf, source: io::Error },
+    /// The receiver could not write the data it received
+    #[error("Cannot write {}: {source}", path.display())]
+    Output { path: PathBuf, source: io::Error },
+    /// The transfer did not complete within the timeout
+    #[error("Timeout after {0:?}")]
+    Timeout(Duration),
+    #[error("Interrupted after sending {sent} of {size} bytes")]
+    Interrupted { sent: usize, size: usize },
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+impl HasCode for UdpError {
+    fn exit_code(&self) -> Code {
+        match self {
+            UdpError::Usage(_) | UdpError::Config(_) => Code::Usage,
+            UdpError::Agent(e) => e.exit_code(),
+            UdpError::Bind { .. } | UdpError::Output { .. } => Code::Failure,
+            UdpError::Input { .. } => Code::Usage,
+            UdpError::Timeout(_) => Code::Timeout,
+            UdpError::Interrupted { .. } => Code::Interrupted,
+            UdpError::Io(e) => e.exit_code(),
+        }
+    }
+}
@@ -0,0 +1,89 @@
+//! The UDP socket of the sender and the receiver, with the datagrams they send
+//! going through an emulated [`netem::Link`]. With `--loss`, `--delay`,
+//! `--jitter`, `--reorder` and `--duplicate`, the retransmission logic can be
+//! tested on a loopback address instead of an impaired network; the seed
+//! makes the choices repeatable.
+
+use std::{io, net::SocketAddr, sync::Mutex, time::Instant};
+
+use netem::{Impairment, Link, Stats, SystemClock};
+use tokio::net::UdpSocket;
+use tracing::info;
+
+pub(crate) struct ImpairedUdpSocket {
+    socket: UdpSocket,
+    // None when nothing is impaired. Never locked across an await
+    link: Option<Mutex<Link<SystemClock>>>,
+}
+
+impl ImpairedUdpSocket {
+    pub(crate) fn new(socket: UdpSocket, impairment: Impairment) -> Self {
+        let link = match impairment.is_none() {
+            true => None,
+            false => {
+                info!("Impairing sent datagrams: {:?}", impairment);
+                Some(Mutex::new(Link::new(impairment, SystemClock::new())))
+            }
+        };
+        Self { socket, link }
+    }
+
+    pub(crate) fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    pub(crate) async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        self.socket.recv_from(buf).await
+    }
+
+    /// Hands the datagram to the link, which sends it now, later or never.
+    pub(crate) async fn send_to(&self, buf: &[u8], to: SocketAddr) -> io::Result<()> {
+        let Some(link) = &self.link else {
+            self.socket.send_to(buf, to).await?;
+            return Ok(());
+        };
+        link.lock().unwrap().push(buf, to);
+        self.flush().await
+    }
+
+    /// Sends the delayed datagrams that are due, see
+    /// [`ImpairedUdpSocket::next_release`].
+    pub(crate) async fn flush(&self) -> io::Result<()> {
+        let Some(link) = &self.link else {
+            return Ok(());
+        };
+        loop {
+            let ready = link.lock().unwrap().pop_ready();
+            let Some((datagram, to)) = ready else {
+                return Ok(());
+            };
+            self.socket.send_to(&datagram, to).await?;
+        }
+    }
+
+    /// When the next delayed datagram is due, for calling
+    /// [`ImpairedUdpSocket::flush`].
+    pub(crate) fn next_release(&self) -> Option<Instant> {
+        let link = self.link.as_ref()?;
+        let delay = link.lock().unwrap().next_release()?;
+        Some(Instant::now() + delay)
+    }
+
+    /// Logs what the link has done, if anything is impaired.
+    pub(crate) fn log_stats(&self) {
+        let Some(link) = &self.link else {
+            return;
+        };
+        let Stats {
+            sent,
+            dropped,
+            duplicated,
+            reordered,
+            delivered,
+        } = link.lock().unwrap().stats();
+        info!(
+            "Impairment: {} datagrams, {} dropped, {} duplicated, {} reordered, {} delivered",
+            sent, dropped, duplicated, reordered, delivered
+        );
+    }
+}
@@ -0,0 +1,421 @@
+//! The task-udp sender as a library, so that the `adnet` multi-tool can run it
+//! as a subcommand. The task-udp binary is a thin wrapper around [`run`].
+//!
+//! With `--listen`, the program is the receiver instead, playing the part of
+//! adnet-agent for testing the sender offline.
+//!
+//! The protocol itself is in [`proto`], for other programs to send with.
+
+mod congestion;
+mod error;
+mod impaired;
+pub mod proto;
+mod receiver;
+mod transmission;
+
+use adnet_core::{
+    config::{self, ConfigArgs},
+    logging::{self, LogArgs},
+    metrics::{self, MetricsArgs},
+    report::{Report, ReportArgs},
+    shutdown::{self, Shutdown},
+    AgentClient,
+};
+use clap::Parser;
+use congestion::Algorithm;
+use netem::Impairment;
+use pktcap::record::{self, RecordArgs, Recorder};
+use proto::{Payload, ProtocolConfig, Sender};
+use serde::Deserialize;
+use std::{
+    future,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+use tokio::{io::AsyncReadExt, time};
+use tracing::{debug, info, warn};
+use transmission::GLOBAL_TIMEOUT;
+
+pub use error::UdpError;
+
+const TCP_PORT: u16 = 12345;
+const UDP_PORT: u16 = 20000;
+const DEFAULT_LISTEN_SIZE: usize = 100_000;
+const DEFAULT_CHARACTER: char = 'A';
+
+/// Sends the data requested by adnet-agent reliably over UDP.
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+pub struct Args {
+    /// Host name or address of adnet-agent, IPv4 or IPv6
+    #[arg(short, long)]
+    server: Option<String>,
+
+    /// UDP port of the agent to send the data to, or with --listen the port to
+    /// receive on [default: 20000]
+    #[arg(long, value_name = "PORT")]
+    udp_port: Option<u16>,
+
+    /// Keyword to send, or with --listen the keyword senders must give
+    /// [default: any]
+    #[arg(short, long)]
+    keyword: Option<String>,
+
+    /// Give up if the transfer has not completed in this many seconds [default: 180]
+    #[arg(long, value_parser = config::parse_secs)]
+    timeout: Option<Duration>,
+
+    /// Send the contents of this file instead of the character the agent asks
+    /// for, ending with an empty packet
+    #[arg(short, long, conflicts_with_all = ["stdin", "listen"])]
+    file: Option<PathBuf>,
+
+    /// Send what is read from standard input, as with --file
+    #[arg(long, conflicts_with = "listen")]
+    stdin: bool,
+
+    /// Send new packets at this many per second, instead of spreading the
+    /// congestion window over the round-trip time
+    #[arg(long, value_name = "PACKETS", conflicts_with = "listen")]
+    pacing_rate: Option<f64>,
+
+    /// Congestion control algorithm [default: reno]
+    #[arg(long, value_enum, conflicts_with = "listen")]
+    cc: Option<Algorithm>,
+
+    /// Be the receiver instead: answer TASK-UDP on TCP port 12345 and receive
+    /// the data on UDP port 20000 of this address
+    #[arg(short, long, conflicts_with = "server")]
+    listen: Option<IpAddr>,
+
+    /// Bytes the senders are asked for, with --listen [default: 100000]
+    #[arg(long, requires = "listen")]
+    size: Option<usize>,
+
+    /// Character the senders are asked for, with --listen [default: A]
+    #[arg(long, requires = "listen")]
+    character: Option<char>,
+
+    /// Once a gap is filled, release the packets buffered after it one per
+    /// arriving datagram, as the course's server does, with --listen
+    #[arg(long, requires = "listen")]
+    gap_fill_quirk: bool,
+
+    /// Report the packets that arrive after a gap in SACK blocks, with --listen
+    #[arg(long, requires = "listen")]
+    sack: bool,
+
+    /// Write the data of each transfer to this file, replacing the previous
+    /// one, with --listen. Senders must end the data with an empty packet, as
+    /// --file does
+    #[arg(short, long, requires = "listen")]
+    output: Option<PathBuf>,
+
+    /// Impairments applied to the datagrams sent: the data, or with --listen
+    /// the acknowledgements
+    #[command(flatten, next_help_heading = "Impairment")]
+    impairment: Impairment,
+
+    #[command(flatten, next_help_heading = "Tuning")]
+    tuning: TuningArgs,
+
+    #[command(flatten)]
+    config: ConfigArgs,
+
+    #[command(flatten)]
+    log: LogArgs,
+
+    #[command(flatten)]
+    metrics: MetricsArgs,
+
+    #[command(flatten)]
+    report: ReportArgs,
+
+    #[command(flatten)]
+    pcap: RecordArgs,
+}
+
+/// Parameters of the protocol, for experiments. The defaults are those of
+/// the template.
+#[derive(clap::Args, Debug)]
+struct TuningArgs {
+    /// Payload bytes per packet [default: 1200]
+    #[arg(long, value_name = "BYTES", conflicts_with = "listen")]
+    max_payload: Option<usize>,
+
+    /// Congestion window at the start, in packets [default: 2]
+    #[arg(long, value_name = "PACKETS", conflicts_with = "listen")]
+    initial_window: Option<usize>,
+
+    /// Largest congestion window, in packets [default: 50]
+    #[arg(long, value_name = "PACKETS", conflicts_with = "listen")]
+    max_window: Option<usize>,
+
+    /// Lower bound of the retransmission timeout in milliseconds [default: 200]
+    #[arg(long, value_name = "MS", conflicts_with = "listen")]
+    min_rto: Option<u64>,
+
+    /// Upper bound of the retransmission timeout in milliseconds [default: 10000]
+    #[arg(long, value_name = "MS", conflicts_with = "listen")]
+    max_rto: Option<u64>,
+
+    /// Duplicate acknowledgements that trigger a fast retransmit [default: 3]
+    #[arg(long, value_name = "ACKS", conflicts_with = "listen")]
+    dup_ack_threshold: Option<u32>,
+}
+
+/// The [task-udp] section of the config file
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct FileConfig {
+    server: Option<String>,
+    udp_port: Option<u16>,
+    keyword: Option<String>,
+    #[serde(deserialize_with = "config::secs")]
+    timeout: Option<Duration>,
+    file: Option<PathBuf>,
+    pacing_rate: Option<f64>,
+    cc: Option<Algorithm>,
+    max_payload: Option<usize>,
+    initial_window: Option<usize>,
+    max_window: Option<usize>,
+    min_rto: Option<u64>,
+    max_rto: Option<u64>,
+    dup_ack_threshold: Option<u32>,
+    listen: Option<IpAddr>,
+    size: Option<usize>,
+    character: Option<char>,
+    gap_fill_quirk: bool,
+    sack: bool,
+    output: Option<PathBuf>,
+}
+
+/// `host:port` of the agent's control port, with brackets around an IPv6
+/// address.
+fn agent_address(server: &str) -> String {
+    match server.contains(':') && !server.starts_with('[') {
+        true => format!("[{}]:{}", server, TCP_PORT),
+        false => format!("{}:{}", server, TCP_PORT),
+    }
+}
+
+/// Runs the sender with the given arguments, as the task-udp binary does.
+pub async fn run(args: Args) -> Result<(), UdpError> {
+    logging::init(&args.log)?;
+    metrics::init(&args.metrics)?;
+    let report_args = args.report.clone();
+    let mut report = Report::new("task-udp");
+    let result = send(args, &mut report).await;
+    report_args.finish(&mut report, result)
+}
+
+async fn send(args: Args, report: &mut Report) -> Result<(), UdpError> {
+    // Command line options take precedence over the config file
+    let file: FileConfig = args.config.section("task-udp").map_err(UdpError::Config)?;
+    if let Some(ip) = args.listen.or(file.listen) {
+        return listen(ip, args, file, report).await;
+    }
+    let usage = |message: &str| UdpError::Usage(message.to_string());
+    let server = args
+        .server
+        .or(file.server)
+        .ok_or_else(|| usage("Server is required (--server)"))?;
+    let keyword = args
+        .keyword
+        .or(file.keyword)
+        .ok_or_else(|| usage("Keyword is required (--keyword)"))?;
+    let defaults = ProtocolConfig::default();
+    let tuning = args.tuning;
+    let millis = |ms: Option<u64>, default| ms.map_or(default, Duration::from_millis);
+    let config = ProtocolConfig {
+        max_payload: tuning
+            .max_payload
+            .or(file.max_payload)
+            .unwrap_or(defaults.max_payload),
+        dup_ack_threshold: tuning
+            .dup_ack_threshold
+            .or(file.dup_ack_threshold)
+            .unwrap_or(defaults.dup_ack_threshold),
+        initial_window: tuning
+            .initial_window
+            .or(file.initial_window)
+            .unwrap_or(defaults.initial_window),
+        max_window: tuning
+            .max_window
+            .or(file.max_window)
+            .unwrap_or(defaults.max_window),
+        min_rto: millis(tuning.min_rto.or(file.min_rto), defaults.min_rto),
+        max_rto: millis(tuning.max_rto.or(file.max_rto), defaults.max_rto),
+        timeout: args.timeout.or(file.timeout).unwrap_or(defaults.timeout),
+        pacing_rate: args.pacing_rate.or(file.pacing_rate),
+        cc: args.cc.or(file.cc).unwrap_or(defaults.cc),
+    };
+    config.validate().map_err(UdpError::Usage)?;
+    let data = match (args.stdin, args.file.or(file.file)) {
+        (true, _) => {
+            let mut data = Vec::new();
+            tokio::io::stdin().read_to_end(&mut data).await?;
+            Some(data)
+        }
+        (false, Some(path)) => Some(
+            tokio::fs::read(&path)
+                .await
+                .map_err(|source| UdpError::Input { path, source })?,
+        ),
+        (false, None) => None,
+    };
+    let shutdown = shutdown::install()?;
+    let recorder = args.pcap.recorder()?;
+    report.keyword = Some(keyword.clone());
+
+    info!("Task-UDP starting");
+    info!("Connecting to server: {}", server);
+    info!("Using keyword: {}", keyword);
+    info!("Congestion control: {:?}", config.cc);
+    debug!("Protocol: {:?}", config);
+
+    let start = Instant::now();
+    let agent = AgentClient::new(agent_address(&server));
+    let (tcp_stream, task) = agent.request_udp_async(&keyword).await?;
+    let payload = match data {
+        Some(data) => {
+            info!(
+                "Starting to transmit {} bytes of data instead of {} bytes of '{}'.",
+                data.len(),
+                task.size,
+                task.character as char
+            );
+            Payload::Data(data)
+        }
+        None => {
+            info!(
+                "Starting to transmit {} bytes of '{}'.",
+                task.size, task.character as char
+            );
+            Payload::Repeated {
+                size: task.size,
+                character: task.character,
+            }
+        }
+    };
+    let size = payload.len();
+
+    // The data goes to the address the control connection reached, of either
+    // family and with the scope of a link-local IPv6 address
+    let mut udp_address = tcp_stream.peer_addr()?;
+    udp_address.set_port(args.udp_port.or(file.udp_port).unwrap_or(UDP_PORT));
+    info!("Sending the data to {}", udp_address);
+
+    // The totals are printed also when the transfer fails or is interrupted
+    let result = transmit_loop(
+        udp_address,
+        &payload,
+        config,
+        args.impairment,
+        &shutdown,
+        recorder.as_ref(),
+        report,
+    )
+    .await;
+    shutdown.finish();
+    if let Some(recorder) = recorder {
+        let path = recorder.path();
+        match recorder.finish() {
+            Ok(()) => info!(
+                "Recorded {} packets to {}",
+                recorder.packets(),
+                path.display()
+            ),
+            Err(e) => warn!("Recording to {} failed: {}", path.display(), e),
+        }
+    }
+    let checknum = result?;
+    let duration = start.elapsed();
+
+    info!(
+        "Size: {} -- Checknum: {} -- Duration: {:?}",
+        size, checknum, duration
+    );
+    if args.metrics.metrics_interval.is_some() {
+        metrics::global().log_dump();
+    }
+
+    Ok(())
+}
+
+/// Serves senders as the receiver until Ctrl-C.
+async fn listen(
+    ip: IpAddr,
+    args: Args,
+    file: FileConfig,
+    report: &mut Report,
+) -> Result<(), UdpError> {
+    let character = args
+        .character
+        .or(file.character)
+        .unwrap_or(DEFAULT_CHARACTER);
+    if !character.is_ascii() {
+        return Err(UdpError::Usage(format!(
+            "Character must be ASCII, not {}",
+            character
+        )));
+    }
+    let settings = receiver::Settings {
+        size: args.size.or(file.size).unwrap_or(DEFAULT_LISTEN_SIZE),
+        character: character as u8,
+        keyword: args.keyword.or(file.keyword),
+        gap_fill_quirk: args.gap_fill_quirk || file.gap_fill_quirk,
+        sack: args.sack || file.sack,
+        output: args.output.or(file.output),
+        udp_port: args.udp_port.or(file.udp_port).unwrap_or(UDP_PORT),
+        timeout: args.timeout.or(file.timeout).unwrap_or(GLOBAL_TIMEOUT),
+    };
+    let shutdown = shutdown::install()?;
+    let result = receiver::serve(ip, &settings, args.impairment, &shutdown, report).await;
+    shutdown.finish();
+    result
+}
+
+/// Transfers the data and fills in the report, also when the transfer fails.
+async fn transmit_loop(
+    server_addr: SocketAddr,
+    payload: &Payload,
+    config: ProtocolConfig,
+    impairment: Impairment,
+    shutdown: &Shutdown,
+    recorder: Option<&Recorder>,
+    report: &mut Report,
+) -> Result<u8, UdpError> {
+    let cc = config.cc;
+    let mut sender = Sender::bind(server_addr, impairment, config).await?;
+    if let Some(recorder) = recorder {
+        let local = record::source_address(sender.local_addr()?, server_addr);
+        sender.state.record(recorder.clone(), local);
+    }
+    let summary = sender.state.metrics.clone();
+    shutdown.on_exit(move || summary.log_summary());
+
+    let start = Instant::now();
+    let result = sender.send_all(payload, shutdown).await;
+    let state = &sender.state;
+    let transfer = state.summary(start.elapsed());
+    transfer.log();
+    sender.log_stats();
+    state.metrics.report(report);
+    report.detail("cc", cc);
+    report.detail("rtt", state.rtt.stats());
+    report.detail("transfer", &transfer);
+    let checknum = result?;
+    report.checknum = Some(checknum);
+    Ok(checknum)
+}
+
+/// Sleeps until `deadline`, or forever without one, for the timers of
+/// `select!`.
+pub(crate) async fn sleep_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => time::sleep_until(deadline.into()).await,
+        None => future::pending().await,
+    }
+}
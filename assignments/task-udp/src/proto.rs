@@ -0,0 +1,251 @@
+//! The reliable-UDP protocol of task-udp, for using it without the command
+//! line: a [`Sender`] transfers a [`Payload`] to one receiver and returns the
+//! receiver's checknum. The packet and acknowledgement format is the wire
+//! crate's; [`TransmissionState`] keeps the sender's side of a transfer.
+//!
+//! ```no_run
+//! # async fn example() -> Result<(), task_udp::UdpError> {
+//! use adnet_core::shutdown::Shutdown;
+//! use netem::Impairment;
+//! use task_udp::proto::{Payload, ProtocolConfig, Sender};
+//!
+//! let peer = "10.0.0.3:20000".parse().unwrap();
+//! let config = ProtocolConfig::default();
+//! let mut sender = Sender::bind(peer, Impairment::default(), config).await?;
+//! let payload = Payload::Data(b"hello".to_vec());
+//! let checknum = sender.send_all(&payload, &Shutdown::new()).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+use adnet_core::shutdown::Shutdown;
+use netem::Impairment;
+use tokio::{net::UdpSocket, time};
+use tracing::debug;
+
+use crate::{impaired::ImpairedUdpSocket, sleep_until, UdpError};
+
+pub use crate::congestion::{Algorithm, Bbr, CongestionController, Cubic, Reno};
+pub use crate::transmission::{Payload, ProtocolConfig, Summary, TransmissionState, MAX_PAYLOAD};
+pub use wire::{Ack, SackAck, SackBlock};
+
+/// The checknum of the data that the receiver sends with its acknowledgements.
+pub type Checknum = u8;
+
+/// A data packet: a sequence number and the payload, in the format of the
+/// wire crate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Packet<'a> {
+    pub seq: u32,
+    pub payload: &'a [u8],
+}
+
+impl<'a> Packet<'a> {
+    pub fn encode(&self) -> Result<Vec<u8>, wire::Error> {
+        let mut datagram = Vec::new();
+        wire::encode(self.seq, self.payload, &mut datagram)?;
+        Ok(datagram)
+    }
+
+    pub fn decode(datagram: &'a [u8]) -> Result<Self, wire::Error> {
+        let (header, payload) = wire::decode(datagram)?;
+        Ok(Self {
+            seq: header.seq,
+            payload,
+        })
+    }
+}
+
+/// Sends one payload reliably to a receiver, from a socket of its own.
+pub struct Sender {
+    socket: ImpairedUdpSocket,
+    peer: SocketAddr,
+    pub state: TransmissionState,
+}
+
+impl Sender {
+    /// Binds a socket of the family of `peer` for sending to it with a
+    /// [valid](ProtocolConfig::validate) configuration, with the datagrams
+    /// sent going through `impairment`.
+    pub async fn bind(
+        peer: SocketAddr,
+        impairment: Impairment,
+        config: ProtocolConfig,
+    ) -> io::Result<Self> {
+        let any: IpAddr = match peer {
+            SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+            SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+        };
+        let socket = UdpSocket::bind(SocketAddr::new(any, 0)).await?;
+        Ok(Self {
+            socket: ImpairedUdpSocket::new(socket, impairment),
+            peer,
+            state: TransmissionState::with_config(config),
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// Logs what the impairment has done to the datagrams sent, if anything.
+    pub fn log_stats(&self) {
+        self.socket.log_stats();
+    }
+
+    /// Sends until everything is acknowledged. The sender waits for
+    /// whichever comes first: an acknowledgement, the retransmission timer,
+    /// the pacing of new packets, a delayed datagram of the impairment, the
+    /// timeout of the configuration or the shutdown. New packets go out whenever the window has
+    /// room and the pacing allows after one of them.
+    ///
+    /// The sequence numbers start from 1, as the receiver expects of a new
+    /// transfer, so a sender sends only one payload.
+    pub async fn send_all(
+        &mut self,
+        payload: &Payload,
+        shutdown: &Shutdown,
+    ) -> Result<Checknum, UdpError> {
+        let Self {
+            socket,
+            peer,
+            state,
+        } = self;
+        let (peer, timeout) = (*peer, state.config.timeout);
+        let deadline = time::sleep(timeout);
+        tokio::pin!(deadline);
+        let mut ack_buf = [0u8; wire::MAX_ACK_SIZE];
+
+        while !state.is_complete(payload) {
+            state.send_new_packets(socket, peer, payload).await?;
+
+            // Without packets in flight there is nothing to time out
+            let retransmission = state.next_timeout();
+            let paced = state.next_send(payload);
+            let release = socket.next_release();
+            tokio::select! {
+                received = socket.recv_from(&mut ack_buf) => {
+                    let (n, from) = match received {
+                        Ok(received) => received,
+                        Err(e) => {
+                            debug!("Receiving an acknowledgement failed: {}", e);
+                            continue;
+                        }
+                    };
+                    state.received(from, &ack_buf[..n]);
+                    if from != peer {
+                        debug!("Ignoring a datagram from {}, not the receiver", from);
+                        continue;
+                    }
+                    match SackAck::decode(&ack_buf[..n]) {
+                        Ok(SackAck { ack, blocks }) => {
+                            if state.handle_ack(ack.seq, ack.checknum, &blocks) {
+                                state.retransmit_if_needed(socket, peer, true).await?;
+                            } else if !blocks.is_empty() {
+                                state.retransmit_holes(socket, peer).await?;
+                            }
+                        }
+                        Err(e) => debug!("Ignoring invalid acknowledgement: {}", e),
+                    }
+                }
+                _ = sleep_until(retransmission) => {
+                    state.retransmit_if_needed(socket, peer, false).await?;
+                }
+                // The packets go out at the top of the loop
+                _ = sleep_until(paced) => {}
+                _ = sleep_until(release) => socket.flush().await?,
+                _ = &mut deadline => return Err(UdpError::Timeout(timeout)),
+                _ = shutdown.requested() => {
+                    let sent = state.transmitted;
+                    let size = payload.len();
+                    return Err(UdpError::Interrupted { sent, size });
+                }
+            }
+        }
+
+        Ok(state.checknum)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn packet_round_trip() {
+        let packet = Packet {
+            seq: 70000,
+            payload: b"hello",
+        };
+        let datagram = packet.encode().unwrap();
+        assert_eq!(datagram.len(), wire::HEADER_SIZE + 5);
+        assert_eq!(Packet::decode(&datagram), Ok(packet));
+    }
+
+    #[test]
+    fn packets_of_the_sender_decode() {
+        let datagram = TransmissionState::create_packet(3, 4, b'z');
+        let packet = Packet::decode(&datagram).unwrap();
+        assert_eq!(packet.seq, 3);
+        assert_eq!(packet.payload, b"zzzz");
+    }
+
+    #[tokio::test]
+    async fn acknowledgements_from_others_are_ignored() {
+        let localhost = |port| SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port);
+        let receiver = UdpSocket::bind(localhost(0)).await.unwrap();
+        let other = UdpSocket::bind(localhost(0)).await.unwrap();
+        let peer = receiver.local_addr().unwrap();
+        let config = ProtocolConfig::default();
+        let mut sender = Sender::bind(peer, Impairment::default(), config)
+            .await
+            .unwrap();
+        let sender_addr = localhost(sender.local_addr().unwrap().port());
+        // Two packets: the data and the empty one that ends it
+        let payload = Payload::Data(b"hello".to_vec());
+        let transfer =
+            tokio::spawn(async move { sender.send_all(&payload, &Shutdown::new()).await });
+
+        let mut buf = [0u8; 2048];
+        let mut received = Vec::new();
+        while received.len() < 2 {
+            let (n, _) = receiver.recv_from(&mut buf).await.unwrap();
+            let seq = Packet::decode(&buf[..n]).unwrap().seq;
+            if !received.contains(&seq) {
+                received.push(seq);
+            }
+        }
+        let ack = |checknum| {
+            let ack = Ack { seq: 2, checknum };
+            SackAck {
+                ack,
+                blocks: Vec::new(),
+            }
+            .encode()
+            .unwrap()
+        };
+        // Acknowledges everything, but from the wrong address
+        other.send_to(&ack(0xee), sender_addr).await.unwrap();
+        time::sleep(time::Duration::from_millis(50)).await;
+        receiver.send_to(&ack(0x42), sender_addr).await.unwrap();
+
+        assert_eq!(transfer.await.unwrap().unwrap(), 0x42);
+    }
+
+    #[test]
+    fn invalid_packets() {
+        assert!(Packet::decode(&[0, 0, 0, 1, 0]).is_err());
+        assert!(Packet::decode(&[0, 0, 0, 1, 0, 2, b'a']).is_err());
+        let too_long = vec![0; wire::MAX_PAYLOAD + 1];
+        let packet = Packet {
+            seq: 1,
+            payload: &too_long,
+        };
+        assert!(packet.encode().is_err());
+    }
+}
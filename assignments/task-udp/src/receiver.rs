@@ -0,0 +1,438 @@
+//! Receiver side of the task-udp protocol, for testing the sender without the
+//! course's adnet-agent: it answers TASK-UDP on the control port as the agent
+//! does, puts the sequenced packets back in order and acknowledges each one
+//! with the highest sequence number received in order and a checknum, and
+//! optionally with SACK blocks of the packets buffered after a gap.
+//!
+//! A sender with --file ends the data with an empty packet. Its data can be
+//! written to a file as it was received.
+//!
+//! The checknum of the real agent is its own business; this one is the sum of
+//! the payload bytes received in order, as in the mock of the integration
+//! tests.
+
+use std::{
+    collections::BTreeMap,
+    io,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use adnet_core::{report::Report, shutdown::Shutdown};
+use serde::Serialize;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UdpSocket},
+    time,
+};
+use tracing::{debug, info, warn};
+use wire::{Ack, SackAck, SackBlock, MAX_SACK_BLOCKS};
+
+use netem::Impairment;
+
+use crate::{impaired::ImpairedUdpSocket, proto::Packet, sleep_until, UdpError, TCP_PORT};
+
+const CONTROL_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_CONTROL_MESSAGE: usize = 256;
+// After everything has arrived, keep acknowledging retransmissions until the
+// sender has been quiet this long, in case the last acknowledgements were lost.
+// Later ones are still acknowledged until the next sender connects
+const LINGER: Duration = Duration::from_millis(1500);
+
+/// What the senders are asked to send, and how the packets are acknowledged.
+pub(crate) struct Settings {
+    pub(crate) size: usize,
+    pub(crate) character: u8,
+    /// Keyword the senders must give, or any if `None`
+    pub(crate) keyword: Option<String>,
+    pub(crate) gap_fill_quirk: bool,
+    /// Report the packets buffered after a gap in SACK blocks
+    pub(crate) sack: bool,
+    /// Port to receive the data on
+    pub(crate) udp_port: u16,
+    /// Longest a transfer may take before it is given up
+    pub(crate) timeout: Duration,
+    /// Where to write the data. The transfers then end with the empty packet
+    /// rather than after `size` bytes.
+    pub(crate) output: Option<PathBuf>,
+}
+
+/// The packets of one transfer, put back in order.
+pub(crate) struct Reassembly {
+    next_seq: u32,
+    pending: BTreeMap<u32, Vec<u8>>,
+    received: usize,
+    checknum: u8,
+    // The data received in order, if kept
+    data: Option<Vec<u8>>,
+    ended: bool,
+    gap_fill_quirk: bool,
+    sack: bool,
+    packets: u64,
+    duplicates: u64,
+}
+
+impl Reassembly {
+    /// With `gap_fill_quirk`, the packets buffered after a gap are released
+    /// one per arriving datagram once the gap is filled, as the course's
+    /// server does, so the acknowledgements catch up only as the sender
+    /// keeps sending.
+    ///
+    /// With `sack`, the acknowledgements carry SACK blocks.
+    ///
+    /// With `keep`, the data received in order is kept for
+    /// [`Reassembly::data`].
+    pub(crate) fn new(gap_fill_quirk: bool, sack: bool, keep: bool) -> Self {
+        Self {
+            next_seq: 1,
+            pending: BTreeMap::new(),
+            received: 0,
+            checknum: 0,
+            data: keep.then(Vec::new),
+            ended: false,
+            gap_fill_quirk,
+            sack,
+            packets: 0,
+            duplicates: 0,
+        }
+    }
+
+    /// Takes a data packet and returns the acknowledgement to answer it with.
+    pub(crate) fn receive(&mut self, seq: u32, payload: &[u8]) -> SackAck {
+        self.packets += 1;
+        if seq < self.next_seq || self.pending.contains_key(&seq) {
+            self.duplicates += 1;
+        } else {
+            self.pending.insert(seq, payload.to_vec());
+        }
+
+        let limit = match self.gap_fill_quirk {
+            true => 1,
+            false => usize::MAX,
+        };
+        for _ in 0..limit {
+            let Some(payload) = self.pending.remove(&self.next_seq) else {
+                break;
+            };
+            self.checknum = payload
+                .iter()
+                .fold(self.checknum, |c, &b| c.wrapping_add(b));
+            self.received += payload.len();
+            self.ended |= payload.is_empty();
+            if let Some(data) = &mut self.data {
+                data.extend_from_slice(&payload);
+            }
+            self.next_seq += 1;
+        }
+        SackAck {
+            ack: Ack {
+                seq: self.next_seq - 1,
+                checknum: self.checknum,
+            },
+            blocks: match self.sack {
+                true => self.blocks(),
+                false => Vec::new(),
+            },
+        }
+    }
+
+    /// The runs of buffered packets, lowest first, as many as fit.
+    fn blocks(&self) -> Vec<SackBlock> {
+        let mut blocks: Vec<SackBlock> = Vec::new();
+        for &seq in self.pending.keys() {
+            let run = blocks
+                .last_mut()
+                .filter(|block| block.last.checked_add(1) == Some(seq));
+            if let Some(block) = run {
+                block.last = seq;
+            } else if blocks.len() == MAX_SACK_BLOCKS {
+                break;
+            } else {
+                blocks.push(SackBlock {
+                    first: seq,
+                    last: seq,
+                });
+            }
+        }
+        blocks
+    }
+
+    /// Payload bytes received in order.
+    pub(crate) fn received(&self) -> usize {
+        self.received
+    }
+
+    /// Whether the empty packet that ends a file has been received in order.
+    pub(crate) fn ended(&self) -> bool {
+        self.ended
+    }
+
+    /// The data received in order, if kept.
+    pub(crate) fn data(&self) -> Option<&[u8]> {
+        self.data.as_deref()
+    }
+}
+
+/// One transfer, for the report.
+#[derive(Serialize)]
+struct Transfer {
+    sender: SocketAddr,
+    keyword: String,
+    bytes: usize,
+    checknum: u8,
+    /// Data packets received, including retransmissions and duplicates
+    packets: u64,
+    duplicates: u64,
+    /// Packets with other bytes than the character asked for
+    corrupt: u64,
+    /// Until the last byte arrived, or until the transfer was given up
+    seconds: f64,
+    complete: bool,
+}
+
+/// Serves senders one at a time until Ctrl-C: the control connection on TCP
+/// port 12345 and the data on the UDP port of the settings, both on `ip`,
+/// which can be IPv4 or IPv6.
+pub(crate) async fn serve(
+    ip: IpAddr,
+    settings: &Settings,
+    impairment: Impairment,
+    shutdown: &Shutdown,
+    report: &mut Report,
+) -> Result<(), UdpError> {
+    let control_addr = SocketAddr::new(ip, TCP_PORT);
+    let listener = TcpListener::bind(control_addr)
+        .await
+        .map_err(|source| UdpError::Bind {
+            address: control_addr,
+            source,
+        })?;
+    let data_addr = SocketAddr::new(ip, settings.udp_port);
+    let socket = UdpSocket::bind(data_addr)
+        .await
+        .map_err(|source| UdpError::Bind {
+            address: data_addr,
+            source,
+        })?;
+    let socket = ImpairedUdpSocket::new(socket, impairment);
+    info!(
+        "Receiving {} bytes of '{}' on {}, control connections on {}",
+        settings.size, settings.character as char, data_addr, control_addr
+    );
+
+    let mut transfers = Vec::new();
+    let result = accept_loop(&listener, &socket, settings, shutdown, &mut transfers).await;
+    socket.log_stats();
+    report.bytes = transfers.iter().map(|t| t.bytes as u64).sum();
+    report.checknum = transfers.last().map(|t| t.checknum);
+    report.detail("transfers", &transfers);
+    result
+}
+
+async fn accept_loop(
+    listener: &TcpListener,
+    socket: &ImpairedUdpSocket,
+    settings: &Settings,
+    shutdown: &Shutdown,
+    transfers: &mut Vec<Transfer>,
+) -> Result<(), UdpError> {
+    // The previous sender, whose retransmissions are still acknowledged
+    let mut finished: Option<(IpAddr, Reassembly)> = None;
+    let mut buf = [0u8; 65536];
+    loop {
+        let (mut control, peer) = tokio::select! {
+            accepted = listener.accept() => accepted?,
+            received = socket.recv_from(&mut buf) => {
+                let (n, from) = received?;
+                acknowledge_late(socket, finished.as_mut(), &buf[..n], from).await?;
+                continue;
+            }
+            _ = sleep_until(socket.next_release()) => {
+                socket.flush().await?;
+                continue;
+            }
+            _ = shutdown.requested() => return Ok(()),
+        };
+        let answer = time::timeout(CONTROL_TIMEOUT, answer_control(&mut control, settings));
+        let keyword = match answer.await {
+            Ok(Ok(keyword)) => keyword,
+            Ok(Err(e)) => {
+                warn!("Closing control connection from {}: {}", peer, e);
+                continue;
+            }
+            Err(_) => {
+                warn!(
+                    "Closing control connection from {}: no request in {:?}",
+                    peer, CONTROL_TIMEOUT
+                );
+                continue;
+            }
+        };
+        info!("{} sends with keyword {}", peer, keyword);
+        let (transfer, reassembly) = receive(socket, peer, keyword, settings, shutdown).await?;
+        finished = Some((peer.ip(), reassembly));
+        if transfer.complete {
+            info!(
+                "Size: {} -- Checknum: {} -- Duration: {:?}",
+                transfer.bytes,
+                transfer.checknum,
+                Duration::from_secs_f64(transfer.seconds)
+            );
+        } else if settings.output.is_some() {
+            warn!(
+                "Transfer from {} ended after {} bytes without the end marker",
+                peer, transfer.bytes
+            );
+        } else {
+            warn!(
+                "Transfer from {} ended with {} of {} bytes",
+                peer, transfer.bytes, settings.size
+            );
+        }
+        transfers.push(transfer);
+    }
+}
+
+/// Answers a retransmission that arrived after the transfer ended, if it is
+/// from the previous sender, as its last acknowledgements may have been lost.
+async fn acknowledge_late(
+    socket: &ImpairedUdpSocket,
+    finished: Option<&mut (IpAddr, Reassembly)>,
+    datagram: &[u8],
+    from: SocketAddr,
+) -> io::Result<()> {
+    let Some((_, reassembly)) = finished.filter(|(ip, _)| *ip == from.ip()) else {
+        debug!("Ignoring datagram from {}", from);
+        return Ok(());
+    };
+    let Ok(packet) = Packet::decode(datagram) else {
+        return Ok(());
+    };
+    let ack = reassembly.receive(packet.seq, packet.payload);
+    let ack = ack.encode().expect("at most MAX_SACK_BLOCKS blocks");
+    socket.send_to(&ack, from).await
+}
+
+/// Reads the TASK-UDP message and answers with the size and character.
+/// Returns the keyword.
+async fn answer_control(control: &mut TcpStream, settings: &Settings) -> io::Result<String> {
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; MAX_CONTROL_MESSAGE];
+    while !buf.contains(&b'\n') && buf.len() < MAX_CONTROL_MESSAGE {
+        match control.read(&mut chunk).await? {
+            0 => break,
+            n => buf.extend_from_slice(&chunk[..n]),
+        }
+    }
+    let message = String::from_utf8_lossy(&buf);
+    let invalid = || {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Unexpected message {:?}", message.trim_end()),
+        )
+    };
+    let keyword = match message.split_whitespace().collect::<Vec<_>>()[..] {
+        ["TASK-UDP", keyword] => keyword.to_string(),
+        _ => return Err(invalid()),
+    };
+    if settings.keyword.as_ref().is_some_and(|k| *k != keyword) {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("Wrong keyword {}", keyword),
+        ));
+    }
+    let answer = format!("{} {}\n", settings.size, settings.character as char);
+    control.write_all(answer.as_bytes()).await?;
+    Ok(keyword)
+}
+
+/// Receives one transfer from the host of `peer` and acknowledges its packets.
+/// Returns also the reassembly, for acknowledging late retransmissions.
+async fn receive(
+    socket: &ImpairedUdpSocket,
+    peer: SocketAddr,
+    keyword: String,
+    settings: &Settings,
+    shutdown: &Shutdown,
+) -> Result<(Transfer, Reassembly), UdpError> {
+    let start = Instant::now();
+    let mut reassembly = Reassembly::new(
+        settings.gap_fill_quirk,
+        settings.sack,
+        settings.output.is_some(),
+    );
+    let mut completed = (settings.size == 0 && settings.output.is_none()).then_some(start);
+    let mut last_packet = start;
+    let mut corrupt = 0;
+    let mut buf = [0u8; 65536];
+
+    loop {
+        let until = match completed {
+            Some(_) => last_packet + LINGER,
+            None => start + settings.timeout,
+        };
+        let (n, from) = tokio::select! {
+            received = socket.recv_from(&mut buf) => match received {
+                Ok(received) => received,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            },
+            _ = sleep_until(socket.next_release()) => {
+                socket.flush().await?;
+                continue;
+            }
+            _ = sleep_until(Some(until)) => break,
+            _ = shutdown.requested() => break,
+        };
+        // Leftovers of an earlier transfer may still arrive from elsewhere
+        if from.ip() != peer.ip() {
+            debug!("Ignoring datagram from {}", from);
+            continue;
+        }
+        let Packet { seq, payload } = match Packet::decode(&buf[..n]) {
+            Ok(packet) => packet,
+            Err(e) => {
+                warn!("Ignoring invalid packet from {}: {}", from, e);
+                continue;
+            }
+        };
+        last_packet = Instant::now();
+        if settings.output.is_none() && payload.iter().any(|&b| b != settings.character) {
+            corrupt += 1;
+        }
+        let ack = reassembly.receive(seq, payload);
+        let ack = ack.encode().expect("at most MAX_SACK_BLOCKS blocks");
+        socket.send_to(&ack, from).await?;
+        let done = match settings.output {
+            Some(_) => reassembly.ended(),
+            None => reassembly.received() >= settings.size,
+        };
+        if completed.is_none() && done {
+            completed = Some(last_packet);
+        }
+    }
+
+    if let (Some(path), Some(data)) = (&settings.output, reassembly.data()) {
+        tokio::fs::write(path, data)
+            .await
+            .map_err(|source| UdpError::Output {
+                path: path.clone(),
+                source,
+            })?;
+        info!("Wrote {} bytes to {}", data.len(), path.display());
+    }
+
+    let transfer = Transfer {
+        sender: peer,
+        keyword,
+        bytes: reassembly.received(),
+        checknum: reassembly.checknum,
+        packets: reassembly.packets,
+        duplicates: reassembly.duplicates,
+        corrupt,
+        seconds: (completed.unwrap_or_else(Instant::now) - start).as_secs_f64(),
+        complete: completed.is_some(),
+    };
+    Ok((transfer, reassembly))
+}
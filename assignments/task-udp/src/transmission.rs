@@ -0,0 +1,929 @@
+//! Sender side of the task-udp protocol: packets carry a 4-byte sequence
+//! number and a 2-byte payload length, and the receiver answers with a 5-byte
+//! cumulative acknowledgement and checknum. The format is in the wire crate.
+//!
+//! Instead of the character the agent asks for, the packets can carry the
+//! contents of a file. The receiver does not know its size in advance, so an
+//! empty packet after the data marks the end.
+//!
+//! Receivers that support it also report the packets that have arrived after
+//! a gap as SACK blocks. Those packets are not resent, except by the
+//! retransmission timer, and the holes below them are resent without waiting
+//! for the timer.
+
+use std::{
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use adnet_core::{
+    metrics::{self, Counter, Gauge, Histogram},
+    report::Report,
+    rtt::{RttEstimator, MAX_RTO, MIN_RTO},
+};
+use pktcap::record::Recorder;
+use serde::Serialize;
+use tracing::info;
+use wire::SackBlock;
+
+use crate::{
+    congestion::{Algorithm, CongestionController, INITIAL_WINDOW, MAX_WINDOW},
+    impaired::ImpairedUdpSocket,
+};
+
+/// Payload bytes per packet, unless configured
+pub const MAX_PAYLOAD: usize = 1200;
+// The largest payload a UDP datagram over IPv4 can carry after the header
+const MAX_DATAGRAM_PAYLOAD: usize = 65507 - wire::HEADER_SIZE;
+const DUP_ACK_THRESHOLD: u32 = 3;
+pub(crate) const GLOBAL_TIMEOUT: Duration = Duration::from_secs(180);
+// How late the pacing timer may fire before the packets it should have sent
+// are no longer made up for, e.g. with intervals below the timer resolution
+const PACING_SLACK: Duration = Duration::from_millis(1);
+const RTT_BUCKETS: [f64; 10] = [0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5];
+
+/// The sender's metrics in the global registry.
+#[derive(Clone)]
+pub(crate) struct Metrics {
+    packets_sent: Counter,
+    bytes_sent: Counter,
+    retransmits: Counter,
+    fast_retransmits: Counter,
+    sack_retransmits: Counter,
+    acks: Counter,
+    dup_acks: Counter,
+    cwnd: Gauge,
+    ssthresh: Gauge,
+    in_flight: Gauge,
+    pacing_rate: Gauge,
+    rtt: Histogram,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        Self {
+            packets_sent: metrics::counter("udp_packets_sent_total", "New data packets sent"),
+            bytes_sent: metrics::counter(
+                "udp_bytes_sent_total",
+                "Payload bytes sent in new packets",
+            ),
+            retransmits: metrics::counter(
+                "udp_retransmits_total",
+                "Packets retransmitted after a timeout",
+            ),
+            fast_retransmits: metrics::counter(
+                "udp_fast_retransmits_total",
+                "Packets retransmitted after duplicate acknowledgements",
+            ),
+            sack_retransmits: metrics::counter(
+                "udp_sack_retransmits_total",
+                "Packets retransmitted below the highest SACKed packet",
+            ),
+            acks: metrics::counter("udp_acks_total", "Acknowledgements received"),
+            dup_acks: metrics::counter("udp_dup_acks_total", "Duplicate acknowledgements received"),
+            cwnd: metrics::gauge("udp_cwnd_packets", "Congestion window"),
+            ssthresh: metrics::gauge("udp_ssthresh_packets", "Slow start threshold"),
+            in_flight: metrics::gauge("udp_in_flight_packets", "Packets sent but not acknowledged"),
+            pacing_rate: metrics::gauge(
+                "udp_pacing_rate_packets",
+                "Packets per second new packets are paced at",
+            ),
+            rtt: metrics::histogram("udp_rtt_seconds", "Round-trip time samples", &RTT_BUCKETS),
+        }
+    }
+
+    /// Logs the totals, for the end of the program.
+    pub(crate) fn log_summary(&self) {
+        info!(
+            "Sent {} packets ({} bytes), {} retransmits, {} fast retransmits, {} SACK retransmits, {} acks ({} duplicate)",
+            self.packets_sent.get(),
+            self.bytes_sent.get(),
+            self.retransmits.get(),
+            self.fast_retransmits.get(),
+            self.sack_retransmits.get(),
+            self.acks.get(),
+            self.dup_acks.get()
+        );
+    }
+
+    /// Fills in the totals of the report.
+    pub(crate) fn report(&self, report: &mut Report) {
+        report.bytes = self.bytes_sent.get();
+        report.retransmits =
+            self.retransmits.get() + self.fast_retransmits.get() + self.sack_retransmits.get();
+        report.detail("packets_sent", self.packets_sent.get());
+        report.detail("fast_retransmits", self.fast_retransmits.get());
+        report.detail("sack_retransmits", self.sack_retransmits.get());
+        report.detail("acks", self.acks.get());
+        report.detail("dup_acks", self.dup_acks.get());
+    }
+}
+
+/// The parameters of the protocol, for experimenting with them without
+/// recompiling. The defaults are those of the course.
+#[derive(Clone, Debug)]
+pub struct ProtocolConfig {
+    /// Payload bytes per packet
+    pub max_payload: usize,
+    /// Duplicate acknowledgements that trigger a fast retransmit
+    pub dup_ack_threshold: u32,
+    /// Congestion window at the start, in packets
+    pub initial_window: usize,
+    /// Largest congestion window, in packets
+    pub max_window: usize,
+    pub min_rto: Duration,
+    pub max_rto: Duration,
+    /// Give up if the transfer has not completed in this time
+    pub timeout: Duration,
+    /// Packets per second to send new packets at, instead of the rate of the
+    /// congestion control
+    pub pacing_rate: Option<f64>,
+    pub cc: Algorithm,
+}
+
+impl ProtocolConfig {
+    /// Checks that the values make sense together, with a message for the
+    /// user if not.
+    pub fn validate(&self) -> Result<(), String> {
+        if !(1..=MAX_DATAGRAM_PAYLOAD).contains(&self.max_payload) {
+            return Err(format!(
+                "Payload size must be from 1 to {} bytes, not {}",
+                MAX_DATAGRAM_PAYLOAD, self.max_payload
+            ));
+        }
+        if self.dup_ack_threshold == 0 {
+            return Err("Duplicate acknowledgement threshold must be at least 1".to_string());
+        }
+        if self.initial_window == 0 || self.initial_window > self.max_window {
+            return Err(format!(
+                "Initial window must be from 1 to the maximum window of {} packets, not {}",
+                self.max_window, self.initial_window
+            ));
+        }
+        if self.min_rto.is_zero() || self.min_rto > self.max_rto {
+            return Err(format!(
+                "Minimum RTO must be above zero and at most the maximum RTO of {:?}, not {:?}",
+                self.max_rto, self.min_rto
+            ));
+        }
+        if let Some(rate) = self
+            .pacing_rate
+            .filter(|rate| !(*rate > 0.0 && rate.is_finite()))
+        {
+            return Err(format!("Invalid pacing rate {}", rate));
+        }
+        Ok(())
+    }
+}
+
+impl Default for ProtocolConfig {
+    fn default() -> Self {
+        Self {
+            max_payload: MAX_PAYLOAD,
+            dup_ack_threshold: DUP_ACK_THRESHOLD,
+            initial_window: INITIAL_WINDOW,
+            max_window: MAX_WINDOW,
+            min_rto: MIN_RTO,
+            max_rto: MAX_RTO,
+            timeout: GLOBAL_TIMEOUT,
+            pacing_rate: None,
+            cc: Algorithm::default(),
+        }
+    }
+}
+
+/// What the packets carry.
+pub enum Payload {
+    /// `size` bytes of the character the agent asked for
+    Repeated { size: usize, character: u8 },
+    /// The contents of a file, followed by the empty end marker
+    Data(Vec<u8>),
+}
+
+impl Payload {
+    /// Bytes to send, without the end marker.
+    pub fn len(&self) -> usize {
+        match self {
+            Payload::Repeated { size, .. } => *size,
+            Payload::Data(data) => data.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Packets the payload takes with `max_payload` bytes in each, including
+    /// the end marker.
+    pub fn packets(&self, max_payload: usize) -> u32 {
+        let full = self.len().div_ceil(max_payload) as u32;
+        match self {
+            Payload::Repeated { .. } => full,
+            Payload::Data(_) => full + 1,
+        }
+    }
+
+    /// The packet `seq`, carrying the bytes from `offset` on.
+    fn packet(&self, seq: u32, offset: usize, max_payload: usize) -> Vec<u8> {
+        let payload_size = (self.len() - offset).min(max_payload);
+        match self {
+            Payload::Repeated { character, .. } => {
+                TransmissionState::create_packet(seq, payload_size, *character)
+            }
+            Payload::Data(data) => {
+                let mut packet = Vec::new();
+                wire::encode(seq, &data[offset..offset + payload_size], &mut packet)
+                    .expect("validated payload size");
+                packet
+            }
+        }
+    }
+}
+
+/// Figures of one transfer that the counters of [`Metrics`] do not tell, for
+/// the summary at the end and the report.
+#[derive(Serialize)]
+pub struct Summary {
+    /// Payload bytes acknowledged
+    pub bytes: usize,
+    pub seconds: f64,
+    /// Acknowledged payload in bits per second
+    pub goodput_bps: f64,
+    pub rtt_min_ms: Option<f64>,
+    pub rtt_avg_ms: Option<f64>,
+    pub rtt_max_ms: Option<f64>,
+    /// Losses detected, by the timer or by the acknowledgements
+    pub recoveries: u64,
+    /// Time from detecting a loss until everything sent before it was
+    /// acknowledged, in total
+    pub recovery_seconds: f64,
+    pub cwnd: usize,
+    pub ssthresh: Option<usize>,
+}
+
+impl Summary {
+    pub fn log(&self) {
+        let ms = |value: Option<f64>| match value {
+            Some(value) => format!("{:.1}", value),
+            None => "-".to_string(),
+        };
+        let ssthresh = match self.ssthresh {
+            Some(ssthresh) => ssthresh.to_string(),
+            None => "-".to_string(),
+        };
+        info!(
+            "Goodput {:.1} kbit/s, RTT min/avg/max {}/{}/{} ms, {} recoveries in {:.3} s, final cwnd {} (ssthresh {})",
+            self.goodput_bps / 1000.0,
+            ms(self.rtt_min_ms),
+            ms(self.rtt_avg_ms),
+            ms(self.rtt_max_ms),
+            self.recoveries,
+            self.recovery_seconds,
+            self.cwnd,
+            ssthresh
+        );
+    }
+}
+
+pub(crate) struct PacketInfo {
+    pub(crate) packet: Vec<u8>,
+    pub(crate) sent_time: Instant,
+    pub(crate) retry_count: u32,
+    /// Reported received in a SACK block
+    pub(crate) sacked: bool,
+}
+
+/// The sender's side of a transfer: the packets in flight, the RTT estimate
+/// and the congestion control.
+pub struct TransmissionState {
+    pub(crate) transmitted: usize,
+    pub(crate) next_seq: u32,
+    pub(crate) checknum: u8,
+    pub(crate) unacked_packets: HashMap<u32, PacketInfo>,
+    pub(crate) last_acked_seq: u32,
+    pub(crate) dup_ack_count: u32,
+    /// The packet last resent after duplicate acknowledgements. Further
+    /// duplicates of the same acknowledgement leave it to the timer, so that
+    /// one loss halves the window only once.
+    pub(crate) fast_retransmitted: Option<u32>,
+    /// Highest sequence number reported in a SACK block
+    pub(crate) highest_sacked: u32,
+    // Unacknowledged packets that have been SACKed
+    sacked_count: usize,
+    acked_bytes: usize,
+    // Smallest and largest RTT sample and the sum of all, in milliseconds
+    rtt_range: Option<(f64, f64)>,
+    rtt_total_ms: f64,
+    /// The loss recovery in progress: the highest sequence number sent when
+    /// the loss was detected, and when that was
+    recovery: Option<(u32, Instant)>,
+    recoveries: u64,
+    recovery_time: Duration,
+    pub(crate) config: ProtocolConfig,
+    // When the next new packet may go out
+    next_send: Instant,
+    pub(crate) rtt: RttEstimator,
+    pub(crate) cc: Box<dyn CongestionController>,
+    pub(crate) metrics: Metrics,
+    // Where to record the datagrams, with the address they are sent from
+    recording: Option<(Recorder, SocketAddr)>,
+}
+
+impl TransmissionState {
+    pub fn new() -> Self {
+        Self::with_config(ProtocolConfig::default())
+    }
+
+    /// The state of a transfer with a [valid](ProtocolConfig::validate)
+    /// configuration.
+    pub fn with_config(config: ProtocolConfig) -> Self {
+        Self {
+            transmitted: 0,
+            next_seq: 1,
+            checknum: 0,
+            unacked_packets: HashMap::new(),
+            last_acked_seq: 0,
+            dup_ack_count: 0,
+            fast_retransmitted: None,
+            highest_sacked: 0,
+            sacked_count: 0,
+            acked_bytes: 0,
+            rtt_range: None,
+            rtt_total_ms: 0.0,
+            recovery: None,
+            recoveries: 0,
+            recovery_time: Duration::ZERO,
+            next_send: Instant::now(),
+            rtt: RttEstimator::new().with_rto_bounds(config.min_rto, config.max_rto),
+            cc: config
+                .cc
+                .controller(config.initial_window, config.max_window),
+            config,
+            metrics: Metrics::new(),
+            recording: None,
+        }
+    }
+
+    /// Records the datagrams sent from `local` and received there from now on.
+    pub(crate) fn record(&mut self, recorder: Recorder, local: SocketAddr) {
+        self.recording = Some((recorder, local));
+    }
+
+    /// Records a datagram received, e.g. an acknowledgement.
+    pub(crate) fn received(&self, from: SocketAddr, datagram: &[u8]) {
+        if let Some((recorder, local)) = &self.recording {
+            recorder.udp(from, *local, datagram);
+        }
+    }
+
+    /// Sends a datagram and records it. Takes the recording rather than `self`,
+    /// so that the packet to send can be borrowed from the state.
+    async fn send(
+        recording: &Option<(Recorder, SocketAddr)>,
+        socket: &ImpairedUdpSocket,
+        packet: &[u8],
+        to: SocketAddr,
+    ) -> io::Result<()> {
+        socket.send_to(packet, to).await?;
+        if let Some((recorder, local)) = recording {
+            recorder.udp(*local, to, packet);
+        }
+        Ok(())
+    }
+
+    /// Packet `seq` with `payload_size` copies of `character`.
+    pub fn create_packet(seq: u32, payload_size: usize, character: u8) -> Vec<u8> {
+        let header = wire::Header {
+            seq,
+            len: payload_size as u16,
+        };
+        let mut packet = Vec::with_capacity(wire::HEADER_SIZE + payload_size);
+        packet.extend_from_slice(&header.encode());
+        packet.resize(wire::HEADER_SIZE + payload_size, character);
+        packet
+    }
+
+    pub(crate) async fn send_new_packets(
+        &mut self,
+        socket: &ImpairedUdpSocket,
+        server_addr: SocketAddr,
+        payload: &Payload,
+    ) -> io::Result<()> {
+        while self.can_send(payload) && self.next_send <= Instant::now() {
+            let max_payload = self.config.max_payload;
+            let packet = payload.packet(self.next_seq, self.transmitted, max_payload);
+            let payload_size = packet.len() - wire::HEADER_SIZE;
+            Self::send(&self.recording, socket, &packet, server_addr).await?;
+
+            self.unacked_packets.insert(
+                self.next_seq,
+                PacketInfo {
+                    packet,
+                    sent_time: Instant::now(),
+                    retry_count: 0,
+                    sacked: false,
+                },
+            );
+            self.transmitted += payload_size;
+            self.next_seq += 1;
+            self.metrics.packets_sent.inc();
+            self.metrics.bytes_sent.add(payload_size as u64);
+            if let Some(interval) = self.pacing_interval() {
+                let now = Instant::now();
+                let earliest = now.checked_sub(PACING_SLACK).unwrap_or(now);
+                self.next_send = self.next_send.max(earliest) + interval;
+            }
+        }
+        self.update_gauges();
+        Ok(())
+    }
+
+    /// Whether a new packet is waiting and the window has room for it.
+    fn can_send(&self, payload: &Payload) -> bool {
+        self.next_seq <= payload.packets(self.config.max_payload)
+            && self.in_flight() < self.cc.window()
+    }
+
+    /// Time between new packets. Until there is an RTT sample to spread the
+    /// window over, the first window goes out at once.
+    fn pacing_interval(&self) -> Option<Duration> {
+        let rate = match self.config.pacing_rate {
+            Some(rate) => rate,
+            None => {
+                let srtt = Duration::from_secs_f64(self.rtt.srtt_ms()? / 1000.0);
+                self.cc.pacing_rate(srtt)
+            }
+        };
+        Duration::try_from_secs_f64(1.0 / rate).ok()
+    }
+
+    /// When [`TransmissionState::send_new_packets`] can next send a packet
+    /// that is only waiting for the pacing, if any.
+    pub(crate) fn next_send(&self, payload: &Payload) -> Option<Instant> {
+        (self.can_send(payload) && self.next_send > Instant::now()).then_some(self.next_send)
+    }
+
+    /// Packets sent and neither acknowledged nor SACKed, which count against
+    /// the congestion window.
+    pub fn in_flight(&self) -> usize {
+        self.unacked_packets.len() - self.sacked_count
+    }
+
+    /// Marks the packets in the SACK blocks as received.
+    fn handle_sack(&mut self, blocks: &[SackBlock]) {
+        for block in blocks {
+            // Only packets that are still waiting for an acknowledgement
+            let first = block.first.max(self.last_acked_seq + 1);
+            let last = block.last.min(self.next_seq.saturating_sub(1));
+            for seq in first..=last {
+                if let Some(info) = self.unacked_packets.get_mut(&seq) {
+                    if !info.sacked {
+                        info.sacked = true;
+                        self.sacked_count += 1;
+                    }
+                }
+            }
+            if first <= last {
+                self.highest_sacked = self.highest_sacked.max(last);
+            }
+        }
+    }
+
+    /// Returns true if fast retransmit should be triggered
+    pub fn handle_ack(&mut self, acked_seq: u32, checknum: u8, sack: &[SackBlock]) -> bool {
+        // An acknowledgement of packets not sent yet is not from the receiver
+        if acked_seq >= self.next_seq {
+            return false;
+        }
+        self.checknum = checknum;
+        self.metrics.acks.inc();
+        self.handle_sack(sack);
+
+        if acked_seq > self.last_acked_seq {
+            // Remove acked packets and grow window
+            let mut retransmitted = false;
+            let mut newest_sent = None;
+            let acked = (acked_seq - self.last_acked_seq) as usize;
+            for seq in (self.last_acked_seq + 1)..=acked_seq {
+                if let Some(info) = self.unacked_packets.remove(&seq) {
+                    retransmitted |= info.retry_count > 0;
+                    self.acked_bytes += info.packet.len() - wire::HEADER_SIZE;
+                    if info.sacked {
+                        self.sacked_count -= 1;
+                    }
+                    if seq == acked_seq {
+                        newest_sent = Some(info.sent_time);
+                    }
+                }
+            }
+            // One RTT sample per ACK, from the packet whose arrival triggered it.
+            // Karn's rule: an ACK that covers a retransmitted packet gives none, as
+            // it cannot tell which transmission arrived
+            let rtt = newest_sent
+                .filter(|_| !retransmitted)
+                .map(|sent_time| sent_time.elapsed());
+            if let Some(rtt) = rtt {
+                let sample = rtt.as_secs_f64();
+                self.rtt.update(sample * 1000.0);
+                self.metrics.rtt.observe(sample);
+                let ms = sample * 1000.0;
+                self.rtt_range = Some(match self.rtt_range {
+                    Some((min, max)) => (min.min(ms), max.max(ms)),
+                    None => (ms, ms),
+                });
+                self.rtt_total_ms += ms;
+            }
+            self.cc.on_ack(acked, rtt);
+            self.last_acked_seq = acked_seq;
+            if let Some((_, started)) = self.recovery.filter(|(end, _)| acked_seq >= *end) {
+                self.recovery_time += started.elapsed();
+                self.recovery = None;
+            }
+            self.dup_ack_count = 0;
+            self.update_gauges();
+            false
+        } else if acked_seq == self.last_acked_seq {
+            self.metrics.dup_acks.inc();
+            // Only a duplicate with packets outstanding means that one was lost
+            if self.unacked_packets.is_empty() {
+                return false;
+            }
+            self.dup_ack_count += 1;
+            let missing = acked_seq + 1;
+            let threshold = self.config.dup_ack_threshold;
+            if self.dup_ack_count >= threshold && self.fast_retransmitted != Some(missing) {
+                self.cc.on_fast_retransmit();
+                self.enter_recovery();
+                self.fast_retransmitted = Some(missing);
+                self.dup_ack_count = 0;
+                return true;
+            }
+            false
+        } else {
+            false
+        }
+    }
+
+    pub(crate) async fn retransmit_if_needed(
+        &mut self,
+        socket: &ImpairedUdpSocket,
+        server_addr: SocketAddr,
+        force: bool,
+    ) -> io::Result<()> {
+        if let Some(seq) = self.first_to_retransmit(force) {
+            if force {
+                self.metrics.fast_retransmits.inc();
+            } else {
+                self.cc.on_timeout();
+                self.rtt.backoff();
+                self.enter_recovery();
+                self.metrics.retransmits.inc();
+            }
+            self.resend(seq, socket, server_addr).await?;
+        }
+        self.retransmit_holes(socket, server_addr).await
+    }
+
+    /// The first unacknowledged packet, if it is due for a retransmission:
+    /// after the retransmission timeout, or when `force`d by duplicate
+    /// acknowledgements. A fast retransmit skips a packet the peer has
+    /// reported, but the timer resends it anyway, in case the peer dropped it
+    /// after all.
+    pub(crate) fn first_to_retransmit(&self, force: bool) -> Option<u32> {
+        let next_expected = self.last_acked_seq + 1;
+        let info = self.unacked_packets.get(&next_expected)?;
+        ((force && !info.sacked) || info.sent_time.elapsed() > self.rtt.rto)
+            .then_some(next_expected)
+    }
+
+    /// Resends the packets below the highest SACKed one that the peer has not
+    /// reported: a later packet arrived, so these were lost. A packet that was
+    /// already resent gets another try only after the retransmission timeout.
+    pub(crate) async fn retransmit_holes(
+        &mut self,
+        socket: &ImpairedUdpSocket,
+        server_addr: SocketAddr,
+    ) -> io::Result<()> {
+        for seq in self.holes_to_retransmit() {
+            self.resend(seq, socket, server_addr).await?;
+            self.metrics.sack_retransmits.inc();
+            self.enter_recovery();
+        }
+        Ok(())
+    }
+
+    /// The holes below the highest SACKed packet that
+    /// [`TransmissionState::retransmit_holes`] resends now.
+    pub(crate) fn holes_to_retransmit(&self) -> Vec<u32> {
+        ((self.last_acked_seq + 1)..self.highest_sacked)
+            .filter(|seq| {
+                self.unacked_packets.get(seq).is_some_and(|info| {
+                    !info.sacked
+                        && (info.retry_count == 0 || info.sent_time.elapsed() > self.rtt.rto)
+                })
+            })
+            .collect()
+    }
+
+    /// Sends an unacknowledged packet again.
+    async fn resend(
+        &mut self,
+        seq: u32,
+        socket: &ImpairedUdpSocket,
+        server_addr: SocketAddr,
+    ) -> io::Result<()> {
+        let Some(info) = self.unacked_packets.get_mut(&seq) else {
+            return Ok(());
+        };
+        Self::send(&self.recording, socket, &info.packet, server_addr).await?;
+        info.sent_time = Instant::now();
+        info.retry_count += 1;
+        Ok(())
+    }
+
+    /// Starts a loss recovery, unless one is already in progress.
+    fn enter_recovery(&mut self) {
+        if self.recovery.is_none() {
+            self.recovery = Some((self.next_seq - 1, Instant::now()));
+            self.recoveries += 1;
+        }
+    }
+
+    /// The figures of the transfer so far, which has taken `elapsed`.
+    pub fn summary(&self, elapsed: Duration) -> Summary {
+        let seconds = elapsed.as_secs_f64();
+        let samples = self.rtt.stats().samples;
+        let ongoing = self
+            .recovery
+            .map_or(Duration::ZERO, |(_, started)| started.elapsed());
+        Summary {
+            bytes: self.acked_bytes,
+            seconds,
+            goodput_bps: match seconds > 0.0 {
+                true => self.acked_bytes as f64 * 8.0 / seconds,
+                false => 0.0,
+            },
+            rtt_min_ms: self.rtt_range.map(|(min, _)| min),
+            rtt_avg_ms: (samples > 0).then(|| self.rtt_total_ms / samples as f64),
+            rtt_max_ms: self.rtt_range.map(|(_, max)| max),
+            recoveries: self.recoveries,
+            recovery_seconds: (self.recovery_time + ongoing).as_secs_f64(),
+            cwnd: self.cc.window(),
+            ssthresh: self.cc.ssthresh(),
+        }
+    }
+
+    /// When [`TransmissionState::retransmit_if_needed`] next has a packet to
+    /// resend after the timeout: the first unacknowledged packet, or a hole
+    /// below the highest SACKed packet that has already been resent once.
+    pub fn next_timeout(&self) -> Option<Instant> {
+        let next_expected = self.last_acked_seq + 1;
+        let first = self
+            .unacked_packets
+            .get(&next_expected)
+            .map(|info| info.sent_time);
+        let holes = (next_expected..self.highest_sacked)
+            .filter_map(|seq| self.unacked_packets.get(&seq))
+            .filter(|info| !info.sacked && info.retry_count > 0)
+            .map(|info| info.sent_time);
+        first
+            .into_iter()
+            .chain(holes)
+            .min()
+            .map(|sent| sent + self.rtt.rto)
+    }
+
+    fn update_gauges(&self) {
+        self.metrics.cwnd.set(self.cc.window() as i64);
+        if let Some(ssthresh) = self.cc.ssthresh() {
+            self.metrics.ssthresh.set(ssthresh as i64);
+        }
+        self.metrics.in_flight.set(self.in_flight() as i64);
+        if let Some(interval) = self.pacing_interval() {
+            self.metrics
+                .pacing_rate
+                .set((1.0 / interval.as_secs_f64()).round() as i64);
+        }
+    }
+
+    pub fn is_complete(&self, payload: &Payload) -> bool {
+        self.next_seq > payload.packets(self.config.max_payload) && self.unacked_packets.is_empty()
+    }
+
+    /// Sender state with packets 1..=n of [`MAX_PAYLOAD`] bytes sent and
+    /// none acknowledged, for the tests and the benchmarks.
+    #[doc(hidden)]
+    pub fn with_in_flight(n: u32) -> Self {
+        let mut state = Self::new();
+        let now = Instant::now();
+        for seq in 1..=n {
+            let packet = Self::create_packet(seq, MAX_PAYLOAD, b'A');
+            state.unacked_packets.insert(
+                seq,
+                PacketInfo {
+                    packet,
+                    sent_time: now,
+                    retry_count: 0,
+                    sacked: false,
+                },
+            );
+        }
+        state.next_seq = n + 1;
+        state.transmitted = n as usize * MAX_PAYLOAD;
+        state
+    }
+}
+
+impl Default for TransmissionState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sack(first: u32, last: u32) -> SackBlock {
+        SackBlock { first, last }
+    }
+
+    fn payload_of(packet: &[u8]) -> Vec<u8> {
+        wire::decode(packet).unwrap().1.to_vec()
+    }
+
+    #[test]
+    fn repeated_payload() {
+        let payload = Payload::Repeated {
+            size: 2 * MAX_PAYLOAD + 1,
+            character: b'x',
+        };
+        assert_eq!(payload.packets(MAX_PAYLOAD), 3);
+        let first = payload.packet(1, 0, MAX_PAYLOAD);
+        assert_eq!(wire::decode(&first).unwrap().0.seq, 1);
+        assert_eq!(payload_of(&first), [b'x'; MAX_PAYLOAD]);
+        assert_eq!(
+            payload_of(&payload.packet(3, 2 * MAX_PAYLOAD, MAX_PAYLOAD)),
+            b"x"
+        );
+        assert_eq!(payload.packets(100), 25);
+        assert_eq!(payload_of(&payload.packet(1, 0, 100)), [b'x'; 100]);
+    }
+
+    #[test]
+    fn data_payload_ends_with_empty_packet() {
+        let data: Vec<u8> = (0..MAX_PAYLOAD + 2).map(|i| i as u8).collect();
+        let payload = Payload::Data(data.clone());
+        assert_eq!(payload.packets(MAX_PAYLOAD), 3);
+        assert_eq!(
+            payload_of(&payload.packet(1, 0, MAX_PAYLOAD)),
+            data[..MAX_PAYLOAD]
+        );
+        assert_eq!(
+            payload_of(&payload.packet(2, MAX_PAYLOAD, MAX_PAYLOAD)),
+            data[MAX_PAYLOAD..]
+        );
+        assert_eq!(payload_of(&payload.packet(3, data.len(), MAX_PAYLOAD)), b"");
+
+        assert_eq!(Payload::Data(Vec::new()).packets(MAX_PAYLOAD), 1);
+    }
+
+    #[test]
+    fn cumulative_ack() {
+        let mut state = TransmissionState::with_in_flight(10);
+        assert!(!state.handle_ack(4, 42, &[]));
+        assert_eq!(state.last_acked_seq, 4);
+        assert_eq!(state.checknum, 42);
+        assert_eq!(state.in_flight(), 6);
+        assert!(!state.unacked_packets.contains_key(&4));
+        assert_eq!(state.rtt.count(), 1);
+
+        // An older acknowledgement changes nothing
+        assert!(!state.handle_ack(2, 42, &[]));
+        assert_eq!(state.last_acked_seq, 4);
+        assert_eq!(state.in_flight(), 6);
+
+        let payload = Payload::Repeated {
+            size: 10 * MAX_PAYLOAD,
+            character: b'A',
+        };
+        assert!(!state.is_complete(&payload));
+        state.handle_ack(10, 42, &[]);
+        assert!(state.is_complete(&payload));
+    }
+
+    #[test]
+    fn ack_of_unsent_packets_is_ignored() {
+        let mut state = TransmissionState::with_in_flight(10);
+        for acked_seq in [11, u32::MAX] {
+            assert!(!state.handle_ack(acked_seq, 99, &[]));
+            assert_eq!(state.last_acked_seq, 0, "{acked_seq}");
+            assert_eq!(state.checknum, 0, "{acked_seq}");
+            assert_eq!(state.in_flight(), 10, "{acked_seq}");
+        }
+        assert_eq!(state.first_to_retransmit(true), Some(1));
+        assert!(state.next_timeout().is_some());
+    }
+
+    #[test]
+    fn no_rtt_sample_from_retransmitted_packets() {
+        let mut state = TransmissionState::with_in_flight(2);
+        state.unacked_packets.get_mut(&1).unwrap().retry_count = 1;
+        state.handle_ack(1, 0, &[]);
+        assert_eq!(state.rtt.count(), 0);
+        state.handle_ack(2, 0, &[]);
+        assert_eq!(state.rtt.count(), 1);
+    }
+
+    #[test]
+    fn third_duplicate_triggers_fast_retransmit_once() {
+        let mut state = TransmissionState::with_in_flight(10);
+        state.handle_ack(1, 0, &[]);
+        assert!(!state.handle_ack(1, 0, &[]));
+        assert!(!state.handle_ack(1, 0, &[]));
+        assert!(state.handle_ack(1, 0, &[]));
+        assert_eq!(state.first_to_retransmit(true), Some(2));
+        // Further duplicates of the same acknowledgement leave it to the timer
+        for _ in 0..6 {
+            assert!(!state.handle_ack(1, 0, &[]));
+        }
+        // The next loss is retransmitted again
+        state.handle_ack(2, 0, &[]);
+        assert!(!state.handle_ack(2, 0, &[]));
+        assert!(!state.handle_ack(2, 0, &[]));
+        assert!(state.handle_ack(2, 0, &[]));
+    }
+
+    #[test]
+    fn duplicates_without_packets_in_flight() {
+        let mut state = TransmissionState::with_in_flight(2);
+        state.handle_ack(2, 0, &[]);
+        for _ in 0..5 {
+            assert!(!state.handle_ack(2, 0, &[]));
+        }
+    }
+
+    #[test]
+    fn sacked_packets_leave_the_window() {
+        let mut state = TransmissionState::with_in_flight(10);
+        // Blocks beyond the packets sent are cut off
+        state.handle_ack(0, 0, &[sack(3, 5), sack(8, 20)]);
+        assert_eq!(state.in_flight(), 4);
+        assert_eq!(state.highest_sacked, 10);
+
+        // Reporting them again does not count them twice
+        state.handle_ack(0, 0, &[sack(3, 5)]);
+        assert_eq!(state.in_flight(), 4);
+
+        // Acknowledged, they are no longer SACKed packets in flight
+        state.handle_ack(5, 0, &[sack(8, 10)]);
+        assert_eq!(state.in_flight(), 2);
+    }
+
+    #[test]
+    fn first_packet_retransmitted_after_timeout() {
+        let mut state = TransmissionState::with_in_flight(3);
+        assert_eq!(state.first_to_retransmit(false), None);
+        assert_eq!(state.first_to_retransmit(true), Some(1));
+
+        let rto = state.rtt.rto;
+        state.unacked_packets.get_mut(&1).unwrap().sent_time -= 2 * rto;
+        assert_eq!(state.first_to_retransmit(false), Some(1));
+        assert!(state.next_timeout().unwrap() <= Instant::now());
+
+        state.handle_ack(3, 0, &[]);
+        assert_eq!(state.first_to_retransmit(true), None);
+        assert_eq!(state.next_timeout(), None);
+    }
+
+    #[test]
+    fn fast_retransmit_skips_sacked_packet() {
+        let mut state = TransmissionState::with_in_flight(3);
+        state.handle_ack(0, 0, &[sack(1, 1)]);
+        assert_eq!(state.first_to_retransmit(true), None);
+        // The timer resends it anyway
+        let rto = state.rtt.rto;
+        state.unacked_packets.get_mut(&1).unwrap().sent_time -= 2 * rto;
+        assert_eq!(state.first_to_retransmit(false), Some(1));
+    }
+
+    #[test]
+    fn holes_below_sacked_packets() {
+        let mut state = TransmissionState::with_in_flight(10);
+        state.handle_ack(2, 0, &[sack(5, 6), sack(9, 9)]);
+        assert_eq!(state.holes_to_retransmit(), [3, 4, 7, 8]);
+
+        // Once resent, a hole waits for the retransmission timeout
+        state.unacked_packets.get_mut(&3).unwrap().retry_count = 1;
+        assert_eq!(state.holes_to_retransmit(), [4, 7, 8]);
+        let rto = state.rtt.rto;
+        state.unacked_packets.get_mut(&3).unwrap().sent_time -= 2 * rto;
+        assert_eq!(state.holes_to_retransmit(), [3, 4, 7, 8]);
+
+        // Nothing above the highest SACKed packet is a hole
+        state.handle_ack(9, 0, &[]);
+        assert!(state.holes_to_retransmit().is_empty());
+    }
+}
@@ -0,0 +1,237 @@
+//! Latency client mode. The client sends numbered messages to an echo
+//! server one at a time, checks that each comes back unchanged, and prints
+//! the round-trip time of each and a summary at the end, like ping. With
+//! `ping`, it sends WebSocket pings and times the pongs instead, which
+//! measures the server's control frame path rather than its echo.
+
+use std::time::{Duration, Instant};
+
+use adnet_core::{report::Report, rtt::RttEstimator, shutdown};
+use tokio::{io::BufReader, net::TcpStream, time};
+use tracing::debug;
+
+use crate::{
+    frame::{CLOSE_NORMAL, MAX_CONTROL_PAYLOAD},
+    handshake,
+    socket::{Message, Role, WebSocket},
+    WsError,
+};
+
+/// What to measure.
+pub struct Probe {
+    pub count: u32,
+    pub interval: Duration,
+    /// Payload bytes in each message, at most 125 with `ping`
+    pub size: usize,
+    pub ping: bool,
+    pub timeout: Duration,
+}
+
+/// The parts of a `ws://host[:port][/path]` URL.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Url {
+    pub host: String,
+    pub port: u16,
+    pub path: String,
+}
+
+impl Url {
+    pub fn parse(url: &str) -> Result<Self, WsError> {
+        let rest = match url.split_once("://") {
+            Some(("ws", rest)) => rest,
+            Some(("wss", _)) => {
+                return Err(WsError::Usage(
+                    "wss:// is not supported, use ws://".to_string(),
+                ))
+            }
+            _ => {
+                return Err(WsError::Usage(format!(
+                    "Invalid URL {}, expected ws://host[:port]/path",
+                    url
+                )))
+            }
+        };
+        let (authority, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/"),
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) if !port.contains(']') => {
+                let port = port
+                    .parse()
+                    .map_err(|_| WsError::Usage(format!("Invalid port in {}", url)))?;
+                (host, port)
+            }
+            _ => (authority, 80),
+        };
+        if host.is_empty() {
+            return Err(WsError::Usage(format!("No host in {}", url)));
+        }
+        Ok(Url {
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+        })
+    }
+
+    /// Address to connect to, with IPv6 brackets removed
+    fn address(&self) -> (&str, u16) {
+        (
+            self.host.trim_start_matches('[').trim_end_matches(']'),
+            self.port,
+        )
+    }
+
+    /// Value of the Host header
+    fn host_header(&self) -> String {
+        match self.port {
+            80 => self.host.clone(),
+            port => format!("{}:{}", self.host, port),
+        }
+    }
+}
+
+/// The message with sequence number `seq`: the number followed by filler,
+/// so that a reply to an earlier message is told apart.
+fn payload(seq: u32, size: usize) -> Vec<u8> {
+    let mut data = format!("{:08}", seq).into_bytes();
+    data.resize(size.max(data.len()), b'.');
+    data
+}
+
+pub async fn client(url: &Url, probe: &Probe, report: &mut Report) -> Result<(), WsError> {
+    if probe.ping && probe.size > MAX_CONTROL_PAYLOAD {
+        return Err(WsError::Usage(format!(
+            "Ping payload is limited to {} bytes",
+            MAX_CONTROL_PAYLOAD
+        )));
+    }
+    let address = format!("{}:{}", url.host, url.port);
+    let socket = time::timeout(probe.timeout, TcpStream::connect(url.address()))
+        .await
+        .map_err(|_| WsError::Timeout(probe.timeout))?
+        .map_err(|source| WsError::Connect {
+            address: address.clone(),
+            source,
+        })?;
+    socket.set_nodelay(true)?;
+    let mut stream = BufReader::new(socket);
+    time::timeout(
+        probe.timeout,
+        handshake::client(&mut stream, &url.host_header(), &url.path),
+    )
+    .await
+    .map_err(|_| WsError::Timeout(probe.timeout))??;
+    let mut ws = WebSocket::new(stream, Role::Client);
+
+    let size = payload(0, probe.size).len();
+    println!(
+        "WS {}{}: {} {} of {} bytes",
+        address,
+        url.path,
+        probe.count,
+        if probe.ping { "pings" } else { "messages" },
+        size
+    );
+
+    let shutdown = shutdown::install()?;
+    let mut rtt = RttEstimator::new();
+    let mut total = Duration::ZERO;
+    let mut sent = 0;
+    let mut result = Ok(());
+    for seq in 0..probe.count {
+        if seq > 0 {
+            tokio::select! {
+                _ = time::sleep(probe.interval) => {}
+                _ = shutdown.requested() => break,
+            }
+        }
+        let data = payload(seq, probe.size);
+        let start = Instant::now();
+        let request = match probe.ping {
+            true => Message::Ping(data.clone()),
+            false => Message::Binary(data.clone()),
+        };
+        ws.send(request).await?;
+        sent += 1;
+
+        let answer = tokio::select! {
+            answer = time::timeout(probe.timeout, await_echo(&mut ws, probe.ping)) => answer,
+            _ = shutdown.requested() => break,
+        };
+        let echo = match answer {
+            Ok(Ok(echo)) => echo,
+            Ok(Err(e)) => {
+                result = Err(e);
+                break;
+            }
+            Err(_) => {
+                result = Err(WsError::Timeout(probe.timeout));
+                break;
+            }
+        };
+        let elapsed = start.elapsed();
+        if echo != data {
+            result = Err(WsError::Verify);
+            break;
+        }
+        rtt.update(elapsed.as_secs_f64() * 1000.0);
+        total += elapsed;
+        println!(
+            "{} bytes from {}: seq={} time={:.3} ms",
+            echo.len(),
+            address,
+            seq,
+            elapsed.as_secs_f64() * 1000.0
+        );
+    }
+
+    let received = rtt.count();
+    println!("--- {} websocket statistics ---", address);
+    println!("{} sent, {} echoed", sent, received);
+    if let (Some(min), Some(max)) = (rtt.min_rtt_ms(), rtt.max_rtt_ms()) {
+        let avg = total.as_secs_f64() * 1000.0 / received as f64;
+        println!("rtt min/avg/max = {:.3}/{:.3}/{:.3} ms", min, avg, max);
+        report.detail("rtt_avg_ms", avg);
+    }
+    report.bytes = received * size as u64;
+    report.detail("sent", sent);
+    report.detail("received", received);
+    report.detail("rtt", rtt.stats());
+
+    if !matches!(result, Err(WsError::Closed(_))) {
+        close(&mut ws, probe.timeout).await;
+    }
+    result
+}
+
+/// Waits for the echo of a message, or for the pong of a ping, and returns
+/// its payload.
+async fn await_echo(ws: &mut WebSocket, ping: bool) -> Result<Vec<u8>, WsError> {
+    loop {
+        match ws.recv().await? {
+            Message::Binary(data) if !ping => return Ok(data),
+            Message::Text(text) if !ping => return Ok(text.into_bytes()),
+            Message::Pong(data) if ping => return Ok(data),
+            Message::Close(reason) => return Err(WsError::Closed(reason)),
+            message => debug!("Ignoring {:?}", message),
+        }
+    }
+}
+
+/// Closes the connection normally and waits for the server to answer.
+async fn close(ws: &mut WebSocket, timeout: Duration) {
+    if ws.close(CLOSE_NORMAL, "").await.is_err() {
+        return;
+    }
+    let waiting = async {
+        while let Ok(message) = ws.recv().await {
+            if let Message::Close(_) = message {
+                break;
+            }
+        }
+    };
+    if time::timeout(timeout, waiting).await.is_err() {
+        debug!("Server did not answer the close in {:?}", timeout);
+    }
+}
@@ -0,0 +1,64 @@
+use std::{io, net::SocketAddr, time::Duration};
+
+use adnet_core::exit::{Code, HasCode};
+use thiserror::Error;
+
+use crate::frame::FrameError;
+
+/// Errors of task-ws. Each kind has its own exit code, see
+/// [`adnet_core::exit`].
+#[derive(Debug, Error)]
+pub enum WsError {
+    /// Invalid or missing options
+    #[error("{0}")]
+    Usage(String),
+    /// The config file could not be read
+    #[error(transparent)]
+    Config(io::Error),
+    #[error("Cannot listen at {address}: {source}")]
+    Bind {
+        address: SocketAddr,
+        source: io::Error,
+    },
+    /// The server did not accept the TCP connection
+    #[error("Connection to {address} failed: {source}")]
+    Connect { address: String, source: io::Error },
+    /// The opening handshake failed
+    #[error("{0}")]
+    Handshake(String),
+    /// The peer broke the framing rules
+    #[error(transparent)]
+    Frame(#[from] FrameError),
+    /// The server closed the connection before the client was done
+    #[error("Server closed the connection{}", reason(.0))]
+    Closed(Option<(u16, String)>),
+    #[error("No answer in {0:?}")]
+    Timeout(Duration),
+    #[error("Echoed message differs from the one sent")]
+    Verify,
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+fn reason(close: &Option<(u16, String)>) -> String {
+    match close {
+        Some((code, reason)) if reason.is_empty() => format!(" with code {}", code),
+        Some((code, reason)) => format!(" with code {}: {}", code, reason),
+        None => String::new(),
+    }
+}
+
+impl HasCode for WsError {
+    fn exit_code(&self) -> Code {
+        match self {
+            WsError::Usage(_) | WsError::Config(_) => Code::Usage,
+            WsError::Bind { .. } => Code::Failure,
+            WsError::Connect { .. } => Code::Connect,
+            WsError::Frame(FrameError::Io(e)) => e.exit_code(),
+            WsError::Handshake(_) | WsError::Frame(_) | WsError::Closed(_) => Code::Protocol,
+            WsError::Timeout(_) => Code::Timeout,
+            WsError::Verify => Code::Verify,
+            WsError::Io(e) => e.exit_code(),
+        }
+    }
+}
@@ -0,0 +1,359 @@
+//! WebSocket frames (RFC 6455 section 5). Every frame starts with two bytes:
+//!
+//! ```text
+//!  0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5
+//! +-+-+-+-+-------+-+-------------+
+//! |F|R|R|R| opcode|M| payload len |
+//! |I|S|S|S|       |A|             |
+//! |N|V|V|V|       |S|             |
+//! | |1|2|3|       |K|             |
+//! +-+-+-+-+-------+-+-------------+
+//! ```
+//!
+//! A payload length of 126 is followed by the real length in 2 bytes, and
+//! 127 by the length in 8 bytes. With the MASK bit set, a 4-byte masking key
+//! follows, and the payload is XORed with it. Clients mask every frame they
+//! send, servers never do.
+
+use std::io;
+
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Longest payload of a control frame
+pub const MAX_CONTROL_PAYLOAD: usize = 125;
+
+pub const CLOSE_NORMAL: u16 = 1000;
+pub const CLOSE_GOING_AWAY: u16 = 1001;
+pub const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+pub const CLOSE_INVALID_DATA: u16 = 1007;
+pub const CLOSE_TOO_BIG: u16 = 1009;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Opcode {
+    Continuation,
+    Text,
+    Binary,
+    Close,
+    Ping,
+    Pong,
+}
+
+impl Opcode {
+    fn from_u8(value: u8) -> Option<Self> {
+        Some(match value {
+            0 => Opcode::Continuation,
+            1 => Opcode::Text,
+            2 => Opcode::Binary,
+            8 => Opcode::Close,
+            9 => Opcode::Ping,
+            10 => Opcode::Pong,
+            _ => return None,
+        })
+    }
+
+    fn to_u8(self) -> u8 {
+        match self {
+            Opcode::Continuation => 0,
+            Opcode::Text => 1,
+            Opcode::Binary => 2,
+            Opcode::Close => 8,
+            Opcode::Ping => 9,
+            Opcode::Pong => 10,
+        }
+    }
+
+    /// Close, ping and pong, which may come between the frames of a message
+    pub fn is_control(self) -> bool {
+        matches!(self, Opcode::Close | Opcode::Ping | Opcode::Pong)
+    }
+}
+
+/// Frames that break the protocol, with the close code to answer them with.
+#[derive(Debug, Error)]
+pub enum FrameError {
+    #[error("Reserved bits set in frame header")]
+    Reserved,
+    #[error("Unknown opcode {0}")]
+    Opcode(u8),
+    #[error("Control frame is fragmented or longer than 125 bytes")]
+    Control,
+    #[error("Frame from the client is not masked")]
+    Unmasked,
+    #[error("Frame from the server is masked")]
+    Masked,
+    #[error("Continuation frame outside a message, or a new message inside one")]
+    Fragment,
+    #[error("Message of {0} bytes is over the limit")]
+    TooBig(u64),
+    #[error("Text message is not valid UTF-8")]
+    Utf8,
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+impl FrameError {
+    /// Code to close the connection with, or `None` if it cannot be used
+    /// any more
+    pub fn close_code(&self) -> Option<u16> {
+        match self {
+            FrameError::TooBig(_) => Some(CLOSE_TOO_BIG),
+            FrameError::Utf8 => Some(CLOSE_INVALID_DATA),
+            FrameError::Io(_) => None,
+            _ => Some(CLOSE_PROTOCOL_ERROR),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Frame {
+    /// Last frame of a message
+    pub fin: bool,
+    pub opcode: Opcode,
+    /// Unmasked payload
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    /// A whole message in one frame
+    pub fn new(opcode: Opcode, payload: Vec<u8>) -> Self {
+        Frame {
+            fin: true,
+            opcode,
+            payload,
+        }
+    }
+
+    /// Close frame with a status code and a reason, which is cut to fit a
+    /// control frame.
+    pub fn close(code: u16, reason: &str) -> Self {
+        let mut payload = code.to_be_bytes().to_vec();
+        let mut end = reason.len().min(MAX_CONTROL_PAYLOAD - 2);
+        while !reason.is_char_boundary(end) {
+            end -= 1;
+        }
+        payload.extend_from_slice(&reason.as_bytes()[..end]);
+        Frame::new(Opcode::Close, payload)
+    }
+
+    /// Status code and reason of a close frame, or `None` if it has no code.
+    pub fn close_reason(&self) -> Option<(u16, String)> {
+        let code = u16::from_be_bytes(self.payload.get(..2)?.try_into().ok()?);
+        Some((
+            code,
+            String::from_utf8_lossy(&self.payload[2..]).into_owned(),
+        ))
+    }
+
+    /// The frame as sent, masked with `mask` if given.
+    pub fn encode(&self, mask: Option<[u8; 4]>) -> Vec<u8> {
+        let len = self.payload.len();
+        let mut out = Vec::with_capacity(len + 14);
+        out.push(((self.fin as u8) << 7) | self.opcode.to_u8());
+        let mask_bit = (mask.is_some() as u8) << 7;
+        match len {
+            0..=125 => out.push(mask_bit | len as u8),
+            126..=0xFFFF => {
+                out.push(mask_bit | 126);
+                out.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            _ => {
+                out.push(mask_bit | 127);
+                out.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+        let start = out.len();
+        match mask {
+            Some(key) => {
+                out.extend_from_slice(&key);
+                out.extend_from_slice(&self.payload);
+                apply_mask(&mut out[start + 4..], key);
+            }
+            None => out.extend_from_slice(&self.payload),
+        }
+        out
+    }
+}
+
+/// Masks or unmasks the payload in place, which are the same operation.
+pub fn apply_mask(payload: &mut [u8], key: [u8; 4]) {
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= key[i % 4];
+    }
+}
+
+/// Reads one frame and unmasks its payload. `masked` is whether the peer
+/// must mask its frames, i.e. whether we are the server.
+pub async fn read_frame<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    masked: bool,
+    max_payload: usize,
+) -> Result<Frame, FrameError> {
+    let mut header = [0u8; 2];
+    reader.read_exact(&mut header).await?;
+    if header[0] & 0x70 != 0 {
+        return Err(FrameError::Reserved);
+    }
+    let fin = header[0] & 0x80 != 0;
+    let opcode = Opcode::from_u8(header[0] & 0x0F).ok_or(FrameError::Opcode(header[0] & 0x0F))?;
+    let has_mask = header[1] & 0x80 != 0;
+    match (has_mask, masked) {
+        (false, true) => return Err(FrameError::Unmasked),
+        (true, false) => return Err(FrameError::Masked),
+        _ => {}
+    }
+
+    let len = match header[1] & 0x7F {
+        126 => reader.read_u16().await? as u64,
+        127 => reader.read_u64().await?,
+        len => len as u64,
+    };
+    if opcode.is_control() && (!fin || len > MAX_CONTROL_PAYLOAD as u64) {
+        return Err(FrameError::Control);
+    }
+    if len > max_payload as u64 {
+        return Err(FrameError::TooBig(len));
+    }
+
+    let mut key = [0u8; 4];
+    if masked {
+        reader.read_exact(&mut key).await?;
+    }
+    let mut payload = vec![0u8; len as usize];
+    reader.read_exact(&mut payload).await?;
+    if masked {
+        apply_mask(&mut payload, key);
+    }
+    Ok(Frame {
+        fin,
+        opcode,
+        payload,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MASK: [u8; 4] = [0x37, 0xfa, 0x21, 0x3d];
+    // The examples of RFC 6455 section 5.7
+    const HELLO: &[u8] = &[0x81, 0x05, 0x48, 0x65, 0x6c, 0x6c, 0x6f];
+    const MASKED_HELLO: &[u8] = &[
+        0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58,
+    ];
+
+    async fn read(mut bytes: &[u8], masked: bool) -> Result<Frame, FrameError> {
+        read_frame(&mut bytes, masked, 1 << 20).await
+    }
+
+    #[tokio::test]
+    async fn rfc_examples() {
+        let hello = Frame::new(Opcode::Text, b"Hello".to_vec());
+        assert_eq!(hello.encode(None), HELLO);
+        assert_eq!(hello.encode(Some(MASK)), MASKED_HELLO);
+        assert_eq!(read(HELLO, false).await.unwrap(), hello);
+        assert_eq!(read(MASKED_HELLO, true).await.unwrap(), hello);
+
+        let ping = Frame::new(Opcode::Ping, b"Hello".to_vec());
+        assert_eq!(ping.encode(None)[..2], [0x89, 0x05]);
+        let pong = Frame::new(Opcode::Pong, b"Hello".to_vec());
+        assert_eq!(pong.encode(Some(MASK))[..2], [0x8a, 0x85]);
+
+        // A text message in two frames
+        let mut fragments: &[u8] = &[0x01, 0x03, 0x48, 0x65, 0x6c, 0x80, 0x02, 0x6c, 0x6f];
+        let first = read_frame(&mut fragments, false, 16).await.unwrap();
+        let second = read_frame(&mut fragments, false, 16).await.unwrap();
+        assert_eq!(
+            (first.fin, first.opcode, &first.payload[..]),
+            (false, Opcode::Text, &b"Hel"[..])
+        );
+        assert_eq!(
+            (second.fin, second.opcode, &second.payload[..]),
+            (true, Opcode::Continuation, &b"lo"[..])
+        );
+    }
+
+    #[tokio::test]
+    async fn payload_lengths() {
+        let cases: [(usize, &[u8]); 6] = [
+            (0, &[0x00]),
+            (125, &[0x7d]),
+            (126, &[0x7e, 0x00, 0x7e]),
+            (256, &[0x7e, 0x01, 0x00]),
+            (0xffff, &[0x7e, 0xff, 0xff]),
+            (0x10000, &[0x7f, 0, 0, 0, 0, 0, 0x01, 0x00, 0x00]),
+        ];
+        for (len, header) in cases {
+            let frame = Frame::new(Opcode::Binary, vec![0xa5; len]);
+            let encoded = frame.encode(None);
+            assert_eq!(encoded[0], 0x82);
+            assert_eq!(&encoded[1..1 + header.len()], header, "{len} bytes");
+            assert_eq!(encoded.len(), 1 + header.len() + len, "{len} bytes");
+            assert_eq!(read(&encoded, false).await.unwrap(), frame, "{len} bytes");
+
+            let masked = frame.encode(Some(MASK));
+            assert_eq!(masked[1], header[0] | 0x80, "{len} bytes masked");
+            assert_eq!(
+                read(&masked, true).await.unwrap(),
+                frame,
+                "{len} bytes masked"
+            );
+        }
+    }
+
+    #[test]
+    fn masking_twice_unmasks() {
+        let mut payload = b"Hello, WebSocket".to_vec();
+        apply_mask(&mut payload, MASK);
+        assert_ne!(payload, b"Hello, WebSocket");
+        apply_mask(&mut payload, MASK);
+        assert_eq!(payload, b"Hello, WebSocket");
+    }
+
+    #[tokio::test]
+    async fn protocol_errors() {
+        let too_big: &[u8] = &[0x82, 0x7f, 0, 0, 0, 0, 0, 0x10, 0x00, 0x01];
+        let e = read(&[0xc1, 0x00], false).await.unwrap_err();
+        assert!(matches!(e, FrameError::Reserved), "{e}");
+        let e = read(&[0x83, 0x00], false).await.unwrap_err();
+        assert!(matches!(e, FrameError::Opcode(3)), "{e}");
+        let e = read(&[0x81, 0x00], true).await.unwrap_err();
+        assert!(matches!(e, FrameError::Unmasked), "{e}");
+        let e = read(&[0x81, 0x80, 0, 0, 0, 0], false).await.unwrap_err();
+        assert!(matches!(e, FrameError::Masked), "{e}");
+        let e = read(&[0x09, 0x00], false).await.unwrap_err();
+        assert!(matches!(e, FrameError::Control), "fragmented ping: {e}");
+        let e = read(&[0x89, 0x7e, 0x00, 0x7e], false).await.unwrap_err();
+        assert!(matches!(e, FrameError::Control), "long ping: {e}");
+        assert_eq!(e.close_code(), Some(CLOSE_PROTOCOL_ERROR));
+        let e = read(too_big, false).await.unwrap_err();
+        assert!(matches!(e, FrameError::TooBig(0x100001)), "{e}");
+        assert_eq!(e.close_code(), Some(CLOSE_TOO_BIG));
+    }
+
+    #[tokio::test]
+    async fn truncated_frames() {
+        for len in 0..MASKED_HELLO.len() {
+            let e = read(&MASKED_HELLO[..len], true).await.unwrap_err();
+            assert!(e.close_code().is_none(), "{len} bytes: {e}");
+        }
+    }
+
+    #[test]
+    fn close_frames() {
+        let close = Frame::close(CLOSE_GOING_AWAY, "bye");
+        assert_eq!(close.payload, b"\x03\xe9bye");
+        assert_eq!(
+            close.close_reason(),
+            Some((CLOSE_GOING_AWAY, "bye".to_string()))
+        );
+        assert_eq!(Frame::new(Opcode::Close, Vec::new()).close_reason(), None);
+
+        // Cut to fit a control frame, between characters
+        let reason = "é".repeat(100);
+        let close = Frame::close(CLOSE_NORMAL, &reason);
+        assert_eq!(close.payload.len(), MAX_CONTROL_PAYLOAD - 1);
+        let (code, cut) = close.close_reason().unwrap();
+        assert_eq!((code, cut.chars().count()), (CLOSE_NORMAL, 61));
+    }
+}
@@ -0,0 +1,275 @@
+//! The opening handshake (RFC 6455 section 4): an HTTP/1.1 GET request that
+//! asks to upgrade the connection to WebSocket, and the server's 101
+//! Switching Protocols response. The client sends a random
+//! `Sec-WebSocket-Key`, and the server proves that it understood the request
+//! by answering with `Sec-WebSocket-Accept`, the base64 of the SHA-1 of the
+//! key and a fixed GUID.
+
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+};
+
+use sha1::{Digest, Sha1};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::WsError;
+
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const VERSION: &str = "13";
+// Longest request or response head we accept
+const MAX_HEAD: usize = 8 * 1024;
+
+/// Request line or status line of an HTTP message, with its headers.
+struct Head {
+    start: String,
+    headers: Vec<(String, String)>,
+}
+
+impl Head {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Whether the comma-separated header contains the token, ignoring case.
+    fn has_token(&self, name: &str, token: &str) -> bool {
+        self.header(name)
+            .is_some_and(|v| v.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)))
+    }
+}
+
+/// Reads the lines of a message head up to the empty line. Anything after
+/// it is left in the reader for the frames.
+async fn read_head<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<Head, WsError> {
+    let mut lines = Vec::new();
+    let mut total = 0;
+    loop {
+        let mut line = String::new();
+        let n = reader.read_line(&mut line).await?;
+        if n == 0 {
+            return Err(WsError::Handshake(
+                "Connection closed during the handshake".to_string(),
+            ));
+        }
+        total += n;
+        if total > MAX_HEAD {
+            return Err(WsError::Handshake("Handshake is too long".to_string()));
+        }
+        let line = line.trim_end_matches(['\r', '\n']);
+        if line.is_empty() {
+            break;
+        }
+        lines.push(line.to_string());
+    }
+
+    let mut lines = lines.into_iter();
+    let start = lines
+        .next()
+        .ok_or_else(|| WsError::Handshake("Empty handshake".to_string()))?;
+    let headers: Vec<(String, String)> = lines
+        .map(|line| match line.split_once(':') {
+            Some((name, value)) => Ok((name.trim().to_string(), value.trim().to_string())),
+            None => Err(WsError::Handshake(format!(
+                "Invalid header line {:?}",
+                line
+            ))),
+        })
+        .collect::<Result<_, _>>()?;
+    Ok(Head { start, headers })
+}
+
+/// The `Sec-WebSocket-Accept` value for a key.
+pub fn accept_key(key: &str) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(key.as_bytes());
+    hasher.update(GUID.as_bytes());
+    base64(&hasher.finalize())
+}
+
+/// Standard base64 with padding, which is all the handshake needs.
+pub fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let b = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let n = ((b[0] as u32) << 16) | ((b[1] as u32) << 8) | b[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[((n >> (18 - 6 * i)) & 0x3F) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+/// Random bytes for keys and masks. They need not be cryptographically
+/// strong, only unpredictable to intermediaries.
+pub fn random_bytes<const N: usize>() -> [u8; N] {
+    let mut bytes = [0u8; N];
+    for chunk in bytes.chunks_mut(8) {
+        let random = RandomState::new().build_hasher().finish().to_be_bytes();
+        chunk.copy_from_slice(&random[..chunk.len()]);
+    }
+    bytes
+}
+
+/// Sends the upgrade request for `path` at `host` and checks the server's
+/// answer.
+pub async fn client<S>(stream: &mut S, host: &str, path: &str) -> Result<(), WsError>
+where
+    S: AsyncBufRead + AsyncWrite + Unpin,
+{
+    let key = base64(&random_bytes::<16>());
+    let request = format!(
+        "GET {path} HTTP/1.1\r\n\
+         Host: {host}\r\n\
+         Upgrade: websocket\r\n\
+         Connection: Upgrade\r\n\
+         Sec-WebSocket-Key: {key}\r\n\
+         Sec-WebSocket-Version: {VERSION}\r\n\r\n"
+    );
+    stream.write_all(request.as_bytes()).await?;
+
+    let head = read_head(stream).await?;
+    let status = head.start.split_whitespace().nth(1).unwrap_or_default();
+    if status != "101" {
+        return Err(WsError::Handshake(format!(
+            "Server refused the upgrade: {}",
+            head.start
+        )));
+    }
+    if !head.has_token("Upgrade", "websocket") || !head.has_token("Connection", "upgrade") {
+        return Err(WsError::Handshake(
+            "Server did not switch to WebSocket".to_string(),
+        ));
+    }
+    if head.header("Sec-WebSocket-Accept") != Some(accept_key(&key).as_str()) {
+        return Err(WsError::Handshake(
+            "Server answered with a wrong Sec-WebSocket-Accept".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Reads the client's upgrade request and accepts it, returning the
+/// requested path. Requests that are not WebSocket upgrades get an HTTP
+/// error response before the error is returned.
+pub async fn server<S>(stream: &mut S) -> Result<String, WsError>
+where
+    S: AsyncBufRead + AsyncWrite + Unpin,
+{
+    let head = read_head(stream).await?;
+    let mut parts = head.start.split_whitespace();
+    let (method, path) = (parts.next().unwrap_or_default(), parts.next());
+
+    let refusal = if method != "GET" || path.is_none() {
+        Some((
+            "405 Method Not Allowed",
+            "",
+            "Not a WebSocket upgrade request",
+        ))
+    } else if !head.has_token("Upgrade", "websocket") || !head.has_token("Connection", "upgrade") {
+        Some((
+            "426 Upgrade Required",
+            "Upgrade: websocket\r\n",
+            "Not a WebSocket upgrade request",
+        ))
+    } else if head.header("Sec-WebSocket-Version") != Some(VERSION) {
+        Some((
+            "426 Upgrade Required",
+            "Sec-WebSocket-Version: 13\r\n",
+            "Unsupported WebSocket version",
+        ))
+    } else if head.header("Sec-WebSocket-Key").map(str::len) != Some(24) {
+        Some((
+            "400 Bad Request",
+            "",
+            "Missing or invalid Sec-WebSocket-Key",
+        ))
+    } else {
+        None
+    };
+    if let Some((status, headers, reason)) = refusal {
+        let response = format!(
+            "HTTP/1.1 {status}\r\n{headers}Content-Length: {}\r\nConnection: close\r\n\r\n{reason}",
+            reason.len()
+        );
+        stream.write_all(response.as_bytes()).await?;
+        return Err(WsError::Handshake(format!("{}: {}", reason, head.start)));
+    }
+
+    let key = head.header("Sec-WebSocket-Key").unwrap_or_default();
+    let response = format!(
+        "HTTP/1.1 101 Switching Protocols\r\n\
+         Upgrade: websocket\r\n\
+         Connection: Upgrade\r\n\
+         Sec-WebSocket-Accept: {}\r\n\r\n",
+        accept_key(key)
+    );
+    stream.write_all(response.as_bytes()).await?;
+    Ok(path.unwrap_or_default().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rfc_accept_key() {
+        // RFC 6455 section 1.3
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn base64_vectors() {
+        // RFC 4648 section 10
+        let cases = [
+            ("", ""),
+            ("f", "Zg=="),
+            ("fo", "Zm8="),
+            ("foo", "Zm9v"),
+            ("foob", "Zm9vYg=="),
+            ("fooba", "Zm9vYmE="),
+            ("foobar", "Zm9vYmFy"),
+        ];
+        for (input, output) in cases {
+            assert_eq!(base64(input.as_bytes()), output, "{input:?}");
+        }
+        assert_eq!(base64(&[0xfb, 0xff]), "+/8=");
+    }
+
+    #[tokio::test]
+    async fn client_and_server() {
+        let (client_end, server_end) = tokio::io::duplex(1024);
+        let mut server_end = tokio::io::BufReader::new(server_end);
+        let server = tokio::spawn(async move { server(&mut server_end).await });
+        let mut client_end = tokio::io::BufReader::new(client_end);
+        client(&mut client_end, "localhost", "/echo").await.unwrap();
+        assert_eq!(server.await.unwrap().unwrap(), "/echo");
+    }
+
+    #[tokio::test]
+    async fn server_refuses_plain_http() {
+        let mut request: &[u8] = b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        let mut response = Vec::new();
+        let mut stream = tokio::io::join(&mut request, &mut response);
+        assert!(server(&mut stream).await.is_err());
+        let response = String::from_utf8(response).unwrap();
+        assert!(
+            response.starts_with("HTTP/1.1 426 Upgrade Required\r\n"),
+            "{response}"
+        );
+    }
+}
@@ -0,0 +1,170 @@
+//! WebSocket (RFC 6455) echo server and latency client, implemented from
+//! the handshake up: the HTTP upgrade, framing with client masking,
+//! fragmented messages, ping/pong and the closing handshake. The server
+//! echoes every message back; the client sends numbered messages or pings
+//! and measures how long the echoes take.
+//!
+//! The task-ws binary and the `adnet ws` subcommand are thin wrappers
+//! around [`run`].
+
+mod client;
+mod error;
+pub mod frame;
+pub mod handshake;
+mod server;
+pub mod socket;
+
+use std::{net::SocketAddr, time::Duration};
+
+use adnet_core::{
+    config::{self, ConfigArgs},
+    logging::{self, LogArgs},
+    metrics::{self, MetricsArgs},
+    report::{Report, ReportArgs},
+};
+use clap::{Parser, Subcommand};
+use client::{Probe, Url};
+use serde::Deserialize;
+
+pub use error::WsError;
+
+const DEFAULT_PORT: u16 = 9001;
+const DEFAULT_COUNT: u32 = 5;
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(1);
+const DEFAULT_SIZE: usize = 56;
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// WebSocket echo server and latency-measuring client.
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+pub struct Args {
+    #[command(subcommand)]
+    mode: Mode,
+
+    #[command(flatten)]
+    config: ConfigArgs,
+
+    #[command(flatten)]
+    log: LogArgs,
+
+    #[command(flatten)]
+    metrics: MetricsArgs,
+
+    #[command(flatten)]
+    report: ReportArgs,
+}
+
+#[derive(Subcommand, Debug)]
+enum Mode {
+    /// Echo the messages of clients back to them
+    Server {
+        /// Address to listen at [default: 0.0.0.0:9001]
+        #[arg(short, long)]
+        listen: Option<SocketAddr>,
+
+        /// Largest message to accept, in bytes [default: 16777216]
+        #[arg(long)]
+        max_message: Option<usize>,
+    },
+
+    /// Measure the round-trip time of messages to an echo server
+    Client {
+        /// Server URL as ws://host[:port][/path] [default port: 80]
+        #[arg(short, long)]
+        url: Option<String>,
+
+        /// Number of messages to send [default: 5]
+        #[arg(short, long)]
+        count: Option<u32>,
+
+        /// Seconds between messages [default: 1]
+        #[arg(short, long, value_parser = config::parse_secs)]
+        interval: Option<Duration>,
+
+        /// Payload bytes in each message, at least 8 [default: 56]
+        #[arg(short, long)]
+        size: Option<usize>,
+
+        /// Send WebSocket pings and time the pongs instead of echoes
+        #[arg(short, long)]
+        ping: bool,
+
+        /// Seconds to wait for the connection and for each answer [default: 2]
+        #[arg(short, long, value_parser = config::parse_secs)]
+        timeout: Option<Duration>,
+    },
+}
+
+/// The [task-ws] section of the config file. Each mode uses its own options.
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct FileConfig {
+    listen: Option<SocketAddr>,
+    max_message: Option<usize>,
+    url: Option<String>,
+    count: Option<u32>,
+    #[serde(deserialize_with = "config::secs")]
+    interval: Option<Duration>,
+    size: Option<usize>,
+    ping: bool,
+    #[serde(deserialize_with = "config::secs")]
+    timeout: Option<Duration>,
+}
+
+/// Runs the server or client with the given arguments, as the task-ws binary does.
+pub async fn run(args: Args) -> Result<(), WsError> {
+    logging::init(&args.log)?;
+    metrics::init(&args.metrics)?;
+    let report_args = args.report.clone();
+    let mut report = Report::new("task-ws");
+    let result = serve_or_measure(args, &mut report).await;
+    report_args.finish(&mut report, result)
+}
+
+async fn serve_or_measure(args: Args, report: &mut Report) -> Result<(), WsError> {
+    // Command line options take precedence over the config file
+    let file: FileConfig = args.config.section("task-ws").map_err(WsError::Config)?;
+    match args.mode {
+        Mode::Server {
+            listen,
+            max_message,
+        } => {
+            let listen = listen
+                .or(file.listen)
+                .unwrap_or_else(|| SocketAddr::from(([0, 0, 0, 0], DEFAULT_PORT)));
+            let max_message = max_message
+                .or(file.max_message)
+                .unwrap_or(socket::DEFAULT_MAX_MESSAGE);
+            report.detail("mode", "server");
+            let metrics = server::Metrics::new();
+            let result = server::serve(listen, max_message, metrics.clone()).await;
+            metrics.report(report);
+            result
+        }
+        Mode::Client {
+            url,
+            count,
+            interval,
+            size,
+            ping,
+            timeout,
+        } => {
+            let url = url
+                .or(file.url)
+                .ok_or_else(|| WsError::Usage("Server URL is required (--url)".to_string()))?;
+            let probe = Probe {
+                count: count.or(file.count).unwrap_or(DEFAULT_COUNT),
+                interval: interval.or(file.interval).unwrap_or(DEFAULT_INTERVAL),
+                size: size.or(file.size).unwrap_or(DEFAULT_SIZE),
+                ping: ping || file.ping,
+                timeout: timeout.or(file.timeout).unwrap_or(DEFAULT_TIMEOUT),
+            };
+            if probe.count == 0 {
+                return Err(WsError::Usage("Count must be positive".to_string()));
+            }
+            report.detail("mode", "client");
+            report.detail("url", &url);
+            client::client(&Url::parse(&url)?, &probe, report).await
+        }
+    }
+}
@@ -0,0 +1,9 @@
+use adnet_core::exit;
+use clap::Parser;
+use std::process::ExitCode;
+use task_ws::Args;
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    exit::exit(task_ws::run(Args::parse()).await)
+}
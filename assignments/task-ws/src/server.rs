@@ -0,0 +1,180 @@
+//! Echo server mode. Every text and binary message is sent back as it came,
+//! pings are answered with pongs, and a close is echoed, which ends the
+//! connection. On shutdown the server closes the open connections with
+//! 1001 (going away).
+
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use adnet_core::{
+    metrics::{self, Counter, Gauge},
+    report::Report,
+    shutdown::{self, Shutdown},
+};
+use tokio::{
+    io::BufReader,
+    net::{TcpListener, TcpStream},
+    task, time,
+};
+use tracing::{debug, info, warn};
+
+use crate::{
+    frame::CLOSE_GOING_AWAY,
+    handshake,
+    socket::{Message, Role, WebSocket},
+    WsError,
+};
+
+/// Time a client has to complete the handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Time a client has to answer our close on shutdown
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The server's metrics in the global registry.
+#[derive(Clone)]
+pub struct Metrics {
+    connections: Counter,
+    active_connections: Gauge,
+    failed: Counter,
+    messages: Counter,
+    bytes: Counter,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self {
+            connections: metrics::counter("ws_connections_total", "Client connections accepted"),
+            active_connections: metrics::gauge(
+                "ws_active_connections",
+                "WebSocket connections currently open",
+            ),
+            failed: metrics::counter(
+                "ws_failed_total",
+                "Client connections that ended with an error",
+            ),
+            messages: metrics::counter("ws_messages_total", "Messages echoed"),
+            bytes: metrics::counter("ws_bytes_echoed_total", "Payload bytes echoed"),
+        }
+    }
+
+    pub fn report(&self, report: &mut Report) {
+        report.bytes = self.bytes.get();
+        report.errors = self.failed.get();
+        report.detail("connections", self.connections.get());
+        report.detail("messages", self.messages.get());
+    }
+}
+
+/// Serves clients until the shutdown is requested, and then gives the open
+/// connections the drain timeout to close.
+pub async fn serve(
+    listen: SocketAddr,
+    max_message: usize,
+    metrics: Metrics,
+) -> Result<(), WsError> {
+    let listener = TcpListener::bind(listen)
+        .await
+        .map_err(|source| WsError::Bind {
+            address: listen,
+            source,
+        })?;
+    info!(
+        "Task-WS echo server listening at {}",
+        listener.local_addr()?
+    );
+
+    let metrics = Arc::new(metrics);
+    let shutdown = shutdown::install()?;
+    loop {
+        let (socket, address) = tokio::select! {
+            accepted = listener.accept() => accepted?,
+            _ = shutdown.requested() => break,
+        };
+        metrics.connections.inc();
+        let metrics = metrics.clone();
+        let in_flight = shutdown.track();
+        let shutdown = shutdown.clone();
+        task::spawn(async move {
+            if let Err(e) = handle_client(socket, max_message, &metrics, &shutdown).await {
+                warn!("{}: {}", address, e);
+                metrics.failed.inc();
+            }
+            drop(in_flight);
+        });
+    }
+
+    if shutdown.in_flight() > 0 {
+        info!("Closing {} open connections", shutdown.in_flight());
+        shutdown.drain_async(shutdown::DEFAULT_DRAIN_TIMEOUT).await;
+    }
+    shutdown.finish();
+    Ok(())
+}
+
+async fn handle_client(
+    socket: TcpStream,
+    max_message: usize,
+    metrics: &Metrics,
+    shutdown: &Shutdown,
+) -> Result<(), WsError> {
+    let address = socket.peer_addr()?;
+    let mut stream = BufReader::new(socket);
+    let path = time::timeout(HANDSHAKE_TIMEOUT, handshake::server(&mut stream))
+        .await
+        .map_err(|_| WsError::Timeout(HANDSHAKE_TIMEOUT))??;
+    debug!("{}: upgraded {}", address, path);
+
+    let mut ws = WebSocket::new(stream, Role::Server);
+    ws.set_max_message(max_message);
+    metrics.active_connections.inc();
+    let result = echo(&mut ws, address, metrics, shutdown).await;
+    metrics.active_connections.dec();
+    result
+}
+
+async fn echo(
+    ws: &mut WebSocket,
+    address: SocketAddr,
+    metrics: &Metrics,
+    shutdown: &Shutdown,
+) -> Result<(), WsError> {
+    loop {
+        let message = tokio::select! {
+            message = ws.recv() => message?,
+            _ = shutdown.requested() => {
+                ws.close(CLOSE_GOING_AWAY, "Server shutting down").await?;
+                await_close(ws).await;
+                return Ok(());
+            }
+        };
+        match message {
+            Message::Text(text) => {
+                metrics.bytes.add(text.len() as u64);
+                ws.send(Message::Text(text)).await?;
+            }
+            Message::Binary(data) => {
+                metrics.bytes.add(data.len() as u64);
+                ws.send(Message::Binary(data)).await?;
+            }
+            // Answered by the socket already
+            Message::Ping(_) | Message::Pong(_) => continue,
+            Message::Close(reason) => {
+                debug!("{}: closed with {:?}", address, reason);
+                return Ok(());
+            }
+        }
+        metrics.messages.inc();
+    }
+}
+
+/// Waits a moment for the client to answer our close, so that it sees a
+/// clean closing handshake. Errors do not matter any more at this point.
+async fn await_close(ws: &mut WebSocket) {
+    let waiting = async {
+        while let Ok(message) = ws.recv().await {
+            if let Message::Close(_) = message {
+                break;
+            }
+        }
+    };
+    let _ = time::timeout(CLOSE_TIMEOUT, waiting).await;
+}
@@ -0,0 +1,169 @@
+//! Messages over a WebSocket connection after the handshake. A message is
+//! one frame or a text or binary frame followed by continuation frames,
+//! and control frames may come between them. Pings are answered as they
+//! arrive, and a close from the peer is echoed, as RFC 6455 requires.
+
+use std::io;
+
+use tokio::{
+    io::{AsyncWriteExt, BufReader},
+    net::TcpStream,
+};
+
+use crate::{
+    frame::{self, Frame, FrameError, Opcode},
+    handshake,
+};
+
+/// Default limit on the size of a received message
+pub const DEFAULT_MAX_MESSAGE: usize = 16 * 1024 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    /// Masks the frames it sends
+    Client,
+    /// Requires the frames it receives to be masked
+    Server,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    /// Status code and reason, if the peer gave them
+    Close(Option<(u16, String)>),
+}
+
+pub struct WebSocket {
+    stream: BufReader<TcpStream>,
+    role: Role,
+    max_message: usize,
+    close_sent: bool,
+    // Opcode and data of a fragmented message so far, kept across the
+    // control frames between its fragments
+    partial: Option<(Opcode, Vec<u8>)>,
+}
+
+impl WebSocket {
+    /// Wraps a connection that has completed the handshake. The reader is
+    /// the one used for the handshake, so that no bytes it buffered are
+    /// lost.
+    pub fn new(stream: BufReader<TcpStream>, role: Role) -> Self {
+        Self {
+            stream,
+            role,
+            max_message: DEFAULT_MAX_MESSAGE,
+            close_sent: false,
+            partial: None,
+        }
+    }
+
+    pub fn set_max_message(&mut self, max_message: usize) {
+        self.max_message = max_message;
+    }
+
+    /// Whether we have sent a close frame, after which only the peer's close
+    /// may be read.
+    pub fn is_closing(&self) -> bool {
+        self.close_sent
+    }
+
+    pub async fn send_frame(&mut self, frame: &Frame) -> io::Result<()> {
+        let mask = match self.role {
+            Role::Client => Some(handshake::random_bytes()),
+            Role::Server => None,
+        };
+        if frame.opcode == Opcode::Close {
+            self.close_sent = true;
+        }
+        self.stream.write_all(&frame.encode(mask)).await
+    }
+
+    pub async fn send(&mut self, message: Message) -> io::Result<()> {
+        let frame = match message {
+            Message::Text(text) => Frame::new(Opcode::Text, text.into_bytes()),
+            Message::Binary(data) => Frame::new(Opcode::Binary, data),
+            Message::Ping(data) => Frame::new(Opcode::Ping, data),
+            Message::Pong(data) => Frame::new(Opcode::Pong, data),
+            Message::Close(Some((code, reason))) => Frame::close(code, &reason),
+            Message::Close(None) => Frame::new(Opcode::Close, Vec::new()),
+        };
+        self.send_frame(&frame).await
+    }
+
+    /// Starts the closing handshake, unless it has been started already.
+    pub async fn close(&mut self, code: u16, reason: &str) -> io::Result<()> {
+        if self.close_sent {
+            return Ok(());
+        }
+        self.send_frame(&Frame::close(code, reason)).await
+    }
+
+    /// Receives the next message. Pings are answered with a pong and then
+    /// returned, and a close is echoed unless we sent ours first. On a
+    /// protocol error the connection is closed with the matching code.
+    pub async fn recv(&mut self) -> Result<Message, FrameError> {
+        match self.read_message().await {
+            Ok(message) => Ok(message),
+            Err(e) => {
+                if let Some(code) = e.close_code() {
+                    // The error matters more than whether the peer heard it
+                    let _ = self.close(code, &e.to_string()).await;
+                }
+                Err(e)
+            }
+        }
+    }
+
+    async fn read_message(&mut self) -> Result<Message, FrameError> {
+        let masked = self.role == Role::Server;
+        loop {
+            let frame = frame::read_frame(&mut self.stream, masked, self.max_message).await?;
+            match frame.opcode {
+                Opcode::Ping => {
+                    if !self.close_sent {
+                        self.send_frame(&Frame::new(Opcode::Pong, frame.payload.clone()))
+                            .await?;
+                    }
+                    return Ok(Message::Ping(frame.payload));
+                }
+                Opcode::Pong => return Ok(Message::Pong(frame.payload)),
+                Opcode::Close => {
+                    let reason = frame.close_reason();
+                    if !self.close_sent {
+                        let echo = match &reason {
+                            Some((code, _)) => Frame::close(*code, ""),
+                            None => Frame::new(Opcode::Close, Vec::new()),
+                        };
+                        self.send_frame(&echo).await?;
+                    }
+                    return Ok(Message::Close(reason));
+                }
+                Opcode::Text | Opcode::Binary if self.partial.is_none() => {
+                    self.partial = Some((frame.opcode, frame.payload));
+                }
+                Opcode::Continuation if self.partial.is_some() => {
+                    let (_, data) = self.partial.as_mut().unwrap();
+                    data.extend_from_slice(&frame.payload);
+                }
+                _ => return Err(FrameError::Fragment),
+            }
+
+            let (_, data) = self.partial.as_ref().unwrap();
+            if data.len() > self.max_message {
+                return Err(FrameError::TooBig(data.len() as u64));
+            }
+            if frame.fin {
+                let (opcode, data) = self.partial.take().unwrap();
+                return match opcode {
+                    Opcode::Text => String::from_utf8(data)
+                        .map(Message::Text)
+                        .map_err(|_| FrameError::Utf8),
+                    _ => Ok(Message::Binary(data)),
+                };
+            }
+        }
+    }
+}
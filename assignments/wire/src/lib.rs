@@ -0,0 +1,446 @@
+//! The packet format of the task-udp protocol, shared by the sender, the
+//! receiver and the mock agent of the tests. A data packet is a 4-byte
+//! sequence number and a 2-byte payload length followed by the payload, and
+//! an acknowledgement is a 4-byte cumulative sequence number and a checknum
+//! byte. All numbers are big-endian.
+//!
+//! Receivers that support selective acknowledgements append SACK blocks to
+//! the acknowledgement, see [`SackAck`]. The course's server does not, and its
+//! 5-byte acknowledgements decode as a [`SackAck`] without blocks.
+//!
+//! The request format of task-srv, which its UDP requests share with the TCP
+//! ones, is in [`srv`].
+
+pub mod srv;
+
+use std::{error, fmt};
+
+/// Bytes before the payload of a data packet
+pub const HEADER_SIZE: usize = 6;
+/// Bytes in an acknowledgement
+pub const ACK_SIZE: usize = 5;
+/// Longest payload the length field can describe
+pub const MAX_PAYLOAD: usize = u16::MAX as usize;
+/// Bytes in a SACK block
+pub const SACK_BLOCK_SIZE: usize = 8;
+/// Most SACK blocks in an acknowledgement
+pub const MAX_SACK_BLOCKS: usize = 4;
+/// Bytes in an acknowledgement with all SACK blocks
+pub const MAX_ACK_SIZE: usize = ACK_SIZE + MAX_SACK_BLOCKS * SACK_BLOCK_SIZE;
+
+/// Why a packet could not be encoded or decoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// Fewer bytes than the header or the payload length calls for
+    Truncated { needed: usize, got: usize },
+    /// More bytes than the header and the payload length call for
+    TrailingBytes(usize),
+    /// Payload longer than [`MAX_PAYLOAD`]
+    PayloadTooLong(usize),
+    /// More SACK blocks than [`MAX_SACK_BLOCKS`]
+    TooManyBlocks(usize),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Truncated { needed, got } => {
+                write!(f, "Truncated packet: {} bytes of {}", got, needed)
+            }
+            Error::TrailingBytes(extra) => write!(f, "{} bytes after the end of the packet", extra),
+            Error::PayloadTooLong(len) => {
+                write!(f, "Payload of {} bytes is longer than {}", len, MAX_PAYLOAD)
+            }
+            Error::TooManyBlocks(count) => {
+                write!(f, "{} SACK blocks, at most {} fit", count, MAX_SACK_BLOCKS)
+            }
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+/// The header of a data packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    pub seq: u32,
+    /// Bytes of payload after the header
+    pub len: u16,
+}
+
+impl Header {
+    pub fn encode(&self) -> [u8; HEADER_SIZE] {
+        let mut buf = [0u8; HEADER_SIZE];
+        buf[0..4].copy_from_slice(&self.seq.to_be_bytes());
+        buf[4..6].copy_from_slice(&self.len.to_be_bytes());
+        buf
+    }
+
+    /// Reads the header at the start of `buf`, whatever follows it.
+    pub fn decode(buf: &[u8]) -> Result<Header, Error> {
+        let Some(header) = buf.get(..HEADER_SIZE) else {
+            return Err(Error::Truncated {
+                needed: HEADER_SIZE,
+                got: buf.len(),
+            });
+        };
+        Ok(Header {
+            seq: u32::from_be_bytes([header[0], header[1], header[2], header[3]]),
+            len: u16::from_be_bytes([header[4], header[5]]),
+        })
+    }
+}
+
+/// Appends a data packet with the sequence number and payload to `out`.
+pub fn encode(seq: u32, payload: &[u8], out: &mut Vec<u8>) -> Result<(), Error> {
+    let len = u16::try_from(payload.len()).map_err(|_| Error::PayloadTooLong(payload.len()))?;
+    out.reserve(HEADER_SIZE + payload.len());
+    out.extend_from_slice(&Header { seq, len }.encode());
+    out.extend_from_slice(payload);
+    Ok(())
+}
+
+/// Splits a data packet into its header and payload. The packet must be
+/// exactly as long as the header says.
+pub fn decode(packet: &[u8]) -> Result<(Header, &[u8]), Error> {
+    let header = Header::decode(packet)?;
+    let needed = HEADER_SIZE + header.len as usize;
+    match packet.len() {
+        got if got < needed => Err(Error::Truncated { needed, got }),
+        got if got > needed => Err(Error::TrailingBytes(got - needed)),
+        _ => Ok((header, &packet[HEADER_SIZE..])),
+    }
+}
+
+/// Acknowledgement of all packets up to and including `seq`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ack {
+    pub seq: u32,
+    pub checknum: u8,
+}
+
+impl Ack {
+    pub fn encode(&self) -> [u8; ACK_SIZE] {
+        let mut buf = [0u8; ACK_SIZE];
+        buf[0..4].copy_from_slice(&self.seq.to_be_bytes());
+        buf[4] = self.checknum;
+        buf
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Ack, Error> {
+        match buf.len() {
+            got if got < ACK_SIZE => Err(Error::Truncated {
+                needed: ACK_SIZE,
+                got,
+            }),
+            got if got > ACK_SIZE => Err(Error::TrailingBytes(got - ACK_SIZE)),
+            _ => Ok(Ack {
+                seq: u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]),
+                checknum: buf[4],
+            }),
+        }
+    }
+}
+
+/// Packets `first..=last`, received after a gap in the sequence numbers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SackBlock {
+    pub first: u32,
+    pub last: u32,
+}
+
+impl SackBlock {
+    pub fn contains(&self, seq: u32) -> bool {
+        (self.first..=self.last).contains(&seq)
+    }
+}
+
+/// An acknowledgement with selective acknowledgements: the 5 bytes of an
+/// [`Ack`] followed by up to [`MAX_SACK_BLOCKS`] blocks of 8 bytes, the first
+/// and last sequence numbers of packets that have arrived above the
+/// cumulative sequence number.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SackAck {
+    pub ack: Ack,
+    pub blocks: Vec<SackBlock>,
+}
+
+impl SackAck {
+    pub fn encode(&self) -> Result<Vec<u8>, Error> {
+        if self.blocks.len() > MAX_SACK_BLOCKS {
+            return Err(Error::TooManyBlocks(self.blocks.len()));
+        }
+        let mut buf = Vec::with_capacity(ACK_SIZE + self.blocks.len() * SACK_BLOCK_SIZE);
+        buf.extend_from_slice(&self.ack.encode());
+        for block in &self.blocks {
+            buf.extend_from_slice(&block.first.to_be_bytes());
+            buf.extend_from_slice(&block.last.to_be_bytes());
+        }
+        Ok(buf)
+    }
+
+    /// Reads an acknowledgement with or without SACK blocks. Bytes after the
+    /// acknowledgement must be whole blocks.
+    pub fn decode(buf: &[u8]) -> Result<SackAck, Error> {
+        let Some(extra) = buf.len().checked_sub(ACK_SIZE) else {
+            return Err(Error::Truncated {
+                needed: ACK_SIZE,
+                got: buf.len(),
+            });
+        };
+        if extra % SACK_BLOCK_SIZE != 0 {
+            return Err(Error::TrailingBytes(extra % SACK_BLOCK_SIZE));
+        }
+        if extra / SACK_BLOCK_SIZE > MAX_SACK_BLOCKS {
+            return Err(Error::TooManyBlocks(extra / SACK_BLOCK_SIZE));
+        }
+        let ack = Ack::decode(&buf[..ACK_SIZE])?;
+        let blocks = buf[ACK_SIZE..]
+            .chunks_exact(SACK_BLOCK_SIZE)
+            .map(|b| SackBlock {
+                first: u32::from_be_bytes([b[0], b[1], b[2], b[3]]),
+                last: u32::from_be_bytes([b[4], b[5], b[6], b[7]]),
+            })
+            .collect();
+        Ok(SackAck { ack, blocks })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet(seq: u32, payload: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        encode(seq, payload, &mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn header_is_big_endian() {
+        let header = Header {
+            seq: 0x0102_0304,
+            len: 0x0506,
+        };
+        assert_eq!(header.encode(), [1, 2, 3, 4, 5, 6]);
+        assert_eq!(Header::decode(&[1, 2, 3, 4, 5, 6]), Ok(header));
+    }
+
+    #[test]
+    fn packet_layout() {
+        assert_eq!(packet(1, b"abc"), [0, 0, 0, 1, 0, 3, b'a', b'b', b'c']);
+        assert_eq!(packet(0, b""), [0; HEADER_SIZE]);
+    }
+
+    #[test]
+    fn encode_appends() {
+        let mut out = vec![0xff];
+        encode(7, b"x", &mut out).unwrap();
+        assert_eq!(out, [0xff, 0, 0, 0, 7, 0, 1, b'x']);
+    }
+
+    #[test]
+    fn round_trip() {
+        for seq in [0, 1, 255, 256, 65535, 65536, u32::MAX - 1, u32::MAX] {
+            for len in [0, 1, 1200, MAX_PAYLOAD] {
+                let payload: Vec<u8> = (0..len).map(|i| i as u8).collect();
+                let encoded = packet(seq, &payload);
+                assert_eq!(encoded.len(), HEADER_SIZE + len);
+                let (header, decoded) = decode(&encoded).unwrap();
+                assert_eq!(header.seq, seq);
+                assert_eq!(header.len as usize, len);
+                assert_eq!(decoded, &payload[..]);
+            }
+        }
+    }
+
+    #[test]
+    fn max_payload() {
+        let mut out = Vec::new();
+        assert_eq!(encode(1, &vec![0; MAX_PAYLOAD], &mut out), Ok(()));
+        assert_eq!(&out[4..6], [0xff, 0xff]);
+
+        let mut out = Vec::new();
+        assert_eq!(
+            encode(1, &vec![0; MAX_PAYLOAD + 1], &mut out),
+            Err(Error::PayloadTooLong(MAX_PAYLOAD + 1))
+        );
+        assert!(out.is_empty(), "nothing is written on error");
+    }
+
+    #[test]
+    fn truncated_header() {
+        let encoded = packet(1, b"");
+        for got in 0..HEADER_SIZE {
+            let expected = Err(Error::Truncated {
+                needed: HEADER_SIZE,
+                got,
+            });
+            assert_eq!(Header::decode(&encoded[..got]), expected);
+            assert_eq!(decode(&encoded[..got]).map(|(h, _)| h), expected);
+        }
+    }
+
+    #[test]
+    fn truncated_payload() {
+        let encoded = packet(1, b"hello");
+        for got in HEADER_SIZE..encoded.len() {
+            assert_eq!(
+                decode(&encoded[..got]),
+                Err(Error::Truncated {
+                    needed: HEADER_SIZE + 5,
+                    got
+                })
+            );
+        }
+    }
+
+    #[test]
+    fn trailing_bytes() {
+        let mut encoded = packet(1, b"hello");
+        encoded.extend_from_slice(b"!!");
+        assert_eq!(decode(&encoded), Err(Error::TrailingBytes(2)));
+        // The header alone does not care
+        assert_eq!(Header::decode(&encoded), Ok(Header { seq: 1, len: 5 }));
+    }
+
+    #[test]
+    fn ack_round_trip() {
+        for seq in [0, 1, 0x0102_0304, u32::MAX] {
+            for checknum in [0, 1, 0x7f, 0xff] {
+                let ack = Ack { seq, checknum };
+                assert_eq!(Ack::decode(&ack.encode()), Ok(ack));
+            }
+        }
+    }
+
+    #[test]
+    fn ack_is_big_endian() {
+        let ack = Ack {
+            seq: 0x0102_0304,
+            checknum: 5,
+        };
+        assert_eq!(ack.encode(), [1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn ack_wrong_length() {
+        let encoded = Ack {
+            seq: 1,
+            checknum: 2,
+        }
+        .encode();
+        for got in 0..ACK_SIZE {
+            assert_eq!(
+                Ack::decode(&encoded[..got]),
+                Err(Error::Truncated {
+                    needed: ACK_SIZE,
+                    got
+                })
+            );
+        }
+        assert_eq!(
+            Ack::decode(&[0; ACK_SIZE + 3]),
+            Err(Error::TrailingBytes(3))
+        );
+    }
+
+    #[test]
+    fn sack_layout() {
+        let sack = SackAck {
+            ack: Ack {
+                seq: 3,
+                checknum: 9,
+            },
+            blocks: vec![
+                SackBlock { first: 5, last: 6 },
+                SackBlock {
+                    first: 0x0102_0304,
+                    last: 0x0506_0708,
+                },
+            ],
+        };
+        let encoded = sack.encode().unwrap();
+        assert_eq!(
+            encoded,
+            [0, 0, 0, 3, 9, 0, 0, 0, 5, 0, 0, 0, 6, 1, 2, 3, 4, 5, 6, 7, 8]
+        );
+        assert_eq!(SackAck::decode(&encoded), Ok(sack));
+    }
+
+    #[test]
+    fn plain_ack_is_sack_without_blocks() {
+        let ack = Ack {
+            seq: 42,
+            checknum: 7,
+        };
+        assert_eq!(
+            SackAck::decode(&ack.encode()),
+            Ok(SackAck {
+                ack,
+                blocks: Vec::new()
+            })
+        );
+    }
+
+    #[test]
+    fn sack_partial_block() {
+        let mut encoded = SackAck {
+            ack: Ack {
+                seq: 1,
+                checknum: 0,
+            },
+            blocks: vec![SackBlock { first: 3, last: 4 }],
+        }
+        .encode()
+        .unwrap();
+        encoded.pop();
+        assert_eq!(SackAck::decode(&encoded), Err(Error::TrailingBytes(7)));
+        assert_eq!(
+            SackAck::decode(&encoded[..3]),
+            Err(Error::Truncated {
+                needed: ACK_SIZE,
+                got: 3
+            })
+        );
+    }
+
+    #[test]
+    fn sack_block_limit() {
+        let block = SackBlock { first: 2, last: 2 };
+        let sack = SackAck {
+            ack: Ack {
+                seq: 1,
+                checknum: 0,
+            },
+            blocks: vec![block; MAX_SACK_BLOCKS + 1],
+        };
+        assert_eq!(
+            sack.encode(),
+            Err(Error::TooManyBlocks(MAX_SACK_BLOCKS + 1))
+        );
+        let mut encoded = sack.ack.encode().to_vec();
+        encoded.resize(ACK_SIZE + (MAX_SACK_BLOCKS + 1) * SACK_BLOCK_SIZE, 0);
+        assert_eq!(
+            SackAck::decode(&encoded),
+            Err(Error::TooManyBlocks(MAX_SACK_BLOCKS + 1))
+        );
+        assert_eq!(encoded.len(), MAX_ACK_SIZE + SACK_BLOCK_SIZE);
+    }
+
+    #[test]
+    fn error_messages() {
+        assert_eq!(
+            Error::Truncated { needed: 6, got: 2 }.to_string(),
+            "Truncated packet: 2 bytes of 6"
+        );
+        assert_eq!(
+            Error::TrailingBytes(2).to_string(),
+            "2 bytes after the end of the packet"
+        );
+        assert_eq!(
+            Error::PayloadTooLong(65536).to_string(),
+            "Payload of 65536 bytes is longer than 65535"
+        );
+    }
+}
@@ -0,0 +1,388 @@
+//! The request format of task-srv, shared by the server and adnet-cli. A
+//! request is the number of bytes wanted (4 bytes) and a value byte, and the
+//! response is that many bytes of the value:
+//!
+//! ```text
+//! length (4) | value (1)
+//! ```
+//!
+//! With the top bit of the length set, the request is extended with a
+//! [`Mode`] byte that says how the bytes are generated from the value, and
+//! the response ends with the CRC-32 of its bytes (4 bytes) for the client to
+//! check them:
+//!
+//! ```text
+//! EXTENDED | length (4) | value (1) | mode (1)
+//! ```
+//!
+//! The 5-byte request of the assignment is thus the repeat mode without the
+//! CRC. All numbers are big-endian.
+//!
+//! A length with the top bit set always means an extended request, so no
+//! request, of either kind, can ask for more than [`MAX_EXTENDED_LENGTH`]
+//! bytes. A request of the assignment for 2 GiB or more cannot be encoded,
+//! and its 5 bytes decode as the start of an extended request whose mode
+//! byte never comes; task-srv closes such a connection.
+
+use std::{error, fmt};
+
+/// Bytes in a request of the assignment
+pub const REQUEST_SIZE: usize = 5;
+/// Bytes in an extended request
+pub const EXTENDED_REQUEST_SIZE: usize = 6;
+/// The bit of the length that marks an extended request
+pub const EXTENDED: u32 = 1 << 31;
+/// Longest response of any request
+pub const MAX_EXTENDED_LENGTH: u32 = EXTENDED - 1;
+/// Bytes of the CRC after the response of an extended request
+pub const CRC_SIZE: usize = 4;
+
+/// Why a request could not be encoded or decoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RequestError {
+    /// Fewer bytes than the request calls for; an extended request needs one
+    /// more after the first [`REQUEST_SIZE`]
+    Truncated { needed: usize, got: usize },
+    /// More bytes than the request calls for
+    TrailingBytes(usize),
+    /// A mode byte of no [`Mode`]
+    UnknownMode(u8),
+    /// Length above [`MAX_EXTENDED_LENGTH`], which would set the bit of an
+    /// extended request
+    TooLong(u32),
+}
+
+impl fmt::Display for RequestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RequestError::Truncated { needed, got } => {
+                write!(f, "Truncated request: {} bytes of {}", got, needed)
+            }
+            RequestError::TrailingBytes(extra) => {
+                write!(f, "{} bytes after the end of the request", extra)
+            }
+            RequestError::UnknownMode(mode) => write!(f, "Unknown mode {}", mode),
+            RequestError::TooLong(length) => write!(
+                f,
+                "Length {} is above the longest request of {}",
+                length, MAX_EXTENDED_LENGTH
+            ),
+        }
+    }
+}
+
+impl error::Error for RequestError {}
+
+/// How the bytes of a response are generated from the value of the request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Mode {
+    /// The value, repeated
+    #[default]
+    Repeat = 0,
+    /// The value, then one more for each byte, wrapping from 255 to 0
+    Increment = 1,
+    /// Pseudorandom bytes of a generator seeded with the value
+    Random = 2,
+}
+
+impl TryFrom<u8> for Mode {
+    type Error = RequestError;
+
+    fn try_from(mode: u8) -> Result<Self, Self::Error> {
+        match mode {
+            0 => Ok(Mode::Repeat),
+            1 => Ok(Mode::Increment),
+            2 => Ok(Mode::Random),
+            _ => Err(RequestError::UnknownMode(mode)),
+        }
+    }
+}
+
+impl fmt::Display for Mode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Mode::Repeat => "repeat",
+            Mode::Increment => "increment",
+            Mode::Random => "random",
+        };
+        f.write_str(name)
+    }
+}
+
+/// A request for `length` bytes generated from `value`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Request {
+    pub length: u32,
+    pub value: u8,
+    pub mode: Mode,
+    /// Whether the request has the mode byte, and the response the CRC
+    pub extended: bool,
+}
+
+impl Request {
+    /// The request of the assignment, for `length` copies of `byte`.
+    pub fn repeat(length: u32, byte: u8) -> Self {
+        Self {
+            length,
+            value: byte,
+            mode: Mode::Repeat,
+            extended: false,
+        }
+    }
+
+    /// An extended request, whose response ends with the CRC.
+    pub fn extended(length: u32, value: u8, mode: Mode) -> Self {
+        Self {
+            length,
+            value,
+            mode,
+            extended: true,
+        }
+    }
+
+    pub fn encode(&self) -> Result<Vec<u8>, RequestError> {
+        if self.length > MAX_EXTENDED_LENGTH {
+            return Err(RequestError::TooLong(self.length));
+        }
+        if !self.extended {
+            let mut buf = self.length.to_be_bytes().to_vec();
+            buf.push(self.value);
+            return Ok(buf);
+        }
+        let mut buf = (self.length | EXTENDED).to_be_bytes().to_vec();
+        buf.extend_from_slice(&[self.value, self.mode as u8]);
+        Ok(buf)
+    }
+
+    /// Decodes a request that is all of `buf`. With the first
+    /// [`REQUEST_SIZE`] bytes of an extended request, fails with
+    /// [`RequestError::Truncated`] asking for the mode byte, so that a
+    /// stream can be read a request at a time.
+    pub fn decode(buf: &[u8]) -> Result<Request, RequestError> {
+        let Some(&[a, b, c, d, value]) = buf.get(..REQUEST_SIZE) else {
+            return Err(RequestError::Truncated {
+                needed: REQUEST_SIZE,
+                got: buf.len(),
+            });
+        };
+        let length = u32::from_be_bytes([a, b, c, d]);
+        if length & EXTENDED == 0 {
+            return match buf.len() - REQUEST_SIZE {
+                0 => Ok(Request::repeat(length, value)),
+                extra => Err(RequestError::TrailingBytes(extra)),
+            };
+        }
+        let mode = match buf.len() {
+            EXTENDED_REQUEST_SIZE => Mode::try_from(buf[REQUEST_SIZE])?,
+            got if got < EXTENDED_REQUEST_SIZE => {
+                return Err(RequestError::Truncated {
+                    needed: EXTENDED_REQUEST_SIZE,
+                    got,
+                })
+            }
+            got => return Err(RequestError::TrailingBytes(got - EXTENDED_REQUEST_SIZE)),
+        };
+        Ok(Request::extended(length & !EXTENDED, value, mode))
+    }
+
+    /// The generator of the response bytes.
+    pub fn pattern(&self) -> Pattern {
+        Pattern::new(self.mode, self.value)
+    }
+}
+
+/// The bytes of a response, generated a buffer at a time.
+#[derive(Debug, Clone)]
+pub struct Pattern {
+    mode: Mode,
+    // The next byte, or the state of the generator
+    state: u32,
+}
+
+impl Pattern {
+    pub fn new(mode: Mode, value: u8) -> Self {
+        let state = match mode {
+            Mode::Repeat | Mode::Increment => value as u32,
+            // xorshift needs a state other than zero
+            Mode::Random => 0x9e37_79b9 ^ value as u32,
+        };
+        Self { mode, state }
+    }
+
+    /// Fills `buf` with the next bytes of the response.
+    pub fn fill(&mut self, buf: &mut [u8]) {
+        match self.mode {
+            Mode::Repeat => buf.fill(self.state as u8),
+            Mode::Increment => {
+                for byte in buf {
+                    *byte = self.state as u8;
+                    self.state = self.state.wrapping_add(1);
+                }
+            }
+            Mode::Random => {
+                for byte in buf {
+                    self.state ^= self.state << 13;
+                    self.state ^= self.state >> 17;
+                    self.state ^= self.state << 5;
+                    *byte = (self.state >> 24) as u8;
+                }
+            }
+        }
+    }
+}
+
+/// CRC-32 of ISO HDLC, as used by Ethernet and zlib, over data that comes in
+/// pieces.
+#[derive(Debug, Clone, Copy)]
+pub struct Crc32(u32);
+
+const CRC_TABLE: [u32; 256] = crc_table();
+
+const fn crc_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg());
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+impl Crc32 {
+    pub fn new() -> Self {
+        Self(!0)
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        for &byte in data {
+            self.0 = (self.0 >> 8) ^ CRC_TABLE[((self.0 ^ byte as u32) & 0xff) as usize];
+        }
+    }
+
+    pub fn finish(&self) -> u32 {
+        !self.0
+    }
+}
+
+impl Default for Crc32 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn generate(request: &Request) -> Vec<u8> {
+        let mut buf = vec![0; request.length as usize];
+        request.pattern().fill(&mut buf);
+        buf
+    }
+
+    #[test]
+    fn request_of_the_assignment() {
+        let request = Request::repeat(0x0102_0304, b'A');
+        assert_eq!(request.encode(), Ok(vec![1, 2, 3, 4, b'A']));
+        assert_eq!(Request::decode(&[1, 2, 3, 4, b'A']), Ok(request));
+    }
+
+    #[test]
+    fn extended_request() {
+        let request = Request::extended(1000, 7, Mode::Random);
+        let encoded = request.encode().unwrap();
+        assert_eq!(encoded, [0x80, 0, 0x03, 0xe8, 7, 2]);
+        assert_eq!(Request::decode(&encoded), Ok(request));
+        // The first bytes ask for the mode byte
+        assert_eq!(
+            Request::decode(&encoded[..REQUEST_SIZE]),
+            Err(RequestError::Truncated {
+                needed: EXTENDED_REQUEST_SIZE,
+                got: REQUEST_SIZE
+            })
+        );
+    }
+
+    #[test]
+    fn request_of_the_assignment_with_the_top_bit_set() {
+        let request = Request::repeat(EXTENDED, b'A');
+        assert_eq!(request.encode(), Err(RequestError::TooLong(EXTENDED)));
+        let longest = Request::repeat(MAX_EXTENDED_LENGTH, b'A');
+        assert_eq!(longest.encode(), Ok(vec![0x7f, 0xff, 0xff, 0xff, b'A']));
+        // Sent anyway, it is the start of an extended request
+        assert_eq!(
+            Request::decode(&[0x80, 0, 0, 0, b'A']),
+            Err(RequestError::Truncated {
+                needed: EXTENDED_REQUEST_SIZE,
+                got: REQUEST_SIZE
+            })
+        );
+    }
+
+    #[test]
+    fn invalid_requests() {
+        assert_eq!(
+            Request::decode(&[0, 0, 1]),
+            Err(RequestError::Truncated {
+                needed: REQUEST_SIZE,
+                got: 3
+            })
+        );
+        assert_eq!(
+            Request::decode(&[0, 0, 0, 1, b'A', 0]),
+            Err(RequestError::TrailingBytes(1))
+        );
+        assert_eq!(
+            Request::decode(&[0x80, 0, 0, 1, b'A', 3]),
+            Err(RequestError::UnknownMode(3))
+        );
+        assert_eq!(
+            Request::extended(EXTENDED, 0, Mode::Repeat).encode(),
+            Err(RequestError::TooLong(EXTENDED))
+        );
+    }
+
+    #[test]
+    fn patterns() {
+        let repeat = generate(&Request::extended(3, b'x', Mode::Repeat));
+        assert_eq!(repeat, b"xxx");
+        let increment = generate(&Request::extended(4, 254, Mode::Increment));
+        assert_eq!(increment, [254, 255, 0, 1]);
+
+        let random = generate(&Request::extended(1000, 1, Mode::Random));
+        assert_ne!(random, generate(&Request::extended(1000, 2, Mode::Random)));
+        assert!(random.iter().any(|&b| b != random[0]));
+    }
+
+    #[test]
+    fn patterns_continue_across_buffers() {
+        for mode in [Mode::Repeat, Mode::Increment, Mode::Random] {
+            let whole = generate(&Request::extended(1000, 9, mode));
+            let mut pattern = Pattern::new(mode, 9);
+            let mut pieces = vec![0; 1000];
+            let (first, rest) = pieces.split_at_mut(333);
+            pattern.fill(first);
+            pattern.fill(rest);
+            assert_eq!(pieces, whole, "{}", mode);
+        }
+    }
+
+    #[test]
+    fn crc_of_the_standard() {
+        let mut crc = Crc32::new();
+        crc.update(b"123456789");
+        assert_eq!(crc.finish(), 0xcbf4_3926);
+
+        let mut pieces = Crc32::new();
+        pieces.update(b"1234");
+        pieces.update(b"56789");
+        assert_eq!(pieces.finish(), 0xcbf4_3926);
+        assert_eq!(Crc32::new().finish(), 0);
+    }
+}